@@ -0,0 +1,203 @@
+//! Dumps the output of each layer and the final logits of a model for a prompt, and
+//! compares two such dumps to find where an implementation diverges from a reference.
+//!
+//! The format of the dumps is documented in `doc/activation-dumps.md`.
+
+use std::{
+    fs::File,
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+use color_eyre::eyre::{self, Context};
+use llm::{OutputRequest, TokenId};
+use serde::{Deserialize, Serialize};
+
+use crate::cli_args;
+
+#[derive(Serialize, Deserialize)]
+struct ActivationDump {
+    /// The tokens of the prompt, including the beginning-of-text token, if any.
+    tokens: Vec<TokenId>,
+    /// The activations, in the order they are computed.
+    tensors: Vec<DumpedTensor>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct DumpedTensor {
+    /// `layer.N` for the output of layer `N`, or `logits`.
+    name: String,
+    /// `[n_tokens, n]`.
+    shape: [usize; 2],
+    /// The values, one token after the other.
+    data: Vec<f32>,
+}
+
+pub fn dump(args: &cli_args::DumpActivations) -> eyre::Result<()> {
+    let prompt = crate::load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
+    let model = args.model_load.load(args.generate.use_gpu)?;
+
+    let tokens: Vec<TokenId> = model
+        .tokenizer()
+        .tokenize(&prompt, true)?
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect();
+    eyre::ensure!(
+        tokens.len() < model.context_size(),
+        "the prompt does not fit in the context window"
+    );
+
+    let mut session = model.start_session(args.generate.inference_session_config()?);
+    session.set_control_vectors(&args.generate.control_vectors()?)?;
+    session.set_evaluated_layers(args.generate.evaluated_layers());
+    session.set_capture_layer_outputs(true);
+
+    // The whole prompt is evaluated at once, so that the layer outputs cover all of it.
+    let mut output_request = OutputRequest {
+        all_logits: Some(vec![]),
+        ..Default::default()
+    };
+    model.evaluate(&mut session, &tokens, &mut output_request)?;
+
+    let mut tensors: Vec<DumpedTensor> = session
+        .layer_outputs()
+        .iter()
+        .enumerate()
+        .filter_map(|(layer, output)| {
+            let output = output.as_ref()?;
+            Some(DumpedTensor {
+                name: format!("layer.{layer}"),
+                shape: [tokens.len(), output.len() / tokens.len()],
+                data: output.clone(),
+            })
+        })
+        .collect();
+    tensors.push(DumpedTensor {
+        name: "logits".to_string(),
+        shape: [tokens.len(), model.tokenizer().len()],
+        data: output_request.all_logits.unwrap_or_default(),
+    });
+
+    let writer = BufWriter::new(
+        File::create(&args.output)
+            .wrap_err_with(|| format!("could not create {:?}", args.output))?,
+    );
+    serde_json::to_writer(writer, &ActivationDump { tokens, tensors })?;
+
+    log::info!(
+        "Saved the activations of {} layers to {:?}",
+        session.layer_outputs().iter().flatten().count(),
+        args.output
+    );
+    Ok(())
+}
+
+pub fn compare(args: &cli_args::CompareActivations) -> eyre::Result<()> {
+    let expected = load(&args.expected)?;
+    let actual = load(&args.actual)?;
+    eyre::ensure!(
+        expected.tokens == actual.tokens,
+        "the dumps are for different tokens, so their activations cannot be compared:\n\
+        expected: {:?}\nactual:   {:?}",
+        expected.tokens,
+        actual.tokens
+    );
+
+    println!(
+        "{:<12} {:>12} {:>12} {:>12} {:>10}",
+        "tensor", "max abs", "mean abs", "max rel", "cosine"
+    );
+    let mut first_divergence = None;
+    for actual_tensor in &actual.tensors {
+        let Some(expected_tensor) = expected
+            .tensors
+            .iter()
+            .find(|t| t.name == actual_tensor.name)
+        else {
+            println!("{:<12} (not in {:?})", actual_tensor.name, args.expected);
+            continue;
+        };
+        eyre::ensure!(
+            expected_tensor.shape == actual_tensor.shape
+                && expected_tensor.data.len() == actual_tensor.data.len(),
+            "{} has shape {:?} in {:?}, but {:?} in {:?}",
+            actual_tensor.name,
+            expected_tensor.shape,
+            args.expected,
+            actual_tensor.shape,
+            args.actual
+        );
+
+        let difference = Difference::between(&expected_tensor.data, &actual_tensor.data);
+        println!(
+            "{:<12} {:>12.6} {:>12.6} {:>12.6} {:>10.6}",
+            actual_tensor.name,
+            difference.max_abs,
+            difference.mean_abs,
+            difference.max_rel,
+            difference.cosine_similarity
+        );
+        let diverged = difference.max_rel.is_nan() || difference.max_rel > args.tolerance;
+        if first_divergence.is_none() && diverged {
+            first_divergence = Some(actual_tensor.name.as_str());
+        }
+    }
+
+    match first_divergence {
+        Some(name) => eyre::bail!(
+            "the activations diverge beyond a relative tolerance of {} at {name}",
+            args.tolerance
+        ),
+        None => {
+            println!(
+                "All activations are within a relative tolerance of {}",
+                args.tolerance
+            );
+            Ok(())
+        }
+    }
+}
+
+fn load(path: &Path) -> eyre::Result<ActivationDump> {
+    let file = File::open(path).wrap_err_with(|| format!("could not open {path:?}"))?;
+    serde_json::from_reader(BufReader::new(file))
+        .wrap_err_with(|| format!("{path:?} is not a valid activation dump"))
+}
+
+/// How far apart two tensors are.
+struct Difference {
+    max_abs: f32,
+    mean_abs: f32,
+    /// The largest absolute difference, relative to the largest magnitude of the
+    /// expected tensor.
+    max_rel: f32,
+    cosine_similarity: f32,
+}
+impl Difference {
+    fn between(expected: &[f32], actual: &[f32]) -> Self {
+        let mut max_abs = 0.0f32;
+        let mut sum_abs = 0.0f64;
+        let mut expected_max = 0.0f32;
+        let (mut dot, mut expected_norm, mut actual_norm) = (0.0f64, 0.0f64, 0.0f64);
+        for (&e, &a) in expected.iter().zip(actual) {
+            let abs = (e - a).abs();
+            // Unlike `f32::max`, this keeps NaNs, which must not be hidden.
+            if abs > max_abs || abs.is_nan() {
+                max_abs = abs;
+            }
+            sum_abs += abs as f64;
+            expected_max = expected_max.max(e.abs());
+            dot += e as f64 * a as f64;
+            expected_norm += e as f64 * e as f64;
+            actual_norm += a as f64 * a as f64;
+        }
+
+        Self {
+            max_abs,
+            mean_abs: (sum_abs / expected.len().max(1) as f64) as f32,
+            max_rel: max_abs / expected_max.max(f32::MIN_POSITIVE),
+            cosine_similarity: (dot / (expected_norm.sqrt() * actual_norm.sqrt())) as f32,
+        }
+    }
+}
@@ -1,29 +1,62 @@
 use std::{
+    convert::Infallible,
     fmt,
     ops::Deref,
     path::{Path, PathBuf},
+    time::Duration,
 };
 
-use clap::{Parser, ValueEnum};
+use clap::{Parser, Subcommand, ValueEnum};
 use color_eyre::eyre::{self, WrapErr};
 use llm::{
-    ggml_format, samplers::build_sampler, ElementType, InferenceParameters, InferenceSessionConfig,
-    InvalidTokenBias, LoadProgress, Model, ModelKVMemoryType, ModelParameters, RoPEOverrides,
+    ggml_format, ControlVector, ElementType, EvaluatedLayers, GenerationPreset,
+    InferenceParameters, InferenceSessionConfig, InvalidTokenBias, LoadProgress, LoraAdapterConfig,
+    Model, ModelKVMemoryType, ModelLoader, PromptTruncation, RoPEOverrides, TensorNameMapping,
     TokenBias, TokenId, TokenizerSource,
 };
 use rand::SeedableRng;
 
 #[derive(Parser, Debug)]
 #[command(author, version, about, long_about = None)]
+pub struct Cli {
+    /// The format of the log messages written to stderr.
+    ///
+    /// `json` writes one JSON object per line, with the load progress, inference
+    /// statistics and errors as structured fields, for log aggregation and scripts.
+    /// Progress spinners are not shown in this format.
+    #[arg(long, global = true, value_enum, default_value_t = LogFormat::Text)]
+    pub log_format: LogFormat,
+
+    #[command(subcommand)]
+    pub command: Args,
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human-readable text.
+    Text,
+    /// One JSON object per line.
+    Json,
+}
+
+#[derive(Subcommand, Debug)]
 pub enum Args {
     #[command()]
     /// Use a model to infer the next tokens in a sequence, and exit.
     Infer(Box<Infer>),
 
+    #[command()]
+    /// Use a code model to generate the code between a prefix and a suffix (fill-in-the-middle).
+    Infill(Box<Infill>),
+
     #[command()]
     /// Measure a model's perplexity for a given prompt.
     Perplexity(Box<Perplexity>),
 
+    #[command()]
+    /// Measure a model's accuracy on a multiple-choice benchmark (HellaSwag or MMLU).
+    Eval(Box<Eval>),
+
     #[command()]
     /// Get information about a GGML model.
     Info(Box<Info>),
@@ -46,8 +79,58 @@ pub enum Args {
     /// have an extended conversation.
     Chat(Box<Chat>),
 
+    /// Convert a Hugging Face checkpoint (a directory with `config.json`, `tokenizer.json`
+    /// and `.safetensors` weights) to a GGML model.
+    Convert(Box<Convert>),
+
     /// Quantize a GGML model to 4-bit.
     Quantize(Box<Quantize>),
+
+    /// Rewrite a model in a legacy container (GGML, GGMF or GGJT) as GGUF, so that it can be
+    /// memory-mapped and read by other tools. The architecture is guessed if it is not
+    /// specified.
+    Upgrade(Box<Upgrade>),
+
+    /// Check a GGML model for truncation and corrupted tensors, and optionally repair it by
+    /// truncating it to its last complete tensor and backfilling tensors from another copy.
+    Validate(Box<Validate>),
+
+    /// Apply LoRA adapters to a GGML model, and save the result as a standalone model.
+    MergeLora(Box<MergeLora>),
+
+    /// Blend the weights of two or more GGML models with the same architecture into a new model.
+    Merge(Box<Merge>),
+
+    /// Compress a GGML model into a zstd-compressed container, which can be loaded directly.
+    Pack(Box<Pack>),
+
+    /// Decompress a compressed model container back into the original GGML model.
+    Unpack(Box<Unpack>),
+
+    /// Split a GGML model into shards of a maximum size and a manifest, which can be loaded
+    /// like the model, for hosting on services that limit the size of each file.
+    Split(Box<Split>),
+
+    /// Reassemble a split model into one file, from its manifest or any of its shards.
+    MergeShards(Box<MergeShards>),
+
+    /// Write a tiny model with random weights, for testing loading and inference
+    /// without downloading a real model.
+    MakeTestModel(Box<MakeTestModel>),
+
+    /// Evaluate a prompt and save the output of each layer and the final logits, for
+    /// comparison against a reference implementation with `compare-activations`.
+    DumpActivations(Box<DumpActivations>),
+
+    /// Compare two activation dumps, and report the first layer at which they diverge.
+    CompareActivations(Box<CompareActivations>),
+
+    /// Manage the local model registry, which lets models be referred to by short names
+    /// (e.g. `-m llama-7b`) instead of paths.
+    Models(Box<Models>),
+
+    /// Read the metadata of a model, or edit the key-value metadata of a GGUF model.
+    Meta(Box<Meta>),
 }
 
 #[derive(Parser, Debug)]
@@ -64,12 +147,15 @@ pub struct Infer {
     #[command(flatten)]
     pub prompt: Prompt,
 
-    /// Hide the prompt in the generation.
+    #[command(flatten)]
+    pub output: Output,
+
+    /// Do not echo the prompt before the generation.
     ///
     /// By default, the prompt tokens will be shown as they are fed to the model.
     /// This option will only show the inferred tokens.
-    #[arg(long, default_value_t = false)]
-    pub hide_prompt: bool,
+    #[arg(long, alias = "hide-prompt", default_value_t = false)]
+    pub no_echo_prompt: bool,
 
     /// Loads a saved inference session from the given path, previously saved using
     /// `--save-session`
@@ -88,13 +174,96 @@ pub struct Infer {
     ///
     /// Equivalent to `--load-session` and `--save-session` with the same path,
     /// but will not error if the path does not exist
+    ///
+    /// If the inference is interrupted (with Ctrl-C or SIGTERM), it stops at the next
+    /// token and the session is still saved, so that a long prompt does not need to be
+    /// evaluated again. This also applies to `--save-session` and `--continue`.
     #[arg(long, default_value = None)]
     pub persist_session: Option<PathBuf>,
 
+    /// Continues the generation saved in the session at the given path, and then saves
+    /// the result to the same path.
+    ///
+    /// The previous output is printed again (unless `--no-echo-prompt` is set), and another
+    /// `--num-predict` tokens are generated. A prompt is optional; if one is given, it is
+    /// fed after the previous output before generating.
+    #[arg(
+        long = "continue",
+        value_name = "SESSION",
+        default_value = None,
+        conflicts_with_all = ["load_session", "save_session", "persist_session"]
+    )]
+    pub continue_session: Option<PathBuf>,
+
     /// Output statistics about the time taken to perform inference, among other
     /// things.
     #[arg(long, default_value_t = false)]
     pub stats: bool,
+
+    /// Records the time at which each token was generated and how long it took to
+    /// the given path, for profiling variance in generation speed.
+    ///
+    /// The trace is written as CSV if the path ends in `.csv`, and as JSON lines
+    /// otherwise.
+    #[arg(long, value_name = "PATH")]
+    pub timing_trace: Option<PathBuf>,
+    /// Records the log-probability of each generated token, and of the most probable
+    /// tokens at that point, to the given path as JSON lines, for calibration, watermark
+    /// detection or distillation.
+    #[arg(long, value_name = "PATH")]
+    pub logprobs_out: Option<PathBuf>,
+
+    /// The number of most probable tokens to record for each generated token with
+    /// `--logprobs-out`.
+    #[arg(long, default_value_t = 5)]
+    pub logprobs_top_n: usize,
+
+    /// Generates this many completions of the prompt, and prints them ranked by their
+    /// mean log-probability, best first (best-of-n sampling). The prompt is only
+    /// evaluated once. The model must support rewinding.
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..),
+        conflicts_with_all = [
+            "load_session",
+            "save_session",
+            "persist_session",
+            "continue_session",
+            "timing_trace",
+            "logprobs_out",
+        ]
+    )]
+    pub best_of: Option<usize>,
+
+    /// The number of the best completions to print with `--best-of`.
+    #[arg(
+        long,
+        default_value_t = 1,
+        requires = "best_of",
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub n_completions: usize,
+}
+
+#[derive(Parser, Debug)]
+pub struct Infill {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    #[command(flatten)]
+    pub output: Output,
+
+    /// The code before the gap to fill.
+    #[arg(long)]
+    pub prefix: String,
+
+    /// The code after the gap to fill.
+    #[arg(long, default_value = "")]
+    pub suffix: String,
 }
 
 #[derive(Parser, Debug)]
@@ -112,6 +281,39 @@ pub struct Perplexity {
     pub prompt: Prompt,
 }
 
+#[derive(Parser, Debug)]
+pub struct Eval {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    /// The benchmark to run.
+    #[arg(long, short = 'b')]
+    pub benchmark: Benchmark,
+
+    /// The path to the benchmark's examples, as JSON lines.
+    ///
+    /// For HellaSwag, this is the `hellaswag_val.jsonl` file from the original dataset.
+    /// For MMLU, each line should have `question`, `choices`, `answer` and (optionally)
+    /// `subject` fields, as in the `cais/mmlu` dataset on Hugging Face.
+    #[arg(long, short = 'd')]
+    pub dataset: PathBuf,
+
+    /// Only evaluate the first N examples of the dataset.
+    #[arg(long)]
+    pub limit: Option<usize>,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum Benchmark {
+    /// Commonsense sentence completion.
+    Hellaswag,
+    /// Multiple-choice questions across 57 subjects.
+    Mmlu,
+}
+
 #[derive(Parser, Debug)]
 pub struct Info {
     #[command(flatten)]
@@ -124,6 +326,14 @@ pub struct Info {
     /// Show all of the tokens in the tokenizer.
     #[arg(long, short = 'k')]
     pub tokenizer: bool,
+
+    /// The context size to estimate the memory needed for.
+    #[arg(long, default_value_t = 2048)]
+    pub num_ctx_tokens: usize,
+
+    /// The batch size to estimate the memory needed for.
+    #[arg(long, default_value_t = 8)]
+    pub batch_size: usize,
 }
 
 #[derive(Parser, Debug)]
@@ -165,6 +375,9 @@ pub struct Repl {
 
     #[command(flatten)]
     pub generate: Generate,
+
+    #[command(flatten)]
+    pub output: Output,
 }
 
 #[derive(Parser, Debug)]
@@ -191,8 +404,19 @@ pub struct Chat {
     #[arg(long, short = 'q')]
     pub message_prompt_prefix_file: Option<PathBuf>,
 
+    /// Loads the chat session from the given path if present, instead of feeding the
+    /// prelude, and saves it to the same path after the prelude and after every message.
+    ///
+    /// As the session is saved after every message, exiting or interrupting the chat
+    /// keeps the conversation so far.
+    #[arg(long, default_value = None)]
+    pub persist_session: Option<PathBuf>,
+
     #[command(flatten)]
     pub generate: Generate,
+
+    #[command(flatten)]
+    pub output: Output,
 }
 impl Chat {
     pub fn message_prompt_prefix(&self) -> eyre::Result<String> {
@@ -238,11 +462,37 @@ pub struct Generate {
     #[arg(long, short = 'n')]
     pub num_predict: Option<usize>,
 
+    /// Sets the minimum number of tokens to predict. The end of stream (EOS/EOD) token
+    /// cannot be generated before this many tokens have been predicted.
+    #[arg(long)]
+    pub min_tokens: Option<usize>,
+
+    /// Stops generating after this many seconds (which can be fractional), counted from
+    /// when the prompt starts being fed. This is checked between tokens, so generation
+    /// can overrun it by the time taken to predict one token.
+    #[arg(long, value_name = "SECONDS", value_parser = parse_duration)]
+    pub max_time: Option<Duration>,
+
     /// How many tokens from the prompt at a time to feed the network. Does not
     /// affect generation.
-    #[arg(long, default_value_t = 8)]
+    ///
+    /// Larger batches feed long prompts faster, but need more memory for the
+    /// intermediate results of each batch; use a smaller batch on machines with
+    /// little memory.
+    #[arg(
+        long,
+        default_value_t = 8,
+        value_parser = clap::builder::RangedU64ValueParser::<usize>::new().range(1..)
+    )]
     pub batch_size: usize,
 
+    /// Loads a sampling configuration (sampler options and order, token biases and stop
+    /// sequences) from a built-in preset (precise, creative or code), or from a TOML or JSON
+    /// preset file. `--sampler`, `--sampler-order` and `--token-bias` apply on top of the
+    /// preset. Stop sequences end generation in `infer`.
+    #[arg(long, value_name = "NAME|PATH", value_parser = parse_preset)]
+    pub preset: Option<GenerationPreset>,
+
     /// Configure sampler settings using a string in the format: sampler_name:key1=value1:key2=value2
     /// To configure multiple samplers at once, separate the sampler configuration strings with space or '/' (forward slash).
     /// NOTE: Mirostat samplers are incompatible with top-p, top-k, locally typical and tail free samplers.
@@ -253,6 +503,21 @@ pub struct Generate {
     ///
     /// Configurable samplers (defaults shown in parenthesis):
     ///
+    /// dry (default: disabled) - Penalizes tokens that would continue a sequence that already appeared earlier, which stops loops without penalizing common tokens. See: <https://github.com/oobabooga/text-generation-webui/pull/5677>
+    ///   multiplier(0.8): The penalty for repeating a sequence of allowed_length tokens. 0.0 disables the sampler.
+    ///   base(1.75): How quickly the penalty grows with the length of the repeated sequence.
+    ///   allowed_length(2): The length of the longest sequence that can be repeated without a penalty.
+    ///   last_n(0): Number of previous tokens to search for repeated sequences. 0 searches all of them.
+    ///
+    /// no_repeat_ngram (default: disabled) - Prevents any n-gram from being generated twice, like the `no_repeat_ngram_size` option of Hugging Face Transformers. Also available as --no-repeat-ngram-size.
+    ///   size(0): The size of the n-grams that cannot be repeated. 0 disables the sampler.
+    ///
+    /// epsilon (default: disabled) - Removes tokens whose probability is below epsilon. See: <https://arxiv.org/abs/2210.15191>
+    ///   epsilon(none): The minimum probability of a token. 3e-4 is a reasonable value.
+    ///
+    /// eta (default: disabled) - Like epsilon, but removes fewer tokens when the model is uncertain: the threshold is min(eta, sqrt(eta) * exp(-entropy)). See: <https://arxiv.org/abs/2210.15191>
+    ///   eta(none): The largest minimum probability of a token. Values from 3e-4 to 2e-3 are reasonable.
+    ///
     /// freq_presence (default: disabled) - Allows penalizing tokens for presence and frequency. May be specified more than once.
     ///   frequency_penalty(0.0): Penalty to apply to tokens based on frequency. For example, if a token has appeared 3 times within the last_n range then it will have its probability decreased by 3 * frequency_penalty.
     ///   presence_penalty(0.0): Penalty to apply to tokens that are already present within the last_n tokens.
@@ -276,6 +541,12 @@ pub struct Generate {
     ///   penalty(1.30): The penalty for repeating tokens. Higher values make the generation less likely to get into a loop, but may harm results when repetitive outputs are desired.
     ///   last_n(64): Number of previous tokens to consider.
     ///
+    /// repetition_decay (default: disabled) - A repetition penalty that penalizes recent tokens more than distant ones. May be specified more than once. Replaces the default repetition sampler.
+    ///   penalty(1.30): The penalty for repeating the most recent token.
+    ///   last_n(64): Number of previous tokens to consider, independently of the context size.
+    ///   decay(linear): How the penalty decreases for older tokens: none, linear (to no penalty at the end of the window) or exponential.
+    ///   half_life(last_n / 4): With exponential decay, the number of tokens after which the penalty is halved.
+    ///
     /// tail_free (default: disabled) - An approach to sampling that attempts to outperform existing nucleus (top-p and top-k) methods. See: <https://trentbrick.github.io/Tail-Free-Sampling/>
     ///   z(1.0): It is not entirely clear what a reasonable value here is but 1.0 appears to be the same as disabled which is similar to top-p sampling.
     ///   min_keep(1): Minimum tokens to keep. Setting this to 0 is not recommended.
@@ -302,6 +573,21 @@ pub struct Generate {
     #[arg(long = "sampler", short = 's', verbatim_doc_comment)]
     pub sampler_options: Vec<String>,
 
+    /// The order to run samplers in, separated by semicolons (e.g. "top_k;top_p;temperature").
+    ///
+    /// The listed samplers take each other's places in the default order (repetition,
+    /// freq_presence, seq_repetition, top_k, tail_free, locally_typical, top_p, top_a, min_p,
+    /// temperature), and the others keep theirs. The order can change the output
+    /// considerably; for example, applying temperature before top-p changes which tokens
+    /// top-p keeps.
+    #[arg(long, alias = "samplers", value_delimiter = ';')]
+    pub sampler_order: Vec<String>,
+
+    /// Prevents any n-gram of this many tokens from being generated twice. The same as
+    /// `--sampler no_repeat_ngram:size=N`.
+    #[arg(long)]
+    pub no_repeat_ngram_size: Option<usize>,
+
     /// Specifies the seed to use during sampling. Note that, depending on
     /// hardware, the same seed may lead to different results on two separate
     /// machines.
@@ -336,6 +622,42 @@ pub struct Generate {
     /// Whether to use GPU acceleration when available
     #[arg(long, default_value_t = false)]
     pub use_gpu: bool,
+
+    /// Control vectors to steer the model with, specified as `path` or `path:strength`.
+    /// May be specified more than once, in which case the directions are summed.
+    #[arg(long = "control-vector", value_parser = parse_control_vector)]
+    pub control_vectors: Vec<(PathBuf, f32)>,
+
+    /// The first layer to apply control vectors to. Defaults to the first layer.
+    #[arg(long)]
+    pub control_vector_layer_start: Option<usize>,
+
+    /// The last layer (inclusive) to apply control vectors to. Defaults to the last layer.
+    #[arg(long)]
+    pub control_vector_layer_end: Option<usize>,
+
+    /// Only evaluate the first N layers of the model, skipping the rest.
+    #[arg(long)]
+    pub max_layers: Option<usize>,
+
+    /// A comma-separated list of layers to skip during evaluation, e.g. "10,11,12".
+    #[arg(long, value_delimiter = ',')]
+    pub skip_layers: Vec<usize>,
+    /// Keep generating when the context window is full by keeping the first N tokens
+    /// (attention sinks) and discarding the oldest half of the rest.
+    #[arg(long)]
+    pub attention_sinks: Option<usize>,
+
+    /// What to do with a prompt that does not fit in the context window: fail, drop
+    /// tokens from its start, or keep its first `--truncate-keep` tokens and drop the
+    /// tokens after them. Truncated prompts are cut to leave room for generation.
+    #[arg(long, value_enum, default_value_t = TruncatePrompt::Error)]
+    pub truncate_prompt: TruncatePrompt,
+
+    /// The number of tokens at the start of the prompt to keep with
+    /// `--truncate-prompt middle`, such as a system prompt.
+    #[arg(long, default_value_t = 0)]
+    pub truncate_keep: usize,
 }
 impl Generate {
     #[cfg(all(target_os = "macos", target_arch = "aarch64"))]
@@ -359,17 +681,46 @@ impl Generate {
             .unwrap_or_else(|| self.autodetect_num_threads())
     }
 
-    pub fn inference_session_config(&self) -> InferenceSessionConfig {
+    pub fn inference_session_config(&self) -> eyre::Result<InferenceSessionConfig> {
         let mem_typ = if self.no_float16 {
             ModelKVMemoryType::Float32
         } else {
             ModelKVMemoryType::Float16
         };
-        InferenceSessionConfig {
-            memory_k_type: mem_typ,
-            memory_v_type: mem_typ,
-            n_batch: self.batch_size,
-            n_threads: self.num_threads(),
+        let mut builder = InferenceSessionConfig::builder()
+            .memory_type(mem_typ)
+            .n_batch(self.batch_size)
+            .n_threads(self.num_threads())
+            .prompt_truncation(match self.truncate_prompt {
+                TruncatePrompt::Error => PromptTruncation::Error,
+                TruncatePrompt::Start => PromptTruncation::TruncateStart,
+                TruncatePrompt::Middle => PromptTruncation::TruncateMiddle {
+                    keep: self.truncate_keep,
+                },
+            });
+        if let Some(attention_sinks) = self.attention_sinks {
+            builder = builder.attention_sinks(attention_sinks);
+        }
+        Ok(builder.build()?)
+    }
+
+    pub fn control_vectors(&self) -> eyre::Result<Vec<ControlVector>> {
+        let layers = self.control_vector_layer_start.unwrap_or(0)
+            ..=self.control_vector_layer_end.unwrap_or(usize::MAX);
+        self.control_vectors
+            .iter()
+            .map(|(path, strength)| {
+                Ok(ControlVector::load(path, *strength)
+                    .wrap_err_with(|| format!("Could not load control vector from {path:?}"))?
+                    .with_layers(layers.clone()))
+            })
+            .collect()
+    }
+
+    pub fn evaluated_layers(&self) -> EvaluatedLayers {
+        EvaluatedLayers {
+            first_n: self.max_layers,
+            skip: self.skip_layers.iter().copied().collect(),
         }
     }
 
@@ -386,14 +737,44 @@ impl Generate {
         eot: TokenId,
         n_vocab: usize,
     ) -> eyre::Result<InferenceParameters> {
-        let mut bias: Vec<(TokenId, f32)> = self.token_bias.clone().unwrap_or_default().into();
+        self.inference_parameters_with_samplers(eot, n_vocab, &self.sampler_options)
+    }
+
+    /// Like [Self::inference_parameters], but with `sampler_options` in place of the
+    /// samplers given on the command line.
+    pub fn inference_parameters_with_samplers(
+        &self,
+        eot: TokenId,
+        n_vocab: usize,
+        sampler_options: &[String],
+    ) -> eyre::Result<InferenceParameters> {
+        // The options given on the command line apply on top of the preset.
+        let mut preset = self.preset.clone().unwrap_or_default();
+        let mut bias = Vec::<(TokenId, f32)>::from(self.token_bias.clone().unwrap_or_default());
         if self.ignore_eos {
             bias.push((eot, f32::NEG_INFINITY));
         }
-        Ok(InferenceParameters {
-            sampler: build_sampler(n_vocab, &bias, &self.sampler_options)
-                .map_err(|e| eyre::eyre!("Invalid sampler configuration: {e}"))?,
-        })
+        for (token, bias) in bias {
+            preset.parameters = preset.parameters.token_bias(token, bias);
+        }
+        preset.samplers.extend(sampler_options.iter().cloned());
+        if let Some(size) = self.no_repeat_ngram_size {
+            preset.samplers.push(format!("no_repeat_ngram:size={size}"));
+        }
+        if !self.sampler_order.is_empty() {
+            preset.sampler_order = self.sampler_order.clone();
+        }
+        preset
+            .inference_parameters(n_vocab)
+            .map_err(|e| eyre::eyre!("Invalid sampler configuration: {e}"))
+    }
+
+    /// The sequences that end generation, from the [preset](Self::preset).
+    pub fn stop_sequences(&self) -> &[String] {
+        self.preset
+            .as_ref()
+            .map(|preset| preset.stop_sequences.as_slice())
+            .unwrap_or_default()
     }
 }
 
@@ -401,6 +782,71 @@ fn parse_bias(s: &str) -> Result<TokenBias, InvalidTokenBias> {
     s.parse()
 }
 
+fn parse_preset(s: &str) -> Result<GenerationPreset, String> {
+    GenerationPreset::resolve(s).map_err(|err| match std::error::Error::source(&err) {
+        Some(source) => format!("{err}: {source}"),
+        None => err.to_string(),
+    })
+}
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum TruncatePrompt {
+    /// Fail.
+    Error,
+    /// Drop tokens from the start of the prompt.
+    Start,
+    /// Keep the start of the prompt and drop tokens after it.
+    Middle,
+}
+
+fn parse_duration(s: &str) -> Result<Duration, String> {
+    let seconds: f64 = s.parse().map_err(|e| format!("{e}"))?;
+    if !seconds.is_finite() || seconds < 0.0 || seconds >= u64::MAX as f64 {
+        return Err(format!("`{s}` is not a valid number of seconds"));
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+fn parse_control_vector(s: &str) -> Result<(PathBuf, f32), Infallible> {
+    // Control vectors use the same `path[:scale]` syntax as LoRA adapters.
+    let LoraAdapterConfig { path, scale } = s.parse()?;
+    Ok((path, scale))
+}
+
+#[derive(Parser, Debug)]
+pub struct Output {
+    /// Wrap the generated text at word boundaries to fit the width of the terminal.
+    #[arg(long, default_value_t = false)]
+    pub wrap: bool,
+
+    /// Wrap the generated text at this many columns, instead of the width of the terminal.
+    /// Implies `--wrap`.
+    #[arg(long)]
+    pub wrap_width: Option<usize>,
+
+    /// Whether to style the output, showing the echoed prompt differently from the
+    /// generated text. `auto` styles it if the output is a terminal and `NO_COLOR` is
+    /// not set.
+    #[arg(long, default_value_t = OutputColor::Auto)]
+    pub color: OutputColor,
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum OutputColor {
+    Auto,
+    Always,
+    Never,
+}
+impl fmt::Display for OutputColor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OutputColor::Auto => write!(f, "auto"),
+            OutputColor::Always => write!(f, "always"),
+            OutputColor::Never => write!(f, "never"),
+        }
+    }
+}
+
 #[derive(Parser, Debug)]
 pub struct ModelTokenizer {
     /// Local path to Hugging Face tokenizer file
@@ -439,13 +885,29 @@ impl ModelTokenizer {
 #[derive(Parser, Debug)]
 pub struct ModelArchitecture {
     /// The model architecture to use. Will attempt to guess if not specified.
-    #[arg(long, short = 'a')]
+    #[arg(long, short = 'a', visible_alias = "arch")]
     pub model_architecture: Option<llm::ModelArchitecture>,
 }
+impl ModelArchitecture {
+    /// Resolves `model`, which is either a path or the name of a model registered with
+    /// `llm models add`, to the path of the model and its architecture. The architecture
+    /// given on the command line takes precedence over the registered one.
+    ///
+    /// All commands that take a model should resolve it with this.
+    pub fn resolve_model(
+        &self,
+        model: &Path,
+    ) -> eyre::Result<(PathBuf, Option<llm::ModelArchitecture>)> {
+        Ok(match crate::registry::resolve(model)? {
+            Some((path, registered)) => (path, self.model_architecture.or(registered)),
+            None => (model.to_owned(), self.model_architecture),
+        })
+    }
+}
 
 #[derive(Parser, Debug)]
 pub struct ModelAndTokenizer {
-    /// Where to load the model from
+    /// Where to load the model from, or the name of a model registered with `llm models add`
     #[arg(long, short = 'm')]
     pub model_path: PathBuf,
 
@@ -459,6 +921,11 @@ impl ModelAndTokenizer {
     pub fn to_source(&self) -> eyre::Result<TokenizerSource> {
         self.tokenizer.to_source()
     }
+
+    /// The path of the model and its architecture. See [ModelArchitecture::resolve_model].
+    pub fn resolve(&self) -> eyre::Result<(PathBuf, Option<llm::ModelArchitecture>)> {
+        self.architecture.resolve_model(&self.model_path)
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -507,35 +974,138 @@ pub struct ModelLoad {
     #[arg(long)]
     pub no_mmap: bool,
 
-    /// LoRA adapter to use for the model
-    #[arg(long, num_args(0..))]
-    pub lora_paths: Option<Vec<PathBuf>>,
+    /// Advise the OS to stream the model's weights from disk during evaluation, and to
+    /// release them after each evaluation, instead of keeping them in memory. This lets
+    /// the OS run models larger than the available RAM, but is much slower; how much of
+    /// the model stays in memory is up to the OS. Requires mmap and a Unix-like system.
+    #[arg(long)]
+    pub stream_weights: bool,
+
+    /// Without mmap, read the model's weights when it is first used instead of while it is
+    /// loaded. The weights of layers run on the GPU are still read while loading.
+    #[arg(long)]
+    pub lazy_load: bool,
+
+    /// A GGML file for the same architecture whose tensors replace the same-named
+    /// tensors of the model when it is loaded. Disables mmap.
+    #[arg(long)]
+    pub tensor_overrides: Option<PathBuf>,
+
+    /// Verify each tensor of the model against the checksums stored next to it
+    /// (`<model>.checksums`, written by `quantize`, `merge` and `merge-lora`) before
+    /// loading it. This reads the whole model, so loading takes longer.
+    #[arg(long)]
+    pub verify: bool,
+
+    /// A file of rules that rename the model's tensors as it is loaded, one
+    /// `pattern => name` rule per line, for conversions that name their tensors
+    /// differently. Patterns are regular expressions that must match the whole name,
+    /// and names can refer to their groups (e.g. `layers\.(\d+)\.attn_q\.weight => layers.$1.attention.wq.weight`).
+    #[arg(long)]
+    pub tensor_name_map: Option<PathBuf>,
+
+    /// Warn about and skip tensors that cannot be loaded, or that the model does not use,
+    /// instead of failing. This allows using models with extra tensors, such as adapters
+    /// or additional heads.
+    #[arg(long)]
+    pub skip_unknown_tensors: bool,
+
+    /// Check the model strictly before loading it, for models from untrusted sources: the
+    /// data of its tensors must not overlap, and its floating-point tensors must only hold
+    /// finite values. This reads the floating-point tensors, so loading takes longer.
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Load and evaluate the model in a separate process before loading it, so that a
+    /// malformed model that crashes the loader only crashes that process. The LoRA
+    /// adapters, tensor overrides and tensor name map are loaded there too. Implies `--strict`.
+    #[arg(long)]
+    pub sandbox: bool,
+
+    /// The most memory the `--sandbox` process may map, e.g. `16GiB`. Defaults to the size
+    /// of the model and of its LoRA adapters and tensor overrides, plus 4 GiB for the
+    /// context. Unix-like systems only.
+    #[arg(long)]
+    pub sandbox_memory_limit: Option<bytesize::ByteSize>,
+
+    /// Load the model even if it is not expected to fit in the available memory.
+    #[arg(long)]
+    pub skip_memory_check: bool,
+
+    /// LoRA adapters to use for the model, specified as `path` or `path:scale`.
+    ///
+    /// Multiple adapters can be provided; they will be applied in the order given.
+    /// The scale multiplies the adapter's own scaling, and defaults to 1.0.
+    #[arg(long = "lora", alias = "lora-paths", num_args(0..))]
+    pub lora_adapters: Vec<LoraAdapterConfig>,
 
     /// Number of layers to run on the GPU. If not specified, all layers will be run on the GPU.
     #[arg(long)]
     pub gpu_layers: Option<usize>,
 
+    /// Overrides the maximum ALiBi bias for models that use ALiBi (BLOOM and MPT).
+    #[arg(long)]
+    pub alibi_bias_max: Option<f32>,
+
     #[command(flatten)]
     pub rope_scaling: RoPEScaling,
 }
 
+/// The memory the `--sandbox` process may use on top of the files it maps, by default.
+#[cfg(unix)]
+const SANDBOX_CONTEXT_MEMORY: u64 = 4 << 30;
+
 impl ModelLoad {
     pub fn load(&self, use_gpu: bool) -> eyre::Result<Box<dyn Model>> {
-        let params = ModelParameters {
-            prefer_mmap: !self.no_mmap,
-            context_size: self.num_ctx_tokens,
-            lora_adapters: self.lora_paths.clone(),
-            use_gpu,
-            gpu_layers: self.gpu_layers,
-            rope_overrides: self.rope_scaling.to_rope_arguments(),
-            n_gqa: None,
-        };
+        let (model_path, architecture) = self.model_and_tokenizer.resolve()?;
+        let tensor_name_mapping = self
+            .tensor_name_map
+            .as_deref()
+            .map(TensorNameMapping::read)
+            .transpose()
+            .wrap_err("failed to read the tensor name mapping")?;
+        // Without an architecture, loading fails before the model is read anyway.
+        if let (true, Some(architecture)) = (self.sandbox, architecture) {
+            self.sandboxed_validator(&model_path, tensor_name_mapping.as_ref())?
+                .validate(&model_path, architecture)
+                .wrap_err("the model failed validation")?;
+        }
 
-        let mut sp = Some(spinoff::Spinner::new(
-            spinoff::spinners::Dots2,
-            "Loading model...",
-            None,
-        ));
+        let mut loader = ModelLoader::from_path(&model_path)
+            .mmap(!self.no_mmap)
+            .context_size(self.num_ctx_tokens)
+            .lora_adapters(self.lora_adapters.iter().cloned())
+            .use_gpu(use_gpu)
+            .stream_weights(self.stream_weights)
+            .lazy_loading(self.lazy_load)
+            .verify_checksums(self.verify)
+            .skip_unknown_tensors(self.skip_unknown_tensors)
+            .strict_validation(self.strict || self.sandbox)
+            .skip_memory_check(self.skip_memory_check);
+        if let Some(architecture) = architecture {
+            loader = loader.architecture(architecture);
+        }
+        if let Some(gpu_layers) = self.gpu_layers.filter(|_| use_gpu) {
+            loader = loader.gpu_layers(gpu_layers);
+        }
+        if let Some(rope_overrides) = self.rope_scaling.to_rope_arguments() {
+            loader = loader.rope_overrides(rope_overrides);
+        }
+        if let Some(alibi_bias_max) = self.alibi_bias_max {
+            loader = loader.alibi_bias_max(alibi_bias_max);
+        }
+        if let Some(tensor_overrides) = &self.tensor_overrides {
+            loader = loader.tensor_overrides(tensor_overrides);
+        }
+        if let Some(tensor_name_mapping) = tensor_name_mapping {
+            loader = loader.tensor_name_mapping(tensor_name_mapping);
+        }
+
+        // With structured logs, the progress is logged as events instead of shown with a
+        // spinner.
+        let structured = crate::util::structured_logs();
+        let mut sp = (!structured)
+            .then(|| spinoff::Spinner::new(spinoff::spinners::Dots2, "Loading model...", None));
         let now = std::time::Instant::now();
         let mut prev_load_time = now;
 
@@ -549,63 +1119,80 @@ impl ModelLoad {
             }
         };
 
-        let model = llm::load_dynamic(
-            self.model_and_tokenizer.architecture.model_architecture,
-            &self.model_and_tokenizer.model_path,
-            tokenizer_source,
-            params,
-            |progress| match progress {
-                LoadProgress::HyperparametersLoaded => {
-                    if let Some(sp) = sp.as_mut() {
-                        sp.update_text("Loaded hyperparameters")
-                    };
+        let model = loader
+            .tokenizer(tokenizer_source)
+            .progress(|progress| {
+                if structured {
+                    return log_load_progress(progress, now);
                 }
-                LoadProgress::ContextSize { bytes } => log::debug!(
-                    "ggml ctx size = {}",
-                    bytesize::to_string(bytes as u64, false)
-                ),
-                LoadProgress::LoraApplied { name, source } => {
-                    if let Some(sp) = sp.as_mut() {
-                        sp.update_text(format!(
-                            "Patched tensor {} via LoRA from '{}'",
-                            name,
-                            source.file_name().unwrap().to_str().unwrap()
-                        ));
+                match progress {
+                    LoadProgress::HyperparametersLoaded => {
+                        if let Some(sp) = sp.as_mut() {
+                            sp.update_text("Loaded hyperparameters")
+                        };
                     }
-                }
-                LoadProgress::TensorLoaded {
-                    current_tensor,
-                    tensor_count,
-                    ..
-                } => {
-                    if prev_load_time.elapsed().as_millis() > 500 {
-                        // We don't want to re-render this on every message, as that causes the
-                        // spinner to constantly reset and not look like it's spinning (and
-                        // it's obviously wasteful).
+                    LoadProgress::ContextSize { bytes } => log::debug!(
+                        "ggml ctx size = {}",
+                        bytesize::to_string(bytes as u64, false)
+                    ),
+                    LoadProgress::LoraApplied { name, source } => {
+                        if let Some(sp) = sp.as_mut() {
+                            sp.update_text(format!(
+                                "Patched tensor {} via LoRA from '{}'",
+                                name,
+                                source.file_name().unwrap().to_str().unwrap()
+                            ));
+                        }
+                    }
+                    LoadProgress::TensorOverridden { name, source } => {
                         if let Some(sp) = sp.as_mut() {
                             sp.update_text(format!(
-                                "Loaded tensor {}/{tensor_count}",
-                                current_tensor + 1,
+                                "Overrode tensor {} from '{}'",
+                                name,
+                                source.file_name().unwrap().to_str().unwrap()
+                            ));
+                        }
+                    }
+                    // The loader also logs skipped tensors as warnings.
+                    LoadProgress::TensorSkipped { name, .. } => {
+                        if let Some(sp) = sp.as_mut() {
+                            sp.update_text(format!("Skipped tensor {name}"));
+                        }
+                    }
+                    LoadProgress::TensorLoaded {
+                        current_tensor,
+                        tensor_count,
+                        ..
+                    } => {
+                        if prev_load_time.elapsed().as_millis() > 500 {
+                            // We don't want to re-render this on every message, as that causes the
+                            // spinner to constantly reset and not look like it's spinning (and
+                            // it's obviously wasteful).
+                            if let Some(sp) = sp.as_mut() {
+                                sp.update_text(format!(
+                                    "Loaded tensor {}/{tensor_count}",
+                                    current_tensor + 1,
+                                ));
+                            };
+                            prev_load_time = std::time::Instant::now();
+                        }
+                    }
+                    LoadProgress::Loaded {
+                        file_size,
+                        tensor_count,
+                    } => {
+                        if let Some(mut sp) = sp.take() {
+                            sp.success(&format!(
+                                "Loaded {tensor_count} tensors ({}) after {}ms",
+                                bytesize::to_string(file_size, false),
+                                now.elapsed().as_millis()
                             ));
                         };
-                        prev_load_time = std::time::Instant::now();
                     }
                 }
-                LoadProgress::Loaded {
-                    file_size,
-                    tensor_count,
-                } => {
-                    if let Some(mut sp) = sp.take() {
-                        sp.success(&format!(
-                            "Loaded {tensor_count} tensors ({}) after {}ms",
-                            bytesize::to_string(file_size, false),
-                            now.elapsed().as_millis()
-                        ));
-                    };
-                }
-            },
-        )
-        .wrap_err("Could not load model");
+            })
+            .load()
+            .wrap_err("Could not load model");
 
         if model.is_err() {
             // If we've failed at loading the model, we probably haven't stopped the spinner yet.
@@ -617,6 +1204,88 @@ impl ModelLoad {
 
         model
     }
+
+    /// The validator that `--sandbox` checks the model at `model_path` with, which loads it
+    /// the same way as [Self::load].
+    fn sandboxed_validator(
+        &self,
+        model_path: &Path,
+        tensor_name_mapping: Option<&TensorNameMapping>,
+    ) -> eyre::Result<llm::sandbox::SandboxedValidator> {
+        let mut validator = llm::sandbox::SandboxedValidator::new()?
+            .context_size(self.num_ctx_tokens)
+            .lora_adapters(self.lora_adapters.iter().cloned())
+            .skip_unknown_tensors(self.skip_unknown_tensors);
+        if let Some(tensor_overrides) = &self.tensor_overrides {
+            validator = validator.tensor_overrides(tensor_overrides);
+        }
+        if let Some(tensor_name_mapping) = tensor_name_mapping {
+            validator = validator.tensor_name_mapping(tensor_name_mapping.clone());
+        }
+
+        #[cfg(unix)]
+        {
+            let limit = match self.sandbox_memory_limit {
+                Some(limit) => limit.as_u64(),
+                None => {
+                    let files = std::iter::once(model_path)
+                        .chain(self.tensor_overrides.as_deref())
+                        .chain(
+                            self.lora_adapters
+                                .iter()
+                                .map(|adapter| adapter.path.as_path()),
+                        );
+                    let mut limit = SANDBOX_CONTEXT_MEMORY;
+                    for path in files {
+                        limit += std::fs::metadata(path)
+                            .wrap_err_with(|| format!("failed to read {path:?}"))?
+                            .len();
+                    }
+                    limit
+                }
+            };
+            validator = validator.memory_limit(limit);
+        }
+        #[cfg(not(unix))]
+        let _ = model_path;
+
+        Ok(validator)
+    }
+}
+
+/// Logs the progress of loading a model as structured events.
+fn log_load_progress(progress: LoadProgress, start: std::time::Instant) {
+    match progress {
+        LoadProgress::HyperparametersLoaded => tracing::info!("loaded hyperparameters"),
+        LoadProgress::ContextSize { bytes } => tracing::debug!(bytes, "allocated context"),
+        LoadProgress::LoraApplied { name, source } => {
+            tracing::info!(tensor = %name, source = %source.display(), "applied LoRA adapter")
+        }
+        LoadProgress::TensorOverridden { name, source } => {
+            tracing::info!(tensor = %name, source = %source.display(), "overrode tensor")
+        }
+        LoadProgress::TensorSkipped { name, reason } => {
+            tracing::info!(tensor = %name, %reason, "skipped tensor")
+        }
+        LoadProgress::TensorLoaded {
+            current_tensor,
+            tensor_count,
+            ..
+        } => tracing::debug!(
+            current_tensor = current_tensor + 1,
+            tensor_count,
+            "loaded tensor"
+        ),
+        LoadProgress::Loaded {
+            file_size,
+            tensor_count,
+        } => tracing::info!(
+            tensor_count,
+            file_size,
+            elapsed_ms = start.elapsed().as_millis() as u64,
+            "loaded model"
+        ),
+    }
 }
 
 #[derive(Parser, Debug)]
@@ -644,7 +1313,7 @@ pub struct Quantize {
     #[command(flatten)]
     pub architecture: ModelArchitecture,
 
-    /// The path to the model to quantize
+    /// The path to the model to quantize, or the name of a registered model
     #[arg()]
     pub source: PathBuf,
 
@@ -666,6 +1335,376 @@ pub struct Quantize {
     pub target: QuantizationTarget,
 }
 
+#[derive(Parser, Debug)]
+pub struct Upgrade {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to upgrade, or the name of a registered model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the upgraded model to, as GGUF
+    #[arg()]
+    pub destination: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Validate {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to validate, or the name of a registered model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// Repair the model in place: truncate it to the end of its last complete tensor, and
+    /// replace its missing and corrupted tensors with those from `--backfill`, if given.
+    ///
+    /// Without this, the damage is only reported.
+    #[arg(long)]
+    pub repair: bool,
+
+    /// Another copy of the model to take missing and corrupted tensors from when repairing.
+    #[arg(long, requires = "repair")]
+    pub backfill: Option<PathBuf>,
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeLora {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the base model, or the name of a registered model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the merged model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// LoRA adapters to merge into the model, specified as `path` or `path:scale`.
+    ///
+    /// Adapters are applied in the order given.
+    #[arg(long = "lora", required = true, num_args(1..))]
+    pub lora_adapters: Vec<LoraAdapterConfig>,
+
+    /// The GGML container type to target.
+    ///
+    /// Note that using GGML requires the original model to have
+    /// an unscored vocabulary, which is not the case for newer models.
+    #[arg(short, long, default_value_t = SaveContainerType::GgjtV3)]
+    pub container_type: SaveContainerType,
+
+    /// Quantize the merged model to this format. If not specified, the merged
+    /// tensors keep the element types of the base model.
+    #[arg(long)]
+    pub quantize: Option<QuantizationTarget>,
+}
+
+#[derive(Parser, Debug)]
+pub struct Merge {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The paths (or registered names) of the models to merge. The hyperparameters and
+    /// vocabulary of the merged model are taken from the first model.
+    #[arg(required = true, num_args(2..))]
+    pub sources: Vec<PathBuf>,
+
+    /// The path to save the merged model to
+    #[arg(long, short = 'o')]
+    pub destination: PathBuf,
+
+    #[command(flatten)]
+    pub tokenizer: ModelTokenizer,
+
+    /// How to combine the weights of the models.
+    #[arg(long, short = 'm', default_value_t = MergeMethod::Linear)]
+    pub method: MergeMethod,
+
+    /// The weight of each model for linear merging, in the same order as the models.
+    /// The weights are normalized to sum to 1. Defaults to equal weights.
+    #[arg(long, value_delimiter = ',')]
+    pub weights: Vec<f32>,
+
+    /// The interpolation factor for SLERP merging; 0.0 returns the first model,
+    /// and 1.0 returns the second.
+    #[arg(long, short = 't', default_value_t = 0.5)]
+    pub t: f32,
+
+    /// The GGML container type to target.
+    ///
+    /// Note that using GGML requires the original model to have
+    /// an unscored vocabulary, which is not the case for newer models.
+    #[arg(short, long, default_value_t = SaveContainerType::GgjtV3)]
+    pub container_type: SaveContainerType,
+}
+impl Merge {
+    pub fn merge_method(&self) -> llm::MergeMethod {
+        match self.method {
+            MergeMethod::Linear => llm::MergeMethod::Linear {
+                weights: self.weights.clone(),
+            },
+            MergeMethod::Slerp => llm::MergeMethod::Slerp { t: self.t },
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct Pack {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to compress, or the name of a registered model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the compressed model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    /// The zstd compression level, from 1 (fastest) to 22 (smallest).
+    #[arg(long, default_value_t = 9, value_parser = clap::value_parser!(i32).range(1..=22))]
+    pub level: i32,
+}
+
+#[derive(Parser, Debug)]
+pub struct Split {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to the model to split, or the name of a registered model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the manifest to. The shards are saved next to it, named after it.
+    ///
+    /// Defaults to the path of the model with `.manifest` appended.
+    #[arg(long, short = 'o')]
+    pub outfile: Option<PathBuf>,
+
+    /// The maximum size of each shard (e.g. `4G`, `500MiB`).
+    #[arg(long)]
+    pub max_size: bytesize::ByteSize,
+}
+
+#[derive(Parser, Debug)]
+pub struct MergeShards {
+    /// The manifest of the split model, or any of its shards if they are named like
+    /// `model-00001-of-00003.bin`
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the reassembled model to
+    #[arg()]
+    pub destination: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Unpack {
+    /// The path to the compressed model
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the decompressed model to
+    #[arg()]
+    pub destination: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct Convert {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The directory of the Hugging Face checkpoint, or a GGML model with `--vocab-only`
+    #[arg()]
+    pub source: PathBuf,
+
+    /// The path to save the model to. Defaults to `ggml-model-<outtype>.bin` (or
+    /// `ggml-vocab.bin` with `--vocab-only`) in the directory of the source.
+    #[arg(long, short = 'o')]
+    pub outfile: Option<PathBuf>,
+
+    /// The element type to store the weights as. With `q8_0`, the tensors that the
+    /// architecture does not quantize are stored as f16.
+    #[arg(long, value_enum, default_value_t = ConvertTarget::F16)]
+    pub outtype: ConvertTarget,
+
+    /// Only write the hyperparameters and the vocabulary, without the weights. The result
+    /// can be used by commands that only tokenize, like `prompt-tokens`. The source can
+    /// also be a GGML model.
+    #[arg(long, default_value_t = false, conflicts_with = "outtype")]
+    pub vocab_only: bool,
+}
+impl Convert {
+    /// The path to save the model to.
+    pub fn outfile(&self) -> PathBuf {
+        if let Some(outfile) = &self.outfile {
+            return outfile.clone();
+        }
+        let directory = if self.source.is_dir() {
+            self.source.as_path()
+        } else {
+            self.source.parent().unwrap_or(Path::new("."))
+        };
+        let name = if self.vocab_only {
+            "ggml-vocab.bin".to_string()
+        } else {
+            format!("ggml-model-{}.bin", self.outtype.name())
+        };
+        directory.join(name)
+    }
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[clap(rename_all = "snake_case")]
+pub enum ConvertTarget {
+    /// 32-bit floats.
+    F32,
+    /// 16-bit floats.
+    F16,
+    /// Quantized 8-bit (type 0).
+    Q8_0,
+}
+impl ConvertTarget {
+    fn name(self) -> &'static str {
+        match self {
+            ConvertTarget::F32 => "f32",
+            ConvertTarget::F16 => "f16",
+            ConvertTarget::Q8_0 => "q8_0",
+        }
+    }
+}
+impl From<ConvertTarget> for ElementType {
+    fn from(t: ConvertTarget) -> Self {
+        match t {
+            ConvertTarget::F32 => ElementType::F32,
+            ConvertTarget::F16 => ElementType::F16,
+            ConvertTarget::Q8_0 => ElementType::Q8_0,
+        }
+    }
+}
+
+#[derive(Parser, Debug)]
+pub struct MakeTestModel {
+    #[command(flatten)]
+    pub architecture: ModelArchitecture,
+
+    /// The path to save the model to
+    #[arg()]
+    pub destination: PathBuf,
+
+    /// The seed for the random weights. The same seed always produces the same model.
+    #[arg(long, default_value_t = 0)]
+    pub seed: u64,
+
+    /// The GGML container type to target.
+    #[arg(short, long, default_value_t = SaveContainerType::GgjtV3)]
+    pub container_type: SaveContainerType,
+}
+
+#[derive(Parser, Debug)]
+pub struct DumpActivations {
+    #[command(flatten)]
+    pub model_load: ModelLoad,
+
+    #[command(flatten)]
+    pub prompt_file: PromptFile,
+
+    #[command(flatten)]
+    pub generate: Generate,
+
+    #[command(flatten)]
+    pub prompt: Prompt,
+
+    /// The path to save the activations to. See `doc/activation-dumps.md` for the format.
+    #[arg(long, short = 'o')]
+    pub output: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+pub struct CompareActivations {
+    /// The dump from the reference implementation.
+    #[arg()]
+    pub expected: PathBuf,
+
+    /// The dump to check against the reference, usually from `dump-activations`.
+    #[arg()]
+    pub actual: PathBuf,
+
+    /// The largest allowed difference between two values, relative to the largest
+    /// magnitude of the expected tensor.
+    #[arg(long, default_value_t = 1e-3)]
+    pub tolerance: f32,
+}
+
+#[derive(Parser, Debug)]
+pub struct Models {
+    #[command(subcommand)]
+    pub command: ModelsCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ModelsCommand {
+    /// List the registered models, and the model files in the models directory, with
+    /// their architectures, formats, sizes and when they were last used.
+    List,
+
+    /// Register a model under a short name, which can then be used instead of its path.
+    Add {
+        /// The name to register the model as.
+        name: String,
+
+        /// The path of the model.
+        path: PathBuf,
+
+        #[command(flatten)]
+        architecture: ModelArchitecture,
+    },
+
+    /// Unregister a model. Its file is deleted if it is in the models directory.
+    Rm {
+        /// The name of the model.
+        name: String,
+
+        /// Keep the model's file, even if it is in the models directory.
+        #[arg(long)]
+        keep_file: bool,
+    },
+
+    /// Unregister models whose files no longer exist, and optionally models that have
+    /// not been used for a while.
+    Prune {
+        /// Also remove models that have not been used for this many days.
+        #[arg(long)]
+        unused_for_days: Option<u64>,
+
+        /// Only show what would be removed.
+        #[arg(long)]
+        dry_run: bool,
+    },
+}
+
+#[derive(Parser, Debug, ValueEnum, Clone, Copy)]
+pub enum MergeMethod {
+    /// Weighted average of the models.
+    Linear,
+    /// Spherical linear interpolation between two models.
+    Slerp,
+}
+impl fmt::Display for MergeMethod {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MergeMethod::Linear => write!(f, "linear"),
+            MergeMethod::Slerp => write!(f, "slerp"),
+        }
+    }
+}
+
 #[derive(Parser, Debug, ValueEnum, Clone, Copy)]
 pub enum SaveContainerType {
     /// GGML container.
@@ -715,3 +1754,109 @@ impl From<QuantizationTarget> for ElementType {
         }
     }
 }
+
+#[derive(Parser, Debug)]
+pub struct Meta {
+    #[command(subcommand)]
+    pub command: MetaCommand,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum MetaCommand {
+    /// Print the value of a metadata key or hyperparameter of a model, or all of them as
+    /// `key: value` lines if no key is given.
+    ///
+    /// Models in the legacy containers (GGML, GGMF and GGJT) only store their
+    /// hyperparameters, so there is no other metadata (such as a chat template or a
+    /// license) to read.
+    Get {
+        /// The model, or the name of a registered model.
+        model: PathBuf,
+
+        /// The metadata key or hyperparameter to print, as shown by `llm info`.
+        key: Option<String>,
+
+        #[command(flatten)]
+        architecture: ModelArchitecture,
+    },
+
+    /// Set a metadata key of a GGUF model, adding it if the model does not have it.
+    ///
+    /// Only the header of the model is rewritten if the new one fits before the tensor
+    /// data; otherwise, the model is written again. The keys that describe the layout of
+    /// the model (its hyperparameters, vocabulary and alignment) cannot be edited.
+    Set {
+        /// The model, or the name of a registered model.
+        model: PathBuf,
+
+        /// The metadata key to set, such as `general.license`.
+        key: String,
+
+        /// The value to set the key to.
+        value: String,
+
+        /// The type of the value. Defaults to the type of the key's current value, or a
+        /// string if the model does not have the key.
+        #[arg(long = "type", value_enum)]
+        value_type: Option<MetaValueType>,
+    },
+
+    /// Remove a metadata key from a GGUF model.
+    ///
+    /// Only the header of the model is rewritten if the new one still ends before the
+    /// tensor data; otherwise, the model is written again.
+    Rm {
+        /// The model, or the name of a registered model.
+        model: PathBuf,
+
+        /// The metadata key to remove.
+        key: String,
+    },
+}
+
+#[derive(Debug, ValueEnum, Clone, Copy, PartialEq, Eq)]
+#[clap(rename_all = "snake_case")]
+pub enum MetaValueType {
+    /// An unsigned 8-bit integer.
+    U8,
+    /// A signed 8-bit integer.
+    I8,
+    /// An unsigned 16-bit integer.
+    U16,
+    /// A signed 16-bit integer.
+    I16,
+    /// An unsigned 32-bit integer.
+    U32,
+    /// A signed 32-bit integer.
+    I32,
+    /// An unsigned 64-bit integer.
+    U64,
+    /// A signed 64-bit integer.
+    I64,
+    /// A 32-bit float.
+    F32,
+    /// A 64-bit float.
+    F64,
+    /// `true` or `false`.
+    Bool,
+    /// A UTF-8 string.
+    String,
+}
+impl From<MetaValueType> for ggml_format::gguf::MetadataValueType {
+    fn from(value: MetaValueType) -> Self {
+        match value {
+            MetaValueType::U8 => Self::U8,
+            MetaValueType::I8 => Self::I8,
+            MetaValueType::U16 => Self::U16,
+            MetaValueType::I16 => Self::I16,
+            MetaValueType::U32 => Self::U32,
+            MetaValueType::I32 => Self::I32,
+            MetaValueType::U64 => Self::U64,
+            MetaValueType::I64 => Self::I64,
+            MetaValueType::F32 => Self::F32,
+            MetaValueType::F64 => Self::F64,
+            MetaValueType::Bool => Self::Bool,
+            MetaValueType::String => Self::String,
+        }
+    }
+}
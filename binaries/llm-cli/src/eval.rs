@@ -0,0 +1,206 @@
+//! Multiple-choice benchmarks (HellaSwag and MMLU), scored by comparing the
+//! log-likelihood the model assigns to each option.
+
+use std::{
+    fs::File,
+    io::{BufRead, BufReader},
+    path::Path,
+};
+
+use color_eyre::eyre::{self, Context};
+use llm::{InferenceSessionConfig, Model, TokenId};
+use serde::Deserialize;
+
+use crate::cli_args::{self, Benchmark};
+
+/// A single multiple-choice question.
+struct Task {
+    /// The text shared by all options.
+    context: String,
+    /// The text of each option, which is appended to the context.
+    options: Vec<String>,
+    /// The index of the correct option.
+    answer: usize,
+}
+
+#[derive(Deserialize)]
+/// A HellaSwag example, as found in the `hellaswag_val.jsonl` file of the original dataset.
+struct HellaSwagExample {
+    ctx: String,
+    endings: Vec<String>,
+    label: usize,
+}
+
+#[derive(Deserialize)]
+/// An MMLU example, as exported from the `cais/mmlu` dataset on Hugging Face.
+struct MmluExample {
+    question: String,
+    choices: Vec<String>,
+    answer: usize,
+    #[serde(default)]
+    subject: Option<String>,
+}
+
+const MMLU_LETTERS: [&str; 4] = ["A", "B", "C", "D"];
+
+fn load_tasks(benchmark: Benchmark, path: &Path) -> eyre::Result<Vec<Task>> {
+    let file = File::open(path).wrap_err_with(|| format!("could not open {path:?}"))?;
+
+    let mut tasks = vec![];
+    for (line_number, line) in BufReader::new(file).lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+
+        let task = match benchmark {
+            Benchmark::Hellaswag => {
+                let example: HellaSwagExample = serde_json::from_str(&line)
+                    .wrap_err_with(|| format!("invalid example on line {}", line_number + 1))?;
+                Task {
+                    context: example.ctx,
+                    options: example
+                        .endings
+                        .into_iter()
+                        .map(|ending| format!(" {ending}"))
+                        .collect(),
+                    answer: example.label,
+                }
+            }
+            Benchmark::Mmlu => {
+                let example: MmluExample = serde_json::from_str(&line)
+                    .wrap_err_with(|| format!("invalid example on line {}", line_number + 1))?;
+                eyre::ensure!(
+                    example.choices.len() <= MMLU_LETTERS.len(),
+                    "the example on line {} has more than {} choices",
+                    line_number + 1,
+                    MMLU_LETTERS.len()
+                );
+
+                let mut context = String::new();
+                if let Some(subject) = example.subject {
+                    let subject = subject.replace('_', " ");
+                    context += &format!(
+                        "The following are multiple choice questions (with answers) about {subject}.\n\n"
+                    );
+                }
+                context += &example.question;
+                for (letter, choice) in MMLU_LETTERS.iter().zip(&example.choices) {
+                    context += &format!("\n{letter}. {choice}");
+                }
+                context += "\nAnswer:";
+
+                Task {
+                    context,
+                    options: MMLU_LETTERS[..example.choices.len()]
+                        .iter()
+                        .map(|letter| format!(" {letter}"))
+                        .collect(),
+                    answer: example.answer,
+                }
+            }
+        };
+
+        eyre::ensure!(
+            task.answer < task.options.len(),
+            "the answer of the example on line {} is out of range",
+            line_number + 1
+        );
+        tasks.push(task);
+    }
+
+    Ok(tasks)
+}
+
+pub fn eval(args: &cli_args::Eval) -> eyre::Result<()> {
+    let mut tasks = load_tasks(args.benchmark, &args.dataset)?;
+    if let Some(limit) = args.limit {
+        tasks.truncate(limit);
+    }
+    eyre::ensure!(!tasks.is_empty(), "the dataset contains no examples");
+
+    let inference_session_config = args.generate.inference_session_config()?;
+    let model = args.model_load.load(args.generate.use_gpu)?;
+
+    let mut correct = 0;
+    let mut correct_normalized = 0;
+    for (i, task) in tasks.iter().enumerate() {
+        let context_tokens = tokenize(model.as_ref(), &task.context, true)?;
+
+        let mut scores = vec![];
+        for option in &task.options {
+            let option_tokens = tokenize(model.as_ref(), option, false)?;
+            let log_likelihood = continuation_log_likelihood(
+                model.as_ref(),
+                inference_session_config,
+                &context_tokens,
+                &option_tokens,
+            )?;
+            // Normalizing by the length of the option removes the bias towards short
+            // options, which is reported as `acc_norm` by most evaluation harnesses.
+            scores.push((log_likelihood, log_likelihood / option.len().max(1) as f32));
+        }
+
+        let best = argmax(scores.iter().map(|(ll, _)| *ll));
+        let best_normalized = argmax(scores.iter().map(|(_, ll)| *ll));
+        correct += usize::from(best == task.answer);
+        correct_normalized += usize::from(best_normalized == task.answer);
+
+        let n = i + 1;
+        log::info!(
+            "[{n}/{}] accuracy: {:.2}%, normalized accuracy: {:.2}%",
+            tasks.len(),
+            100.0 * correct as f32 / n as f32,
+            100.0 * correct_normalized as f32 / n as f32
+        );
+    }
+
+    println!(
+        "{:?}: {} examples, accuracy: {:.2}%, normalized accuracy: {:.2}%",
+        args.benchmark,
+        tasks.len(),
+        100.0 * correct as f32 / tasks.len() as f32,
+        100.0 * correct_normalized as f32 / tasks.len() as f32
+    );
+
+    Ok(())
+}
+
+fn tokenize(model: &dyn Model, text: &str, bos: bool) -> eyre::Result<Vec<TokenId>> {
+    Ok(model
+        .tokenizer()
+        .tokenize(text, bos)?
+        .into_iter()
+        .map(|(_, id)| id)
+        .collect())
+}
+
+/// Returns the total log-likelihood of `continuation` following `context`.
+fn continuation_log_likelihood(
+    model: &dyn Model,
+    config: InferenceSessionConfig,
+    context: &[TokenId],
+    continuation: &[TokenId],
+) -> eyre::Result<f32> {
+    eyre::ensure!(
+        !context.is_empty() && context.len() + continuation.len() < model.context_size(),
+        "the example does not fit in the context window"
+    );
+
+    let mut session = model.start_session(config);
+    session.score(model, context)?;
+    Ok(session.score(model, continuation)?.into_iter().sum())
+}
+
+fn argmax(values: impl Iterator<Item = f32>) -> usize {
+    values
+        .enumerate()
+        .fold((0, f32::NEG_INFINITY), |best, (i, v)| {
+            if v > best.1 {
+                (i, v)
+            } else {
+                best
+            }
+        })
+        .0
+}
@@ -1,5 +1,3 @@
-use std::convert::Infallible;
-
 use color_eyre::eyre;
 use rustyline::{
     error::ReadlineError,
@@ -9,55 +7,86 @@ use rustyline::{
 };
 
 use crate::{
-    cli_args::{Chat, Repl},
+    cli_args::{Chat, Generate, ModelLoad, Repl},
+    output::{OutputFormatter, Style},
     snapshot, util,
 };
 
+const COMMAND_HELP: &str = "\
+Commands:
+  /set <sampler>...  Change samplers for the following generations, using the same
+                     syntax as --sampler (e.g. /set temperature:temperature=0.5 top-p:p=0.9)
+  /unset <name>...   Go back to the command line settings of the named samplers
+  /samplers          Show the current sampler settings
+  /reset             Go back to the command line sampler settings
+  /help              Show this message
+Start a line with // to send a line that starts with /.";
+
 pub fn repl(
     Repl {
         generate,
         model_load,
         prompt_file,
+        output,
     }: &Repl,
 ) -> eyre::Result<()> {
-    let (inference_session_config, parameters, model, mut rng) =
+    let (inference_session_config, mut samplers, model, mut rng) =
         initialize_common_state(generate, model_load)?;
+    util::handle_interrupts()?;
 
     let template = prompt_file.contents()?;
+    let mut output = OutputFormatter::new(output);
+    let control_vectors = generate.control_vectors()?;
 
     let model = model.as_ref();
-    let mut session = create_session(model, inference_session_config);
-    readline_loop(|raw_line| {
+    let new_session = || {
+        create_session(
+            model,
+            inference_session_config,
+            &control_vectors,
+            generate.evaluated_layers(),
+        )
+    };
+    let mut session = new_session();
+    readline_loop(&mut samplers, |raw_line, parameters| {
         let line = raw_line.replace("\\\n", "\n");
 
         let prompt = template
             .as_deref()
             .map(|template| util::process_prompt(template, &line))
             .unwrap_or(line);
-        feed_prompt_with_spinner(model, &mut session, prompt)?;
+        let result = run_turn(model, &mut session, new_session, |session| {
+            feed_prompt_with_spinner(model, session, prompt)?;
 
-        session.infer::<Infallible>(
-            model,
-            &mut rng,
-            &llm::InferenceRequest {
-                prompt: "".into(),
-                parameters: &parameters,
-                play_back_previous_tokens: false,
-                maximum_token_count: generate.num_predict,
-            },
-            &mut Default::default(),
-            |r| {
-                if let llm::InferenceResponse::InferredToken(t) = r {
-                    util::print_token(t);
-                }
-                Ok(llm::InferenceFeedback::Continue)
-            },
-        )?;
+            if !util::interrupted() {
+                session.infer(
+                    model,
+                    &mut rng,
+                    &llm::InferenceRequest::builder("", parameters)
+                        .maximum_token_count(generate.num_predict)
+                        .maximum_duration(generate.max_time)
+                        .minimum_token_count(generate.min_tokens)
+                        .build(),
+                    &mut Default::default(),
+                    |r| {
+                        if util::interrupted() {
+                            return llm::InferenceFeedback::Halt;
+                        }
+                        if let llm::InferenceResponse::InferredToken(t) = r {
+                            output.print(Style::Generated, &t);
+                        }
+                        llm::InferenceFeedback::Continue
+                    },
+                )?;
+            }
+            Ok(())
+        })?;
 
-        if !session_ends_with_newline(&session) {
-            println!();
+        output.finish();
+        if let Err(err) = result {
+            log::error!("{err}");
         }
-        session = create_session(model, inference_session_config);
+        session = new_session();
 
         Ok(())
     })
@@ -71,17 +100,43 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
         ..
     } = args;
 
-    let (inference_session_config, parameters, model, mut rng) =
+    let (inference_session_config, mut samplers, model, mut rng) =
         initialize_common_state(generate, model_load)?;
+    util::handle_interrupts()?;
 
     let prelude_prompt = std::fs::read_to_string(prelude_prompt_file)?;
+    let mut output = OutputFormatter::new(&args.output);
     let message_prompt_prefix = args.message_prompt_prefix()?;
+    let control_vectors = generate.control_vectors()?;
 
     let model = model.as_ref();
-    let mut session = create_session(model, inference_session_config);
-    feed_prompt_with_spinner(model, &mut session, prelude_prompt)?;
+    let new_session = || {
+        create_session(
+            model,
+            inference_session_config,
+            &control_vectors,
+            generate.evaluated_layers(),
+        )
+    };
+    let persist_session = args.persist_session.as_deref();
+    let (mut session, session_loaded) = snapshot::read_or_create_session(
+        model,
+        persist_session,
+        None,
+        inference_session_config,
+        &control_vectors,
+        generate.evaluated_layers(),
+    );
+    if !session_loaded {
+        util::set_busy(true);
+        feed_prompt_with_spinner(model, &mut session, prelude_prompt)?;
+        util::set_busy(false);
+        if let Some(path) = persist_session {
+            snapshot::write_session(&mut session, path);
+        }
+    }
 
-    readline_loop(|raw_line| {
+    readline_loop(&mut samplers, |raw_line, parameters| {
         let prompt = {
             let line = raw_line.replace("\\\n", "\n");
             let mut prompt = format!("{message_prompt_prefix}{line}");
@@ -92,45 +147,206 @@ pub fn chat(args: &Chat) -> eyre::Result<()> {
             prompt
         };
 
-        session.infer::<Infallible>(
-            model,
-            &mut rng,
-            &llm::InferenceRequest {
-                prompt: (&prompt).into(),
-                parameters: &parameters,
-                play_back_previous_tokens: false,
-                maximum_token_count: generate.num_predict,
-            },
-            &mut Default::default(),
-            llm::conversation_inference_callback(&message_prompt_prefix, util::print_token),
-        )?;
-
-        if !session_ends_with_newline(&session) {
-            println!();
+        let mut callback = llm::conversation_inference_callback(&message_prompt_prefix, |t| {
+            output.print(Style::Generated, &t)
+        });
+        let result = run_turn(model, &mut session, new_session, |session| {
+            session.infer(
+                model,
+                &mut rng,
+                &llm::InferenceRequest::builder(&prompt, parameters)
+                    .maximum_token_count(generate.num_predict)
+                    .maximum_duration(generate.max_time)
+                    .minimum_token_count(generate.min_tokens)
+                    .build(),
+                &mut Default::default(),
+                |r| {
+                    if util::interrupted() {
+                        return llm::InferenceFeedback::Halt;
+                    }
+                    callback(r)
+                },
+            )?;
+            Ok(())
+        })?;
+        drop(callback);
+
+        output.finish();
+        if let Err(err) = result {
+            log::error!("{err}");
+        }
+        // Failed turns have been rolled back, so the session can be saved either way.
+        if let Some(path) = persist_session {
+            snapshot::write_session(&mut session, path);
         }
 
         Ok(())
     })
 }
 
-fn initialize_common_state(
-    generate: &crate::cli_args::Generate,
-    model_load: &crate::cli_args::ModelLoad,
+fn initialize_common_state<'a>(
+    generate: &'a Generate,
+    model_load: &ModelLoad,
 ) -> eyre::Result<(
     llm::InferenceSessionConfig,
-    llm::InferenceParameters,
+    SamplerSettings<'a>,
     Box<dyn llm::Model>,
     rand::rngs::StdRng,
 )> {
     let model = model_load.load(generate.use_gpu)?;
     Ok((
-        generate.inference_session_config(),
-        generate.inference_parameters(model.eot_token_id(), model.tokenizer().len())?,
+        generate.inference_session_config()?,
+        SamplerSettings::new(generate, model.as_ref())?,
         model,
         generate.rng(),
     ))
 }
 
+/// The samplers used for generation, which can be changed between generations with
+/// slash commands without restarting the session.
+struct SamplerSettings<'a> {
+    generate: &'a Generate,
+    eot: llm::TokenId,
+    n_vocab: usize,
+    /// One sampler configuration (`name:key=value:...`) per entry.
+    options: Vec<String>,
+    parameters: llm::InferenceParameters,
+}
+impl<'a> SamplerSettings<'a> {
+    fn new(generate: &'a Generate, model: &dyn llm::Model) -> eyre::Result<Self> {
+        let (eot, n_vocab) = (model.eot_token_id(), model.tokenizer().len());
+        let options = split_sampler_options(&generate.sampler_options);
+        let parameters = generate.inference_parameters_with_samplers(eot, n_vocab, &options)?;
+        Ok(Self {
+            generate,
+            eot,
+            n_vocab,
+            options,
+            parameters,
+        })
+    }
+
+    /// Runs the slash command `command` (without the slash).
+    fn run_command(&mut self, command: &str) -> eyre::Result<()> {
+        let (name, args) = command
+            .trim()
+            .split_once(char::is_whitespace)
+            .unwrap_or((command.trim(), ""));
+        let args = split_sampler_options(&[args]);
+        match name {
+            "set" => {
+                eyre::ensure!(!args.is_empty(), "Usage: /set <sampler>...");
+                let mut options = self.options.clone();
+                for arg in args {
+                    let name = sampler_name(&arg);
+                    options.retain(|o| sampler_name(o) != name);
+                    options.push(arg);
+                }
+                self.update(options)?;
+            }
+            "unset" => {
+                eyre::ensure!(!args.is_empty(), "Usage: /unset <name>...");
+                let names: Vec<_> = args.iter().map(|a| sampler_name(a)).collect();
+                let mut options = self.options.clone();
+                options.retain(|o| !names.contains(&sampler_name(o)));
+                for default in split_sampler_options(&self.generate.sampler_options) {
+                    if names.contains(&sampler_name(&default)) {
+                        options.push(default);
+                    }
+                }
+                self.update(options)?;
+            }
+            "reset" => self.update(split_sampler_options(&self.generate.sampler_options))?,
+            "samplers" => {}
+            "help" => {
+                println!("{COMMAND_HELP}");
+                return Ok(());
+            }
+            _ => eyre::bail!("Unknown command /{name}. Type /help for a list of commands"),
+        }
+
+        if self.options.is_empty() {
+            println!("Samplers: defaults");
+        } else {
+            println!("Samplers: {}", self.options.join(" "));
+        }
+        Ok(())
+    }
+
+    /// Switches to `options`, unless they are not a valid sampler configuration.
+    fn update(&mut self, options: Vec<String>) -> eyre::Result<()> {
+        self.parameters =
+            self.generate
+                .inference_parameters_with_samplers(self.eot, self.n_vocab, &options)?;
+        self.options = options;
+        Ok(())
+    }
+}
+
+/// Splits sampler configurations, which can be separated by spaces or slashes, into one
+/// configuration per entry.
+fn split_sampler_options(options: &[impl AsRef<str>]) -> Vec<String> {
+    options
+        .iter()
+        .flat_map(|o| o.as_ref().split(|c: char| c == '/' || c.is_whitespace()))
+        .filter(|o| !o.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+/// The name of the sampler configured by `option`, ignoring case, underscores and
+/// dashes, as the sampler configuration does.
+fn sampler_name(option: &str) -> String {
+    option
+        .split(':')
+        .next()
+        .unwrap_or_default()
+        .chars()
+        .filter(|c| *c != '_' && *c != '-')
+        .flat_map(char::to_lowercase)
+        .collect()
+}
+
+/// Runs one turn of a REPL or chat with `turn`. If the turn fails, the session is rolled
+/// back to how it was before the turn, so that a failed turn does not leave its tokens in
+/// the session and affect the following turns.
+///
+/// Returns the error the turn failed with, if any. The outer error is returned if the
+/// session could not be rolled back.
+fn run_turn(
+    model: &dyn llm::Model,
+    session: &mut llm::InferenceSession,
+    new_session: impl FnOnce() -> llm::InferenceSession,
+    turn: impl FnOnce(&mut llm::InferenceSession) -> eyre::Result<()>,
+) -> eyre::Result<eyre::Result<()>> {
+    let tokens_before = session.tokens().to_vec();
+    let Err(err) = turn(session) else {
+        return Ok(Ok(()));
+    };
+
+    // Rewinding is only possible if the tokens from before the turn are still at the
+    // start of the session, which is not the case if its context window rolled.
+    if model.supports_rewind()
+        && !tokens_before.is_empty()
+        && session.tokens().starts_with(&tokens_before)
+    {
+        let n_turn_tokens = session.tokens().len() - tokens_before.len();
+        session.rewind(model, n_turn_tokens)?;
+    } else {
+        // Otherwise, evaluate the tokens from before the turn again in a new session.
+        *session = new_session();
+        if !tokens_before.is_empty() {
+            session.feed_prompt(
+                model,
+                llm::Prompt::Tokens(&tokens_before),
+                &mut Default::default(),
+                |_| llm::InferenceFeedback::Continue,
+            )?;
+        }
+    }
+    Ok(Err(err))
+}
+
 fn feed_prompt_with_spinner(
     model: &dyn llm::Model,
     session: &mut llm::InferenceSession,
@@ -142,12 +358,22 @@ fn feed_prompt_with_spinner(
     }
 
     let mut sp = spinoff::Spinner::new(spinoff::spinners::Dots2, "".to_string(), None);
-    let result = session.feed_prompt(
+    let result = session.feed_prompt_with_progress(
         model,
         &prompt,
         // OutputRequest
         &mut Default::default(),
-        |_| Ok::<_, Infallible>(llm::InferenceFeedback::Continue),
+        |progress| {
+            sp.update_text(format!(
+                "Fed {}/{} tokens",
+                progress.tokens_fed, progress.total_tokens
+            ));
+            if util::interrupted() {
+                llm::InferenceFeedback::Halt
+            } else {
+                llm::InferenceFeedback::Continue
+            }
+        },
     );
     sp.clear();
 
@@ -157,8 +383,18 @@ fn feed_prompt_with_spinner(
 fn create_session(
     model: &dyn llm::Model,
     inference_session_config: llm::InferenceSessionConfig,
+    control_vectors: &[llm::ControlVector],
+    evaluated_layers: llm::EvaluatedLayers,
 ) -> llm::InferenceSession {
-    snapshot::read_or_create_session(model, None, None, inference_session_config).0
+    snapshot::read_or_create_session(
+        model,
+        None,
+        None,
+        inference_session_config,
+        control_vectors,
+        evaluated_layers,
+    )
+    .0
 }
 
 fn session_ends_with_newline(session: &llm::InferenceSession) -> bool {
@@ -168,7 +404,10 @@ fn session_ends_with_newline(session: &llm::InferenceSession) -> bool {
         .map_or(true, |t| *t == b'\n')
 }
 
-fn readline_loop(mut body: impl FnMut(String) -> eyre::Result<()>) -> eyre::Result<()> {
+fn readline_loop(
+    samplers: &mut SamplerSettings,
+    mut body: impl FnMut(String, &llm::InferenceParameters) -> eyre::Result<()>,
+) -> eyre::Result<()> {
     let mut rl = rustyline::Editor::<LineContinuationValidator, DefaultHistory>::new()?;
     rl.set_helper(Some(LineContinuationValidator));
     rl.bind_sequence(force_newline_event_seq(), Cmd::Newline);
@@ -176,7 +415,20 @@ fn readline_loop(mut body: impl FnMut(String) -> eyre::Result<()>) -> eyre::Resu
     loop {
         match rl.readline(">> ") {
             Ok(raw_line) => {
-                if let Err(err) = body(raw_line) {
+                let raw_line = match raw_line.strip_prefix('/') {
+                    Some(line) if line.starts_with('/') => line.to_owned(),
+                    Some(command) => {
+                        if let Err(err) = samplers.run_command(command) {
+                            log::error!("{err}");
+                        }
+                        continue;
+                    }
+                    None => raw_line,
+                };
+                util::set_busy(true);
+                let result = body(raw_line, &samplers.parameters);
+                util::set_busy(false);
+                if let Err(err) = result {
                     log::error!("{err}");
                     break;
                 }
@@ -218,3 +470,174 @@ impl Validator for LineContinuationValidator {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use rand::SeedableRng;
+
+    use super::*;
+
+    /// Loads a test model of the first enabled architecture that has one.
+    fn load_test_model(name: &str) -> Option<Box<dyn llm::Model>> {
+        struct WriteTestModel(std::path::PathBuf);
+        impl llm::ModelArchitectureVisitor<bool> for WriteTestModel {
+            fn visit<M: llm::KnownModel + 'static>(&mut self) -> bool {
+                let mut file = std::io::BufWriter::new(std::fs::File::create(&self.0).unwrap());
+                llm::write_test_model::<M, _>(
+                    &mut file,
+                    llm::ggml_format::SaveContainerType::GgjtV3,
+                    1,
+                )
+                .is_ok()
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("llm-cli-{name}-{}.bin", std::process::id()));
+        let architecture = llm::ModelArchitecture::ALL
+            .iter()
+            .copied()
+            .find(|architecture| architecture.visit(&mut WriteTestModel(path.clone())));
+        let model = architecture.map(|architecture| {
+            llm::load_dynamic(
+                Some(architecture),
+                &path,
+                llm::TokenizerSource::Embedded,
+                Default::default(),
+                |_| {},
+            )
+            .unwrap()
+        });
+        let _ = std::fs::remove_file(&path);
+        model
+    }
+
+    fn feed<'a>(
+        model: &'a dyn llm::Model,
+        text: &'static str,
+    ) -> impl FnOnce(&mut llm::InferenceSession) -> eyre::Result<()> + 'a {
+        move |session| {
+            session.feed_prompt(model, text, &mut Default::default(), |_| {
+                llm::InferenceFeedback::Continue
+            })?;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_run_turn_keeps_history_and_rolls_back_failed_turns() {
+        let Some(model) = load_test_model("turn-history") else {
+            return;
+        };
+        let model = model.as_ref();
+        let new_session = || model.start_session(Default::default());
+        let mut session = new_session();
+
+        run_turn(model, &mut session, new_session, feed(model, "Hello"))
+            .unwrap()
+            .unwrap();
+        let first_turn = session.tokens().to_vec();
+        run_turn(model, &mut session, new_session, feed(model, " world"))
+            .unwrap()
+            .unwrap();
+        let history = session.tokens().to_vec();
+        assert!(history.len() > first_turn.len());
+        assert!(history.starts_with(&first_turn));
+
+        let result = run_turn(model, &mut session, new_session, |session| {
+            feed(model, " again")(session)?;
+            eyre::bail!("the turn failed")
+        })
+        .unwrap();
+        assert_eq!(result.unwrap_err().to_string(), "the turn failed");
+        assert_eq!(session.tokens(), history);
+
+        // The following turns continue from the history of the successful ones.
+        run_turn(model, &mut session, new_session, feed(model, "!"))
+            .unwrap()
+            .unwrap();
+        assert!(session.tokens().len() > history.len());
+        assert!(session.tokens().starts_with(&history));
+
+        // A failed first turn leaves an empty session.
+        let mut session = new_session();
+        let result = run_turn(model, &mut session, new_session, |session| {
+            feed(model, "Hello")(session)?;
+            eyre::bail!("the turn failed")
+        })
+        .unwrap();
+        assert!(result.is_err());
+        assert!(session.tokens().is_empty());
+    }
+
+    #[test]
+    fn test_run_turn_keeps_stopped_turns() {
+        let Some(model) = load_test_model("turn-stop") else {
+            return;
+        };
+        let model = model.as_ref();
+        let new_session = || model.start_session(Default::default());
+        let mut session = new_session();
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        let parameters = llm::InferenceParameters::default();
+
+        let mut infer = |session: &mut llm::InferenceSession, stop_after: usize| {
+            let mut n_tokens = 0;
+            session.infer(
+                model,
+                &mut rng,
+                &llm::InferenceRequest::builder("Hello", &parameters)
+                    .maximum_token_count(8)
+                    .ignore_eos(true)
+                    .build(),
+                &mut Default::default(),
+                |response| {
+                    if let llm::InferenceResponse::InferredToken(_) = response {
+                        n_tokens += 1;
+                    }
+                    if n_tokens >= stop_after {
+                        llm::InferenceFeedback::Halt
+                    } else {
+                        llm::InferenceFeedback::Continue
+                    }
+                },
+            )
+        };
+
+        // A turn stopped by its callback, as when the user interrupts it, is kept.
+        let mut stats = None;
+        run_turn(model, &mut session, new_session, |session| {
+            stats = Some(infer(session, 2)?);
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+        let stats = stats.unwrap();
+        assert!(stats.predict_tokens > 0 && stats.predict_tokens < 8);
+        assert_eq!(
+            session.tokens().len(),
+            stats.prompt_tokens + stats.predict_tokens
+        );
+        let history = session.tokens().to_vec();
+
+        // A turn that stops at the maximum number of tokens is kept too.
+        let mut stats = None;
+        run_turn(model, &mut session, new_session, |session| {
+            stats = Some(infer(session, usize::MAX)?);
+            Ok(())
+        })
+        .unwrap()
+        .unwrap();
+        assert_eq!(stats.unwrap().predict_tokens, 8);
+        assert!(session.tokens().starts_with(&history));
+
+        // A turn that fails after generating tokens is rolled back.
+        let history = session.tokens().to_vec();
+        let result = run_turn(model, &mut session, new_session, |session| {
+            infer(session, usize::MAX)?;
+            eyre::bail!("the callback failed")
+        })
+        .unwrap();
+        assert!(result.is_err());
+        assert_eq!(session.tokens(), history);
+    }
+}
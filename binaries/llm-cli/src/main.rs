@@ -1,51 +1,102 @@
 use std::{
-    convert::Infallible,
     fs::File,
-    io::{BufReader, BufWriter},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
 };
 
 use clap::Parser;
-use cli_args::Args;
+use cli_args::{Args, Cli, LogFormat};
 use color_eyre::eyre::{self, Context, ContextCompat};
 use is_terminal::IsTerminal;
+use output::{OutputFormatter, Style};
 
+mod activations;
 mod cli_args;
+mod eval;
 mod interactive;
+mod output;
+mod registry;
 mod snapshot;
 mod util;
 
 fn main() -> eyre::Result<()> {
-    tracing_subscriber::fmt()
+    // `--sandbox` validates models in another instance of this executable.
+    llm::sandbox::run_validator_if_requested();
+
+    let cli = Cli::parse();
+
+    let subscriber = tracing_subscriber::fmt()
         .with_writer(std::io::stderr)
-        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env())
-        .with_ansi(std::io::stderr().is_terminal())
-        .init();
+        .with_env_filter(tracing_subscriber::EnvFilter::from_default_env());
+    match cli.log_format {
+        LogFormat::Text => subscriber.with_ansi(std::io::stderr().is_terminal()).init(),
+        LogFormat::Json => subscriber.json().flatten_event(true).init(),
+    }
+    util::set_structured_logs(cli.log_format == LogFormat::Json);
 
     color_eyre::install()?;
 
-    let args = Args::parse();
-    match args {
+    let result = match cli.command {
         Args::Infer(args) => infer(&args),
+        Args::Infill(args) => infill(&args),
         Args::Perplexity(args) => perplexity(&args),
+        Args::Eval(args) => eval::eval(&args),
         Args::Info(args) => info(&args),
         Args::PromptTokens(args) => prompt_tokens(&args),
         Args::Repl(args) => interactive::repl(&args),
         Args::Chat(args) => interactive::chat(&args),
+        Args::Convert(args) => convert(&args),
         Args::Quantize(args) => quantize(&args),
+        Args::Upgrade(args) => upgrade(&args),
+        Args::Validate(args) => validate(&args),
+        Args::MergeLora(args) => merge_lora(&args),
+        Args::Merge(args) => merge(&args),
+        Args::Pack(args) => pack(&args),
+        Args::Unpack(args) => unpack(&args),
+        Args::Split(args) => split(&args),
+        Args::MergeShards(args) => merge_shards(&args),
+        Args::MakeTestModel(args) => make_test_model(&args),
+        Args::DumpActivations(args) => activations::dump(&args),
+        Args::CompareActivations(args) => activations::compare(&args),
+        Args::Models(args) => registry::models(&args),
+        Args::Meta(args) => meta(&args),
+    };
+
+    if let (LogFormat::Json, Err(err)) = (cli.log_format, &result) {
+        // Report the error as an event, so that the whole log is in the same format.
+        let causes: Vec<String> = err.chain().skip(1).map(ToString::to_string).collect();
+        tracing::error!(error = %err, ?causes, "the command failed");
+        std::process::exit(1);
     }
+    result
 }
 
 #[tracing::instrument(skip_all)]
 fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
-    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
-    let inference_session_config = args.generate.inference_session_config();
+    if let Some(best_of) = args.best_of {
+        return infer_best_of(args, best_of);
+    }
+
+    let continuing = args.continue_session.is_some();
+    // A continued generation picks up where it left off, so it does not need a prompt.
+    let prompt = if continuing && args.prompt_file.prompt_file.is_none() && args.prompt.is_none() {
+        String::new()
+    } else {
+        load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?
+    };
+    let inference_session_config = args.generate.inference_session_config()?;
     let model = args.model_load.load(args.generate.use_gpu)?;
+    let control_vectors = args.generate.control_vectors()?;
 
     let (mut session, session_loaded) = snapshot::read_or_create_session(
         model.as_ref(),
         args.persist_session.as_deref(),
-        args.load_session.as_deref(),
+        args.load_session
+            .as_deref()
+            .or(args.continue_session.as_deref()),
         inference_session_config,
+        &control_vectors,
+        args.generate.evaluated_layers(),
     );
     let parameters = args
         .generate
@@ -53,41 +104,72 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
 
     let mut rng = args.generate.rng();
 
+    let session_path = args
+        .save_session
+        .as_ref()
+        .or(args.persist_session.as_ref())
+        .or(args.continue_session.as_ref());
+    if session_path.is_some() {
+        // Stop at the next token when interrupted, so that the session evaluated so far
+        // (such as a long prompt) can still be saved.
+        util::handle_interrupts()?;
+        util::set_busy(true);
+    }
+
+    session.set_record_token_timings(args.timing_trace.is_some());
+    session.set_record_logprobs(args.logprobs_out.is_some().then_some(args.logprobs_top_n));
+    let mut output = OutputFormatter::new(&args.output);
+
+    let mut stop_sequences = llm::StopSequences::new(args.generate.stop_sequences());
+
     let span = tracing::trace_span!("infer");
 
     span.in_scope(|| {
         // do work inside the span...
-        let res = session.infer::<Infallible>(
+        let res = session.infer(
             model.as_ref(),
             &mut rng,
-            &llm::InferenceRequest {
-                prompt: prompt.as_str().into(),
-                parameters: &parameters,
-                play_back_previous_tokens: session_loaded,
-                maximum_token_count: args.generate.num_predict,
-            },
+            &llm::InferenceRequest::builder(prompt.as_str(), &parameters)
+                .play_back_previous_tokens(session_loaded)
+                .maximum_token_count(args.generate.num_predict)
+                .maximum_duration(args.generate.max_time)
+                .minimum_token_count(args.generate.min_tokens)
+                .build(),
             // OutputRequest
             &mut Default::default(),
             |r| {
+                if util::interrupted() {
+                    return llm::InferenceFeedback::Halt;
+                }
                 match r {
-                    llm::InferenceResponse::PromptToken(t) if !args.hide_prompt => {
-                        util::print_token(t)
+                    llm::InferenceResponse::SnapshotToken(t)
+                        if continuing && !args.no_echo_prompt =>
+                    {
+                        output.print(Style::Prompt, &t)
+                    }
+                    llm::InferenceResponse::PromptToken(t) if !args.no_echo_prompt => {
+                        output.print(Style::Prompt, &t)
+                    }
+                    llm::InferenceResponse::InferredToken(t) => {
+                        let (text, stopped) = stop_sequences.push(&t);
+                        output.print(Style::Generated, &text);
+                        if stopped {
+                            return llm::InferenceFeedback::Halt;
+                        }
                     }
-                    llm::InferenceResponse::InferredToken(t) => util::print_token(t),
                     _ => {}
                 }
-                Ok(llm::InferenceFeedback::Continue)
+                llm::InferenceFeedback::Continue
             },
         );
 
-        println!();
+        output.print(Style::Generated, &stop_sequences.finish());
+        output.finish();
 
         match res {
             Ok(stats) => {
                 if args.stats {
-                    println!();
-                    println!("{}", stats);
-                    println!();
+                    print_stats(&stats);
                 }
             }
             Err(llm::InferenceError::ContextFull) => {
@@ -99,26 +181,260 @@ fn infer(args: &cli_args::Infer) -> eyre::Result<()> {
             Err(llm::InferenceError::SamplerFailure(err)) => {
                 log::error!("A sampling-related failure occurred: {}", err);
             }
-            Err(llm::InferenceError::UserCallback(_)) | Err(llm::InferenceError::EndOfText) => {
+            Err(llm::InferenceError::WeightsUnavailable(err)) => {
+                log::error!("Failed to read the weights of the model: {}", err);
+            }
+            Err(llm::InferenceError::UserCallback(_))
+            | Err(llm::InferenceError::EndOfText)
+            | Err(llm::InferenceError::Rewind(_)) => {
                 unreachable!("cannot fail")
             }
         }
     });
 
-    if let Some(session_path) = args.save_session.as_ref().or(args.persist_session.as_ref()) {
+    if let Some(path) = &args.timing_trace {
+        write_timing_trace(path, session.token_timings())?;
+    }
+    if let Some(path) = &args.logprobs_out {
+        write_logprobs(path, model.as_ref(), session.token_logprobs())?;
+    }
+
+    if let Some(session_path) = session_path {
         // Write the memory to the cache file
-        snapshot::write_session(session, session_path);
+        snapshot::write_session(&mut session, session_path);
+    }
+    if util::interrupted() {
+        log::warn!("Interrupted before the inference finished");
+        std::process::exit(130);
+    }
+
+    Ok(())
+}
+
+fn infer_best_of(args: &cli_args::Infer, best_of: usize) -> eyre::Result<()> {
+    let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
+    let model = args.model_load.load(args.generate.use_gpu)?;
+    let control_vectors = args.generate.control_vectors()?;
+    let (mut session, _) = snapshot::read_or_create_session(
+        model.as_ref(),
+        None,
+        None,
+        args.generate.inference_session_config()?,
+        &control_vectors,
+        args.generate.evaluated_layers(),
+    );
+    let parameters = args
+        .generate
+        .inference_parameters(model.eot_token_id(), model.tokenizer().len())?;
+    let mut rng = args.generate.rng();
+
+    let ranked = match session.infer_best_of(
+        model.as_ref(),
+        &mut rng,
+        &llm::InferenceRequest::builder(prompt.as_str(), &parameters)
+            .maximum_token_count(args.generate.num_predict)
+            .maximum_duration(args.generate.max_time)
+            .minimum_token_count(args.generate.min_tokens)
+            .build(),
+        best_of,
+        llm::GeneratedSequence::mean_logprob,
+        |_, _| llm::InferenceFeedback::Continue,
+    ) {
+        Err(llm::InferenceError::Rewind(llm::RewindError::UnsupportedArchitecture)) => {
+            eyre::bail!("The model does not support rewinding, which `--best-of` requires")
+        }
+        result => result?,
+    };
+
+    let mut output = OutputFormatter::new(&args.output);
+    for (rank, candidate) in ranked.iter().take(args.n_completions).enumerate() {
+        println!(
+            "--- Completion {} of {} (mean log-probability {:.4}) ---",
+            rank + 1,
+            ranked.len(),
+            candidate.score
+        );
+        if !args.no_echo_prompt {
+            output.print(Style::Prompt, &prompt);
+        }
+        output.print(Style::Generated, &candidate.sequence.text);
+        output.finish();
+        if args.stats {
+            print_stats(&candidate.sequence.stats);
+        }
+    }
+
+    Ok(())
+}
+
+/// Prints the statistics of an inference, or logs them as an event with structured logs.
+fn print_stats(stats: &llm::InferenceStats) {
+    if util::structured_logs() {
+        tracing::info!(
+            prompt_tokens = stats.prompt_tokens,
+            feed_prompt_duration_ms = stats.feed_prompt_duration.as_millis() as u64,
+            predict_tokens = stats.predict_tokens,
+            predict_duration_ms = stats.predict_duration.as_millis() as u64,
+            token_latency_mean_us = stats.token_latency.mean.as_micros() as u64,
+            token_latency_p50_us = stats.token_latency.p50.as_micros() as u64,
+            token_latency_p90_us = stats.token_latency.p90.as_micros() as u64,
+            token_latency_p99_us = stats.token_latency.p99.as_micros() as u64,
+            context_memory_peak = stats.memory.context_peak as u64,
+            context_memory_size = stats.memory.context_size as u64,
+            scratch_memory_peak = stats.memory.scratch_peak as u64,
+            scratch_memory_size = stats.memory.scratch_size as u64,
+            kv_cache_memory_peak = stats.memory.kv_cache_peak as u64,
+            kv_cache_memory_size = stats.memory.kv_cache_size as u64,
+            "inference finished"
+        );
+    } else {
+        println!();
+        println!("{stats}");
+        println!();
+    }
+}
+
+fn infill(args: &cli_args::Infill) -> eyre::Result<()> {
+    let model = args.model_load.load(args.generate.use_gpu)?;
+    let parameters = args
+        .generate
+        .inference_parameters(model.eot_token_id(), model.tokenizer().len())?;
+    let mut session = model.start_session(args.generate.inference_session_config()?);
+    let mut rng = args.generate.rng();
+    let mut output = OutputFormatter::new(&args.output);
+
+    output.print(Style::Prompt, &args.prefix);
+    let stats = session.infill(
+        model.as_ref(),
+        &mut rng,
+        &llm::InfillRequest::builder(&args.prefix, &args.suffix, &parameters)
+            .maximum_token_count(args.generate.num_predict)
+            .maximum_duration(args.generate.max_time)
+            .build(),
+        &mut Default::default(),
+        |r| {
+            if let llm::InferenceResponse::InferredToken(t) = r {
+                output.print(Style::Generated, &t);
+            }
+            llm::InferenceFeedback::Continue
+        },
+    );
+    output.print(Style::Prompt, &args.suffix);
+    output.finish();
+
+    match stats {
+        Err(llm::InfillError::UnsupportedModel) => eyre::bail!(
+            "The model does not have fill-in-the-middle tokens; use a code model such as CodeLlama or StarCoder"
+        ),
+        Err(llm::InfillError::Inference(llm::InferenceError::ContextFull)) => {
+            log::warn!("Context window full, stopping inference.")
+        }
+        result => {
+            result?;
+        }
+    }
+    Ok(())
+}
+
+fn write_timing_trace(path: &Path, timings: &[llm::TokenTiming]) -> eyre::Result<()> {
+    let mut writer =
+        BufWriter::new(File::create(path).wrap_err_with(|| format!("could not create {path:?}"))?);
+    let csv = path
+        .extension()
+        .map_or(false, |e| e.eq_ignore_ascii_case("csv"));
+    if csv {
+        writeln!(writer, "index,token_id,timestamp_ms,latency_ms")?;
+    }
+    for (index, timing) in timings.iter().enumerate() {
+        // Milliseconds since the Unix epoch.
+        let timestamp_ms = timing
+            .timestamp
+            .duration_since(std::time::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs_f64()
+            * 1000.0;
+        let latency_ms = timing.latency.as_secs_f64() * 1000.0;
+        if csv {
+            writeln!(
+                writer,
+                "{index},{},{timestamp_ms:.3},{latency_ms:.3}",
+                timing.token_id
+            )?;
+        } else {
+            serde_json::to_writer(
+                &mut writer,
+                &serde_json::json!({
+                    "index": index,
+                    "token_id": timing.token_id,
+                    "timestamp_ms": timestamp_ms,
+                    "latency_ms": latency_ms,
+                }),
+            )?;
+            writeln!(writer)?;
+        }
     }
+    writer.flush()?;
 
+    log::info!("Wrote the timings of {} tokens to {path:?}", timings.len());
+    Ok(())
+}
+
+fn write_logprobs(
+    path: &Path,
+    model: &dyn llm::Model,
+    logprobs: &[llm::TokenLogprobs],
+) -> eyre::Result<()> {
+    let mut writer =
+        BufWriter::new(File::create(path).wrap_err_with(|| format!("could not create {path:?}"))?);
+    let text = |token_id: llm::TokenId| {
+        String::from_utf8_lossy(&model.tokenizer().token(token_id as usize)).into_owned()
+    };
+    for (index, token) in logprobs.iter().enumerate() {
+        let top_logprobs: Vec<_> = token
+            .top_logprobs
+            .iter()
+            .map(|&(token_id, logprob)| {
+                serde_json::json!({
+                    "token_id": token_id,
+                    "text": text(token_id),
+                    "logprob": logprob,
+                })
+            })
+            .collect();
+        serde_json::to_writer(
+            &mut writer,
+            &serde_json::json!({
+                "index": index,
+                "token_id": token.token_id,
+                "text": text(token.token_id),
+                "logprob": token.logprob,
+                "top_logprobs": top_logprobs,
+            }),
+        )?;
+        writeln!(writer)?;
+    }
+    writer.flush()?;
+
+    log::info!(
+        "Wrote the log-probabilities of {} tokens to {path:?}",
+        logprobs.len()
+    );
     Ok(())
 }
 
 fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
-    let inference_session_config = args.generate.inference_session_config();
+    let inference_session_config = args.generate.inference_session_config()?;
     let model = args.model_load.load(args.generate.use_gpu)?;
-    let (mut session, _) =
-        snapshot::read_or_create_session(model.as_ref(), None, None, inference_session_config);
+    let control_vectors = args.generate.control_vectors()?;
+    let (mut session, _) = snapshot::read_or_create_session(
+        model.as_ref(),
+        None,
+        None,
+        inference_session_config,
+        &control_vectors,
+        args.generate.evaluated_layers(),
+    );
 
     session.perplexity(model.as_ref(), prompt.as_str(), |chunk, perplexity| {
         println!("Perplexity[{chunk}]: {perplexity}");
@@ -128,37 +444,54 @@ fn perplexity(args: &cli_args::Perplexity) -> eyre::Result<()> {
 }
 
 fn info(args: &cli_args::Info) -> eyre::Result<()> {
-    struct InfoVisitor<'a>(&'a cli_args::Info);
+    struct InfoVisitor<'a>(&'a cli_args::Info, PathBuf);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for InfoVisitor<'_> {
         fn visit<M: llm::KnownModel + 'static>(&mut self) -> eyre::Result<()> {
             let args = self.0;
 
-            let model_path = &args.model_and_tokenizer.model_path;
-            let tokenizer = args.model_and_tokenizer.to_source()?.retrieve(model_path)?;
+            let model_path = &self.1;
+            // Only the header of the model is read, as we are only interested in the metadata.
+            let model_file = llm::ModelFile::<M::Hyperparameters>::open(
+                model_path,
+                args.model_and_tokenizer.to_source()?,
+            )?;
 
-            let file = File::open(model_path)?;
-            let mut reader = BufReader::new(&file);
-            let mut loader: llm::Loader<M::Hyperparameters, _> =
-                llm::Loader::new(tokenizer, |_| {
-                    // We purposely do not print progress here, as we are only interested in the metadata
-                });
-
-            llm::ggml_format::load(&mut reader, &mut loader)?;
+            log::info!("Container type: {:?}", model_file.container_type);
+            let hyperparameters =
+                llm::DescribeHyperparameters::describe(&model_file.hyperparameters);
+            log::info!("Hyperparameters:");
+            for (key, value) in &hyperparameters.metadata {
+                log::info!("- {key}: {value}");
+            }
+            if let Some(context_size_trained) = hyperparameters.context_size_trained {
+                log::info!("Trained context size: {context_size_trained}");
+            }
+            log::info!("Tokenizer vocabulary size: {}", model_file.tokenizer.len());
 
-            log::info!("Container type: {:?}", loader.container_type);
-            log::info!("Hyperparameters: {:?}", loader.hyperparameters);
-            log::info!("Tokenizer vocabulary size: {}", loader.tokenizer.len());
+            let estimate =
+                llm::estimate_memory::<M>(model_path, args.num_ctx_tokens, args.batch_size)?;
+            let size = |bytes: usize| bytesize::to_string(bytes as u64, false);
+            log::info!(
+                "Estimated memory with a context of {} tokens and a batch size of {}: {} \
+                (weights: {}, key/value memory: {}, scratch: {})",
+                args.num_ctx_tokens,
+                args.batch_size,
+                size(estimate.total()),
+                size(estimate.weights),
+                size(estimate.kv_cache),
+                size(estimate.scratch)
+            );
 
             if args.tokenizer {
                 log::info!("Tokens:");
-                for i in 0..loader.tokenizer.len() {
-                    log::info!("- {}: {}", i, utf8_or_array(&loader.tokenizer.token(i)));
+                for i in 0..model_file.tokenizer.len() {
+                    log::info!("- {}: {}", i, utf8_or_array(&model_file.tokenizer.token(i)));
                 }
             }
 
             if args.tensors {
                 log::info!("Tensors:");
-                for (name, tensor) in &loader.tensors {
+                for (name, tensor) in &model_file.tensors {
                     log::info!("- {} ({:?} {:?})", name, tensor.element_type, tensor.dims());
                 }
             }
@@ -171,17 +504,31 @@ fn info(args: &cli_args::Info) -> eyre::Result<()> {
         }
     }
 
-    args.model_and_tokenizer
-        .architecture
-        .model_architecture
+    let (model_path, architecture) = args.model_and_tokenizer.resolve()?;
+    architecture
         .wrap_err("a model architecture is required at present")?
-        .visit(&mut InfoVisitor(args))
+        .visit(&mut InfoVisitor(args, model_path))
 }
 
 fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
+    struct PromptTokensVisitor<'a>(&'a cli_args::PromptTokens, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<llm::Tokenizer>> for PromptTokensVisitor<'_> {
+        fn visit<M: llm::KnownModel + 'static>(&mut self) -> eyre::Result<llm::Tokenizer> {
+            // Only the header of the model is read, so vocabulary-only models work too.
+            let model_file = llm::ModelFile::<M::Hyperparameters>::open(
+                &self.1,
+                self.0.model_load.model_and_tokenizer.to_source()?,
+            )?;
+            Ok(model_file.tokenizer)
+        }
+    }
+
     let prompt = load_prompt_file_with_prompt(&args.prompt_file, args.prompt.as_deref())?;
-    let model = args.model_load.load(false)?;
-    let toks = match model.tokenizer().tokenize(&prompt, false) {
+    let (model_path, architecture) = args.model_load.model_and_tokenizer.resolve()?;
+    let tokenizer = architecture
+        .wrap_err("a model architecture is required at present")?
+        .visit(&mut PromptTokensVisitor(args, model_path))?;
+    let toks = match tokenizer.tokenize(&prompt, false) {
         Ok(toks) => toks,
         Err(e) => {
             log::error!("Could not tokenize prompt: {e}");
@@ -207,18 +554,74 @@ fn prompt_tokens(args: &cli_args::PromptTokens) -> eyre::Result<()> {
     Ok(())
 }
 
-fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
-    use llm::QuantizeProgress;
+fn convert(args: &cli_args::Convert) -> eyre::Result<()> {
+    struct ConvertVisitor<'a>(&'a cli_args::Convert);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for ConvertVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let outfile = args.outfile();
+            let mut destination: BufWriter<File> = BufWriter::new(std::fs::File::create(&outfile)?);
+            if args.vocab_only {
+                let result = if args.source.is_file() {
+                    let model_file = llm::ModelFile::<M::Hyperparameters>::open(
+                        &args.source,
+                        llm::TokenizerSource::Embedded,
+                    )?;
+                    llm::write_vocabulary_only(
+                        &mut destination,
+                        &model_file.hyperparameters,
+                        &model_file.tokenizer,
+                    )
+                } else {
+                    llm::convert_hf_vocabulary::<M, _>(&args.source, &mut destination, |_| {})
+                };
+                result.wrap_err("failed to write the vocabulary")?;
+                destination.flush()?;
+                log::info!("Wrote the vocabulary to {outfile:?}");
+                return Ok(());
+            }
+
+            let element_type: llm::ElementType = args.outtype.into();
+            llm::convert_hf::<M, _>(&args.source, &mut destination, element_type, |progress| {
+                match progress {
+                    llm::ConvertProgress::HyperparametersRead => {
+                        log::info!("Read hyperparameters")
+                    }
+                    llm::ConvertProgress::TensorConverting { source_name, name } => {
+                        log::info!("Converting tensor `{source_name}` to `{name}`")
+                    }
+                    llm::ConvertProgress::TensorSkipped { name } => {
+                        log::warn!("Skipped tensor `{name}`, which the architecture does not use")
+                    }
+                    llm::ConvertProgress::Finished { n_tensors } => {
+                        log::info!("Converted {n_tensors} tensors")
+                    }
+                }
+            })
+            .wrap_err("failed to convert model")?;
+            destination.flush()?;
+            log::info!("Wrote the model to {outfile:?}");
+            write_checksums::<M>(&outfile)
+        }
+    }
+
+    args.architecture
+        .model_architecture
+        .wrap_err("the architecture must be specified for conversion")?
+        .visit(&mut ConvertVisitor(args))
+}
 
-    struct QuantizeVisitor<'a>(&'a cli_args::Quantize);
+fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
+    struct QuantizeVisitor<'a>(&'a cli_args::Quantize, PathBuf);
     impl llm::ModelArchitectureVisitor<eyre::Result<()>> for QuantizeVisitor<'_> {
         fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
             let args = self.0;
 
-            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&args.source)?);
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&self.1)?);
             let mut destination: BufWriter<File> =
                 BufWriter::new(std::fs::File::create(&args.destination)?);
-            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&args.source)?;
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&self.1)?;
 
             llm::quantize::<M, _, _>(
                 &mut source,
@@ -226,45 +629,523 @@ fn quantize(args: &cli_args::Quantize) -> eyre::Result<()> {
                 tokenizer,
                 args.container_type.into(),
                 args.target.into(),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to quantize model")?;
+            destination.flush()?;
+            write_checksums::<M>(&args.destination)
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    architecture
+        .wrap_err("the architecture must be known for quantization")?
+        .visit(&mut QuantizeVisitor(args, source))
+}
+
+fn upgrade(args: &cli_args::Upgrade) -> eyre::Result<()> {
+    struct UpgradeVisitor<'a>(&'a cli_args::Upgrade, PathBuf, llm::ModelArchitecture);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for UpgradeVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&self.1)?);
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+
+            llm::upgrade::<M, _, _>(
+                &mut source,
+                &mut destination,
+                self.2.name(),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to upgrade model")?;
+            destination.flush()?;
+            write_checksums::<M>(&args.destination)
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    let architecture = match architecture {
+        Some(architecture) => architecture,
+        None => {
+            let guesses = llm::guess_model_architectures(&source);
+            let architecture = *guesses.first().wrap_err(
+                "the architecture of the model could not be guessed; specify it with `-a`",
+            )?;
+            if guesses.len() > 1 {
+                log::warn!(
+                    "The model could be any of {guesses:?}; assuming {architecture}. \
+                     Specify the architecture with `-a` if this is wrong."
+                );
+            } else {
+                log::info!("Guessed that the model is {architecture}");
+            }
+            architecture
+        }
+    };
+    architecture.visit(&mut UpgradeVisitor(args, source, architecture))
+}
+
+fn validate(args: &cli_args::Validate) -> eyre::Result<()> {
+    struct ValidateVisitor<'a>(&'a cli_args::Validate, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<llm::RepairReport>> for ValidateVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<llm::RepairReport> {
+            let args = self.0;
+            let options = llm::RepairOptions {
+                dry_run: !args.repair,
+                backfill_from: args.backfill.as_deref(),
+            };
+            llm::repair::<M::Hyperparameters>(&self.1, options)
+                .wrap_err_with(|| format!("failed to validate {:?}", self.1))
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    let architecture = architecture
+        .or_else(|| llm::guess_model_architectures(&source).first().copied())
+        .wrap_err("the architecture of the model could not be guessed; specify it with `-a`")?;
+    let report = architecture.visit(&mut ValidateVisitor(args, source.clone()))?;
+
+    if report.is_intact() {
+        println!("{source:?} is intact");
+        return Ok(());
+    }
+
+    let action = if args.repair {
+        "Truncated"
+    } else {
+        "Would truncate"
+    };
+    if let Some(truncated_to) = report.truncated_to {
+        println!(
+            "{action} the model from {} to {truncated_to} bytes, the end of its last complete tensor",
+            report.original_len
+        );
+    }
+    for name in &report.corrupted {
+        println!("Corrupted tensor: {name}");
+    }
+    for name in &report.missing {
+        println!("Missing tensor: {name}");
+    }
+    let action = if args.repair {
+        "Backfilled"
+    } else {
+        "Would backfill"
+    };
+    for name in &report.backfilled {
+        println!("{action} tensor: {name}");
+    }
+
+    let unrepaired: Vec<_> = report.unrepaired().collect();
+    if !unrepaired.is_empty() {
+        eyre::bail!(
+            "{} tensors are still damaged; repair the model with `--backfill` from another copy, \
+             or download it again",
+            unrepaired.len()
+        );
+    }
+    if !args.repair {
+        eyre::bail!("the model is damaged; run with `--repair` to repair it");
+    }
+    Ok(())
+}
+
+fn merge_lora(args: &cli_args::MergeLora) -> eyre::Result<()> {
+    struct MergeLoraVisitor<'a>(&'a cli_args::MergeLora, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MergeLoraVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut source: BufReader<File> = BufReader::new(std::fs::File::open(&self.1)?);
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&self.1)?;
+
+            llm::merge_lora::<M, _, _>(
+                &mut source,
+                &mut destination,
+                tokenizer,
+                &args.lora_adapters,
+                args.container_type.into(),
+                args.quantize.map(Into::into),
+                log_quantize_progress,
+            )
+            .wrap_err("failed to merge LoRA adapters into model")?;
+            destination.flush()?;
+            write_checksums::<M>(&args.destination)
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    architecture
+        .wrap_err("the architecture must be known for merging LoRA adapters")?
+        .visit(&mut MergeLoraVisitor(args, source))
+}
+
+fn merge(args: &cli_args::Merge) -> eyre::Result<()> {
+    struct MergeVisitor<'a>(&'a cli_args::Merge, Vec<PathBuf>);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MergeVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut sources = self
+                .1
+                .iter()
+                .map(|path| {
+                    Ok(BufReader::new(File::open(path).wrap_err_with(|| {
+                        format!("Could not open model at {path:?}")
+                    })?))
+                })
+                .collect::<eyre::Result<Vec<_>>>()?;
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            let tokenizer: llm::Tokenizer = args.tokenizer.to_source()?.retrieve(&self.1[0])?;
+
+            llm::merge::<M, _, _>(
+                &mut sources,
+                &mut destination,
+                tokenizer,
+                &args.merge_method(),
+                args.container_type.into(),
                 |progress| match progress {
-                    QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
-                    QuantizeProgress::TensorLoading {
+                    llm::MergeProgress::ModelsLoaded { count } => {
+                        log::info!("Loaded {count} models")
+                    }
+                    llm::MergeProgress::TensorMerged {
                         name,
-                        dims,
                         element_type,
                         n_elements,
-                    } => log::info!(
-                        "Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)"
-                    ),
-                    QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
-                    QuantizeProgress::TensorQuantized {
-                        name,
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                    "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({history:?})"
-                ),
-                    QuantizeProgress::TensorSkipped { name, size } => {
-                        log::info!("Skipped tensor `{name}` ({size} bytes)")
+                    } => {
+                        log::info!("Merged tensor `{name}` ({n_elements} {element_type} elements)")
+                    }
+                    llm::MergeProgress::Finished { n_tensors } => {
+                        log::info!("Finished merging {n_tensors} tensors")
                     }
-                    QuantizeProgress::Finished {
-                        original_size,
-                        reduced_size,
-                        history,
-                    } => log::info!(
-                        "Finished quantization from {original_size} to {reduced_size} bytes ({history:?})"
-                    ),
                 },
             )
-            .wrap_err("failed to quantize model")
+            .wrap_err("failed to merge models")?;
+            destination.flush()?;
+            write_checksums::<M>(&args.destination)
+        }
+    }
+
+    let mut sources = vec![];
+    let mut architecture = None;
+    for source in &args.sources {
+        let (path, registered) = args.architecture.resolve_model(source)?;
+        sources.push(path);
+        architecture = architecture.or(registered);
+    }
+    architecture
+        .wrap_err("the architecture must be known for merging")?
+        .visit(&mut MergeVisitor(args, sources))
+}
+
+fn pack(args: &cli_args::Pack) -> eyre::Result<()> {
+    struct PackVisitor<'a>(&'a cli_args::Pack, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for PackVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            let stats = llm::pack::<M::Hyperparameters>(&self.1, &mut destination, args.level)
+                .wrap_err("failed to compress model")?;
+            copy_checksums(&self.1, &args.destination)?;
+
+            log::info!(
+                "Compressed {} to {} ({:.1}%)",
+                bytesize::to_string(stats.original_size, false),
+                bytesize::to_string(stats.packed_size, false),
+                100.0 * stats.packed_size as f64 / stats.original_size.max(1) as f64
+            );
+            Ok(())
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    architecture
+        .wrap_err("the architecture must be known for compression")?
+        .visit(&mut PackVisitor(args, source))
+}
+
+fn unpack(args: &cli_args::Unpack) -> eyre::Result<()> {
+    let source = BufReader::new(
+        File::open(&args.source).wrap_err_with(|| format!("could not open {:?}", args.source))?,
+    );
+    let mut destination = BufWriter::new(std::fs::File::create(&args.destination)?);
+    let len = llm::unpack(source, &mut destination).wrap_err("failed to decompress model")?;
+    copy_checksums(&args.source, &args.destination)?;
+
+    log::info!(
+        "Decompressed {} to {:?}",
+        bytesize::to_string(len, false),
+        args.destination
+    );
+    Ok(())
+}
+
+/// Writes the checksums of the tensors of the model at `path` next to it, so that the
+/// model can be verified when it is loaded with `--verify`.
+fn write_checksums<M: llm::KnownModel>(path: &Path) -> eyre::Result<()> {
+    llm::write_checksums::<M::Hyperparameters>(path)
+        .wrap_err_with(|| format!("failed to write checksums for {path:?}"))?;
+    log::info!("Wrote checksums to {:?}", llm::checksums_path(path));
+    Ok(())
+}
+
+/// Copies the checksums stored for the model at `source`, if it has any, to be stored for
+/// `destination`, which holds the same model in another form.
+fn copy_checksums(source: &Path, destination: &Path) -> eyre::Result<()> {
+    let checksums_path = llm::checksums_path(source);
+    if checksums_path.exists() {
+        std::fs::copy(checksums_path, llm::checksums_path(destination))?;
+    }
+    Ok(())
+}
+
+fn split(args: &cli_args::Split) -> eyre::Result<()> {
+    struct SplitVisitor<'a>(&'a cli_args::Split, PathBuf, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for SplitVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let manifest =
+                llm::split::<M::Hyperparameters>(&self.1, &self.2, self.0.max_size.as_u64())
+                    .wrap_err("failed to split model")?;
+
+            for shard in &manifest.shards {
+                log::info!(
+                    "Wrote {:?} ({})",
+                    shard.path,
+                    bytesize::to_string(shard.len, false)
+                );
+            }
+            log::info!(
+                "Split the model into {} shards; load it from {:?}",
+                manifest.shards.len(),
+                self.2
+            );
+            Ok(())
+        }
+    }
+
+    let (source, architecture) = args.architecture.resolve_model(&args.source)?;
+    let outfile = args.outfile.clone().unwrap_or_else(|| {
+        let mut path = source.clone().into_os_string();
+        path.push(".manifest");
+        PathBuf::from(path)
+    });
+    let architecture = architecture
+        .or_else(|| llm::guess_model_architectures(&source).first().copied())
+        .wrap_err("the architecture of the model could not be guessed; specify it with `-a`")?;
+    architecture.visit(&mut SplitVisitor(args, source, outfile))
+}
+
+fn merge_shards(args: &cli_args::MergeShards) -> eyre::Result<()> {
+    let mut destination = BufWriter::new(std::fs::File::create(&args.destination)?);
+    let len = llm::merge_shards(&args.source, &mut destination)
+        .wrap_err_with(|| format!("failed to merge the shards of {:?}", args.source))?;
+
+    // The checksums of a model split by `llm split` are kept with its manifest.
+    copy_checksums(&args.source, &args.destination)?;
+
+    log::info!(
+        "Merged {} into {:?}",
+        bytesize::to_string(len, false),
+        args.destination
+    );
+    Ok(())
+}
+
+fn meta(args: &cli_args::Meta) -> eyre::Result<()> {
+    use llm::ggml_format::gguf;
+
+    match &args.command {
+        cli_args::MetaCommand::Get {
+            model,
+            key,
+            architecture,
+        } => meta_get(model, key.as_deref(), architecture),
+        cli_args::MetaCommand::Set {
+            model,
+            key,
+            value,
+            value_type,
+        } => {
+            let path = resolve_registered_model(model)?;
+            let metadata = llm::read_gguf_metadata(&path)?
+                .wrap_err("only the metadata of GGUF models can be edited")?;
+            let value_type = match (value_type, metadata.get(key)) {
+                (Some(value_type), _) => (*value_type).into(),
+                (None, Some(current)) => current.value_type(),
+                (None, None) => gguf::MetadataValueType::String,
+            };
+            let value = gguf::MetadataValue::parse(value_type, value)
+                .wrap_err_with(|| format!("{value:?} is not a valid {value_type:?} value"))?;
+
+            let (_, rewrite) = llm::set_gguf_metadata(&path, key, value)?;
+            log_header_rewrite(&path, rewrite);
+            Ok(())
+        }
+        cli_args::MetaCommand::Rm { model, key } => {
+            let path = resolve_registered_model(model)?;
+            let (_, rewrite) = llm::remove_gguf_metadata(&path, key)?
+                .wrap_err_with(|| format!("the model has no metadata key `{key}`"))?;
+            log_header_rewrite(&path, rewrite);
+            Ok(())
+        }
+    }
+}
+
+fn meta_get(
+    model: &Path,
+    key: Option<&str>,
+    architecture: &cli_args::ModelArchitecture,
+) -> eyre::Result<()> {
+    use llm::ggml_format::gguf;
+
+    struct MetaVisitor<'a>(Option<&'a str>, PathBuf);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MetaVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let model_file = llm::ModelFile::<M::Hyperparameters>::open(
+                &self.1,
+                llm::TokenizerSource::Embedded,
+            )?;
+            let hyperparameters =
+                llm::DescribeHyperparameters::describe(&model_file.hyperparameters);
+
+            match self.0 {
+                Some(key) => {
+                    let value = hyperparameters
+                        .get(key)
+                        .wrap_err_with(|| format!("the model has no hyperparameter `{key}`"))?;
+                    println!("{value}");
+                }
+                None => {
+                    for (key, value) in &hyperparameters.metadata {
+                        println!("{key}: {value}");
+                    }
+                }
+            }
+            Ok(())
+        }
+    }
+
+    let (path, architecture) = architecture.resolve_model(model)?;
+    let metadata = llm::read_gguf_metadata(&path)?;
+    if let (Some(metadata), Some(key)) = (&metadata, key) {
+        if let Some(value) = metadata.get(key) {
+            println!("{value}");
+            return Ok(());
+        }
+    }
+
+    // GGUF models written by other tools only have the standard keys of their architecture,
+    // which are listed with the rest of the metadata below. They are only read as
+    // hyperparameters if the architecture is given, as it cannot be guessed from the names
+    // of their tensors.
+    if architecture.is_some()
+        || metadata.as_ref().map_or(true, |metadata| {
+            metadata.get(gguf::HYPERPARAMETERS_KEY).is_some()
+        })
+    {
+        let architecture = architecture
+            .or_else(|| llm::guess_model_architectures(&path).first().copied())
+            .wrap_err("the architecture of the model could not be guessed; specify it with `-a`")?;
+        architecture.visit(&mut MetaVisitor(key, path))?;
+    } else if let Some(key) = key {
+        eyre::bail!("the model has no metadata key `{key}`");
+    }
+
+    if let (Some(metadata), None) = (&metadata, key) {
+        for (key, value) in metadata.iter() {
+            match value.as_array() {
+                // The hyperparameters are printed above, and the vocabulary is too long
+                // to be useful here.
+                Some(values) => println!("{key}: [{} values]", values.len()),
+                None => println!("{key}: {value}"),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Resolves `model` as the name of a registered model, or a path if it is not one.
+fn resolve_registered_model(model: &Path) -> eyre::Result<PathBuf> {
+    Ok(match registry::resolve(model)? {
+        Some((path, _)) => path,
+        None => model.to_owned(),
+    })
+}
+
+fn log_header_rewrite(path: &Path, rewrite: llm::HeaderRewrite) {
+    match rewrite {
+        llm::HeaderRewrite::InPlace => log::info!("Rewrote the header of {path:?} in place"),
+        llm::HeaderRewrite::WholeFile => {
+            log::info!("Rewrote {path:?}, as its new header does not fit before its tensor data")
+        }
+    }
+}
+
+fn make_test_model(args: &cli_args::MakeTestModel) -> eyre::Result<()> {
+    struct MakeTestModelVisitor<'a>(&'a cli_args::MakeTestModel);
+    impl llm::ModelArchitectureVisitor<eyre::Result<()>> for MakeTestModelVisitor<'_> {
+        fn visit<M: llm::KnownModel>(&mut self) -> eyre::Result<()> {
+            let args = self.0;
+
+            let mut destination: BufWriter<File> =
+                BufWriter::new(std::fs::File::create(&args.destination)?);
+            llm::write_test_model::<M, _>(&mut destination, args.container_type.into(), args.seed)
+                .wrap_err("failed to write test model")?;
+
+            log::info!("Wrote test model to {:?}", args.destination);
+            Ok(())
         }
     }
 
     args.architecture
         .model_architecture
-        .wrap_err("the architecture must be known for quantization")?
-        .visit(&mut QuantizeVisitor(args))
+        .wrap_err("the architecture must be specified for making a test model")?
+        .visit(&mut MakeTestModelVisitor(args))
+}
+
+fn log_quantize_progress(progress: llm::QuantizeProgress) {
+    use llm::QuantizeProgress;
+
+    match progress {
+        QuantizeProgress::HyperparametersLoaded => log::info!("Loaded hyperparameters"),
+        QuantizeProgress::TensorLoading {
+            name,
+            dims,
+            element_type,
+            n_elements,
+        } => {
+            log::info!("Loading tensor `{name}` ({n_elements} ({dims:?}) {element_type} elements)")
+        }
+        QuantizeProgress::TensorQuantizing { name } => log::info!("Quantizing tensor `{name}`"),
+        QuantizeProgress::TensorQuantized {
+            name,
+            original_size,
+            reduced_size,
+            history,
+        } => log::info!(
+            "Quantized tensor `{name}` from {original_size} to {reduced_size} bytes ({history:?})"
+        ),
+        QuantizeProgress::LoraApplied { name, source } => {
+            log::info!("Patched tensor `{name}` via LoRA from {source:?}")
+        }
+        QuantizeProgress::TensorSkipped { name, size } => {
+            log::info!("Skipped tensor `{name}` ({size} bytes)")
+        }
+        QuantizeProgress::Finished {
+            original_size,
+            reduced_size,
+            history,
+        } => log::info!(
+            "Finished quantization from {original_size} to {reduced_size} bytes ({history:?})"
+        ),
+    }
 }
 
 fn load_prompt_file_with_prompt(
@@ -0,0 +1,169 @@
+//! Formats generated text for the terminal: wraps it at word boundaries, and styles the
+//! echoed prompt differently from the generated text.
+
+use std::io::Write;
+
+use is_terminal::IsTerminal;
+
+use crate::cli_args::{self, OutputColor};
+
+/// The width to wrap at when it is not given, and the terminal's cannot be determined.
+const DEFAULT_WIDTH: usize = 80;
+
+const STYLE_PROMPT: &str = "\x1b[2m";
+const STYLE_RESET: &str = "\x1b[0m";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Style {
+    /// Text that was given to the model.
+    Prompt,
+    /// Text that the model generated.
+    Generated,
+}
+
+/// Prints text as it is generated, keeping track of the current column so that it can
+/// wrap words that do not fit on the line.
+pub struct OutputFormatter {
+    width: Option<usize>,
+    color: bool,
+    style: Style,
+    column: usize,
+    /// The word that is being printed, which is held back until it is complete so that
+    /// it can be moved to the next line if it does not fit. Includes escape codes.
+    word: String,
+    /// The number of visible characters in `word`.
+    word_len: usize,
+}
+impl OutputFormatter {
+    pub fn new(args: &cli_args::Output) -> Self {
+        let width = match args.wrap_width {
+            Some(width) => Some(width.max(1)),
+            None if args.wrap => Some(
+                terminal_size::terminal_size()
+                    .map_or(DEFAULT_WIDTH, |(terminal_size::Width(w), _)| w as usize),
+            ),
+            None => None,
+        };
+        let color = match args.color {
+            OutputColor::Always => true,
+            OutputColor::Never => false,
+            OutputColor::Auto => {
+                std::io::stdout().is_terminal() && std::env::var_os("NO_COLOR").is_none()
+            }
+        };
+
+        Self {
+            width,
+            color,
+            style: Style::Generated,
+            column: 0,
+            word: String::new(),
+            word_len: 0,
+        }
+    }
+
+    /// Prints `text` in `style`.
+    pub fn print(&mut self, style: Style, text: &str) {
+        if style != self.style {
+            self.style = style;
+            if self.color {
+                self.word.push_str(match style {
+                    Style::Prompt => STYLE_PROMPT,
+                    Style::Generated => STYLE_RESET,
+                });
+            }
+        }
+
+        let Some(width) = self.width else {
+            self.word.push_str(text);
+            self.column = match text.rfind('\n') {
+                Some(i) => text[i + 1..].chars().count(),
+                None => self.column + text.chars().count(),
+            };
+            self.flush();
+            return;
+        };
+
+        for c in text.chars() {
+            if c == '\n' {
+                self.end_word(width);
+                self.word.push('\n');
+                self.column = 0;
+            } else if c.is_whitespace() {
+                self.end_word(width);
+                // Whitespace at the end of a line is replaced by the line break.
+                if self.column < width {
+                    self.word.push(c);
+                    self.column += 1;
+                }
+            } else {
+                // Words that do not fit on a line of their own are broken.
+                if self.word_len == width {
+                    self.end_word(width);
+                    self.word.push('\n');
+                    self.column = 0;
+                }
+                self.word.push(c);
+                self.word_len += 1;
+            }
+        }
+        self.flush();
+    }
+
+    /// Finishes the output: prints the rest of it, resets its style, and ends the line.
+    pub fn finish(&mut self) {
+        if let Some(width) = self.width {
+            self.end_word(width);
+        }
+        if self.color && self.style != Style::Generated {
+            self.word.push_str(STYLE_RESET);
+        }
+        self.style = Style::Generated;
+        if self.column > 0 {
+            self.word.push('\n');
+            self.column = 0;
+        }
+        self.flush();
+    }
+
+    /// Moves the pending word to the next line if it does not fit on this one.
+    fn end_word(&mut self, width: usize) {
+        if self.word_len == 0 {
+            return;
+        }
+        if self.column > 0 && self.column + self.word_len > width {
+            let start = self.word.len() - self.word_byte_len();
+            self.word.insert(start, '\n');
+            self.column = 0;
+        }
+        self.column += self.word_len;
+        self.word_len = 0;
+    }
+
+    /// The length in bytes of the pending word, excluding the text before it that is
+    /// waiting to be printed.
+    fn word_byte_len(&self) -> usize {
+        // Escape codes within the word are kept with it; the word starts after the
+        // last whitespace.
+        self.word
+            .rfind(char::is_whitespace)
+            .map_or(self.word.len(), |i| self.word.len() - i - 1)
+    }
+
+    /// Prints everything before the pending word.
+    fn flush(&mut self) {
+        let pending = if self.width.is_some() && self.word_len > 0 {
+            self.word_byte_len()
+        } else {
+            0
+        };
+        let printed = self.word.len() - pending;
+        if printed == 0 {
+            return;
+        }
+        let mut stdout = std::io::stdout().lock();
+        stdout.write_all(&self.word.as_bytes()[..printed]).unwrap();
+        stdout.flush().unwrap();
+        self.word.drain(..printed);
+    }
+}
@@ -0,0 +1,373 @@
+//! A registry of local models, so that they can be referred to by short names instead
+//! of paths, and the commands that manage it (`llm models ...`).
+//!
+//! The registry lives in a models directory (`LLM_MODELS_DIR`, or the `llm/models`
+//! directory in the platform's local data directory). Registered models can live
+//! anywhere; model files in the models directory are listed even if they have not been
+//! registered.
+
+use std::{
+    collections::BTreeMap,
+    fs::{self, File},
+    io::BufReader,
+    path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use color_eyre::eyre::{self, Context, ContextCompat};
+use serde::{Deserialize, Serialize};
+
+use crate::cli_args::{self, ModelsCommand};
+
+const REGISTRY_FILE_NAME: &str = "registry.json";
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+#[derive(Serialize, Deserialize, Default)]
+struct Registry {
+    models: BTreeMap<String, RegisteredModel>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct RegisteredModel {
+    path: PathBuf,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    architecture: Option<String>,
+    /// Seconds since the Unix epoch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    last_used: Option<u64>,
+    /// Seconds since the Unix epoch. Models registered before this was recorded have none.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    added_at: Option<u64>,
+}
+
+/// The directory that contains the registry, and the models that are managed by it.
+pub fn models_dir() -> eyre::Result<PathBuf> {
+    match std::env::var_os("LLM_MODELS_DIR") {
+        Some(dir) => Ok(PathBuf::from(dir)),
+        None => Ok(dirs::data_local_dir()
+            .wrap_err("could not determine the local data directory; set LLM_MODELS_DIR")?
+            .join("llm")
+            .join("models")),
+    }
+}
+
+impl Registry {
+    fn load(dir: &Path) -> eyre::Result<Self> {
+        let path = dir.join(REGISTRY_FILE_NAME);
+        if !path.is_file() {
+            return Ok(Self::default());
+        }
+        let contents = fs::read_to_string(&path)?;
+        serde_json::from_str(&contents).wrap_err_with(|| format!("could not parse {path:?}"))
+    }
+
+    fn save(&self, dir: &Path) -> eyre::Result<()> {
+        fs::create_dir_all(dir)?;
+        let path = dir.join(REGISTRY_FILE_NAME);
+        fs::write(&path, serde_json::to_string_pretty(self)? + "\n")
+            .wrap_err_with(|| format!("could not write {path:?}"))
+    }
+}
+
+/// Resolves `model` to a path and the architecture it was registered with, if it is the
+/// name of a registered model and not an existing path, and records that it was used.
+pub fn resolve(model: &Path) -> eyre::Result<Option<(PathBuf, Option<llm::ModelArchitecture>)>> {
+    if model.exists() {
+        return Ok(None);
+    }
+    let Some(name) = model.to_str() else {
+        return Ok(None);
+    };
+
+    let dir = models_dir()?;
+    let mut registry = Registry::load(&dir)?;
+    let Some(entry) = registry.models.get_mut(name) else {
+        return Ok(None);
+    };
+
+    let architecture = entry
+        .architecture
+        .as_deref()
+        .map(|a| a.parse::<llm::ModelArchitecture>())
+        .transpose()?;
+    let path = entry.path.clone();
+    entry.last_used = Some(now());
+    registry.save(&dir)?;
+
+    log::info!("Using registered model {name:?} at {path:?}");
+    Ok(Some((path, architecture)))
+}
+
+pub fn models(args: &cli_args::Models) -> eyre::Result<()> {
+    let dir = models_dir()?;
+    match &args.command {
+        ModelsCommand::List => list(&dir),
+        ModelsCommand::Add {
+            name,
+            path,
+            architecture,
+        } => add(&dir, name, path, architecture.model_architecture),
+        ModelsCommand::Rm { name, keep_file } => {
+            let mut registry = Registry::load(&dir)?;
+            remove(&dir, &mut registry, name, *keep_file)?;
+            registry.save(&dir)
+        }
+        ModelsCommand::Prune {
+            unused_for_days,
+            dry_run,
+        } => prune(&dir, *unused_for_days, *dry_run),
+    }
+}
+
+fn list(dir: &Path) -> eyre::Result<()> {
+    let registry = Registry::load(dir)?;
+
+    let mut rows: Vec<_> = registry
+        .models
+        .iter()
+        .map(|(name, entry)| {
+            (
+                name.clone(),
+                entry.path.clone(),
+                entry.architecture.clone(),
+                entry.last_used,
+            )
+        })
+        .collect();
+
+    // Model files that were put in the models directory without being registered.
+    if dir.is_dir() {
+        for file in fs::read_dir(dir)? {
+            let path = file?.path();
+            let registered = registry.models.values().any(|m| same_file(&m.path, &path));
+            if registered || !path.is_file() || container_type(&path).is_none() {
+                continue;
+            }
+            let name = path.file_stem().unwrap_or_default().to_string_lossy();
+            rows.push((name.into_owned(), path, None, None));
+        }
+    }
+
+    if rows.is_empty() {
+        println!("No models in {dir:?}. Register one with `llm models add <name> <path>`.");
+        return Ok(());
+    }
+
+    println!(
+        "{:<24} {:<10} {:<8} {:>10} {:<14} path",
+        "name", "arch", "format", "size", "last used"
+    );
+    for (name, path, architecture, last_used) in rows {
+        let (format, size) = match fs::metadata(&path) {
+            Ok(metadata) => (
+                container_type(&path).unwrap_or_else(|| "unknown".to_string()),
+                bytesize::to_string(metadata.len(), false),
+            ),
+            Err(_) => ("missing".to_string(), "-".to_string()),
+        };
+        println!(
+            "{:<24} {:<10} {:<8} {:>10} {:<14} {}",
+            name,
+            architecture.as_deref().unwrap_or("?"),
+            format,
+            size,
+            last_used.map_or("never".to_string(), format_last_used),
+            path.display()
+        );
+    }
+
+    Ok(())
+}
+
+fn add(
+    dir: &Path,
+    name: &str,
+    path: &Path,
+    architecture: Option<llm::ModelArchitecture>,
+) -> eyre::Result<()> {
+    let path = path
+        .canonicalize()
+        .wrap_err_with(|| format!("could not find the model at {path:?}"))?;
+    eyre::ensure!(
+        container_type(&path).is_some(),
+        "{path:?} is not a GGML model"
+    );
+
+    let mut registry = Registry::load(dir)?;
+    let previous = registry.models.insert(
+        name.to_owned(),
+        RegisteredModel {
+            path: path.clone(),
+            architecture: architecture.map(|a| a.name().to_string()),
+            last_used: None,
+            added_at: Some(now()),
+        },
+    );
+    registry.save(dir)?;
+
+    match previous {
+        Some(previous) => println!("Replaced {name:?} ({:?}) with {path:?}", previous.path),
+        None => println!("Registered {path:?} as {name:?}"),
+    }
+    Ok(())
+}
+
+/// Removes `name` from the registry. Its file is deleted if it is in the models directory,
+/// unless `keep_file` is set; files elsewhere were registered by the user, and are kept.
+fn remove(dir: &Path, registry: &mut Registry, name: &str, keep_file: bool) -> eyre::Result<()> {
+    let entry = registry
+        .models
+        .remove(name)
+        .wrap_err_with(|| format!("there is no model named {name:?}"))?;
+
+    let managed = entry
+        .path
+        .parent()
+        .map_or(false, |parent| same_file(parent, dir));
+    if managed && !keep_file && entry.path.exists() {
+        fs::remove_file(&entry.path)
+            .wrap_err_with(|| format!("could not delete {:?}", entry.path))?;
+        println!("Removed {name:?} and deleted {:?}", entry.path);
+    } else {
+        println!("Removed {name:?}; {:?} was kept", entry.path);
+    }
+    Ok(())
+}
+
+/// Removes models whose files no longer exist, and, if `unused_for_days` is set, models
+/// that have not been used for that long. Models that were never used count from when
+/// they were added; those registered before that was recorded are only removed if their
+/// files are missing.
+fn prune(dir: &Path, unused_for_days: Option<u64>, dry_run: bool) -> eyre::Result<()> {
+    let mut registry = Registry::load(dir)?;
+    let cutoff = unused_for_days.map(|days| now().saturating_sub(days * SECONDS_PER_DAY));
+
+    let stale: Vec<String> = registry
+        .models
+        .iter()
+        .filter(|(_, entry)| {
+            let unused = cutoff
+                .zip(entry.last_used.or(entry.added_at))
+                .map_or(false, |(cutoff, last_used)| last_used < cutoff);
+            !entry.path.exists() || unused
+        })
+        .map(|(name, _)| name.clone())
+        .collect();
+
+    if stale.is_empty() {
+        println!("Nothing to prune");
+        return Ok(());
+    }
+    for name in &stale {
+        if dry_run {
+            println!("Would remove {name:?} ({:?})", registry.models[name].path);
+        } else {
+            remove(dir, &mut registry, name, false)?;
+        }
+    }
+    if !dry_run {
+        registry.save(dir)?;
+    }
+    Ok(())
+}
+
+/// The container type of the model at `path`, if it is a GGML model.
+fn container_type(path: &Path) -> Option<String> {
+    let mut reader = BufReader::new(File::open(path).ok()?);
+    let container_type = llm::ContainerType::read::<std::convert::Infallible>(&mut reader).ok()?;
+    Some(match container_type {
+        llm::ContainerType::Ggml => "GGML".to_string(),
+        llm::ContainerType::Ggmf(version) => format!("GGMF v{version}"),
+        llm::ContainerType::Ggjt(version) => format!("GGJT v{version}"),
+        llm::ContainerType::Ggla(version) => format!("GGLA v{version}"),
+        llm::ContainerType::Gguf(version) => format!("GGUF v{version}"),
+    })
+}
+
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (a.canonicalize(), b.canonicalize()) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+fn format_last_used(last_used: u64) -> String {
+    let elapsed = now().saturating_sub(last_used);
+    match elapsed {
+        0..=59 => "just now".to_string(),
+        60..=3599 => format!("{} min ago", elapsed / 60),
+        3600..=86399 => format!("{} h ago", elapsed / 3600),
+        _ => format!("{} days ago", elapsed / SECONDS_PER_DAY),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_prune() {
+        let dir = std::env::temp_dir().join(format!("llm-registry-prune-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+
+        let long_ago = now() - 60 * SECONDS_PER_DAY;
+        let mut registry = Registry::default();
+        let mut register = |name: &str, last_used, added_at| {
+            let path = dir.join(format!("{name}.bin"));
+            fs::write(&path, b"model").unwrap();
+            registry.models.insert(
+                name.to_owned(),
+                RegisteredModel {
+                    path,
+                    architecture: None,
+                    last_used,
+                    added_at,
+                },
+            );
+        };
+        register("used-recently", Some(now()), Some(long_ago));
+        register("used-long-ago", Some(long_ago), Some(long_ago));
+        register("added-recently", None, Some(now()));
+        register("added-long-ago", None, Some(long_ago));
+        register("added-before-tracking", None, None);
+        registry.models.insert(
+            "missing".to_owned(),
+            RegisteredModel {
+                path: dir.join("missing.bin"),
+                architecture: None,
+                last_used: Some(now()),
+                added_at: Some(now()),
+            },
+        );
+        registry.save(&dir).unwrap();
+
+        // A dry run changes nothing.
+        prune(&dir, Some(30), true).unwrap();
+        assert_eq!(Registry::load(&dir).unwrap().models.len(), 6);
+
+        prune(&dir, Some(30), false).unwrap();
+        let names: Vec<_> = Registry::load(&dir).unwrap().models.into_keys().collect();
+        assert_eq!(
+            names,
+            ["added-before-tracking", "added-recently", "used-recently"]
+        );
+        assert!(dir.join("added-recently.bin").exists());
+        assert!(dir.join("added-before-tracking.bin").exists());
+        assert!(!dir.join("used-long-ago.bin").exists());
+        assert!(!dir.join("added-long-ago.bin").exists());
+
+        // Without a cutoff, only models whose files are missing are removed.
+        fs::remove_file(dir.join("used-recently.bin")).unwrap();
+        prune(&dir, None, false).unwrap();
+        let names: Vec<_> = Registry::load(&dir).unwrap().models.into_keys().collect();
+        assert_eq!(names, ["added-before-tracking", "added-recently"]);
+
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
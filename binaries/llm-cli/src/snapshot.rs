@@ -1,11 +1,15 @@
 use std::{
     error::Error,
-    fs::File,
-    io::{BufReader, BufWriter},
-    path::Path,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
 };
 
-use llm::{InferenceSession, InferenceSessionConfig, Model};
+use bincode::Options;
+use llm::{
+    ControlVector, EvaluatedLayers, InferenceSession, InferenceSessionConfig, InferenceSnapshot,
+    InferenceSnapshotRef, Model,
+};
 
 use zstd::{
     stream::{read::Decoder, write::Encoder},
@@ -14,20 +18,28 @@ use zstd::{
 
 const SNAPSHOT_COMPRESSION_LEVEL: CompressionLevel = 1;
 
+/// The magic number at the start of a session file. It is followed by the CRC32 checksum
+/// of the rest of the file, which is the compressed snapshot. Session files written
+/// before the checksum was added start with the compressed snapshot instead.
+const SNAPSHOT_MAGIC: [u8; 4] = *b"llms";
+
+/// The largest snapshot that is read, once decompressed. This is larger than the memory
+/// of a session with a context of 32,768 tokens for a 70B model, with an `f16` memory.
+const MAX_SNAPSHOT_SIZE: u64 = 128 << 30;
+
 /// Read or create a session
 pub fn read_or_create_session(
     model: &dyn Model,
     persist_session: Option<&Path>,
     load_session: Option<&Path>,
     inference_session_config: InferenceSessionConfig,
+    control_vectors: &[ControlVector],
+    evaluated_layers: EvaluatedLayers,
 ) -> (InferenceSession, bool) {
     fn load(model: &dyn Model, path: &Path) -> InferenceSession {
         let file = unwrap_or_exit(File::open(path), || format!("Could not open file {path:?}"));
-        let decoder = unwrap_or_exit(Decoder::new(BufReader::new(file)), || {
-            format!("Could not create decoder for {path:?}")
-        });
-        let snapshot = unwrap_or_exit(bincode::deserialize_from(decoder), || {
-            format!("Could not deserialize inference session from {path:?}")
+        let snapshot = unwrap_or_exit(read_snapshot(file), || {
+            format!("Could not read inference session from {path:?}")
         });
         let session = unwrap_or_exit(InferenceSession::from_snapshot(snapshot, model), || {
             format!("Could not convert snapshot from {path:?} to session")
@@ -36,31 +48,124 @@ pub fn read_or_create_session(
         session
     }
 
-    match (persist_session, load_session) {
+    let (mut session, session_loaded) = match (persist_session, load_session) {
         (Some(path), _) if path.exists() => (load(model, path), true),
         (_, Some(path)) => (load(model, path), true),
         _ => (model.start_session(inference_session_config), false),
-    }
+    };
+    unwrap_or_exit(session.set_control_vectors(control_vectors), || {
+        "Could not apply control vectors".to_string()
+    });
+    session.set_evaluated_layers(evaluated_layers);
+    (session, session_loaded)
 }
 
 /// Write the session
-pub fn write_session(mut session: InferenceSession, path: &Path) {
-    // SAFETY: the session is consumed here, so nothing else can access it.
+///
+/// The session is written to a temporary file next to `path`, which then replaces the
+/// file at `path`, so that a write that fails or is interrupted never leaves a truncated
+/// session behind.
+pub fn write_session(session: &mut InferenceSession, path: &Path) {
+    // SAFETY: the session is borrowed exclusively here, so nothing else can access it.
     let snapshot = unsafe { session.get_snapshot() };
-    let file = unwrap_or_exit(File::create(path), || {
-        format!("Could not create file {path:?}")
+    let temp_path = temp_path(path);
+    let result = write_snapshot(&snapshot, &temp_path).and_then(|()| fs::rename(&temp_path, path));
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    unwrap_or_exit(result, || {
+        format!("Could not write inference session to {path:?}")
     });
-    let encoder = unwrap_or_exit(
-        Encoder::new(BufWriter::new(file), SNAPSHOT_COMPRESSION_LEVEL),
-        || format!("Could not create encoder for {path:?}"),
-    );
-    unwrap_or_exit(
-        bincode::serialize_into(encoder.auto_finish(), &snapshot),
-        || format!("Could not serialize inference session to {path:?}"),
-    );
     log::info!("Successfully wrote session to {path:?}");
 }
 
+fn temp_path(path: &Path) -> PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_owned();
+    file_name.push(".tmp");
+    path.with_file_name(file_name)
+}
+
+fn write_snapshot(snapshot: &InferenceSnapshotRef<'_>, path: &Path) -> io::Result<()> {
+    let mut file = File::create(path)?;
+    file.write_all(&SNAPSHOT_MAGIC)?;
+    // The checksum is written once the snapshot has been.
+    file.write_all(&[0; 4])?;
+
+    let mut writer = ChecksumWriter {
+        inner: BufWriter::new(file),
+        hasher: crc32fast::Hasher::new(),
+    };
+    let mut encoder = Encoder::new(&mut writer, SNAPSHOT_COMPRESSION_LEVEL)?;
+    bincode::serialize_into(&mut encoder, snapshot).map_err(|err| bincode_error(*err))?;
+    encoder.finish()?;
+
+    let ChecksumWriter { inner, hasher } = writer;
+    let mut file = inner.into_inner().map_err(|err| err.into_error())?;
+    file.seek(SeekFrom::Start(SNAPSHOT_MAGIC.len() as u64))?;
+    file.write_all(&hasher.finalize().to_le_bytes())?;
+    file.sync_all()
+}
+
+fn read_snapshot(file: File) -> io::Result<InferenceSnapshot> {
+    let mut reader = BufReader::new(file);
+    let mut magic = [0; 4];
+    if reader.read_exact(&mut magic).is_err() || magic != SNAPSHOT_MAGIC {
+        // The session was written before session files had checksums.
+        reader.seek(SeekFrom::Start(0))?;
+        return deserialize_snapshot(reader);
+    }
+    let mut checksum = [0; 4];
+    reader.read_exact(&mut checksum)?;
+
+    // Verify the checksum before decompressing anything, so that a corrupted session is
+    // rejected before its contents are trusted.
+    let mut compressed = vec![];
+    reader.read_to_end(&mut compressed)?;
+    if crc32fast::hash(&compressed) != u32::from_le_bytes(checksum) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "the session file is corrupted: its checksum does not match",
+        ));
+    }
+    deserialize_snapshot(compressed.as_slice())
+}
+
+/// Decompresses and deserializes a snapshot, reading at most [MAX_SNAPSHOT_SIZE] bytes of it,
+/// so that a length in the file cannot make it allocate without bound.
+fn deserialize_snapshot(reader: impl Read) -> io::Result<InferenceSnapshot> {
+    bincode::options()
+        // The options of `bincode::serialize_into`, which wrote the snapshot.
+        .with_fixint_encoding()
+        .allow_trailing_bytes()
+        .with_limit(MAX_SNAPSHOT_SIZE)
+        .deserialize_from(Decoder::new(reader)?)
+        .map_err(|err| bincode_error(*err))
+}
+
+fn bincode_error(err: bincode::ErrorKind) -> io::Error {
+    match err {
+        bincode::ErrorKind::Io(err) => err,
+        err => io::Error::new(io::ErrorKind::InvalidData, err),
+    }
+}
+
+/// Computes the checksum of the data written to the inner writer.
+struct ChecksumWriter<W> {
+    inner: W,
+    hasher: crc32fast::Hasher,
+}
+impl<W: Write> Write for ChecksumWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let n = self.inner.write(buf)?;
+        self.hasher.update(&buf[..n]);
+        Ok(n)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
 fn unwrap_or_exit<T, E: Error>(result: Result<T, E>, error_message: impl Fn() -> String) -> T {
     match result {
         Ok(t) => t,
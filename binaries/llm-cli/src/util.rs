@@ -1,10 +1,54 @@
-use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
 
-pub fn process_prompt(raw_prompt: &str, prompt: &str) -> String {
-    raw_prompt.replace("{{PROMPT}}", prompt)
+use color_eyre::eyre;
+
+/// Whether the CLI logs structured JSON events instead of human-readable text.
+static STRUCTURED_LOGS: AtomicBool = AtomicBool::new(false);
+
+pub fn set_structured_logs(enabled: bool) {
+    STRUCTURED_LOGS.store(enabled, Ordering::Relaxed);
 }
 
-pub fn print_token(t: String) {
-    print!("{t}");
-    std::io::stdout().flush().unwrap();
+/// Whether progress and statistics should be logged as structured events, rather than
+/// shown with spinners and printed as text.
+pub fn structured_logs() -> bool {
+    STRUCTURED_LOGS.load(Ordering::Relaxed)
+}
+
+/// Set when the CLI is interrupted while the model is busy, so that it stops at the next
+/// token.
+static INTERRUPTED: AtomicBool = AtomicBool::new(false);
+/// Whether the model is busy, so that an interrupt stops it instead of exiting.
+static BUSY: AtomicBool = AtomicBool::new(false);
+
+/// Makes Ctrl-C (or SIGTERM) stop the model at the next token while it is
+/// [busy](set_busy), instead of exiting, so that the tokens evaluated until then stay in
+/// the session. Interrupting again before the model stops exits, in case it does not, as
+/// does an interrupt while the model is not busy.
+///
+/// While a line is being read, Ctrl-C is handled by the line editor instead.
+pub fn handle_interrupts() -> eyre::Result<()> {
+    ctrlc::set_handler(|| {
+        if !BUSY.load(Ordering::SeqCst) || INTERRUPTED.swap(true, Ordering::SeqCst) {
+            std::process::exit(130);
+        }
+    })?;
+    Ok(())
+}
+
+/// Marks the model as busy or not. Starting to be busy clears any previous interrupt.
+pub fn set_busy(busy: bool) {
+    if busy {
+        INTERRUPTED.store(false, Ordering::SeqCst);
+    }
+    BUSY.store(busy, Ordering::SeqCst);
+}
+
+/// Whether the model was interrupted since it last became busy.
+pub fn interrupted() -> bool {
+    INTERRUPTED.load(Ordering::SeqCst)
+}
+
+pub fn process_prompt(raw_prompt: &str, prompt: &str) -> String {
+    raw_prompt.replace("{{PROMPT}}", prompt)
 }
@@ -0,0 +1,175 @@
+//! Tests that a model generates exactly the same tokens as when its golden outputs
+//! were recorded, and generates the tiny models these tests are usually run on.
+//!
+//! See [crate::TestCase::Golden].
+
+use std::{
+    collections::BTreeMap,
+    convert::Infallible,
+    fs::{self, File},
+    io::BufWriter,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use llm::{
+    ggml_format::SaveContainerType, InferenceError, InferenceFeedback, InferenceParameters,
+    InferenceSessionConfig, Model, ModelArchitecture, ModelArchitectureVisitor, TokenId,
+};
+use serde::{Deserialize, Serialize};
+
+use crate::{inference::DeterministicSampler, ModelConfig, TestCaseReport, TestCaseReportMeta};
+
+/// A tiny model with random weights that is generated by the test runner instead of
+/// being downloaded. See [llm::write_test_model].
+#[derive(Deserialize, Debug, Clone)]
+pub struct GeneratedModel {
+    /// The seed for the weights of the model.
+    seed: u64,
+}
+
+impl GeneratedModel {
+    /// Writes a model of `architecture` to `path`. The same model is written on every machine.
+    pub fn generate(&self, architecture: ModelArchitecture, path: &Path) -> anyhow::Result<()> {
+        struct GenerateVisitor<'a>(&'a Path, u64);
+        impl ModelArchitectureVisitor<anyhow::Result<()>> for GenerateVisitor<'_> {
+            fn visit<M: llm::KnownModel + 'static>(&mut self) -> anyhow::Result<()> {
+                let mut writer = BufWriter::new(File::create(self.0)?);
+                llm::write_test_model::<M, _>(&mut writer, SaveContainerType::GgjtV3, self.1)?;
+                Ok(())
+            }
+        }
+
+        architecture.visit(&mut GenerateVisitor(path, self.seed))?;
+        log::info!("Generated test model at {}", path.display());
+        Ok(())
+    }
+}
+
+/// The recorded outputs of the golden test cases of one config, keyed by input.
+pub struct GoldenOutputs {
+    path: PathBuf,
+    outputs: BTreeMap<String, Vec<TokenId>>,
+}
+
+impl GoldenOutputs {
+    /// Loads the golden outputs from `path`, if they have been recorded.
+    pub fn load(path: PathBuf) -> anyhow::Result<Self> {
+        let outputs = if path.is_file() {
+            serde_json::from_str(&fs::read_to_string(&path)?)?
+        } else {
+            BTreeMap::new()
+        };
+
+        Ok(Self { path, outputs })
+    }
+
+    /// Writes the golden outputs back to disk.
+    pub fn save(&self) -> anyhow::Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(
+            &self.path,
+            serde_json::to_string_pretty(&self.outputs)? + "\n",
+        )?;
+        log::info!("Saved golden outputs to {}", self.path.display());
+        Ok(())
+    }
+}
+
+/// Tests that greedily generating from `input` reproduces the golden output for it,
+/// or records the output if the runner was started with `--bless`.
+pub(crate) fn can_reproduce(
+    model: &dyn Model,
+    model_config: &ModelConfig,
+    golden: &mut GoldenOutputs,
+    input: &str,
+    maximum_token_count: usize,
+) -> TestCaseReport {
+    let mut report = GoldenReport {
+        input: input.to_owned(),
+        expected_tokens: golden.outputs.get(input).cloned(),
+        actual_tokens: vec![],
+    };
+
+    let config = match InferenceSessionConfig::builder()
+        .n_threads(model_config.threads)
+        .build()
+    {
+        Ok(config) => config,
+        Err(err) => return report.failure(&err.to_string()),
+    };
+    let mut session = model.start_session(config);
+    if let Err(err) = session.feed_prompt(model, input, &mut Default::default(), |_| {
+        Ok::<_, Infallible>(InferenceFeedback::Continue)
+    }) {
+        return report.failure(&err.to_string());
+    }
+    let prompt_len = session.tokens().len();
+
+    let parameters =
+        InferenceParameters::new(Arc::new(Mutex::new(DeterministicSampler::default())));
+    let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+    for _ in 0..maximum_token_count {
+        match session.infer_next_token(model, &parameters, &mut Default::default(), &mut rng) {
+            Ok(_) => {}
+            Err(InferenceError::EndOfText) => break,
+            Err(err) => return report.failure(&err.to_string()),
+        }
+    }
+    report.actual_tokens = session.tokens()[prompt_len..].to_vec();
+
+    if model_config.bless {
+        golden
+            .outputs
+            .insert(input.to_owned(), report.actual_tokens.clone());
+        log::info!("Recorded golden output for {input:?}");
+        return report.success();
+    }
+
+    match &report.expected_tokens {
+        None => report.failure(&format!(
+            "No golden output has been recorded for {input:?}; run with `--bless` to record it."
+        )),
+        Some(expected) if *expected != report.actual_tokens => {
+            let position = expected
+                .iter()
+                .zip(&report.actual_tokens)
+                .take_while(|(a, b)| a == b)
+                .count();
+            report.failure(&format!(
+                "The generated tokens diverged from the golden output at token {position}."
+            ))
+        }
+        Some(_) => {
+            log::info!("`can_reproduce` test passed!");
+            report.success()
+        }
+    }
+}
+
+#[derive(Serialize)]
+pub struct GoldenReport {
+    input: String,
+    expected_tokens: Option<Vec<TokenId>>,
+    actual_tokens: Vec<TokenId>,
+}
+
+impl GoldenReport {
+    fn failure(self, msg: &str) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Error {
+                error: msg.to_owned(),
+            },
+            report: crate::TestCaseReportInner::Golden(self),
+        }
+    }
+
+    fn success(self) -> TestCaseReport {
+        TestCaseReport {
+            meta: TestCaseReportMeta::Success,
+            report: crate::TestCaseReportInner::Golden(self),
+        }
+    }
+}
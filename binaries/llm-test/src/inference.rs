@@ -2,10 +2,7 @@
 //!
 //! See [crate::TestCase::Inference].
 
-use std::{
-    convert::Infallible,
-    sync::{Arc, Mutex},
-};
+use std::sync::{Arc, Mutex};
 
 use llm::{InferenceSessionConfig, InferenceStats, TokenId};
 
@@ -20,10 +17,11 @@ pub(crate) fn can_infer(
     expected_output: Option<&str>,
     maximum_token_count: usize,
 ) -> anyhow::Result<TestCaseReport> {
-    let mut session = model.start_session(InferenceSessionConfig {
-        n_threads: model_config.threads,
-        ..Default::default()
-    });
+    let mut session = model.start_session(
+        InferenceSessionConfig::builder()
+            .n_threads(model_config.threads)
+            .build()?,
+    );
     let (actual_output, res) = run_inference(model, &mut session, input, maximum_token_count);
 
     // Process the results
@@ -65,24 +63,22 @@ fn run_inference(
     maximum_token_count: usize,
 ) -> (String, Result<InferenceStats, llm::InferenceError>) {
     let mut actual_output: String = String::new();
-    let res = session.infer::<Infallible>(
+    let res = session.infer(
         model,
         &mut rand::rngs::mock::StepRng::new(0, 1),
-        &llm::InferenceRequest {
-            prompt: input.into(),
-            parameters: &llm::InferenceParameters {
-                sampler: Arc::new(Mutex::new(DeterministicSampler::default())),
-            },
-            play_back_previous_tokens: false,
-            maximum_token_count: Some(maximum_token_count),
-        },
+        &llm::InferenceRequest::builder(
+            input,
+            &llm::InferenceParameters::new(Arc::new(Mutex::new(DeterministicSampler::default()))),
+        )
+        .maximum_token_count(maximum_token_count)
+        .build(),
         &mut Default::default(),
         |r| match r {
             llm::InferenceResponse::PromptToken(t) | llm::InferenceResponse::InferredToken(t) => {
                 actual_output += &t;
-                Ok(llm::InferenceFeedback::Continue)
+                llm::InferenceFeedback::Continue
             }
-            _ => Ok(llm::InferenceFeedback::Continue),
+            _ => llm::InferenceFeedback::Continue,
         },
     );
 
@@ -92,7 +88,7 @@ fn run_inference(
 // Takes the most likely element from the logits, except if they've appeared in `previous_tokens`
 // at all
 #[derive(Debug, Default)]
-struct DeterministicSampler(SampleGreedy);
+pub(crate) struct DeterministicSampler(SampleGreedy);
 
 impl Sampler for DeterministicSampler {
     fn sample<'a>(
@@ -2,6 +2,7 @@
 
 mod common;
 mod delete;
+mod golden;
 mod inference;
 mod tokens;
 
@@ -37,6 +38,10 @@ struct Cli {
     #[clap(short, long)]
     threads: Option<usize>,
 
+    /// Record the outputs of the golden test cases, instead of comparing against them.
+    #[clap(long)]
+    bless: bool,
+
     /// The model architecture to test. If not specified, all architectures will be tested.
     architecture: Option<String>,
 }
@@ -68,13 +73,16 @@ async fn main() -> anyhow::Result<()> {
         .filter(|p| p.extension().unwrap_or_default() == "json")
         .map(|path| {
             let file_name = path.file_stem().unwrap().to_string_lossy().to_string();
-            let test_config: TestConfig = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            let mut test_config: TestConfig = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            test_config.name = file_name.clone();
             anyhow::Ok((file_name, test_config))
         })
         .collect::<Result<_, _>>()?;
     let model_config = ModelConfig {
         mmap: !args.no_mmap,
         threads: args.threads.unwrap_or(2),
+        bless: args.bless,
+        golden_dir: cwd.join("binaries/llm-test/golden"),
     };
 
     // Test models
@@ -91,7 +99,7 @@ async fn main() -> anyhow::Result<()> {
     } else {
         test_configs.values().cloned().collect()
     };
-    test_configs.sort_by_key(|tc| tc.architecture.clone());
+    test_configs.sort_by_key(|tc| tc.name.clone());
 
     let test_configs_len = test_configs.len();
     for test_config in test_configs {
@@ -108,13 +116,22 @@ async fn main() -> anyhow::Result<()> {
 struct ModelConfig {
     mmap: bool,
     threads: usize,
+    bless: bool,
+    golden_dir: PathBuf,
 }
 
 #[derive(Deserialize, Debug, Clone)]
 struct TestConfig {
-    url: String,
+    /// The name of the config file, without its extension.
+    #[serde(skip)]
+    name: String,
+    #[serde(default)]
+    url: Option<String>,
     filename: PathBuf,
     architecture: String,
+    /// If set, the model is generated instead of being downloaded from `url`.
+    #[serde(default)]
+    generate: Option<golden::GeneratedModel>,
     test_cases: Vec<TestCase>,
 }
 
@@ -130,6 +147,10 @@ enum TestCase {
         output: usize,
     },
     Delete {},
+    Golden {
+        input: String,
+        maximum_token_count: usize,
+    },
 }
 
 #[derive(Serialize)]
@@ -161,6 +182,7 @@ pub enum TestCaseReportInner {
     },
     Tokens(tokens::TokensReport),
     Delete(delete::DeleteReport),
+    Golden(golden::GoldenReport),
 }
 
 async fn test_model(
@@ -186,8 +208,17 @@ async fn test_model(
         local_path.display()
     );
 
-    // Download the model if necessary
-    download_file(&test_config.url, &local_path).await?;
+    // Generate or download the model if necessary
+    match &test_config.generate {
+        Some(generated_model) => generated_model.generate(architecture, &local_path)?,
+        None => {
+            let url = test_config
+                .url
+                .as_deref()
+                .with_context(|| format!("No `url` in config `{}`", test_config.name))?;
+            download_file(url, &local_path).await?;
+        }
+    }
 
     struct TestVisitor<'a> {
         model_config: &'a ModelConfig,
@@ -210,9 +241,10 @@ async fn test_model(
                 let model = llm::load::<M>(
                     local_path,
                     llm::TokenizerSource::Embedded,
-                    llm::ModelParameters {
-                        prefer_mmap: model_config.mmap,
-                        ..Default::default()
+                    {
+                        let mut params = llm::ModelParameters::default();
+                        params.prefer_mmap = model_config.mmap;
+                        params
                     },
                     |progress| {
                         let print = !matches!(&progress,
@@ -264,6 +296,11 @@ async fn test_model(
             //
 
             // Run the test cases
+            let mut golden = golden::GoldenOutputs::load(
+                model_config
+                    .golden_dir
+                    .join(format!("{}.json", test_config.name)),
+            )?;
             let mut test_case_reports = vec![];
             for test_case in &test_config.test_cases {
                 match test_case {
@@ -284,8 +321,25 @@ async fn test_model(
                     TestCase::Delete {} => {
                         test_case_reports.push(delete::can_delete(&model));
                     }
+                    TestCase::Golden {
+                        input,
+                        maximum_token_count,
+                    } => test_case_reports.push(golden::can_reproduce(
+                        &model,
+                        model_config,
+                        &mut golden,
+                        input,
+                        *maximum_token_count,
+                    )),
                 }
             }
+            let has_golden_test_cases = test_config
+                .test_cases
+                .iter()
+                .any(|tc| matches!(tc, TestCase::Golden { .. }));
+            if model_config.bless && has_golden_test_cases {
+                golden.save()?;
+            }
             let first_error: Option<String> =
                 test_case_reports
                     .iter()
@@ -333,7 +387,7 @@ fn write_report(
     report: &Report,
 ) -> anyhow::Result<()> {
     let json_report = serde_json::to_string_pretty(&report)?;
-    let report_path = results_dir.join(format!("{}.json", test_config.architecture));
+    let report_path = results_dir.join(format!("{}.json", test_config.name));
     fs::write(report_path, json_report)?;
     Ok(())
 }
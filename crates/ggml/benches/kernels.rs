@@ -0,0 +1,96 @@
+//! Benchmarks the matrix-vector multiplication kernels of each weight format, which
+//! dominate the time taken to generate a token.
+//!
+//! Run with `cargo bench -p ggml`.
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use ggml::{Context, GraphExecutionPlan, Tensor, Type};
+use rand::{distributions::Uniform, prelude::*};
+
+/// The dimensions of the weight matrix: large enough to not fit in the caches of most
+/// CPUs, like the weights of a real model.
+const N_ROWS: usize = 4096;
+const N_COLUMNS: usize = 4096;
+
+const WEIGHT_TYPES: [Type; 7] = [
+    Type::F32,
+    Type::F16,
+    Type::Q4_0,
+    Type::Q4_1,
+    Type::Q5_0,
+    Type::Q5_1,
+    Type::Q8_0,
+];
+
+fn mat_vec(c: &mut Criterion) {
+    let mut rng = StdRng::seed_from_u64(0);
+    let weights: Vec<f32> = Uniform::new(-1.0, 1.0)
+        .sample_iter(&mut rng)
+        .take(N_ROWS * N_COLUMNS)
+        .collect();
+    let input: Vec<f32> = Uniform::new(-1.0, 1.0)
+        .sample_iter(&mut rng)
+        .take(N_COLUMNS)
+        .collect();
+
+    let mut group = c.benchmark_group("mat_vec");
+    group.throughput(Throughput::Elements((N_ROWS * N_COLUMNS) as u64));
+    for element_type in WEIGHT_TYPES {
+        let weights_size = ggml::type_sizef(element_type) * (N_ROWS * N_COLUMNS) as f64;
+        let weights_context = Context::new_with_allocate(weights_size as usize + 1024 * 1024);
+        let mut weight = weights_context.new_tensor_2d(element_type, N_COLUMNS, N_ROWS);
+        unsafe { weight.write_data(&encode(element_type, &weights)) };
+
+        group.bench_function(
+            BenchmarkId::from_parameter(format!("{element_type:?}")),
+            |b| {
+                b.iter_batched(
+                    || build_mat_vec(&weight, &input),
+                    |(context, mut plan)| plan.execute(&context),
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+}
+
+/// Builds a graph multiplying `weight` with `input` in a new context, so that each
+/// iteration only measures the multiplication.
+fn build_mat_vec(weight: &Tensor, input: &[f32]) -> (Context, GraphExecutionPlan) {
+    let context = Context::new_with_allocate(
+        (N_ROWS + N_COLUMNS) * 4 * 2 + ggml::graph_overhead() + 1024 * 1024,
+    );
+
+    let mut x = context.new_tensor_1d(Type::F32, N_COLUMNS);
+    unsafe { x.write_data(&encode(Type::F32, input)) };
+    let result = context.op_mul_mat(weight, &x);
+
+    let mut graph = context.create_compute_graph();
+    graph.build_forward_expand(&result);
+    let plan = GraphExecutionPlan::new(&mut graph, 1);
+    (context, plan)
+}
+
+/// Converts `values` to the in-memory representation of `element_type`.
+fn encode(element_type: Type, values: &[f32]) -> Vec<u8> {
+    let quantize = match element_type {
+        Type::F32 => return values.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        Type::F16 => {
+            return values
+                .iter()
+                .flat_map(|&v| unsafe { ggml::sys::ggml_fp32_to_fp16(v) }.to_le_bytes())
+                .collect()
+        }
+        Type::Q4_0 => ggml::quantize_q4_0,
+        Type::Q4_1 => ggml::quantize_q4_1,
+        Type::Q5_0 => ggml::quantize_q5_0,
+        Type::Q5_1 => ggml::quantize_q5_1,
+        Type::Q8_0 => ggml::quantize_q8_0,
+        _ => unreachable!("{element_type:?} is not a weight type"),
+    };
+    quantize(values, values.len(), N_COLUMNS).output
+}
+
+criterion_group!(benches, mat_vec);
+criterion_main!(benches);
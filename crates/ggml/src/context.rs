@@ -1,4 +1,5 @@
 use std::{
+    cell::Cell,
     collections::HashMap,
     ffi::c_void,
     os::raw::c_int,
@@ -28,6 +29,9 @@ pub struct Context {
 
     /// Whether the context can offload tensors to the GPU
     pub can_offload: bool,
+
+    /// The most memory used of any scratch buffer, recorded when it is replaced.
+    scratch_peak: Cell<usize>,
 }
 
 /// Contains state shared between a context and its tensors
@@ -155,6 +159,7 @@ impl Context {
             inner: ContextInner::new(raw),
             storage: Some(storage),
             can_offload: false,
+            scratch_peak: Cell::new(0),
         }
     }
 
@@ -216,7 +221,7 @@ impl Context {
             (0, std::ptr::null_mut())
         };
         // SAFETY: this just passes (most likely uninitialized) memory buffer to the ggml C API
-        unsafe {
+        let previous_used = unsafe {
             sys::ggml_set_scratch(
                 self.as_ptr(),
                 sys::ggml_scratch {
@@ -224,8 +229,17 @@ impl Context {
                     size,
                     data,
                 },
-            );
-        }
+            )
+        };
+        self.scratch_peak
+            .set(self.scratch_peak.get().max(previous_used));
+    }
+
+    /// Retrieves the most memory used of any scratch buffer by this [Context]. The usage of
+    /// a scratch buffer is known once it is replaced with [Context::use_scratch], which
+    /// includes disabling it.
+    pub fn scratch_peak(&self) -> usize {
+        self.scratch_peak.get()
     }
 
     /// Creates a new 1D tensor.
@@ -533,6 +547,28 @@ impl Context {
         self.new_tensor_raw(tensor)
     }
 
+    /// In-place; reshapes `a` in accordance with the specified dimensions.
+    pub fn op_reshape_4d(
+        &self,
+        a: &Tensor,
+        ne0: usize,
+        ne1: usize,
+        ne2: usize,
+        ne3: usize,
+    ) -> Tensor {
+        let tensor = unsafe {
+            sys::ggml_reshape_4d(
+                self.as_ptr(),
+                a.ptr.as_ptr(),
+                usize_to_i64(ne0),
+                usize_to_i64(ne1),
+                usize_to_i64(ne2),
+                usize_to_i64(ne3),
+            )
+        };
+        self.new_tensor_raw(tensor)
+    }
+
     /// ggml_cont
     pub fn op_cont(&self, a: &Tensor) -> Tensor {
         let tensor = unsafe { sys::ggml_cont(self.as_ptr(), a.ptr.as_ptr()) };
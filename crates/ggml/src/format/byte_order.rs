@@ -0,0 +1,122 @@
+//! Support for GGML files whose byte order differs from the host's.
+//!
+//! GGML files are written in the byte order of the machine that wrote them, which is almost
+//! always little-endian. The loader detects the byte order of a file from its magic number,
+//! reads its header in that order, and reports it in [TensorLoadInfo](super::TensorLoadInfo)
+//! so that the tensors' data can be converted with [swap_byte_order] as it is loaded.
+
+use std::io::{self, BufRead, Read};
+
+use crate::{ElementType, Type};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+/// The byte order of the numbers in a GGML file.
+pub enum ByteOrder {
+    /// Least significant byte first, as on x86 and most ARM machines.
+    Little,
+    /// Most significant byte first, as on s390x and some PowerPC machines.
+    Big,
+}
+impl ByteOrder {
+    /// The byte order of the host.
+    pub const NATIVE: Self = if cfg!(target_endian = "big") {
+        Self::Big
+    } else {
+        Self::Little
+    };
+
+    pub(crate) fn read_u32(self, reader: &mut dyn BufRead) -> io::Result<u32> {
+        let bytes = crate::util::read_bytes::<4>(reader)?;
+        Ok(match self {
+            Self::Little => u32::from_le_bytes(bytes),
+            Self::Big => u32::from_be_bytes(bytes),
+        })
+    }
+
+    pub(crate) fn read_i32(self, reader: &mut dyn BufRead) -> io::Result<i32> {
+        Ok(self.read_u32(reader)? as i32)
+    }
+
+    pub(crate) fn read_f32(self, reader: &mut dyn BufRead) -> io::Result<f32> {
+        Ok(f32::from_bits(self.read_u32(reader)?))
+    }
+}
+
+/// The size in bytes of each number in a block of `element_type`, and its offset within the
+/// block, or `None` if the byte order of the element type cannot be converted.
+fn block_fields(element_type: ElementType) -> Option<&'static [(usize, usize)]> {
+    Some(match element_type {
+        Type::F32 | Type::I32 => &[(0, 4)],
+        Type::F16 => &[(0, 2)],
+        Type::I8 => &[],
+        // `d` (and `m`) are `f16`; `qh` is read as a `u32`.
+        Type::Q4_0 | Type::Q8_0 => &[(0, 2)],
+        Type::Q4_1 => &[(0, 2), (2, 2)],
+        Type::Q5_0 => &[(0, 2), (2, 4)],
+        Type::Q5_1 => &[(0, 2), (2, 2), (4, 4)],
+        // `d` and `s` are `f32`.
+        Type::Q8_1 => &[(0, 4), (4, 4)],
+        // The k-quants pack their scales into words whose layout depends on the byte order.
+        Type::Q2_K | Type::Q3_K | Type::Q4_K | Type::Q5_K | Type::Q6_K => return None,
+    })
+}
+
+/// Whether the byte order of data of `element_type` can be converted with [swap_byte_order].
+pub fn can_swap_byte_order(element_type: ElementType) -> bool {
+    block_fields(element_type).is_some()
+}
+
+/// Converts `data` of `element_type` between little- and big-endian in place.
+///
+/// # Panics
+///
+/// Panics if the byte order of `element_type` cannot be converted (see [can_swap_byte_order]).
+pub fn swap_byte_order(element_type: ElementType, data: &mut [u8]) {
+    let fields = block_fields(element_type)
+        .unwrap_or_else(|| panic!("cannot convert the byte order of {element_type} data"));
+    for block in data.chunks_exact_mut(crate::type_size(element_type)) {
+        for &(offset, size) in fields {
+            block[offset..offset + size].reverse();
+        }
+    }
+}
+
+/// Reverses the bytes of each 4-byte word that is read, so that hyperparameters, which are
+/// made of 4-byte numbers, can be read from a file with the other byte order.
+pub(crate) struct WordSwappingReader<'a> {
+    inner: &'a mut dyn BufRead,
+    word: [u8; 4],
+    position: usize,
+}
+impl<'a> WordSwappingReader<'a> {
+    pub(crate) fn new(inner: &'a mut dyn BufRead) -> Self {
+        Self {
+            inner,
+            word: [0; 4],
+            position: 4,
+        }
+    }
+}
+impl Read for WordSwappingReader<'_> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = buf.len().min(available.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+impl BufRead for WordSwappingReader<'_> {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        if self.position == self.word.len() {
+            self.inner.read_exact(&mut self.word)?;
+            self.word.reverse();
+            self.position = 0;
+        }
+        Ok(&self.word[self.position..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.position = (self.position + amt).min(self.word.len());
+    }
+}
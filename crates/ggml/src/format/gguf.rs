@@ -0,0 +1,805 @@
+//! Reading and writing of [GGUF](https://github.com/ggerganov/ggml/blob/master/docs/gguf.md)
+//! files, the successor of GGJT, which describe a model with key-value [Metadata] instead
+//! of a fixed header.
+//!
+//! Only little-endian files of versions 2 and 3 are supported. [load](super::load) reads the
+//! hyperparameters of a model in the layout of the older containers from
+//! [HYPERPARAMETERS_KEY], as those written by [save_gguf] with [vocabulary_metadata] carry
+//! them, or from the standard keys of its architecture (such as `llama.context_length`)
+//! with [LoadHandler::read_gguf_hyperparameters](super::LoadHandler::read_gguf_hyperparameters)
+//! if the file does not have that key.
+
+use std::{
+    error::Error,
+    io::{BufRead, Seek, Write},
+};
+
+use super::{LoadError, SaveError, SaveHandler};
+use crate::{util, ContainerType, ElementType};
+
+/// The version of GGUF that is written.
+pub const GGUF_VERSION: u32 = 3;
+/// The alignment of the tensor data, unless [ALIGNMENT_KEY] sets another.
+pub const DEFAULT_ALIGNMENT: u64 = 32;
+/// The key of the alignment of the tensor data.
+pub const ALIGNMENT_KEY: &str = "general.alignment";
+/// The key of the architecture of the model.
+pub const ARCHITECTURE_KEY: &str = "general.architecture";
+/// The key of the hyperparameters of the model, in the layout of the older containers.
+/// Only files written by llm have it.
+pub const HYPERPARAMETERS_KEY: &str = "llm.hyperparameters";
+/// The key of the file type of the model, which describes how most of its tensors are
+/// quantized.
+pub const FILE_TYPE_KEY: &str = "general.file_type";
+/// The key of the version of the quantization formats of the tensors of the model.
+pub const QUANTIZATION_VERSION_KEY: &str = "general.quantization_version";
+/// The key of the kind of tokenizer of the vocabulary, such as `llama` (SentencePiece) or
+/// `gpt2` (byte-level BPE).
+pub const TOKENIZER_MODEL_KEY: &str = "tokenizer.ggml.model";
+/// The key of the tokens of the vocabulary.
+pub const TOKENS_KEY: &str = "tokenizer.ggml.tokens";
+/// The key of the scores of the tokens of the vocabulary.
+pub const SCORES_KEY: &str = "tokenizer.ggml.scores";
+/// The key of the [TokenType]s of the tokens of the vocabulary.
+pub const TOKEN_TYPES_KEY: &str = "tokenizer.ggml.token_type";
+/// The key of the merges of a BPE vocabulary, each a pair of tokens separated by a space.
+pub const MERGES_KEY: &str = "tokenizer.ggml.merges";
+/// The key of the ID of the token that begins a text.
+pub const BOS_TOKEN_ID_KEY: &str = "tokenizer.ggml.bos_token_id";
+/// The key of the ID of the token that ends a text.
+pub const EOS_TOKEN_ID_KEY: &str = "tokenizer.ggml.eos_token_id";
+/// The key of the ID of the token for text that is not in the vocabulary.
+pub const UNKNOWN_TOKEN_ID_KEY: &str = "tokenizer.ggml.unknown_token_id";
+/// The key of the ID of the token that pads a text.
+pub const PADDING_TOKEN_ID_KEY: &str = "tokenizer.ggml.padding_token_id";
+/// The key of the Hugging Face `tokenizer.json` the vocabulary was converted from.
+pub const HUGGINGFACE_TOKENIZER_KEY: &str = "tokenizer.huggingface.json";
+
+/// The most dimensions a tensor of a GGUF file can have.
+const MAX_TENSOR_DIMS: usize = 4;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a [MetadataValue].
+pub enum MetadataValueType {
+    /// An unsigned 8-bit integer.
+    U8,
+    /// A signed 8-bit integer.
+    I8,
+    /// An unsigned 16-bit integer.
+    U16,
+    /// A signed 16-bit integer.
+    I16,
+    /// An unsigned 32-bit integer.
+    U32,
+    /// A signed 32-bit integer.
+    I32,
+    /// A 32-bit float.
+    F32,
+    /// A boolean.
+    Bool,
+    /// A UTF-8 string.
+    String,
+    /// An array of values of one type.
+    Array,
+    /// An unsigned 64-bit integer.
+    U64,
+    /// A signed 64-bit integer.
+    I64,
+    /// A 64-bit float.
+    F64,
+}
+impl MetadataValueType {
+    const ALL: [Self; 13] = [
+        Self::U8,
+        Self::I8,
+        Self::U16,
+        Self::I16,
+        Self::U32,
+        Self::I32,
+        Self::F32,
+        Self::Bool,
+        Self::String,
+        Self::Array,
+        Self::U64,
+        Self::I64,
+        Self::F64,
+    ];
+
+    fn from_u32(value: u32) -> Option<Self> {
+        Self::ALL.get(usize::try_from(value).ok()?).copied()
+    }
+
+    fn to_u32(self) -> u32 {
+        Self::ALL
+            .iter()
+            .position(|&t| t == self)
+            .unwrap_or_default() as u32
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A value of the [Metadata] of a GGUF file.
+pub enum MetadataValue {
+    /// An unsigned 8-bit integer.
+    U8(u8),
+    /// A signed 8-bit integer.
+    I8(i8),
+    /// An unsigned 16-bit integer.
+    U16(u16),
+    /// A signed 16-bit integer.
+    I16(i16),
+    /// An unsigned 32-bit integer.
+    U32(u32),
+    /// A signed 32-bit integer.
+    I32(i32),
+    /// A 32-bit float.
+    F32(f32),
+    /// A boolean.
+    Bool(bool),
+    /// A UTF-8 string.
+    String(String),
+    /// An array of values, all of the given type. Arrays of arrays are not supported.
+    Array(MetadataValueType, Vec<MetadataValue>),
+    /// An unsigned 64-bit integer.
+    U64(u64),
+    /// A signed 64-bit integer.
+    I64(i64),
+    /// A 64-bit float.
+    F64(f64),
+}
+impl MetadataValue {
+    /// The type of the value.
+    pub fn value_type(&self) -> MetadataValueType {
+        match self {
+            Self::U8(_) => MetadataValueType::U8,
+            Self::I8(_) => MetadataValueType::I8,
+            Self::U16(_) => MetadataValueType::U16,
+            Self::I16(_) => MetadataValueType::I16,
+            Self::U32(_) => MetadataValueType::U32,
+            Self::I32(_) => MetadataValueType::I32,
+            Self::F32(_) => MetadataValueType::F32,
+            Self::Bool(_) => MetadataValueType::Bool,
+            Self::String(_) => MetadataValueType::String,
+            Self::Array(..) => MetadataValueType::Array,
+            Self::U64(_) => MetadataValueType::U64,
+            Self::I64(_) => MetadataValueType::I64,
+            Self::F64(_) => MetadataValueType::F64,
+        }
+    }
+
+    /// The value as a `u64`, if it is a non-negative integer.
+    pub fn as_u64(&self) -> Option<u64> {
+        match *self {
+            Self::U8(v) => Some(v.into()),
+            Self::U16(v) => Some(v.into()),
+            Self::U32(v) => Some(v.into()),
+            Self::U64(v) => Some(v),
+            Self::I8(v) => v.try_into().ok(),
+            Self::I16(v) => v.try_into().ok(),
+            Self::I32(v) => v.try_into().ok(),
+            Self::I64(v) => v.try_into().ok(),
+            _ => None,
+        }
+    }
+
+    /// The value as a string, if it is one.
+    pub fn as_str(&self) -> Option<&str> {
+        match self {
+            Self::String(v) => Some(v),
+            _ => None,
+        }
+    }
+
+    /// The values of the array, if this is one.
+    pub fn as_array(&self) -> Option<&[MetadataValue]> {
+        match self {
+            Self::Array(_, values) => Some(values),
+            _ => None,
+        }
+    }
+
+    /// Parses a value of `value_type` from `text`, as it is displayed. Arrays cannot be
+    /// parsed.
+    pub fn parse(value_type: MetadataValueType, text: &str) -> Option<Self> {
+        Some(match value_type {
+            MetadataValueType::U8 => Self::U8(text.parse().ok()?),
+            MetadataValueType::I8 => Self::I8(text.parse().ok()?),
+            MetadataValueType::U16 => Self::U16(text.parse().ok()?),
+            MetadataValueType::I16 => Self::I16(text.parse().ok()?),
+            MetadataValueType::U32 => Self::U32(text.parse().ok()?),
+            MetadataValueType::I32 => Self::I32(text.parse().ok()?),
+            MetadataValueType::F32 => Self::F32(text.parse().ok()?),
+            MetadataValueType::Bool => Self::Bool(text.parse().ok()?),
+            MetadataValueType::String => Self::String(text.to_string()),
+            MetadataValueType::Array => return None,
+            MetadataValueType::U64 => Self::U64(text.parse().ok()?),
+            MetadataValueType::I64 => Self::I64(text.parse().ok()?),
+            MetadataValueType::F64 => Self::F64(text.parse().ok()?),
+        })
+    }
+
+    fn read<E: Error>(
+        reader: &mut dyn BufRead,
+        value_type: MetadataValueType,
+    ) -> Result<Self, LoadError<E>> {
+        Ok(match value_type {
+            MetadataValueType::U8 => Self::U8(u8::from_le_bytes(util::read_bytes(reader)?)),
+            MetadataValueType::I8 => Self::I8(i8::from_le_bytes(util::read_bytes(reader)?)),
+            MetadataValueType::U16 => Self::U16(u16::from_le_bytes(util::read_bytes(reader)?)),
+            MetadataValueType::I16 => Self::I16(i16::from_le_bytes(util::read_bytes(reader)?)),
+            MetadataValueType::U32 => Self::U32(util::read_u32(reader)?),
+            MetadataValueType::I32 => Self::I32(util::read_i32(reader)?),
+            MetadataValueType::F32 => Self::F32(util::read_f32(reader)?),
+            MetadataValueType::Bool => match util::read_bytes::<1>(reader)? {
+                [0] => Self::Bool(false),
+                [1] => Self::Bool(true),
+                [value] => {
+                    return Err(LoadError::InvariantBroken(format!(
+                        "a boolean is 0 or 1, not {value}"
+                    )))
+                }
+            },
+            MetadataValueType::String => Self::String(read_string(reader)?),
+            MetadataValueType::Array => {
+                let element_type = read_value_type(reader)?;
+                if element_type == MetadataValueType::Array {
+                    return Err(LoadError::InvariantBroken(
+                        "arrays of arrays are not supported".to_string(),
+                    ));
+                }
+                // The values are read one at a time, so a corrupt length cannot cause a
+                // huge allocation.
+                let len = util::read_u64(reader)?;
+                let mut values = vec![];
+                for _ in 0..len {
+                    values.push(Self::read(reader, element_type)?);
+                }
+                Self::Array(element_type, values)
+            }
+            MetadataValueType::U64 => Self::U64(util::read_u64(reader)?),
+            MetadataValueType::I64 => Self::I64(util::read_u64(reader)? as i64),
+            MetadataValueType::F64 => Self::F64(f64::from_bits(util::read_u64(reader)?)),
+        })
+    }
+
+    fn write(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        match self {
+            Self::U8(v) => writer.write_all(&v.to_le_bytes()),
+            Self::I8(v) => writer.write_all(&v.to_le_bytes()),
+            Self::U16(v) => writer.write_all(&v.to_le_bytes()),
+            Self::I16(v) => writer.write_all(&v.to_le_bytes()),
+            Self::U32(v) => util::write_u32(writer, *v),
+            Self::I32(v) => util::write_i32(writer, *v),
+            Self::F32(v) => util::write_f32(writer, *v),
+            Self::Bool(v) => writer.write_all(&[u8::from(*v)]),
+            Self::String(v) => write_string(writer, v),
+            Self::Array(element_type, values) => {
+                if *element_type == MetadataValueType::Array
+                    || values.iter().any(|v| v.value_type() != *element_type)
+                {
+                    return Err(std::io::Error::new(
+                        std::io::ErrorKind::InvalidInput,
+                        format!("the values of an array must all be of type {element_type:?}"),
+                    ));
+                }
+                util::write_u32(writer, element_type.to_u32())?;
+                util::write_u64(writer, values.len() as u64)?;
+                values.iter().try_for_each(|v| v.write(writer))
+            }
+            Self::U64(v) => util::write_u64(writer, *v),
+            Self::I64(v) => writer.write_all(&v.to_le_bytes()),
+            Self::F64(v) => writer.write_all(&v.to_le_bytes()),
+        }
+    }
+}
+
+impl std::fmt::Display for MetadataValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::U8(v) => write!(f, "{v}"),
+            Self::I8(v) => write!(f, "{v}"),
+            Self::U16(v) => write!(f, "{v}"),
+            Self::I16(v) => write!(f, "{v}"),
+            Self::U32(v) => write!(f, "{v}"),
+            Self::I32(v) => write!(f, "{v}"),
+            Self::F32(v) => write!(f, "{v}"),
+            Self::Bool(v) => write!(f, "{v}"),
+            Self::String(v) => write!(f, "{v}"),
+            Self::Array(_, values) => {
+                write!(f, "[")?;
+                for (i, value) in values.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    match value {
+                        Self::String(v) => write!(f, "{v:?}")?,
+                        value => write!(f, "{value}")?,
+                    }
+                }
+                write!(f, "]")
+            }
+            Self::U64(v) => write!(f, "{v}"),
+            Self::I64(v) => write!(f, "{v}"),
+            Self::F64(v) => write!(f, "{v}"),
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+/// The key-value metadata of a GGUF file, in the order of the file.
+pub struct Metadata(Vec<(String, MetadataValue)>);
+impl Metadata {
+    /// The value of `key`, if there is one.
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.0.iter().find(|(k, _)| k == key).map(|(_, v)| v)
+    }
+
+    /// Sets the value of `key`, returning its previous value. A new key is added after
+    /// the others.
+    pub fn insert(
+        &mut self,
+        key: impl Into<String>,
+        value: MetadataValue,
+    ) -> Option<MetadataValue> {
+        let key = key.into();
+        match self.0.iter_mut().find(|(k, _)| *k == key) {
+            Some((_, v)) => Some(std::mem::replace(v, value)),
+            None => {
+                self.0.push((key, value));
+                None
+            }
+        }
+    }
+
+    /// Removes `key`, returning its value if it had one.
+    pub fn remove(&mut self, key: &str) -> Option<MetadataValue> {
+        let index = self.0.iter().position(|(k, _)| k == key)?;
+        Some(self.0.remove(index).1)
+    }
+
+    /// The keys and their values, in order.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &MetadataValue)> {
+        self.0.iter().map(|(k, v)| (k.as_str(), v))
+    }
+
+    /// The number of keys.
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    /// Whether there are no keys.
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// Information about a tensor of a GGUF file.
+pub struct TensorInfo {
+    /// The name of the tensor.
+    pub name: String,
+    /// The dimensions of the tensor.
+    pub dims: Vec<usize>,
+    /// The type of the elements of the tensor.
+    pub element_type: ElementType,
+    /// The offset of the tensor's data from the start of the tensor data of the file.
+    pub offset: u64,
+}
+impl TensorInfo {
+    /// The number of elements of the tensor, if it fits in a `usize`.
+    pub fn n_elements(&self) -> Option<usize> {
+        self.dims
+            .iter()
+            .try_fold(1usize, |n, &dim| n.checked_mul(dim))
+    }
+
+    /// The size of the tensor's data in bytes, if it fits in a `usize`.
+    pub fn data_size(&self) -> Option<usize> {
+        let n_blocks = self.n_elements()? / crate::blck_size(self.element_type);
+        n_blocks.checked_mul(crate::type_size(self.element_type))
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The header of a GGUF file: its metadata and the tensors it contains.
+pub struct Gguf {
+    /// The metadata of the file.
+    pub metadata: Metadata,
+    /// The tensors of the file, in order.
+    pub tensor_infos: Vec<TensorInfo>,
+}
+impl Gguf {
+    /// Reads the header of a GGUF file from its start, leaving `reader` at its end.
+    pub fn read<E: Error>(reader: &mut dyn BufRead) -> Result<Self, LoadError<E>> {
+        match ContainerType::read(reader)? {
+            ContainerType::Gguf(version) => Self::read_after_version(reader, version),
+            container_type => Err(LoadError::InvalidFormatVersion(container_type)),
+        }
+    }
+
+    /// Reads the header of a GGUF file of the given `version`, after its version.
+    pub(crate) fn read_after_version<E: Error>(
+        reader: &mut dyn BufRead,
+        version: u32,
+    ) -> Result<Self, LoadError<E>> {
+        if !(2..=3).contains(&version) {
+            return Err(LoadError::InvalidFormatVersion(ContainerType::Gguf(
+                version,
+            )));
+        }
+        let n_tensors = util::read_u64(reader)?;
+        let n_kv = util::read_u64(reader)?;
+
+        let mut metadata = Metadata::default();
+        for _ in 0..n_kv {
+            let key = read_string(reader)?;
+            let value_type = read_value_type(reader)?;
+            let value = MetadataValue::read(reader, value_type)?;
+            if metadata.insert(key.clone(), value).is_some() {
+                return Err(LoadError::InvariantBroken(format!(
+                    "the metadata key `{key}` appears more than once"
+                )));
+            }
+        }
+
+        let mut tensor_infos = vec![];
+        for _ in 0..n_tensors {
+            let name = read_string(reader)?;
+            let n_dims = usize::try_from(util::read_u32(reader)?)?;
+            if n_dims > MAX_TENSOR_DIMS {
+                return Err(LoadError::InvariantBroken(format!(
+                    "tensor {name} has {n_dims} dimensions, more than the maximum of \
+                     {MAX_TENSOR_DIMS}"
+                )));
+            }
+            let dims = (0..n_dims)
+                .map(|_| Ok(usize::try_from(util::read_u64(reader)?)?))
+                .collect::<Result<Vec<_>, LoadError<E>>>()?;
+            let ftype = util::read_u32(reader)?;
+            let element_type =
+                ElementType::try_from(ftype).map_err(|_| LoadError::UnsupportedElementType {
+                    tensor_name: name.clone(),
+                    ftype,
+                })?;
+            let offset = util::read_u64(reader)?;
+            tensor_infos.push(TensorInfo {
+                name,
+                dims,
+                element_type,
+                offset,
+            });
+        }
+
+        let gguf = Self {
+            metadata,
+            tensor_infos,
+        };
+        if gguf.alignment().is_none() {
+            return Err(LoadError::InvariantBroken(format!(
+                "`{ALIGNMENT_KEY}` is a power of two"
+            )));
+        }
+        Ok(gguf)
+    }
+
+    /// The alignment of the tensor data, or `None` if [ALIGNMENT_KEY] is not a power of
+    /// two.
+    pub fn alignment(&self) -> Option<u64> {
+        match self.metadata.get(ALIGNMENT_KEY) {
+            Some(alignment) => alignment.as_u64().filter(|a| a.is_power_of_two()),
+            None => Some(DEFAULT_ALIGNMENT),
+        }
+    }
+
+    /// The length of the header in bytes, without the padding before the tensor data.
+    pub fn header_len(&self) -> u64 {
+        let mut counter = CountingWriter(0);
+        // Writing to the counter cannot fail, other than for invalid arrays, which are
+        // counted up to the invalid value; such a header cannot be written.
+        let _ = self.write_unpadded(&mut counter);
+        counter.0
+    }
+
+    /// The position of the tensor data in the file: the end of the header, aligned.
+    pub fn tensor_data_position(&self) -> u64 {
+        align(
+            self.header_len(),
+            self.alignment().unwrap_or(DEFAULT_ALIGNMENT),
+        )
+    }
+
+    /// Writes the header, and the padding that aligns the tensor data after it.
+    pub fn write(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        self.write_unpadded(writer)?;
+        let padding = self.tensor_data_position() - self.header_len();
+        writer.write_all(&vec![0; padding as usize])
+    }
+
+    fn write_unpadded(&self, writer: &mut dyn Write) -> std::io::Result<()> {
+        ContainerType::Gguf(GGUF_VERSION).write(writer)?;
+        util::write_u64(writer, self.tensor_infos.len() as u64)?;
+        util::write_u64(writer, self.metadata.len() as u64)?;
+        for (key, value) in self.metadata.iter() {
+            write_string(writer, key)?;
+            util::write_u32(writer, value.value_type().to_u32())?;
+            value.write(writer)?;
+        }
+        for info in &self.tensor_infos {
+            write_string(writer, &info.name)?;
+            util::write_u32(writer, info.dims.len() as u32)?;
+            for &dim in &info.dims {
+                util::write_u64(writer, dim as u64)?;
+            }
+            util::write_u32(writer, info.element_type.into())?;
+            util::write_u64(writer, info.offset)?;
+        }
+        Ok(())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// The type of a token of the vocabulary of a GGUF file, as in `llama.cpp`.
+pub enum TokenType {
+    /// A token of text.
+    Normal = 1,
+    /// The unknown token.
+    Unknown = 2,
+    /// A special token that controls the model, such as the beginning or end of text.
+    Control = 3,
+    /// A token added by the user.
+    UserDefined = 4,
+    /// A token that is never used.
+    Unused = 5,
+    /// A single byte, written as `<0xXX>`.
+    Byte = 6,
+}
+impl TryFrom<i32> for TokenType {
+    type Error = ();
+
+    fn try_from(value: i32) -> Result<Self, Self::Error> {
+        match value {
+            1 => Ok(Self::Normal),
+            2 => Ok(Self::Unknown),
+            3 => Ok(Self::Control),
+            4 => Ok(Self::UserDefined),
+            5 => Ok(Self::Unused),
+            6 => Ok(Self::Byte),
+            _ => Err(()),
+        }
+    }
+}
+
+/// Returns the metadata that describes a model with the `hyperparameters`, written as
+/// [SaveHandler::write_hyperparameters] writes them, and the `vocabulary`, whose tokens
+/// are of the given types, or [TokenType::Normal] if `token_types` is `None`.
+///
+/// Tokens that are not valid UTF-8 are written as a sequence of `<0xXX>` bytes, with the
+/// type [TokenType::Byte].
+pub fn vocabulary_metadata(
+    hyperparameters: Vec<u8>,
+    vocabulary: &[(Vec<u8>, f32)],
+    token_types: Option<&[TokenType]>,
+) -> Metadata {
+    let mut tokens = Vec::with_capacity(vocabulary.len());
+    let mut types = Vec::with_capacity(vocabulary.len());
+    for (i, (token, _)) in vocabulary.iter().enumerate() {
+        let token_type = token_types
+            .and_then(|types| types.get(i).copied())
+            .unwrap_or(TokenType::Normal);
+        let (token, token_type) = match std::str::from_utf8(token) {
+            Ok(token) if token_type != TokenType::Byte => (token.to_string(), token_type),
+            _ => (
+                token.iter().map(|b| format!("<0x{b:02X}>")).collect(),
+                TokenType::Byte,
+            ),
+        };
+        tokens.push(MetadataValue::String(token));
+        types.push(MetadataValue::I32(token_type as i32));
+    }
+
+    let mut metadata = Metadata::default();
+    metadata.insert(
+        HYPERPARAMETERS_KEY,
+        MetadataValue::Array(
+            MetadataValueType::U8,
+            hyperparameters.into_iter().map(MetadataValue::U8).collect(),
+        ),
+    );
+    metadata.insert(
+        TOKENS_KEY,
+        MetadataValue::Array(MetadataValueType::String, tokens),
+    );
+    metadata.insert(
+        SCORES_KEY,
+        MetadataValue::Array(
+            MetadataValueType::F32,
+            vocabulary
+                .iter()
+                .map(|(_, score)| MetadataValue::F32(*score))
+                .collect(),
+        ),
+    );
+    metadata.insert(
+        TOKEN_TYPES_KEY,
+        MetadataValue::Array(MetadataValueType::I32, types),
+    );
+    metadata
+}
+
+/// Returns the IDs of the special tokens of the vocabulary described by `metadata`: the
+/// tokens of the type [TokenType::Control], and the beginning-of-text, end-of-text,
+/// unknown and padding tokens. Returns `None` if the metadata marks none of them, as is the
+/// case for vocabularies written without token types (see [vocabulary_metadata]).
+pub fn special_token_ids(metadata: &Metadata) -> Option<Vec<u32>> {
+    let mut ids: Vec<u32> = metadata
+        .get(TOKEN_TYPES_KEY)
+        .and_then(|v| v.as_array())
+        .into_iter()
+        .flatten()
+        .enumerate()
+        .filter(|(_, token_type)| {
+            matches!(token_type, MetadataValue::I32(t) if *t == TokenType::Control as i32)
+        })
+        .filter_map(|(id, _)| u32::try_from(id).ok())
+        .collect();
+    ids.extend(
+        [
+            BOS_TOKEN_ID_KEY,
+            EOS_TOKEN_ID_KEY,
+            UNKNOWN_TOKEN_ID_KEY,
+            PADDING_TOKEN_ID_KEY,
+        ]
+        .iter()
+        .filter_map(|key| metadata.get(key)?.as_u64())
+        .filter_map(|id| u32::try_from(id).ok()),
+    );
+    if ids.is_empty() {
+        return None;
+    }
+    ids.sort_unstable();
+    ids.dedup();
+    Some(ids)
+}
+
+/// Reads the vocabulary written by [vocabulary_metadata]: each token with its score.
+pub fn read_vocabulary<E: Error>(metadata: &Metadata) -> Result<Vec<(Vec<u8>, f32)>, LoadError<E>> {
+    let array = |key: &str| {
+        metadata
+            .get(key)
+            .and_then(|v| v.as_array())
+            .ok_or_else(|| LoadError::InvariantBroken(format!("the metadata has an array `{key}`")))
+    };
+    let tokens = array(TOKENS_KEY)?;
+    let scores = match metadata.get(SCORES_KEY) {
+        Some(_) => Some(array(SCORES_KEY)?),
+        None => None,
+    };
+    let types = match metadata.get(TOKEN_TYPES_KEY) {
+        Some(_) => Some(array(TOKEN_TYPES_KEY)?),
+        None => None,
+    };
+
+    tokens
+        .iter()
+        .enumerate()
+        .map(|(i, token)| {
+            let token = token.as_str().ok_or_else(|| {
+                LoadError::InvariantBroken(format!("token {i} of `{TOKENS_KEY}` is a string"))
+            })?;
+            let score = match scores.and_then(|scores| scores.get(i)) {
+                Some(&MetadataValue::F32(score)) => score,
+                _ => 0.0,
+            };
+            let is_byte = matches!(
+                types.and_then(|types| types.get(i)),
+                Some(&MetadataValue::I32(t)) if t == TokenType::Byte as i32
+            );
+            let token = if is_byte {
+                parse_bytes(token).ok_or_else(|| {
+                    LoadError::InvariantBroken(format!(
+                        "byte token {i} is a sequence of `<0xXX>`, not {token:?}"
+                    ))
+                })?
+            } else {
+                token.as_bytes().to_vec()
+            };
+            Ok((token, score))
+        })
+        .collect()
+}
+
+/// Parses a sequence of `<0xXX>` bytes.
+fn parse_bytes(token: &str) -> Option<Vec<u8>> {
+    let mut rest = token;
+    let mut bytes = vec![];
+    while !rest.is_empty() {
+        let hex = rest.strip_prefix("<0x")?.get(..3)?.strip_suffix('>')?;
+        bytes.push(u8::from_str_radix(hex, 16).ok()?);
+        rest = &rest[6..];
+    }
+    Some(bytes)
+}
+
+/// Saves a GGUF model to `writer`: the `metadata`, and then the tensors described by
+/// `tensor_infos`, whose offsets are ignored, with the data returned by the `handler`.
+///
+/// The `handler`'s [SaveHandler::write_hyperparameters] is not used, as a GGUF model is
+/// described by its metadata; see [vocabulary_metadata].
+pub fn save_gguf<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    handler: &mut dyn SaveHandler<E>,
+    metadata: Metadata,
+    tensor_infos: Vec<TensorInfo>,
+) -> Result<(), SaveError<E>> {
+    let mut gguf = Gguf {
+        metadata,
+        tensor_infos,
+    };
+    let alignment = gguf.alignment().ok_or_else(|| {
+        SaveError::InvariantBroken(format!("`{ALIGNMENT_KEY}` is a power of two"))
+    })?;
+
+    let mut offset = 0;
+    for info in &mut gguf.tensor_infos {
+        let data_size = info.data_size().ok_or_else(|| {
+            SaveError::InvariantBroken(format!("the size of tensor {} fits in usize", info.name))
+        })?;
+        info.offset = offset;
+        offset = align(offset + data_size as u64, alignment);
+    }
+    gguf.write(writer)?;
+
+    let data_position = gguf.tensor_data_position();
+    for info in &gguf.tensor_infos {
+        let data = handler
+            .tensor_data(&info.name)
+            .map_err(SaveError::ImplementationError)?;
+        if data.element_type != info.element_type
+            || data.dims.get(..data.n_dims) != Some(&info.dims[..])
+            || Some(data.data.len()) != info.data_size()
+        {
+            return Err(SaveError::InvariantBroken(format!(
+                "the data of tensor {} matches its info",
+                info.name
+            )));
+        }
+        let position = writer.stream_position()?;
+        writer.write_all(&vec![0; (data_position + info.offset - position) as usize])?;
+        writer.write_all(&data.data)?;
+    }
+    Ok(())
+}
+
+/// Rounds `offset` up to a multiple of `alignment`, which is a power of two.
+pub(crate) fn align(offset: u64, alignment: u64) -> u64 {
+    (offset + alignment - 1) & !(alignment - 1)
+}
+
+fn read_string<E: Error>(reader: &mut dyn BufRead) -> Result<String, LoadError<E>> {
+    let len = usize::try_from(util::read_u64(reader)?)?;
+    Ok(String::from_utf8(util::read_bytes_with_len(reader, len)?)?)
+}
+
+fn write_string(writer: &mut dyn Write, value: &str) -> std::io::Result<()> {
+    util::write_u64(writer, value.len() as u64)?;
+    writer.write_all(value.as_bytes())
+}
+
+fn read_value_type<E: Error>(reader: &mut dyn BufRead) -> Result<MetadataValueType, LoadError<E>> {
+    let value_type = util::read_u32(reader)?;
+    MetadataValueType::from_u32(value_type)
+        .ok_or_else(|| LoadError::InvariantBroken(format!("{value_type} is a metadata value type")))
+}
+
+/// Counts the bytes written to it.
+struct CountingWriter(u64);
+impl Write for CountingWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.0 += buf.len() as u64;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
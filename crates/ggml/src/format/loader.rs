@@ -10,8 +10,13 @@ use std::{
     io::{BufRead, Seek, SeekFrom},
 };
 
+use super::{
+    byte_order::WordSwappingReader,
+    gguf::{self, Gguf, Metadata},
+    ByteOrder,
+};
 use crate::{
-    util::{has_data_left, read_bytes_with_len, read_f32, read_i32, read_u32},
+    util::{has_data_left, read_bytes_with_len},
     ContainerType, ElementType,
 };
 
@@ -83,6 +88,9 @@ pub struct TensorLoadInfo {
     pub element_type: ElementType,
     /// start of tensor - start of file
     pub start_offset: u64,
+    /// The byte order of the tensor's data in the file. If it is not [ByteOrder::NATIVE],
+    /// the data must be converted with [swap_byte_order](super::swap_byte_order) before use.
+    pub byte_order: ByteOrder,
 }
 impl TensorLoadInfo {
     /// Get the dimensions of the tensor.
@@ -104,14 +112,14 @@ impl TensorLoadInfo {
         }
     }
 
-    /// Reads the tensor's data from the given reader in an owned fashion.
+    /// Reads the tensor's data from the given reader in an owned fashion, in the byte order
+    /// of the file.
     ///
     /// The behaviour is undefined if the reader does not correspond to this info.
     ///
     /// Do not use this if loading with `mmap`.
     pub fn read_data<R: BufRead + Seek>(&self, reader: &mut R) -> std::io::Result<Vec<u8>> {
-        let n_bytes = self.n_elements * crate::type_size(self.element_type);
-        let mut data = vec![0; n_bytes];
+        let mut data = vec![0; self.calc_size()];
         reader.seek(SeekFrom::Start(self.start_offset))?;
         reader.read_exact(&mut data)?;
         Ok(data)
@@ -145,6 +153,13 @@ pub struct PartialHyperparameters {
 pub trait LoadHandler<E: Error> {
     /// Called when the [ContainerType] is read.
     fn container_type(&mut self, container_type: ContainerType) -> Result<(), E>;
+    /// Called with the metadata of a [GGUF](ContainerType::Gguf) file, after
+    /// [LoadHandler::container_type] and before the hyperparameters are read.
+    ///
+    /// By default, the metadata is ignored.
+    fn metadata(&mut self, _metadata: &Metadata) -> Result<(), E> {
+        Ok(())
+    }
     /// Called when a token is read so it can be added to the model's embedded vocabulary.
     fn vocabulary_token(&mut self, i: usize, token: Vec<u8>, score: f32) -> Result<(), E>;
     /// Called when the model's hyperparameters need to be read.
@@ -152,8 +167,28 @@ pub trait LoadHandler<E: Error> {
         &mut self,
         reader: &mut dyn BufRead,
     ) -> Result<PartialHyperparameters, E>;
+    /// Called when the hyperparameters of a [GGUF](ContainerType::Gguf) file that does not
+    /// have them under [HYPERPARAMETERS_KEY](gguf::HYPERPARAMETERS_KEY), such as one written
+    /// by another tool, need to be read from the standard keys of its `metadata`.
+    ///
+    /// By default, they are not read, and loading fails.
+    fn read_gguf_hyperparameters(
+        &mut self,
+        _metadata: &Metadata,
+    ) -> Result<Option<PartialHyperparameters>, E> {
+        Ok(None)
+    }
     /// Called when a new [crate::Tensor] is read for the model.
     fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), E>;
+    /// Called when a tensor is read that cannot be loaded, such as one with more than two
+    /// dimensions, with the reason it cannot be loaded. Returns whether to skip the tensor
+    /// and continue loading; if it is not skipped, loading fails. Tensors with an unknown
+    /// element type cannot be skipped, as the size of their data is unknown.
+    ///
+    /// By default, no tensors are skipped.
+    fn skip_unsupported_tensor(&mut self, _tensor_name: &str, _reason: &str) -> bool {
+        false
+    }
 }
 
 /// Load a GGML model from a `reader` with the [LoadHandler], which will be called when certain events occur.
@@ -162,13 +197,14 @@ pub fn load<E: Error, R: BufRead + Seek>(
     handler: &mut impl LoadHandler<E>,
 ) -> Result<(), LoadError<E>> {
     // Verify magic
-    let container_type = ContainerType::read(reader)?;
+    let (container_type, byte_order) = ContainerType::read_with_byte_order(reader)?;
 
     match container_type {
         ContainerType::Ggml
         | ContainerType::Ggmf(1)
         | ContainerType::Ggjt(1..=3)
-        | ContainerType::Ggla(1) => {}
+        | ContainerType::Ggla(1)
+        | ContainerType::Gguf(2..=3) => {}
         _ => return Err(LoadError::InvalidFormatVersion(container_type)),
     }
 
@@ -176,22 +212,32 @@ pub fn load<E: Error, R: BufRead + Seek>(
         .container_type(container_type)
         .map_err(LoadError::ImplementationError)?;
 
-    // Load hyper params
-    let hparams = handler
-        .read_hyperparameters(reader)
-        .map_err(LoadError::ImplementationError)?;
+    if let ContainerType::Gguf(version) = container_type {
+        return load_gguf(reader, handler, version);
+    }
+
+    // Load hyper params. They are made of 4-byte numbers, so they can be read from a file
+    // with the other byte order by reversing each word.
+    let hparams = match byte_order {
+        ByteOrder::Little => handler.read_hyperparameters(reader),
+        ByteOrder::Big => handler.read_hyperparameters(&mut WordSwappingReader::new(reader)),
+    }
+    .map_err(LoadError::ImplementationError)?;
     let n_vocab = hparams.n_vocab;
 
     // Load vocabulary
     for i in 0..n_vocab {
-        let len = read_u32(reader)?.try_into()?;
+        let len = byte_order.read_u32(reader)?.try_into()?;
         let token = read_bytes_with_len(reader, len)?;
         let token_score = match container_type {
-            ContainerType::Ggmf(_version) | ContainerType::Ggjt(_version) => read_f32(reader)?,
+            ContainerType::Ggmf(_version) | ContainerType::Ggjt(_version) => {
+                byte_order.read_f32(reader)?
+            }
             ContainerType::Ggml | ContainerType::Ggla(_) => {
                 // Legacy model, set empty score
                 0.
             }
+            ContainerType::Gguf(_) => unreachable!("GGUF files are loaded by load_gguf"),
         };
         handler
             .vocabulary_token(i, token, token_score)
@@ -200,10 +246,155 @@ pub fn load<E: Error, R: BufRead + Seek>(
 
     // Load tensor data
     match container_type {
-        ContainerType::Ggmf(_) | ContainerType::Ggml => load_weights(reader, handler, false),
+        ContainerType::Ggmf(_) | ContainerType::Ggml => {
+            load_weights(reader, handler, byte_order, false)
+        }
         ContainerType::Ggjt(_version) | ContainerType::Ggla(_version) => {
-            load_weights(reader, handler, true)
+            load_weights(reader, handler, byte_order, true)
         }
+        ContainerType::Gguf(_) => unreachable!("GGUF files are loaded by load_gguf"),
+    }
+}
+
+/// Loads the rest of a GGUF file of the given `version`, after its version.
+fn load_gguf<E: Error, R: BufRead + Seek>(
+    reader: &mut R,
+    handler: &mut impl LoadHandler<E>,
+    version: u32,
+) -> Result<(), LoadError<E>> {
+    let header = Gguf::read_after_version(reader, version)?;
+    let alignment = header.alignment().unwrap_or(gguf::DEFAULT_ALIGNMENT);
+    let data_position = gguf::align(reader.stream_position()?, alignment);
+    let file_len = reader.seek(SeekFrom::End(0))?;
+
+    handler
+        .metadata(&header.metadata)
+        .map_err(LoadError::ImplementationError)?;
+
+    // The files written by llm store the hyperparameters of the model in the layout of the
+    // older containers; those written by other tools only have the standard keys.
+    let hyperparameters = match header.metadata.get(gguf::HYPERPARAMETERS_KEY) {
+        Some(value) => Some(
+            value
+                .as_array()
+                .and_then(|values| {
+                    values
+                        .iter()
+                        .map(|value| match value {
+                            gguf::MetadataValue::U8(byte) => Some(*byte),
+                            _ => None,
+                        })
+                        .collect::<Option<Vec<u8>>>()
+                })
+                .ok_or_else(|| {
+                    LoadError::InvariantBroken(format!(
+                        "`{}` is an array of bytes",
+                        gguf::HYPERPARAMETERS_KEY
+                    ))
+                })?,
+        ),
+        None => None,
+    };
+    let hparams = match hyperparameters {
+        Some(hyperparameters) => handler
+            .read_hyperparameters(&mut hyperparameters.as_slice())
+            .map_err(LoadError::ImplementationError)?,
+        None => handler
+            .read_gguf_hyperparameters(&header.metadata)
+            .map_err(LoadError::ImplementationError)?
+            .ok_or_else(|| {
+                LoadError::InvariantBroken(format!(
+                    "the metadata has the hyperparameters of the model, as a `{}` array of \
+                     bytes or under the standard keys of its architecture",
+                    gguf::HYPERPARAMETERS_KEY
+                ))
+            })?,
+    };
+
+    let vocabulary = gguf::read_vocabulary(&header.metadata)?;
+    if vocabulary.len() != hparams.n_vocab {
+        return Err(LoadError::InvariantBroken(format!(
+            "the vocabulary has {} tokens, as the hyperparameters say, not {}",
+            hparams.n_vocab,
+            vocabulary.len()
+        )));
+    }
+    for (i, (token, score)) in vocabulary.into_iter().enumerate() {
+        handler
+            .vocabulary_token(i, token, score)
+            .map_err(LoadError::ImplementationError)?;
+    }
+
+    for info in header.tensor_infos {
+        let n_dims = info.dims.len();
+        let mut dims = [1usize, 1];
+        for (dim, &info_dim) in dims.iter_mut().zip(&info.dims) {
+            *dim = info_dim;
+        }
+        let n_elements = info.n_elements().ok_or_else(|| {
+            LoadError::InvariantBroken(format!(
+                "the number of elements of {:?} fits in usize",
+                info.dims
+            ))
+        })?;
+
+        let unsupported = unsupported_tensor(&info.name, n_dims, dims, info.element_type);
+        if let Some(invariant) = &unsupported {
+            if !handler.skip_unsupported_tensor(&info.name, invariant) {
+                return Err(LoadError::InvariantBroken(invariant.clone()));
+            }
+        }
+
+        let start_offset = data_position
+            .checked_add(info.offset)
+            .filter(|offset| offset % alignment == 0);
+        let data_end = start_offset
+            .zip(info.data_size())
+            .and_then(|(start, n_bytes)| start.checked_add(n_bytes as u64))
+            .filter(|&data_end| data_end <= file_len);
+        let (Some(start_offset), Some(_)) = (start_offset, data_end) else {
+            return Err(LoadError::InvariantBroken(format!(
+                "the data of tensor {} is aligned and ends before the end of the file \
+                 ({file_len} bytes)",
+                info.name
+            )));
+        };
+
+        if unsupported.is_none() {
+            handler
+                .tensor_buffer(TensorLoadInfo {
+                    name: info.name,
+                    n_dims,
+                    dims,
+                    n_elements,
+                    element_type: info.element_type,
+                    start_offset,
+                    byte_order: ByteOrder::Little,
+                })
+                .map_err(LoadError::ImplementationError)?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns why a tensor cannot be loaded, if it cannot be.
+fn unsupported_tensor(
+    name: &str,
+    n_dims: usize,
+    dims: [usize; 2],
+    ftype: ElementType,
+) -> Option<String> {
+    let ne_len = dims.len();
+    let block_size = crate::blck_size(ftype);
+    if n_dims > ne_len {
+        Some(format!("{n_dims} <= {ne_len}"))
+    } else if matches!(ftype, ElementType::Q4_0 | ElementType::Q4_1) && dims[0] % 64 != 0 {
+        Some(format!("{dims:?}[0] % 64 == 0"))
+    } else if dims[0] % block_size != 0 {
+        Some(format!("{dims:?}[0] % {block_size} == 0 for tensor {name}"))
+    } else {
+        None
     }
 }
 
@@ -214,26 +405,43 @@ pub fn load<E: Error, R: BufRead + Seek>(
 fn load_weights<E: Error, R: BufRead + Seek>(
     reader: &mut R,
     handler: &mut impl LoadHandler<E>,
+    byte_order: ByteOrder,
     align: bool,
 ) -> Result<(), LoadError<E>> {
+    // The tensors' data must be within the file; remember its length to check that.
+    let data_start = reader.stream_position()?;
+    let file_len = reader.seek(SeekFrom::End(0))?;
+    reader.seek(SeekFrom::Start(data_start))?;
+
     while has_data_left(reader)? {
         // load tensor header
-        let n_dims: usize = read_i32(reader)?.try_into()?;
-        let name_len = read_i32(reader)?;
-        let ftype = read_u32(reader)?;
+        let n_dims: usize = byte_order.read_i32(reader)?.try_into()?;
+        let name_len = byte_order.read_i32(reader)?;
+        let ftype = byte_order.read_u32(reader)?;
 
         let mut n_elements: usize = 1;
         let mut dims = [1usize, 1];
         let ne_len = dims.len();
-        if n_dims > ne_len {
-            return Err(LoadError::InvariantBroken(format!("{n_dims} <= {ne_len}")));
+        // Tensors with more dimensions than are supported can only be skipped.
+        if n_dims > crate::MAX_DIMS {
+            return Err(LoadError::InvariantBroken(format!(
+                "a tensor has {n_dims} dimensions, more than the maximum of {}",
+                crate::MAX_DIMS
+            )));
         }
 
-        #[allow(clippy::needless_range_loop)]
+        let mut all_dims = vec![1usize; n_dims];
         for i in 0..n_dims {
-            let dim: usize = read_i32(reader)?.try_into()?;
-            dims[i] = dim;
-            n_elements *= dim;
+            let dim: usize = byte_order.read_i32(reader)?.try_into()?;
+            all_dims[i] = dim;
+            if i < ne_len {
+                dims[i] = dim;
+            }
+            n_elements = n_elements.checked_mul(dim).ok_or_else(|| {
+                LoadError::InvariantBroken(format!(
+                    "the number of elements of {all_dims:?} fits in usize"
+                ))
+            })?;
         }
 
         // load tensor name
@@ -245,13 +453,12 @@ fn load_weights<E: Error, R: BufRead + Seek>(
             })?;
 
         // sanity check
-        match ftype {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(LoadError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
+        let block_size = crate::blck_size(ftype);
+        let unsupported = unsupported_tensor(&name, n_dims, dims, ftype);
+        if let Some(invariant) = &unsupported {
+            if !handler.skip_unsupported_tensor(&name, invariant) {
+                return Err(LoadError::InvariantBroken(invariant.clone()));
             }
-            _ => {}
         }
 
         // load tensor weights
@@ -269,12 +476,25 @@ fn load_weights<E: Error, R: BufRead + Seek>(
             n_elements,
             element_type: ftype,
             start_offset: offset_aligned,
+            byte_order,
         };
-        let n_bytes = tensor_info.calc_size();
-        handler
-            .tensor_buffer(tensor_info)
-            .map_err(LoadError::ImplementationError)?;
-        reader.seek(SeekFrom::Start(offset_aligned + n_bytes as u64))?;
+        // The size is computed with checked arithmetic, as the dimensions may be corrupt.
+        let offset_end = (n_elements / block_size)
+            .checked_mul(crate::type_size(ftype))
+            .and_then(|n_bytes| offset_aligned.checked_add(n_bytes as u64))
+            .filter(|&offset_end| offset_end <= file_len)
+            .ok_or_else(|| {
+                LoadError::InvariantBroken(format!(
+                    "the data of tensor {} ends before the end of the file ({file_len} bytes)",
+                    tensor_info.name
+                ))
+            })?;
+        if unsupported.is_none() {
+            handler
+                .tensor_buffer(tensor_info)
+                .map_err(LoadError::ImplementationError)?;
+        }
+        reader.seek(SeekFrom::Start(offset_end))?;
     }
 
     Ok(())
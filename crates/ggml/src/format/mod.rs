@@ -1,7 +1,10 @@
 //! Loading and saving of [GGML](https://github.com/ggerganov/ggml) files.
 
+mod byte_order;
+pub mod gguf;
 mod loader;
 mod saver;
 
+pub use byte_order::{can_swap_byte_order, swap_byte_order, ByteOrder};
 pub use loader::*;
 pub use saver::*;
@@ -115,46 +115,68 @@ pub fn save<E: Error, W: Write + Seek>(
 
     // Write tensors
     for name in tensor_names {
-        let TensorSaveInfo {
-            n_dims,
-            dims,
-            element_type,
-            data,
-        } = handler
+        let info = handler
             .tensor_data(name)
             .map_err(SaveError::ImplementationError)?;
+        write_tensor(
+            writer,
+            name,
+            &info,
+            container_type != SaveContainerType::Ggml,
+        )?;
+    }
 
-        match element_type {
-            ElementType::Q4_0 | ElementType::Q4_1 => {
-                if dims[0] % 64 != 0 {
-                    return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
-                }
-            }
-            _ => {}
-        }
+    Ok(())
+}
 
-        // Write tensor header
-        util::write_i32(writer, n_dims.try_into()?)?;
-        util::write_i32(writer, name.len().try_into()?)?;
-        util::write_u32(writer, element_type.into())?;
-        for &dim in &dims[0..n_dims] {
-            util::write_i32(writer, dim.try_into()?)?;
+/// Writes the tensor `name` described by `info` at the current position of `writer`, as
+/// [save] does for each tensor. `align` is whether the container aligns tensor data to 32
+/// bytes, which all but the legacy GGML and GGMF containers do.
+///
+/// This can append a tensor to a model, as tensors are the last part of a model file.
+pub fn write_tensor<E: Error, W: Write + Seek>(
+    writer: &mut W,
+    name: &str,
+    info: &TensorSaveInfo,
+    align: bool,
+) -> Result<(), SaveError<E>> {
+    let TensorSaveInfo {
+        n_dims,
+        dims,
+        element_type,
+        data,
+    } = info;
+
+    match element_type {
+        ElementType::Q4_0 | ElementType::Q4_1 => {
+            if dims[0] % 64 != 0 {
+                return Err(SaveError::InvariantBroken(format!("{dims:?}[0] % 64 == 0")));
+            }
         }
+        _ => {}
+    }
 
-        // Write tensor name
-        writer.write_all(name.as_bytes())?;
+    // Write tensor header
+    util::write_i32(writer, (*n_dims).try_into()?)?;
+    util::write_i32(writer, name.len().try_into()?)?;
+    util::write_u32(writer, (*element_type).into())?;
+    for &dim in &dims[0..*n_dims] {
+        util::write_i32(writer, dim.try_into()?)?;
+    }
 
-        // Align to nearest 32 bytes
-        if container_type != SaveContainerType::Ggml {
-            let offset_curr = writer.stream_position()?;
-            let offset_aligned = (offset_curr + 31) & !31;
-            let padding = usize::try_from(offset_aligned - offset_curr)?;
-            writer.write_all(&vec![0; padding])?;
-        }
+    // Write tensor name
+    writer.write_all(name.as_bytes())?;
 
-        // Write tensor data
-        writer.write_all(&data)?;
+    // Align to nearest 32 bytes
+    if align {
+        let offset_curr = writer.stream_position()?;
+        let offset_aligned = (offset_curr + 31) & !31;
+        let padding = usize::try_from(offset_aligned - offset_curr)?;
+        writer.write_all(&vec![0; padding])?;
     }
 
+    // Write tensor data
+    writer.write_all(data)?;
+
     Ok(())
 }
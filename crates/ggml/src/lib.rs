@@ -43,6 +43,9 @@ pub enum ContainerType {
     Ggjt(u32),
     /// LoRA adapter format.
     Ggla(u32),
+    /// The successor of GGJT, which describes the model with key-value metadata; see
+    /// [format::gguf]. Only little-endian GGUF files are supported.
+    Gguf(u32),
 }
 impl ContainerType {
     /// Does this container type support mmap?
@@ -52,6 +55,7 @@ impl ContainerType {
             ContainerType::Ggmf(_) => false,
             ContainerType::Ggla(_) => false,
             ContainerType::Ggjt(_) => true,
+            ContainerType::Gguf(_) => true,
         }
     }
 
@@ -59,30 +63,41 @@ impl ContainerType {
     pub fn read<E: std::error::Error>(
         reader: &mut dyn std::io::BufRead,
     ) -> Result<Self, crate::format::LoadError<E>> {
+        Self::read_with_byte_order(reader).map(|(container_type, _)| container_type)
+    }
+
+    /// Read the container type from a reader, along with the byte order of the file, which
+    /// is determined from its magic number.
+    pub fn read_with_byte_order<E: std::error::Error>(
+        reader: &mut dyn std::io::BufRead,
+    ) -> Result<(Self, format::ByteOrder), crate::format::LoadError<E>> {
         // Verify magic
         let magic = util::read_u32(reader)?;
+        let is_known = |magic| {
+            matches!(
+                magic,
+                FILE_MAGIC_GGML | FILE_MAGIC_GGMF | FILE_MAGIC_GGJT | FILE_MAGIC_GGLA
+            )
+        };
+        let (magic, byte_order) = if is_known(magic) || magic == FILE_MAGIC_GGUF {
+            (magic, format::ByteOrder::Little)
+        } else if is_known(magic.swap_bytes()) {
+            (magic.swap_bytes(), format::ByteOrder::Big)
+        } else {
+            return Err(crate::format::LoadError::InvalidMagic(format::FormatMagic(
+                magic,
+            )));
+        };
+
         let container_type: ContainerType = match magic {
-            crate::FILE_MAGIC_GGML => ContainerType::Ggml,
-            crate::FILE_MAGIC_GGMF => {
-                let version = util::read_u32(reader)?;
-                ContainerType::Ggmf(version)
-            }
-            crate::FILE_MAGIC_GGJT => {
-                let version = util::read_u32(reader)?;
-                ContainerType::Ggjt(version)
-            }
-            crate::FILE_MAGIC_GGLA => {
-                let version = util::read_u32(reader)?;
-                ContainerType::Ggla(version)
-            }
-            magic => {
-                return Err(crate::format::LoadError::InvalidMagic(format::FormatMagic(
-                    magic,
-                )))
-            }
+            crate::FILE_MAGIC_GGMF => ContainerType::Ggmf(byte_order.read_u32(reader)?),
+            crate::FILE_MAGIC_GGJT => ContainerType::Ggjt(byte_order.read_u32(reader)?),
+            crate::FILE_MAGIC_GGLA => ContainerType::Ggla(byte_order.read_u32(reader)?),
+            crate::FILE_MAGIC_GGUF => ContainerType::Gguf(byte_order.read_u32(reader)?),
+            _ => ContainerType::Ggml,
         };
 
-        Ok(container_type)
+        Ok((container_type, byte_order))
     }
 
     /// Write the container type to a writer.
@@ -103,6 +118,10 @@ impl ContainerType {
                 util::write_u32(writer, FILE_MAGIC_GGLA)?;
                 util::write_u32(writer, *version)?;
             }
+            ContainerType::Gguf(version) => {
+                util::write_u32(writer, FILE_MAGIC_GGUF)?;
+                util::write_u32(writer, *version)?;
+            }
         }
         Ok(())
     }
@@ -116,6 +135,8 @@ pub const FILE_MAGIC_GGMF: u32 = 0x67676d66;
 pub const FILE_MAGIC_GGJT: u32 = 0x67676a74;
 /// Magic constant for `ggla` files (LoRA adapter).
 pub const FILE_MAGIC_GGLA: u32 = 0x67676C61;
+/// Magic constant for `gguf` files.
+pub const FILE_MAGIC_GGUF: u32 = 0x46554747;
 
 /// The current quantization version.
 pub const QNT_VERSION: u32 = sys::GGML_QNT_VERSION;
@@ -128,6 +149,9 @@ pub const OBJECT_SIZE: usize = sys::GGML_OBJECT_SIZE;
 /// The maximum length of a `ggml` tensor-name.
 pub const MAX_NAME_LENGTH: usize = sys::GGML_MAX_NAME as usize;
 
+/// The maximum number of dimensions a tensor can have.
+pub const MAX_DIMS: usize = sys::GGML_MAX_DIMS as usize;
+
 /// Default epsilon to use for RMS computation.
 pub const DEFAULT_EPS: f32 = sys::llama::LLAMA_DEFAULT_RMS_EPS as f32;
 
@@ -482,6 +506,32 @@ fn quantize_impl(
     QuantizationResult { output, history }
 }
 
+/// Converts `n_elements` elements of `element_type` in `src` back to `f32`.
+///
+/// Returns `None` if `element_type` cannot be converted to `f32`.
+///
+/// # Panics
+///
+/// - If `n_elements` is not a multiple of the [block size](blck_size) of `element_type`.
+/// - If `src` is shorter than `n_elements` elements of `element_type`.
+pub fn dequantize(element_type: Type, src: &[u8], n_elements: usize) -> Option<Vec<f32>> {
+    let to_float = unsafe { sys::ggml_internal_get_type_traits(element_type.into()) }.to_float?;
+
+    let block_size = blck_size(element_type);
+    assert_eq!(n_elements % block_size, 0);
+    assert!(src.len() >= n_elements / block_size * type_size(element_type));
+
+    let mut output = vec![0f32; n_elements];
+    unsafe {
+        to_float(
+            src.as_ptr() as *const c_void,
+            output.as_mut_ptr(),
+            usize_to_i32(n_elements),
+        )
+    };
+    Some(output)
+}
+
 /// Returns true if the current system has BLAS support.
 pub fn cpu_has_blas() -> bool {
     unsafe { sys::ggml_cpu_has_blas() != 0 }
@@ -492,6 +542,27 @@ pub fn cpu_has_gpublas() -> bool {
     unsafe { sys::ggml_cpu_has_gpublas() != 0 }
 }
 
+/// Returns true if ggml was built with its ARM NEON kernels.
+pub fn cpu_has_neon() -> bool {
+    unsafe { sys::ggml_cpu_has_neon() != 0 }
+}
+
+/// Returns true if ggml was built to use ARM fused multiply-add instructions.
+pub fn cpu_has_arm_fma() -> bool {
+    unsafe { sys::ggml_cpu_has_arm_fma() != 0 }
+}
+
+/// Returns true if ggml was built to use ARM half-precision vector arithmetic
+/// (ARMv8.2-A FP16).
+pub fn cpu_has_fp16_va() -> bool {
+    unsafe { sys::ggml_cpu_has_fp16_va() != 0 }
+}
+
+/// Returns true if ggml was built with its WebAssembly SIMD kernels.
+pub fn cpu_has_wasm_simd() -> bool {
+    unsafe { sys::ggml_cpu_has_wasm_simd() != 0 }
+}
+
 /// Returns the graph overhead in bytes.
 pub fn graph_overhead() -> usize {
     unsafe { sys::ggml_graph_overhead() }
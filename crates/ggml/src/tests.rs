@@ -5,6 +5,12 @@ use std::{
 };
 
 use crate::*;
+use proptest::{
+    arbitrary::any,
+    collection, prop_assert, prop_assert_eq, prop_oneof, proptest, sample,
+    strategy::{Just, Strategy},
+    test_runner::TestCaseError,
+};
 use rand::{distributions::Uniform, prelude::*};
 
 #[derive(Debug)]
@@ -57,13 +63,383 @@ fn can_roundtrip_loader_and_saver_ggjt_v3() {
     roundtrip_test(format::SaveContainerType::GgjtV3, tokenizer).unwrap();
 }
 
+#[test]
+fn will_fail_on_truncated_tensor_data() {
+    let model = Model {
+        hyperparameters: Hyperparameters::default(),
+        tokenizer: vec![],
+        tensors: BTreeMap::from([(
+            "tensor".to_string(),
+            format::TensorSaveInfo {
+                n_dims: 2,
+                dims: [4, 4],
+                element_type: crate::Type::F32,
+                data: vec![0; 4 * 4 * 4],
+            },
+        )]),
+    };
+    let mut buffer = Vec::new();
+    format::save(
+        &mut std::io::Cursor::new(&mut buffer),
+        &mut MockSaveHandler { model: &model },
+        format::SaveContainerType::GgjtV3,
+        &model.tokenizer,
+        &["tensor".to_string()],
+    )
+    .unwrap();
+    buffer.truncate(buffer.len() - 1);
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        skipped_tensors: None,
+        expected_container_type: ContainerType::Ggjt(3),
+    };
+    let err = format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap_err();
+    assert!(
+        matches!(err, format::LoadError::InvariantBroken(_)),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn can_skip_unsupported_tensors() {
+    let supported = format::TensorSaveInfo {
+        n_dims: 2,
+        dims: [4, 4],
+        element_type: crate::Type::F32,
+        data: vec![0; 4 * 4 * 4],
+    };
+    let model = Model {
+        hyperparameters: Hyperparameters::default(),
+        tokenizer: vec![],
+        tensors: BTreeMap::from([
+            ("supported".to_string(), supported.clone()),
+            (
+                // Rows must be a whole number of blocks of 32 elements long.
+                "unsupported".to_string(),
+                format::TensorSaveInfo {
+                    n_dims: 2,
+                    dims: [16, 2],
+                    element_type: crate::Type::Q8_0,
+                    data: vec![0; crate::type_size(crate::Type::Q8_0)],
+                },
+            ),
+        ]),
+    };
+    let mut buffer = Vec::new();
+    format::save(
+        &mut std::io::Cursor::new(&mut buffer),
+        &mut MockSaveHandler { model: &model },
+        format::SaveContainerType::GgjtV3,
+        &model.tokenizer,
+        &["unsupported".to_string(), "supported".to_string()],
+    )
+    .unwrap();
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: ContainerType::Ggjt(3),
+        skipped_tensors: None,
+    };
+    let err = format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap_err();
+    assert!(
+        matches!(err, format::LoadError::InvariantBroken(_)),
+        "{err:?}"
+    );
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: ContainerType::Ggjt(3),
+        skipped_tensors: Some(vec![]),
+    };
+    format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap();
+    assert_eq!(
+        load_handler.skipped_tensors,
+        Some(vec!["unsupported".to_string()])
+    );
+    assert_eq!(
+        load_handler.loaded_model.tensors,
+        BTreeMap::from([("supported".to_string(), supported)])
+    );
+}
+
+#[test]
+fn can_load_big_endian_files() {
+    let values = [1.5f32, -2.0];
+
+    // A GGJTv3 file written on a big-endian machine, by hand.
+    let mut buffer = Vec::new();
+    let be = |buffer: &mut Vec<u8>, value: u32| buffer.extend(value.to_be_bytes());
+    be(&mut buffer, crate::FILE_MAGIC_GGJT);
+    be(&mut buffer, 3);
+    // some_hyperparameter, some_other_hyperparameter, tokenizer_size
+    for value in [1, 2, 1] {
+        be(&mut buffer, value);
+    }
+    be(&mut buffer, 4);
+    buffer.extend(b"fast");
+    be(&mut buffer, 0.5f32.to_bits());
+    // n_dims, name_len, ftype, dims
+    for value in [1, 6, u32::from(Type::F32), 2] {
+        be(&mut buffer, value);
+    }
+    buffer.extend(b"tensor");
+    buffer.resize((buffer.len() + 31) & !31, 0);
+    for value in values {
+        be(&mut buffer, value.to_bits());
+    }
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        expected_container_type: ContainerType::Ggjt(3),
+        skipped_tensors: None,
+    };
+    format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap();
+
+    let model = load_handler.loaded_model;
+    assert_eq!(
+        model.hyperparameters,
+        Hyperparameters {
+            some_hyperparameter: 1,
+            some_other_hyperparameter: 2,
+            tokenizer_size: 1,
+        }
+    );
+    assert_eq!(model.tokenizer, vec![(b"fast".to_vec(), 0.5)]);
+
+    // The data is read in the file's byte order, and converted separately.
+    let mut data = model.tensors["tensor"].data.clone();
+    if format::ByteOrder::NATIVE == format::ByteOrder::Little {
+        format::swap_byte_order(Type::F32, &mut data);
+    }
+    let native: Vec<f32> = data
+        .chunks_exact(4)
+        .map(|chunk| f32::from_ne_bytes(chunk.try_into().unwrap()))
+        .collect();
+    assert_eq!(native, values);
+}
+
+#[test]
+fn can_swap_byte_order_of_quantized_blocks() {
+    // A Q4_1 block: `d` and `m` as f16, then 16 bytes of quants.
+    let mut block: Vec<u8> = (0..type_size(Type::Q4_1) as u8).collect();
+    format::swap_byte_order(Type::Q4_1, &mut block);
+    assert_eq!(&block[..4], &[1, 0, 3, 2]);
+    assert_eq!(&block[4..], (4..20).collect::<Vec<u8>>());
+
+    assert!(!format::can_swap_byte_order(Type::Q4_K));
+}
+
+#[test]
+fn will_fail_on_oversized_token_length() {
+    let mut buffer = Vec::new();
+    ContainerType::Ggjt(3).write(&mut buffer).unwrap();
+    Hyperparameters {
+        tokenizer_size: 1,
+        ..Default::default()
+    }
+    .write(&mut buffer)
+    .unwrap();
+    // A token that claims to be 4 GiB long, in a file of a few bytes.
+    util::write_u32(&mut buffer, u32::MAX).unwrap();
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        skipped_tensors: None,
+        expected_container_type: ContainerType::Ggjt(3),
+    };
+    let err = format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap_err();
+    assert!(matches!(err, format::LoadError::Io(_)), "{err:?}");
+}
+
+#[test]
+fn can_roundtrip_loader_and_saver_gguf() {
+    let tokenizer = vec![
+        ("<s>".as_bytes().to_vec(), 0.0),
+        ("blazingly".as_bytes().to_vec(), 0.1),
+        // Not valid UTF-8 on its own, so it is stored as a byte token.
+        (vec![0xE2, 0x96], 0.2),
+        ("fast".as_bytes().to_vec(), 0.3),
+    ];
+    let model = random_model(tokenizer).unwrap();
+
+    let mut hyperparameters = vec![];
+    model.hyperparameters.write(&mut hyperparameters).unwrap();
+    let token_types = [
+        format::gguf::TokenType::Control,
+        format::gguf::TokenType::Normal,
+        format::gguf::TokenType::Normal,
+        format::gguf::TokenType::Normal,
+    ];
+    let mut metadata = format::gguf::vocabulary_metadata(
+        hyperparameters,
+        &model.tokenizer,
+        Some(&token_types[..]),
+    );
+    metadata.insert(
+        format::gguf::MERGES_KEY,
+        format::gguf::MetadataValue::Array(
+            format::gguf::MetadataValueType::String,
+            vec![format::gguf::MetadataValue::String("fa st".to_string())],
+        ),
+    );
+    let tensor_infos = model
+        .tensors
+        .iter()
+        .map(|(name, info)| format::gguf::TensorInfo {
+            name: name.clone(),
+            dims: info.dims[..info.n_dims].to_vec(),
+            element_type: info.element_type,
+            offset: 0,
+        })
+        .collect();
+
+    let mut buffer = Vec::new();
+    format::gguf::save_gguf(
+        &mut std::io::Cursor::new(&mut buffer),
+        &mut MockSaveHandler { model: &model },
+        metadata.clone(),
+        tensor_infos,
+    )
+    .unwrap();
+
+    // The header is read back as it was written, with the tensors aligned after it.
+    let header = format::gguf::Gguf::read::<DummyError>(&mut buffer.as_slice()).unwrap();
+    assert_eq!(header.metadata, metadata);
+    assert_eq!(header.tensor_data_position() % 32, 0);
+    for info in &header.tensor_infos {
+        assert_eq!(info.offset % 32, 0);
+    }
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        skipped_tensors: None,
+        expected_container_type: ContainerType::Gguf(3),
+    };
+    format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap();
+    assert_eq!(load_handler.loaded_model, model);
+}
+
+#[test]
+fn will_fail_on_gguf_without_hyperparameters() {
+    let header = format::gguf::Gguf {
+        metadata: format::gguf::Metadata::default(),
+        tensor_infos: vec![],
+    };
+    let mut buffer = Vec::new();
+    header.write(&mut buffer).unwrap();
+
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        skipped_tensors: None,
+        expected_container_type: ContainerType::Gguf(3),
+    };
+    let err = format::load(&mut std::io::Cursor::new(&buffer), &mut load_handler).unwrap_err();
+    assert!(
+        matches!(err, format::LoadError::InvariantBroken(_)),
+        "{err:?}"
+    );
+}
+
+#[test]
+fn can_parse_displayed_gguf_metadata_values() {
+    use format::gguf::{MetadataValue, MetadataValueType};
+
+    for value in [
+        MetadataValue::U32(4096),
+        MetadataValue::I8(-3),
+        MetadataValue::F32(0.5),
+        MetadataValue::Bool(true),
+        MetadataValue::String("{{ messages }}".to_string()),
+    ] {
+        let text = value.to_string();
+        assert_eq!(MetadataValue::parse(value.value_type(), &text), Some(value));
+    }
+    assert_eq!(MetadataValue::parse(MetadataValueType::U8, "256"), None);
+    assert_eq!(MetadataValue::parse(MetadataValueType::Array, "[]"), None);
+    assert_eq!(
+        MetadataValue::Array(
+            MetadataValueType::String,
+            vec![
+                MetadataValue::String("a".to_string()),
+                MetadataValue::String("b c".to_string())
+            ]
+        )
+        .to_string(),
+        r#"["a", "b c"]"#
+    );
+}
+
+#[test]
+fn can_read_gguf_special_token_ids() {
+    use format::gguf::{self, Metadata, MetadataValue, TokenType};
+
+    let vocabulary: Vec<_> = ["<s>", "</s>", "a", "<|im_end|>"]
+        .iter()
+        .map(|token| (token.as_bytes().to_vec(), 0.0))
+        .collect();
+    let types = [
+        TokenType::Control,
+        TokenType::Control,
+        TokenType::Normal,
+        TokenType::Normal,
+    ];
+    let mut metadata = gguf::vocabulary_metadata(vec![], &vocabulary, Some(&types));
+    assert_eq!(gguf::special_token_ids(&metadata), Some(vec![0, 1]));
+
+    // The special token IDs are special tokens, even if they are not control tokens.
+    metadata.insert(gguf::EOS_TOKEN_ID_KEY, MetadataValue::U32(3));
+    assert_eq!(gguf::special_token_ids(&metadata), Some(vec![0, 1, 3]));
+
+    assert_eq!(gguf::special_token_ids(&Metadata::default()), None);
+    let metadata = gguf::vocabulary_metadata(vec![], &vocabulary, None);
+    assert_eq!(gguf::special_token_ids(&metadata), None);
+}
+
 fn roundtrip_test(
     save_container_type: format::SaveContainerType,
     tokenizer: Vec<(Vec<u8>, f32)>,
 ) -> anyhow::Result<()> {
+    let model = random_model(tokenizer)?;
+
+    // Save the model.
+    let mut buffer = Vec::new();
+    let mut cursor = std::io::Cursor::new(&mut buffer);
+    let mut save_handler = MockSaveHandler { model: &model };
+    format::save(
+        &mut cursor,
+        &mut save_handler,
+        save_container_type,
+        &model.tokenizer,
+        &model.tensors.keys().cloned().collect::<Vec<String>>(),
+    )?;
+
+    // Load the model and confirm that it is the same as the original.
+    let mut cursor = std::io::Cursor::new(&buffer);
+    let mut load_handler = MockLoadHandler {
+        data: &buffer,
+        loaded_model: Model::default(),
+        skipped_tensors: None,
+        expected_container_type: save_container_type.into(),
+    };
+    format::load(&mut cursor, &mut load_handler)?;
+    assert_eq!(load_handler.loaded_model, model);
+
+    Ok(())
+}
+
+/// Creates a model with the `tokenizer` and random hyperparameters and tensors.
+fn random_model(tokenizer: Vec<(Vec<u8>, f32)>) -> anyhow::Result<Model> {
     let mut rng = rand::thread_rng();
     let element_type = crate::Type::F16;
-    let model = Model {
+    Ok(Model {
         hyperparameters: Hyperparameters {
             some_hyperparameter: random(),
             some_other_hyperparameter: random(),
@@ -94,31 +470,7 @@ fn roundtrip_test(
                 )
             })
             .collect(),
-    };
-
-    // Save the model.
-    let mut buffer = Vec::new();
-    let mut cursor = std::io::Cursor::new(&mut buffer);
-    let mut save_handler = MockSaveHandler { model: &model };
-    format::save(
-        &mut cursor,
-        &mut save_handler,
-        save_container_type,
-        &model.tokenizer,
-        &model.tensors.keys().cloned().collect::<Vec<String>>(),
-    )?;
-
-    // Load the model and confirm that it is the same as the original.
-    let mut cursor = std::io::Cursor::new(&buffer);
-    let mut load_handler = MockLoadHandler {
-        data: &buffer,
-        loaded_model: Model::default(),
-        expected_container_type: save_container_type.into(),
-    };
-    format::load(&mut cursor, &mut load_handler)?;
-    assert_eq!(load_handler.loaded_model, model);
-
-    Ok(())
+    })
 }
 
 #[derive(Default, PartialEq, Debug)]
@@ -173,6 +525,8 @@ struct MockLoadHandler<'a> {
     data: &'a [u8],
     loaded_model: Model,
     expected_container_type: ContainerType,
+    /// The tensors that were skipped, if unsupported tensors are to be skipped.
+    skipped_tensors: Option<Vec<String>>,
 }
 impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
     fn container_type(&mut self, container_type: ContainerType) -> Result<(), DummyError> {
@@ -213,4 +567,172 @@ impl format::LoadHandler<DummyError> for MockLoadHandler<'_> {
         self.loaded_model.tensors.insert(info.name, data);
         Ok(())
     }
+
+    fn skip_unsupported_tensor(&mut self, tensor_name: &str, _reason: &str) -> bool {
+        match &mut self.skipped_tensors {
+            Some(skipped_tensors) => {
+                skipped_tensors.push(tensor_name.to_owned());
+                true
+            }
+            None => false,
+        }
+    }
+}
+
+/// The block size of all of the quantized types below.
+const QK: usize = 32;
+const QUANTIZED_TYPES: [Type; 5] = [Type::Q4_0, Type::Q4_1, Type::Q5_0, Type::Q5_1, Type::Q8_0];
+
+proptest! {
+    #[test]
+    fn can_roundtrip_quantization_with_bounded_error(
+        element_type in sample::select(QUANTIZED_TYPES.to_vec()),
+        values in blocks(-1000.0f32..1000.0),
+    ) {
+        check_roundtrip_error(element_type, &values)?;
+    }
+
+    #[test]
+    fn can_roundtrip_quantization_of_edge_cases_with_bounded_error(
+        element_type in sample::select(QUANTIZED_TYPES.to_vec()),
+        values in blocks(edge_case_value()),
+    ) {
+        check_roundtrip_error(element_type, &values)?;
+    }
+
+    #[test]
+    fn will_not_spread_non_finite_values_across_blocks(
+        element_type in sample::select(QUANTIZED_TYPES.to_vec()),
+        values in blocks(-1000.0f32..1000.0),
+        non_finite in sample::select(vec![f32::NAN, f32::INFINITY, f32::NEG_INFINITY]),
+        index in any::<sample::Index>(),
+    ) {
+        let expected = quantize_roundtrip(element_type, &values);
+
+        // Only the block containing the non-finite value may be affected.
+        let index = index.index(values.len());
+        let mut values = values;
+        values[index] = non_finite;
+        let actual = quantize_roundtrip(element_type, &values);
+
+        let poisoned_block = index / QK;
+        for (i, (expected, actual)) in expected.chunks(QK).zip(actual.chunks(QK)).enumerate() {
+            if i != poisoned_block {
+                prop_assert_eq!(expected, actual, "block {} of {:?} changed", i, element_type);
+            }
+        }
+    }
+}
+
+#[test]
+fn can_roundtrip_all_zero_blocks_exactly() {
+    for element_type in QUANTIZED_TYPES {
+        for zero in [0.0, -0.0] {
+            let output = quantize_roundtrip(element_type, &[zero; QK * 4]);
+            assert!(
+                output.iter().all(|&y| y == 0.0),
+                "{element_type:?}: {output:?}"
+            );
+        }
+    }
+}
+
+#[test]
+fn records_the_peak_scratch_usage() {
+    let context = Context::new_with_allocate(1024 * 1024);
+    let scratch = [Buffer::new(4096), Buffer::new(4096)];
+    assert_eq!(context.scratch_peak(), 0);
+
+    context.use_scratch(Some(&scratch[0]));
+    context.new_tensor_1d(Type::F32, 16);
+    context.use_scratch(Some(&scratch[1]));
+    context.new_tensor_1d(Type::F32, 256);
+    context.use_scratch(None);
+    assert!(context.scratch_peak() >= 256 * 4);
+    assert!(context.scratch_peak() < 4096);
+
+    context.use_scratch(Some(&scratch[0]));
+    context.use_scratch(None);
+    assert!(context.scratch_peak() >= 256 * 4);
+}
+
+/// Rows of one to eight blocks of values drawn from `value`.
+fn blocks(value: impl Strategy<Value = f32> + Clone) -> impl Strategy<Value = Vec<f32>> {
+    (1..=8usize).prop_flat_map(move |n_blocks| collection::vec(value.clone(), n_blocks * QK))
+}
+
+/// Zeros, denormals, the extremes of the quantization grid, and ordinary values.
+fn edge_case_value() -> impl Strategy<Value = f32> + Clone {
+    prop_oneof![
+        Just(0.0f32),
+        Just(-0.0f32),
+        (-1.0f32..1.0).prop_map(|x| x * f32::MIN_POSITIVE),
+        Just(f32::MIN_POSITIVE),
+        Just(1.0f32),
+        Just(-1.0f32),
+        -1000.0f32..1000.0,
+    ]
+}
+
+fn quantize_roundtrip(element_type: Type, values: &[f32]) -> Vec<f32> {
+    let quantize = match element_type {
+        Type::Q4_0 => quantize_q4_0,
+        Type::Q4_1 => quantize_q4_1,
+        Type::Q5_0 => quantize_q5_0,
+        Type::Q5_1 => quantize_q5_1,
+        Type::Q8_0 => quantize_q8_0,
+        _ => unreachable!("{element_type:?} is not quantized"),
+    };
+    let quantized = quantize(values, values.len(), values.len());
+    assert_eq!(
+        quantized.output.len(),
+        values.len() / QK * type_size(element_type)
+    );
+
+    dequantize(element_type, &quantized.output, values.len()).unwrap()
+}
+
+fn check_roundtrip_error(element_type: Type, values: &[f32]) -> Result<(), TestCaseError> {
+    let output = quantize_roundtrip(element_type, values);
+    for (block, output_block) in values.chunks(QK).zip(output.chunks(QK)) {
+        let max_error = max_roundtrip_error(element_type, block);
+        for (x, y) in block.iter().zip(output_block) {
+            prop_assert!(
+                (x - y).abs() <= max_error,
+                "{:?}: {} became {} (maximum error {})",
+                element_type,
+                x,
+                y,
+                max_error
+            );
+        }
+    }
+    Ok(())
+}
+
+/// The largest error that quantizing `block` to `element_type` and back may introduce.
+fn max_roundtrip_error(element_type: Type, block: &[f32]) -> f32 {
+    let (min, max) = block
+        .iter()
+        .fold((f32::INFINITY, f32::NEG_INFINITY), |(min, max), &x| {
+            (min.min(x), max.max(x))
+        });
+    let abs_max = min.abs().max(max.abs());
+    let range = max - min;
+
+    let step_error = match element_type {
+        // The scale maps the value with the largest magnitude to -8 (or -16), so a value of
+        // the same magnitude and opposite sign is clamped, and may be off by a whole step.
+        Type::Q4_0 => abs_max / 8.0,
+        Type::Q5_0 => abs_max / 16.0,
+        // The other types round to the nearest step.
+        Type::Q4_1 => range / 30.0,
+        Type::Q5_1 => range / 62.0,
+        Type::Q8_0 => abs_max / 254.0,
+        _ => unreachable!("{element_type:?} is not quantized"),
+    };
+
+    // The scales (and minimums) are stored as f16, which adds a small relative error,
+    // and flushes the scales of blocks of tiny values to zero.
+    step_error + abs_max / 512.0 + 1e-5
 }
@@ -29,10 +29,15 @@ fn main() {
         enable_cublas(build, &out_dir);
     } else if cfg_clblast() {
         enable_clblast(build);
-    } else if cfg!(target_os = "macos") {
-        if cfg_metal() {
+    } else if target_os == "macos" || target_os == "ios" {
+        // This checks the target rather than the host, so that cross-compiling from macOS
+        // (e.g. for Android) does not link Accelerate.
+        if cfg_metal() && target_os == "macos" {
             enable_metal(build, &out_dir);
         } else {
+            if cfg_metal() {
+                println!("cargo:warning=Metal is only supported on macOS; using Accelerate");
+            }
             println!("cargo:rustc-link-lib=framework=Accelerate");
 
             build.define("GGML_USE_ACCELERATE", None);
@@ -80,10 +85,22 @@ fn main() {
                 } else if std::env::var("HOST") == std::env::var("TARGET") {
                     build.flag("-mcpu=native");
                     build.flag("-mfpu=neon");
+                } else {
+                    // When cross-compiling (e.g. for Android), NEON is always available, but
+                    // the half-precision and dot product kernels need the target features
+                    // they use to be enabled, e.g. with `-C target-feature=+fp16,+dotprod`.
+                    build.flag(&aarch64::Features::get_target().march());
                 }
                 build.flag("-pthread");
             }
         }
+        "wasm32" => {
+            // ggml's WebAssembly SIMD kernels are only compiled when the Rust target
+            // feature is enabled, e.g. with `-C target-feature=+simd128`.
+            if get_supported_target_features().contains("simd128") {
+                build.flag("-msimd128");
+            }
+        }
         _ => {}
     }
 
@@ -439,3 +456,36 @@ mod x86 {
         }
     }
 }
+
+mod aarch64 {
+    #[derive(Clone, Debug, PartialEq, Eq)]
+    pub struct Features {
+        pub fp16: bool,
+        pub dotprod: bool,
+    }
+    impl Features {
+        pub fn get_target() -> Self {
+            let features = crate::get_supported_target_features();
+            Self {
+                fp16: features.contains("fp16"),
+                dotprod: features.contains("dotprod"),
+            }
+        }
+
+        /// The `-march` flag that enables the features.
+        pub fn march(&self) -> String {
+            let mut march = if self.fp16 || self.dotprod {
+                "-march=armv8.2-a".to_string()
+            } else {
+                "-march=armv8-a".to_string()
+            };
+            if self.fp16 {
+                march.push_str("+fp16");
+            }
+            if self.dotprod {
+                march.push_str("+dotprod");
+            }
+            march
+        }
+    }
+}
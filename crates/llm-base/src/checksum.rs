@@ -0,0 +1,157 @@
+//! Implements per-tensor checksums, which are stored next to a model so that a corrupted or
+//! partially-downloaded model can be detected when it is loaded.
+//!
+//! The checksums are stored in a text file named after the model with a `.checksums`
+//! extension appended (e.g. `model.bin.checksums`). Each line holds the CRC-32 of a
+//! tensor's data as eight hexadecimal digits, followed by two spaces and the tensor's name.
+//!
+//! When a model is loaded with its checksums verified, the data of each tensor is checked as
+//! it is read, so the model is not read twice.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+use ggml::format::TensorLoadInfo;
+
+use crate::{Hyperparameters, LoadError, ModelFile, TensorNameMapping, TokenizerSource};
+
+/// The CRC-32 checksums of the data of each tensor in a model, by tensor name.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct TensorChecksums(pub BTreeMap<String, u32>);
+impl TensorChecksums {
+    /// Computes the checksums of the tensors in `model_file`.
+    pub fn compute<Hp: Hyperparameters>(model_file: &ModelFile<Hp>) -> Result<Self, LoadError> {
+        model_file
+            .tensors
+            .keys()
+            .map(|name| {
+                Ok((
+                    name.clone(),
+                    crc32fast::hash(&model_file.read_tensor(name)?),
+                ))
+            })
+            .collect::<Result<_, LoadError>>()
+            .map(Self)
+    }
+
+    /// Reads the checksums stored for the model at `model_path`. If there are none next to
+    /// it, the checksums next to the model without its last extension are read instead, so
+    /// that a compressed `model.bin.zst` is verified with the checksums of `model.bin`.
+    pub fn read(model_path: &Path) -> Result<Self, LoadError> {
+        let path = stored_checksums_path(model_path);
+        let contents = fs::read_to_string(&path).map_err(|source| LoadError::OpenFileFailed {
+            source,
+            path: path.clone(),
+        })?;
+
+        contents
+            .lines()
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                line.split_once("  ")
+                    .and_then(|(checksum, name)| {
+                        Some((name.to_owned(), u32::from_str_radix(checksum, 16).ok()?))
+                    })
+                    .ok_or_else(|| LoadError::InvariantBroken {
+                        path: Some(path.clone()),
+                        invariant: format!("invalid checksum line {line:?}"),
+                    })
+            })
+            .collect::<Result<_, LoadError>>()
+            .map(Self)
+    }
+
+    /// Writes the checksums next to the model at `model_path`.
+    pub fn write(&self, model_path: &Path) -> std::io::Result<()> {
+        let contents: String = self
+            .0
+            .iter()
+            .map(|(name, checksum)| format!("{checksum:08x}  {name}\n"))
+            .collect();
+        fs::write(checksums_path(model_path), contents)
+    }
+}
+
+/// Returns the path that the checksums of the model at `model_path` are stored at.
+pub fn checksums_path(model_path: &Path) -> PathBuf {
+    let mut path = model_path.as_os_str().to_owned();
+    path.push(".checksums");
+    PathBuf::from(path)
+}
+
+/// Computes the checksums of the tensors of the model at `model_path`, and writes them next
+/// to it so that they can be verified when the model is loaded.
+pub fn write_checksums<Hp: Hyperparameters>(
+    model_path: &Path,
+) -> Result<TensorChecksums, LoadError> {
+    let model_file = ModelFile::<Hp>::open(model_path, TokenizerSource::Embedded)?;
+    let checksums = TensorChecksums::compute(&model_file)?;
+    checksums.write(model_path)?;
+    Ok(checksums)
+}
+
+/// Returns the path of the checksums that are stored for the model at `model_path`: next to
+/// it, or next to the model without its last extension if only those exist.
+fn stored_checksums_path(model_path: &Path) -> PathBuf {
+    let path = checksums_path(model_path);
+    if path.exists() || model_path.extension().is_none() {
+        return path;
+    }
+    let fallback = checksums_path(&model_path.with_extension(""));
+    if fallback.exists() {
+        fallback
+    } else {
+        path
+    }
+}
+
+/// Reads the checksums stored for the model at `path` to verify the data of its `tensors`
+/// against as it is read, failing if there are checksums for tensors that the model does not
+/// have. If the tensors are renamed per `mapping` when they are loaded, so are the checksums.
+pub(crate) fn read_for_tensors(
+    path: &Path,
+    tensors: &HashMap<String, TensorLoadInfo>,
+    mapping: Option<&TensorNameMapping>,
+) -> Result<TensorChecksums, LoadError> {
+    let checksums = TensorChecksums::read(path)?;
+    if let Some(tensor_name) = checksums.0.keys().find(|name| !tensors.contains_key(*name)) {
+        return Err(LoadError::UnknownTensor {
+            tensor_name: tensor_name.clone(),
+            path: path.to_owned(),
+        });
+    }
+
+    Ok(match mapping {
+        Some(mapping) => TensorChecksums(
+            checksums
+                .0
+                .into_iter()
+                .map(|(name, checksum)| (mapping.map(&name).into_owned(), checksum))
+                .collect(),
+        ),
+        None => checksums,
+    })
+}
+
+/// Verifies `data`, the data of the tensor `name` of the model at `path` as it is stored in
+/// the file, against its checksum in `checksums`.
+pub(crate) fn verify_tensor(
+    checksums: &TensorChecksums,
+    name: &str,
+    data: &[u8],
+    path: &Path,
+) -> Result<(), LoadError> {
+    let actual = crc32fast::hash(data);
+    match checksums.0.get(name) {
+        Some(&expected) if expected == actual => Ok(()),
+        expected => Err(LoadError::TensorChecksumMismatch {
+            tensor_name: name.to_owned(),
+            expected: expected.copied(),
+            actual,
+            path: path.to_owned(),
+        }),
+    }
+}
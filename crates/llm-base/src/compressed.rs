@@ -0,0 +1,338 @@
+//! Implements a zstd-compressed container for model files, which `load` reads transparently.
+//!
+//! The container compresses the model file in frames: one for each tensor's data, and one
+//! for each run of header data between them. An index at the start of the container maps
+//! the frames to their ranges in the model file, so that reading a tensor only
+//! decompresses that tensor, and reading the header does not decompress any tensors.
+//!
+//! The layout is: the magic `GGZS`, the version and the number of frames as little-endian
+//! `u32`s, then for each frame, its offset and length in the model file and its offset
+//! and length in the container as little-endian `u64`s, followed by the frames.
+
+use std::{
+    collections::BTreeSet,
+    fs::File,
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use thiserror::Error;
+
+use crate::{util, Hyperparameters, LoadError, ModelFile, TokenizerSource};
+
+/// The magic at the start of a compressed model container.
+pub const COMPRESSED_MAGIC: [u8; 4] = *b"GGZS";
+const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+/// Errors encountered while packing or unpacking a compressed model container.
+pub enum PackError {
+    #[error("could not load model")]
+    /// There was an error while attempting to read the model.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error, which includes frames that cannot be decompressed.
+    Io(#[from] io::Error),
+}
+
+/// The sizes of a model before and after [pack]ing it.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PackStats {
+    /// The size of the model file, in bytes.
+    pub original_size: u64,
+    /// The size of the compressed container, in bytes.
+    pub packed_size: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Frame {
+    offset: u64,
+    len: u64,
+    compressed_offset: u64,
+    compressed_len: u64,
+}
+impl Frame {
+    const SIZE: usize = 4 * std::mem::size_of::<u64>();
+}
+
+/// Compresses the model at `source` into a container written to `destination`, at the
+/// given zstd compression `level` (1 to 22).
+pub fn pack<Hp: Hyperparameters>(
+    source: &Path,
+    destination: &mut (impl Write + Seek),
+    level: i32,
+) -> Result<PackStats, PackError> {
+    let model_file = ModelFile::<Hp>::open(source, TokenizerSource::Embedded)?;
+    let original_size = std::fs::metadata(source)?.len();
+
+    let mut boundaries = BTreeSet::from([0, original_size]);
+    for info in model_file.tensors.values() {
+        boundaries.insert(info.start_offset);
+        boundaries.insert(info.start_offset + info.calc_size() as u64);
+    }
+    let boundaries: Vec<u64> = boundaries
+        .into_iter()
+        .filter(|b| *b <= original_size)
+        .collect();
+
+    let mut frames: Vec<Frame> = boundaries
+        .windows(2)
+        .map(|range| Frame {
+            offset: range[0],
+            len: range[1] - range[0],
+            compressed_offset: 0,
+            compressed_len: 0,
+        })
+        .collect();
+
+    destination.write_all(&COMPRESSED_MAGIC)?;
+    destination.write_all(&VERSION.to_le_bytes())?;
+    destination.write_all(&(frames.len() as u32).to_le_bytes())?;
+    // The index is written once the frames have been compressed.
+    let index_start = destination.stream_position()?;
+    destination.write_all(&vec![0; frames.len() * Frame::SIZE])?;
+
+    let mut reader = BufReader::new(File::open(source)?);
+    for frame in &mut frames {
+        let mut data = vec![0; frame.len as usize];
+        reader.seek(SeekFrom::Start(frame.offset))?;
+        reader.read_exact(&mut data)?;
+        let compressed = zstd::bulk::compress(&data, level)?;
+
+        frame.compressed_offset = destination.stream_position()?;
+        frame.compressed_len = compressed.len() as u64;
+        destination.write_all(&compressed)?;
+    }
+    let packed_size = destination.stream_position()?;
+
+    destination.seek(SeekFrom::Start(index_start))?;
+    for frame in &frames {
+        for value in [
+            frame.offset,
+            frame.len,
+            frame.compressed_offset,
+            frame.compressed_len,
+        ] {
+            destination.write_all(&value.to_le_bytes())?;
+        }
+    }
+    destination.seek(SeekFrom::Start(packed_size))?;
+    destination.flush()?;
+
+    Ok(PackStats {
+        original_size,
+        packed_size,
+    })
+}
+
+/// Decompresses the container in `source` into the original model file, written to
+/// `destination`. Returns the size of the model file.
+pub fn unpack(source: impl Read + Seek, destination: &mut impl Write) -> Result<u64, PackError> {
+    let mut reader = CompressedReader::new(source)?;
+    let len = io::copy(&mut reader, destination)?;
+    destination.flush()?;
+    Ok(len)
+}
+
+/// Whether the file at `path` is a compressed model container.
+pub fn is_compressed(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; 4];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == COMPRESSED_MAGIC),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the model file in a compressed container, decompressing each frame as it is read.
+pub struct CompressedReader<R> {
+    inner: R,
+    frames: Vec<Frame>,
+    len: u64,
+    position: u64,
+    /// The index of the frame that was decompressed last, and its data.
+    current: Option<(usize, Vec<u8>)>,
+}
+impl<R: Read + Seek> CompressedReader<R> {
+    /// Reads the index of the container in `inner`.
+    ///
+    /// The index is checked against the length of the container, so that an invalid
+    /// container is rejected instead of causing huge allocations or overflows.
+    pub fn new(mut inner: R) -> Result<Self, LoadError> {
+        let invalid = |reason: &str| LoadError::InvalidCompressedContainer {
+            reason: reason.to_owned(),
+        };
+
+        let container_len = inner.seek(SeekFrom::End(0))?;
+        inner.seek(SeekFrom::Start(0))?;
+        let mut header = [0; 12];
+        inner.read_exact(&mut header)?;
+        if header[0..4] != COMPRESSED_MAGIC {
+            return Err(invalid("not a compressed model container"));
+        }
+        let version = u32::from_le_bytes(header[4..8].try_into().unwrap());
+        if version != VERSION {
+            return Err(invalid(&format!("unsupported version {version}")));
+        }
+        let frame_count = u32::from_le_bytes(header[8..12].try_into().unwrap()) as u64;
+
+        let index_len = frame_count
+            .checked_mul(Frame::SIZE as u64)
+            .filter(|len| header.len() as u64 + len <= container_len)
+            .ok_or_else(|| invalid("the index is larger than the container"))?;
+        let mut index = vec![0; index_len as usize];
+        inner.read_exact(&mut index)?;
+        let frames: Vec<Frame> = index
+            .chunks_exact(Frame::SIZE)
+            .map(|entry| {
+                let value =
+                    |i: usize| u64::from_le_bytes(entry[i * 8..i * 8 + 8].try_into().unwrap());
+                Frame {
+                    offset: value(0),
+                    len: value(1),
+                    compressed_offset: value(2),
+                    compressed_len: value(3),
+                }
+            })
+            .collect();
+
+        let mut len: u64 = 0;
+        for frame in &frames {
+            if frame.offset != len {
+                return Err(invalid("the frames are not contiguous"));
+            }
+            let compressed_end = frame.compressed_offset.checked_add(frame.compressed_len);
+            if compressed_end.map_or(true, |end| end > container_len) {
+                return Err(invalid("a frame extends past the end of the container"));
+            }
+            len = len
+                .checked_add(frame.len)
+                .ok_or_else(|| invalid("the frames are too long"))?;
+        }
+
+        Ok(Self {
+            inner,
+            frames,
+            len,
+            position: 0,
+            current: None,
+        })
+    }
+
+    /// The length of the model file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the model file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn frame(&mut self, index: usize) -> io::Result<&[u8]> {
+        if !matches!(&self.current, Some((current, _)) if *current == index) {
+            let frame = self.frames[index];
+            let mut compressed = vec![0; frame.compressed_len as usize];
+            self.inner.seek(SeekFrom::Start(frame.compressed_offset))?;
+            self.inner.read_exact(&mut compressed)?;
+            // The data is decompressed as a stream, so that the length in the index is not
+            // trusted for the allocation.
+            let mut data = vec![];
+            zstd::stream::read::Decoder::new(compressed.as_slice())?
+                .take(frame.len + 1)
+                .read_to_end(&mut data)?;
+            if data.len() as u64 != frame.len {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "a frame of the container decompressed to the wrong length",
+                ));
+            }
+            self.current = Some((index, data));
+        }
+        Ok(&self.current.as_ref().unwrap().1)
+    }
+}
+impl<R: Read + Seek> Read for CompressedReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let index = self
+            .frames
+            .partition_point(|frame| frame.offset + frame.len <= self.position);
+        let offset = (self.position - self.frames[index].offset) as usize;
+        let data = self.frame(index)?;
+        let n = buf.len().min(data.len() - offset);
+        buf[..n].copy_from_slice(&data[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+impl<R: Read + Seek> Seek for CompressedReader<R> {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = util::seek_position(self.position, self.len, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn container(frames: &[[u64; 4]]) -> Vec<u8> {
+        let mut container = COMPRESSED_MAGIC.to_vec();
+        container.extend(VERSION.to_le_bytes());
+        container.extend((frames.len() as u32).to_le_bytes());
+        for frame in frames {
+            for value in frame {
+                container.extend(value.to_le_bytes());
+            }
+        }
+        container
+    }
+
+    fn reason(container: Vec<u8>) -> String {
+        match CompressedReader::new(Cursor::new(container)) {
+            Err(LoadError::InvalidCompressedContainer { reason }) => reason,
+            Err(err) => panic!("unexpected error {err}"),
+            Ok(_) => panic!("the container was not rejected"),
+        }
+    }
+
+    #[test]
+    fn test_invalid_index() {
+        let mut huge_frame_count = container(&[]);
+        huge_frame_count[8..12].copy_from_slice(&u32::MAX.to_le_bytes());
+        assert_eq!(
+            reason(huge_frame_count),
+            "the index is larger than the container"
+        );
+
+        assert_eq!(
+            reason(container(&[[0, 10, 100, 10]])),
+            "a frame extends past the end of the container"
+        );
+        assert_eq!(
+            reason(container(&[[0, 10, u64::MAX, 10]])),
+            "a frame extends past the end of the container"
+        );
+        assert_eq!(
+            reason(container(&[[0, u64::MAX, 0, 1], [u64::MAX, 1, 0, 1]])),
+            "the frames are too long"
+        );
+    }
+
+    #[test]
+    fn test_frame_with_wrong_length() {
+        let data = zstd::bulk::compress(&[1; 100], 3).unwrap();
+        let mut container = container(&[[0, 10, 44, data.len() as u64]]);
+        container.extend(&data);
+
+        let mut reader = CompressedReader::new(Cursor::new(container)).unwrap();
+        assert_eq!(reader.len(), 10);
+        let err = reader.read_to_end(&mut vec![]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+}
@@ -0,0 +1,202 @@
+//! Implements control vectors (also known as steering vectors), which are added to
+//! the hidden state of a model between layers to steer its output.
+
+use crate::{
+    model::HyperparametersWriteError, util, FileType, Hyperparameters, LoadError, Loader, Tokenizer,
+};
+
+use std::{collections::HashMap, fs::File, io::BufReader, ops::RangeBounds, path::Path};
+use thiserror::Error;
+use tracing::log;
+
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+/// Parameters for a control vector file.
+pub struct ControlVectorParameters {
+    /// Size of the hidden state the directions apply to.
+    pub n_embd: i32,
+}
+impl Hyperparameters for ControlVectorParameters {
+    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        Ok(ControlVectorParameters {
+            n_embd: util::read_i32(reader)?,
+        })
+    }
+
+    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
+        util::write_i32(writer, self.n_embd)?;
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        // Control vectors do not have a vocabulary.
+        0
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        None
+    }
+
+    fn file_type_mut(&mut self) -> Option<&mut FileType> {
+        None
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// A control vector: a set of per-layer directions that are added to the output of
+/// the corresponding layer, multiplied by [ControlVector::strength].
+///
+/// Control vectors can be used to steer the style or sentiment of a model without
+/// fine-tuning it. They are applied to an [InferenceSession](crate::InferenceSession)
+/// with [InferenceSession::set_control_vectors](crate::InferenceSession::set_control_vectors).
+pub struct ControlVector {
+    /// The direction to add to the output of each layer, indexed by layer.
+    ///
+    /// Each direction must have as many elements as the model's embedding size.
+    /// Layers without a direction are left unchanged.
+    pub directions: HashMap<usize, Vec<f32>>,
+    /// How strongly to apply the directions. Negative values steer away from them.
+    pub strength: f32,
+}
+impl ControlVector {
+    /// Creates a new control vector from the given per-layer `directions`.
+    pub fn new(directions: HashMap<usize, Vec<f32>>, strength: f32) -> Self {
+        Self {
+            directions,
+            strength,
+        }
+    }
+
+    /// Loads a control vector from a GGML file at `path`.
+    ///
+    /// The file must contain one `f32` tensor per layer, named `direction.{layer}`.
+    pub fn load(path: &Path, strength: f32) -> Result<Self, LoadError> {
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        let mut loader: Loader<ControlVectorParameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        let mut directions = HashMap::new();
+        for (name, info) in &loader.tensors {
+            let layer = name
+                .strip_prefix("direction.")
+                .and_then(|layer| layer.parse::<usize>().ok())
+                .ok_or_else(|| LoadError::UnknownTensor {
+                    tensor_name: name.to_owned(),
+                    path: path.to_owned(),
+                })?;
+
+            if info.element_type != ggml::Type::F32 {
+                return Err(LoadError::UnsupportedElementType {
+                    tensor_name: name.to_owned(),
+                    ftype: info.element_type.into(),
+                    path: path.to_owned(),
+                });
+            }
+            if info.n_elements != loader.hyperparameters.n_embd as usize {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name.to_owned(),
+                    path: path.to_owned(),
+                });
+            }
+
+            let mut data = info.read_data(&mut reader)?;
+            if info.byte_order != ggml::format::ByteOrder::Little {
+                ggml::format::swap_byte_order(info.element_type, &mut data);
+            }
+            let direction = data
+                .chunks_exact(4)
+                .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+                .collect();
+            directions.insert(layer, direction);
+        }
+
+        log::trace!("Loaded control vector from {path:?}");
+        Ok(Self::new(directions, strength))
+    }
+
+    /// Restricts this control vector to the given range of layers, discarding the
+    /// directions for all other layers.
+    pub fn with_layers(mut self, layers: impl RangeBounds<usize>) -> Self {
+        self.directions.retain(|layer, _| layers.contains(layer));
+        self
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered when applying control vectors to a session.
+pub enum ControlVectorError {
+    /// A direction does not match the model's embedding size.
+    #[error("the direction for layer {layer} has {actual} elements, but the model's embedding size is {expected}")]
+    DimensionMismatch {
+        /// The layer the direction applies to.
+        layer: usize,
+        /// The embedding size of the model.
+        expected: usize,
+        /// The number of elements in the direction.
+        actual: usize,
+    },
+}
+
+/// Combines `control_vectors` into a single direction per layer, scaling each by its strength.
+pub(crate) fn combine(
+    control_vectors: &[ControlVector],
+    n_embd: usize,
+) -> Result<HashMap<usize, Vec<f32>>, ControlVectorError> {
+    let mut combined: HashMap<usize, Vec<f32>> = HashMap::new();
+    for control_vector in control_vectors {
+        for (&layer, direction) in &control_vector.directions {
+            if direction.len() != n_embd {
+                return Err(ControlVectorError::DimensionMismatch {
+                    layer,
+                    expected: n_embd,
+                    actual: direction.len(),
+                });
+            }
+
+            let target = combined.entry(layer).or_insert_with(|| vec![0.0; n_embd]);
+            for (t, d) in target.iter_mut().zip(direction) {
+                *t += d * control_vector.strength;
+            }
+        }
+    }
+    Ok(combined)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_load_big_endian() {
+        let direction = [1.5f32, -2.0];
+
+        // A GGJTv3 control vector written on a big-endian machine, by hand.
+        let mut buffer = Vec::new();
+        let be = |buffer: &mut Vec<u8>, value: u32| buffer.extend(value.to_be_bytes());
+        be(&mut buffer, ggml::FILE_MAGIC_GGJT);
+        be(&mut buffer, 3);
+        // n_embd
+        be(&mut buffer, 2);
+        // n_dims, name_len, ftype, dims
+        for value in [1, 11, u32::from(ggml::Type::F32), 2] {
+            be(&mut buffer, value);
+        }
+        buffer.extend(b"direction.0");
+        buffer.resize((buffer.len() + 31) & !31, 0);
+        for value in direction {
+            be(&mut buffer, value.to_bits());
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("control-vector.bin");
+        std::fs::write(&path, buffer).unwrap();
+
+        let control_vector = ControlVector::load(&path, 1.0).unwrap();
+        assert_eq!(control_vector.directions[&0], direction);
+    }
+}
@@ -0,0 +1,454 @@
+//! Implements [Conversation], which manages the message history of a chat with a model:
+//! it formats messages with a [ChatTemplate], keeps track of how many tokens each turn
+//! takes, and makes room for new messages when the context window would overflow.
+
+use thiserror::Error;
+use tracing::log;
+
+use crate::{
+    conversation_inference_callback, InferenceError, InferenceFeedback, InferenceParameters,
+    InferenceRequest, InferenceSession, InferenceSessionConfig, Model, TokenizationError,
+};
+
+/// The instruction used to summarize old turns by default; see [OverflowStrategy::Summarize].
+pub const DEFAULT_SUMMARY_INSTRUCTION: &str =
+    "Summarize the following conversation in a few sentences, keeping any facts, names and decisions that later messages may refer to.";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How messages are formatted for the model, which depends on how it was fine-tuned.
+///
+/// Each message is formatted as its role's prefix, its content, and the message suffix.
+/// After a user message, the assistant prefix is fed to the model, which then generates
+/// its reply until it generates the stop sequence (or its end-of-text token).
+pub struct ChatTemplate {
+    /// The text before a system message.
+    pub system_prefix: String,
+    /// The text before a user message.
+    pub user_prefix: String,
+    /// The text before an assistant message.
+    pub assistant_prefix: String,
+    /// The text after each message.
+    pub message_suffix: String,
+    /// The text that ends the assistant's reply when it is generated.
+    pub stop_sequence: String,
+}
+impl ChatTemplate {
+    /// The ChatML template, used by many chat models.
+    pub fn chatml() -> Self {
+        Self {
+            system_prefix: "<|im_start|>system\n".to_string(),
+            user_prefix: "<|im_start|>user\n".to_string(),
+            assistant_prefix: "<|im_start|>assistant\n".to_string(),
+            message_suffix: "<|im_end|>\n".to_string(),
+            stop_sequence: "<|im_end|>".to_string(),
+        }
+    }
+
+    /// A plain-text template, where each message is a line starting with the speaker.
+    pub fn plain(user_name: &str, assistant_name: &str) -> Self {
+        Self {
+            system_prefix: String::new(),
+            user_prefix: format!("{user_name}: "),
+            assistant_prefix: format!("{assistant_name}: "),
+            message_suffix: "\n".to_string(),
+            stop_sequence: format!("\n{user_name}:"),
+        }
+    }
+
+    fn system_message(&self, content: &str) -> String {
+        format!("{}{content}{}", self.system_prefix, self.message_suffix)
+    }
+
+    /// The text fed for a user message, which ends with the prefix of the reply.
+    fn user_message(&self, content: &str) -> String {
+        format!(
+            "{}{content}{}{}",
+            self.user_prefix, self.message_suffix, self.assistant_prefix
+        )
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+/// What a [Conversation] does with its oldest turns when a new message would overflow the
+/// context window.
+pub enum OverflowStrategy {
+    /// Discard the oldest turns.
+    #[default]
+    Evict,
+    /// Ask the model to summarize the oldest turns (and any previous summary), and keep the
+    /// summary as a system message after the conversation's system message.
+    Summarize {
+        /// The instruction given to the model, e.g. [DEFAULT_SUMMARY_INSTRUCTION].
+        instruction: String,
+        /// The maximum number of tokens in a summary.
+        maximum_token_count: usize,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// A user message and the assistant's reply to it.
+pub struct Turn {
+    /// The user's message.
+    pub user: String,
+    /// The assistant's reply.
+    pub assistant: String,
+    /// The number of tokens that this turn takes in the context window.
+    pub n_tokens: usize,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by a [Conversation].
+pub enum ConversationError {
+    /// The message and the tokens reserved for the reply do not fit in the context window,
+    /// even without any previous turns.
+    #[error("the message does not fit in the context window")]
+    MessageTooLong,
+    /// Tokenizing a message failed.
+    #[error("failed to tokenize a message")]
+    Tokenization(#[from] TokenizationError),
+    /// Evaluating a message or generating a reply failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
+}
+
+/// A chat with a model, which owns its message history and the [InferenceSession] that
+/// the history has been evaluated in.
+///
+/// Before each user message is sent, enough tokens for the message and for
+/// [Self::reply_token_budget] tokens of reply are set aside. If they do not fit after
+/// the previous turns, the oldest turns are removed according to [Self::overflow_strategy]
+/// and the session is rebuilt from the remaining history. The system message is never
+/// removed.
+pub struct Conversation {
+    /// The maximum number of tokens in each reply. Defaults to 256.
+    pub reply_token_budget: usize,
+    /// What to do with old turns when the context window would overflow.
+    pub overflow_strategy: OverflowStrategy,
+
+    session: InferenceSession,
+    template: ChatTemplate,
+    system_message: Option<String>,
+    summary: Option<String>,
+    turns: Vec<Turn>,
+}
+impl Conversation {
+    /// Starts a conversation with `model`, optionally with a system message that sets the
+    /// behaviour of the assistant.
+    pub fn new(
+        model: &dyn Model,
+        config: InferenceSessionConfig,
+        template: ChatTemplate,
+        system_message: Option<&str>,
+    ) -> Self {
+        Self {
+            reply_token_budget: 256,
+            overflow_strategy: OverflowStrategy::default(),
+            session: model.start_session(config),
+            template,
+            system_message: system_message.map(str::to_owned),
+            summary: None,
+            turns: vec![],
+        }
+    }
+
+    /// The turns of the conversation that are still in the context window, oldest first.
+    pub fn turns(&self) -> &[Turn] {
+        &self.turns
+    }
+
+    /// The summary of the turns that were removed from the context window, if the
+    /// [OverflowStrategy::Summarize] strategy has been used.
+    pub fn summary(&self) -> Option<&str> {
+        self.summary.as_deref()
+    }
+
+    /// The session that the conversation has been evaluated in.
+    pub fn session(&self) -> &InferenceSession {
+        &self.session
+    }
+
+    /// Sends a user message, and generates the assistant's reply to it, calling `callback`
+    /// with each piece of the reply as it is generated. Returns the reply.
+    pub fn send(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        parameters: &InferenceParameters,
+        message: &str,
+        mut callback: impl FnMut(&str),
+    ) -> Result<String, ConversationError> {
+        if self.session.n_past == 0 {
+            self.feed_preamble(model)?;
+        }
+
+        let user_message = self.template.user_message(message);
+        let n_message_tokens = model
+            .tokenizer()
+            .tokenize(&user_message, self.session.n_past == 0)?
+            .len();
+        let n_needed = n_message_tokens + self.reply_token_budget;
+        if self.session.n_past + n_needed >= model.context_size() {
+            self.make_room(model, rng, parameters, n_needed)?;
+        }
+
+        let n_past_before = self.session.n_past;
+        feed(&mut self.session, model, &user_message)?;
+        let n_past_before_reply = self.session.n_past;
+        let (reply, finished) = self.generate(
+            model,
+            rng,
+            parameters,
+            self.reply_token_budget,
+            &mut callback,
+        )?;
+
+        // When the model ends the reply itself, the session also has the stop sequence or
+        // end-of-text token that it ended with, which the history does not; they are
+        // replaced with the message suffix, so that the session has the same text as the
+        // history it would be rebuilt from.
+        let ending = if !finished {
+            Some(self.template.message_suffix.clone())
+        } else if self.rewind_reply(model, n_past_before_reply) {
+            Some(format!("{reply}{}", self.template.message_suffix))
+        } else {
+            None
+        };
+        match ending {
+            Some(ending) => {
+                feed(&mut self.session, model, &ending)?;
+                self.turns.push(Turn {
+                    user: message.to_owned(),
+                    assistant: reply.clone(),
+                    n_tokens: self.session.n_past - n_past_before,
+                });
+            }
+            None => {
+                // The model cannot rewind, so the session is rebuilt from the history.
+                self.turns.push(Turn {
+                    user: message.to_owned(),
+                    assistant: reply.clone(),
+                    n_tokens: 0,
+                });
+                self.rebuild_session(model)?;
+            }
+        }
+        Ok(reply)
+    }
+
+    /// Removes the tokens generated after `n_past_before_reply` from the session, and
+    /// returns whether it could.
+    fn rewind_reply(&mut self, model: &dyn Model, n_past_before_reply: usize) -> bool {
+        match self.session.n_past.checked_sub(n_past_before_reply) {
+            Some(n_generated) if model.supports_rewind() => {
+                self.session.rewind(model, n_generated).is_ok()
+            }
+            _ => false,
+        }
+    }
+
+    /// Removes the oldest turns until `n_needed` more tokens fit in the context window,
+    /// and rebuilds the session from the remaining history.
+    fn make_room(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        parameters: &InferenceParameters,
+        n_needed: usize,
+    ) -> Result<(), ConversationError> {
+        let n_summary_tokens = match &self.overflow_strategy {
+            OverflowStrategy::Evict => 0,
+            OverflowStrategy::Summarize {
+                maximum_token_count,
+                ..
+            } => {
+                let template = self.template.system_message("");
+                maximum_token_count + model.tokenizer().tokenize(&template, false)?.len()
+            }
+        };
+        let n_preamble_tokens = self.preamble_token_count(model)?;
+        let context_size = model.context_size();
+
+        let mut n_history_tokens: usize = self.turns.iter().map(|turn| turn.n_tokens).sum();
+        let mut n_evicted = 0;
+        while n_evicted < self.turns.len()
+            && n_preamble_tokens + n_summary_tokens + n_history_tokens + n_needed >= context_size
+        {
+            n_history_tokens -= self.turns[n_evicted].n_tokens;
+            n_evicted += 1;
+        }
+        if n_preamble_tokens + n_summary_tokens + n_history_tokens + n_needed >= context_size {
+            return Err(ConversationError::MessageTooLong);
+        }
+        log::debug!("Removing {n_evicted} turns from the conversation to make room");
+
+        let evicted: Vec<_> = self.turns.drain(..n_evicted).collect();
+        if let OverflowStrategy::Summarize {
+            instruction,
+            maximum_token_count,
+        } = self.overflow_strategy.clone()
+        {
+            self.summary = self.summarize(
+                model,
+                rng,
+                parameters,
+                &evicted,
+                &instruction,
+                maximum_token_count,
+            )?;
+        }
+
+        self.rebuild_session(model)
+    }
+
+    /// Evaluates the history again in a new session.
+    fn rebuild_session(&mut self, model: &dyn Model) -> Result<(), ConversationError> {
+        self.session = model.start_session(self.session.config);
+        self.feed_preamble(model)?;
+        for turn in &mut self.turns {
+            let n_past_before = self.session.n_past;
+            feed(
+                &mut self.session,
+                model,
+                &self.template.user_message(&turn.user),
+            )?;
+            feed(
+                &mut self.session,
+                model,
+                &format!("{}{}", turn.assistant, self.template.message_suffix),
+            )?;
+            turn.n_tokens = self.session.n_past - n_past_before;
+        }
+        Ok(())
+    }
+
+    /// Asks the model to summarize `evicted` and the previous summary, in a new session.
+    /// Returns the previous summary if there was nothing to summarize, or not even the
+    /// newest evicted turn fits in the context window with it.
+    fn summarize(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        parameters: &InferenceParameters,
+        evicted: &[Turn],
+        instruction: &str,
+        maximum_token_count: usize,
+    ) -> Result<Option<String>, ConversationError> {
+        // The oldest evicted turns are left out of the transcript if they do not all fit
+        // with the previous summary, the instruction and the new summary.
+        let mut n_skipped = 0;
+        let prompt = loop {
+            if n_skipped == evicted.len() {
+                if !evicted.is_empty() {
+                    log::warn!("The turns to summarize do not fit in the context window");
+                }
+                return Ok(self.summary.clone());
+            }
+            let prompt = self.summary_prompt(instruction, &evicted[n_skipped..]);
+            let n_prompt_tokens = model.tokenizer().tokenize(&prompt, true)?.len();
+            if n_prompt_tokens + maximum_token_count < model.context_size() {
+                break prompt;
+            }
+            n_skipped += 1;
+        };
+        if n_skipped > 0 {
+            log::warn!("Leaving {n_skipped} turns that do not fit out of the summary");
+        }
+
+        self.session = model.start_session(self.session.config);
+        feed(&mut self.session, model, &prompt)?;
+        let (summary, _) = self.generate(model, rng, parameters, maximum_token_count, |_| {})?;
+        Ok(Some(summary.trim().to_owned()))
+    }
+
+    /// The prompt that asks the model to summarize the previous summary and `turns`.
+    fn summary_prompt(&self, instruction: &str, turns: &[Turn]) -> String {
+        let mut transcript = self.summary.clone().unwrap_or_default();
+        for turn in turns {
+            transcript.push_str(&format!(
+                "{}{}{}{}{}{}",
+                self.template.user_prefix,
+                turn.user,
+                self.template.message_suffix,
+                self.template.assistant_prefix,
+                turn.assistant,
+                self.template.message_suffix
+            ));
+        }
+        format!(
+            "{}{}",
+            self.template.system_message(instruction),
+            self.template.user_message(&transcript)
+        )
+    }
+
+    /// Generates the assistant's reply, and returns it and whether the model finished it.
+    fn generate(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        parameters: &InferenceParameters,
+        maximum_token_count: usize,
+        mut callback: impl FnMut(&str),
+    ) -> Result<(String, bool), ConversationError> {
+        let mut reply = String::new();
+        let mut finished = false;
+        let mut stop_sequence_callback =
+            conversation_inference_callback(&self.template.stop_sequence, |token| {
+                callback(&token);
+                reply.push_str(&token);
+            });
+        let result = self.session.infer(
+            model,
+            rng,
+            &InferenceRequest::builder("", parameters)
+                .maximum_token_count(maximum_token_count)
+                .build(),
+            &mut Default::default(),
+            |response| {
+                let feedback = stop_sequence_callback(response);
+                finished |= matches!(feedback, InferenceFeedback::Halt);
+                feedback
+            },
+        );
+        drop(stop_sequence_callback);
+
+        match result {
+            Ok(_) => Ok((reply, finished)),
+            // Stop replying when the context window is full; the next message makes room.
+            Err(InferenceError::ContextFull) => Ok((reply, false)),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    /// The text that the conversation starts with: the system message and the summary.
+    fn preamble(&self) -> String {
+        [&self.system_message, &self.summary]
+            .into_iter()
+            .flatten()
+            .map(|content| self.template.system_message(content))
+            .collect()
+    }
+
+    fn preamble_token_count(&self, model: &dyn Model) -> Result<usize, ConversationError> {
+        Ok(model.tokenizer().tokenize(&self.preamble(), true)?.len())
+    }
+
+    fn feed_preamble(&mut self, model: &dyn Model) -> Result<(), ConversationError> {
+        let preamble = self.preamble();
+        feed(&mut self.session, model, &preamble)
+    }
+}
+
+fn feed(
+    session: &mut InferenceSession,
+    model: &dyn Model,
+    text: &str,
+) -> Result<(), ConversationError> {
+    if text.is_empty() {
+        return Ok(());
+    }
+    session.feed_prompt(model, text, &mut Default::default(), |_| {
+        Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+    })?;
+    Ok(())
+}
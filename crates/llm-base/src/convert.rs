@@ -0,0 +1,1369 @@
+//! Implements [convert_hf], which converts a checkpoint in the Hugging Face format (a
+//! directory with a `config.json`, a `tokenizer.json` and sharded `.safetensors` weights)
+//! to a GGUF model, without the upstream Python conversion scripts.
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs::File,
+    io::{Read, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ggml::format::{
+    gguf::{self, TokenType},
+    SaveContainerType, SaveError, SaveHandler, TensorSaveInfo,
+};
+use half::{bf16, f16};
+use thiserror::Error;
+
+use crate::{
+    gguf_metadata, model::HyperparametersWriteError, FileType, FileTypeFormat, Hyperparameters,
+    KnownModel, Regex, Tokenizer,
+};
+
+#[derive(Error, Debug)]
+/// Errors encountered while converting a Hugging Face checkpoint.
+pub enum ConvertError {
+    #[error("could not read {path:?}")]
+    /// A file of the checkpoint could not be read.
+    Io {
+        /// The path of the file.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: std::io::Error,
+    },
+    #[error("{path:?} is not valid JSON")]
+    /// A JSON file of the checkpoint could not be parsed.
+    Json {
+        /// The path of the file.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: serde_json::Error,
+    },
+    #[error("{path:?} is not a valid safetensors file: {reason}")]
+    /// A weights file is not a valid safetensors file.
+    InvalidSafetensors {
+        /// The path of the file.
+        path: PathBuf,
+        /// What is wrong with the file.
+        reason: String,
+    },
+    #[error("no .safetensors files were found in {path:?}")]
+    /// The checkpoint has no weights in the safetensors format.
+    NoWeights {
+        /// The path of the checkpoint.
+        path: PathBuf,
+    },
+    #[error("the tokenizer at {path:?} could not be read")]
+    /// The tokenizer of the checkpoint could not be read.
+    Tokenizer {
+        /// The path of the tokenizer.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: Box<dyn std::error::Error + Send + Sync>,
+    },
+    #[error("config.json has no valid `{key}`")]
+    /// A hyperparameter is missing from `config.json`, or is not valid.
+    MissingConfigValue {
+        /// The key of the hyperparameter.
+        key: String,
+    },
+    #[error("the architecture cannot be converted from a Hugging Face checkpoint")]
+    /// The architecture does not support conversion.
+    UnsupportedArchitecture,
+    #[error("cannot convert to {element_type:?}; only f32, f16 and q8_0 are supported")]
+    /// Attempted to convert to an element type other than `f32` or `f16`.
+    InvalidTarget {
+        /// The element type.
+        element_type: ggml::Type,
+    },
+    #[error("tensor {name} has an unsupported data type {dtype}")]
+    /// A tensor's data type cannot be converted.
+    UnsupportedDataType {
+        /// The name of the tensor.
+        name: String,
+        /// The safetensors data type.
+        dtype: String,
+    },
+    #[error("tensor {name} has an unsupported shape {shape:?}")]
+    /// A tensor has more dimensions than GGML files support.
+    UnsupportedShape {
+        /// The name of the tensor.
+        name: String,
+        /// The shape of the tensor.
+        shape: Vec<usize>,
+    },
+    #[error("an error was encountered while writing the hyperparameters")]
+    /// An error was encountered while writing the hyperparameters.
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    #[error("could not write the model")]
+    /// The converted model could not be written.
+    Write(#[source] std::io::Error),
+    #[error("invariant broken: {0}")]
+    /// An invariant was broken while writing the model.
+    InvariantBroken(String),
+}
+impl ConvertError {
+    fn io(path: &Path) -> impl FnOnce(std::io::Error) -> Self + '_ {
+        move |source| Self::Io {
+            path: path.to_owned(),
+            source,
+        }
+    }
+
+    fn from_format_error(value: SaveError<ConvertError>) -> Self {
+        match value {
+            SaveError::Io(io) => ConvertError::Write(io),
+            SaveError::InvalidIntegerConversion(e) => ConvertError::InvariantBroken(e.to_string()),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => ConvertError::InvariantBroken(invariant),
+            SaveError::VocabularyScoringNotSupported => ConvertError::InvariantBroken(
+                "the container type does not support vocabulary scoring".to_string(),
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+/// Progress of a conversion.
+pub enum ConvertProgress<'a> {
+    /// The hyperparameters have been read from `config.json`.
+    HyperparametersRead,
+    /// A tensor is being converted.
+    TensorConverting {
+        /// The name of the tensor in the checkpoint.
+        source_name: &'a str,
+        /// The name of the tensor in the converted model.
+        name: &'a str,
+    },
+    /// A tensor of the checkpoint is not used by the architecture, and was skipped.
+    TensorSkipped {
+        /// The name of the tensor in the checkpoint.
+        name: &'a str,
+    },
+    /// The model has been converted.
+    Finished {
+        /// The number of tensors in the converted model.
+        n_tensors: usize,
+    },
+}
+
+#[derive(Debug, Clone)]
+/// The `config.json` of a Hugging Face checkpoint, from which architectures read their
+/// hyperparameters in [KnownModel::hf_hyperparameters].
+pub struct HfConfig(serde_json::Map<String, serde_json::Value>);
+impl HfConfig {
+    /// Reads the `config.json` at `path`.
+    pub fn read(path: &Path) -> Result<Self, ConvertError> {
+        let text = std::fs::read_to_string(path).map_err(ConvertError::io(path))?;
+        Self::parse(&text).map_err(|source| ConvertError::Json {
+            path: path.to_owned(),
+            source,
+        })
+    }
+
+    /// Parses the text of a `config.json`.
+    pub fn parse(text: &str) -> Result<Self, serde_json::Error> {
+        serde_json::from_str(text).map(Self)
+    }
+
+    /// Returns the value of `key`, if it is present.
+    pub fn get(&self, key: &str) -> Option<&serde_json::Value> {
+        self.0.get(key).filter(|value| !value.is_null())
+    }
+
+    /// Returns the value of `key` as an integer.
+    pub fn usize(&self, key: &str) -> Result<usize, ConvertError> {
+        self.optional_usize(key)?
+            .ok_or_else(|| ConvertError::MissingConfigValue {
+                key: key.to_string(),
+            })
+    }
+
+    /// Returns the value of `key` as an integer, or `None` if it is not present.
+    pub fn optional_usize(&self, key: &str) -> Result<Option<usize>, ConvertError> {
+        self.optional(key, |value| value.as_u64().and_then(|v| v.try_into().ok()))
+    }
+
+    /// Returns the value of `key` as a float, or `None` if it is not present.
+    pub fn optional_f32(&self, key: &str) -> Result<Option<f32>, ConvertError> {
+        self.optional(key, |value| value.as_f64().map(|v| v as f32))
+    }
+
+    /// Returns the value of `key` as a boolean, or `None` if it is not present.
+    pub fn optional_bool(&self, key: &str) -> Result<Option<bool>, ConvertError> {
+        self.optional(key, serde_json::Value::as_bool)
+    }
+
+    /// Returns the nested object at `key` (e.g. the `attn_config` of MPT), or `None` if it
+    /// is not present.
+    pub fn optional_section(&self, key: &str) -> Result<Option<HfConfig>, ConvertError> {
+        self.optional(key, |value| value.as_object().cloned().map(Self))
+    }
+
+    fn optional<T>(
+        &self,
+        key: &str,
+        convert: impl FnOnce(&serde_json::Value) -> Option<T>,
+    ) -> Result<Option<T>, ConvertError> {
+        self.get(key)
+            .map(|value| {
+                convert(value).ok_or_else(|| ConvertError::MissingConfigValue {
+                    key: key.to_string(),
+                })
+            })
+            .transpose()
+    }
+}
+
+/// Converts the Hugging Face checkpoint in `directory` to a model of the architecture `M`,
+/// and writes it to `writer` in the GGUF format, with its 2D tensors stored as
+/// `element_type` (`f32`, `f16` or `q8_0`). An `f32` or `f16` model can then be quantized
+/// further with [quantize](crate::quantize). With `q8_0`, only the tensors that
+/// [KnownModel::quantize_tensors] selects are quantized, and the others are stored as `f16`.
+///
+/// The hyperparameters are read from `config.json` with [KnownModel::hf_hyperparameters],
+/// the vocabulary and the scores of its tokens from `tokenizer.model` (if present) or
+/// `tokenizer.json`, and the weights from the shards of the checkpoint, which are renamed
+/// with [KnownModel::hf_tensor_names] and rearranged with [KnownModel::hf_transform_tensor]
+/// and written to a single model. Tensors that are not renamed are skipped. The shards are
+/// those listed by `model.safetensors.index.json` if the checkpoint has one, or every
+/// `.safetensors` file in the directory otherwise.
+///
+/// Besides the bytes and score of each token, the metadata of the model records the type of
+/// each token, the merges of a BPE tokenizer, the IDs of the special tokens and, if the
+/// checkpoint has one, its whole `tokenizer.json`, which the loader then tokenizes with, so
+/// that the converted model tokenizes exactly as the checkpoint does. The hyperparameters
+/// are also recorded under the standard keys of the `model_type` of the checkpoint.
+pub fn convert_hf<M: KnownModel, W: Write + Seek>(
+    directory: &Path,
+    writer: &mut W,
+    element_type: ggml::Type,
+    progress_callback: impl Fn(ConvertProgress),
+) -> Result<(), ConvertError> {
+    let (format, quantization_version) = match element_type {
+        ggml::Type::F32 => (FileTypeFormat::F32, 0),
+        ggml::Type::F16 => (FileTypeFormat::MostlyF16, 0),
+        ggml::Type::Q8_0 => (FileTypeFormat::MostlyQ8_0, ggml::QNT_VERSION),
+        element_type => return Err(ConvertError::InvalidTarget { element_type }),
+    };
+
+    let config = HfConfig::read(&directory.join("config.json"))?;
+    let hyperparameters = M::hf_hyperparameters(
+        &config,
+        FileType {
+            format,
+            quantization_version,
+        },
+    )?;
+    progress_callback(ConvertProgress::HyperparametersRead);
+
+    let vocabulary = read_hf_vocabulary(directory, hyperparameters.n_vocabulary())?;
+    let metadata = hf_metadata(&config, &hyperparameters, vocabulary)?;
+
+    let mapping = M::hf_tensor_names();
+    let mut tensors = BTreeMap::new();
+    for (source_name, tensor) in read_safetensors_index(directory)? {
+        match mapping.try_map(&source_name) {
+            Some(name) => {
+                if tensor.shape.is_empty() || tensor.shape.len() > 2 {
+                    return Err(ConvertError::UnsupportedShape {
+                        name: source_name,
+                        shape: tensor.shape,
+                    });
+                }
+                tensors.insert(name.into_owned(), (source_name, tensor));
+            }
+            None => progress_callback(ConvertProgress::TensorSkipped { name: &source_name }),
+        }
+    }
+
+    let mut saver = ConvertSaver::<M, _> {
+        hyperparameters: &hyperparameters,
+        tensors,
+        element_type,
+        to_quantize: M::quantize_tensors(),
+        to_skip: M::skip_quantize_tensors(),
+        progress_callback: &progress_callback,
+    };
+    // The header lists every tensor, so their layout is worked out before any are converted.
+    let tensor_infos: Vec<_> = saver
+        .tensors
+        .iter()
+        .map(|(name, (_, tensor))| {
+            let (n_dims, dims, element_type) = saver.layout(name, &tensor.shape);
+            gguf::TensorInfo {
+                name: name.clone(),
+                dims: dims[..n_dims].to_vec(),
+                element_type,
+                offset: 0,
+            }
+        })
+        .collect();
+    let n_tensors = tensor_infos.len();
+    gguf::save_gguf(writer, &mut saver, metadata, tensor_infos)
+        .map_err(ConvertError::from_format_error)?;
+
+    progress_callback(ConvertProgress::Finished { n_tensors });
+    Ok(())
+}
+
+/// Converts the hyperparameters and the vocabulary of the Hugging Face checkpoint in
+/// `directory`, like [convert_hf], and writes them to `writer` as a model without tensors.
+///
+/// The result is a few hundred kilobytes rather than gigabytes, and can be opened with
+/// [ModelFile](crate::ModelFile) by tools that only need to tokenize, such as those that
+/// count the tokens of prompts. It cannot be loaded as a model.
+pub fn convert_hf_vocabulary<M: KnownModel, W: Write + Seek>(
+    directory: &Path,
+    writer: &mut W,
+    progress_callback: impl Fn(ConvertProgress),
+) -> Result<(), ConvertError> {
+    let config = HfConfig::read(&directory.join("config.json"))?;
+    let hyperparameters = M::hf_hyperparameters(
+        &config,
+        FileType {
+            format: FileTypeFormat::MostlyF16,
+            quantization_version: 0,
+        },
+    )?;
+    progress_callback(ConvertProgress::HyperparametersRead);
+
+    let vocabulary = read_hf_vocabulary(directory, hyperparameters.n_vocabulary())?;
+    let metadata = hf_metadata(&config, &hyperparameters, vocabulary)?;
+    gguf::save_gguf(
+        writer,
+        &mut VocabularyOnlySaver(&hyperparameters),
+        metadata,
+        vec![],
+    )
+    .map_err(ConvertError::from_format_error)?;
+
+    progress_callback(ConvertProgress::Finished { n_tensors: 0 });
+    Ok(())
+}
+
+/// Writes the `hyperparameters` and the vocabulary of `tokenizer` to `writer` as a model
+/// without tensors, like [convert_hf_vocabulary]. This makes a vocabulary-only file from a
+/// model that has already been converted, by opening it with [ModelFile](crate::ModelFile).
+///
+/// The vocabulary is padded or truncated to the size of the vocabulary of the
+/// hyperparameters. Only an embedded tokenizer has scores; the tokens of a Hugging Face
+/// tokenizer are written with a score of 0.
+pub fn write_vocabulary_only<Hp: Hyperparameters, W: Write + Seek>(
+    writer: &mut W,
+    hyperparameters: &Hp,
+    tokenizer: &Tokenizer,
+) -> Result<(), ConvertError> {
+    let mut vocabulary = match tokenizer {
+        Tokenizer::Embedded(tokenizer) => tokenizer.iter().collect(),
+        Tokenizer::HuggingFace(_) => (0..tokenizer.len())
+            .map(|id| (tokenizer.token(id), 0.0))
+            .collect::<Vec<_>>(),
+    };
+    let n_vocab = hyperparameters.n_vocabulary();
+    vocabulary.truncate(n_vocab);
+    for id in vocabulary.len()..n_vocab {
+        vocabulary.push((format!("[PAD{id}]").into_bytes(), 0.0));
+    }
+    save_vocabulary_only(writer, hyperparameters, &vocabulary)
+}
+
+fn save_vocabulary_only<Hp: Hyperparameters, W: Write + Seek>(
+    writer: &mut W,
+    hyperparameters: &Hp,
+    vocabulary: &[(Vec<u8>, f32)],
+) -> Result<(), ConvertError> {
+    ggml::format::save(
+        writer,
+        &mut VocabularyOnlySaver(hyperparameters),
+        SaveContainerType::GgjtV3,
+        vocabulary,
+        &[],
+    )
+    .map_err(ConvertError::from_format_error)
+}
+
+struct VocabularyOnlySaver<'a, Hp>(&'a Hp);
+impl<Hp: Hyperparameters> SaveHandler<ConvertError> for VocabularyOnlySaver<'_, Hp> {
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), ConvertError> {
+        self.0
+            .write_ggml(writer)
+            .map_err(ConvertError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, ConvertError> {
+        Err(ConvertError::InvariantBroken(format!(
+            "a vocabulary-only model has no tensor {tensor_name}"
+        )))
+    }
+}
+
+/// Reverses the permutation of the rows of the query and key weights that the Hugging Face
+/// conversion of LLaMA applies, so that they work with the rotary position embeddings of
+/// GGML. `data` is row-major with `rows` rows, which are split between `n_head` heads.
+pub fn reverse_hf_rotary_permutation(data: &mut [f32], rows: usize, n_head: usize) {
+    let columns = data.len() / rows;
+    let half = rows / n_head / 2;
+    let permuted = data.to_vec();
+    for head in 0..n_head {
+        for i in 0..half {
+            for j in 0..2 {
+                let source = head * 2 * half + j * half + i;
+                let destination = head * 2 * half + i * 2 + j;
+                data[destination * columns..(destination + 1) * columns]
+                    .copy_from_slice(&permuted[source * columns..(source + 1) * columns]);
+            }
+        }
+    }
+}
+
+/// A tensor in a safetensors file.
+#[derive(Debug, Clone)]
+struct SafetensorsTensor {
+    path: PathBuf,
+    dtype: String,
+    shape: Vec<usize>,
+    /// The offset of the tensor's data in the file.
+    offset: u64,
+    /// The size of the tensor's data in bytes.
+    size: usize,
+}
+
+/// Reads the headers of every `.safetensors` file in `directory`.
+fn read_safetensors_index(
+    directory: &Path,
+) -> Result<BTreeMap<String, SafetensorsTensor>, ConvertError> {
+    // Sharded checkpoints list the shard of each tensor in an index.
+    let index_path = directory.join("model.safetensors.index.json");
+    let weight_map = if index_path.exists() {
+        let text = std::fs::read_to_string(&index_path).map_err(ConvertError::io(&index_path))?;
+        let index: serde_json::Value =
+            serde_json::from_str(&text).map_err(|source| ConvertError::Json {
+                path: index_path.clone(),
+                source,
+            })?;
+        let weight_map: Option<BTreeMap<String, String>> =
+            index["weight_map"].as_object().and_then(|map| {
+                map.iter()
+                    .map(|(name, shard)| Some((name.clone(), shard.as_str()?.to_string())))
+                    .collect()
+            });
+        Some(weight_map.ok_or_else(|| ConvertError::InvalidSafetensors {
+            path: index_path.clone(),
+            reason: "the index has no valid weight map".to_string(),
+        })?)
+    } else {
+        None
+    };
+
+    let mut paths = vec![];
+    match &weight_map {
+        Some(weight_map) => {
+            paths.extend(weight_map.values().map(|shard| directory.join(shard)));
+            paths.dedup();
+        }
+        None => {
+            for entry in std::fs::read_dir(directory).map_err(ConvertError::io(directory))? {
+                let path = entry.map_err(ConvertError::io(directory))?.path();
+                if path.extension().map_or(false, |e| e == "safetensors") {
+                    paths.push(path);
+                }
+            }
+        }
+    }
+    if paths.is_empty() {
+        return Err(ConvertError::NoWeights {
+            path: directory.to_owned(),
+        });
+    }
+    paths.sort();
+    paths.dedup();
+
+    let mut tensors = BTreeMap::new();
+    for path in paths {
+        for (name, tensor) in read_safetensors_header(&path)? {
+            if let Some(previous) = tensors.get(&name).map(|t: &SafetensorsTensor| &t.path) {
+                return Err(ConvertError::InvalidSafetensors {
+                    path,
+                    reason: format!("{name} is also in {previous:?}"),
+                });
+            }
+            tensors.insert(name, tensor);
+        }
+    }
+    if let Some(name) = weight_map
+        .iter()
+        .flat_map(|weight_map| weight_map.keys())
+        .find(|name| !tensors.contains_key(*name))
+    {
+        return Err(ConvertError::InvalidSafetensors {
+            path: index_path,
+            reason: format!("{name} is listed in the index, but is in none of the shards"),
+        });
+    }
+    Ok(tensors)
+}
+
+/// The size in bytes of an element of a safetensors `dtype`.
+fn safetensors_dtype_size(dtype: &str) -> Option<usize> {
+    Some(match dtype {
+        "BOOL" | "U8" | "I8" | "F8_E4M3" | "F8_E5M2" => 1,
+        "U16" | "I16" | "F16" | "BF16" => 2,
+        "U32" | "I32" | "F32" => 4,
+        "U64" | "I64" | "F64" => 8,
+        _ => return None,
+    })
+}
+
+fn read_safetensors_header(path: &Path) -> Result<Vec<(String, SafetensorsTensor)>, ConvertError> {
+    let invalid = |reason: &str| ConvertError::InvalidSafetensors {
+        path: path.to_owned(),
+        reason: reason.to_string(),
+    };
+
+    let mut file = File::open(path).map_err(ConvertError::io(path))?;
+    let file_len = file.metadata().map_err(ConvertError::io(path))?.len();
+    let mut header_size = [0; 8];
+    file.read_exact(&mut header_size)
+        .map_err(ConvertError::io(path))?;
+    let header_size = u64::from_le_bytes(header_size);
+    if header_size > 100 * 1024 * 1024 {
+        return Err(invalid("the header is too large"));
+    }
+    let mut header = vec![0; header_size as usize];
+    file.read_exact(&mut header)
+        .map_err(ConvertError::io(path))?;
+    let header: serde_json::Map<String, serde_json::Value> = serde_json::from_slice(&header)
+        .map_err(|source| ConvertError::Json {
+            path: path.to_owned(),
+            source,
+        })?;
+
+    let data_start = 8 + header_size;
+    let mut tensors = vec![];
+    for (name, info) in header {
+        if name == "__metadata__" {
+            continue;
+        }
+        let invalid_tensor = |reason: &str| invalid(&format!("{name} {reason}"));
+
+        let dtype = info["dtype"]
+            .as_str()
+            .ok_or_else(|| invalid_tensor("has no dtype"))?;
+        let dtype_size = safetensors_dtype_size(dtype)
+            .ok_or_else(|| invalid_tensor(&format!("has the unknown dtype {dtype}")))?;
+        let shape = info["shape"]
+            .as_array()
+            .and_then(|shape| {
+                shape
+                    .iter()
+                    .map(|d| d.as_u64().and_then(|d| usize::try_from(d).ok()))
+                    .collect::<Option<Vec<_>>>()
+            })
+            .ok_or_else(|| invalid_tensor("has no valid shape"))?;
+        let (begin, end) = match info["data_offsets"].as_array().map(Vec::as_slice) {
+            Some([begin, end]) => begin.as_u64().zip(end.as_u64()),
+            _ => None,
+        }
+        .filter(|(begin, end)| begin <= end)
+        .ok_or_else(|| invalid_tensor("has no valid data offsets"))?;
+
+        // The offsets come from the file, so check them against the file before anything
+        // is allocated or read with them.
+        let (offset, data_end) = data_start
+            .checked_add(begin)
+            .zip(data_start.checked_add(end))
+            .filter(|&(_, data_end)| data_end <= file_len)
+            .ok_or_else(|| invalid_tensor("has data past the end of the file"))?;
+        let size = shape
+            .iter()
+            .try_fold(dtype_size, |size, &d| size.checked_mul(d))
+            .filter(|&size| u64::try_from(size).ok() == Some(data_end - offset))
+            .ok_or_else(|| invalid_tensor("has data that does not match its shape"))?;
+
+        tensors.push((
+            name,
+            SafetensorsTensor {
+                path: path.to_owned(),
+                dtype: dtype.to_string(),
+                shape,
+                offset,
+                size,
+            },
+        ));
+    }
+    Ok(tensors)
+}
+
+/// The vocabulary of a checkpoint, and what its tokenizer records besides the tokens.
+#[derive(Debug, Clone)]
+struct HfVocabulary {
+    /// Each token, with its score.
+    tokens: Vec<(Vec<u8>, f32)>,
+    /// The type of each token.
+    token_types: Vec<TokenType>,
+    /// The merges of a BPE tokenizer, each a pair of tokens separated by a space.
+    merges: Vec<String>,
+    /// The ID of the token for text that is not in the vocabulary, if there is one.
+    unknown_token_id: Option<usize>,
+    /// The kind of tokenizer, as [gguf::TOKENIZER_MODEL_KEY] names it.
+    model: &'static str,
+    /// The text of the checkpoint's `tokenizer.json`, if it has one.
+    tokenizer_json: Option<String>,
+}
+impl HfVocabulary {
+    /// Creates a vocabulary of `n_vocab` unused padding tokens.
+    fn new(n_vocab: usize) -> Self {
+        Self {
+            tokens: (0..n_vocab)
+                .map(|id| (format!("[PAD{id}]").into_bytes(), 0.0))
+                .collect(),
+            token_types: vec![TokenType::Unused; n_vocab],
+            merges: vec![],
+            unknown_token_id: None,
+            model: "llama",
+            tokenizer_json: None,
+        }
+    }
+
+    /// Sets the token `id`, if it is within the vocabulary.
+    fn set_token(&mut self, id: usize, token: Vec<u8>, score: f32, token_type: TokenType) {
+        if let (Some(entry), Some(entry_type)) =
+            (self.tokens.get_mut(id), self.token_types.get_mut(id))
+        {
+            *entry = (token, score);
+            *entry_type = token_type;
+        }
+    }
+
+    /// Reads the vocabulary, merges and special tokens of the `tokenizer.json` in `text`.
+    /// Returns the IDs of its special tokens.
+    fn read_tokenizer_json(
+        &mut self,
+        text: String,
+    ) -> Result<Vec<usize>, Box<dyn std::error::Error + Send + Sync>> {
+        let tokenizer: tokenizers::Tokenizer = text.parse()?;
+        let json: serde_json::Value = serde_json::from_str(&text)?;
+        let scores = hf_token_scores(&json);
+        let byte_level = matches!(
+            tokenizer.get_decoder(),
+            Some(tokenizers::DecoderWrapper::ByteLevel(_))
+        );
+
+        for (token, id) in tokenizer.get_vocab(true) {
+            let id = id as usize;
+            let token = if byte_level {
+                decode_byte_level_token(&token)
+            } else {
+                decode_sentencepiece_token(&token)
+            };
+            let score = scores.get(&id).copied().unwrap_or(0.0);
+            self.set_token(id, token, score, TokenType::Normal);
+        }
+
+        self.merges = hf_merges(&json);
+        self.unknown_token_id = json["model"]["unk_token"]
+            .as_str()
+            .and_then(|token| tokenizer.token_to_id(token))
+            .map(|id| id as usize);
+        self.model = if byte_level { "gpt2" } else { "llama" };
+        self.tokenizer_json = Some(text);
+
+        let added_tokens = json["added_tokens"].as_array().into_iter().flatten();
+        Ok(added_tokens
+            .filter(|token| token["special"].as_bool() == Some(true))
+            .filter_map(|token| token["id"].as_u64().map(|id| id as usize))
+            .collect())
+    }
+}
+
+/// Reads the vocabulary of the checkpoint, with the score and type of each token, padded to
+/// `n_vocab` tokens. The SentencePiece model (`tokenizer.model`) is preferred for the tokens
+/// when it is present, as it records the scores that SentencePiece tokenizes with;
+/// otherwise, they are read from `tokenizer.json`. The merges and special tokens are read
+/// from `tokenizer.json`, if there is one.
+fn read_hf_vocabulary(directory: &Path, n_vocab: usize) -> Result<HfVocabulary, ConvertError> {
+    let mut vocabulary = HfVocabulary::new(n_vocab);
+
+    let sentencepiece_path = directory.join("tokenizer.model");
+    let path = directory.join("tokenizer.json");
+    let mut special_token_ids = vec![];
+    if path.exists() || !sentencepiece_path.exists() {
+        let text = std::fs::read_to_string(&path).map_err(ConvertError::io(&path))?;
+        special_token_ids =
+            vocabulary
+                .read_tokenizer_json(text)
+                .map_err(|source| ConvertError::Tokenizer {
+                    path: path.clone(),
+                    source,
+                })?;
+    }
+
+    if sentencepiece_path.exists() {
+        let bytes =
+            std::fs::read(&sentencepiece_path).map_err(ConvertError::io(&sentencepiece_path))?;
+        let pieces =
+            parse_sentencepiece_model(&bytes).map_err(|reason| ConvertError::Tokenizer {
+                path: sentencepiece_path.clone(),
+                source: reason.into(),
+            })?;
+        for (id, (piece, score, token_type)) in pieces.into_iter().enumerate() {
+            vocabulary.set_token(id, decode_sentencepiece_token(&piece), score, token_type);
+        }
+        vocabulary.model = "llama";
+        vocabulary.unknown_token_id = vocabulary
+            .token_types
+            .iter()
+            .position(|&t| t == TokenType::Unknown)
+            .or(vocabulary.unknown_token_id);
+    }
+
+    for id in special_token_ids {
+        if let Some(token_type) = vocabulary.token_types.get_mut(id) {
+            *token_type = TokenType::Control;
+        }
+    }
+    Ok(vocabulary)
+}
+
+/// Returns the metadata of a model with the `hyperparameters` and `vocabulary` of the
+/// checkpoint with `config`.
+fn hf_metadata(
+    config: &HfConfig,
+    hyperparameters: &impl Hyperparameters,
+    vocabulary: HfVocabulary,
+) -> Result<gguf::Metadata, ConvertError> {
+    let mut hyperparameter_bytes = vec![];
+    hyperparameters
+        .write_ggml(&mut hyperparameter_bytes)
+        .map_err(ConvertError::HyperparametersWriteError)?;
+    let mut metadata = hf_metadata_from_bytes(config, hyperparameter_bytes, vocabulary);
+    if let Some(architecture) = config.get("model_type").and_then(|v| v.as_str()) {
+        gguf_metadata::insert_gguf_hyperparameters(&mut metadata, architecture, hyperparameters);
+    }
+    Ok(metadata)
+}
+
+/// Returns the metadata of a model whose hyperparameters are written as
+/// `hyperparameter_bytes`, like [hf_metadata].
+fn hf_metadata_from_bytes(
+    config: &HfConfig,
+    hyperparameter_bytes: Vec<u8>,
+    vocabulary: HfVocabulary,
+) -> gguf::Metadata {
+    let HfVocabulary {
+        tokens,
+        mut token_types,
+        merges,
+        unknown_token_id,
+        model,
+        tokenizer_json,
+    } = vocabulary;
+
+    // Some configs list several end-of-text tokens; only single IDs are recorded.
+    let config_id = |key: &str| {
+        config
+            .get(key)
+            .and_then(|id| id.as_u64())
+            .map(|id| id as usize)
+    };
+    let special_tokens = [
+        (
+            gguf::BOS_TOKEN_ID_KEY,
+            config_id("bos_token_id"),
+            TokenType::Control,
+        ),
+        (
+            gguf::EOS_TOKEN_ID_KEY,
+            config_id("eos_token_id"),
+            TokenType::Control,
+        ),
+        (
+            gguf::PADDING_TOKEN_ID_KEY,
+            config_id("pad_token_id"),
+            TokenType::Control,
+        ),
+        (
+            gguf::UNKNOWN_TOKEN_ID_KEY,
+            unknown_token_id,
+            TokenType::Unknown,
+        ),
+    ];
+    let mut special_token_ids = vec![];
+    for (key, id, token_type) in special_tokens {
+        let Some(id) = id.filter(|&id| id < tokens.len()) else {
+            continue;
+        };
+        if token_types[id] == TokenType::Normal {
+            token_types[id] = token_type;
+        }
+        special_token_ids.push((key, gguf::MetadataValue::U32(id as u32)));
+    }
+
+    let mut metadata =
+        gguf::vocabulary_metadata(hyperparameter_bytes, &tokens, Some(token_types.as_slice()));
+    if let Some(architecture) = config.get("model_type").and_then(|v| v.as_str()) {
+        metadata.insert(
+            gguf::ARCHITECTURE_KEY,
+            gguf::MetadataValue::String(architecture.to_string()),
+        );
+    }
+    metadata.insert(
+        gguf::TOKENIZER_MODEL_KEY,
+        gguf::MetadataValue::String(model.to_string()),
+    );
+    if !merges.is_empty() {
+        metadata.insert(
+            gguf::MERGES_KEY,
+            gguf::MetadataValue::Array(
+                gguf::MetadataValueType::String,
+                merges
+                    .into_iter()
+                    .map(gguf::MetadataValue::String)
+                    .collect(),
+            ),
+        );
+    }
+    for (key, id) in special_token_ids {
+        metadata.insert(key, id);
+    }
+    if let Some(json) = tokenizer_json {
+        metadata.insert(
+            gguf::HUGGINGFACE_TOKENIZER_KEY,
+            gguf::MetadataValue::String(json),
+        );
+    }
+    metadata
+}
+
+/// Reads the merges of the BPE model of a `tokenizer.json`, each as a pair of tokens
+/// separated by a space.
+fn hf_merges(tokenizer: &serde_json::Value) -> Vec<String> {
+    let merges = tokenizer["model"]["merges"]
+        .as_array()
+        .into_iter()
+        .flatten();
+    merges
+        .filter_map(|merge| match merge {
+            serde_json::Value::String(merge) => Some(merge.clone()),
+            serde_json::Value::Array(parts) => parts
+                .iter()
+                .map(|part| part.as_str())
+                .collect::<Option<Vec<_>>>()
+                .map(|parts| parts.join(" ")),
+            _ => None,
+        })
+        .collect()
+}
+
+/// Reads the scores of the tokens of the model of a `tokenizer.json`, by token ID.
+///
+/// Unigram models store the scores of their tokens. For BPE models, the score of a token is
+/// the negated rank of the first merge that produces it, so that merging the pair of tokens
+/// with the highest score reproduces the order of the merges.
+fn hf_token_scores(tokenizer: &serde_json::Value) -> HashMap<usize, f32> {
+    let model = &tokenizer["model"];
+    let mut scores = HashMap::new();
+    match model["type"].as_str() {
+        Some("Unigram") => {
+            let entries = model["vocab"].as_array().into_iter().flatten();
+            for (id, entry) in entries.enumerate() {
+                if let Some(score) = entry[1].as_f64() {
+                    scores.insert(id, score as f32);
+                }
+            }
+        }
+        Some("BPE") => {
+            let vocab = &model["vocab"];
+            let merges = model["merges"].as_array().into_iter().flatten();
+            for (rank, merge) in merges.enumerate() {
+                // Merges are either `"a b"` or, in newer files, `["a", "b"]`.
+                let merged = match merge {
+                    serde_json::Value::String(merge) => merge.replacen(' ', "", 1),
+                    serde_json::Value::Array(parts) => {
+                        parts.iter().filter_map(|part| part.as_str()).collect()
+                    }
+                    _ => continue,
+                };
+                if let Some(id) = vocab[merged.as_str()].as_u64() {
+                    scores.entry(id as usize).or_insert(-(rank as f32));
+                }
+            }
+        }
+        _ => {}
+    }
+    scores
+}
+
+/// Parses the pieces, and their scores and types, of a serialized SentencePiece `ModelProto`.
+///
+/// Only the fields of the pieces are read, so this decodes just enough of the protobuf wire
+/// format to skip over the others.
+fn parse_sentencepiece_model(bytes: &[u8]) -> Result<Vec<(String, f32, TokenType)>, String> {
+    fn read_varint(bytes: &[u8], position: &mut usize) -> Result<u64, String> {
+        let mut value = 0u64;
+        for shift in (0..64).step_by(7) {
+            let byte = *bytes
+                .get(*position)
+                .ok_or("the model ends in the middle of a field")?;
+            *position += 1;
+            value |= u64::from(byte & 0x7F) << shift;
+            if byte & 0x80 == 0 {
+                return Ok(value);
+            }
+        }
+        Err("the model has an invalid varint".to_string())
+    }
+
+    /// Calls `visit` with the number and the bytes of each field of the message in `bytes`;
+    /// varints are passed as their little-endian bytes.
+    fn visit_fields(
+        bytes: &[u8],
+        mut visit: impl FnMut(u64, &[u8]) -> Result<(), String>,
+    ) -> Result<(), String> {
+        let mut position = 0;
+        while position < bytes.len() {
+            let key = read_varint(bytes, &mut position)?;
+            let length = match key & 0x7 {
+                0 => {
+                    let value = read_varint(bytes, &mut position)?;
+                    visit(key >> 3, &value.to_le_bytes())?;
+                    continue;
+                }
+                1 => 8,
+                2 => read_varint(bytes, &mut position)? as usize,
+                5 => 4,
+                wire_type => return Err(format!("the model has an unknown wire type {wire_type}")),
+            };
+            let value = position
+                .checked_add(length)
+                .and_then(|end| bytes.get(position..end))
+                .ok_or("the model ends in the middle of a field")?;
+            visit(key >> 3, value)?;
+            position += length;
+        }
+        Ok(())
+    }
+
+    let mut pieces = vec![];
+    // `ModelProto.pieces` is field 1; `SentencePiece.piece` is field 1,
+    // `SentencePiece.score` is field 2 and `SentencePiece.type` is field 3, whose values
+    // are those of [TokenType].
+    visit_fields(bytes, |field, value| {
+        if field != 1 {
+            return Ok(());
+        }
+        let (mut piece, mut score, mut token_type) = (String::new(), 0.0, TokenType::Normal);
+        visit_fields(value, |field, value| {
+            match field {
+                1 => {
+                    piece = String::from_utf8(value.to_vec())
+                        .map_err(|_| "a piece is not valid UTF-8".to_string())?
+                }
+                2 => {
+                    score = f32::from_le_bytes(
+                        value
+                            .try_into()
+                            .map_err(|_| "a score is not a float".to_string())?,
+                    )
+                }
+                3 => {
+                    token_type = value
+                        .get(..4)
+                        .and_then(|bytes| {
+                            TokenType::try_from(i32::from_le_bytes(bytes.try_into().ok()?)).ok()
+                        })
+                        .ok_or_else(|| "a piece has an unknown type".to_string())?
+                }
+                _ => {}
+            }
+            Ok(())
+        })?;
+        pieces.push((piece, score, token_type));
+        Ok(())
+    })?;
+    Ok(pieces)
+}
+
+/// Decodes a token of a SentencePiece-style vocabulary, where spaces are stored as `▁` and
+/// bytes that are not valid UTF-8 on their own as `<0xNN>`.
+fn decode_sentencepiece_token(token: &str) -> Vec<u8> {
+    if let Some(byte) = token
+        .strip_prefix("<0x")
+        .and_then(|t| t.strip_suffix('>'))
+        .filter(|t| t.len() == 2)
+        .and_then(|t| u8::from_str_radix(t, 16).ok())
+    {
+        return vec![byte];
+    }
+    token.replace('\u{2581}', " ").into_bytes()
+}
+
+/// Decodes a token of a GPT-2-style byte-level vocabulary, where each byte is stored as a
+/// printable character.
+fn decode_byte_level_token(token: &str) -> Vec<u8> {
+    let byte_for_char = |c: char| -> Option<u8> {
+        let c = c as u32;
+        let printable = |c: u32| {
+            (u32::from(b'!')..=u32::from(b'~')).contains(&c)
+                || (0xA1..=0xAC).contains(&c)
+                || (0xAE..=0xFF).contains(&c)
+        };
+        if printable(c) {
+            return Some(c as u8);
+        }
+        // The other bytes are mapped, in order, to the characters from U+0100.
+        (0..=255u32)
+            .filter(|&b| !printable(b))
+            .nth(c.checked_sub(0x100)? as usize)
+            .map(|b| b as u8)
+    };
+    token
+        .chars()
+        .map(byte_for_char)
+        .collect::<Option<Vec<_>>>()
+        .unwrap_or_else(|| token.as_bytes().to_vec())
+}
+
+struct ConvertSaver<'a, M: KnownModel, F: Fn(ConvertProgress)> {
+    hyperparameters: &'a M::Hyperparameters,
+    /// The tensors to convert, by their converted names.
+    tensors: BTreeMap<String, (String, SafetensorsTensor)>,
+    element_type: ggml::Type,
+    /// The tensors that are quantized when converting to a quantized type.
+    to_quantize: Vec<Regex>,
+    to_skip: Vec<Regex>,
+    progress_callback: &'a F,
+}
+impl<M: KnownModel, F: Fn(ConvertProgress)> SaveHandler<ConvertError> for ConvertSaver<'_, M, F> {
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), ConvertError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(ConvertError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, name: &str) -> Result<TensorSaveInfo, ConvertError> {
+        let (source_name, tensor) = &self.tensors[name];
+        (self.progress_callback)(ConvertProgress::TensorConverting { source_name, name });
+
+        let (element_size, decode): (usize, fn(&[u8]) -> f32) = match tensor.dtype.as_str() {
+            "F32" => (4, |b| f32::from_le_bytes([b[0], b[1], b[2], b[3]])),
+            "F16" => (2, |b| f16::from_le_bytes([b[0], b[1]]).to_f32()),
+            "BF16" => (2, |b| bf16::from_le_bytes([b[0], b[1]]).to_f32()),
+            dtype => {
+                return Err(ConvertError::UnsupportedDataType {
+                    name: source_name.clone(),
+                    dtype: dtype.to_string(),
+                })
+            }
+        };
+
+        // The header was checked when it was read: the data is within the file, and its
+        // size matches the shape and dtype of the tensor.
+        let mut bytes = vec![0; tensor.size];
+        let mut file = File::open(&tensor.path).map_err(ConvertError::io(&tensor.path))?;
+        file.seek(SeekFrom::Start(tensor.offset))
+            .and_then(|_| file.read_exact(&mut bytes))
+            .map_err(ConvertError::io(&tensor.path))?;
+
+        let mut data: Vec<f32> = bytes.chunks_exact(element_size).map(decode).collect();
+        let n_elements = data.len();
+        M::hf_transform_tensor(name, &tensor.shape, &mut data, self.hyperparameters);
+
+        let (n_dims, dims, element_type) = self.layout(name, &tensor.shape);
+        // GGML files are little-endian, whatever the byte order of the host.
+        let data = match element_type {
+            ggml::Type::F16 => data
+                .iter()
+                .flat_map(|&v| f16::from_f32(v).to_le_bytes())
+                .collect(),
+            ggml::Type::Q8_0 => ggml::quantize_q8_0(&data, n_elements, dims[0]).output,
+            _ => data.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        };
+
+        Ok(TensorSaveInfo {
+            n_dims,
+            dims,
+            element_type,
+            data,
+        })
+    }
+}
+impl<M: KnownModel, F: Fn(ConvertProgress)> ConvertSaver<'_, M, F> {
+    /// Returns the number of dimensions, the dimensions and the element type of the
+    /// converted tensor `name`, whose shape in the checkpoint is `shape`.
+    fn layout(&self, name: &str, shape: &[usize]) -> (usize, [usize; 2], ggml::Type) {
+        // GGML lists dimensions from the innermost; 1D tensors are always stored as f32.
+        let (n_dims, dims) = match shape[..] {
+            [n] => (1, [n, 1]),
+            [rows, columns] => (2, [columns, rows]),
+            _ => unreachable!("the shape was checked when the tensors were listed"),
+        };
+        let element_type = match self.element_type {
+            _ if n_dims == 1 => ggml::Type::F32,
+            // Like the quantizer, only quantize the tensors the architecture allows, and only
+            // those whose rows are whole blocks.
+            element_type if element_type.is_quantized() => {
+                let quantize = self.to_quantize.iter().any(|re| re.is_match(name))
+                    && !self.to_skip.iter().any(|re| re.is_match(name))
+                    && dims[0] % ggml::blck_size(element_type) == 0;
+                if quantize {
+                    element_type
+                } else {
+                    ggml::Type::F16
+                }
+            }
+            element_type => element_type,
+        };
+        (n_dims, dims, element_type)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_model::{write_test_file, TestHyperparameters as Hp},
+        ModelFile, TokenizerSource,
+    };
+
+    #[test]
+    fn test_reverse_hf_rotary_permutation() {
+        // Two heads of four rows; the Hugging Face conversion stores the even rows of each
+        // head before the odd ones.
+        let mut data: Vec<f32> = [0, 2, 1, 3, 4, 6, 5, 7].map(|r| r as f32).to_vec();
+        reverse_hf_rotary_permutation(&mut data, 8, 2);
+        assert_eq!(data, (0..8).map(|r| r as f32).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn test_decode_tokens() {
+        assert_eq!(decode_sentencepiece_token("\u{2581}Hello"), b" Hello");
+        assert_eq!(decode_sentencepiece_token("<0x0A>"), b"\n");
+        assert_eq!(decode_sentencepiece_token("<s>"), b"<s>");
+        assert_eq!(decode_byte_level_token("\u{120}world"), b" world");
+        assert_eq!(decode_byte_level_token("\u{10a}"), b"\n");
+    }
+
+    #[test]
+    fn test_hf_token_scores() {
+        let bpe = serde_json::json!({
+            "model": {
+                "type": "BPE",
+                "vocab": {"a": 0, "b": 1, "ab": 2, "abb": 3},
+                "merges": ["a b", ["ab", "b"]]
+            }
+        });
+        let scores = hf_token_scores(&bpe);
+        assert_eq!(scores.get(&2), Some(&0.0));
+        assert_eq!(scores.get(&3), Some(&-1.0));
+        assert_eq!(scores.get(&0), None);
+
+        let unigram = serde_json::json!({
+            "model": {"type": "Unigram", "vocab": [["<unk>", 0.0], ["\u{2581}a", -2.5]]}
+        });
+        assert_eq!(hf_token_scores(&unigram).get(&1), Some(&-2.5));
+    }
+
+    #[test]
+    fn test_parse_sentencepiece_model() {
+        let piece = |text: &[u8], score: f32, token_type: TokenType| {
+            let mut message = vec![0x0A, text.len() as u8];
+            message.extend(text);
+            message.push(0x15);
+            message.extend(score.to_le_bytes());
+            message.extend([0x18, token_type as u8]);
+            let mut field = vec![0x0A, message.len() as u8];
+            field.extend(message);
+            field
+        };
+        let mut model = piece(b"<s>", 0.0, TokenType::Control);
+        model.extend(piece("\u{2581}a".as_bytes(), -1.5, TokenType::Normal));
+        // An empty `trainer_spec`, which is skipped.
+        model.extend([0x12, 0x00]);
+
+        assert_eq!(
+            parse_sentencepiece_model(&model).unwrap(),
+            vec![
+                ("<s>".to_string(), 0.0, TokenType::Control),
+                ("\u{2581}a".to_string(), -1.5, TokenType::Normal)
+            ]
+        );
+        assert!(parse_sentencepiece_model(&model[..model.len() - 1]).is_err());
+    }
+
+    #[test]
+    fn test_hf_tokenizer_metadata() {
+        let tokenizer_json = serde_json::json!({
+            "version": "1.0",
+            "truncation": null,
+            "padding": null,
+            "added_tokens": [{
+                "id": 0,
+                "content": "<|endoftext|>",
+                "single_word": false,
+                "lstrip": false,
+                "rstrip": false,
+                "normalized": false,
+                "special": true
+            }],
+            "normalizer": null,
+            "pre_tokenizer": null,
+            "post_processor": null,
+            "decoder": null,
+            "model": {
+                "type": "BPE",
+                "dropout": null,
+                "unk_token": "<unk>",
+                "continuing_subword_prefix": null,
+                "end_of_word_suffix": null,
+                "fuse_unk": false,
+                "vocab": {"<|endoftext|>": 0, "<unk>": 1, "a": 2, "b": 3, "ab": 4},
+                "merges": [["a", "b"]]
+            }
+        })
+        .to_string();
+
+        let mut vocabulary = HfVocabulary::new(6);
+        let special_token_ids = vocabulary
+            .read_tokenizer_json(tokenizer_json.clone())
+            .unwrap();
+        assert_eq!(special_token_ids, vec![0]);
+        assert_eq!(vocabulary.merges, vec!["a b".to_string()]);
+        assert_eq!(vocabulary.unknown_token_id, Some(1));
+        assert_eq!(vocabulary.tokens[4], (b"ab".to_vec(), 0.0));
+        // The vocabulary is padded to the size the hyperparameters give.
+        assert_eq!(vocabulary.token_types[5], TokenType::Unused);
+
+        let config = HfConfig::parse(r#"{"model_type": "gpt_neox", "eos_token_id": 0}"#).unwrap();
+        let tokens = vocabulary.tokens.clone();
+        let metadata = hf_metadata_from_bytes(&config, vec![1, 2, 3], vocabulary);
+        let get = |key| metadata.get(key).unwrap();
+        assert_eq!(get(gguf::ARCHITECTURE_KEY).as_str(), Some("gpt_neox"));
+        assert_eq!(get(gguf::EOS_TOKEN_ID_KEY).as_u64(), Some(0));
+        assert_eq!(get(gguf::UNKNOWN_TOKEN_ID_KEY).as_u64(), Some(1));
+        assert!(metadata.get(gguf::BOS_TOKEN_ID_KEY).is_none());
+        assert_eq!(
+            get(gguf::MERGES_KEY).as_array().unwrap(),
+            [gguf::MetadataValue::String("a b".to_string())]
+        );
+        assert_eq!(
+            get(gguf::HUGGINGFACE_TOKENIZER_KEY).as_str(),
+            Some(tokenizer_json.as_str())
+        );
+        let token_types: Vec<_> = get(gguf::TOKEN_TYPES_KEY)
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|t| match t {
+                gguf::MetadataValue::I32(t) => TokenType::try_from(*t).unwrap(),
+                _ => panic!("token types are i32"),
+            })
+            .collect();
+        assert_eq!(
+            token_types[..3],
+            [TokenType::Control, TokenType::Unknown, TokenType::Normal]
+        );
+        assert_eq!(
+            gguf::read_vocabulary::<std::convert::Infallible>(&metadata).unwrap(),
+            tokens
+        );
+    }
+
+    #[test]
+    fn test_config_values() {
+        let config =
+            HfConfig::parse(r#"{"hidden_size": 4096, "num_key_value_heads": null}"#).unwrap();
+        assert_eq!(config.usize("hidden_size").unwrap(), 4096);
+        assert_eq!(config.optional_usize("num_key_value_heads").unwrap(), None);
+        assert!(matches!(
+            config.usize("num_hidden_layers"),
+            Err(ConvertError::MissingConfigValue { .. })
+        ));
+
+        let config = HfConfig::parse(
+            r#"{"use_parallel_residual": true, "attn_config": {"alibi_bias_max": 8, "clip_qkv": null}}"#,
+        )
+        .unwrap();
+        assert_eq!(
+            config.optional_bool("use_parallel_residual").unwrap(),
+            Some(true)
+        );
+        let attn_config = config.optional_section("attn_config").unwrap().unwrap();
+        assert_eq!(
+            attn_config.optional_f32("alibi_bias_max").unwrap(),
+            Some(8.0)
+        );
+        assert_eq!(attn_config.optional_f32("clip_qkv").unwrap(), None);
+        assert!(config.optional_f32("use_parallel_residual").is_err());
+    }
+
+    #[test]
+    fn test_read_safetensors_header() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.safetensors");
+        let read = |header: serde_json::Value, data_len: usize| {
+            let header = header.to_string();
+            let mut file = (header.len() as u64).to_le_bytes().to_vec();
+            file.extend(header.as_bytes());
+            file.extend(vec![0; data_len]);
+            std::fs::write(&path, file).unwrap();
+            read_safetensors_header(&path)
+        };
+        let tensor = |dtype: &str, shape: &[u64], begin: u64, end: u64| serde_json::json!({ "a": { "dtype": dtype, "shape": shape, "data_offsets": [begin, end] } });
+
+        let tensors = read(tensor("F16", &[2, 3], 0, 12), 12).unwrap();
+        assert_eq!(tensors.len(), 1);
+        assert_eq!(tensors[0].1.size, 12);
+
+        for (header, data_len) in [
+            // The data does not match the shape.
+            (tensor("F16", &[2, 3], 0, 8), 12),
+            (tensor("F32", &[2, 3], 0, 12), 12),
+            // The data is past the end of the file.
+            (tensor("F16", &[2, 3], 4, 16), 12),
+            (tensor("F16", &[2, 3], u64::MAX - 4, u64::MAX), 12),
+            // The shape overflows.
+            (tensor("F16", &[u64::MAX, 2], 0, 12), 12),
+            (tensor("X", &[2, 3], 0, 12), 12),
+        ] {
+            assert!(matches!(
+                read(header, data_len),
+                Err(ConvertError::InvalidSafetensors { .. })
+            ));
+        }
+    }
+
+    #[test]
+    fn test_write_vocabulary_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("model.bin");
+        let vocab_path = dir.path().join("vocab-only.bin");
+        write_test_file(&path, SaveContainerType::GgjtV3);
+
+        let model_file = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&vocab_path).unwrap());
+        write_vocabulary_only(
+            &mut file,
+            &model_file.hyperparameters,
+            &model_file.tokenizer,
+        )
+        .unwrap();
+        drop(file);
+
+        let vocab_file = ModelFile::<Hp>::open(&vocab_path, TokenizerSource::Embedded).unwrap();
+        assert!(vocab_file.tensors.is_empty());
+        assert_eq!(vocab_file.hyperparameters, model_file.hyperparameters);
+        assert_eq!(
+            vocab_file.tokenizer.tokenize("hello world", false).unwrap(),
+            model_file.tokenizer.tokenize("hello world", false).unwrap()
+        );
+        assert!(
+            std::fs::metadata(&vocab_path).unwrap().len() < std::fs::metadata(&path).unwrap().len()
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! Implements [Model::embed], which computes the embeddings of many texts at once, pooled
+//! into one vector per text, for indexing documents for semantic search.
+
+use thiserror::Error;
+
+use crate::{
+    inference_session::SequenceSlots, InferenceError, InferenceSessionConfig, Model, OutputRequest,
+    Prompt,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// How the embeddings of the tokens of a text are combined into the embedding of the text.
+pub enum Pooling {
+    /// The embedding of the last token, which is the only one that has attended to all of
+    /// the text in a causal model.
+    #[default]
+    LastToken,
+    /// The mean of the embeddings of all of the tokens.
+    Mean,
+    /// The embedding of an end-of-text token appended to the text. This is the closest
+    /// equivalent of the `[CLS]` token of encoder models that a causal model has.
+    EndOfText,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+/// Options for [Model::embed].
+pub struct EmbeddingOptions {
+    /// How the embeddings of the tokens of each text are pooled.
+    pub pooling: Pooling,
+    /// Whether to scale each embedding to a length (L2 norm) of 1, so that their dot
+    /// products are their cosine similarities.
+    pub normalize: bool,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [Model::embed].
+pub enum EmbeddingError {
+    /// One of the texts has no tokens to embed.
+    #[error("text {index} has no tokens")]
+    EmptyText {
+        /// The index of the text.
+        index: usize,
+    },
+    /// Evaluating one of the texts failed.
+    #[error("embedding text {index} failed")]
+    Inference {
+        /// The index of the text.
+        index: usize,
+        /// The underlying error.
+        #[source]
+        source: InferenceError,
+    },
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The embeddings returned by [Model::embed]: a matrix with one row per text.
+pub struct Embeddings {
+    n_embd: usize,
+    values: Vec<f32>,
+}
+impl Embeddings {
+    /// The number of embeddings.
+    pub fn len(&self) -> usize {
+        self.values.len() / self.n_embd
+    }
+
+    /// Whether there are no embeddings.
+    pub fn is_empty(&self) -> bool {
+        self.values.is_empty()
+    }
+
+    /// The length of each embedding.
+    pub fn n_embd(&self) -> usize {
+        self.n_embd
+    }
+
+    /// The embedding of the text at `index`, if there is one.
+    pub fn get(&self, index: usize) -> Option<&[f32]> {
+        self.values.chunks_exact(self.n_embd).nth(index)
+    }
+
+    /// The embeddings, in the order of the texts.
+    pub fn iter(&self) -> impl Iterator<Item = &[f32]> {
+        self.values.chunks_exact(self.n_embd)
+    }
+
+    /// The embeddings as a row-major matrix of `len() * n_embd()` values.
+    pub fn as_slice(&self) -> &[f32] {
+        &self.values
+    }
+
+    /// Returns the embeddings as a row-major matrix of `len() * n_embd()` values.
+    pub fn into_vec(self) -> Vec<f32> {
+        self.values
+    }
+}
+
+pub(crate) fn embed(
+    model: &dyn Model,
+    texts: &[&str],
+    options: &EmbeddingOptions,
+    config: InferenceSessionConfig,
+) -> Result<Embeddings, EmbeddingError> {
+    let n_embd = model.hyperparameters().n_embd;
+    let mut values = Vec::with_capacity(texts.len() * n_embd);
+
+    // The session, and its key/value memory, is reused for all of the texts.
+    let mut session = model.start_session(config);
+    let texts = texts
+        .iter()
+        .enumerate()
+        .map(|(index, text)| {
+            let inference_error = |source| EmbeddingError::Inference { index, source };
+
+            let mut tokens = Prompt::from(*text)
+                .to_tokens(model.tokenizer(), true)
+                .map_err(|e| inference_error(e.into()))?;
+            if options.pooling == Pooling::EndOfText {
+                tokens.push(model.eot_token_id());
+            }
+            if tokens.is_empty() {
+                return Err(EmbeddingError::EmptyText { index });
+            }
+            session
+                .check_tokens(&tokens)
+                .map_err(|e| inference_error(e.into()))?;
+            if tokens.len() >= model.context_size() {
+                return Err(inference_error(InferenceError::ContextFull));
+            }
+            Ok(tokens)
+        })
+        .collect::<Result<Vec<_>, _>>()?;
+
+    if model.supports_sequence_batches() {
+        // The texts are evaluated together, each padded to the longest of them, in as few
+        // passes as fit in the context window.
+        let mut start = 0;
+        while start < texts.len() {
+            let mut end = start + 1;
+            let mut longest = texts[start].len();
+            while end < texts.len()
+                && longest.max(texts[end].len()) * (end + 1 - start) < model.context_size()
+            {
+                longest = longest.max(texts[end].len());
+                end += 1;
+            }
+
+            let sequences: Vec<_> = texts[start..end]
+                .iter()
+                .enumerate()
+                .map(|(sequence, tokens)| (sequence, tokens.as_slice()))
+                .collect();
+            let mut output_request = OutputRequest {
+                all_embeddings: Some(vec![]),
+                ..Default::default()
+            };
+            session.reset();
+            session
+                .evaluate_sequences(
+                    model,
+                    &mut SequenceSlots::new(0),
+                    &sequences,
+                    0,
+                    &mut output_request,
+                )
+                .map_err(|source| EmbeddingError::Inference {
+                    index: start,
+                    source,
+                })?;
+
+            let all_embeddings = output_request.all_embeddings.unwrap_or_default();
+            for (sequence, tokens) in sequences {
+                let token_embeddings =
+                    &all_embeddings[sequence * longest * n_embd..][..tokens.len() * n_embd];
+                values.extend(pool(token_embeddings, n_embd, options));
+            }
+            start = end;
+        }
+        return Ok(Embeddings { n_embd, values });
+    }
+
+    for (index, tokens) in texts.iter().enumerate() {
+        session.reset();
+        let mut token_embeddings = vec![];
+        for batch in tokens.chunks(session.config.n_batch) {
+            let mut output_request = match options.pooling {
+                Pooling::Mean => OutputRequest {
+                    all_embeddings: Some(vec![]),
+                    ..Default::default()
+                },
+                Pooling::LastToken | Pooling::EndOfText => OutputRequest {
+                    embeddings: Some(vec![]),
+                    ..Default::default()
+                },
+            };
+            model
+                .evaluate(&mut session, batch, &mut output_request)
+                .map_err(|source| EmbeddingError::Inference { index, source })?;
+
+            if let Some(all_embeddings) = output_request.all_embeddings {
+                token_embeddings.extend(all_embeddings);
+            } else if let Some(last_embedding) = output_request.embeddings {
+                token_embeddings = last_embedding;
+            }
+        }
+        values.extend(pool(&token_embeddings, n_embd, options));
+    }
+
+    Ok(Embeddings { n_embd, values })
+}
+
+/// Pools the embeddings of the tokens of a text, `n_embd` values each, into the embedding of
+/// the text as described by `options`. Only the embedding of the last token is needed unless
+/// they are pooled with [Pooling::Mean].
+fn pool(token_embeddings: &[f32], n_embd: usize, options: &EmbeddingOptions) -> Vec<f32> {
+    let mut embedding = match options.pooling {
+        Pooling::Mean => {
+            let mut embedding = vec![0.0; n_embd];
+            for token_embedding in token_embeddings.chunks_exact(n_embd) {
+                for (sum, value) in embedding.iter_mut().zip(token_embedding) {
+                    *sum += value;
+                }
+            }
+            let n_tokens = (token_embeddings.len() / n_embd) as f32;
+            for value in &mut embedding {
+                *value /= n_tokens;
+            }
+            embedding
+        }
+        Pooling::LastToken | Pooling::EndOfText => {
+            token_embeddings[token_embeddings.len() - n_embd..].to_vec()
+        }
+    };
+
+    if options.normalize {
+        let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+        if norm > 0.0 {
+            for value in &mut embedding {
+                *value /= norm;
+            }
+        }
+    }
+    embedding
+}
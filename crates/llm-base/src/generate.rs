@@ -0,0 +1,217 @@
+//! Implements [generate], which completes a prompt in one call, for applications that do
+//! not need to stream the output or to reuse the session.
+
+use std::time::{Duration, Instant};
+
+use crate::{
+    InferenceError, InferenceFeedback, InferenceParameters, InferenceRequest, InferenceResponse,
+    InferenceSessionConfig, InferenceStats, Model, Prompt, TokenId,
+};
+
+#[derive(Debug, Clone, Default, serde::Deserialize)]
+#[serde(default)]
+/// Options for [generate]. These can be deserialized, e.g. from the body of a request,
+/// with missing fields keeping their defaults; the parameters are deserialized as
+/// described for [InferenceParametersBuilder](crate::InferenceParametersBuilder).
+pub struct GenerateOptions {
+    /// The parameters to sample tokens with.
+    pub parameters: InferenceParameters,
+    /// The configuration of the session the prompt is completed in.
+    pub session_config: InferenceSessionConfig,
+    /// The maximum number of tokens to generate. If `None`, tokens are generated until the
+    /// end of text, a stop sequence or the end of the context window.
+    pub maximum_token_count: Option<usize>,
+    /// The maximum time to spend on the prompt and its completion; see
+    /// [InferenceRequest::maximum_duration].
+    pub maximum_duration: Option<Duration>,
+    /// Texts that end the completion when it generates one of them. The stop sequence is
+    /// not included in the returned text.
+    pub stop_sequences: Vec<String>,
+    /// The seed of the random number generator tokens are sampled with. If `None`, the
+    /// generator is seeded randomly.
+    pub seed: Option<u64>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// Why [generate] stopped generating.
+pub enum StopReason {
+    /// The model generated the end-of-text token.
+    EndOfText,
+    /// The completion generated one of the [GenerateOptions::stop_sequences].
+    StopSequence,
+    /// [GenerateOptions::maximum_token_count] tokens were generated.
+    MaximumTokens,
+    /// [GenerateOptions::maximum_duration] passed.
+    MaximumDuration,
+    /// The context window is full.
+    ContextFull,
+}
+
+#[derive(Debug, Clone)]
+/// The completion of a prompt returned by [generate].
+pub struct GenerationResult {
+    /// The text of the completion, up to any stop sequence.
+    pub text: String,
+    /// The tokens generated after the prompt, including the end-of-text token if the
+    /// completion ended with one, and the tokens of the stop sequence if it ended with one.
+    pub tokens: Vec<TokenId>,
+    /// Why generation stopped.
+    pub stop_reason: StopReason,
+    /// Statistics about the prompt and the completion.
+    pub stats: InferenceStats,
+}
+
+/// Completes `prompt` with `model` in a new session, and returns the completion.
+///
+/// This feeds the prompt and samples tokens until the end of text, one of the
+/// [stop sequences](GenerateOptions::stop_sequences), or one of the limits of the
+/// `options`. Unless the session rolls its context window (see
+/// [InferenceSessionConfig::attention_sinks]), generation also stops when the context
+/// window is full, instead of failing. Use [InferenceSession::infer](crate::InferenceSession::infer)
+/// to stream the completion or to continue from it.
+pub fn generate<'a>(
+    model: &dyn Model,
+    prompt: impl Into<Prompt<'a>>,
+    options: &GenerateOptions,
+) -> Result<GenerationResult, InferenceError> {
+    let mut session = model.start_session(options.session_config);
+
+    // The prompt is fed separately, so that the room left after it (which depends on how
+    // it was truncated) is known before generating.
+    let prompt = prompt.into();
+    let feed_prompt_start_at = Instant::now();
+    if !prompt.is_empty() {
+        session.feed_prompt(model, prompt, &mut Default::default(), |_| {
+            InferenceFeedback::Continue
+        })?;
+    }
+    let feed_prompt_duration = feed_prompt_start_at.elapsed();
+    let n_prompt_tokens = session.tokens().len();
+
+    let room = match options.session_config.attention_sinks {
+        Some(_) => usize::MAX,
+        // The last position of the context window is left free: evaluating the token
+        // sampled there would need room that is not left.
+        None => model.context_size().saturating_sub(n_prompt_tokens + 1),
+    };
+    let maximum_token_count = options.maximum_token_count.unwrap_or(usize::MAX);
+
+    let mut stop_sequences = StopSequences::new(&options.stop_sequences);
+    let mut text = String::new();
+    let mut stopped_at_sequence = false;
+    let mut stats = session.infer(
+        model,
+        &mut rand::thread_rng(),
+        &InferenceRequest::builder("", &options.parameters)
+            .maximum_token_count(maximum_token_count.min(room))
+            .maximum_duration(
+                options
+                    .maximum_duration
+                    .map(|duration| duration.saturating_sub(feed_prompt_duration)),
+            )
+            .seed(options.seed)
+            .build(),
+        &mut Default::default(),
+        |response| {
+            if let InferenceResponse::InferredToken(token) = response {
+                let (printable, stopped) = stop_sequences.push(&token);
+                text.push_str(&printable);
+                if stopped {
+                    stopped_at_sequence = true;
+                    return InferenceFeedback::Halt;
+                }
+            }
+            InferenceFeedback::Continue
+        },
+    )?;
+    text.push_str(&stop_sequences.finish());
+    stats.feed_prompt_duration = feed_prompt_duration;
+    stats.prompt_tokens = n_prompt_tokens;
+
+    let tokens = session.tokens()[n_prompt_tokens..].to_vec();
+    let stop_reason = if stopped_at_sequence {
+        StopReason::StopSequence
+    } else if tokens.last() == Some(&model.eot_token_id()) {
+        StopReason::EndOfText
+    } else if stats.predict_tokens >= maximum_token_count {
+        StopReason::MaximumTokens
+    } else if stats.predict_tokens >= room {
+        StopReason::ContextFull
+    } else {
+        StopReason::MaximumDuration
+    };
+
+    Ok(GenerationResult {
+        text,
+        tokens,
+        stop_reason,
+        stats,
+    })
+}
+
+/// Finds stop sequences in generated text as it is streamed, holding back the end of the
+/// text while it could still become a stop sequence, so that no part of a stop sequence
+/// is passed on. [generate] uses it to end the completion at its stop sequences.
+#[derive(Debug, Clone)]
+pub struct StopSequences<'a> {
+    sequences: &'a [String],
+    pending: String,
+}
+impl<'a> StopSequences<'a> {
+    /// Creates a matcher for `sequences`. Empty sequences are ignored.
+    pub fn new(sequences: &'a [String]) -> Self {
+        Self {
+            sequences,
+            pending: String::new(),
+        }
+    }
+
+    /// Adds `text` to the generated text, and returns the text that can be passed on and
+    /// whether a stop sequence was found. The text ends before the stop sequence.
+    pub fn push(&mut self, text: &str) -> (String, bool) {
+        self.pending.push_str(text);
+        let stop = self
+            .sequences
+            .iter()
+            .filter(|stop| !stop.is_empty())
+            .filter_map(|stop| self.pending.find(stop.as_str()))
+            .min();
+        if let Some(stop) = stop {
+            self.pending.truncate(stop);
+            return (std::mem::take(&mut self.pending), true);
+        }
+
+        let longest = self.sequences.iter().map(String::len).max().unwrap_or(0);
+        let mut printable = self.pending.len().saturating_sub(longest.saturating_sub(1));
+        while !self.pending.is_char_boundary(printable) {
+            printable -= 1;
+        }
+        let held_back = self.pending.split_off(printable);
+        (std::mem::replace(&mut self.pending, held_back), false)
+    }
+
+    /// Returns the text that was held back, once generation has finished.
+    pub fn finish(self) -> String {
+        self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stop_sequences() {
+        let sequences = ["\n\n".to_string(), "END".to_string()];
+        let mut stop = StopSequences::new(&sequences);
+        // The end of the text is held back while it could start a stop sequence.
+        assert_eq!(stop.push("Hello"), ("Hel".to_string(), false));
+        assert_eq!(stop.push(", E"), ("lo,".to_string(), false));
+        assert_eq!(stop.push("ND of it"), (" ".to_string(), true));
+
+        // Text is not split inside a character.
+        let mut stop = StopSequences::new(&sequences);
+        assert_eq!(stop.push("abé!"), ("ab".to_string(), false));
+        assert_eq!(stop.finish(), "é!");
+    }
+}
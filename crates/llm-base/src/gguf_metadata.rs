@@ -0,0 +1,405 @@
+//! Implements reading and editing the key-value metadata of GGUF models, such as their
+//! chat template or license, without rewriting their tensors where possible.
+//!
+//! The tensor data of a GGUF file starts after its header, aligned, so the header can be
+//! rewritten in place if it still ends before the same aligned position. Otherwise, the
+//! model is written again to a temporary file next to it, which then replaces it; the
+//! offsets of the tensors are relative to the start of their data, so they are unchanged.
+//!
+//! It also reads and writes the hyperparameters of a model under the standard keys of its
+//! architecture, such as `llama.context_length`, with [GgufHyperparameters].
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, BufReader, BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ggml::format::gguf::{self, Gguf, Metadata, MetadataValue};
+use thiserror::Error;
+
+use crate::{model, ContainerType, FileType, FileTypeFormat, Hyperparameters, LoadError};
+
+#[derive(Error, Debug)]
+/// Errors encountered while editing the metadata of a GGUF model.
+pub enum GgufMetadataError {
+    #[error("could not read the model")]
+    /// The model could not be read.
+    Load(#[from] LoadError),
+    #[error("{path:?} is a {container_type:?} model, which has no key-value metadata")]
+    /// The model is not a GGUF model.
+    NotGguf {
+        /// The path of the model.
+        path: PathBuf,
+        /// The container type of the model.
+        container_type: ContainerType,
+    },
+    #[error("the metadata key `{key}` describes the layout of the model, and cannot be edited")]
+    /// The key is one that `llm` needs to read the model, such as its hyperparameters,
+    /// vocabulary or the alignment of its tensors.
+    ProtectedKey {
+        /// The key.
+        key: String,
+    },
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] io::Error),
+}
+
+/// How the header of a model was rewritten after its metadata was edited.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HeaderRewrite {
+    /// The header was rewritten in place, as it still fits before the tensor data.
+    InPlace,
+    /// The whole model was rewritten, as the header no longer fits before the tensor data.
+    WholeFile,
+}
+
+/// The keys that describe the layout of a model, rather than the model itself.
+const PROTECTED_KEYS: [&str; 4] = [
+    gguf::ALIGNMENT_KEY,
+    gguf::HYPERPARAMETERS_KEY,
+    gguf::TOKENS_KEY,
+    gguf::SCORES_KEY,
+];
+
+/// Reads the metadata of the model at `path`, or returns `None` if it is not a GGUF model.
+pub fn read_gguf_metadata(path: &Path) -> Result<Option<Metadata>, LoadError> {
+    Ok(read_header(path)?.ok().map(|gguf| gguf.metadata))
+}
+
+/// Sets the metadata key `key` of the GGUF model at `path` to `value`, adding it if the
+/// model does not have it. Returns the previous value, and how the header was rewritten.
+pub fn set_gguf_metadata(
+    path: &Path,
+    key: &str,
+    value: MetadataValue,
+) -> Result<(Option<MetadataValue>, HeaderRewrite), GgufMetadataError> {
+    edit_metadata(path, key, |metadata| metadata.insert(key, value))
+        .map(|(previous, rewrite)| (previous, rewrite.expect("the metadata was changed")))
+}
+
+/// Removes the metadata key `key` from the GGUF model at `path`. Returns its value, and
+/// how the header was rewritten, or `None` if the model does not have the key, in which
+/// case it is left untouched.
+pub fn remove_gguf_metadata(
+    path: &Path,
+    key: &str,
+) -> Result<Option<(MetadataValue, HeaderRewrite)>, GgufMetadataError> {
+    let (previous, rewrite) = edit_metadata(path, key, |metadata| metadata.remove(key))?;
+    Ok(previous.zip(rewrite))
+}
+
+/// Applies `edit` to the metadata of the model at `path`, and writes the new header if it
+/// returns a value (the previous value of `key`) or `key` was added.
+fn edit_metadata(
+    path: &Path,
+    key: &str,
+    edit: impl FnOnce(&mut Metadata) -> Option<MetadataValue>,
+) -> Result<(Option<MetadataValue>, Option<HeaderRewrite>), GgufMetadataError> {
+    if PROTECTED_KEYS.contains(&key) {
+        return Err(GgufMetadataError::ProtectedKey {
+            key: key.to_string(),
+        });
+    }
+
+    let mut header = read_header(path)?.map_err(|container_type| GgufMetadataError::NotGguf {
+        path: path.to_owned(),
+        container_type,
+    })?;
+    let data_position = header.tensor_data_position();
+    let had_key = header.metadata.get(key).is_some();
+    let previous = edit(&mut header.metadata);
+    if previous.is_none() && had_key == header.metadata.get(key).is_some() {
+        return Ok((None, None));
+    }
+
+    if header.tensor_data_position() == data_position {
+        let mut file = OpenOptions::new().write(true).open(path)?;
+        let mut writer = BufWriter::new(&mut file);
+        header.write(&mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        return Ok((previous, Some(HeaderRewrite::InPlace)));
+    }
+
+    // Write the model again next to it, so that it is replaced atomically.
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temporary_path = path.with_file_name(format!(".{file_name}.{}.tmp", std::process::id()));
+    let result = (|| -> io::Result<()> {
+        let mut source = BufReader::new(File::open(path)?);
+        source.seek(SeekFrom::Start(data_position))?;
+        let mut file = File::create(&temporary_path)?;
+        let mut writer = BufWriter::new(&mut file);
+        header.write(&mut writer)?;
+        io::copy(&mut source, &mut writer)?;
+        writer.flush()?;
+        drop(writer);
+        file.sync_all()?;
+        fs::rename(&temporary_path, path)
+    })();
+    if let Err(err) = result {
+        let _ = fs::remove_file(&temporary_path);
+        return Err(err.into());
+    }
+    Ok((previous, Some(HeaderRewrite::WholeFile)))
+}
+
+/// Reads the header of the model at `path`, or returns its container type if it is not a
+/// GGUF model.
+fn read_header(path: &Path) -> Result<Result<Gguf, ContainerType>, LoadError> {
+    let file = File::open(path).map_err(|source| LoadError::OpenFileFailed {
+        source,
+        path: path.to_owned(),
+    })?;
+    let mut reader = BufReader::new(file);
+    let container_type = ContainerType::read::<LoadError>(&mut reader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+    if !matches!(container_type, ContainerType::Gguf(_)) {
+        return Ok(Err(container_type));
+    }
+    reader.seek(SeekFrom::Start(0))?;
+    Gguf::read::<LoadError>(&mut reader)
+        .map(Ok)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))
+}
+
+/// The hyperparameters of a GGUF model under the standard keys of its architecture, such as
+/// `llama.context_length`, which [Hyperparameters::read_gguf] reads them from.
+///
+/// Models written by `llm` also record their hyperparameters in the layout of the older
+/// containers, which is read instead; those written by other tools only have these keys.
+#[derive(Debug, Clone, Copy)]
+pub struct GgufHyperparameters<'a> {
+    architecture: &'a str,
+    metadata: &'a Metadata,
+}
+impl<'a> GgufHyperparameters<'a> {
+    /// Returns the hyperparameters in `metadata`, or `None` if it does not record the
+    /// architecture of the model.
+    pub fn new(metadata: &'a Metadata) -> Option<Self> {
+        let architecture = metadata.get(gguf::ARCHITECTURE_KEY)?.as_str()?;
+        Some(Self {
+            architecture,
+            metadata,
+        })
+    }
+
+    /// Returns the architecture of the model, such as `llama`.
+    pub fn architecture(&self) -> &'a str {
+        self.architecture
+    }
+
+    /// Returns the value of `key` of the architecture (such as `context_length` for
+    /// `llama.context_length`), if it is present.
+    pub fn get(&self, key: &str) -> Option<&'a MetadataValue> {
+        self.metadata.get(&format!("{}.{key}", self.architecture))
+    }
+
+    /// Returns the value of `key` as an integer.
+    pub fn usize(&self, key: &str) -> Result<usize, LoadError> {
+        self.optional_usize(key)?
+            .ok_or_else(|| self.invalid(key, "an integer"))
+    }
+
+    /// Returns the value of `key` as an integer, or `None` if it is not present.
+    pub fn optional_usize(&self, key: &str) -> Result<Option<usize>, LoadError> {
+        self.optional(key, "an integer", |value| {
+            value.as_u64().and_then(|v| v.try_into().ok())
+        })
+    }
+
+    /// Returns the value of `key` as a float, or `None` if it is not present.
+    pub fn optional_f32(&self, key: &str) -> Result<Option<f32>, LoadError> {
+        self.optional(key, "a float", |value| match *value {
+            MetadataValue::F32(v) => Some(v),
+            MetadataValue::F64(v) => Some(v as f32),
+            _ => None,
+        })
+    }
+
+    /// Returns the value of `key` as a boolean, or `None` if it is not present.
+    pub fn optional_bool(&self, key: &str) -> Result<Option<bool>, LoadError> {
+        self.optional(key, "a boolean", |value| match *value {
+            MetadataValue::Bool(v) => Some(v),
+            _ => None,
+        })
+    }
+
+    /// Returns the number of tokens in the vocabulary of the model.
+    pub fn n_vocab(&self) -> usize {
+        self.metadata
+            .get(gguf::TOKENS_KEY)
+            .and_then(|tokens| tokens.as_array())
+            .map_or(0, |tokens| tokens.len())
+    }
+
+    /// Returns the file type of the model from `general.file_type` and
+    /// `general.quantization_version`. Models that do not record it are assumed to be
+    /// mostly `f16`.
+    pub fn file_type(&self) -> Result<FileType, LoadError> {
+        let format = match self.metadata.get(gguf::FILE_TYPE_KEY) {
+            Some(value) => {
+                let ftype = value
+                    .as_u64()
+                    .and_then(|v| i32::try_from(v).ok())
+                    .ok_or_else(|| self.invalid(gguf::FILE_TYPE_KEY, "an integer"))?;
+                FileTypeFormat::try_from(ftype as ggml::sys::llama::llama_ftype)
+                    .map_err(|()| LoadError::UnsupportedFileType(ftype))?
+            }
+            None => FileTypeFormat::default(),
+        };
+        let quantization_version = match self.metadata.get(gguf::QUANTIZATION_VERSION_KEY) {
+            Some(value) => value
+                .as_u64()
+                .and_then(|v| u32::try_from(v).ok())
+                .ok_or_else(|| self.invalid(gguf::QUANTIZATION_VERSION_KEY, "an integer"))?,
+            None => 0,
+        };
+        Ok(FileType {
+            format,
+            quantization_version,
+        })
+    }
+
+    fn optional<T>(
+        &self,
+        key: &str,
+        expected: &str,
+        convert: impl FnOnce(&MetadataValue) -> Option<T>,
+    ) -> Result<Option<T>, LoadError> {
+        self.get(key)
+            .map(|value| convert(value).ok_or_else(|| self.invalid(key, expected)))
+            .transpose()
+    }
+
+    fn invalid(&self, key: &str, expected: &str) -> LoadError {
+        let key = if key.starts_with("general.") {
+            key.to_string()
+        } else {
+            format!("{}.{key}", self.architecture)
+        };
+        LoadError::InvariantBroken {
+            path: None,
+            invariant: format!("the metadata has `{key}` as {expected}"),
+        }
+    }
+}
+
+/// Records the `hyperparameters` of a model of `architecture` in `metadata` under the
+/// standard keys of the architecture, and its file type under `general.file_type` and
+/// `general.quantization_version`, so that other tools can read the model.
+pub(crate) fn insert_gguf_hyperparameters(
+    metadata: &mut Metadata,
+    architecture: &str,
+    hyperparameters: &impl Hyperparameters,
+) {
+    for (key, value) in hyperparameters.gguf_metadata() {
+        let value = match value {
+            model::MetadataValue::Integer(v) => match u32::try_from(v) {
+                Ok(v) => MetadataValue::U32(v),
+                Err(_) => MetadataValue::I64(v),
+            },
+            model::MetadataValue::Float(v) => MetadataValue::F32(v),
+            model::MetadataValue::Bool(v) => MetadataValue::Bool(v),
+            model::MetadataValue::String(v) => MetadataValue::String(v),
+        };
+        metadata.insert(format!("{architecture}.{key}"), value);
+    }
+    if let Some(file_type) = hyperparameters.file_type() {
+        let ftype = ggml::sys::llama::llama_ftype::from(file_type.format);
+        metadata.insert(gguf::FILE_TYPE_KEY, MetadataValue::U32(ftype as u32));
+        metadata.insert(
+            gguf::QUANTIZATION_VERSION_KEY,
+            MetadataValue::U32(file_type.quantization_version),
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ggml::format::gguf::TensorInfo;
+
+    /// Writes a GGUF model with one tensor, whose data is `data`, and returns the position
+    /// of its tensor data.
+    fn write_model(path: &Path, metadata: Metadata, data: &[u8]) -> u64 {
+        let header = Gguf {
+            metadata,
+            tensor_infos: vec![TensorInfo {
+                name: "weights".to_string(),
+                dims: vec![data.len()],
+                element_type: ggml::Type::I8,
+                offset: 0,
+            }],
+        };
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        bytes.extend_from_slice(data);
+        fs::write(path, bytes).unwrap();
+        header.tensor_data_position()
+    }
+
+    fn tensor_data(path: &Path) -> Vec<u8> {
+        let header = read_header(path).unwrap().unwrap();
+        fs::read(path).unwrap()[header.tensor_data_position() as usize..].to_vec()
+    }
+
+    #[test]
+    fn test_edit_gguf_metadata() {
+        let path = std::env::temp_dir().join(format!("llm-gguf-meta-{}.gguf", std::process::id()));
+        let mut metadata = Metadata::default();
+        metadata.insert(
+            gguf::ARCHITECTURE_KEY,
+            MetadataValue::String("llama".to_string()),
+        );
+        // Leave room for a short key before the tensor data.
+        metadata.insert(gguf::ALIGNMENT_KEY, MetadataValue::U32(256));
+        let data: Vec<u8> = (0..=255).collect();
+        let data_position = write_model(&path, metadata, &data);
+
+        // A short name fits in the padding before the tensor data.
+        let name = "general.name";
+        let padding = data_position - read_header(&path).unwrap().unwrap().header_len();
+        assert!(padding > 8 + name.len() as u64 + 4 + 8);
+        assert_eq!(
+            set_gguf_metadata(&path, name, MetadataValue::String("x".to_string())).unwrap(),
+            (None, HeaderRewrite::InPlace)
+        );
+        assert_eq!(fs::metadata(&path).unwrap().len(), data_position + 256);
+        assert_eq!(tensor_data(&path), data);
+
+        // A long license does not.
+        let license = MetadataValue::String("license text ".repeat(20));
+        assert_eq!(
+            set_gguf_metadata(&path, "general.license", license.clone()).unwrap(),
+            (None, HeaderRewrite::WholeFile)
+        );
+        assert_eq!(tensor_data(&path), data);
+        let metadata = read_gguf_metadata(&path).unwrap().unwrap();
+        assert_eq!(metadata.get("general.license"), Some(&license));
+        assert_eq!(
+            metadata.get(name),
+            Some(&MetadataValue::String("x".to_string()))
+        );
+
+        assert_eq!(
+            remove_gguf_metadata(&path, "general.license").unwrap(),
+            Some((license, HeaderRewrite::WholeFile))
+        );
+        assert_eq!(
+            remove_gguf_metadata(&path, "general.license").unwrap(),
+            None
+        );
+        assert_eq!(tensor_data(&path), data);
+        assert!(matches!(
+            remove_gguf_metadata(&path, gguf::HYPERPARAMETERS_KEY),
+            Err(GgufMetadataError::ProtectedKey { .. })
+        ));
+
+        fs::write(&path, b"ggml").unwrap();
+        assert!(read_gguf_metadata(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,465 @@
+//! Implements reading models over HTTP(S) with range requests, so that only the parts
+//! of the file that are needed are downloaded, as they are needed, and they can be cached
+//! locally.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs,
+    io::{self, Read, Seek, SeekFrom},
+    path::{Path, PathBuf},
+};
+
+use sha2::{Digest, Sha256};
+use tracing::log;
+
+use crate::{
+    load_from_reader, util, KnownModel, LoadError, LoadProgress, ModelParameters, TokenizerSource,
+};
+
+/// The size of the ranges that are requested, and of the pages that are cached.
+const PAGE_SIZE: u64 = 4 * 1024 * 1024;
+/// The number of pages that are kept in memory.
+const PAGES_IN_MEMORY: usize = 16;
+
+/// Reads a file over HTTP(S) with range requests, downloading it a page at a time as it is
+/// read. Pages are kept in memory while they are likely to be read again, and are also
+/// written to a cache directory if one is given, so that they are not downloaded again.
+///
+/// Reads that continue where the previous one ended are streamed from a single request,
+/// so reading the file from start to end downloads it as it is read, without waiting for
+/// a request per page; a new request is only made when a read skips ahead or goes back.
+///
+/// The server must support range requests, and report the length of the file. If it also
+/// reports an `ETag` (or a `Last-Modified` time), the pages are only read from the cache
+/// and downloaded for that version of the file, so a file that changes is downloaded again
+/// instead of being mixed with stale pages.
+pub struct HttpRangeReader {
+    agent: ureq::Agent,
+    url: String,
+    len: u64,
+    version: Option<String>,
+    position: u64,
+    cache_dir: Option<PathBuf>,
+    cache_key: String,
+    pages: HashMap<u64, Vec<u8>>,
+    page_order: VecDeque<u64>,
+    /// The response that is being streamed, and the index of the next page it returns.
+    stream: Option<(u64, Box<dyn Read + Send + Sync>)>,
+}
+impl HttpRangeReader {
+    /// Opens the file at `url`, caching the pages that are read in `cache_dir` if it is given.
+    pub fn new(url: &str, cache_dir: Option<&Path>) -> io::Result<Self> {
+        let agent = ureq::Agent::new();
+        let response = agent.head(url).call().map_err(http_error)?;
+        if response.header("Accept-Ranges") != Some("bytes") {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!("{url} does not support range requests"),
+            ));
+        }
+        let len = response
+            .header("Content-Length")
+            .and_then(|len| len.parse().ok())
+            .ok_or_else(|| {
+                io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!("{url} did not report its length"),
+                )
+            })?;
+
+        let version = response
+            .header("ETag")
+            .or_else(|| response.header("Last-Modified"))
+            .map(str::to_owned);
+
+        if let Some(cache_dir) = cache_dir {
+            fs::create_dir_all(cache_dir)?;
+        }
+        let cache_key = cache_key(url, len, version.as_deref());
+
+        Ok(Self {
+            agent,
+            url: url.to_owned(),
+            len,
+            version,
+            position: 0,
+            cache_dir: cache_dir.map(Path::to_owned),
+            cache_key,
+            pages: HashMap::new(),
+            page_order: VecDeque::new(),
+            stream: None,
+        })
+    }
+
+    /// The length of the file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    fn page(&mut self, index: u64) -> io::Result<&[u8]> {
+        if !self.pages.contains_key(&index) {
+            let page = match self.read_cached_page(index) {
+                Some(page) => page,
+                None => self.fetch_page(index)?,
+            };
+            if self.page_order.len() == PAGES_IN_MEMORY {
+                if let Some(oldest) = self.page_order.pop_front() {
+                    self.pages.remove(&oldest);
+                }
+            }
+            self.page_order.push_back(index);
+            self.pages.insert(index, page);
+        }
+        Ok(&self.pages[&index])
+    }
+
+    fn cached_page_path(&self, index: u64) -> Option<PathBuf> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        Some(cache_dir.join(format!("{}-{index}", self.cache_key)))
+    }
+
+    fn read_cached_page(&self, index: u64) -> Option<Vec<u8>> {
+        let page = fs::read(self.cached_page_path(index)?).ok()?;
+        (page.len() as u64 == self.page_len(index)).then_some(page)
+    }
+
+    /// Downloads the page `index`, continuing the current stream if it is at that page, or
+    /// starting a new one that runs to the end of the file otherwise.
+    fn fetch_page(&mut self, index: u64) -> io::Result<Vec<u8>> {
+        let mut stream = match self.stream.take() {
+            Some((next, stream)) if next == index => stream,
+            _ => self.request_from(index * PAGE_SIZE)?,
+        };
+
+        let mut page = vec![0; self.page_len(index) as usize];
+        stream.read_exact(&mut page).map_err(|err| {
+            io::Error::new(
+                err.kind(),
+                format!("{} returned a short range: {err}", self.url),
+            )
+        })?;
+        self.stream = Some((index + 1, stream));
+
+        if let Some(path) = self.cached_page_path(index) {
+            // The cache is only an optimization; the page can be fetched again.
+            if let Err(err) = fs::write(&path, &page) {
+                log::warn!("Could not cache {path:?}: {err}");
+            }
+        }
+        Ok(page)
+    }
+
+    /// Requests the file from `start` to its end.
+    fn request_from(&self, start: u64) -> io::Result<Box<dyn Read + Send + Sync>> {
+        let mut request = self
+            .agent
+            .get(&self.url)
+            .set("Range", &format!("bytes={start}-"));
+        if let Some(version) = &self.version {
+            // If the file has changed, the server returns all of it instead of the range.
+            request = request.set("If-Range", version);
+        }
+        let response = request.call().map_err(http_error)?;
+        if response.status() != 206 {
+            return Err(io::Error::new(
+                io::ErrorKind::Unsupported,
+                format!(
+                    "{} ignored the range request, or the file changed while it was read",
+                    self.url
+                ),
+            ));
+        }
+        Ok(response.into_reader())
+    }
+
+    fn page_len(&self, index: u64) -> u64 {
+        PAGE_SIZE.min(self.len - index * PAGE_SIZE)
+    }
+}
+impl Read for HttpRangeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let offset = (self.position % PAGE_SIZE) as usize;
+        let page = self.page(self.position / PAGE_SIZE)?;
+        let n = buf.len().min(page.len() - offset);
+        buf[..n].copy_from_slice(&page[offset..offset + n]);
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+impl Seek for HttpRangeReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = util::seek_position(self.position, self.len, pos)?;
+        Ok(self.position)
+    }
+}
+
+fn http_error(err: ureq::Error) -> io::Error {
+    io::Error::new(io::ErrorKind::Other, err)
+}
+
+/// The name of the cached pages of the file at `url`. The length and the version are part
+/// of the key, so that a changed file is not read from the cache. The key is a SHA-256
+/// hash, so that it stays the same between builds and versions of Rust, and the cache
+/// remains usable after `llm` is updated.
+fn cache_key(url: &str, len: u64, version: Option<&str>) -> String {
+    let mut hasher = Sha256::new();
+    // Each field is prefixed with its length, so that they cannot run into each other.
+    for field in [
+        url.as_bytes(),
+        &len.to_le_bytes(),
+        version.unwrap_or("").as_bytes(),
+    ] {
+        hasher.update((field.len() as u64).to_le_bytes());
+        hasher.update(field);
+    }
+    hasher.update([u8::from(version.is_some())]);
+    format!("{:x}", hasher.finalize())
+}
+
+/// Load a GGML model from `url` with HTTP range requests and configure it per the
+/// `params`, like [load_from_reader]. Only the header and the tensors of the model are
+/// downloaded, and they are loaded as they are downloaded; if `cache_dir` is given, they
+/// are cached there, so that loading the model again does not download them again.
+pub fn load_from_url<M: KnownModel>(
+    url: &str,
+    cache_dir: Option<&Path>,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let reader = HttpRangeReader::new(url, cache_dir)?;
+    load_from_reader(
+        reader,
+        Path::new(url),
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{BufRead, BufReader, Write},
+        net::{TcpListener, TcpStream},
+        sync::{Arc, Mutex},
+    };
+
+    use super::*;
+
+    /// The file served by a [TestServer], and the requests for it.
+    struct Served {
+        data: Vec<u8>,
+        etag: String,
+        range_requests: usize,
+    }
+
+    /// An HTTP server that serves one file with range requests, like a static file server.
+    struct TestServer {
+        url: String,
+        served: Arc<Mutex<Served>>,
+    }
+    impl TestServer {
+        fn new(data: Vec<u8>, etag: &str) -> Self {
+            let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+            let url = format!("http://{}/model.bin", listener.local_addr().unwrap());
+            let served = Arc::new(Mutex::new(Served {
+                data,
+                etag: etag.to_owned(),
+                range_requests: 0,
+            }));
+
+            let server_served = served.clone();
+            std::thread::spawn(move || {
+                for stream in listener.incoming().flatten() {
+                    // A streamed response keeps its connection open while it is read, so
+                    // each connection is served on its own thread.
+                    let served = server_served.clone();
+                    std::thread::spawn(move || {
+                        // The client may hang up before a streamed response is finished.
+                        let _ = Self::respond(stream, &served);
+                    });
+                }
+            });
+            Self { url, served }
+        }
+
+        fn respond(mut stream: TcpStream, served: &Mutex<Served>) -> io::Result<()> {
+            let mut reader = BufReader::new(stream.try_clone()?);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line)?;
+            let mut headers = HashMap::new();
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line)?;
+                match line.trim_end().split_once(": ") {
+                    Some((name, value)) => {
+                        headers.insert(name.to_ascii_lowercase(), value.to_owned());
+                    }
+                    None => break,
+                }
+            }
+
+            let (status, body, etag, len) = {
+                let mut served = served.lock().unwrap();
+                let len = served.data.len();
+                let range = headers
+                    .get("range")
+                    .and_then(|range| range.strip_prefix("bytes="))
+                    .and_then(|range| range.split_once('-'))
+                    .map(|(start, end)| {
+                        let start: usize = start.parse().unwrap();
+                        let end = end.parse::<usize>().map_or(len, |end| end + 1);
+                        start..end.min(len)
+                    })
+                    .filter(|_| {
+                        headers
+                            .get("if-range")
+                            .map_or(true, |etag| *etag == served.etag)
+                    });
+                if range.is_some() {
+                    served.range_requests += 1;
+                }
+                let (status, body) = match range {
+                    Some(range) => ("206 Partial Content", served.data[range].to_vec()),
+                    None => ("200 OK", served.data.clone()),
+                };
+                (status, body, served.etag.clone(), len)
+            };
+
+            let head = request_line.starts_with("HEAD ");
+            write!(
+                stream,
+                "HTTP/1.1 {status}\r\nAccept-Ranges: bytes\r\nETag: {etag}\r\n\
+                Content-Length: {}\r\nConnection: close\r\n\r\n",
+                if head { len } else { body.len() }
+            )?;
+            if !head {
+                stream.write_all(&body)?;
+            }
+            Ok(())
+        }
+
+        fn range_requests(&self) -> usize {
+            self.served.lock().unwrap().range_requests
+        }
+
+        /// Replaces the served file with `data`, as a new version with the ETag `etag`.
+        fn replace(&self, data: Vec<u8>, etag: &str) {
+            let mut served = self.served.lock().unwrap();
+            served.data = data;
+            served.etag = etag.to_owned();
+        }
+    }
+
+    fn test_data(len: usize, seed: u8) -> Vec<u8> {
+        (0..len)
+            .map(|i| (i as u8).wrapping_mul(31) ^ seed)
+            .collect()
+    }
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("llm-http-{name}-{}", std::process::id()))
+    }
+
+    #[test]
+    fn test_read_streams_sequential_reads() {
+        let data = test_data(2 * PAGE_SIZE as usize + 1000, 0);
+        let server = TestServer::new(data.clone(), "\"v1\"");
+
+        let mut reader = HttpRangeReader::new(&server.url, None).unwrap();
+        assert_eq!(reader.len(), data.len() as u64);
+        let mut read = vec![];
+        reader.read_to_end(&mut read).unwrap();
+        assert_eq!(read, data);
+        // Reading from start to end streams every page from one request.
+        assert_eq!(server.range_requests(), 1);
+
+        // Going back to a page that is still in memory does not download it again, but
+        // skipping to one that is not starts a new request.
+        reader.seek(SeekFrom::Start(10)).unwrap();
+        let mut buf = [0; 100];
+        reader.read_exact(&mut buf).unwrap();
+        assert_eq!(buf, data[10..110]);
+        assert_eq!(server.range_requests(), 1);
+    }
+
+    #[test]
+    fn test_read_from_cache() {
+        let cache_dir = temp_dir("cache");
+        let data = test_data(PAGE_SIZE as usize + 1000, 0);
+        let server = TestServer::new(data.clone(), "\"v1\"");
+
+        let mut read = vec![];
+        HttpRangeReader::new(&server.url, Some(&cache_dir))
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(server.range_requests(), 1);
+
+        // The cached pages are read instead of being downloaded again.
+        let mut read = vec![];
+        HttpRangeReader::new(&server.url, Some(&cache_dir))
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert_eq!(server.range_requests(), 1);
+
+        // A new version of the file, even of the same length, is downloaded again.
+        let new_data = test_data(data.len(), 1);
+        server.replace(new_data.clone(), "\"v2\"");
+        let mut read = vec![];
+        HttpRangeReader::new(&server.url, Some(&cache_dir))
+            .unwrap()
+            .read_to_end(&mut read)
+            .unwrap();
+        assert_eq!(read, new_data);
+        assert_eq!(server.range_requests(), 2);
+
+        fs::remove_dir_all(&cache_dir).unwrap();
+    }
+
+    #[test]
+    fn test_cache_key() {
+        // The key does not depend on the build, so the pages cached by one version of
+        // `llm` are found by the next.
+        let key = cache_key("https://example.com/model.bin", 1000, Some("\"v1\""));
+        assert_eq!(
+            key,
+            "ca9523a3f636eae950cd2701d2412bc26b7e20817d4cd60ab12dc21de695ac51"
+        );
+        for other in [
+            cache_key("https://example.com/model.bin", 1000, Some("\"v2\"")),
+            cache_key("https://example.com/model.bin", 1000, None),
+            cache_key("https://example.com/model.bin", 1001, Some("\"v1\"")),
+            cache_key("https://example.com/other.bin", 1000, Some("\"v1\"")),
+        ] {
+            assert_ne!(key, other);
+        }
+    }
+
+    #[test]
+    fn test_file_changed_while_reading() {
+        let data = test_data(3 * PAGE_SIZE as usize, 0);
+        let server = TestServer::new(data.clone(), "\"v1\"");
+
+        let mut reader = HttpRangeReader::new(&server.url, None).unwrap();
+        let mut buf = vec![0; 100];
+        reader.read_exact(&mut buf).unwrap();
+
+        // Pages of the new version are not mixed with those of the old one.
+        server.replace(test_data(data.len(), 1), "\"v2\"");
+        reader.seek(SeekFrom::Start(PAGE_SIZE * 5 / 2)).unwrap();
+        assert!(reader.read_exact(&mut buf).is_err());
+    }
+}
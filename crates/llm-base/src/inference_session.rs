@@ -1,6 +1,13 @@
 use ggml::{Buffer, ComputationGraph, Context, GraphExecutionPlan, Tensor};
+use rand::SeedableRng;
 use serde::Serialize;
-use std::{cell::RefCell, fmt::Display, sync::Arc};
+use std::{
+    cell::RefCell,
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use thiserror::Error;
 use tracing::{instrument, log};
 
@@ -8,18 +15,61 @@ use tracing::{instrument, log};
 use ggml::accelerator::metal::MetalContext;
 
 use crate::{
-    mulf, util, InferenceParameters, Model, ModelContext, ModelParameters, OutputRequest, Prompt,
-    TokenId, TokenUtf8Buffer, TokenizationError,
+    control_vector::{self, ControlVector, ControlVectorError},
+    mulf,
+    samplers::SuppressTokens,
+    util, InferenceParameters, InfillTokens, LoadError, Model, ModelContext, ModelParameters,
+    OutputRequest, Prompt, PromptFedEvent, TelemetrySink, TokenGeneratedEvent, TokenId,
+    TokenUtf8Buffer, TokenizationError,
 };
 
 // The size of a scratch buffer used for inference. This is used for temporary
 // storage of intermediate results during inference.
 //
 // The specific value was copied from `llama.cpp`.
-const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
+pub(crate) const SCRATCH_SIZE: usize = 512 * 1024 * 1024;
 
 type ScratchBuffers = [ggml::Buffer; 2];
 
+// The GPU backends use global state (e.g. scratch buffers and streams) while computing a
+// graph, so graphs from sessions on different threads must not be computed on the GPU
+// at the same time. CPU computation does not need this, as each session has its own
+// context and scratch buffers, and the model's weights are only read.
+#[cfg(any(feature = "cublas", feature = "clblast"))]
+static GPU_COMPUTE_LOCK: std::sync::Mutex<()> = std::sync::Mutex::new(());
+
+/// The size of the key/value memory of a session, which stores `n_embd` keys and values
+/// for each of the `n_ctx` tokens in each of the `n_layer` layers.
+pub(crate) fn kv_memory_size(
+    config: &InferenceSessionConfig,
+    n_ctx: usize,
+    n_layer: usize,
+    n_embd: usize,
+) -> usize {
+    let memory_k = mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_k_type.into())
+    );
+    let memory_v = mulf!(
+        n_ctx,
+        n_layer,
+        n_embd,
+        ggml::type_sizef(config.memory_v_type.into())
+    );
+    let object_overhead = n_layer
+        .saturating_mul(10)
+        .saturating_add(5)
+        .saturating_mul(256);
+
+    // Absurd hyperparameters give an absurd size, which fails the memory check on loading,
+    // instead of overflowing.
+    memory_k
+        .saturating_add(memory_v)
+        .saturating_add(object_overhead)
+}
+
 fn scratch_buffers() -> ScratchBuffers {
     [
         ggml::Buffer::new(SCRATCH_SIZE),
@@ -91,6 +141,39 @@ pub struct InferenceSession {
     n_embd: usize,
 
     scratch: ScratchBuffers,
+
+    // The combined control vector directions to add to the output of each layer.
+    control_vectors: HashMap<usize, Vec<f32>>,
+
+    // The layers of the model to evaluate.
+    evaluated_layers: EvaluatedLayers,
+
+    n_layer: usize,
+
+    // Whether the output of each layer is captured, and the outputs captured by the last evaluation.
+    capture_layer_outputs: bool,
+    layer_outputs: Vec<Option<Vec<f32>>>,
+
+    // Whether the timing of each generated token is recorded, and the recorded timings.
+    record_token_timings: bool,
+    token_timings: Vec<TokenTiming>,
+
+    // The number of alternatives to record the log-probabilities of for each generated
+    // token, if log-probabilities are recorded, and the recorded log-probabilities.
+    record_logprobs: Option<usize>,
+    token_logprobs: Vec<TokenLogprobs>,
+
+    // The most memory used of ctx0 and of the scratch buffers by an evaluation, and the
+    // most tokens held in the key/value memory.
+    ctx0_peak: usize,
+    scratch_peak: usize,
+    n_past_peak: usize,
+
+    // Receives events about the work done by the session.
+    telemetry: Option<Arc<dyn TelemetrySink>>,
+
+    // The sequences that the tokens being evaluated belong to, if there are several.
+    sequence_batch: Option<SequenceBatch>,
 }
 
 pub struct BuildContext<'session> {
@@ -100,12 +183,111 @@ pub struct BuildContext<'session> {
     pub memory_k: &'session Tensor,
     pub memory_v: &'session Tensor,
     pub scratch: &'session ScratchBuffers,
+    pub control_vectors: HashMap<usize, Tensor>,
+    pub evaluated_layers: &'session EvaluatedLayers,
+    /// The tensors the output of each layer is copied to, if layer outputs are being captured.
+    pub layer_outputs: Option<&'session [Tensor]>,
+    /// The position of the first token being evaluated, which is the number of tokens
+    /// evaluated before it unless the tokens belong to several sequences.
+    pub position: usize,
+    /// The number of independent sequences that the tokens being evaluated belong to, one
+    /// after the other, each starting at [Self::position]. This is only more than 1 for
+    /// models that [support sequence batches](Model::supports_sequence_batches).
+    pub n_sequences: usize,
+    /// The mask of the attention scores, with a row for each token being evaluated and a
+    /// column for each key/value slot, if the tokens belong to several sequences.
+    pub attention_mask: Option<&'session Tensor>,
 }
 
 impl<'session> BuildContext<'session> {
     pub fn get_scratch(&self, idx: usize) -> Option<&Buffer> {
         Some(&self.scratch[idx])
     }
+
+    /// Whether `layer` should be evaluated. Skipped layers pass their input through unchanged.
+    pub fn should_evaluate_layer(&self, layer: usize) -> bool {
+        self.evaluated_layers.contains(layer)
+    }
+
+    /// Finishes `layer`, given its output `hidden`: adds the control vector direction for
+    /// the layer (if any), and copies the result out of the scratch buffers if layer
+    /// outputs are being captured. The returned tensor is the input of the next layer.
+    pub fn finish_layer(&self, ctx0: &Context, layer: usize, hidden: Tensor) -> Tensor {
+        let hidden = match self.control_vectors.get(&layer) {
+            Some(direction) => ctx0.op_add(&hidden, &ctx0.op_repeat(direction, &hidden)),
+            None => hidden,
+        };
+        match self.layer_outputs {
+            Some(layer_outputs) => ctx0.op_cpy(&hidden, &layer_outputs[layer]),
+            None => hidden,
+        }
+    }
+
+    /// Splits `a`, which holds the `n_head` heads of `head_dim` values of each token being
+    /// evaluated, into `[head_dim, n_head, n_tokens, n_sequences]`, so that RoPE from
+    /// [Self::position] gives the tokens of each sequence their own positions.
+    pub fn split_sequences(
+        &self,
+        ctx0: &Context,
+        a: &Tensor,
+        head_dim: usize,
+        n_head: usize,
+    ) -> Tensor {
+        let n_tokens = a.nelements() / (head_dim * n_head * self.n_sequences);
+        ctx0.op_reshape_4d(a, head_dim, n_head, n_tokens, self.n_sequences)
+    }
+
+    /// Masks the attention scores `kq` so that each token only attends to the tokens before
+    /// it (the first `n_past` of which were evaluated before), and, if the tokens belong to
+    /// several sequences, only to those of its own sequence.
+    pub fn mask_attention(&self, ctx0: &Context, kq: &Tensor, n_past: usize) -> Tensor {
+        match self.attention_mask {
+            Some(mask) => ctx0.op_add(kq, &ctx0.op_repeat(mask, kq)),
+            None => ctx0.op_diag_mask_inf_inplace(kq, n_past),
+        }
+    }
+}
+
+/// Tracks which sequence each slot of the key/value memory of a session belongs to, when
+/// several independent sequences that continue the same tokens are evaluated together; see
+/// [InferenceSession::evaluate_sequences].
+pub(crate) struct SequenceSlots {
+    /// The number of slots at the start of the memory, which every sequence attends to.
+    shared: usize,
+    /// The sequence that each of the slots after them belongs to, or `None` for padding.
+    slots: Vec<Option<usize>>,
+}
+impl SequenceSlots {
+    /// Slots for sequences that continue the first `shared` tokens of the memory.
+    pub(crate) fn new(shared: usize) -> Self {
+        Self {
+            shared,
+            slots: vec![],
+        }
+    }
+}
+
+/// A sequence being generated by [InferenceSession::infer_sequences] together with others.
+struct BatchedSequence {
+    tokens: Vec<TokenId>,
+    decoded_tokens: Vec<u8>,
+    last_logits: Vec<f32>,
+    seeded_rng: Option<rand::rngs::StdRng>,
+    token_utf8_buf: TokenUtf8Buffer,
+    token_logprobs: Vec<TokenLogprobs>,
+    token_timings: Vec<TokenTiming>,
+    predict_duration: Duration,
+    finished: bool,
+}
+
+/// The tokens of several sequences that are evaluated together; see
+/// [InferenceSession::evaluate_sequences].
+struct SequenceBatch {
+    position: usize,
+    n_sequences: usize,
+    /// The attention mask: for each token, 0 for the slots it attends to, and negative
+    /// infinity for the others.
+    mask: Vec<f32>,
 }
 
 unsafe impl Send for InferenceSession {}
@@ -124,25 +306,15 @@ impl InferenceSession {
             ..
         } = *params;
 
-        let context_byte_size = {
-            let mut size = 0;
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_k_type.into())
-            ); // memory_k
-            size += mulf!(
-                context_size,
-                n_layer,
-                n_embd,
-                ggml::type_sizef(config.memory_v_type.into())
-            ); // memory_v
-            size += (5 + 10 * n_layer) * 256; // object overhead
-
-            size
+        // The fields are public, so a batch size of 0 can get past the builder; prompts
+        // cannot be split into empty batches, so it is treated as 1.
+        let config = InferenceSessionConfig {
+            n_batch: config.n_batch.max(1),
+            ..config
         };
 
+        let context_byte_size = kv_memory_size(&config, context_size, n_layer, n_embd);
+
         if use_gpu {
             ggml::accelerator::initialize(0);
             ggml::accelerator::set_scratch_size(config.n_batch * 1024 * 1024);
@@ -213,19 +385,122 @@ impl InferenceSession {
             ctx0,
             n_embd,
             scratch,
+            control_vectors: HashMap::new(),
+            evaluated_layers: EvaluatedLayers::default(),
+            n_layer,
+            capture_layer_outputs: false,
+            layer_outputs: vec![],
+            record_token_timings: false,
+            token_timings: vec![],
+            record_logprobs: None,
+            token_logprobs: vec![],
+            ctx0_peak: 0,
+            scratch_peak: 0,
+            n_past_peak: 0,
+            telemetry: params.telemetry.clone(),
+            sequence_batch: None,
+        }
+    }
+
+    /// Sets the control vectors to apply to the model's hidden state during evaluation,
+    /// replacing any that were previously set. Passing an empty slice disables steering.
+    ///
+    /// If multiple control vectors have a direction for the same layer, their
+    /// directions are summed.
+    pub fn set_control_vectors(
+        &mut self,
+        control_vectors: &[ControlVector],
+    ) -> Result<(), ControlVectorError> {
+        self.control_vectors = control_vector::combine(control_vectors, self.n_embd)?;
+        Ok(())
+    }
+
+    /// Sets which of the model's layers are evaluated. By default, all layers are.
+    ///
+    /// Skipping layers makes evaluation faster at the cost of quality, and can be used
+    /// to compare the model's behaviour layer-by-layer against a reference implementation.
+    /// The key/value memory of skipped layers is not updated, so this should usually be
+    /// set before any tokens are fed to the session.
+    pub fn set_evaluated_layers(&mut self, evaluated_layers: EvaluatedLayers) {
+        self.evaluated_layers = evaluated_layers;
+    }
+
+    /// Sets whether the output of each layer is captured during evaluation, so that it
+    /// can be retrieved with [Self::layer_outputs] afterwards. This is meant for comparing
+    /// the model's activations against a reference implementation, and costs an extra
+    /// copy of the hidden state per layer.
+    pub fn set_capture_layer_outputs(&mut self, capture: bool) {
+        self.capture_layer_outputs = capture;
+        if !capture {
+            self.layer_outputs.clear();
         }
     }
 
+    /// The output of each layer for the tokens of the last evaluation, if capturing was
+    /// enabled with [Self::set_capture_layer_outputs]. Each output has `n_embd` values for
+    /// each token, one token after the other. Skipped layers have no output.
+    pub fn layer_outputs(&self) -> &[Option<Vec<f32>>] {
+        &self.layer_outputs
+    }
+
+    /// Sets the sink that receives events about the work done by the session, replacing
+    /// the one of the model it was started from ([ModelParameters::telemetry]). Passing
+    /// `None` stops sending events.
+    pub fn set_telemetry_sink(&mut self, sink: Option<Arc<dyn TelemetrySink>>) {
+        self.telemetry = sink;
+    }
+
+    /// Sets whether [Self::infer_next_token] records when each token was generated and how
+    /// long it took, so that the timings can be retrieved with [Self::token_timings]. This
+    /// is meant for profiling variance in generation speed over a long generation.
+    pub fn set_record_token_timings(&mut self, record: bool) {
+        self.record_token_timings = record;
+        if !record {
+            self.token_timings.clear();
+        }
+    }
+
+    /// The timings of the tokens generated since recording was enabled with
+    /// [Self::set_record_token_timings], in the order they were generated.
+    pub fn token_timings(&self) -> &[TokenTiming] {
+        &self.token_timings
+    }
+
+    /// Sets whether [Self::infer_next_token] records the log-probability of each token it
+    /// generates, and of the `top_n` most probable tokens at that point, so that they can be
+    /// retrieved with [Self::token_logprobs]. Passing `None` stops recording. This is meant
+    /// for calibration, watermark detection and distillation.
+    pub fn set_record_logprobs(&mut self, top_n: Option<usize>) {
+        self.record_logprobs = top_n;
+        if top_n.is_none() {
+            self.token_logprobs.clear();
+        }
+    }
+
+    /// The log-probabilities of the tokens generated since recording was enabled with
+    /// [Self::set_record_logprobs], in the order they were generated.
+    pub fn token_logprobs(&self) -> &[TokenLogprobs] {
+        &self.token_logprobs
+    }
+
     /// Compute a model (possibly building a graph in the provided closure when called for the first time and/or when parameters have)
+    ///
+    /// Fails with [InferenceError::WeightsUnavailable] if the model was loaded lazily and its
+    /// weights cannot be read; see [Model::load_deferred_tensors].
     pub fn compute<F>(
         &mut self,
-        #[allow(unused_variables)] model_context: ModelContext,
+        model_context: ModelContext,
         input_tokens: &[TokenId],
         builder: F,
-    ) -> GraphOutputs
+    ) -> Result<GraphOutputs, InferenceError>
     where
         F: FnOnce(BuildContext) -> (ComputationGraph, GraphOutputs),
     {
+        // Models loaded lazily read their weights on first use.
+        model_context
+            .read_deferred_tensors()
+            .map_err(InferenceError::WeightsUnavailable)?;
+
         // Build a graph
         self.ctx0.recreate();
         let ctx0 = &mut self.ctx0;
@@ -233,12 +508,51 @@ impl InferenceSession {
             .new_tensor_1d(ggml::Type::I32, input_tokens.len())
             .set_name("embd");
 
+        // Create the control vector tensors before the builder enables the scratch buffers,
+        // so that their data is not overwritten during computation.
+        let control_vectors = self
+            .control_vectors
+            .iter()
+            .map(|(&layer, direction)| {
+                let mut tensor = ctx0.new_tensor_1d(ggml::Type::F32, direction.len());
+                unsafe { tensor.write_data(bytemuck::cast_slice(direction)) };
+                (layer, tensor)
+            })
+            .collect();
+
+        // Likewise, the captured layer outputs must not live in the scratch buffers.
+        let layer_outputs: Option<Vec<Tensor>> = self.capture_layer_outputs.then(|| {
+            (0..self.n_layer)
+                .map(|_| ctx0.new_tensor_2d(ggml::Type::F32, self.n_embd, input_tokens.len()))
+                .collect()
+        });
+
+        // Likewise, the attention mask of tokens from several sequences.
+        let attention_mask = self.sequence_batch.as_ref().map(|batch| {
+            let n_kv = batch.mask.len() / input_tokens.len();
+            let mut mask = ctx0.new_tensor_2d(ggml::Type::F32, n_kv, input_tokens.len());
+            unsafe { mask.write_data(bytemuck::cast_slice(&batch.mask)) };
+            mask
+        });
+
         let bc = BuildContext {
             ctx0: RefCell::new(ctx0),
             embd: &embd,
             memory_k: &self.memory_k,
             memory_v: &self.memory_v,
             scratch: &mut self.scratch,
+            control_vectors,
+            evaluated_layers: &self.evaluated_layers,
+            layer_outputs: layer_outputs.as_deref(),
+            position: self
+                .sequence_batch
+                .as_ref()
+                .map_or(self.n_past, |batch| batch.position),
+            n_sequences: self
+                .sequence_batch
+                .as_ref()
+                .map_or(1, |batch| batch.n_sequences),
+            attention_mask: attention_mask.as_ref(),
         };
         let (mut built_gf, built_result) = builder(bc);
 
@@ -246,7 +560,7 @@ impl InferenceSession {
         #[cfg(feature = "metal")]
         {
             if let Some(ref mut metal_context) = self.metal_context {
-                metal_context.add_context(model_context.0);
+                metal_context.add_context(model_context.context.clone());
             }
         }
 
@@ -256,6 +570,11 @@ impl InferenceSession {
         // Compute the graph
         built_gf.build_forward_expand(&built_result.result);
 
+        #[cfg(any(feature = "cublas", feature = "clblast"))]
+        let _gpu_compute_guard = GPU_COMPUTE_LOCK
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+
         #[cfg(feature = "metal")]
         {
             // FIXME can only process one token at a time currently
@@ -279,74 +598,418 @@ impl InferenceSession {
             plan.execute(ctx0);
         }
 
+        model_context.release_streamed_weights();
+
+        if let Some(layer_outputs) = layer_outputs {
+            self.layer_outputs = layer_outputs
+                .iter()
+                .enumerate()
+                .map(|(layer, tensor)| {
+                    self.evaluated_layers.contains(layer).then(|| {
+                        let mut output = vec![0.0; tensor.nelements()];
+                        unsafe { tensor.read_data(0, bytemuck::cast_slice_mut(&mut output)) };
+                        output
+                    })
+                })
+                .collect();
+        }
+
         // Adjust the required memory per token if we didn't know that already
         if self.mem_per_token == 0 {
             self.mem_per_token = ctx0.used_mem() / self.n_embd;
         }
 
+        // Record the memory used by this evaluation. ctx0 is recreated for the next one,
+        // so its usage must be read now.
+        ctx0.use_scratch(None);
+        self.ctx0_peak = self.ctx0_peak.max(ctx0.used_mem());
+        self.scratch_peak = self.scratch_peak.max(ctx0.scratch_peak());
+
         // Adjust n_past to new length.
         self.n_past += input_tokens.len();
+        self.n_past_peak = self.n_past_peak.max(self.n_past);
 
         // Safety: ctx0 will linger around
-        GraphOutputs {
+        Ok(GraphOutputs {
             result: built_result.result.share(),
             embedding_result: built_result.embedding_result.share(),
-        }
+        })
     }
 
     /// Feed a prompt to the model for this session.
+    ///
+    /// If the prompt does not fit in the context window, it is truncated as set by
+    /// [InferenceSessionConfig::prompt_truncation], or [InferenceError::ContextFull] is
+    /// returned.
+    #[instrument(skip_all)]
+    pub fn feed_prompt<'a, R: InferenceCallbackResult, P: Into<Prompt<'a>>>(
+        &mut self,
+        model: &dyn Model,
+        prompt: P,
+        output_request: &mut OutputRequest,
+        mut callback: impl FnMut(&[u8]) -> R,
+    ) -> Result<(), InferenceError> {
+        let truncation = self.config.prompt_truncation;
+        let result = self.feed_prompt_with_truncation(
+            model,
+            prompt.into(),
+            truncation,
+            output_request,
+            |token| callback(token).into_result(),
+        );
+        if let (Some(telemetry), Err(err)) = (&self.telemetry, &result) {
+            telemetry.error(err);
+        }
+        result
+    }
+
+    /// Like [Self::feed_prompt], but calls `progress` after each batch of the prompt is
+    /// evaluated, so that the progress of feeding a long prompt can be shown. Returning
+    /// [InferenceFeedback::Halt] from `progress` stops feeding the prompt after that batch.
+    ///
+    /// To feed a prompt in parts across several calls instead, use [Self::prepare_prompt]
+    /// and [Self::feed_prompt_batch].
     #[instrument(skip_all)]
-    pub fn feed_prompt<'a, E: std::error::Error + Send + Sync + 'static, P: Into<Prompt<'a>>>(
+    pub fn feed_prompt_with_progress<'a, R: InferenceCallbackResult, P: Into<Prompt<'a>>>(
         &mut self,
         model: &dyn Model,
         prompt: P,
         output_request: &mut OutputRequest,
+        progress: impl FnMut(FeedPromptProgress) -> R,
+    ) -> Result<(), InferenceError> {
+        let result = self.feed_prompt_with_progress_unreported(
+            model,
+            prompt.into(),
+            output_request,
+            progress,
+        );
+        if let (Some(telemetry), Err(err)) = (&self.telemetry, &result) {
+            telemetry.error(err);
+        }
+        result
+    }
+
+    fn feed_prompt_with_progress_unreported<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        prompt: Prompt,
+        output_request: &mut OutputRequest,
+        mut progress: impl FnMut(FeedPromptProgress) -> R,
+    ) -> Result<(), InferenceError> {
+        let mut pending =
+            self.prepare_prompt_with_truncation(model, prompt, self.config.prompt_truncation)?;
+        while !pending.is_finished() {
+            self.feed_batch(model, &mut pending, output_request, &mut |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            })?;
+            match progress(pending.progress()).into_result() {
+                Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                Ok(InferenceFeedback::Continue) => (),
+                Ok(InferenceFeedback::Halt) => break,
+            }
+        }
+        self.report_prompt_fed(&pending);
+        Ok(())
+    }
+
+    /// Tokenizes `prompt` (and truncates it, as set by
+    /// [InferenceSessionConfig::prompt_truncation]) so that it can be fed to the session
+    /// a batch at a time with [Self::feed_prompt_batch]. Nothing is evaluated until then.
+    ///
+    /// The prompt is prepared for the session as it is now, so nothing else should be fed
+    /// to the session until the prompt is finished.
+    pub fn prepare_prompt<'a>(
+        &self,
+        model: &dyn Model,
+        prompt: impl Into<Prompt<'a>>,
+    ) -> Result<PendingPrompt, InferenceError> {
+        let result = self.prepare_prompt_with_truncation(
+            model,
+            prompt.into(),
+            self.config.prompt_truncation,
+        );
+        if let (Some(telemetry), Err(err)) = (&self.telemetry, &result) {
+            telemetry.error(err);
+        }
+        result
+    }
+
+    /// Evaluates the next batch of [InferenceSessionConfig::n_batch] tokens of `prompt`,
+    /// and returns how much of it has been fed. Call this until
+    /// [PendingPrompt::is_finished] to feed the whole prompt, e.g. while updating a
+    /// progress bar or between other work.
+    pub fn feed_prompt_batch(
+        &mut self,
+        model: &dyn Model,
+        prompt: &mut PendingPrompt,
+        output_request: &mut OutputRequest,
+    ) -> Result<FeedPromptProgress, InferenceError> {
+        if !prompt.is_finished() {
+            let result = self.feed_batch(model, prompt, output_request, &mut |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            });
+            if let Err(err) = result {
+                if let Some(telemetry) = &self.telemetry {
+                    telemetry.error(&err);
+                }
+                return Err(err);
+            }
+            if prompt.is_finished() {
+                self.report_prompt_fed(prompt);
+            }
+        }
+        Ok(prompt.progress())
+    }
+
+    fn feed_prompt_with_truncation<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        prompt: Prompt,
+        truncation: PromptTruncation,
+        output_request: &mut OutputRequest,
         mut callback: impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
     ) -> Result<(), InferenceError> {
+        let mut pending = self.prepare_prompt_with_truncation(model, prompt, truncation)?;
+        while !pending.is_finished() {
+            if self.feed_batch(model, &mut pending, output_request, &mut callback)? {
+                break;
+            }
+        }
+        log::trace!("Finished feed prompt");
+        self.report_prompt_fed(&pending);
+
+        Ok(())
+    }
+
+    fn prepare_prompt_with_truncation(
+        &self,
+        model: &dyn Model,
+        prompt: Prompt,
+        truncation: PromptTruncation,
+    ) -> Result<PendingPrompt, InferenceError> {
         let beginning_of_sentence = self.n_past == 0;
 
         let vocab = model.tokenizer();
-        let prompt_tokens = prompt.into().to_tokens(vocab, beginning_of_sentence)?;
+        let mut prompt_tokens = prompt.to_tokens(vocab, beginning_of_sentence)?;
+        self.check_tokens(&prompt_tokens)?;
+
+        if self.config.attention_sinks.is_none()
+            && self.n_past + prompt_tokens.len() >= model.context_size()
+        {
+            let room = model.context_size().saturating_sub(self.n_past + 1);
+            let n_bot = usize::from(
+                beginning_of_sentence && prompt_tokens.first().copied() == model.bot_token_id(),
+            );
+            truncation.apply(&mut prompt_tokens, room, n_bot)?;
+        }
+
+        Ok(PendingPrompt {
+            tokens: prompt_tokens,
+            tokens_fed: 0,
+            batches_fed: 0,
+            duration: Duration::ZERO,
+        })
+    }
+
+    /// Evaluates the next batch of `prompt`, calling `callback` with each of its tokens,
+    /// and returns whether the callback halted.
+    fn feed_batch<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        prompt: &mut PendingPrompt,
+        output_request: &mut OutputRequest,
+        callback: &mut impl FnMut(&[u8]) -> Result<InferenceFeedback, E>,
+    ) -> Result<bool, InferenceError> {
+        let start_at = Instant::now();
+        let end = (prompt.tokens_fed + self.config.n_batch).min(prompt.tokens.len());
+        let batch = &prompt.tokens[prompt.tokens_fed..end];
+        self.make_room(model, batch.len())?;
+
+        #[cfg(feature = "instrumentation")]
+        let _span = tracing::debug_span!(
+            "evaluate_batch",
+            n_tokens = batch.len(),
+            n_past = self.n_past
+        )
+        .entered();
+
+        model.evaluate(self, batch, output_request)?;
+        let mut halted = false;
+        for &tk in batch {
+            let should_call_callback = Some(tk) != model.bot_token_id();
+
+            let mut token = self.decode_next_token(model, tk);
+
+            if should_call_callback {
+                // NOTE: No string ever tokenizes to the end of sentence. So we
+                // can just return the id here.
+                match callback(&token) {
+                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                    Ok(f) => match f {
+                        InferenceFeedback::Continue => (),
+                        InferenceFeedback::Halt => {
+                            halted = true;
+                            break;
+                        }
+                    },
+                }
+            }
+
+            // Update the tokens for this session
+            self.tokens.push(tk);
+            self.decoded_tokens.append(&mut token);
+        }
+        prompt.tokens_fed = end;
+        prompt.batches_fed += 1;
+        prompt.duration += start_at.elapsed();
 
-        if self.n_past + prompt_tokens.len() >= model.context_size() {
+        Ok(halted)
+    }
+
+    fn report_prompt_fed(&self, prompt: &PendingPrompt) {
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.prompt_fed(&PromptFedEvent {
+                tokens: prompt.tokens_fed,
+                duration: prompt.duration,
+            });
+        }
+    }
+
+    /// Evaluates `tokens` without sampling, and returns the log-likelihood of each of them
+    /// given all of the tokens before it in the session (teacher forcing).
+    ///
+    /// If the session has not evaluated any tokens yet, the first token has nothing to be
+    /// predicted from, so it is not scored; it should usually be the beginning-of-sentence
+    /// token. The tokens are added to the session, so that further tokens can be scored
+    /// (or inferred) after them.
+    ///
+    /// This is the building block for perplexity, reranking and classification.
+    pub fn score(
+        &mut self,
+        model: &dyn Model,
+        tokens: &[TokenId],
+    ) -> Result<Vec<f32>, InferenceError> {
+        if self.n_past + tokens.len() >= model.context_size() {
             return Err(InferenceError::ContextFull);
         }
+        self.check_tokens(tokens)?;
+
+        let n_vocab = self.last_logits.len();
+        let mut scores = Vec::with_capacity(tokens.len());
+        let mut previous_logits = (self.n_past > 0).then(|| self.last_logits.clone());
+        for batch in tokens.chunks(self.config.n_batch) {
+            let mut output_request = OutputRequest {
+                all_logits: Some(vec![]),
+                ..Default::default()
+            };
+            model.evaluate(self, batch, &mut output_request)?;
+            let logits = output_request.all_logits.unwrap_or_default();
+
+            for (&token, logits) in batch.iter().zip(logits.chunks_exact(n_vocab)) {
+                if let Some(previous_logits) = &previous_logits {
+                    scores.push(util::log_softmax(previous_logits)[token as usize]);
+                }
+                previous_logits = Some(logits.to_vec());
 
-        'outer: for batch in prompt_tokens.chunks(self.config.n_batch) {
-            model.evaluate(self, batch, output_request);
-            for &tk in batch {
-                let should_call_callback = Some(tk) != model.bot_token_id();
+                let mut decoded = self.decode_next_token(model, token);
+                self.tokens.push(token);
+                self.decoded_tokens.append(&mut decoded);
+            }
+        }
 
-                let mut token = match model.tokenizer() {
-                    crate::Tokenizer::Embedded(_) => model.tokenizer().token(tk as usize).to_vec(),
-                    crate::Tokenizer::HuggingFace(_) => {
-                        let mut tokens = self.tokens.clone();
-                        tokens.push(tk);
+        Ok(scores)
+    }
 
-                        get_newly_decoded_portion_huggingface(model, tokens, &self.decoded_tokens)
-                    }
-                };
+    /// Scores each of `candidates` as a continuation of the session, and returns the
+    /// most probable one (forced decoding). This is useful for classification and
+    /// multiple-choice questions, where the answer must be one of a fixed set.
+    ///
+    /// Each candidate is scored with [Self::score] and then rewound, so the session is left
+    /// as it was; the model must support rewinding, and the session must already have
+    /// evaluated a prompt. Candidates are tokenized on their own, so they should usually
+    /// start with the whitespace that would separate them from the prompt, and must not be
+    /// empty.
+    pub fn choose(
+        &mut self,
+        model: &dyn Model,
+        candidates: &[&str],
+    ) -> Result<Choice, ChooseError> {
+        if candidates.is_empty() {
+            return Err(ChooseError::NoCandidates);
+        }
+        if self.n_past == 0 {
+            return Err(ChooseError::EmptySession);
+        }
+        if !model.supports_rewind() {
+            return Err(RewindError::UnsupportedArchitecture.into());
+        }
 
-                if should_call_callback {
-                    // NOTE: No string ever tokenizes to the end of sentence. So we
-                    // can just return the id here.
-                    match callback(&token) {
-                        Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
-                        Ok(f) => match f {
-                            InferenceFeedback::Continue => (),
-                            InferenceFeedback::Halt => break 'outer,
-                        },
-                    }
+        // A candidate without tokens would have a log-likelihood of 0, and beat every
+        // real candidate, so they are all tokenized and checked before any are scored.
+        let candidates = candidates
+            .iter()
+            .enumerate()
+            .map(|(index, candidate)| {
+                let tokens = Prompt::from(*candidate)
+                    .to_tokens(model.tokenizer(), false)
+                    .map_err(InferenceError::from)?;
+                if tokens.is_empty() {
+                    return Err(ChooseError::EmptyCandidate { index });
                 }
+                Ok(tokens)
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let last_logits = self.last_logits.clone();
+        let mut scores = Vec::with_capacity(candidates.len());
+        for tokens in candidates {
+            let log_likelihood: f32 = self.score(model, &tokens)?.iter().sum();
+            self.rewind(model, tokens.len())?;
+            self.last_logits.clone_from(&last_logits);
+            scores.push(log_likelihood);
+        }
+
+        let index = scores
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b))
+            .map_or(0, |(index, _)| index);
+        Ok(Choice { index, scores })
+    }
 
-                // Update the tokens for this session
-                self.tokens.push(tk);
-                self.decoded_tokens.append(&mut token);
+    /// Returns the bytes that `token` adds to the decoded text of this session.
+    fn decode_next_token(&self, model: &dyn Model, token: TokenId) -> Vec<u8> {
+        match model.tokenizer() {
+            crate::Tokenizer::Embedded(_) => model.tokenizer().token(token as usize).to_vec(),
+            crate::Tokenizer::HuggingFace(_) => {
+                let mut tokens = self.tokens.clone();
+                tokens.push(token);
+
+                get_newly_decoded_portion_huggingface(model, tokens, &self.decoded_tokens)
             }
         }
-        log::trace!("Finished feed prompt");
+    }
 
-        Ok(())
+    /// Checks that `tokens` are in the model's vocabulary, as evaluating a token that is not
+    /// would read past the end of its embeddings. The model's tokenizer may have more tokens
+    /// than the model if it was loaded from elsewhere.
+    pub(crate) fn check_tokens(&self, tokens: &[TokenId]) -> Result<(), TokenizationError> {
+        let n_vocab = self.last_logits.len();
+        match tokens.iter().position(|&token| token as usize >= n_vocab) {
+            Some(index) => Err(TokenizationError::InvalidTokenId {
+                token_id: tokens[index],
+                index,
+            }),
+            None => Ok(()),
+        }
+    }
+
+    /// Discards all of the tokens of the session, so that it can be reused for unrelated
+    /// text without allocating its key/value memory again.
+    pub(crate) fn reset(&mut self) {
+        self.n_past = 0;
+        self.tokens.clear();
+        self.decoded_tokens.clear();
     }
 
     /// Removes `num` tokens from the end of the buffer. Roughly the inverse of `feed_prompt`.
@@ -363,12 +1026,9 @@ impl InferenceSession {
         let token_start = self.tokens.len() - num;
         let deleted_tokens: Vec<_> = self.tokens.drain(token_start..).collect();
 
-        // Remove the corresponding chars from decoded
-        let mut decoded_start = self.decoded_tokens.len();
-        for id in &deleted_tokens {
-            decoded_start -= model.tokenizer().token(*id as usize).len();
-        }
-        self.decoded_tokens.truncate(decoded_start);
+        // Decode the remaining tokens again, rather than removing the text of the deleted
+        // ones: the end-of-text token that ends generation is not decoded.
+        self.decode_tokens(model);
 
         // Decrement the n_past tokens counter.
         self.n_past -= num;
@@ -376,6 +1036,66 @@ impl InferenceSession {
         Ok(deleted_tokens)
     }
 
+    /// Ensures that `n_tokens` more tokens fit in the context window, rolling it if
+    /// [InferenceSessionConfig::attention_sinks] is set and it is full.
+    fn make_room(&mut self, model: &dyn Model, n_tokens: usize) -> Result<(), InferenceError> {
+        let context_size = model.context_size();
+        if self.n_past + n_tokens < context_size {
+            return Ok(());
+        }
+        let Some(n_sinks) = self.config.attention_sinks else {
+            return Err(InferenceError::ContextFull);
+        };
+        let n_sinks = n_sinks.min(self.n_past);
+        if n_sinks + n_tokens >= context_size || !model.supports_rewind() {
+            return Err(InferenceError::ContextFull);
+        }
+
+        // Discard the oldest half of the tokens after the sinks, or more if that is not
+        // enough to make room.
+        let n_rolling = self.n_past - n_sinks;
+        let n_discard = (n_rolling / 2).max(self.n_past + n_tokens + 1 - context_size);
+        self.roll_context(model, n_sinks, n_discard)
+    }
+
+    /// Decodes the tokens of the session again, after some of them were removed.
+    fn decode_tokens(&mut self, model: &dyn Model) {
+        self.decoded_tokens = match model.tokenizer() {
+            crate::Tokenizer::Embedded(_) => self
+                .tokens
+                .iter()
+                .flat_map(|&id| model.tokenizer().token(id as usize))
+                .collect(),
+            crate::Tokenizer::HuggingFace(_) => model.tokenizer().decode(self.tokens.clone(), true),
+        };
+    }
+
+    /// Discards the `n_discard` tokens after the first `n_sinks` tokens of the session, and
+    /// evaluates the tokens after them again so that they follow on from the sinks.
+    fn roll_context(
+        &mut self,
+        model: &dyn Model,
+        n_sinks: usize,
+        n_discard: usize,
+    ) -> Result<(), InferenceError> {
+        #[cfg(feature = "instrumentation")]
+        let _span = tracing::debug_span!("roll_context", n_sinks, n_discard).entered();
+
+        log::debug!("Context window is full; discarding {n_discard} tokens after {n_sinks} sinks");
+
+        self.tokens.drain(n_sinks..n_sinks + n_discard);
+        self.decode_tokens(model);
+
+        // The key/value memory of the sinks is untouched; the memory after them is
+        // overwritten as the kept tokens are evaluated again.
+        self.n_past = n_sinks;
+        let kept_tokens = self.tokens[n_sinks..].to_vec();
+        for batch in kept_tokens.chunks(self.config.n_batch) {
+            model.evaluate(self, batch, &mut OutputRequest::default())?;
+        }
+        Ok(())
+    }
+
     /// Infer the next token for this session.
     #[instrument(level = "trace", skip_all)]
     pub fn infer_next_token(
@@ -385,41 +1105,65 @@ impl InferenceSession {
         output_request: &mut OutputRequest,
         rng: &mut impl rand::Rng,
     ) -> Result<Vec<u8>, InferenceError> {
-        if self.n_past + 1 >= model.context_size() {
-            return Err(InferenceError::ContextFull);
-        }
+        self.infer_next_token_timed(model, params, output_request, rng)
+            .map(|(token, _)| token)
+    }
 
-        let next_token = crate::samplers::sample_token(
-            params.sampler.clone(),
-            rng,
+    /// Like [Self::infer_next_token], but also returns when the token was generated and
+    /// how long it took, whether or not [token timings](Self::set_record_token_timings)
+    /// are recorded.
+    fn infer_next_token_timed(
+        &mut self,
+        model: &dyn Model,
+        params: &InferenceParameters,
+        output_request: &mut OutputRequest,
+        rng: &mut impl rand::Rng,
+    ) -> Result<(Vec<u8>, TokenTiming), InferenceError> {
+        self.make_room(model, 1)?;
+        let start = Instant::now();
+
+        let (next_token, logprobs) = sample_next_token(
+            params,
             &self.tokens,
-            self.last_logits.iter().copied(),
-        )
-        .map_err(InferenceError::SamplerFailure)?;
+            &self.last_logits,
+            self.record_logprobs,
+            rng,
+        )?;
+        self.token_logprobs.extend(logprobs);
 
         // Update the tokens for this session
         self.tokens.push(next_token);
 
         // Then, evaluate the network again to compute the new last_logits
-        model.evaluate(self, &[next_token], output_request);
+        {
+            #[cfg(feature = "instrumentation")]
+            let _span = tracing::trace_span!("evaluate_token", n_past = self.n_past).entered();
+
+            model.evaluate(self, &[next_token], output_request)?;
+        }
+
+        let timing = TokenTiming {
+            token_id: next_token,
+            timestamp: SystemTime::now(),
+            latency: start.elapsed(),
+        };
+        if self.record_token_timings {
+            self.token_timings.push(timing);
+        }
+        if let Some(telemetry) = &self.telemetry {
+            telemetry.token_generated(&TokenGeneratedEvent {
+                token_id: next_token,
+                latency: timing.latency,
+            });
+        }
 
         // Return the next token
         if next_token as TokenId == model.eot_token_id() {
             Err(InferenceError::EndOfText)
         } else {
-            let res = match model.tokenizer() {
-                crate::Tokenizer::Embedded(_) => {
-                    model.tokenizer().token(next_token as usize).to_vec()
-                }
-                crate::Tokenizer::HuggingFace(_) => get_newly_decoded_portion_huggingface(
-                    model,
-                    self.tokens.clone(),
-                    &self.decoded_tokens,
-                ),
-            };
-
+            let res = decode_next_token(model, &self.tokens, &self.decoded_tokens);
             self.decoded_tokens.append(&mut res.clone());
-            Ok(res)
+            Ok((res, timing))
         }
     }
 
@@ -427,86 +1171,692 @@ impl InferenceSession {
     ///
     /// The `callback` is called with each new token until an end-of-text (EOT)
     /// token is encountered or the maximum number of tokens have been
-    /// generated (specified by [InferenceRequest::maximum_token_count]).
+    /// generated (specified by [InferenceRequest::maximum_token_count]). The EOT token
+    /// is not sampled before [InferenceRequest::minimum_token_count] tokens have been
+    /// generated, or at all if [InferenceRequest::ignore_eos] is set. Generation also
+    /// stops once [InferenceRequest::maximum_duration] has passed.
+    ///
+    /// Tokens are sampled with `rng`, unless [InferenceRequest::seed] is set. The
+    /// `callback` can stop generation early by returning [InferenceFeedback::Halt], and
+    /// only needs to return a `Result` if it can fail (see [InferenceCallbackResult]).
+    ///
+    /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
+    #[instrument(skip_all)]
+    pub fn infer<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
+        mut callback: impl FnMut(InferenceResponse) -> R,
+    ) -> Result<InferenceStats, InferenceError> {
+        let result = self.infer_unreported(model, rng, request, output_request, |response| {
+            callback(response).into_result()
+        });
+        if let Some(telemetry) = &self.telemetry {
+            match &result {
+                Ok(stats) => telemetry.generation_finished(stats),
+                Err(err) => telemetry.error(err),
+            }
+        }
+        result
+    }
+
+    fn infer_unreported<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        output_request: &mut OutputRequest,
+        mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
+    ) -> Result<InferenceStats, InferenceError> {
+        let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
+        if request.play_back_previous_tokens {
+            // "Play back" the existing tokens, so that loading from an inference snapshot works
+            // as expected.
+            let mut token_utf8_buf = TokenUtf8Buffer::new();
+            for token_id in &self.tokens {
+                // Buffer the token until it's valid UTF-8, then call the callback.
+                if let Some(tokens) =
+                    token_utf8_buf.push(&model.tokenizer().token(*token_id as usize))
+                {
+                    if let Err(e) = callback(InferenceResponse::SnapshotToken(tokens)) {
+                        return Err(InferenceError::UserCallback(Box::new(e)));
+                    }
+                }
+            }
+        }
+        log::trace!(
+            "Starting inference request with max_token_count: {}",
+            maximum_token_count
+        );
+
+        let mut stats = InferenceStats::default();
+        let start_at = Instant::now();
+        let deadline = request
+            .maximum_duration
+            .and_then(|duration| start_at.checked_add(duration));
+        let n_past_before_prompt = self.n_past;
+
+        let parameters = request.parameters;
+        let mut seeded_rng = request.seed.map(rand::rngs::StdRng::seed_from_u64);
+        let mut rng: &mut dyn rand::RngCore = match &mut seeded_rng {
+            Some(seeded_rng) => seeded_rng,
+            None => rng,
+        };
+
+        // Feed the initial prompt through the transformer, to update its
+        // context window with new data, if necessary.
+        if !request.prompt.is_empty() {
+            self.feed_prompt_with_truncation(
+                model,
+                request.prompt,
+                self.config.prompt_truncation,
+                output_request,
+                feed_prompt_callback(&mut callback),
+            )?;
+        }
+        stats.feed_prompt_duration = start_at.elapsed();
+        stats.prompt_tokens = self.n_past.saturating_sub(n_past_before_prompt);
+        let predict_start_at = Instant::now();
+        let mut token_timings = vec![];
+
+        // After the prompt is consumed, sample tokens by repeatedly calling
+        // `infer_next_token`. We generate tokens until the model returns an
+        // EndOfText token, or we run out of space in the context window,
+        // or we reach the specified limit or deadline.
+        let minimum_token_count = if request.ignore_eos {
+            usize::MAX
+        } else {
+            request.minimum_token_count.unwrap_or(0)
+        };
+        let eot_suppressed_parameters = (minimum_token_count > 0).then(|| {
+            parameters
+                .clone()
+                .with_logits_processor(SuppressTokens(vec![model.eot_token_id()]))
+        });
+
+        let mut tokens_processed = 0;
+        let mut token_utf8_buf = TokenUtf8Buffer::new();
+        while tokens_processed < maximum_token_count {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                log::debug!("Stopping inference at the deadline");
+                break;
+            }
+            let parameters = match &eot_suppressed_parameters {
+                Some(parameters) if tokens_processed < minimum_token_count => parameters,
+                _ => parameters,
+            };
+            let token = match self.infer_next_token_timed(
+                model,
+                parameters,
+                &mut Default::default(),
+                &mut rng,
+            ) {
+                Ok((token, timing)) => {
+                    token_timings.push(timing);
+                    token
+                }
+                Err(InferenceError::EndOfText) => break,
+                Err(e) => return Err(e),
+            };
+
+            // Buffer the token until it's valid UTF-8, then call the callback.
+            if let Some(tokens) = token_utf8_buf.push(&token) {
+                match callback(InferenceResponse::InferredToken(tokens)) {
+                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                    Ok(f) => match f {
+                        InferenceFeedback::Continue => (),
+                        InferenceFeedback::Halt => break,
+                    },
+                }
+            }
+
+            tokens_processed += 1;
+        }
+        stats.predict_duration = predict_start_at.elapsed();
+        stats.predict_tokens = token_timings.len();
+        stats.token_latency = LatencyStats::new(&token_timings);
+        stats.memory = self.memory_stats();
+
+        Ok(stats)
+    }
+
+    /// Generates `n_sequences` completions of the same prompt, e.g. to pick the best of
+    /// several samples or to take a majority vote over them (self-consistency).
+    ///
+    /// The prompt is evaluated once, and its key/value memory is shared by all of the
+    /// sequences. If the model [supports sequence batches](Model::supports_sequence_batches),
+    /// the sequences are generated together, with the next token of each of them evaluated
+    /// in one pass of the model, so the tokens of all of the sequences must fit in the
+    /// context window after the prompt. Otherwise, each sequence is generated after the
+    /// prompt with [Self::infer], and then rewound, so that only the memory for one
+    /// sequence's own tokens is needed at a time. The `callback` is called with the index
+    /// of the sequence and each of its tokens.
+    ///
+    /// If [InferenceRequest::seed] is set, the sequences are seeded with consecutive seeds
+    /// starting from it, so that they differ from each other. The log-probabilities of each
+    /// sequence's tokens are returned with it instead of being added to
+    /// [Self::token_logprobs]. The model must support rewinding. When this returns
+    /// successfully, the session has evaluated the prompt and none of the sequences;
+    /// [InferenceSessionConfig::attention_sinks] is not used.
+    ///
+    /// [InferenceRequest::maximum_duration] limits the time taken to generate all of the
+    /// sequences, so the later ones may be cut short, or even empty.
+    pub fn infer_sequences<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        n_sequences: usize,
+        mut callback: impl FnMut(usize, InferenceResponse) -> R,
+    ) -> Result<Vec<GeneratedSequence>, InferenceError> {
+        if !model.supports_rewind() {
+            return Err(RewindError::UnsupportedArchitecture.into());
+        }
+
+        // Rolling the context window would discard part of the shared prompt.
+        let attention_sinks = self.config.attention_sinks.take();
+        // The log-probabilities are needed to rank the sequences, even if the session is
+        // not recording them.
+        let record_logprobs = self.record_logprobs;
+        self.record_logprobs = Some(record_logprobs.unwrap_or(0));
+        let result = self.infer_sequences_with_shared_prompt(
+            model,
+            rng,
+            request,
+            n_sequences,
+            &mut callback,
+        );
+        self.config.attention_sinks = attention_sinks;
+        self.record_logprobs = record_logprobs;
+        result
+    }
+
+    /// Generates `n_sequences` completions of the same prompt with [Self::infer_sequences],
+    /// and returns them ranked by `score`, best first (best-of-n sampling).
+    ///
+    /// Passing [GeneratedSequence::mean_logprob] ranks the completions by how likely the
+    /// model found them; any other measure, such as a reward model or running the tests of
+    /// generated code, can be used instead. Sequences with a NaN score are ranked last.
+    pub fn infer_best_of<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        n_sequences: usize,
+        mut score: impl FnMut(&GeneratedSequence) -> f32,
+        callback: impl FnMut(usize, InferenceResponse) -> R,
+    ) -> Result<Vec<RankedSequence>, InferenceError> {
+        let sequences = self.infer_sequences(model, rng, request, n_sequences, callback)?;
+        let mut ranked: Vec<_> = sequences
+            .into_iter()
+            .map(|sequence| RankedSequence {
+                score: score(&sequence),
+                sequence,
+            })
+            .collect();
+
+        let rank = |score: f32| {
+            if score.is_nan() {
+                f32::NEG_INFINITY
+            } else {
+                score
+            }
+        };
+        ranked.sort_by(|a, b| rank(b.score).total_cmp(&rank(a.score)));
+        Ok(ranked)
+    }
+
+    fn infer_sequences_with_shared_prompt<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        n_sequences: usize,
+        callback: &mut impl FnMut(usize, InferenceResponse) -> R,
+    ) -> Result<Vec<GeneratedSequence>, InferenceError> {
+        // The deadline covers all of the sequences, not each of them.
+        let deadline = request
+            .maximum_duration
+            .and_then(|duration| Instant::now().checked_add(duration));
+        if !request.prompt.is_empty() {
+            self.feed_prompt(model, request.prompt, &mut Default::default(), |_| {
+                InferenceFeedback::Continue
+            })?;
+        }
+
+        if model.supports_sequence_batches() {
+            let n_prompt_tokens = self.n_past;
+            let prompt_logits = self.last_logits.clone();
+            let mut sequences = (0..n_sequences)
+                .map(|index| BatchedSequence {
+                    tokens: self.tokens.clone(),
+                    decoded_tokens: self.decoded_tokens.clone(),
+                    last_logits: self.last_logits.clone(),
+                    seeded_rng: request.seed.map(|seed| {
+                        rand::rngs::StdRng::seed_from_u64(seed.wrapping_add(index as u64))
+                    }),
+                    token_utf8_buf: TokenUtf8Buffer::new(),
+                    token_logprobs: vec![],
+                    token_timings: vec![],
+                    predict_duration: Duration::ZERO,
+                    finished: false,
+                })
+                .collect::<Vec<_>>();
+            let result = self.infer_batched_sequences(
+                model,
+                rng,
+                request,
+                deadline,
+                &mut sequences,
+                callback,
+            );
+            // Discard the sequences, leaving the memory of the prompt.
+            self.n_past = n_prompt_tokens;
+            self.last_logits = prompt_logits;
+            result?;
+
+            let memory = self.memory_stats();
+            return Ok(sequences
+                .into_iter()
+                .map(|sequence| {
+                    let stats = InferenceStats {
+                        predict_duration: sequence.predict_duration,
+                        predict_tokens: sequence.token_timings.len(),
+                        token_latency: LatencyStats::new(&sequence.token_timings),
+                        memory,
+                        ..Default::default()
+                    };
+                    if let Some(telemetry) = &self.telemetry {
+                        telemetry.generation_finished(&stats);
+                    }
+                    GeneratedSequence {
+                        tokens: sequence.tokens[self.tokens.len()..].to_vec(),
+                        text: String::from_utf8_lossy(
+                            &sequence.decoded_tokens[self.decoded_tokens.len()..],
+                        )
+                        .into_owned(),
+                        token_logprobs: sequence.token_logprobs,
+                        stats,
+                    }
+                })
+                .collect());
+        }
+
+        let n_prompt_tokens = self.n_past;
+        let prompt_logits = self.last_logits.clone();
+        let prompt_decoded_tokens = self.decoded_tokens.clone();
+
+        let mut sequences = Vec::with_capacity(n_sequences);
+        for index in 0..n_sequences {
+            let sequence_request = InferenceRequest {
+                prompt: Prompt::Tokens(&[]),
+                play_back_previous_tokens: false,
+                seed: request.seed.map(|seed| seed.wrapping_add(index as u64)),
+                maximum_duration: deadline
+                    .map(|deadline| deadline.saturating_duration_since(Instant::now())),
+                ..*request
+            };
+            let n_logprobs = self.token_logprobs.len();
+            let stats = self.infer(
+                model,
+                rng,
+                &sequence_request,
+                &mut Default::default(),
+                |response| callback(index, response),
+            )?;
+
+            sequences.push(GeneratedSequence {
+                tokens: self.tokens[n_prompt_tokens..].to_vec(),
+                text: String::from_utf8_lossy(&self.decoded_tokens[prompt_decoded_tokens.len()..])
+                    .into_owned(),
+                token_logprobs: self.token_logprobs.drain(n_logprobs..).collect(),
+                stats,
+            });
+
+            // Discard the sequence, leaving the memory of the prompt for the next one.
+            if self.n_past > n_prompt_tokens {
+                self.rewind(model, self.n_past - n_prompt_tokens)?;
+            }
+            self.decoded_tokens.clone_from(&prompt_decoded_tokens);
+            self.last_logits.clone_from(&prompt_logits);
+        }
+        Ok(sequences)
+    }
+
+    /// Generates the `sequences` in lockstep, evaluating the next token of each of them
+    /// in one pass of the model, until each has ended. The session is left with the
+    /// tokens of the sequences in its memory, after those of the prompt.
+    fn infer_batched_sequences<R: InferenceCallbackResult>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InferenceRequest,
+        deadline: Option<Instant>,
+        sequences: &mut [BatchedSequence],
+        callback: &mut impl FnMut(usize, InferenceResponse) -> R,
+    ) -> Result<(), InferenceError> {
+        let start_at = Instant::now();
+        let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
+        let minimum_token_count = if request.ignore_eos {
+            usize::MAX
+        } else {
+            request.minimum_token_count.unwrap_or(0)
+        };
+        let eot_suppressed_parameters = (minimum_token_count > 0).then(|| {
+            request
+                .parameters
+                .clone()
+                .with_logits_processor(SuppressTokens(vec![model.eot_token_id()]))
+        });
+
+        let mut slots = SequenceSlots::new(self.n_past);
+        let position = self.n_past;
+        let n_vocab = self.last_logits.len();
+        let mut output_request = OutputRequest {
+            all_logits: Some(vec![]),
+            ..Default::default()
+        };
+        for step in 0..maximum_token_count {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                log::debug!("Stopping inference at the deadline");
+                break;
+            }
+            let parameters = match &eot_suppressed_parameters {
+                Some(parameters) if step < minimum_token_count => parameters,
+                _ => request.parameters,
+            };
+
+            // Sample the next token of each sequence that has not ended.
+            let sample_start_at = Instant::now();
+            let mut next_tokens = vec![];
+            for (index, sequence) in sequences.iter_mut().enumerate() {
+                if sequence.finished {
+                    continue;
+                }
+                let mut rng: &mut dyn rand::RngCore = match &mut sequence.seeded_rng {
+                    Some(seeded_rng) => seeded_rng,
+                    None => &mut *rng,
+                };
+                let (token, logprobs) = sample_next_token(
+                    parameters,
+                    &sequence.tokens,
+                    &sequence.last_logits,
+                    self.record_logprobs,
+                    &mut rng,
+                )?;
+                sequence.token_logprobs.extend(logprobs);
+                sequence.tokens.push(token);
+                next_tokens.push((index, token));
+            }
+            if next_tokens.is_empty() {
+                break;
+            }
+            let sample_duration = sample_start_at.elapsed();
+
+            // Sequences that end with this token are not evaluated any further.
+            let mut batch = vec![];
+            for &(index, token) in &next_tokens {
+                let sequence = &mut sequences[index];
+                if token == model.eot_token_id() {
+                    sequence.finished = true;
+                    continue;
+                }
+                let text = decode_next_token(model, &sequence.tokens, &sequence.decoded_tokens);
+                sequence.decoded_tokens.extend_from_slice(&text);
+                sequence.finished = step + 1 >= maximum_token_count;
+                if let Some(tokens) = sequence.token_utf8_buf.push(&text) {
+                    match callback(index, InferenceResponse::InferredToken(tokens)).into_result() {
+                        Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
+                        Ok(InferenceFeedback::Continue) => (),
+                        Ok(InferenceFeedback::Halt) => sequence.finished = true,
+                    }
+                }
+                if !sequence.finished {
+                    batch.push((index, token));
+                }
+            }
+
+            // Like the prompt, the tokens are evaluated in batches of at most
+            // `n_batch` tokens.
+            let evaluate_start_at = Instant::now();
+            for batch in batch.chunks(self.config.n_batch) {
+                let batch_tokens: Vec<_> = batch
+                    .iter()
+                    .map(|(index, token)| (*index, std::slice::from_ref(token)))
+                    .collect();
+                self.evaluate_sequences(
+                    model,
+                    &mut slots,
+                    &batch_tokens,
+                    position + step,
+                    &mut output_request,
+                )?;
+                let all_logits = output_request.all_logits.as_deref().unwrap_or_default();
+                for (row, &(index, _)) in batch.iter().enumerate() {
+                    sequences[index]
+                        .last_logits
+                        .copy_from_slice(&all_logits[row * n_vocab..][..n_vocab]);
+                }
+            }
+            let latency = sample_duration + evaluate_start_at.elapsed();
+
+            for &(index, token) in &next_tokens {
+                let sequence = &mut sequences[index];
+                let timing = TokenTiming {
+                    token_id: token,
+                    timestamp: SystemTime::now(),
+                    latency,
+                };
+                if token != model.eot_token_id() {
+                    sequence.token_timings.push(timing);
+                }
+                sequence.predict_duration = start_at.elapsed();
+                if self.record_token_timings {
+                    self.token_timings.push(timing);
+                }
+                if let Some(telemetry) = &self.telemetry {
+                    telemetry.token_generated(&TokenGeneratedEvent {
+                        token_id: token,
+                        latency,
+                    });
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Evaluates the tokens of several independent `sequences`, each given with its index,
+    /// in one pass of the model, which must
+    /// [support sequence batches](Model::supports_sequence_batches).
+    ///
+    /// Each sequence continues the tokens that `slots` shares between all of them, and its
+    /// own tokens evaluated before, and its tokens are at positions from `position`. The
+    /// sequences are padded to the length of the longest of them, and the outputs are laid
+    /// out as the padded tokens are, one sequence after the other.
+    pub(crate) fn evaluate_sequences(
+        &mut self,
+        model: &dyn Model,
+        slots: &mut SequenceSlots,
+        sequences: &[(usize, &[TokenId])],
+        position: usize,
+        output_request: &mut OutputRequest,
+    ) -> Result<(), InferenceError> {
+        let n_tokens = sequences
+            .iter()
+            .map(|(_, tokens)| tokens.len())
+            .max()
+            .unwrap_or(0);
+        let n_batch = n_tokens * sequences.len();
+        if n_batch == 0 {
+            return Ok(());
+        }
+        if self.n_past + n_batch >= model.context_size() {
+            return Err(InferenceError::ContextFull);
+        }
+
+        let n_kv = self.n_past + n_batch;
+        let mut input_tokens = Vec::with_capacity(n_batch);
+        let mut mask = vec![f32::NEG_INFINITY; n_kv * n_batch];
+        for (b, &(sequence, tokens)) in sequences.iter().enumerate() {
+            let batch_start = self.n_past + b * n_tokens;
+            for i in 0..n_tokens {
+                let row = &mut mask[(b * n_tokens + i) * n_kv..][..n_kv];
+                match tokens.get(i) {
+                    Some(&token) => {
+                        input_tokens.push(token);
+                        row[..slots.shared].fill(0.0);
+                        for (slot, owner) in slots.slots.iter().enumerate() {
+                            if *owner == Some(sequence) {
+                                row[slots.shared + slot] = 0.0;
+                            }
+                        }
+                        row[batch_start..=batch_start + i].fill(0.0);
+                    }
+                    None => {
+                        // Padding only attends to itself, so that none of its scores are
+                        // all masked.
+                        input_tokens.push(tokens.last().copied().unwrap_or_default());
+                        row[batch_start + i] = 0.0;
+                    }
+                }
+            }
+        }
+        self.sequence_batch = Some(SequenceBatch {
+            position,
+            n_sequences: sequences.len(),
+            mask,
+        });
+        let result = model.evaluate(self, &input_tokens, output_request);
+        self.sequence_batch = None;
+        result?;
+
+        for &(sequence, tokens) in sequences {
+            slots
+                .slots
+                .extend((0..n_tokens).map(|i| (i < tokens.len()).then_some(sequence)));
+        }
+        Ok(())
+    }
+
+    /// Generates the code that goes between [InfillRequest::prefix] and
+    /// [InfillRequest::suffix] (fill-in-the-middle), for models trained with infill tokens
+    /// (see [InfillTokens::detect]), such as CodeLlama and StarCoder.
     ///
-    /// This is a wrapper around [Self::feed_prompt] and [Self::infer_next_token].
+    /// The `callback` is called with the prompt tokens and then with each generated token,
+    /// until the model ends the code for the gap, [InfillRequest::maximum_token_count]
+    /// tokens have been generated or [InfillRequest::maximum_duration] has passed. This
+    /// should usually be done in a new session. The prompt is never truncated, whatever
+    /// [InferenceSessionConfig::prompt_truncation] is set to.
     #[instrument(skip_all)]
-    pub fn infer<E: std::error::Error + Send + Sync + 'static>(
+    pub fn infill<R: InferenceCallbackResult>(
         &mut self,
         model: &dyn Model,
         rng: &mut impl rand::Rng,
-        request: &InferenceRequest,
+        request: &InfillRequest,
         output_request: &mut OutputRequest,
-        mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
-    ) -> Result<InferenceStats, InferenceError> {
-        let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
-        if request.play_back_previous_tokens {
-            // "Play back" the existing tokens, so that loading from an inference snapshot works
-            // as expected.
-            let mut token_utf8_buf = TokenUtf8Buffer::new();
-            for token_id in &self.tokens {
-                // Buffer the token until it's valid UTF-8, then call the callback.
-                if let Some(tokens) =
-                    token_utf8_buf.push(&model.tokenizer().token(*token_id as usize))
-                {
-                    if let Err(e) = callback(InferenceResponse::SnapshotToken(tokens)) {
-                        return Err(InferenceError::UserCallback(Box::new(e)));
-                    }
-                }
+        mut callback: impl FnMut(InferenceResponse) -> R,
+    ) -> Result<InferenceStats, InfillError> {
+        let result = self.infill_unreported(model, rng, request, output_request, |response| {
+            callback(response).into_result()
+        });
+        if let Some(telemetry) = &self.telemetry {
+            match &result {
+                Ok(stats) => telemetry.generation_finished(stats),
+                Err(err) => telemetry.error(err),
             }
         }
-        log::trace!(
-            "Starting inference request with max_token_count: {}",
-            maximum_token_count
-        );
-
-        let mut stats = InferenceStats::default();
-        let start_at = std::time::SystemTime::now();
+        result
+    }
 
-        let parameters = request.parameters;
+    fn infill_unreported<E: std::error::Error + Send + Sync + 'static>(
+        &mut self,
+        model: &dyn Model,
+        rng: &mut impl rand::Rng,
+        request: &InfillRequest,
+        output_request: &mut OutputRequest,
+        mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E>,
+    ) -> Result<InferenceStats, InfillError> {
+        let infill_tokens =
+            InfillTokens::detect(model.tokenizer()).ok_or(InfillError::UnsupportedModel)?;
+        let tokenize = |text| {
+            Prompt::Text(text)
+                .to_tokens(model.tokenizer(), false)
+                .map_err(InferenceError::from)
+        };
 
-        // Feed the initial prompt through the transformer, to update its
-        // context window with new data, if necessary.
-        if !request.prompt.is_empty() {
-            self.feed_prompt(
-                model,
-                request.prompt,
-                output_request,
-                feed_prompt_callback(&mut callback),
-            )?;
+        let mut prompt = vec![];
+        if self.n_past == 0 {
+            prompt.extend(model.bot_token_id());
         }
-        stats.feed_prompt_duration = start_at.elapsed().unwrap();
-        stats.prompt_tokens = self.n_past;
+        prompt.push(infill_tokens.prefix);
+        prompt.extend(tokenize(request.prefix)?);
+        prompt.push(infill_tokens.suffix);
+        prompt.extend(tokenize(request.suffix)?);
+        prompt.push(infill_tokens.middle);
 
-        // After the prompt is consumed, sample tokens by repeatedly calling
-        // `infer_next_token`. We generate tokens until the model returns an
-        // EndOfText token, or we run out of space in the context window,
-        // or we reach the specified limit.
-        let mut tokens_processed = 0;
+        let mut stats = InferenceStats::default();
+        let start_at = Instant::now();
+        let deadline = request
+            .maximum_duration
+            .and_then(|duration| start_at.checked_add(duration));
+        let n_past_before_prompt = self.n_past;
+        // Truncating the prompt would cut off the prefix or suffix.
+        self.feed_prompt_with_truncation(
+            model,
+            Prompt::Tokens(&prompt),
+            PromptTruncation::Error,
+            output_request,
+            feed_prompt_callback(&mut callback),
+        )?;
+        stats.feed_prompt_duration = start_at.elapsed();
+        stats.prompt_tokens = self.n_past.saturating_sub(n_past_before_prompt);
+        let predict_start_at = Instant::now();
+        let mut token_timings = vec![];
+
+        let mut seeded_rng = request.seed.map(rand::rngs::StdRng::seed_from_u64);
+        let mut rng: &mut dyn rand::RngCore = match &mut seeded_rng {
+            Some(seeded_rng) => seeded_rng,
+            None => rng,
+        };
+
+        let maximum_token_count = request.maximum_token_count.unwrap_or(usize::MAX);
         let mut token_utf8_buf = TokenUtf8Buffer::new();
-        while tokens_processed < maximum_token_count {
-            let token = match self.infer_next_token(model, parameters, &mut Default::default(), rng)
-            {
-                Ok(token) => token,
+        for _ in 0..maximum_token_count {
+            if matches!(deadline, Some(deadline) if Instant::now() >= deadline) {
+                log::debug!("Stopping infill at the deadline");
+                break;
+            }
+            let token = match self.infer_next_token_timed(
+                model,
+                request.parameters,
+                &mut Default::default(),
+                &mut rng,
+            ) {
+                Ok((token, timing)) => {
+                    token_timings.push(timing);
+                    token
+                }
                 Err(InferenceError::EndOfText) => break,
-                Err(e) => return Err(e),
+                Err(e) => return Err(e.into()),
             };
+            if infill_tokens.end.is_some() && self.tokens.last().copied() == infill_tokens.end {
+                break;
+            }
 
-            // Buffer the token until it's valid UTF-8, then call the callback.
             if let Some(tokens) = token_utf8_buf.push(&token) {
                 match callback(InferenceResponse::InferredToken(tokens)) {
-                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e))),
-                    Ok(f) => match f {
-                        InferenceFeedback::Continue => (),
-                        InferenceFeedback::Halt => break,
-                    },
+                    Err(e) => return Err(InferenceError::UserCallback(Box::new(e)).into()),
+                    Ok(InferenceFeedback::Continue) => (),
+                    Ok(InferenceFeedback::Halt) => break,
                 }
             }
-
-            tokens_processed += 1;
         }
-        stats.predict_duration = start_at.elapsed().unwrap();
-        stats.predict_tokens = self.n_past;
+        stats.predict_duration = predict_start_at.elapsed();
+        stats.predict_tokens = token_timings.len();
+        stats.token_latency = LatencyStats::new(&token_timings);
+        stats.memory = self.memory_stats();
 
         Ok(stats)
     }
@@ -521,17 +1871,18 @@ impl InferenceSession {
         model: &dyn Model,
         prompt: P,
         mut perplexity_callback: impl FnMut(usize, f32),
-    ) -> Result<(), TokenizationError> {
+    ) -> Result<(), InferenceError> {
         // Implementation based on perplexity example of llama.cpp:
         // https://github.com/ggerganov/llama.cpp/blob/2d5db48371052087a83974abda3767d1aedec598/examples/perplexity/perplexity.cpp#L24
         let mut tokens = prompt.into().to_tokens(model.tokenizer(), true)?;
+        self.check_tokens(&tokens)?;
 
         let mut count = 0;
 
         // TODO: make this handle <context_size tokens
         let context_size = model.context_size();
         let n_chunk = tokens.len() / context_size;
-        let n_vocab = model.tokenizer().len();
+        let n_vocab = self.last_logits.len();
         let n_batch = self.config.n_batch;
 
         let mut nll = 0.0;
@@ -542,6 +1893,8 @@ impl InferenceSession {
 
             let num_batches = (context_size + n_batch - 1) / n_batch;
 
+            // Each chunk fills the context, so it is evaluated from the start of the session.
+            self.n_past = 0;
             let mut logits = vec![];
 
             for j in 0..num_batches {
@@ -565,7 +1918,7 @@ impl InferenceSession {
                     self,
                     &tokens[batch_start..batch_start + batch_size],
                     &mut output_request,
-                );
+                )?;
 
                 // Restore the original token.
                 tokens[batch_start] = token_org;
@@ -605,6 +1958,7 @@ impl InferenceSession {
         };
 
         InferenceSnapshotRef {
+            version: InferenceSnapshot::VERSION,
             npast: self.n_past,
             config: self.config,
             tokens: self.tokens.clone(),
@@ -619,6 +1973,13 @@ impl InferenceSession {
         snapshot: InferenceSnapshot,
         model: &dyn Model,
     ) -> Result<Self, SnapshotError> {
+        if snapshot.version != InferenceSnapshot::VERSION {
+            return Err(SnapshotError::UnsupportedVersion {
+                version: snapshot.version,
+                expected: InferenceSnapshot::VERSION,
+            });
+        }
+
         let mut session = model.start_session(snapshot.config);
 
         if session.memory_k.nbytes() != snapshot.memory_k.len()
@@ -654,6 +2015,22 @@ impl InferenceSession {
     pub fn decoded_tokens(&self) -> &[u8] {
         self.decoded_tokens.as_ref()
     }
+
+    /// Returns the memory allocated by this session, and the most of it that has been used
+    /// by any evaluation so far.
+    pub fn memory_stats(&self) -> MemoryStats {
+        let kv_cache_size = self.memory_k.nbytes() + self.memory_v.nbytes();
+        let kv_cache_tokens = self.memory_k.nelements() / (self.n_layer * self.n_embd);
+        MemoryStats {
+            context_size: self.ctx0.storage().as_buffer().map_or(0, Buffer::size),
+            context_peak: self.ctx0_peak,
+            scratch_size: self.scratch.iter().map(Buffer::size).sum(),
+            scratch_peak: self.scratch_peak,
+            kv_cache_size,
+            kv_cache_peak: kv_cache_size * self.n_past_peak.min(kv_cache_tokens)
+                / kv_cache_tokens.max(1),
+        }
+    }
 }
 
 impl Drop for InferenceSession {
@@ -664,6 +2041,52 @@ impl Drop for InferenceSession {
     }
 }
 
+/// Samples the token that follows `tokens` from the `logits` that the model gave for it per
+/// the `params`, returning it with its log-probability, and those of the `top_n` most
+/// probable tokens, if `record_logprobs` is `Some(top_n)`.
+fn sample_next_token(
+    params: &InferenceParameters,
+    tokens: &[TokenId],
+    logits: &[f32],
+    record_logprobs: Option<usize>,
+    rng: &mut impl rand::Rng,
+) -> Result<(TokenId, Option<TokenLogprobs>), InferenceError> {
+    let processed_logits;
+    let logits = if params.logits_processors.is_empty() {
+        logits
+    } else {
+        let mut logits = logits.to_vec();
+        crate::samplers::process_logits(&params.logits_processors, tokens, &mut logits)
+            .map_err(InferenceError::SamplerFailure)?;
+        processed_logits = logits;
+        &processed_logits
+    };
+
+    let next_token =
+        crate::samplers::sample_token(params.sampler.clone(), rng, tokens, logits.iter().copied())
+            .map_err(InferenceError::SamplerFailure)?;
+
+    #[cfg(feature = "instrumentation")]
+    tracing::trace!(token = next_token, "sampled token");
+
+    let logprobs = record_logprobs.map(|top_n| TokenLogprobs::new(next_token, logits, top_n));
+    Ok((next_token, logprobs))
+}
+
+/// Returns the text of the last of `tokens`, which follows the text `decoded_tokens` of the
+/// tokens before it.
+fn decode_next_token(model: &dyn Model, tokens: &[TokenId], decoded_tokens: &[u8]) -> Vec<u8> {
+    match model.tokenizer() {
+        crate::Tokenizer::Embedded(_) => {
+            let token = tokens.last().copied().unwrap_or_default();
+            model.tokenizer().token(token as usize).to_vec()
+        }
+        crate::Tokenizer::HuggingFace(_) => {
+            get_newly_decoded_portion_huggingface(model, tokens.to_vec(), decoded_tokens)
+        }
+    }
+}
+
 fn get_newly_decoded_portion_huggingface(
     model: &dyn Model,
     tokens: Vec<u32>,
@@ -699,7 +2122,48 @@ pub enum InferenceError {
     UserCallback(Box<dyn std::error::Error + Send + Sync>),
     /// Sampling returned an error.
     #[error("token sampling failed")]
-    SamplerFailure(crate::samplers::SamplingError),
+    SamplerFailure(#[source] crate::samplers::SamplingError),
+    /// Rewinding the session failed.
+    #[error("failed to rewind the session")]
+    Rewind(#[from] RewindError),
+    /// The model was loaded lazily, and its weights could not be read when they were
+    /// first used.
+    #[error("failed to read the weights of the model")]
+    WeightsUnavailable(#[source] LoadError),
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [InferenceSession::choose].
+pub enum ChooseError {
+    /// There were no candidates to choose from.
+    #[error("there are no candidates to choose from")]
+    NoCandidates,
+    /// The session has not evaluated any tokens to score the candidates against.
+    #[error("the session has not evaluated a prompt")]
+    EmptySession,
+    /// A candidate is empty, or has no tokens, so there is nothing to score.
+    #[error("candidate {index} has no tokens")]
+    EmptyCandidate {
+        /// The index of the candidate.
+        index: usize,
+    },
+    /// Scoring a candidate failed.
+    #[error("failed to score a candidate")]
+    Inference(#[from] InferenceError),
+    /// Removing a candidate from the session after scoring it failed.
+    #[error("failed to rewind a candidate")]
+    Rewind(#[from] RewindError),
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [InferenceSession::infill].
+pub enum InfillError {
+    /// The model does not have the special tokens for infilling.
+    #[error("the model does not support infilling")]
+    UnsupportedModel,
+    /// Evaluating the prompt or generating the code failed.
+    #[error("inference failed")]
+    Inference(#[from] InferenceError),
 }
 
 #[derive(Error, Debug)]
@@ -720,6 +2184,14 @@ pub enum SnapshotError {
     /// Arbitrary I/O error.
     #[error("I/O error while reading or writing snapshot")]
     IO(#[from] std::io::Error),
+    /// The snapshot was created by an incompatible version of `llm`.
+    #[error("unsupported snapshot version {version} (expected {expected})")]
+    UnsupportedVersion {
+        /// The version of the snapshot.
+        version: u32,
+        /// The version supported by this version of `llm`.
+        expected: u32,
+    },
     /// Mismatch between the snapshotted memory and the in-memory memory.
     #[error("could not read snapshot due to size mismatch (self={self_size}, input={input_size})")]
     MemorySizeMismatch {
@@ -730,6 +2202,20 @@ pub enum SnapshotError {
     },
 }
 
+#[derive(Error, Debug, Clone, Copy, PartialEq, Eq)]
+/// Errors encountered by [InferenceSessionConfigBuilder::build].
+pub enum InferenceSessionConfigError {
+    /// The batch size was 0.
+    #[error("the batch size must be at least 1")]
+    InvalidBatchSize,
+    /// The number of threads was 0.
+    #[error("the number of threads must be at least 1")]
+    InvalidThreadCount,
+    /// The number of attention sinks was 0.
+    #[error("the number of attention sinks must be at least 1")]
+    InvalidAttentionSinks,
+}
+
 #[derive(serde::Serialize, Clone, PartialEq)]
 /// A serializable snapshot of the inference process.
 /// Can be created by calling [InferenceSession::get_snapshot].
@@ -739,6 +2225,8 @@ pub enum SnapshotError {
 /// are likely to serialize this as an array of numbers at extreme cost.
 // Keep in sync with [InferenceSession] and [InferenceSnapshot].
 pub struct InferenceSnapshotRef<'a> {
+    /// The version of the snapshot format; see [InferenceSnapshot::VERSION].
+    pub version: u32,
     /// How many tokens have been stored in the memory so far.
     pub npast: usize,
     /// Parameters associated with the saved inference session.
@@ -760,6 +2248,7 @@ impl InferenceSnapshotRef<'_> {
     /// The [ToOwned] trait is not used due to its blanket implementation for all [Clone] types.
     pub fn to_owned(&self) -> InferenceSnapshot {
         InferenceSnapshot {
+            version: self.version,
             npast: self.npast,
             config: self.config,
             tokens: self.tokens.clone(),
@@ -775,6 +2264,10 @@ impl InferenceSnapshotRef<'_> {
 #[derive(serde::Deserialize, Clone, PartialEq)]
 // Keep in sync with [InferenceSession] and [InferenceSnapshotRef].
 pub struct InferenceSnapshot {
+    /// The version of the snapshot format; see [InferenceSnapshot::VERSION]. Snapshots
+    /// saved before the version was recorded have version 0.
+    #[serde(default)]
+    pub version: u32,
     /// How many tokens have been stored in the memory so far.
     pub npast: usize,
     /// Parameters associated with the saved inference session.
@@ -790,12 +2283,23 @@ pub struct InferenceSnapshot {
     #[serde(with = "serde_bytes")]
     pub memory_v: Vec<u8>,
 }
+impl InferenceSnapshot {
+    /// The current version of the snapshot format. Snapshots with a different version
+    /// cannot be restored.
+    pub const VERSION: u32 = 1;
+}
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
 /// Configuration for an inference session.
 ///
 /// This is specified at the time of creation of an [InferenceSession],
-/// and cannot be changed after the session has been created.
+/// and cannot be changed after the session has been created. When it is deserialized,
+/// the fields that are missing keep their [default](Self::default) values.
+///
+/// New fields may be added, so the configuration cannot be created with a struct literal;
+/// use [InferenceSessionConfig::builder] or [Default].
+#[non_exhaustive]
 pub struct InferenceSessionConfig {
     /// The type of the memory K tensor.
     pub memory_k_type: ModelKVMemoryType,
@@ -809,8 +2313,10 @@ pub struct InferenceSessionConfig {
     /// trying to speed up the ingestion of prompts, as it allows for parallelization.
     /// However, you will be fundamentally limited by your machine's ability to evaluate
     /// the transformer model, so increasing the batch size will not always help.
+    /// Larger batches also need more memory for the intermediate results of each batch
+    /// (see [estimate_memory](crate::estimate_memory)).
     ///
-    /// A reasonable default value is 8.
+    /// Must be at least 1; sessions treat 0 as 1. A reasonable default value is 8.
     pub n_batch: usize,
     /// The number of threads to use. This is dependent on your user's system,
     /// and should be selected accordingly.
@@ -827,6 +2333,23 @@ pub struct InferenceSessionConfig {
     /// A reasonable default value is 8, as most modern high-performance computers have
     /// 8 physical cores. Adjust to your needs.
     pub n_threads: usize,
+    /// The number of tokens at the start of the context window to always keep when it
+    /// fills up (attention sinks), or `None` to stop generating when it is full.
+    ///
+    /// When set, and the context window is full, the first `attention_sinks` tokens are
+    /// kept as they are and the oldest half of the remaining tokens are discarded, so that
+    /// generation can continue indefinitely. Models attend strongly to the first tokens
+    /// of their context regardless of their content, so keeping them keeps the quality of
+    /// the output stable (see [StreamingLLM](https://arxiv.org/abs/2309.17453)).
+    ///
+    /// The positions of tokens are part of their cached keys, so the tokens that are kept
+    /// after the sinks are evaluated again at their new positions each time the window
+    /// rolls. This requires the model to support rewinding. A reasonable value is 4.
+    pub attention_sinks: Option<usize>,
+    /// What [InferenceSession::feed_prompt] does with a prompt that does not fit in the
+    /// context window. This is not used when [Self::attention_sinks] is set, as the
+    /// context window rolls instead.
+    pub prompt_truncation: PromptTruncation,
 }
 
 impl Default for InferenceSessionConfig {
@@ -836,12 +2359,144 @@ impl Default for InferenceSessionConfig {
             memory_v_type: ModelKVMemoryType::Float16,
             n_batch: 8,
             n_threads: 8,
+            attention_sinks: None,
+            prompt_truncation: PromptTruncation::Error,
+        }
+    }
+}
+impl InferenceSessionConfig {
+    /// Returns a builder for the configuration, which starts from the [Default] values
+    /// and checks them when it is built.
+    pub fn builder() -> InferenceSessionConfigBuilder {
+        InferenceSessionConfigBuilder::default()
+    }
+}
+
+/// Builds an [InferenceSessionConfig], checking that it is valid. Created with
+/// [InferenceSessionConfig::builder].
+///
+/// Unset options keep their defaults: 16-bit memory, a batch size of 8, 8 threads, no
+/// attention sinks and [PromptTruncation::Error].
+#[derive(Clone, Copy, Debug, Default)]
+pub struct InferenceSessionConfigBuilder {
+    config: InferenceSessionConfig,
+}
+impl InferenceSessionConfigBuilder {
+    /// Sets the type of both the memory K and V tensors.
+    pub fn memory_type(mut self, memory_type: ModelKVMemoryType) -> Self {
+        self.config.memory_k_type = memory_type;
+        self.config.memory_v_type = memory_type;
+        self
+    }
+
+    /// Sets [InferenceSessionConfig::memory_k_type].
+    pub fn memory_k_type(mut self, memory_type: ModelKVMemoryType) -> Self {
+        self.config.memory_k_type = memory_type;
+        self
+    }
+
+    /// Sets [InferenceSessionConfig::memory_v_type].
+    pub fn memory_v_type(mut self, memory_type: ModelKVMemoryType) -> Self {
+        self.config.memory_v_type = memory_type;
+        self
+    }
+
+    /// Sets [InferenceSessionConfig::n_batch], which must be at least 1.
+    pub fn n_batch(mut self, n_batch: usize) -> Self {
+        self.config.n_batch = n_batch;
+        self
+    }
+
+    /// Sets [InferenceSessionConfig::n_threads], which must be at least 1.
+    pub fn n_threads(mut self, n_threads: usize) -> Self {
+        self.config.n_threads = n_threads;
+        self
+    }
+
+    /// Keeps the first `attention_sinks` tokens when the context window fills up, and
+    /// rolls the rest (see [InferenceSessionConfig::attention_sinks]). Must be at least 1.
+    pub fn attention_sinks(mut self, attention_sinks: usize) -> Self {
+        self.config.attention_sinks = Some(attention_sinks);
+        self
+    }
+
+    /// Sets [InferenceSessionConfig::prompt_truncation].
+    pub fn prompt_truncation(mut self, prompt_truncation: PromptTruncation) -> Self {
+        self.config.prompt_truncation = prompt_truncation;
+        self
+    }
+
+    /// Checks the options and builds the configuration.
+    pub fn build(self) -> Result<InferenceSessionConfig, InferenceSessionConfigError> {
+        let config = self.config;
+        if config.n_batch == 0 {
+            return Err(InferenceSessionConfigError::InvalidBatchSize);
+        }
+        if config.n_threads == 0 {
+            return Err(InferenceSessionConfigError::InvalidThreadCount);
+        }
+        if config.attention_sinks == Some(0) {
+            return Err(InferenceSessionConfigError::InvalidAttentionSinks);
+        }
+        Ok(config)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+/// What to do with a prompt that does not fit in the context window (see
+/// [InferenceSessionConfig::prompt_truncation]).
+///
+/// When a prompt is truncated, tokens are dropped until it fills half of the space left
+/// in the context window (after the tokens it keeps), so that there is room to generate
+/// a reply to it. The beginning-of-sentence token is always kept.
+pub enum PromptTruncation {
+    /// Fail with [InferenceError::ContextFull].
+    #[default]
+    Error,
+    /// Drop tokens from the start of the prompt, keeping its end.
+    TruncateStart,
+    /// Keep the first `keep` tokens of the prompt, such as a system prompt, and drop the
+    /// tokens after them, keeping the end of the prompt.
+    TruncateMiddle {
+        /// The number of tokens at the start of the prompt to keep.
+        keep: usize,
+    },
+}
+impl PromptTruncation {
+    /// Truncates `tokens` to fit in `room` tokens, keeping the first `n_fixed` tokens
+    /// (such as the beginning-of-sentence token) in addition to those this keeps.
+    fn apply(
+        self,
+        tokens: &mut Vec<TokenId>,
+        room: usize,
+        n_fixed: usize,
+    ) -> Result<(), InferenceError> {
+        let keep = match self {
+            Self::Error => return Err(InferenceError::ContextFull),
+            Self::TruncateStart => n_fixed,
+            Self::TruncateMiddle { keep } => n_fixed.saturating_add(keep),
+        };
+        if keep >= room {
+            return Err(InferenceError::ContextFull);
         }
+
+        let n_end = ((room - keep) / 2).max(1);
+        let n_dropped = tokens.len() - keep - n_end;
+        log::warn!(
+            "The prompt does not fit in the context window; dropping {n_dropped} of its {} tokens",
+            tokens.len()
+        );
+        tokens.drain(keep..keep + n_dropped);
+        Ok(())
     }
 }
 
 #[derive(Debug, Clone, Copy)]
 /// Settings specific to [InferenceSession::infer].
+///
+/// New fields may be added, so the request cannot be created with a struct literal; use
+/// [InferenceRequest::builder].
+#[non_exhaustive]
 pub struct InferenceRequest<'a> {
     /// The prompt to feed to the model.
     pub prompt: Prompt<'a>,
@@ -855,29 +2510,349 @@ pub struct InferenceRequest<'a> {
     pub play_back_previous_tokens: bool,
     /// The maximum number of tokens to generate.
     pub maximum_token_count: Option<usize>,
+    /// The maximum time to spend on the request, measured from when it starts, so
+    /// including the time taken to feed the prompt. It is checked before each token is
+    /// generated, so the request can overrun it by the time taken to generate one token,
+    /// and it takes precedence over [Self::minimum_token_count].
+    pub maximum_duration: Option<std::time::Duration>,
+    /// The minimum number of tokens to generate. The end-of-text token cannot be
+    /// sampled until this many tokens have been generated.
+    pub minimum_token_count: Option<usize>,
+    /// Whether to prevent the end-of-text token from being sampled at all, so that
+    /// generation only stops at the maximum token count, when the context is full,
+    /// or when the callback halts it.
+    pub ignore_eos: bool,
+    /// If set, tokens are sampled with a random number generator seeded with this
+    /// value instead of the one passed to [InferenceSession::infer], so that the
+    /// result of the request does not depend on what that generator was used for before.
+    pub seed: Option<u64>,
+}
+impl<'a> InferenceRequest<'a> {
+    /// Returns a builder for a request that feeds `prompt` and then generates tokens with
+    /// `parameters`. See [InferenceRequestBuilder] for the defaults of the other settings.
+    ///
+    /// ```
+    /// # use llm_base::{InferenceParameters, InferenceRequest};
+    /// let parameters = InferenceParameters::default();
+    /// let request = InferenceRequest::builder("Rust is", &parameters)
+    ///     .maximum_token_count(64)
+    ///     .seed(0)
+    ///     .build();
+    /// ```
+    pub fn builder(
+        prompt: impl Into<Prompt<'a>>,
+        parameters: &'a InferenceParameters,
+    ) -> InferenceRequestBuilder<'a> {
+        InferenceRequestBuilder {
+            request: InferenceRequest {
+                prompt: prompt.into(),
+                parameters,
+                play_back_previous_tokens: false,
+                maximum_token_count: None,
+                maximum_duration: None,
+                minimum_token_count: None,
+                ignore_eos: false,
+                seed: None,
+            },
+        }
+    }
+}
+
+/// Builds an [InferenceRequest]. Created with [InferenceRequest::builder].
+///
+/// Unset settings keep their defaults: the previous tokens are not played back, there is
+/// no limit on the number or duration of the generated tokens, the end-of-text token can be
+/// sampled at any time, and tokens are sampled with the generator passed to
+/// [InferenceSession::infer].
+#[derive(Debug, Clone, Copy)]
+pub struct InferenceRequestBuilder<'a> {
+    request: InferenceRequest<'a>,
+}
+impl<'a> InferenceRequestBuilder<'a> {
+    /// Sets [InferenceRequest::play_back_previous_tokens].
+    pub fn play_back_previous_tokens(mut self, play_back_previous_tokens: bool) -> Self {
+        self.request.play_back_previous_tokens = play_back_previous_tokens;
+        self
+    }
+
+    /// Sets [InferenceRequest::maximum_token_count].
+    pub fn maximum_token_count(mut self, maximum_token_count: impl Into<Option<usize>>) -> Self {
+        self.request.maximum_token_count = maximum_token_count.into();
+        self
+    }
+
+    /// Sets [InferenceRequest::maximum_duration].
+    pub fn maximum_duration(mut self, maximum_duration: impl Into<Option<Duration>>) -> Self {
+        self.request.maximum_duration = maximum_duration.into();
+        self
+    }
+
+    /// Sets [InferenceRequest::minimum_token_count].
+    pub fn minimum_token_count(mut self, minimum_token_count: impl Into<Option<usize>>) -> Self {
+        self.request.minimum_token_count = minimum_token_count.into();
+        self
+    }
+
+    /// Sets [InferenceRequest::ignore_eos].
+    pub fn ignore_eos(mut self, ignore_eos: bool) -> Self {
+        self.request.ignore_eos = ignore_eos;
+        self
+    }
+
+    /// Sets [InferenceRequest::seed].
+    pub fn seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.request.seed = seed.into();
+        self
+    }
+
+    /// Builds the request.
+    pub fn build(self) -> InferenceRequest<'a> {
+        self.request
+    }
+}
+
+/// A prompt that is being fed to a session a batch at a time, created by
+/// [InferenceSession::prepare_prompt] and fed with [InferenceSession::feed_prompt_batch].
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingPrompt {
+    tokens: Vec<TokenId>,
+    tokens_fed: usize,
+    batches_fed: usize,
+    duration: Duration,
+}
+impl PendingPrompt {
+    /// The tokens of the prompt, after it was truncated.
+    pub fn tokens(&self) -> &[TokenId] {
+        &self.tokens
+    }
+
+    /// Whether all of the tokens of the prompt have been fed.
+    pub fn is_finished(&self) -> bool {
+        self.tokens_fed >= self.tokens.len()
+    }
+
+    /// How much of the prompt has been fed.
+    pub fn progress(&self) -> FeedPromptProgress {
+        FeedPromptProgress {
+            tokens_fed: self.tokens_fed,
+            total_tokens: self.tokens.len(),
+            batches_fed: self.batches_fed,
+        }
+    }
+}
+
+/// How much of a prompt has been fed (see [InferenceSession::feed_prompt_with_progress]
+/// and [InferenceSession::feed_prompt_batch]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct FeedPromptProgress {
+    /// The number of tokens of the prompt that have been evaluated.
+    pub tokens_fed: usize,
+    /// The number of tokens in the prompt, after it was truncated.
+    pub total_tokens: usize,
+    /// The number of batches that have been evaluated; the last of them is the current
+    /// batch.
+    pub batches_fed: usize,
+}
+
+/// The result of [InferenceSession::choose].
+#[derive(Debug, Clone, PartialEq)]
+pub struct Choice {
+    /// The index of the most probable candidate.
+    pub index: usize,
+    /// The log-likelihood of each candidate: the sum of the log-probabilities of its
+    /// tokens. Longer candidates have more tokens to pay for, so divide by their token
+    /// counts to compare candidates of very different lengths.
+    pub scores: Vec<f32>,
+}
+
+/// A completion generated by [InferenceSession::infer_sequences].
+#[derive(Debug, Clone)]
+pub struct GeneratedSequence {
+    /// The tokens generated after the prompt.
+    pub tokens: Vec<TokenId>,
+    /// The text of the generated tokens.
+    pub text: String,
+    /// The log-probabilities of the generated tokens, including the end of text token if
+    /// the sequence ended with one. The most probable tokens are only recorded if the
+    /// session is recording them (see [InferenceSession::set_record_logprobs]).
+    pub token_logprobs: Vec<TokenLogprobs>,
+    /// Statistics about the generation of this sequence.
+    pub stats: InferenceStats,
+}
+impl GeneratedSequence {
+    /// The mean log-probability of the generated tokens, or negative infinity if there are
+    /// none. Unlike their sum, this does not favour shorter sequences.
+    pub fn mean_logprob(&self) -> f32 {
+        if self.token_logprobs.is_empty() {
+            return f32::NEG_INFINITY;
+        }
+        let sum: f32 = self.token_logprobs.iter().map(|t| t.logprob).sum();
+        sum / self.token_logprobs.len() as f32
+    }
+}
+
+/// A completion generated by [InferenceSession::infer_best_of], with its score.
+#[derive(Debug, Clone)]
+pub struct RankedSequence {
+    /// The completion.
+    pub sequence: GeneratedSequence,
+    /// The score the completion was ranked by; higher is better.
+    pub score: f32,
+}
+
+/// When a token was generated by [InferenceSession::infer_next_token], and how long it took.
+/// Recorded if enabled with [InferenceSession::set_record_token_timings].
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenTiming {
+    /// The generated token.
+    pub token_id: TokenId,
+    /// When the token was generated, after it was evaluated.
+    pub timestamp: SystemTime,
+    /// How long it took to sample the token and evaluate it.
+    pub latency: Duration,
+}
+
+/// The log-probability of a token generated by [InferenceSession::infer_next_token], and
+/// of the most probable tokens at that point. Recorded if enabled with
+/// [InferenceSession::set_record_logprobs].
+///
+/// The log-probabilities are those of the logits the token was sampled from, after the
+/// [logits processors](crate::LogitsProcessor) but before the samplers (e.g. temperature).
+#[derive(Debug, Clone, PartialEq)]
+pub struct TokenLogprobs {
+    /// The generated token.
+    pub token_id: TokenId,
+    /// The log-probability of the generated token.
+    pub logprob: f32,
+    /// The most probable tokens and their log-probabilities, most probable first.
+    pub top_logprobs: Vec<(TokenId, f32)>,
+}
+impl TokenLogprobs {
+    fn new(token_id: TokenId, logits: &[f32], top_n: usize) -> Self {
+        let logprobs = util::log_softmax(logits);
+        let mut top_logprobs: Vec<(TokenId, f32)> = logprobs
+            .iter()
+            .enumerate()
+            .map(|(id, &logprob)| (id as TokenId, logprob))
+            .collect();
+        let top_n = top_n.min(top_logprobs.len());
+        if top_n > 0 && top_n < top_logprobs.len() {
+            top_logprobs.select_nth_unstable_by(top_n - 1, |a, b| b.1.total_cmp(&a.1));
+        }
+        top_logprobs.truncate(top_n);
+        top_logprobs.sort_by(|a, b| b.1.total_cmp(&a.1));
+
+        Self {
+            token_id,
+            logprob: logprobs[token_id as usize],
+            top_logprobs,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+/// Settings specific to [InferenceSession::infill].
+///
+/// New fields may be added, so the request cannot be created with a struct literal; use
+/// [InfillRequest::builder].
+#[non_exhaustive]
+pub struct InfillRequest<'a> {
+    /// The code before the gap.
+    pub prefix: &'a str,
+    /// The code after the gap.
+    pub suffix: &'a str,
+    /// The parameters to use during this infill attempt.
+    pub parameters: &'a InferenceParameters,
+    /// The maximum number of tokens to generate.
+    pub maximum_token_count: Option<usize>,
+    /// The maximum time to spend on the request, including feeding the prompt. It is
+    /// checked before each token is generated.
+    pub maximum_duration: Option<std::time::Duration>,
+    /// The seed to sample with. If set, the `rng` passed to [InferenceSession::infill]
+    /// is not used, so that the same code is generated every time.
+    pub seed: Option<u64>,
+}
+impl<'a> InfillRequest<'a> {
+    /// Returns a builder for a request that fills the gap between `prefix` and `suffix`
+    /// with `parameters`. See [InfillRequestBuilder] for the defaults of the other settings.
+    ///
+    /// ```
+    /// # use llm_base::{InferenceParameters, InfillRequest};
+    /// let parameters = InferenceParameters::default();
+    /// let request = InfillRequest::builder("fn add(a: i32, b: i32) -> i32 {", "}", &parameters)
+    ///     .maximum_token_count(64)
+    ///     .build();
+    /// ```
+    pub fn builder(
+        prefix: &'a str,
+        suffix: &'a str,
+        parameters: &'a InferenceParameters,
+    ) -> InfillRequestBuilder<'a> {
+        InfillRequestBuilder {
+            request: InfillRequest {
+                prefix,
+                suffix,
+                parameters,
+                maximum_token_count: None,
+                maximum_duration: None,
+                seed: None,
+            },
+        }
+    }
+}
+
+/// Builds an [InfillRequest]. Created with [InfillRequest::builder].
+///
+/// Unset settings keep their defaults: there is no limit on the number or duration of the
+/// generated tokens, and tokens are sampled with the generator passed to
+/// [InferenceSession::infill].
+#[derive(Debug, Clone, Copy)]
+pub struct InfillRequestBuilder<'a> {
+    request: InfillRequest<'a>,
+}
+impl<'a> InfillRequestBuilder<'a> {
+    /// Sets [InfillRequest::maximum_token_count].
+    pub fn maximum_token_count(mut self, maximum_token_count: impl Into<Option<usize>>) -> Self {
+        self.request.maximum_token_count = maximum_token_count.into();
+        self
+    }
+
+    /// Sets [InfillRequest::maximum_duration].
+    pub fn maximum_duration(mut self, maximum_duration: impl Into<Option<Duration>>) -> Self {
+        self.request.maximum_duration = maximum_duration.into();
+        self
+    }
+
+    /// Sets [InfillRequest::seed].
+    pub fn seed(mut self, seed: impl Into<Option<u64>>) -> Self {
+        self.request.seed = seed.into();
+        self
+    }
+
+    /// Builds the request.
+    pub fn build(self) -> InfillRequest<'a> {
+        self.request
+    }
 }
 
-/// Statistics about the inference process.
-#[derive(Serialize, Debug, Clone, Copy)]
+/// Statistics about the inference process, returned by [InferenceSession::infer] and
+/// [InferenceSession::infill].
+#[derive(Serialize, Debug, Clone, Copy, Default)]
 pub struct InferenceStats {
-    /// How long it took to feed the prompt.
+    /// How long it took to feed the prompt (prefill).
     pub feed_prompt_duration: std::time::Duration,
-    /// How many tokens the prompt was.
+    /// How many tokens of the prompt were fed.
     pub prompt_tokens: usize,
-    /// How long it took to predict new tokens.
+    /// How long it took to predict new tokens (decode), including the time spent in the
+    /// callback.
     pub predict_duration: std::time::Duration,
     /// The number of predicted tokens.
     pub predict_tokens: usize,
-}
-impl Default for InferenceStats {
-    fn default() -> Self {
-        Self {
-            feed_prompt_duration: std::time::Duration::from_secs(0),
-            prompt_tokens: 0,
-            predict_duration: std::time::Duration::from_secs(0),
-            predict_tokens: 0,
-        }
-    }
+    /// The distribution of the time it took to predict each token, excluding the time
+    /// spent in the callback.
+    pub token_latency: LatencyStats,
+    /// The memory used by the session, as of the end of the inference.
+    pub memory: MemoryStats,
 }
 impl Display for InferenceStats {
     fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
@@ -886,6 +2861,8 @@ impl Display for InferenceStats {
             prompt_tokens,
             predict_duration,
             predict_tokens,
+            token_latency,
+            memory,
         } = *self;
 
         let feed_prompt_duration = feed_prompt_duration.as_millis();
@@ -895,12 +2872,123 @@ impl Display for InferenceStats {
         } else {
             predict_duration as f64 / predict_tokens as f64
         };
+        let ms = |duration: Duration| duration.as_secs_f64() * 1000.0;
 
         writeln!(f, "feed_prompt_duration: {}ms", feed_prompt_duration)?;
         writeln!(f, "prompt_tokens: {}", prompt_tokens)?;
         writeln!(f, "predict_duration: {}ms", predict_duration)?;
         writeln!(f, "predict_tokens: {}", predict_tokens)?;
-        write!(f, "per_token_duration: {:.3}ms", per_token_duration)
+        writeln!(f, "per_token_duration: {:.3}ms", per_token_duration)?;
+        write!(
+            f,
+            "token_latency: p50 {:.3}ms, p90 {:.3}ms, p99 {:.3}ms, max {:.3}ms",
+            ms(token_latency.p50),
+            ms(token_latency.p90),
+            ms(token_latency.p99),
+            ms(token_latency.max)
+        )?;
+        writeln!(f)?;
+        write!(f, "{memory}")
+    }
+}
+
+/// The memory allocated by an [InferenceSession] and the most of it that has been used,
+/// as returned by [InferenceSession::memory_stats]. All sizes are in bytes.
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MemoryStats {
+    /// The size of the context that holds the computation graph of an evaluation.
+    pub context_size: usize,
+    /// The most of the context used by an evaluation.
+    pub context_peak: usize,
+    /// The combined size of the scratch buffers, which hold the intermediate results of
+    /// an evaluation.
+    pub scratch_size: usize,
+    /// The most of a scratch buffer used by an evaluation.
+    pub scratch_peak: usize,
+    /// The size of the key/value memory.
+    pub kv_cache_size: usize,
+    /// The most of the key/value memory that has held tokens.
+    pub kv_cache_peak: usize,
+}
+impl Display for MemoryStats {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        let mib = |bytes: usize| bytes as f64 / (1024.0 * 1024.0);
+
+        writeln!(
+            f,
+            "context_memory: {:.2}MiB peak of {:.2}MiB",
+            mib(self.context_peak),
+            mib(self.context_size)
+        )?;
+        writeln!(
+            f,
+            "scratch_memory: {:.2}MiB peak of {:.2}MiB",
+            mib(self.scratch_peak),
+            mib(self.scratch_size)
+        )?;
+        write!(
+            f,
+            "kv_cache_memory: {:.2}MiB peak of {:.2}MiB",
+            mib(self.kv_cache_peak),
+            mib(self.kv_cache_size)
+        )
+    }
+}
+
+/// The distribution of a set of latencies, such as [InferenceStats::token_latency].
+#[derive(Serialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct LatencyStats {
+    /// The mean latency.
+    pub mean: Duration,
+    /// The median latency.
+    pub p50: Duration,
+    /// The 90th percentile latency.
+    pub p90: Duration,
+    /// The 99th percentile latency.
+    pub p99: Duration,
+    /// The maximum latency.
+    pub max: Duration,
+}
+impl LatencyStats {
+    /// Computes the distribution of the latencies of `timings`. The percentiles use the
+    /// nearest-rank method. All of the statistics are zero if there are no timings.
+    pub fn new(timings: &[TokenTiming]) -> Self {
+        if timings.is_empty() {
+            return Self::default();
+        }
+
+        let mut sorted: Vec<Duration> = timings.iter().map(|timing| timing.latency).collect();
+        sorted.sort_unstable();
+        let percentile = |p: f64| {
+            let rank = (p * sorted.len() as f64).ceil() as usize;
+            sorted[rank.clamp(1, sorted.len()) - 1]
+        };
+
+        Self {
+            mean: sorted.iter().sum::<Duration>() / sorted.len() as u32,
+            p50: percentile(0.5),
+            p90: percentile(0.9),
+            p99: percentile(0.99),
+            max: sorted[sorted.len() - 1],
+        }
+    }
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+/// Selects which of a model's transformer layers are evaluated by an [InferenceSession].
+///
+/// By default, all layers are evaluated.
+pub struct EvaluatedLayers {
+    /// If set, only the first `n` layers are evaluated, and the remaining layers are
+    /// skipped (early exit).
+    pub first_n: Option<usize>,
+    /// Layers that are skipped entirely.
+    pub skip: HashSet<usize>,
+}
+impl EvaluatedLayers {
+    /// Whether `layer` is evaluated.
+    pub fn contains(&self, layer: usize) -> bool {
+        self.first_n.map_or(true, |n| layer < n) && !self.skip.contains(&layer)
     }
 }
 
@@ -943,24 +3031,53 @@ pub enum InferenceFeedback {
     Halt,
 }
 
+/// What the `callback` of [InferenceSession::infer] and the other inference functions
+/// returns: an [InferenceFeedback] for callbacks that cannot fail, or a `Result` of one
+/// for callbacks that can. Returning [InferenceFeedback::Halt] stops generation cleanly;
+/// returning an error stops it and fails the inference with
+/// [InferenceError::UserCallback].
+pub trait InferenceCallbackResult {
+    /// The error the callback can fail with.
+    type Error: std::error::Error + Send + Sync + 'static;
+
+    /// Converts the value returned by the callback into a `Result`.
+    fn into_result(self) -> Result<InferenceFeedback, Self::Error>;
+}
+impl InferenceCallbackResult for InferenceFeedback {
+    type Error = std::convert::Infallible;
+
+    fn into_result(self) -> Result<InferenceFeedback, Self::Error> {
+        Ok(self)
+    }
+}
+impl<E: std::error::Error + Send + Sync + 'static> InferenceCallbackResult
+    for Result<InferenceFeedback, E>
+{
+    type Error = E;
+
+    fn into_result(self) -> Result<InferenceFeedback, Self::Error> {
+        self
+    }
+}
+
 /// Adapt an [InferenceResponse] callback so that it can be used in a call to
 /// [InferenceSession::feed_prompt].
-pub fn feed_prompt_callback<'a, E: std::error::Error + Send + Sync + 'static>(
-    mut callback: impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E> + 'a,
-) -> impl FnMut(&[u8]) -> Result<InferenceFeedback, E> + 'a {
+pub fn feed_prompt_callback<'a, R: InferenceCallbackResult>(
+    mut callback: impl FnMut(InferenceResponse) -> R + 'a,
+) -> impl FnMut(&[u8]) -> Result<InferenceFeedback, R::Error> + 'a {
     let mut buffer = TokenUtf8Buffer::new();
     move |token| match buffer.push(token) {
-        Some(tokens) => callback(InferenceResponse::PromptToken(tokens)),
+        Some(tokens) => callback(InferenceResponse::PromptToken(tokens)).into_result(),
         None => Ok(InferenceFeedback::Continue),
     }
 }
 
 /// An [InferenceResponse] callback that will halt inference when a `stop_sequence` is generated.
 /// This callback is used in [InferenceSession::infer] in chat_mode.
-pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync + 'static>(
+pub fn conversation_inference_callback<'a>(
     stop_sequence: &'a str,
     mut callback: impl FnMut(String) + 'a,
-) -> impl FnMut(InferenceResponse) -> Result<InferenceFeedback, E> + 'a {
+) -> impl FnMut(InferenceResponse) -> InferenceFeedback + 'a {
     let mut stop_sequence_buf = String::new();
     move |resp| match resp {
         InferenceResponse::InferredToken(token) => {
@@ -974,21 +3091,21 @@ pub fn conversation_inference_callback<'a, E: std::error::Error + Send + Sync +
                 // which may affect generation. This is non-ideal, but it's the best we can do without
                 // modifying the model.
                 stop_sequence_buf.clear();
-                return Ok(InferenceFeedback::Halt);
+                return InferenceFeedback::Halt;
             } else if stop_sequence.starts_with(&buf) {
                 // We've generated a prefix of the stop sequence, so we need to keep buffering.
                 stop_sequence_buf = buf;
-                return Ok(InferenceFeedback::Continue);
+                return InferenceFeedback::Continue;
             }
 
             // We've generated a token that isn't part of the stop sequence, so we can
             // pass it to the callback.
             stop_sequence_buf.clear();
             callback(buf);
-            Ok(InferenceFeedback::Continue)
+            InferenceFeedback::Continue
         }
-        InferenceResponse::EotToken => Ok(InferenceFeedback::Halt),
-        _ => Ok(InferenceFeedback::Continue),
+        InferenceResponse::EotToken => InferenceFeedback::Halt,
+        _ => InferenceFeedback::Continue,
     }
 }
 
@@ -1019,3 +3136,76 @@ fn kv_memory(
 
     (memory_k, memory_v)
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_session_config_builder() {
+        let config = InferenceSessionConfig::builder()
+            .memory_type(ModelKVMemoryType::Float32)
+            .n_batch(32)
+            .n_threads(4)
+            .attention_sinks(4)
+            .build()
+            .unwrap();
+        assert_eq!(config.memory_k_type, ModelKVMemoryType::Float32);
+        assert_eq!(config.memory_v_type, ModelKVMemoryType::Float32);
+        assert_eq!(config.n_batch, 32);
+        assert_eq!(config.n_threads, 4);
+        assert_eq!(config.attention_sinks, Some(4));
+        assert_eq!(config.prompt_truncation, PromptTruncation::Error);
+
+        let builder = InferenceSessionConfig::builder();
+        assert_eq!(
+            builder.n_batch(0).build().unwrap_err(),
+            InferenceSessionConfigError::InvalidBatchSize
+        );
+        assert_eq!(
+            builder.n_threads(0).build().unwrap_err(),
+            InferenceSessionConfigError::InvalidThreadCount
+        );
+        assert_eq!(
+            builder.attention_sinks(0).build().unwrap_err(),
+            InferenceSessionConfigError::InvalidAttentionSinks
+        );
+    }
+
+    #[test]
+    fn test_deserialize_partial_session_config() {
+        let config: InferenceSessionConfig =
+            serde_json::from_str(r#"{"n_threads": 4, "prompt_truncation": "TruncateStart"}"#)
+                .unwrap();
+        assert_eq!(
+            config,
+            InferenceSessionConfig {
+                n_threads: 4,
+                prompt_truncation: PromptTruncation::TruncateStart,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn test_latency_stats() {
+        assert_eq!(LatencyStats::new(&[]), LatencyStats::default());
+
+        let timing = |ms| TokenTiming {
+            token_id: 0,
+            timestamp: SystemTime::UNIX_EPOCH,
+            latency: Duration::from_millis(ms),
+        };
+        let timings: Vec<TokenTiming> = (1..=100).rev().map(timing).collect();
+        let stats = LatencyStats::new(&timings);
+        assert_eq!(stats.mean, Duration::from_micros(50_500));
+        assert_eq!(stats.p50, Duration::from_millis(50));
+        assert_eq!(stats.p90, Duration::from_millis(90));
+        assert_eq!(stats.p99, Duration::from_millis(99));
+        assert_eq!(stats.max, Duration::from_millis(100));
+
+        let stats = LatencyStats::new(&[timing(7)]);
+        assert_eq!(stats.p50, Duration::from_millis(7));
+        assert_eq!(stats.p99, Duration::from_millis(7));
+    }
+}
@@ -7,10 +7,30 @@
 //! As a user, you probably want to use the [llm](https://crates.io/crates/llm) crate instead.
 #![deny(missing_docs)]
 
+mod checksum;
+mod compressed;
+mod control_vector;
+mod conversation;
+mod convert;
+mod embeddings;
+mod generate;
+mod gguf_metadata;
+#[cfg(feature = "http")]
+mod http;
 mod inference_session;
 mod loader;
 mod lora;
+mod memory;
+mod merge;
+mod preset;
 mod quantize;
+mod repair;
+mod rerank;
+mod session_slots;
+mod split;
+mod telemetry;
+mod tensor_name_mapping;
+mod test_model;
 mod tokenizer;
 
 pub mod model;
@@ -19,28 +39,74 @@ pub mod util;
 
 use std::sync::{Arc, Mutex};
 
+pub use checksum::{checksums_path, write_checksums, TensorChecksums};
+pub use compressed::{
+    is_compressed, pack, unpack, CompressedReader, PackError, PackStats, COMPRESSED_MAGIC,
+};
+pub use control_vector::{ControlVector, ControlVectorError, ControlVectorParameters};
+pub use conversation::{
+    ChatTemplate, Conversation, ConversationError, OverflowStrategy, Turn,
+    DEFAULT_SUMMARY_INSTRUCTION,
+};
+pub use convert::{
+    convert_hf, convert_hf_vocabulary, reverse_hf_rotary_permutation, write_vocabulary_only,
+    ConvertError, ConvertProgress, HfConfig,
+};
+pub use embeddings::{EmbeddingError, EmbeddingOptions, Embeddings, Pooling};
 pub use ggml;
 pub use ggml::Type as ElementType;
 
+#[cfg(feature = "http")]
+pub use http::{load_from_url, HttpRangeReader};
+
+pub use generate::{generate, GenerateOptions, GenerationResult, StopReason, StopSequences};
+pub use gguf_metadata::{
+    read_gguf_metadata, remove_gguf_metadata, set_gguf_metadata, GgufHyperparameters,
+    GgufMetadataError, HeaderRewrite,
+};
 pub use inference_session::{
-    conversation_inference_callback, feed_prompt_callback, GraphOutputs, InferenceError,
-    InferenceFeedback, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    ModelKVMemoryType, RewindError, SnapshotError,
+    conversation_inference_callback, feed_prompt_callback, Choice, ChooseError, EvaluatedLayers,
+    FeedPromptProgress, GeneratedSequence, GraphOutputs, InferenceCallbackResult, InferenceError,
+    InferenceFeedback, InferenceRequest, InferenceRequestBuilder, InferenceResponse,
+    InferenceSession, InferenceSessionConfig, InferenceSessionConfigBuilder,
+    InferenceSessionConfigError, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
+    InfillError, InfillRequest, InfillRequestBuilder, LatencyStats, MemoryStats, ModelKVMemoryType,
+    PendingPrompt, PromptTruncation, RankedSequence, RewindError, SnapshotError, TokenLogprobs,
+    TokenTiming,
 };
 pub use llm_samplers::prelude::{Sampler, SamplerChain};
 pub use loader::{
-    load, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat, FormatMagic,
-    LoadError, LoadProgress, Loader, TensorLoader,
+    load, load_from_reader, load_progress_callback_stdout, ContainerType, FileType, FileTypeFormat,
+    FormatMagic, LayerTensorLoader, LoadError, LoadProgress, Loader, ModelFile, ReadSeek,
+    TensorLoader,
 };
-pub use lora::{LoraAdapter, LoraParameters};
+pub use lora::{LoraAdapter, LoraAdapterConfig, LoraParameters};
 pub use memmap2::Mmap;
-pub use model::{Hyperparameters, KnownModel, Model, ModelContext, ModelParameters, OutputRequest};
-pub use quantize::{quantize, QuantizeError, QuantizeProgress};
+pub use memory::{estimate_memory, MemoryEstimate};
+pub use merge::{merge, MergeError, MergeMethod, MergeProgress};
+pub use model::{
+    DescribeHyperparameters, Hyperparameters, KnownModel, MetadataValue, Model, ModelContext,
+    ModelHyperparameters, ModelParameters, OutputRequest,
+};
+pub use preset::{GenerationPreset, PresetError};
+pub use quantize::{merge_lora, quantize, upgrade, QuantizeError, QuantizeProgress};
 pub use regex::Regex;
+pub use repair::{repair, RepairError, RepairOptions, RepairReport};
+pub use rerank::{RerankError, RerankTemplate};
+pub use samplers::{InferenceParametersBuilder, LogitsProcessor};
+pub use session_slots::{AcquiredSlot, SessionSlots};
+pub use split::{
+    find_shards, is_split_manifest, merge_shards, split, Shard, SplitError, SplitManifest,
+    SplitReader, SPLIT_MANIFEST_MAGIC,
+};
+pub use telemetry::{ModelLoadedEvent, PromptFedEvent, TelemetrySink, TokenGeneratedEvent};
+pub use tensor_name_mapping::{TensorNameMapping, TensorNameMappingError};
+pub use test_model::{
+    test_vocabulary, write_test_lora_adapter, write_test_model, TestModel, TestModelError,
+};
 pub use tokenizer::{
-    InvalidTokenBias, Prompt, TokenBias, TokenId, TokenizationError, Tokenizer, TokenizerLoadError,
-    TokenizerSource,
+    EmbeddedTokenizer, HuggingFaceTokenizer, InfillTokens, InvalidTokenBias, Prompt, TokenBias,
+    TokenId, TokenizationError, Tokenizer, TokenizerLoadError, TokenizerSource,
 };
 pub use util::TokenUtf8Buffer;
 
@@ -49,6 +115,10 @@ pub use util::TokenUtf8Buffer;
 ///
 /// This needs to be provided during all inference calls,
 /// but can be changed between calls.
+///
+/// New fields may be added, so the parameters cannot be created with a struct literal;
+/// use [InferenceParameters::builder], [InferenceParameters::new] or [Default].
+#[non_exhaustive]
 pub struct InferenceParameters {
     /// The sampler to use for sampling tokens from the model's probabilities.
     ///
@@ -61,6 +131,8 @@ pub struct InferenceParameters {
     /// the `llm-samplers` documentation for possible samplers and suggested
     /// combinations: <https://docs.rs/llm-samplers>
     pub sampler: Arc<Mutex<dyn Sampler>>,
+    /// The [LogitsProcessor]s that modify the logits before the sampler runs, in order.
+    pub logits_processors: Vec<Arc<Mutex<dyn LogitsProcessor>>>,
 }
 
 //Since Sampler implements Send and Sync, InferenceParameters should too.
@@ -71,6 +143,47 @@ impl Default for InferenceParameters {
     fn default() -> Self {
         Self {
             sampler: samplers::default_samplers(),
+            logits_processors: vec![],
+        }
+    }
+}
+impl InferenceParameters {
+    /// Returns a builder for the parameters, which configures the common samplers and
+    /// checks their options. See [InferenceParametersBuilder] for the defaults.
+    ///
+    /// ```
+    /// # use llm_base::InferenceParameters;
+    /// let parameters = InferenceParameters::builder()
+    ///     .temperature(0.7)
+    ///     .top_p(0.9)
+    ///     .build()?;
+    /// # Ok::<(), llm_base::samplers::SamplerConfigurationError>(())
+    /// ```
+    pub fn builder() -> InferenceParametersBuilder {
+        InferenceParametersBuilder::default()
+    }
+
+    /// Creates parameters that sample tokens with `sampler`, and have no logits processors.
+    pub fn new(sampler: Arc<Mutex<dyn Sampler>>) -> Self {
+        Self {
+            sampler,
+            logits_processors: vec![],
         }
     }
+
+    /// Adds `processor` to the end of the [logits_processors](Self::logits_processors).
+    pub fn with_logits_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.logits_processors.push(Arc::new(Mutex::new(processor)));
+        self
+    }
+}
+/// Deserializes the options of an [InferenceParametersBuilder] and builds the parameters
+/// from them, failing if they are invalid. As the sampler cannot be inspected, the
+/// parameters cannot be serialized; serialize the builder instead.
+impl<'de> serde::Deserialize<'de> for InferenceParameters {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        <InferenceParametersBuilder as serde::Deserialize>::deserialize(deserializer)?
+            .build()
+            .map_err(serde::de::Error::custom)
+    }
 }
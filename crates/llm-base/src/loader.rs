@@ -4,19 +4,29 @@ use std::{
     fmt::{Debug, Display, Formatter},
     fs::File,
     io::{BufRead, BufReader, Read, Seek, SeekFrom},
+    marker::PhantomData,
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
+    time::Instant,
 };
 
 use crate::{
-    util, Hyperparameters, KnownModel, LoraAdapter, LoraParameters, ModelContext, ModelParameters,
-    TokenId, Tokenizer, TokenizerLoadError, TokenizerSource,
+    checksum,
+    compressed::{self, CompressedReader},
+    gguf_metadata::GgufHyperparameters,
+    memory,
+    split::{self, SplitReader},
+    util, DescribeHyperparameters, Hyperparameters, KnownModel, LoraAdapter, LoraAdapterConfig,
+    MetadataValue, ModelContext, ModelHyperparameters, ModelLoadedEvent, ModelParameters,
+    TensorChecksums, TensorNameMapping, TokenId, Tokenizer, TokenizerLoadError, TokenizerSource,
 };
-pub use ggml::{format::FormatMagic, ContainerType};
 use ggml::{
-    format::{LoadError as FormatLoadError, PartialHyperparameters, TensorLoadInfo},
+    accelerator::Backend,
+    format::{gguf, LoadError as FormatLoadError, PartialHyperparameters, TensorLoadInfo},
     Context, MAX_NAME_LENGTH,
 };
+pub use ggml::{format::FormatMagic, ContainerType};
 use memmap2::Mmap;
 use thiserror::Error;
 use tracing::log;
@@ -193,6 +203,13 @@ pub enum LoadProgress {
         /// LoRA file the patch was applied from.
         source: PathBuf,
     },
+    /// A tensor was replaced with a tensor from an override file.
+    TensorOverridden {
+        /// The name of the replaced tensor.
+        name: String,
+        /// The override file the tensor was loaded from.
+        source: PathBuf,
+    },
     /// A tensor from the current part has been loaded.
     TensorLoaded {
         /// The current tensor (0-indexed).
@@ -200,6 +217,14 @@ pub enum LoadProgress {
         /// The number of total tensors.
         tensor_count: usize,
     },
+    /// A tensor was skipped, because it could not be loaded or the model does not use it.
+    /// Only reported when [ModelParameters::skip_unknown_tensors] is set.
+    TensorSkipped {
+        /// The name of the skipped tensor.
+        name: String,
+        /// Why the tensor was skipped.
+        reason: String,
+    },
     /// A model part has finished fully loading.
     Loaded {
         /// The number of bytes in the part.
@@ -282,6 +307,23 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    #[error("the hyperparameters of {path:?} are invalid: {reason}")]
+    /// The hyperparameters of the model are inconsistent with each other or with the
+    /// [ModelParameters], so the model cannot be evaluated.
+    InvalidHyperparameters {
+        /// The path that failed.
+        path: PathBuf,
+        /// Why the hyperparameters are invalid.
+        reason: String,
+    },
+    #[error("{path:?} failed strict validation: {reason}")]
+    /// The model failed one of the checks of [ModelParameters::strict_validation].
+    StrictValidationFailed {
+        /// The path that failed.
+        path: PathBuf,
+        /// Which check failed.
+        reason: String,
+    },
     #[error("the tensor `{tensor_name}` has the wrong size in {path:?}")]
     /// The tensor `tensor_name` did not match its expected size.
     TensorWrongSize {
@@ -347,7 +389,100 @@ pub enum LoadError {
         /// The path that failed.
         path: PathBuf,
     },
+    /// The requested model architecture is unknown, or support for it was not enabled.
+    #[error("{reason}")]
+    UnsupportedModelArchitecture {
+        /// The name of the architecture that was requested.
+        architecture: String,
+        /// Why the architecture is not supported.
+        reason: String,
+    },
+    /// The weights of the model cannot be modified after loading.
+    ///
+    /// This is returned when attempting to apply a LoRA adapter to a model that
+    /// was memory-mapped or offloaded to an accelerator.
+    #[error("the weights of the model cannot be modified: {reason}")]
+    WeightsNotModifiable {
+        /// Why the weights cannot be modified.
+        reason: String,
+    },
+    #[error("could not read the data of tensor `{tensor_name}` at offset {offset} in {path:?}")]
+    /// The data of a tensor could not be read from the file.
+    TensorReadFailed {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The offset of the tensor's data in the file.
+        offset: u64,
+        /// The path that failed.
+        path: PathBuf,
+        /// The underlying error.
+        #[source]
+        source: std::io::Error,
+    },
+    #[error(
+        "the tensor `{tensor_name}` in {path:?} is corrupted: its checksum is {actual:08x}, \
+        but {} was expected",
+        expected.map_or("none".to_owned(), |e| format!("{e:08x}"))
+    )]
+    /// The data of the tensor `tensor_name` did not match the checksum stored for the model.
+    /// This usually means that the model was only partially downloaded or was corrupted.
+    /// See [crate::TensorChecksums].
+    TensorChecksumMismatch {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The checksum that was stored for the tensor, if there was one.
+        expected: Option<u32>,
+        /// The checksum of the tensor's data.
+        actual: u32,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error(
+        "the tensor `{tensor_name}` in {path:?} was written with a different byte order, \
+        and {element_type} data cannot be converted"
+    )]
+    /// The model was written on a machine with a different byte order, and the data of the
+    /// tensor `tensor_name` cannot be converted to the host's byte order.
+    ByteOrderConversionUnsupported {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The element type of the tensor.
+        element_type: ggml::Type,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("the LoRA adapter {adapter} has not been applied to this model")]
+    /// An attempt was made to remove a LoRA adapter that has not been applied.
+    LoraAdapterNotApplied {
+        /// The adapter that was to be removed.
+        adapter: LoraAdapterConfig,
+    },
+    #[error(
+        "loading {path:?} needs about {:.1} GiB of memory, but only {:.1} GiB is available. \
+        Try a smaller context size, a more heavily quantized model, offloading layers to the GPU, \
+        or memory-mapping the model",
+        *required as f64 / GIB,
+        *available as f64 / GIB
+    )]
+    /// There is not enough memory available to load the model and run inference with it.
+    /// See [crate::estimate_memory].
+    InsufficientMemory {
+        /// The path of the model.
+        path: PathBuf,
+        /// The estimated memory needed, in bytes.
+        required: usize,
+        /// The memory available, in bytes.
+        available: usize,
+    },
+    #[error("invalid compressed model container: {reason}")]
+    /// The compressed model container is invalid. See [crate::CompressedReader].
+    InvalidCompressedContainer {
+        /// Why the container is invalid.
+        reason: String,
+    },
 }
+
+const GIB: f64 = (1024 * 1024 * 1024) as f64;
 impl From<util::FindAllModelFilesError> for LoadError {
     fn from(value: util::FindAllModelFilesError) -> Self {
         match value {
@@ -398,16 +533,113 @@ impl LoadError {
 pub trait TensorLoader<E: std::error::Error> {
     /// Gets a tensor from the loader.
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, E>;
+    /// Gets a tensor from the loader if it is present in the model. This should be used
+    /// for tensors that are optional in an architecture.
+    fn load_optional(&mut self, name: &str) -> Result<Option<ggml::Tensor>, E>;
+    /// Gets a tensor from the loader, checking that it has the dimensions `dims`. This
+    /// should be used for the tensors that are indexed by token ID or position, and
+    /// those whose output is read, so that a model whose tensors do not match its
+    /// hyperparameters fails to load instead of reading out of bounds when evaluated.
+    fn load_with_dims(&mut self, name: &str, dims: &[usize]) -> Result<ggml::Tensor, E>;
+    /// Gets a tensor from the loader if it is present in the model, checking that it has
+    /// the dimensions `dims`; see [TensorLoader::load_with_dims].
+    fn load_optional_with_dims(
+        &mut self,
+        name: &str,
+        dims: &[usize],
+    ) -> Result<Option<ggml::Tensor>, E>;
+    /// Loads the tensors of each of the `n_layer` layers of a model with `load_layer`.
+    ///
+    /// `load_layer` is given a [LayerTensorLoader] that prefixes the names of the tensors
+    /// it loads with `prefix(layer)`, and offloads them to the accelerator if the layer
+    /// [should be offloaded](ModelParameters::should_offload).
+    fn load_layers<L>(
+        &mut self,
+        params: &ModelParameters,
+        n_layer: usize,
+        prefix: impl Fn(usize) -> String,
+        mut load_layer: impl FnMut(&mut LayerTensorLoader<'_, Self, E>) -> Result<L, E>,
+    ) -> Result<Vec<L>, E>
+    where
+        Self: Sized,
+    {
+        // `n_layer` is read from the model, so it is not trusted to allocate up-front; a
+        // corrupt count fails on the first missing tensor instead.
+        let mut layers = Vec::new();
+        for index in 0..n_layer {
+            let mut layer_loader = LayerTensorLoader {
+                loader: self,
+                index,
+                prefix: prefix(index),
+                offload: params.should_offload(index),
+                _error: PhantomData,
+            };
+            layers.push(load_layer(&mut layer_loader)?);
+        }
+        Ok(layers)
+    }
     /// Finish loading the model, returning the context.
     fn finish(self) -> ModelContext;
 }
 
+/// Loads the tensors of a single layer of a model; see [TensorLoader::load_layers].
+pub struct LayerTensorLoader<'a, T, E> {
+    loader: &'a mut T,
+    index: usize,
+    prefix: String,
+    offload: bool,
+    _error: PhantomData<E>,
+}
+impl<T: TensorLoader<E>, E: std::error::Error> LayerTensorLoader<'_, T, E> {
+    /// The index of the layer being loaded.
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// Gets the tensor `name` of this layer, offloaded if the layer is.
+    pub fn load(&mut self, name: &str) -> Result<ggml::Tensor, E> {
+        let tensor = self.loader.load(&format!("{}{name}", self.prefix))?;
+        Ok(self.place(tensor))
+    }
+
+    /// Gets the tensor `name` of this layer, offloaded if the layer is, checking that it
+    /// has the dimensions `dims`.
+    pub fn load_with_dims(&mut self, name: &str, dims: &[usize]) -> Result<ggml::Tensor, E> {
+        let tensor = self
+            .loader
+            .load_with_dims(&format!("{}{name}", self.prefix), dims)?;
+        Ok(self.place(tensor))
+    }
+
+    /// Gets the tensor `name` of this layer, offloaded if the layer is, if it is present
+    /// in the model.
+    pub fn load_optional(&mut self, name: &str) -> Result<Option<ggml::Tensor>, E> {
+        let tensor = self
+            .loader
+            .load_optional(&format!("{}{name}", self.prefix))?;
+        Ok(tensor.map(|tensor| self.place(tensor)))
+    }
+
+    /// Offloads `tensor` to the accelerator if this layer is offloaded; otherwise, the
+    /// tensor is left on the CPU untouched.
+    fn place(&self, tensor: ggml::Tensor) -> ggml::Tensor {
+        if self.offload {
+            tensor.transfer_to(Backend::Gpu)
+        } else {
+            tensor
+        }
+    }
+}
+
 /// Load a GGML model from the `path` and configure it per the `params`. The status
 /// of the loading process will be reported through `load_progress_callback`.
 ///
 /// Note that the model must be a single-part model, and the model in `path`
 /// *must* match the architecture of `M`.
 ///
+/// `path` may also be the manifest of a model split with [split](crate::split), in which
+/// case the model is read from its shards.
+///
 /// # Panics
 ///
 /// - If the model does not match the architecture of `M`. This is not checked
@@ -416,11 +648,15 @@ pub trait TensorLoader<E: std::error::Error> {
 ///
 ///   This is a limitation of the GGML format, which does not
 ///   store any information about the architecture.
+#[cfg_attr(
+    feature = "instrumentation",
+    tracing::instrument(skip_all, fields(path = %path.display()))
+)]
 pub fn load<M: KnownModel>(
     path: &Path,
     tokenizer_source: TokenizerSource,
     params: ModelParameters,
-    load_progress_callback: impl FnMut(LoadProgress),
+    mut load_progress_callback: impl FnMut(LoadProgress),
 ) -> Result<M, LoadError> {
     if !path.exists() {
         return Err(LoadError::FileDoesNotExist {
@@ -433,143 +669,640 @@ pub fn load<M: KnownModel>(
         return Err(LoadError::MultipartNotSupported { paths });
     }
 
-    let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
-        source: e,
-        path: path.to_owned(),
-    })?;
-    let mut reader = BufReader::new(&file);
-    log::trace!("Read model file from {:?}", path);
-
-    let tokenizer = tokenizer_source.retrieve(path)?;
-    let mut loader = Loader::new(tokenizer, load_progress_callback);
+    if split::is_split_manifest(path)? {
+        // Split models are read from their shards in turn, as if they were one file.
+        return load_from_reader(
+            SplitReader::open(path)?,
+            path,
+            tokenizer_source,
+            params,
+            load_progress_callback,
+        );
+    }
 
-    ggml::format::load(&mut reader, &mut loader)
-        .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
-    log::trace!("Loaded GGML model from reader");
+    if compressed::is_compressed(path)? {
+        // Compressed models are decompressed a tensor at a time as they are read.
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        return load_from_reader(
+            CompressedReader::new(BufReader::new(file))?,
+            path,
+            tokenizer_source,
+            params,
+            load_progress_callback,
+        );
+    }
 
-    let Loader {
+    // Only the header is read here; the tensor data is read (or mapped) as the model
+    // asks for each tensor.
+    let ModelFile {
+        container_type,
         hyperparameters,
         tokenizer,
         tensors,
-        mut load_progress_callback,
+        file,
+        ..
+    } = ModelFile::<M::Hyperparameters>::open_with_progress(
+        path,
+        tokenizer_source,
+        params.skip_unknown_tensors,
+        &mut load_progress_callback,
+    )?;
+    log::trace!("Loaded GGML model header from {:?}", path);
+
+    let header = Header {
         container_type,
+        hyperparameters,
+        tokenizer,
+        tensors,
+    };
+    load_weights(
+        path,
+        header,
+        &mut &file,
+        Some(&file),
+        params,
+        &mut load_progress_callback,
+    )
+}
+
+/// Load a GGML model from `reader` and configure it per the `params`, like [load].
+///
+/// This allows loading models from sources other than the filesystem, such as archives,
+/// object storage or network streams. The model is never memory-mapped: each tensor is
+/// read into memory as the model asks for it, in the order the model asks for them.
+/// Sources that cannot seek, such as sockets, can be buffered into memory or a temporary
+/// file first.
+///
+/// `name` identifies the source in errors; it does not need to exist.
+pub fn load_from_reader<M: KnownModel>(
+    mut reader: impl Read + Seek,
+    name: &Path,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    mut load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let header = Header::<M::Hyperparameters>::read(
+        &mut BufReader::new(&mut reader),
+        name,
+        tokenizer_source,
+        params.skip_unknown_tensors,
+        &mut load_progress_callback,
+    )?;
+    log::trace!("Loaded GGML model header from {:?}", name);
+
+    load_weights(
+        name,
+        header,
+        &mut reader,
+        None,
+        params,
+        &mut load_progress_callback,
+    )
+}
+
+/// Checks that the `hyperparameters` of the model at `path` are consistent with each other
+/// and with the `params`, so that a corrupt or malicious model fails to load instead of
+/// panicking or aborting when a session is started or the model is evaluated.
+fn validate_hyperparameters(
+    path: &Path,
+    hyperparameters: &ModelHyperparameters,
+    params: &ModelParameters,
+) -> Result<(), LoadError> {
+    let ModelHyperparameters {
+        n_embd,
+        n_layer,
+        n_head,
+        n_head_kv,
+        n_vocab,
         ..
-    } = loader;
+    } = *hyperparameters;
+    // The dimensions rotated by RoPE, for the architectures that use it.
+    let n_rot = match hyperparameters.get("n_rot") {
+        Some(&MetadataValue::Integer(n_rot)) => Some(n_rot),
+        _ => None,
+    };
 
-    let quantization_version = (&hyperparameters as &M::Hyperparameters)
-        .file_type()
-        .map(|ft| ft.quantization_version)
-        .unwrap_or_default();
-    let quantization_version = if quantization_version == 0 {
-        // HACK: I think llama.cpp does not actually write the quantization version correctly,
-        // so we need to guess it from the container type.
-        if container_type == ggml::ContainerType::Ggjt(2) {
-            1
-        } else if container_type == ggml::ContainerType::Ggjt(3) {
-            2
-        } else {
-            quantization_version
-        }
+    let reason = if n_embd == 0 || n_layer == 0 || n_head == 0 || n_head_kv == 0 || n_vocab == 0 {
+        Some(format!(
+            "n_embd ({n_embd}), n_layer ({n_layer}), n_head ({n_head}), \
+            n_head_kv ({n_head_kv}) and n_vocab ({n_vocab}) must not be zero"
+        ))
+    } else if n_embd % n_head != 0 {
+        Some(format!(
+            "n_embd ({n_embd}) must be a multiple of n_head ({n_head})"
+        ))
+    } else if let Some(n_rot) =
+        n_rot.filter(|&n_rot| usize::try_from(n_rot).map_or(true, |n_rot| n_rot > n_embd / n_head))
+    {
+        Some(format!(
+            "n_rot ({n_rot}) must be between 0 and the size of a head ({})",
+            n_embd / n_head
+        ))
+    } else if n_head % n_head_kv != 0 {
+        Some(format!(
+            "n_head ({n_head}) must be a multiple of n_head_kv ({n_head_kv})"
+        ))
+    } else if matches!(params.n_gqa, Some(n_gqa) if n_gqa == 0 || n_head % n_gqa != 0) {
+        Some(format!(
+            "n_head ({n_head}) must be a multiple of the n_gqa parameter ({:?})",
+            params.n_gqa
+        ))
+    } else if params.context_size == 0 {
+        Some("the context size must not be zero".to_owned())
+    } else if TokenId::try_from(n_vocab).is_err() {
+        Some(format!("n_vocab ({n_vocab}) must fit in a token ID"))
+    } else if params
+        .context_size
+        .checked_mul(n_layer)
+        .and_then(|n| n.checked_mul(n_embd))
+        .and_then(|n| n.checked_mul(std::mem::size_of::<f32>()))
+        .is_none()
+    {
+        Some(format!(
+            "the key/value memory for a context of {} tokens must fit in memory",
+            params.context_size
+        ))
     } else {
-        quantization_version
+        None
     };
+
+    match reason {
+        Some(reason) => Err(LoadError::InvalidHyperparameters {
+            path: path.to_owned(),
+            reason,
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Runs the checks of [ModelParameters::strict_validation] on the model at `path`, reading
+/// the data of its floating-point tensors from `source`.
+fn validate_strictly(
+    path: &Path,
+    hyperparameters: &ModelHyperparameters,
+    tokenizer: &Tokenizer,
+    tensors: &HashMap<String, TensorLoadInfo>,
+    source: &mut dyn ReadSeek,
+) -> Result<(), LoadError> {
+    let fail = |reason: String| LoadError::StrictValidationFailed {
+        path: path.to_owned(),
+        reason,
+    };
+
+    if tokenizer.len() > hyperparameters.n_vocab {
+        return Err(fail(format!(
+            "the vocabulary has {} tokens, but the model only has embeddings for {}",
+            tokenizer.len(),
+            hyperparameters.n_vocab
+        )));
+    }
+
+    // The tensors of a well-formed model never share their data.
+    let mut ranges: Vec<_> = tensors
+        .values()
+        .map(|info| {
+            let end = info.start_offset.saturating_add(info.calc_size() as u64);
+            (info.start_offset, end, info)
+        })
+        .collect();
+    ranges.sort_by_key(|&(start, end, _)| (start, end));
+    for pair in ranges.windows(2) {
+        let ((_, end, first), (start, _, second)) = (pair[0], pair[1]);
+        if start < end {
+            return Err(fail(format!(
+                "the data of the tensors `{}` and `{}` overlaps",
+                first.name, second.name
+            )));
+        }
+    }
+
+    // The tensors are read in chunks, so that large ones are not read into memory at once.
+    let mut buffer = vec![0; 1 << 20];
+    for (start, end, info) in ranges {
+        let element_size = match info.element_type {
+            ggml::Type::F32 => 4,
+            ggml::Type::F16 => 2,
+            _ => continue,
+        };
+        source.seek(SeekFrom::Start(start))?;
+        let mut offset = start;
+        while offset < end {
+            let chunk = &mut buffer[..(end - offset).min(1 << 20) as usize];
+            source
+                .read_exact(chunk)
+                .map_err(|source| LoadError::TensorReadFailed {
+                    tensor_name: info.name.clone(),
+                    offset,
+                    path: path.to_owned(),
+                    source,
+                })?;
+            let non_finite = chunk
+                .chunks_exact(element_size)
+                .position(|bytes| !is_finite(bytes, info.byte_order));
+            if let Some(index) = non_finite {
+                return Err(fail(format!(
+                    "the tensor `{}` holds a value that is not finite at index {}",
+                    info.name,
+                    (offset - start) as usize / element_size + index
+                )));
+            }
+            offset += chunk.len() as u64;
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns whether the `f32` or `f16` in `bytes`, written in `byte_order`, is finite.
+fn is_finite(bytes: &[u8], byte_order: ggml::format::ByteOrder) -> bool {
+    use ggml::format::ByteOrder;
+    match (bytes.len(), byte_order) {
+        (4, ByteOrder::Little) => f32::from_le_bytes(bytes.try_into().unwrap()).is_finite(),
+        (4, ByteOrder::Big) => f32::from_be_bytes(bytes.try_into().unwrap()).is_finite(),
+        // An f16 is infinite or NaN if all of its exponent bits are set.
+        (_, ByteOrder::Little) => u16::from_le_bytes([bytes[0], bytes[1]]) & 0x7c00 != 0x7c00,
+        (_, ByteOrder::Big) => u16::from_be_bytes([bytes[0], bytes[1]]) & 0x7c00 != 0x7c00,
+    }
+}
+
+/// The parts of a model file that are read before its tensors.
+struct Header<Hp: Hyperparameters> {
+    container_type: ContainerType,
+    hyperparameters: Hp,
+    tokenizer: Tokenizer,
+    tensors: HashMap<String, TensorLoadInfo>,
+}
+impl<Hp: Hyperparameters> Header<Hp> {
+    fn read(
+        reader: &mut (impl BufRead + Seek),
+        path: &Path,
+        tokenizer_source: TokenizerSource,
+        skip_unsupported_tensors: bool,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError> {
+        let tokenizer = tokenizer_source.retrieve(path)?;
+        let mut loader: Loader<Hp, _> = Loader::new(tokenizer, load_progress_callback);
+        loader.skip_unsupported_tensors = skip_unsupported_tensors;
+        ggml::format::load(reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        Ok(Self {
+            container_type: loader.container_type,
+            hyperparameters: loader.hyperparameters,
+            tokenizer: loader.tokenizer,
+            tensors: loader.tensors,
+        })
+    }
+}
+
+/// Creates the model from its `header`, reading its tensors from `source`. If the model is
+/// in `file`, its tensors are mapped from it if the parameters allow it, or read from it on
+/// several threads otherwise.
+fn load_weights<M: KnownModel>(
+    path: &Path,
+    header: Header<M::Hyperparameters>,
+    source: &mut dyn ReadSeek,
+    file: Option<&File>,
+    params: ModelParameters,
+    load_progress_callback: &mut dyn FnMut(LoadProgress),
+) -> Result<M, LoadError> {
+    let start_at = Instant::now();
+    let Header {
+        container_type,
+        hyperparameters,
+        tokenizer,
+        tensors,
+    } = header;
+
+    validate_hyperparameters(path, &hyperparameters.describe(), &params)?;
+    if params.strict_validation {
+        validate_strictly(
+            path,
+            &hyperparameters.describe(),
+            &tokenizer,
+            &tensors,
+            source,
+        )?;
+        log::trace!("Strictly validated {} tensors", tensors.len());
+    }
+
+    let quantization_version = quantization_version(container_type, &hyperparameters);
     log::trace!(
         "Determined quantization version of model as {:?}",
         quantization_version
     );
 
     // TODO: this is temporary while we figure out how to handle this
-    if tensors.values().any(|t| t.element_type.is_quantized()) {
-        assert_eq!(quantization_version, 2, "quantization version must be 2");
+    if quantization_version != 2 && tensors.values().any(|t| t.element_type.is_quantized()) {
+        return Err(LoadError::InvariantBroken {
+            path: Some(path.to_owned()),
+            invariant: format!("quantization version must be 2, not {quantization_version}"),
+        });
     }
 
-    let use_mmap =
-        params.prefer_mmap && container_type.support_mmap() && params.lora_adapters.is_none();
+    // The checksums are verified as the data of each tensor is read.
+    let checksums = params
+        .verify_checksums
+        .then(|| checksum::read_for_tensors(path, &tensors, params.tensor_name_mapping.as_ref()))
+        .transpose()?
+        .map(Arc::new);
 
-    let ctx_size = tensors
+    let tensors = match &params.tensor_name_mapping {
+        Some(mapping) => rename_tensors(path, tensors, mapping)?,
+        None => tensors,
+    };
+
+    // Tensors written with the other byte order are converted as they are read, so they
+    // cannot be memory-mapped.
+    let swap_byte_order = tensors
         .values()
-        .map(|ti| ti.calc_absolute_size(use_mmap))
-        .sum::<usize>();
+        .any(|info| info.byte_order != ggml::format::ByteOrder::NATIVE);
+    if swap_byte_order {
+        if let Some(info) = tensors
+            .values()
+            .find(|info| !ggml::format::can_swap_byte_order(info.element_type))
+        {
+            return Err(LoadError::ByteOrderConversionUnsupported {
+                tensor_name: info.name.clone(),
+                element_type: info.element_type,
+                path: path.to_owned(),
+            });
+        }
+        log::info!("Converting the tensors of {path:?} to the host's byte order as they are read");
+    }
+
+    let tensor_overrides = params
+        .tensor_overrides
+        .as_deref()
+        .map(|path| TensorOverrides::load::<M::Hyperparameters>(path, &tensors))
+        .transpose()?;
+
+    let mmap_file = file.filter(|_| {
+        params.prefer_mmap
+            && container_type.support_mmap()
+            && !swap_byte_order
+            && params.lora_adapters.is_none()
+            && tensor_overrides.is_none()
+    });
+    let use_mmap = mmap_file.is_some();
+
+    let ctx_size = tensors
+        .iter()
+        .map(|(name, ti)| {
+            tensor_overrides
+                .as_ref()
+                .and_then(|o| o.tensors.get(name))
+                .unwrap_or(ti)
+                .calc_absolute_size(use_mmap)
+        })
+        .try_fold(0usize, usize::checked_add)
+        .ok_or_else(|| LoadError::InvariantBroken {
+            path: Some(path.to_owned()),
+            invariant: "the size of the tensors fits in usize".to_owned(),
+        })?;
     log::trace!("Context size: {:?}", ctx_size);
 
-    let mut lora_adapters: Option<Vec<LoraAdapter>> = None;
-    if let Some(lora_paths) = &params.lora_adapters {
-        let adapters: Result<Vec<_>, _> = lora_paths
-            .iter()
-            .map(|lora_path| {
-                // Read the LoRA file
-                let lora_file = File::open(lora_path).map_err(|e| LoadError::OpenFileFailed {
-                    source: e,
-                    path: lora_path.to_owned(),
-                })?;
-                let mut lora_reader = BufReader::new(&lora_file);
-                // TODO: Consider updating the progress callback to report the progress of the LoRA file.
-                // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
-                let mut lora_loader: Loader<LoraParameters, _> =
-                    Loader::new(Tokenizer::empty_embedded(), |_| {});
-                ggml::format::load(&mut lora_reader, &mut lora_loader)
-                    .map_err(|err| LoadError::from_format_error(err, lora_path.to_owned()))?;
-
-                // Collect the names of the tensors that should be patched
-                let tensors_to_patch = lora_loader
-                    .tensors
-                    .keys()
-                    .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
-                    .collect();
-
-                log::trace!("Loaded LoRA weights");
-                // Return the LoRA patches
-                Ok::<_, LoadError>(LoraAdapter {
-                    scaling: lora_loader.hyperparameters.calculate_scaling(),
-                    tensors: lora_loader.tensors,
-                    tensors_to_patch,
-                    file: lora_file,
-                    path: lora_path.to_owned(),
-                })
-            })
-            .collect();
-        lora_adapters = Some(adapters?);
+    // Fail before allocating anything if the weights and the key/value memory of a
+    // session will not fit. Memory-mapped weights are paged in from the file as needed,
+    // and the weights of offloaded layers are moved to the GPU.
+    if !params.skip_memory_check {
+        let offloaded = memory::offloaded_weights(&tensors, &params);
+        memory::check_available_memory(
+            path,
+            &hyperparameters,
+            if use_mmap {
+                0
+            } else {
+                ctx_size.saturating_sub(offloaded)
+            },
+            params.context_size,
+        )?;
     }
 
+    let lora_adapters = params
+        .lora_adapters
+        .iter()
+        .flatten()
+        .map(|config| Ok((config.clone(), LoraAdapter::load(config)?)))
+        .collect::<Result<Vec<_>, LoadError>>()?;
+
     (load_progress_callback)(LoadProgress::ContextSize { bytes: ctx_size });
-    let (context, file_size) = if use_mmap {
-        let file = File::open(path)?;
-        unsafe {
-            let mmap = Mmap::map(&file)?;
+    let stream_weights = params.stream_weights && use_mmap && cfg!(unix);
+    if params.stream_weights && !stream_weights {
+        log::warn!("Weight streaming requires mmap on a Unix-like system; it will not be used");
+    }
+
+    let (context, file_size) = match mmap_file {
+        Some(file) => unsafe {
+            let mmap = Mmap::map(file)?;
+            #[cfg(unix)]
+            if stream_weights {
+                // Read the weights ahead as they are used, and let them be released soon after.
+                mmap.advise(memmap2::Advice::Sequential)?;
+            }
             let file_size = mmap.len() as u64;
             (Context::new_with_mmap(mmap), file_size)
+        },
+        None => (
+            Context::new_with_allocate(ctx_size),
+            source.seek(SeekFrom::End(0))?,
+        ),
+    };
+
+    // Without mmap, the tensors are read on a pool of threads while the model is created,
+    // or when it is first used if loading lazily. LoRA adapters patch each tensor as it is
+    // loaded, so they need its data straight away.
+    let read_in_parallel = !use_mmap && lora_adapters.is_empty() && cfg!(any(unix, windows));
+    let (parallel_reader, deferred_reads) = match file {
+        Some(file) if read_in_parallel && params.lazy_loading && !params.use_gpu => {
+            let deferred_reads = DeferredReads {
+                file: file.try_clone()?,
+                path: path.to_owned(),
+                checksums: checksums.clone(),
+                reads: vec![],
+            };
+            (None, Some(deferred_reads))
         }
-    } else {
-        (Context::new_with_allocate(ctx_size), file.metadata()?.len())
+        Some(file) if read_in_parallel => (
+            Some(ParallelTensorReader::new(file, path, checksums.clone())?),
+            None,
+        ),
+        _ => (None, None),
     };
+    let mut read_error = None;
+    let mut unused_tensors = vec![];
+    // Tensors may be offloaded to the GPU as soon as they are loaded, so their data must
+    // have been read by then.
+    let wait_for_reads = params.use_gpu;
 
+    let skip_unknown_tensors = params.skip_unknown_tensors;
+    let telemetry = params.telemetry.clone();
     let tensors_len = tensors.len();
     let tl = MmapCompatibleLoader {
+        parallel_reader,
+        deferred_reads,
+        wait_for_reads,
+        read_error: &mut read_error,
+        unused_tensors: &mut unused_tensors,
         path: path.to_owned(),
-        file,
+        source,
         tensors,
         context,
         lora_adapters,
-        load_progress_callback: &mut load_progress_callback,
+        tensor_overrides,
+        checksums,
+        stream_weights,
+        load_progress_callback,
         loaded_tensors: Default::default(),
     };
 
     let model = KnownModel::new(hyperparameters, params, tokenizer, tl)?;
+    if let Some(err) = read_error {
+        return Err(err);
+    }
+    if skip_unknown_tensors {
+        unused_tensors.sort();
+        for name in unused_tensors {
+            let reason = "the model does not use it".to_owned();
+            log::warn!("Skipping tensor `{name}`, as {reason}");
+            (load_progress_callback)(LoadProgress::TensorSkipped { name, reason });
+        }
+    }
 
     (load_progress_callback)(LoadProgress::Loaded {
         file_size,
         tensor_count: tensors_len,
     });
+    if let Some(telemetry) = telemetry {
+        telemetry.model_loaded(&ModelLoadedEvent {
+            path,
+            file_size,
+            tensor_count: tensors_len,
+            duration: start_at.elapsed(),
+        });
+    }
 
     log::trace!("Loaded model");
 
     Ok(model)
 }
 
+/// Determines the version of the quantization formats that the tensors of a model with
+/// `hyperparameters` in a `container_type` container were quantized with.
+pub(crate) fn quantization_version(
+    container_type: ContainerType,
+    hyperparameters: &impl Hyperparameters,
+) -> u32 {
+    let quantization_version = hyperparameters
+        .file_type()
+        .map(|ft| ft.quantization_version)
+        .unwrap_or_default();
+    if quantization_version == 0 {
+        // HACK: I think llama.cpp does not actually write the quantization version correctly,
+        // so we need to guess it from the container type.
+        if container_type == ggml::ContainerType::Ggjt(2) {
+            1
+        } else if matches!(
+            container_type,
+            ggml::ContainerType::Ggjt(3) | ggml::ContainerType::Gguf(_)
+        ) {
+            2
+        } else {
+            quantization_version
+        }
+    } else {
+        quantization_version
+    }
+}
+
+/// A model file whose header has been read: its container type, hyperparameters,
+/// tokenizer and the layout of its tensors.
+///
+/// Tensor data is only read when it is asked for with [ModelFile::read_tensor], so opening
+/// a model file is cheap even for large models. This suits tools that only need part of
+/// a model, such as inspecting its metadata, validating it or extracting its vocabulary.
+/// [load] opens the model file this way before creating the model from it.
+pub struct ModelFile<Hp: Hyperparameters> {
+    /// The path of the model file.
+    pub path: PathBuf,
+    /// The container type of the model.
+    pub container_type: ContainerType,
+    /// The hyperparameters of the model.
+    pub hyperparameters: Hp,
+    /// The tokenizer of the model.
+    pub tokenizer: Tokenizer,
+    /// The tensors of the model, by name.
+    pub tensors: HashMap<String, TensorLoadInfo>,
+    file: File,
+}
+impl<Hp: Hyperparameters> ModelFile<Hp> {
+    /// Reads the header of the model at `path`, with the tokenizer from `tokenizer_source`.
+    ///
+    /// The model in `path` must match the architecture of `Hp`.
+    pub fn open(path: &Path, tokenizer_source: TokenizerSource) -> Result<Self, LoadError> {
+        Self::open_with_progress(path, tokenizer_source, false, |_| {})
+    }
+
+    pub(crate) fn open_with_progress(
+        path: &Path,
+        tokenizer_source: TokenizerSource,
+        skip_unsupported_tensors: bool,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError> {
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let Header {
+            container_type,
+            hyperparameters,
+            tokenizer,
+            tensors,
+        } = Header::read(
+            &mut BufReader::new(&file),
+            path,
+            tokenizer_source,
+            skip_unsupported_tensors,
+            load_progress_callback,
+        )?;
+        Ok(Self {
+            path: path.to_owned(),
+            container_type,
+            hyperparameters,
+            tokenizer,
+            tensors,
+            file,
+        })
+    }
+
+    /// Reads the data of the tensor `name` from the file.
+    pub fn read_tensor(&self, name: &str) -> Result<Vec<u8>, LoadError> {
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| LoadError::UnknownTensor {
+                tensor_name: name.to_owned(),
+                path: self.path.clone(),
+            })?;
+        info.read_data(&mut BufReader::new(&self.file))
+            .map_err(|source| LoadError::TensorReadFailed {
+                tensor_name: name.to_owned(),
+                offset: info.start_offset,
+                path: self.path.clone(),
+                source,
+            })
+    }
+}
+
 /// A GGML format loader for LLMs.
 pub struct Loader<Hp: Hyperparameters, F: FnMut(LoadProgress)> {
     // Input
     load_progress_callback: F,
+    /// Whether to skip tensors that cannot be loaded instead of failing.
+    pub skip_unsupported_tensors: bool,
 
     // Input/Output
     /// The tokenizer of the model.
@@ -588,6 +1321,7 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> Loader<Hp, F> {
     pub fn new(tokenizer: Tokenizer, load_progress_callback: F) -> Self {
         Self {
             load_progress_callback,
+            skip_unsupported_tensors: false,
 
             container_type: ContainerType::Ggml,
             hyperparameters: Hp::default(),
@@ -604,6 +1338,24 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         Ok(())
     }
 
+    fn metadata(&mut self, metadata: &gguf::Metadata) -> Result<(), LoadError> {
+        // A model converted from a Hugging Face tokenizer carries it, with its merges and
+        // special tokens, so it is used in place of the embedded vocabulary.
+        let json = metadata
+            .get(gguf::HUGGINGFACE_TOKENIZER_KEY)
+            .and_then(|value| value.as_str());
+        if let (Tokenizer::Embedded(_), Some(json)) = (&self.tokenizer, json) {
+            self.tokenizer = TokenizerSource::HuggingFaceTokenizerString(json.to_owned())
+                .retrieve(Path::new(gguf::HUGGINGFACE_TOKENIZER_KEY))?;
+        }
+        if let (Tokenizer::Embedded(mv), Some(ids)) =
+            (&mut self.tokenizer, gguf::special_token_ids(metadata))
+        {
+            mv.set_special_token_ids(ids);
+        }
+        Ok(())
+    }
+
     fn vocabulary_token(&mut self, i: usize, token: Vec<u8>, score: f32) -> Result<(), LoadError> {
         if let Tokenizer::Embedded(mv) = &mut self.tokenizer {
             let id = match TokenId::try_from(i) {
@@ -632,35 +1384,145 @@ impl<Hp: Hyperparameters, F: FnMut(LoadProgress)> ggml::format::LoadHandler<Load
         Ok(partial)
     }
 
+    fn read_gguf_hyperparameters(
+        &mut self,
+        metadata: &gguf::Metadata,
+    ) -> Result<Option<PartialHyperparameters>, LoadError> {
+        let Some(metadata) = GgufHyperparameters::new(metadata) else {
+            return Ok(None);
+        };
+        let hyperparameters = Hp::read_gguf(&metadata)?;
+        let partial = PartialHyperparameters {
+            n_vocab: hyperparameters.n_vocabulary(),
+        };
+        self.hyperparameters = hyperparameters;
+        (self.load_progress_callback)(LoadProgress::HyperparametersLoaded);
+
+        Ok(Some(partial))
+    }
+
     fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), LoadError> {
         self.tensors.insert(info.name.clone(), info);
         Ok(())
     }
+
+    fn skip_unsupported_tensor(&mut self, tensor_name: &str, reason: &str) -> bool {
+        if self.skip_unsupported_tensors {
+            let reason = format!("it cannot be loaded (invariant broken: {reason})");
+            log::warn!("Skipping tensor `{tensor_name}`, as {reason}");
+            (self.load_progress_callback)(LoadProgress::TensorSkipped {
+                name: tensor_name.to_owned(),
+                reason,
+            });
+        }
+        self.skip_unsupported_tensors
+    }
+}
+
+/// Renames the tensors of the model at `path` per the `mapping`.
+fn rename_tensors(
+    path: &Path,
+    tensors: HashMap<String, TensorLoadInfo>,
+    mapping: &TensorNameMapping,
+) -> Result<HashMap<String, TensorLoadInfo>, LoadError> {
+    let mut renamed = HashMap::with_capacity(tensors.len());
+    for (name, mut info) in tensors {
+        let new_name = mapping.map(&name).into_owned();
+        if new_name != name {
+            log::trace!("Mapped tensor `{name}` to `{new_name}`");
+        }
+        info.name = new_name.clone();
+        if renamed.insert(new_name.clone(), info).is_some() {
+            return Err(LoadError::InvariantBroken {
+                path: Some(path.to_owned()),
+                invariant: format!("more than one tensor is mapped to `{new_name}`"),
+            });
+        }
+    }
+    Ok(renamed)
 }
 
 struct MmapCompatibleLoader<'a> {
+    /// Reads the tensors' data, if they are not read as they are loaded. This must be
+    /// dropped before `context`, as it writes to the context's memory.
+    parallel_reader: Option<ParallelTensorReader>,
+    /// The reads of the tensors' data that are deferred until the model is first used, if
+    /// they are.
+    deferred_reads: Option<DeferredReads>,
+    /// Whether the read of each tensor's data is waited for before the tensor is returned,
+    /// rather than when the model is finished.
+    wait_for_reads: bool,
+    /// Where the error of the parallel reads, if any, is stored once they are finished.
+    read_error: &'a mut Option<LoadError>,
+    /// Where the names of the tensors that the model did not load are stored once it is
+    /// finished.
+    unused_tensors: &'a mut Vec<String>,
     path: PathBuf,
-    file: File,
+    source: &'a mut dyn ReadSeek,
     tensors: HashMap<String, TensorLoadInfo>,
     context: Context,
-    lora_adapters: Option<Vec<LoraAdapter>>,
+    lora_adapters: Vec<(LoraAdapterConfig, LoraAdapter)>,
+    tensor_overrides: Option<TensorOverrides>,
+    /// The checksums that the data of the tensors is verified against as it is read, if it
+    /// is.
+    checksums: Option<Arc<TensorChecksums>>,
+    stream_weights: bool,
     load_progress_callback: &'a mut dyn FnMut(LoadProgress),
     loaded_tensors: HashMap<String, ggml::Tensor>,
 }
 impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
     fn load(&mut self, name: &str) -> Result<ggml::Tensor, LoadError> {
-        let info = self.tensors.get(name).ok_or(LoadError::UnknownTensor {
-            tensor_name: String::from(name),
-            path: Default::default(),
-        })?;
+        let info = self
+            .tensors
+            .get(name)
+            .ok_or_else(|| LoadError::UnknownTensor {
+                tensor_name: String::from(name),
+                path: self.path.clone(),
+            })?;
 
-        let mut main_context = FileContext::new(&self.context, &mut self.file, &self.path);
-
-        let mut tensor = main_context.get_tensor(info)?;
+        let mut tensor = match &mut self.tensor_overrides {
+            Some(overrides) if overrides.tensors.contains_key(name) => {
+                let mut override_context =
+                    FileContext::new(&self.context, &mut overrides.file, &overrides.path);
+                let tensor = override_context.get_tensor(&overrides.tensors[name])?;
+                (self.load_progress_callback)(LoadProgress::TensorOverridden {
+                    name: name.to_owned(),
+                    source: overrides.path.to_owned(),
+                });
+                tensor
+            }
+            _ => {
+                let mut main_context = FileContext::new(&self.context, self.source, &self.path)
+                    .with_checksums(self.checksums.as_deref());
+                match (&mut self.deferred_reads, &self.parallel_reader) {
+                    (Some(deferred_reads), _) => {
+                        let mut tensor = main_context.create_tensor(info)?;
+                        deferred_reads
+                            .reads
+                            .push(TensorRead::new(info, &mut tensor));
+                        tensor
+                    }
+                    (None, Some(reader)) => {
+                        let mut tensor = main_context.create_tensor(info)?;
+                        let pending = reader.read(TensorRead::new(info, &mut tensor));
+                        if self.wait_for_reads && !pending.wait() {
+                            let reader = self.parallel_reader.take().unwrap();
+                            return Err(reader.finish().err().unwrap_or_else(|| {
+                                LoadError::InvariantBroken {
+                                    path: Some(self.path.clone()),
+                                    invariant: format!("the data of `{name}` was read"),
+                                }
+                            }));
+                        }
+                        tensor
+                    }
+                    (None, None) => main_context.get_tensor(info)?,
+                }
+            }
+        };
 
-        if let Some(lora_adapters) = &mut self.lora_adapters {
-            for lora_adapter in lora_adapters {
-                lora_adapter.patch(info, &mut tensor)?;
+        for (_, lora_adapter) in &mut self.lora_adapters {
+            if lora_adapter.patch(name, &mut tensor)? {
                 (self.load_progress_callback)(LoadProgress::LoraApplied {
                     name: name.to_owned(),
                     source: lora_adapter.path.to_owned(),
@@ -668,6 +1530,14 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
             }
         }
 
+        #[cfg(feature = "instrumentation")]
+        tracing::trace!(
+            tensor = name,
+            element_type = ?tensor.get_type(),
+            n_bytes = tensor.nbytes(),
+            "loaded tensor"
+        );
+
         (self.load_progress_callback)(LoadProgress::TensorLoaded {
             current_tensor: self.loaded_tensors.len(),
             tensor_count: self.tensors.len(),
@@ -677,29 +1547,182 @@ impl TensorLoader<LoadError> for MmapCompatibleLoader<'_> {
         Ok(tensor)
     }
 
-    fn finish(self) -> ModelContext {
+    fn load_optional(&mut self, name: &str) -> Result<Option<ggml::Tensor>, LoadError> {
+        if !self.tensors.contains_key(name) {
+            return Ok(None);
+        }
+        self.load(name).map(Some)
+    }
+
+    fn load_with_dims(&mut self, name: &str, dims: &[usize]) -> Result<ggml::Tensor, LoadError> {
+        // Overridden tensors are checked to have the dimensions of the original ones.
+        if let Some(info) = self.tensors.get(name) {
+            if info.dims() != dims {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name.to_owned(),
+                    path: self.path.clone(),
+                });
+            }
+        }
+        self.load(name)
+    }
+
+    fn load_optional_with_dims(
+        &mut self,
+        name: &str,
+        dims: &[usize],
+    ) -> Result<Option<ggml::Tensor>, LoadError> {
+        if !self.tensors.contains_key(name) {
+            return Ok(None);
+        }
+        self.load_with_dims(name, dims).map(Some)
+    }
+
+    fn finish(mut self) -> ModelContext {
+        if let Some(reader) = self.parallel_reader.take() {
+            if let Err(err) = reader.finish() {
+                *self.read_error = Some(err);
+            }
+        }
+        *self.unused_tensors = self
+            .tensors
+            .keys()
+            .filter(|name| !self.loaded_tensors.contains_key(*name))
+            .cloned()
+            .collect();
         // We can ignore this warning as it's OK to share this particular
         // context around, being that it is immutable.
         #[allow(clippy::arc_with_non_send_sync)]
-        ModelContext(Arc::new(self.context))
+        ModelContext {
+            context: Arc::new(self.context),
+            tensors: Arc::new(self.loaded_tensors),
+            lora_adapters: Arc::new(Mutex::new(
+                self.lora_adapters
+                    .into_iter()
+                    .map(|(config, _)| config)
+                    .collect(),
+            )),
+            stream_weights: self.stream_weights,
+            deferred_reads: Arc::new(Mutex::new(self.deferred_reads)),
+        }
     }
 }
 
+/// Tensors from a secondary file that replace the same-named tensors of the model.
+struct TensorOverrides {
+    path: PathBuf,
+    file: File,
+    tensors: HashMap<String, TensorLoadInfo>,
+}
+impl TensorOverrides {
+    /// Reads the tensors in `path`, checking that each of them replaces a tensor
+    /// in `base_tensors` with the same dimensions.
+    fn load<Hp: Hyperparameters>(
+        path: &Path,
+        base_tensors: &HashMap<String, TensorLoadInfo>,
+    ) -> Result<Self, LoadError> {
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        let mut loader: Loader<Hp, _> = Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        for (name, info) in &loader.tensors {
+            let Some(base) = base_tensors.get(name) else {
+                return Err(LoadError::UnknownTensor {
+                    tensor_name: name.to_owned(),
+                    path: path.to_owned(),
+                });
+            };
+            if base.dims() != info.dims() {
+                return Err(LoadError::TensorWrongSize {
+                    tensor_name: name.to_owned(),
+                    path: path.to_owned(),
+                });
+            }
+        }
+
+        log::trace!(
+            "Loaded {} tensor overrides from {path:?}",
+            loader.tensors.len()
+        );
+        Ok(Self {
+            path: path.to_owned(),
+            file,
+            tensors: loader.tensors,
+        })
+    }
+}
+
+/// A source of model data that can be read from at any offset, for [load_from_reader].
+/// Implemented for everything that implements [Read] and [Seek].
+pub trait ReadSeek: Read + Seek {}
+impl<T: Read + Seek> ReadSeek for T {}
+
 pub(crate) struct FileContext<'a> {
     context: &'a Context,
-    file: &'a mut File,
+    file: &'a mut dyn ReadSeek,
     path: &'a Path,
+    checksums: Option<&'a TensorChecksums>,
 }
 impl<'a> FileContext<'a> {
-    pub(crate) fn new(context: &'a Context, file: &'a mut File, path: &'a Path) -> Self {
+    pub(crate) fn new(context: &'a Context, file: &'a mut dyn ReadSeek, path: &'a Path) -> Self {
         Self {
             context,
             file,
             path,
+            checksums: None,
         }
     }
 
+    /// Verifies the data of the tensors against `checksums` as it is read, if they are given.
+    fn with_checksums(mut self, checksums: Option<&'a TensorChecksums>) -> Self {
+        self.checksums = checksums;
+        self
+    }
+
     pub(crate) fn get_tensor(&mut self, info: &TensorLoadInfo) -> Result<ggml::Tensor, LoadError> {
+        let mut tensor = self.create_tensor(info)?;
+
+        match self.context.storage().as_mmap() {
+            Some(mmap) => unsafe {
+                let ptr = mmap.as_ptr().offset(info.start_offset as isize);
+                if let Some(checksums) = self.checksums {
+                    let data = std::slice::from_raw_parts(ptr, tensor.nbytes());
+                    checksum::verify_tensor(checksums, &info.name, data, self.path)?;
+                }
+                tensor.set_data(ptr as *mut std::ffi::c_void);
+            },
+            None => {
+                let buf: &mut [u8] = unsafe {
+                    std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
+                };
+                self.file
+                    .seek(SeekFrom::Start(info.start_offset))
+                    .and_then(|_| self.file.read_exact(buf))
+                    .map_err(|source| LoadError::TensorReadFailed {
+                        tensor_name: info.name.to_owned(),
+                        offset: info.start_offset,
+                        path: self.path.to_owned(),
+                        source,
+                    })?;
+                if let Some(checksums) = self.checksums {
+                    checksum::verify_tensor(checksums, &info.name, buf, self.path)?;
+                }
+                if info.byte_order != ggml::format::ByteOrder::NATIVE {
+                    ggml::format::swap_byte_order(info.element_type, buf);
+                }
+            }
+        }
+
+        Ok(tensor)
+    }
+
+    /// Creates the tensor described by `info` in the context, without reading its data.
+    fn create_tensor(&self, info: &TensorLoadInfo) -> Result<ggml::Tensor, LoadError> {
         let name = &info.name;
         let ne = info.dims();
         let dims = ne.len();
@@ -714,7 +1737,7 @@ impl<'a> FileContext<'a> {
             });
         }
 
-        let mut tensor = match dims {
+        let tensor = match dims {
             1 => self.context.new_tensor_1d(info.element_type, ne[0]),
             2 => self.context.new_tensor_2d(info.element_type, ne[0], ne[1]),
             3 => self
@@ -730,28 +1753,204 @@ impl<'a> FileContext<'a> {
             }
         };
 
-        match self.context.storage().as_mmap() {
-            Some(mmap) => unsafe {
-                let ptr = mmap.as_ptr().offset(info.start_offset as isize);
-                tensor.set_data(ptr as *mut std::ffi::c_void);
-            },
-            None => {
-                let buf: &mut [u8] = unsafe {
-                    std::slice::from_raw_parts_mut(tensor.data() as *mut u8, tensor.nbytes())
-                };
-                self.file.seek(SeekFrom::Start(info.start_offset))?;
-                self.file.read_exact(buf)?;
-            }
+        // The tensor name is truncated to its maximum length, at a character boundary, and
+        // cannot contain NUL bytes, which a model file does not prevent.
+        let mut start = name.len().saturating_sub(MAX_NAME_LENGTH);
+        while !name.is_char_boundary(start) {
+            start += 1;
         }
+        let tensor_name = name[start..].replace('\0', "");
 
-        // The tensor name is truncated to its maximum length.
-        let tensor_name = if name.len() >= MAX_NAME_LENGTH {
-            &name[name.len() - MAX_NAME_LENGTH..]
-        } else {
-            name
-        };
+        Ok(tensor.set_name(&tensor_name))
+    }
+}
+
+/// Reads the data of tensors into their memory on a pool of threads, so that the reads
+/// overlap with each other and with the creation of the rest of the model.
+///
+/// Converting the data that is read (verifying its checksum and swapping its byte order) is
+/// pipelined with the reads: it is done on a second pool of threads, so that the reads of the
+/// next tensors are not held up by it.
+struct ParallelTensorReader {
+    jobs: Option<mpsc::Sender<TensorJob>>,
+    read_workers: Vec<JoinHandle<Result<(), LoadError>>>,
+    conversion_workers: Vec<JoinHandle<Result<(), LoadError>>>,
+}
+/// A read of a tensor's data from the file into the tensor's memory.
+#[derive(Clone)]
+struct TensorRead {
+    name: String,
+    offset: u64,
+    element_type: ggml::Type,
+    byte_order: ggml::format::ByteOrder,
+    data: *mut u8,
+    len: usize,
+}
+// SAFETY: The tensor's memory belongs to the model's context, which outlives the read, as
+// the reader is finished (or dropped) before the context is. Nothing else accesses the
+// memory until the read is finished.
+unsafe impl Send for TensorRead {}
+impl TensorRead {
+    /// The read of the data of `tensor`, described by `info`.
+    fn new(info: &TensorLoadInfo, tensor: &mut ggml::Tensor) -> Self {
+        Self {
+            name: info.name.clone(),
+            offset: info.start_offset,
+            element_type: info.element_type,
+            byte_order: info.byte_order,
+            data: unsafe { tensor.data() } as *mut u8,
+            len: tensor.nbytes(),
+        }
+    }
+}
+/// A queued [TensorRead], and the channel that its completion is signalled on.
+struct TensorJob {
+    read: TensorRead,
+    done: mpsc::Sender<()>,
+}
+/// A [TensorRead] that has been queued; see [ParallelTensorReader::read].
+struct PendingRead(mpsc::Receiver<()>);
+impl PendingRead {
+    /// Waits for the read, and the conversion of its data, to finish. Returns `false` if
+    /// they failed, in which case [ParallelTensorReader::finish] returns the error.
+    fn wait(self) -> bool {
+        self.0.recv().is_ok()
+    }
+}
+
+impl ParallelTensorReader {
+    /// Starts the threads that read from `file`, verifying the data that they read against
+    /// `checksums` if they are given.
+    fn new(
+        file: &File,
+        path: &Path,
+        checksums: Option<Arc<TensorChecksums>>,
+    ) -> Result<Self, LoadError> {
+        let n_threads = std::thread::available_parallelism().map_or(1, |n| n.get());
+        let (jobs, receiver) = mpsc::channel::<TensorJob>();
+        let receiver = Arc::new(Mutex::new(receiver));
+        let (conversions, conversion_receiver) = mpsc::channel::<TensorJob>();
+        let conversion_receiver = Arc::new(Mutex::new(conversion_receiver));
+
+        let read_workers = (0..n_threads)
+            .map(|_| {
+                let file = file.try_clone()?;
+                let receiver = receiver.clone();
+                let conversions = conversions.clone();
+                let path = path.to_owned();
+                let needs_conversion = checksums.is_some();
+                Ok(std::thread::spawn(move || loop {
+                    let Ok(job) = receiver.lock().unwrap().recv() else {
+                        return Ok(());
+                    };
+                    let read = &job.read;
+                    let buf = unsafe { std::slice::from_raw_parts_mut(read.data, read.len) };
+                    util::read_exact_at(&file, buf, read.offset).map_err(|source| {
+                        LoadError::TensorReadFailed {
+                            tensor_name: read.name.clone(),
+                            offset: read.offset,
+                            path: path.clone(),
+                            source,
+                        }
+                    })?;
+                    if needs_conversion || read.byte_order != ggml::format::ByteOrder::NATIVE {
+                        // Sending only fails if every conversion worker has stopped on an
+                        // error, which `finish` returns.
+                        let _ = conversions.send(job);
+                    } else {
+                        let _ = job.done.send(());
+                    }
+                }))
+            })
+            .collect::<Result<_, LoadError>>()?;
+        // The conversion workers stop once the read workers, which queue the conversions,
+        // have stopped.
+        drop(conversions);
+
+        let conversion_workers = (0..n_threads)
+            .map(|_| {
+                let receiver = conversion_receiver.clone();
+                let path = path.to_owned();
+                let checksums = checksums.clone();
+                std::thread::spawn(move || loop {
+                    let Ok(job) = receiver.lock().unwrap().recv() else {
+                        return Ok(());
+                    };
+                    let read = &job.read;
+                    let buf = unsafe { std::slice::from_raw_parts_mut(read.data, read.len) };
+                    if let Some(checksums) = &checksums {
+                        checksum::verify_tensor(checksums, &read.name, buf, &path)?;
+                    }
+                    if read.byte_order != ggml::format::ByteOrder::NATIVE {
+                        ggml::format::swap_byte_order(read.element_type, buf);
+                    }
+                    let _ = job.done.send(());
+                })
+            })
+            .collect();
+
+        Ok(Self {
+            jobs: Some(jobs),
+            read_workers,
+            conversion_workers,
+        })
+    }
+
+    /// Queues `read`. The data of the tensor must not be used until the returned
+    /// [PendingRead] is waited for, or the reader is finished.
+    fn read(&self, read: TensorRead) -> PendingRead {
+        let (done, pending) = mpsc::channel();
+        // Sending only fails if every worker has stopped on an error, which `finish` returns.
+        let _ = self.jobs.as_ref().unwrap().send(TensorJob { read, done });
+        PendingRead(pending)
+    }
+
+    /// Waits for the queued reads to finish, returning the first error.
+    fn finish(mut self) -> Result<(), LoadError> {
+        self.join()
+    }
+
+    fn join(&mut self) -> Result<(), LoadError> {
+        self.jobs = None;
+        let mut result = Ok(());
+        // The read workers are joined first, as they queue work for the conversion workers.
+        let workers = self
+            .read_workers
+            .drain(..)
+            .chain(self.conversion_workers.drain(..));
+        for worker in workers.collect::<Vec<_>>() {
+            let worker_result = worker.join().expect("tensor reader thread panicked");
+            if result.is_ok() {
+                result = worker_result;
+            }
+        }
+        result
+    }
+}
+impl Drop for ParallelTensorReader {
+    fn drop(&mut self) {
+        // The reads write to the context's memory, so they must finish before it is freed.
+        let _ = self.join();
+    }
+}
 
-        Ok(tensor.set_name(tensor_name))
+/// The reads of the data of a model's tensors that were deferred until the model is first
+/// used; see [ModelParameters::lazy_loading].
+pub(crate) struct DeferredReads {
+    file: File,
+    path: PathBuf,
+    checksums: Option<Arc<TensorChecksums>>,
+    reads: Vec<TensorRead>,
+}
+impl DeferredReads {
+    /// Reads the data of all of the tensors on a pool of threads.
+    pub(crate) fn read(&self) -> Result<(), LoadError> {
+        let reader = ParallelTensorReader::new(&self.file, &self.path, self.checksums.clone())?;
+        for read in &self.reads {
+            // The reads are waited for by `finish`.
+            let _ = reader.read(read.clone());
+        }
+        reader.finish()
     }
 }
 
@@ -791,5 +1990,15 @@ pub fn load_progress_callback_stdout(progress: LoadProgress) {
                 source.file_name().unwrap().to_str().unwrap()
             );
         }
+        LoadProgress::TensorOverridden { name, source } => {
+            println!(
+                "Overrode tensor {} from '{}'",
+                name,
+                source.file_name().unwrap().to_str().unwrap()
+            );
+        }
+        LoadProgress::TensorSkipped { name, reason } => {
+            println!("Skipped tensor {name}, as {reason}");
+        }
     };
 }
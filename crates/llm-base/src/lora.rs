@@ -1,14 +1,18 @@
 use crate::{
     loader::FileContext, model::HyperparametersWriteError, util, FileType, Hyperparameters,
-    LoadError,
+    LoadError, Loader, Tokenizer,
 };
 
 use ggml::{format::TensorLoadInfo, GraphExecutionPlan};
 use std::{
     collections::{HashMap, HashSet},
+    fmt::{Display, Formatter},
     fs::File,
-    path::PathBuf,
+    io::BufReader,
+    path::{Path, PathBuf},
+    str::FromStr,
 };
+use tracing::log;
 
 #[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
 /// Parameters for a [LoRA](https://arxiv.org/abs/2106.09685) adapter.
@@ -52,6 +56,56 @@ impl Hyperparameters for LoraParameters {
     }
 }
 
+#[derive(Debug, PartialEq, Clone)]
+/// A [LoRA](https://arxiv.org/abs/2106.09685) adapter to apply to a model, and the
+/// strength it should be applied with.
+pub struct LoraAdapterConfig {
+    /// Path to the LoRA file.
+    pub path: PathBuf,
+    /// Multiplier for the adapter's own `alpha / r` scaling. `1.0` applies the adapter
+    /// as trained; `0.5` applies it at half-strength.
+    pub scale: f32,
+}
+impl LoraAdapterConfig {
+    /// Creates a new configuration for the adapter at `path` with the given `scale`.
+    pub fn new(path: impl Into<PathBuf>, scale: f32) -> Self {
+        Self {
+            path: path.into(),
+            scale,
+        }
+    }
+}
+impl From<PathBuf> for LoraAdapterConfig {
+    fn from(path: PathBuf) -> Self {
+        Self::new(path, 1.0)
+    }
+}
+impl From<&Path> for LoraAdapterConfig {
+    fn from(path: &Path) -> Self {
+        Self::new(path, 1.0)
+    }
+}
+impl FromStr for LoraAdapterConfig {
+    type Err = std::convert::Infallible;
+
+    /// Parses `path` or `path:scale`. If the part after the last `:` is not a valid
+    /// number, the entire string is treated as a path (e.g. `C:\adapter.bin`).
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(match s.rsplit_once(':') {
+            Some((path, scale)) if !path.is_empty() => match scale.parse::<f32>() {
+                Ok(scale) => Self::new(path, scale),
+                Err(_) => Self::new(s, 1.0),
+            },
+            _ => Self::new(s, 1.0),
+        })
+    }
+}
+impl Display for LoraAdapterConfig {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}:{}", self.path.display(), self.scale)
+    }
+}
+
 /// [LoRA](https://arxiv.org/abs/2106.09685) adapter for a model.
 pub struct LoraAdapter {
     /// Scaling to apply to the LoRA weights.
@@ -66,58 +120,118 @@ pub struct LoraAdapter {
     pub path: PathBuf,
 }
 
+/// The `a` and `b` tensors of the patch for one tensor, read by [LoraAdapter::read_patch].
+pub(crate) struct LoraPatch {
+    a: ggml::Tensor,
+    b: ggml::Tensor,
+    /// The context that holds `a` and `b`.
+    _context: ggml::Context,
+}
+
 impl LoraAdapter {
-    /// Patch a tensor via LoRA
-    pub fn patch(
-        &mut self,
-        info: &TensorLoadInfo,
-        tensor: &mut ggml::Tensor,
-    ) -> Result<(), LoadError> {
+    /// Reads the adapter described by `config`. The scaling of the returned adapter
+    /// is the product of the adapter's own `alpha / r` and [LoraAdapterConfig::scale].
+    pub fn load(config: &LoraAdapterConfig) -> Result<Self, LoadError> {
+        let path = &config.path;
+        let file = File::open(path).map_err(|e| LoadError::OpenFileFailed {
+            source: e,
+            path: path.to_owned(),
+        })?;
+        let mut reader = BufReader::new(&file);
+        // TODO: Consider updating the progress callback to report the progress of the LoRA file.
+        // Most LoRAs are small enough that this is not necessary, but it would be nice to have.
+        let mut loader: Loader<LoraParameters, _> =
+            Loader::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(&mut reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?;
+
+        // Collect the names of the tensors that should be patched
+        let tensors_to_patch = loader
+            .tensors
+            .keys()
+            .filter_map(|k| Some(k.rsplit_once('.')?.0.to_owned()))
+            .collect();
+
+        log::trace!("Loaded LoRA weights from {path:?}");
+        Ok(LoraAdapter {
+            scaling: loader.hyperparameters.calculate_scaling() * config.scale,
+            tensors: loader.tensors,
+            tensors_to_patch,
+            file,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Patch a tensor via LoRA. Returns `true` if the adapter contained a patch
+    /// for the tensor `name`, and `false` if it was left untouched.
+    pub fn patch(&mut self, name: &str, tensor: &mut ggml::Tensor) -> Result<bool, LoadError> {
+        match self.read_patch(name)? {
+            Some(patch) => {
+                self.apply_patch(&patch, tensor);
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+
+    /// Reads the `a` and `b` tensors of the patch for the tensor `name`, or returns `None`
+    /// if the adapter does not patch it. The patch is applied with [Self::apply_patch], so
+    /// that every patch can be read before any tensor is changed.
+    pub(crate) fn read_patch(&mut self, name: &str) -> Result<Option<LoraPatch>, LoadError> {
         // Check if we need to patch this tensor
-        let name = &info.name;
         if !self.tensors_to_patch.contains(name) {
-            return Ok(());
+            return Ok(None);
         }
 
         let a_info = self.get_info(&format!("{}.loraA", name))?;
         let b_info = self.get_info(&format!("{}.loraB", name))?;
 
+        // Load the A and B tensors into a context of their own, which is kept until the
+        // patch is applied.
+        let context = ggml::Context::new_with_allocate(
+            a_info.calc_absolute_size(false) + b_info.calc_absolute_size(false),
+        );
+        let mut patch_file = FileContext::new(&context, &mut self.file, &self.path);
+        let a = patch_file.get_tensor(&a_info)?;
+        let b = patch_file.get_tensor(&b_info)?;
+
+        Ok(Some(LoraPatch {
+            a,
+            b,
+            _context: context,
+        }))
+    }
+
+    /// Applies a patch read with [Self::read_patch] to `tensor`.
+    pub(crate) fn apply_patch(&self, patch: &LoraPatch, tensor: &mut ggml::Tensor) {
         let must_scale = self.scaling != 1.0;
         // Calculate the size of the patch context via the following steps:
-        // 1. Calculate the size of the two `a` and `b` tensors
-        // 2. Calculate the size of the original tensor
-        // 3. Calculate the  size of the `ba` and tensors. It has the same dimensions as the original tensor, but is of the element type of the `a` or `b` tensor e.g. fp16
-        let ba_size = ggml::format::tensor_size(a_info.element_type, info.dims().iter().product());
-        let mut patch_context_size = a_info.calc_absolute_size(false)
-            + b_info.calc_absolute_size(false)
-            + info.calc_absolute_size(false)
-            + ba_size;
-
-        // 3b. (Optional) If we need to scale the `ba` tensor, we need to allocate for a second `ba` and the `scaled` tensors which will be crated as an `f32` tensor.
+        // 1. Calculate the size of the original tensor
+        // 2. Calculate the  size of the `ba` and tensors. It has the same dimensions as the original tensor, but is of the element type of the `a` or `b` tensor e.g. fp16
+        let n_elements = tensor.nelements();
+        let ba_size = ggml::format::tensor_size(patch.a.get_type(), n_elements);
+        let mut patch_context_size =
+            ggml::format::tensor_size(tensor.get_type(), n_elements) + ba_size;
+
+        // 2b. (Optional) If we need to scale the `ba` tensor, we need to allocate for a second `ba` and the `scaled` tensors which will be crated as an `f32` tensor.
         if must_scale {
-            let scaled_size =
-                ggml::format::tensor_size(ggml::ElementType::F32, info.dims().iter().product());
+            let scaled_size = ggml::format::tensor_size(ggml::ElementType::F32, n_elements);
             patch_context_size += scaled_size + ba_size;
         }
 
-        // 4. Add 5% as ggml overhead (I dont know why this is needed but the calculation is always a few 100-1000 bytes off)
+        // 3. Add 5% as ggml overhead (I dont know why this is needed but the calculation is always a few 100-1000 bytes off)
         patch_context_size = patch_context_size + (patch_context_size / 20);
 
         // Create a temporary context for the patching operations
         // TODO: test if GPU can be enabled (make it configurable)
         let patch_context = ggml::Context::new_with_allocate(patch_context_size);
-        let mut patch_file = FileContext::new(&patch_context, &mut self.file, &self.path);
-
-        // Load the A and B tensors
-        let a = patch_file.get_tensor(&a_info)?;
-        let b = patch_file.get_tensor(&b_info)?;
 
         //Build a ggml context and apply the patch
 
         let mut gf = patch_context.create_compute_graph();
 
         // LoRA formula: w = w + ba*s
-        let mut ba = patch_context.op_mul_mat(&a, &b);
+        let mut ba = patch_context.op_mul_mat(&patch.a, &patch.b);
         if must_scale {
             let scaling_tensor = patch_context.new_f32(self.scaling);
             ba = patch_context.op_scale(&ba, &scaling_tensor);
@@ -137,8 +251,6 @@ impl LoraAdapter {
         unsafe {
             std::ptr::copy_nonoverlapping(output.data(), tensor.data(), tensor.nbytes());
         }
-
-        Ok(())
     }
 
     fn get_info(&self, name: &str) -> Result<TensorLoadInfo, LoadError> {
@@ -151,3 +263,26 @@ impl LoraAdapter {
             })
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lora_adapter_config_from_str() {
+        let config: LoraAdapterConfig = "adapter.bin".parse().unwrap();
+        assert_eq!(config, LoraAdapterConfig::new("adapter.bin", 1.0));
+
+        let config: LoraAdapterConfig = "adapter.bin:0.5".parse().unwrap();
+        assert_eq!(config, LoraAdapterConfig::new("adapter.bin", 0.5));
+
+        let config: LoraAdapterConfig = "C:\\adapters\\adapter.bin".parse().unwrap();
+        assert_eq!(
+            config,
+            LoraAdapterConfig::new("C:\\adapters\\adapter.bin", 1.0)
+        );
+
+        let config: LoraAdapterConfig = "C:\\adapter.bin:-1".parse().unwrap();
+        assert_eq!(config, LoraAdapterConfig::new("C:\\adapter.bin", -1.0));
+    }
+}
@@ -0,0 +1,182 @@
+//! Implements estimating the memory needed to load and run a model, before loading it.
+
+use std::{collections::HashMap, path::Path};
+
+use ggml::format::TensorLoadInfo;
+use sysinfo::{System, SystemExt};
+
+use crate::{
+    inference_session::{kv_memory_size, SCRATCH_SIZE},
+    DescribeHyperparameters, InferenceSessionConfig, KnownModel, LoadError, ModelFile,
+    ModelHyperparameters, ModelParameters, TokenizerSource,
+};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+/// An estimate of the memory needed to load a model and run inference with it, in bytes.
+/// Returned by [estimate_memory].
+pub struct MemoryEstimate {
+    /// The weights of the model. If the model is memory-mapped, the weights are read
+    /// from the file as they are needed, and can be evicted by the operating system.
+    pub weights: usize,
+    /// The key/value memory of one [InferenceSession](crate::InferenceSession) with the
+    /// given context size, at the default precision.
+    pub kv_cache: usize,
+    /// The intermediate results of evaluating a batch of tokens.
+    pub scratch: usize,
+}
+impl MemoryEstimate {
+    /// The total memory needed.
+    pub fn total(&self) -> usize {
+        self.weights
+            .saturating_add(self.kv_cache)
+            .saturating_add(self.scratch)
+    }
+
+    fn new(
+        hyperparameters: &ModelHyperparameters,
+        weights: usize,
+        n_ctx: usize,
+        n_batch: usize,
+    ) -> Self {
+        let ModelHyperparameters {
+            n_embd,
+            n_layer,
+            n_head,
+            n_vocab,
+            ..
+        } = *hyperparameters;
+
+        // The scratch buffers are allocated up-front, but only the parts that are used
+        // take up memory. The largest intermediate results of each token are its attention
+        // scores, its feed-forward activations (a few times `n_embd`) and its logits, and
+        // there are two scratch buffers. The hyperparameters may come from an untrusted
+        // model, so the arithmetic saturates rather than overflows.
+        let per_token = n_head
+            .saturating_mul(n_ctx)
+            .saturating_add(n_embd.saturating_mul(16))
+            .saturating_add(n_vocab);
+        let scratch = (2 * n_batch)
+            .saturating_mul(per_token)
+            .saturating_mul(std::mem::size_of::<f32>())
+            .min(2 * SCRATCH_SIZE)
+            + ggml::graph_overhead();
+
+        Self {
+            weights,
+            kv_cache: kv_memory_size(&InferenceSessionConfig::default(), n_ctx, n_layer, n_embd),
+            scratch,
+        }
+    }
+}
+
+/// Estimates the memory needed to load the model at `path` and run inference with
+/// a context of `n_ctx` tokens, feeding `n_batch` tokens at once.
+///
+/// Only the header of the model is read, so this is cheap even for large models.
+pub fn estimate_memory<M: KnownModel>(
+    path: &Path,
+    n_ctx: usize,
+    n_batch: usize,
+) -> Result<MemoryEstimate, LoadError> {
+    let model_file = ModelFile::<M::Hyperparameters>::open(path, TokenizerSource::Embedded)?;
+
+    let weights = model_file
+        .tensors
+        .values()
+        .map(|tensor| tensor.calc_absolute_size(false))
+        .sum();
+    Ok(MemoryEstimate::new(
+        &model_file.hyperparameters.describe(),
+        weights,
+        n_ctx,
+        n_batch,
+    ))
+}
+
+/// Checks that loading the model at `path` with weights of `weights` bytes (zero if they
+/// are memory-mapped) and creating a session with a context of `n_ctx` tokens fits in
+/// the memory that is currently available.
+pub(crate) fn check_available_memory(
+    path: &Path,
+    hyperparameters: &impl DescribeHyperparameters,
+    weights: usize,
+    n_ctx: usize,
+) -> Result<(), LoadError> {
+    let Some(available) = available_memory() else {
+        return Ok(());
+    };
+
+    let estimate = MemoryEstimate::new(
+        &hyperparameters.describe(),
+        weights,
+        n_ctx,
+        InferenceSessionConfig::default().n_batch,
+    );
+    let required = estimate.total();
+    if required > available {
+        return Err(LoadError::InsufficientMemory {
+            path: path.to_owned(),
+            required,
+            available,
+        });
+    }
+    Ok(())
+}
+
+/// The size of the weights of the layers that `params` offloads to the GPU, which do
+/// not take up main memory once they are loaded.
+pub(crate) fn offloaded_weights(
+    tensors: &HashMap<String, TensorLoadInfo>,
+    params: &ModelParameters,
+) -> usize {
+    if !params.use_gpu {
+        return 0;
+    }
+
+    tensors
+        .iter()
+        .filter(|(name, _)| layer_index(name).map_or(false, |il| params.should_offload(il)))
+        .map(|(_, tensor)| tensor.calc_absolute_size(false))
+        .fold(0, usize::saturating_add)
+}
+
+/// The index of the layer the tensor `name` belongs to, if any. The architectures
+/// prefix the tensors of each layer with its index, e.g. `layers.3.`, `transformer.h.3.`
+/// or `model/h3/`.
+fn layer_index(name: &str) -> Option<usize> {
+    name.split(['.', '/'])
+        .find_map(|part| part.strip_prefix('h').unwrap_or(part).parse().ok())
+}
+
+/// The memory that is available to this process, if it can be determined on this platform.
+fn available_memory() -> Option<usize> {
+    if !System::IS_SUPPORTED {
+        return None;
+    }
+
+    let mut system = System::new();
+    system.refresh_memory();
+    match system.available_memory() {
+        0 => None,
+        available => usize::try_from(available).ok(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_layer_index() {
+        assert_eq!(layer_index("layers.3.attention.wq.weight"), Some(3));
+        assert_eq!(layer_index("transformer.h.12.attn.q_proj.weight"), Some(12));
+        assert_eq!(
+            layer_index("gpt_neox.layers.0.mlp.dense_h_to_4h.weight"),
+            Some(0)
+        );
+        assert_eq!(layer_index("model/h7/attn/c_attn/w"), Some(7));
+        assert_eq!(layer_index("tok_embeddings.weight"), None);
+        assert_eq!(layer_index("model/wte"), None);
+        assert_eq!(layer_index("transformer.ln_f.weight"), None);
+    }
+}
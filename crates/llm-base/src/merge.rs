@@ -0,0 +1,425 @@
+//! Implements merging of the weights of multiple models with the same architecture.
+
+use crate::{
+    model::HyperparametersWriteError, Hyperparameters, KnownModel, LoadError, Loader, Tokenizer,
+};
+use ggml::format::{
+    can_swap_byte_order, swap_byte_order, ByteOrder, SaveError, SaveHandler, TensorLoadInfo,
+    TensorSaveInfo,
+};
+use half::f16;
+use std::{
+    collections::HashMap,
+    io::{BufRead, Seek, Write},
+    path::PathBuf,
+};
+use thiserror::Error;
+
+#[derive(Debug, Clone, PartialEq)]
+/// How the weights of the models should be combined.
+pub enum MergeMethod {
+    /// A weighted average of the tensors of all of the models.
+    ///
+    /// The weights are normalized so that they sum to 1.
+    Linear {
+        /// The weight of each model, in the same order as the models.
+        /// If empty, all models are weighted equally.
+        weights: Vec<f32>,
+    },
+    /// Spherical linear interpolation between the tensors of exactly two models.
+    ///
+    /// This interpolates along the arc between the two tensors instead of the line
+    /// between them, which better preserves the magnitude of the weights.
+    Slerp {
+        /// The interpolation factor; `0.0` returns the first model, and `1.0` returns the second.
+        t: f32,
+    },
+}
+
+#[derive(Clone, Debug)]
+/// Progress of merging.
+pub enum MergeProgress<'a> {
+    /// The models have been loaded.
+    ModelsLoaded {
+        /// The number of models that were loaded.
+        count: usize,
+    },
+    /// A tensor has been merged.
+    TensorMerged {
+        /// Name of the tensor.
+        name: &'a str,
+        /// Type of the tensor.
+        element_type: ggml::Type,
+        /// Number of elements in the tensor.
+        n_elements: usize,
+    },
+    /// The models have been merged.
+    Finished {
+        /// The number of tensors that were merged.
+        n_tensors: usize,
+    },
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered during the merging process.
+pub enum MergeError {
+    #[error("could not load model")]
+    /// There was an error while attempting to load one of the models.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    /// An invariant was broken.
+    #[error("invariant broken: {invariant}")]
+    InvariantBroken {
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    /// The merge method cannot be used with the given models.
+    #[error("invalid merge: {reason}")]
+    InvalidMerge {
+        /// Why the merge is invalid.
+        reason: String,
+    },
+    /// The tensors of the models do not match, so they cannot be merged.
+    #[error("the tensor `{tensor_name}` is incompatible between models: {reason}")]
+    IncompatibleTensor {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// How the tensor differs between models.
+        reason: String,
+    },
+    /// Only `f32` and `f16` tensors can be merged.
+    #[error("unsupported element type {element_type:?} for tensor `{tensor_name}`")]
+    UnsupportedElementType {
+        /// The name of the tensor.
+        tensor_name: String,
+        /// The element type.
+        element_type: ggml::Type,
+    },
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// An attempt was made to save a model with a container type that does not
+    /// support vocabulary scoring, despite the model having a scored vocabulary.
+    #[error("container type does not support vocabulary scoring")]
+    VocabularyScoringNotSupported,
+}
+impl MergeError {
+    fn from_format_error(value: SaveError<MergeError>) -> Self {
+        match value {
+            SaveError::Io(io) => MergeError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => MergeError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => MergeError::InvariantBroken { invariant },
+            SaveError::VocabularyScoringNotSupported => MergeError::VocabularyScoringNotSupported,
+        }
+    }
+}
+
+/// Merges the weights of several models with the same architecture and shape into a new model.
+///
+/// The hyperparameters and vocabulary of the merged model are taken from the first model,
+/// which uses `tokenizer`. Only unquantized (`f32` or `f16`) models can be merged; the merged
+/// model can be quantized afterwards.
+pub fn merge<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    readers: &mut [R],
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    method: &MergeMethod,
+    save_container_type: ggml::format::SaveContainerType,
+    progress_callback: impl Fn(MergeProgress),
+) -> Result<(), MergeError> {
+    let weights = match method {
+        _ if readers.len() < 2 => {
+            return Err(MergeError::InvalidMerge {
+                reason: "at least two models are required".to_string(),
+            })
+        }
+        MergeMethod::Linear { weights } if weights.is_empty() => {
+            vec![1.0 / readers.len() as f32; readers.len()]
+        }
+        MergeMethod::Linear { weights } => {
+            if weights.len() != readers.len() {
+                return Err(MergeError::InvalidMerge {
+                    reason: format!(
+                        "{} weights were provided for {} models",
+                        weights.len(),
+                        readers.len()
+                    ),
+                });
+            }
+            let sum: f32 = weights.iter().sum();
+            if sum == 0.0 {
+                return Err(MergeError::InvalidMerge {
+                    reason: "the weights sum to zero".to_string(),
+                });
+            }
+            weights.iter().map(|w| w / sum).collect()
+        }
+        MergeMethod::Slerp { .. } if readers.len() != 2 => {
+            return Err(MergeError::InvalidMerge {
+                reason: "SLERP requires exactly two models".to_string(),
+            })
+        }
+        MergeMethod::Slerp { .. } => vec![],
+    };
+
+    // Load the models. Only the first model's vocabulary is kept.
+    let mut loaders = vec![];
+    let mut tokenizer = Some(tokenizer);
+    for reader in readers.iter_mut() {
+        let mut loader = Loader::<M::Hyperparameters, _>::new(
+            tokenizer.take().unwrap_or_else(Tokenizer::empty_embedded),
+            |_| {},
+        );
+        ggml::format::load(reader, &mut loader)
+            .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+        loaders.push(loader);
+    }
+    progress_callback(MergeProgress::ModelsLoaded {
+        count: loaders.len(),
+    });
+
+    // Check that all of the models have the same tensors.
+    let (first, rest) = loaders.split_first().unwrap();
+    for (name, info) in &first.tensors {
+        for other in rest {
+            let Some(other) = other.tensors.get(name) else {
+                return Err(MergeError::IncompatibleTensor {
+                    tensor_name: name.clone(),
+                    reason: "it is missing from one of the models".to_string(),
+                });
+            };
+            if other.dims() != info.dims() {
+                return Err(MergeError::IncompatibleTensor {
+                    tensor_name: name.clone(),
+                    reason: format!(
+                        "its dimensions are {:?} and {:?}",
+                        info.dims(),
+                        other.dims()
+                    ),
+                });
+            }
+        }
+    }
+    if let Some(name) = rest
+        .iter()
+        .flat_map(|l| l.tensors.keys())
+        .find(|name| !first.tensors.contains_key(*name))
+    {
+        return Err(MergeError::IncompatibleTensor {
+            tensor_name: name.clone(),
+            reason: "it is missing from the first model".to_string(),
+        });
+    }
+
+    let tensors: Vec<_> = loaders.iter().map(|l| &l.tensors).collect();
+    let vocabulary = match &first.tokenizer {
+        Tokenizer::Embedded(v) => v.iter().collect::<Vec<_>>(),
+        Tokenizer::HuggingFace(_) => vec![],
+    };
+    let tensor_names = first.tensors.keys().cloned().collect::<Vec<_>>();
+
+    let mut saver = MergeSaver {
+        method,
+        weights,
+        hyperparameters: &first.hyperparameters,
+        tensors,
+        readers,
+        progress_callback: &progress_callback,
+    };
+    ggml::format::save(
+        writer,
+        &mut saver,
+        save_container_type,
+        &vocabulary,
+        &tensor_names,
+    )
+    .map_err(MergeError::from_format_error)?;
+
+    progress_callback(MergeProgress::Finished {
+        n_tensors: tensor_names.len(),
+    });
+
+    Ok(())
+}
+
+struct MergeSaver<'a, F: Fn(MergeProgress), H: Hyperparameters, R: BufRead + Seek> {
+    method: &'a MergeMethod,
+    weights: Vec<f32>,
+    hyperparameters: &'a H,
+    tensors: Vec<&'a HashMap<String, TensorLoadInfo>>,
+    readers: &'a mut [R],
+    progress_callback: &'a F,
+}
+impl<F: Fn(MergeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<MergeError>
+    for MergeSaver<'_, F, H, R>
+{
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), MergeError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(MergeError::HyperparametersWriteError)?;
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, MergeError> {
+        let mut values = vec![];
+        for (tensors, reader) in self.tensors.iter().zip(self.readers.iter_mut()) {
+            let tensor = tensors.get(tensor_name).expect(
+                "tensor not found; should be impossible due to handler being populated from loader",
+            );
+            values.push(read_f32(tensor, reader)?);
+        }
+
+        let merged = match self.method {
+            MergeMethod::Linear { .. } => {
+                let mut merged = vec![0.0; values[0].len()];
+                for (value, weight) in values.iter().zip(&self.weights) {
+                    for (m, v) in merged.iter_mut().zip(value) {
+                        *m += v * weight;
+                    }
+                }
+                merged
+            }
+            MergeMethod::Slerp { t } => slerp(&values[0], &values[1], *t),
+        };
+
+        // The merged tensor keeps the element type of the first model.
+        let tensor = &self.tensors[0][tensor_name];
+        let data = match tensor.element_type {
+            ggml::Type::F32 => merged.iter().flat_map(|v| v.to_le_bytes()).collect(),
+            ggml::Type::F16 => merged
+                .iter()
+                .flat_map(|v| f16::from_f32(*v).to_bits().to_le_bytes())
+                .collect(),
+            _ => unreachable!("element type was checked when the tensor was read"),
+        };
+
+        (self.progress_callback)(MergeProgress::TensorMerged {
+            name: tensor_name,
+            element_type: tensor.element_type,
+            n_elements: tensor.n_elements,
+        });
+
+        Ok(TensorSaveInfo {
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            element_type: tensor.element_type,
+            data,
+        })
+    }
+}
+
+/// Reads the data of `tensor` from `reader`, converting it to `f32`.
+fn read_f32<R: BufRead + Seek>(
+    tensor: &TensorLoadInfo,
+    reader: &mut R,
+) -> Result<Vec<f32>, MergeError> {
+    let mut raw_data = tensor.read_data(reader)?;
+    if tensor.byte_order != ByteOrder::Little && can_swap_byte_order(tensor.element_type) {
+        swap_byte_order(tensor.element_type, &mut raw_data);
+    }
+    Ok(match tensor.element_type {
+        ggml::Type::F32 => raw_data
+            .chunks_exact(4)
+            .map(|chunk| f32::from_le_bytes(chunk.try_into().unwrap()))
+            .collect(),
+        ggml::Type::F16 => raw_data
+            .chunks_exact(2)
+            .map(|chunk| f16::from_bits(u16::from_le_bytes(chunk.try_into().unwrap())).to_f32())
+            .collect(),
+        element_type => {
+            return Err(MergeError::UnsupportedElementType {
+                tensor_name: tensor.name.clone(),
+                element_type,
+            })
+        }
+    })
+}
+
+/// Spherically interpolates between `a` and `b` by `t`, treating each as a single vector.
+///
+/// Falls back to linear interpolation if the vectors are close to parallel.
+fn slerp(a: &[f32], b: &[f32], t: f32) -> Vec<f32> {
+    fn norm(v: &[f32]) -> f64 {
+        v.iter().map(|x| (*x as f64).powi(2)).sum::<f64>().sqrt()
+    }
+
+    let (norm_a, norm_b) = (norm(a), norm(b));
+    let dot = if norm_a > 0.0 && norm_b > 0.0 {
+        let dot: f64 = a.iter().zip(b).map(|(x, y)| *x as f64 * *y as f64).sum();
+        (dot / (norm_a * norm_b)).clamp(-1.0, 1.0)
+    } else {
+        1.0
+    };
+
+    let t = t as f64;
+    let (scale_a, scale_b) = if dot.abs() > 0.9995 {
+        (1.0 - t, t)
+    } else {
+        let omega = dot.acos();
+        let sin_omega = omega.sin();
+        (
+            ((1.0 - t) * omega).sin() / sin_omega,
+            (t * omega).sin() / sin_omega,
+        )
+    };
+
+    a.iter()
+        .zip(b)
+        .map(|(x, y)| (scale_a * *x as f64 + scale_b * *y as f64) as f32)
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_slerp_endpoints() {
+        let a = [1.0, 0.0];
+        let b = [0.0, 1.0];
+        assert_eq!(slerp(&a, &b, 0.0), vec![1.0, 0.0]);
+
+        let halfway = slerp(&a, &b, 0.5);
+        let expected = std::f32::consts::FRAC_1_SQRT_2;
+        assert!((halfway[0] - expected).abs() < 1e-6);
+        assert!((halfway[1] - expected).abs() < 1e-6);
+    }
+
+    #[test]
+    fn test_slerp_parallel_falls_back_to_lerp() {
+        assert_eq!(slerp(&[2.0, 2.0], &[4.0, 4.0], 0.5), vec![3.0, 3.0]);
+    }
+
+    #[test]
+    fn test_read_f32_big_endian() {
+        let tensor = |element_type, byte_order| TensorLoadInfo {
+            name: "tensor".to_string(),
+            n_dims: 1,
+            dims: [2, 1],
+            n_elements: 2,
+            element_type,
+            start_offset: 0,
+            byte_order,
+        };
+        let values = [1.5f32, -2.0];
+
+        let f32_data: Vec<u8> = values.iter().flat_map(|v| v.to_be_bytes()).collect();
+        let f32_tensor = tensor(ggml::Type::F32, ByteOrder::Big);
+        let read = read_f32(&f32_tensor, &mut std::io::Cursor::new(f32_data)).unwrap();
+        assert_eq!(read, values);
+
+        let f16_data: Vec<u8> = values
+            .iter()
+            .flat_map(|v| f16::from_f32(*v).to_be_bytes())
+            .collect();
+        let f16_tensor = tensor(ggml::Type::F16, ByteOrder::Big);
+        let read = read_f32(&f16_tensor, &mut std::io::Cursor::new(f16_data)).unwrap();
+        assert_eq!(read, values);
+    }
+}
@@ -1,7 +1,37 @@
-use ggml::Tensor;
+use ggml::{Context, Tensor};
 
 use crate::{InferenceSession, OutputRequest};
 
+#[derive(Debug, Clone, Copy, PartialEq)]
+/// How the attention scores of a model are biased by the relative positions of tokens.
+pub enum PositionalBias {
+    /// No bias is applied; positions are encoded elsewhere (e.g. with RoPE or learned
+    /// position embeddings).
+    None,
+    /// [ALiBi](https://arxiv.org/abs/2108.12409): each head penalizes attention to distant
+    /// tokens linearly, with a per-head slope derived from the number of heads and
+    /// `bias_max`.
+    ///
+    /// For a power-of-two number of heads, the slopes are a geometric sequence from
+    /// `2^(-bias_max / n_head)` to `2^-bias_max`. Otherwise, the remaining heads are
+    /// interleaved with slopes from the sequence for twice the closest power of two, as
+    /// in the reference implementation.
+    Alibi {
+        /// The maximum bias, which determines the range of the slopes.
+        bias_max: f32,
+    },
+}
+impl PositionalBias {
+    /// Applies this bias to the (scaled) attention scores `kq` of `n_head` heads, for a
+    /// session that has already processed `n_past` tokens.
+    pub fn apply(&self, ctx: &Context, kq: &Tensor, n_past: usize, n_head: usize) -> Tensor {
+        match *self {
+            PositionalBias::None => kq.share(),
+            PositionalBias::Alibi { bias_max } => ctx.op_alibi(kq, n_past, n_head, bias_max),
+        }
+    }
+}
+
 /// Return result for just the last token
 pub fn read_last_token(
     session: &mut InferenceSession,
@@ -45,15 +75,22 @@ pub fn extract_embeddings(
     n: usize,
 ) {
     // Extract embeddings
+    if output_request.embeddings.is_none() && output_request.all_embeddings.is_none() {
+        return;
+    }
+
+    // Create a new vector to hold all embeddings
+    let mut all_embeddings = vec![0.0; n_embd * n];
+    // SAFETY: Same rationale as for the "Extract logits" section applies.
+    assert_eq!(embeddings_tensor.nelements(), n_embd * n);
+    unsafe {
+        embeddings_tensor.read_data(0, bytemuck::cast_slice_mut(&mut all_embeddings));
+    }
     if let Some(embeddings) = &mut output_request.embeddings {
-        embeddings.resize(n_embd, 0.0);
-        // Create a new vector to hold all embeddings
-        let mut all_embeddings = vec![0.0; n_embd * n];
-        // SAFETY: Same rationale as for the "Extract logits" section applies.
-        assert_eq!(embeddings_tensor.nelements(), n_embd * n);
-        unsafe {
-            embeddings_tensor.read_data(0, bytemuck::cast_slice_mut(&mut all_embeddings));
-        }
-        embeddings.copy_from_slice(&all_embeddings[n_embd * (n - 1)..]);
+        embeddings.clear();
+        embeddings.extend_from_slice(&all_embeddings[n_embd * (n - 1)..]);
+    }
+    if let Some(embeddings) = &mut output_request.all_embeddings {
+        *embeddings = all_embeddings;
     }
 }
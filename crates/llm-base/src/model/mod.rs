@@ -1,20 +1,29 @@
 //! Large language model traits and types
 
 use std::{
+    collections::HashMap,
     error::Error,
-    fmt::Debug,
+    fmt::{Debug, Display},
     io::{BufRead, Write},
     path::{Path, PathBuf},
-    sync::Arc,
+    sync::{Arc, Mutex},
 };
 
 use ggml::accelerator::Backend;
 use regex::Regex;
 use thiserror::Error;
+#[cfg(unix)]
+use tracing::log;
 
 use crate::{
-    loader::TensorLoader, tokenizer::TokenId, FileType, InferenceSession, InferenceSessionConfig,
-    LoadError, LoadProgress, Tokenizer, TokenizerSource,
+    convert::{ConvertError, HfConfig},
+    embeddings::{EmbeddingError, EmbeddingOptions, Embeddings},
+    gguf_metadata::GgufHyperparameters,
+    loader::{DeferredReads, TensorLoader},
+    tokenizer::TokenId,
+    FileType, InferenceError, InferenceSession, InferenceSessionConfig, LoadError, LoadProgress,
+    LoraAdapter, LoraAdapterConfig, Prompt, RerankError, RerankTemplate, TelemetrySink,
+    TensorNameMapping, TestModel, Tokenizer, TokenizerSource,
 };
 
 /// Common functions for model evaluation
@@ -24,7 +33,7 @@ pub mod common;
 /// of [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning)).
 pub trait KnownModel: Send + Sync {
     /// Hyperparameters for the model.
-    type Hyperparameters: Hyperparameters;
+    type Hyperparameters: Hyperparameters + DescribeHyperparameters;
 
     /// Load this model from the `path` and configure it per the `params`. The status
     /// of the loading process will be reported through `load_progress_callback`. This
@@ -41,6 +50,29 @@ pub trait KnownModel: Send + Sync {
         crate::load(path, tokenizer_source, params, load_progress_callback)
     }
 
+    /// Load this model from `bytes`, which hold the contents of a model file, and
+    /// configure it per the `params`. This allows embedding small models with
+    /// `include_bytes!`, or using models received over the network without writing
+    /// them to disk. The weights are copied out of `bytes`. This is a helper function
+    /// on top of [llm_base::load_from_reader](crate::load_from_reader).
+    fn load_from_bytes(
+        bytes: &[u8],
+        tokenizer_source: TokenizerSource,
+        params: ModelParameters,
+        load_progress_callback: impl FnMut(LoadProgress),
+    ) -> Result<Self, LoadError>
+    where
+        Self: Sized,
+    {
+        crate::load_from_reader(
+            std::io::Cursor::new(bytes),
+            Path::new("<bytes>"),
+            tokenizer_source,
+            params,
+            load_progress_callback,
+        )
+    }
+
     /// Creates a new model from the provided [ModelParameters] hyperparameters.
     /// This function is called by the [load](crate::loader::load) function.
     fn new<E: Error>(
@@ -59,12 +91,15 @@ pub trait KnownModel: Send + Sync {
     /// to generate output by evaluating the `input_tokens`.
     /// The [OutputRequest] is used to specify additional data to fetch from the
     /// model.
+    ///
+    /// Fails with [InferenceError::WeightsUnavailable] if the model was loaded lazily and
+    /// its weights cannot be read.
     fn evaluate(
         &self,
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    );
+    ) -> Result<(), InferenceError>;
 
     /// Get the hyperparameters for this model.
     fn hyperparameters(&self) -> &Self::Hyperparameters;
@@ -72,6 +107,9 @@ pub trait KnownModel: Send + Sync {
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
+    /// Get the [ModelContext] holding the weights of this model.
+    fn context(&self) -> &ModelContext;
+
     /// Get the context size (configured with [ModelParameters::context_size]) used by
     /// this model.
     fn context_size(&self) -> usize;
@@ -88,11 +126,55 @@ pub trait KnownModel: Send + Sync {
     /// Get the list of regexes to use to determine if a tensor in this model should not be quantized.
     fn skip_quantize_tensors() -> Vec<Regex>;
 
+    /// Get the layout of a tiny model of this architecture with a vocabulary of `n_vocab`
+    /// tokens, which [write_test_model](crate::write_test_model) fills with random weights.
+    ///
+    /// Architectures that do not provide one return `None`, the default, and cannot be
+    /// written as test models.
+    fn test_model(_n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        None
+    }
+
+    /// Reads the hyperparameters of a Hugging Face checkpoint of this architecture from its
+    /// `config.json`, for [convert_hf](crate::convert_hf). Architectures that cannot be
+    /// converted return [ConvertError::UnsupportedArchitecture].
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, ConvertError> {
+        let _ = (config, file_type);
+        Err(ConvertError::UnsupportedArchitecture)
+    }
+
+    /// Get the rules that rename the tensors of a Hugging Face checkpoint of this
+    /// architecture to the names it loads. Tensors that no rule matches are not converted.
+    fn hf_tensor_names() -> TensorNameMapping {
+        TensorNameMapping::default()
+    }
+
+    /// Rearranges the data of the tensor `name` (as this architecture names it) of a Hugging
+    /// Face checkpoint, if the checkpoint stores it differently. `data` is row-major.
+    fn hf_transform_tensor(
+        name: &str,
+        shape: &[usize],
+        data: &mut [f32],
+        hyperparameters: &Self::Hyperparameters,
+    ) {
+        let _ = (name, shape, data, hyperparameters);
+    }
+
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool {
         // Assume we can't delete unless otherwise specified
         false
     }
+
+    /// Returns whether the model can evaluate the tokens of several independent sequences
+    /// in one pass, using the `position`, `n_sequences` and `attention_mask` of the
+    /// graph builder's context.
+    fn supports_sequence_batches(&self) -> bool {
+        false
+    }
 }
 
 /// A type-erased model to allow for interacting with a model without knowing
@@ -105,16 +187,22 @@ pub trait Model: Send + Sync {
     /// to generate output by evaluating the `input_tokens`.
     /// The [OutputRequest] is used to specify additional data to fetch from the
     /// model.
+    ///
+    /// Fails with [InferenceError::WeightsUnavailable] if the model was loaded lazily and
+    /// its weights cannot be read.
     fn evaluate(
         &self,
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    );
+    ) -> Result<(), InferenceError>;
 
     /// Get the tokenizer for this model.
     fn tokenizer(&self) -> &Tokenizer;
 
+    /// Get an architecture-agnostic view of the hyperparameters of this model.
+    fn hyperparameters(&self) -> ModelHyperparameters;
+
     /// Get the context size (configured with [ModelParameters::context_size]) used by
     /// this model.
     fn context_size(&self) -> usize;
@@ -127,8 +215,73 @@ pub trait Model: Send + Sync {
 
     /// Returns whether the model supports deleting tokens.
     fn supports_rewind(&self) -> bool;
+
+    /// Returns whether the model can evaluate the tokens of several independent sequences
+    /// in one pass.
+    fn supports_sequence_batches(&self) -> bool;
+
+    /// Returns the log-likelihood of each token of `text` under this model, given the
+    /// tokens before it, using a new session with the given `config`.
+    ///
+    /// The text is tokenized with a beginning-of-sentence token, which is not scored. If
+    /// the tokenizer does not add one, the first token of the text is not scored instead.
+    /// See [InferenceSession::score] to score tokens after an existing context.
+    fn score(&self, text: &str, config: InferenceSessionConfig)
+        -> Result<Vec<f32>, InferenceError>;
+
+    /// Returns how relevant each of `documents` is to `query`, from 0 to 1, in the order of
+    /// the documents; sort by it to rerank them.
+    ///
+    /// Each document is presented to the model with the query as described by `template`,
+    /// and its relevance is the probability the model gives to answering that it is
+    /// relevant. Each document is evaluated in a new session with the given `config`, or,
+    /// if the model supports rewinding, after the query in a shared session.
+    fn rerank(
+        &self,
+        query: &str,
+        documents: &[&str],
+        template: &RerankTemplate,
+        config: InferenceSessionConfig,
+    ) -> Result<Vec<f32>, RerankError>;
+
+    /// Returns the embeddings of each of `texts`, pooled into one vector per text as
+    /// described by `options`.
+    ///
+    /// A single session with the given `config` is reused for all of the texts. If the
+    /// model [supports sequence batches](Model::supports_sequence_batches), the texts are
+    /// evaluated together, padded to the longest of them, in as few passes as fit in the
+    /// context window; otherwise, each text is evaluated [InferenceSessionConfig::n_batch]
+    /// tokens at a time.
+    fn embed(
+        &self,
+        texts: &[&str],
+        options: &EmbeddingOptions,
+        config: InferenceSessionConfig,
+    ) -> Result<Embeddings, EmbeddingError>;
+
+    /// Applies a [LoRA](https://arxiv.org/abs/2106.09685) adapter on top of the current
+    /// weights of this model. Adapters are stacked in the order they are applied.
+    ///
+    /// The model must have been loaded without mmap and with its weights on the CPU.
+    fn apply_lora_adapter(&mut self, config: &LoraAdapterConfig) -> Result<(), LoadError>;
+
+    /// Reverts a LoRA adapter that was previously applied with the same `config`, either
+    /// at load time or through [Model::apply_lora_adapter].
+    ///
+    /// Note that weights stored in a reduced-precision format may not be restored bit-for-bit.
+    fn remove_lora_adapter(&mut self, config: &LoraAdapterConfig) -> Result<(), LoadError>;
+
+    /// Returns the LoRA adapters that are currently applied to this model, in order of application.
+    fn lora_adapters(&self) -> Vec<LoraAdapterConfig>;
+
+    /// Reads the data of the tensors whose reads were deferred by
+    /// [ModelParameters::lazy_loading], if it has not been read yet.
+    ///
+    /// Evaluating the model does this on first use, but panics if it fails; call this
+    /// first to handle the error instead.
+    fn load_deferred_tensors(&self) -> Result<(), LoadError>;
 }
-impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
+impl<H: Hyperparameters + DescribeHyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
         KnownModel::start_session(self, config)
     }
@@ -138,7 +291,7 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         KnownModel::evaluate(self, session, input_tokens, output_request)
     }
 
@@ -146,6 +299,10 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
         KnownModel::tokenizer(self)
     }
 
+    fn hyperparameters(&self) -> ModelHyperparameters {
+        KnownModel::hyperparameters(self).describe()
+    }
+
     fn context_size(&self) -> usize {
         KnownModel::context_size(self)
     }
@@ -161,6 +318,59 @@ impl<H: Hyperparameters, M: KnownModel<Hyperparameters = H>> Model for M {
     fn supports_rewind(&self) -> bool {
         KnownModel::supports_rewind(self)
     }
+
+    fn supports_sequence_batches(&self) -> bool {
+        KnownModel::supports_sequence_batches(self)
+    }
+
+    fn score(
+        &self,
+        text: &str,
+        config: InferenceSessionConfig,
+    ) -> Result<Vec<f32>, InferenceError> {
+        let tokens = Prompt::from(text).to_tokens(KnownModel::tokenizer(self), true)?;
+        let mut session = KnownModel::start_session(self, config);
+        session.score(self, &tokens)
+    }
+
+    fn rerank(
+        &self,
+        query: &str,
+        documents: &[&str],
+        template: &RerankTemplate,
+        config: InferenceSessionConfig,
+    ) -> Result<Vec<f32>, RerankError> {
+        crate::rerank::rerank(self, query, documents, template, config)
+    }
+
+    fn embed(
+        &self,
+        texts: &[&str],
+        options: &EmbeddingOptions,
+        config: InferenceSessionConfig,
+    ) -> Result<Embeddings, EmbeddingError> {
+        crate::embeddings::embed(self, texts, options, config)
+    }
+
+    fn apply_lora_adapter(&mut self, config: &LoraAdapterConfig) -> Result<(), LoadError> {
+        KnownModel::context(self).apply_lora_adapter(config)
+    }
+
+    fn remove_lora_adapter(&mut self, config: &LoraAdapterConfig) -> Result<(), LoadError> {
+        KnownModel::context(self).remove_lora_adapter(config)
+    }
+
+    fn lora_adapters(&self) -> Vec<LoraAdapterConfig> {
+        KnownModel::context(self)
+            .lora_adapters
+            .lock()
+            .unwrap()
+            .clone()
+    }
+
+    fn load_deferred_tensors(&self) -> Result<(), LoadError> {
+        KnownModel::context(self).read_deferred_tensors()
+    }
 }
 
 /// Implemented by model hyperparameters for interacting with hyperparameters
@@ -180,7 +390,114 @@ pub trait Hyperparameters: Sized + Default + Debug + PartialEq + Eq {
 
     /// Get mutable access to filetype of the model.
     fn file_type_mut(&mut self) -> Option<&mut FileType>;
+
+    /// Get the parameters as the standard GGUF keys of the architecture, without its prefix
+    /// (such as `context_length` for `llama.context_length`), which are written alongside
+    /// the GGML layout so that other tools can read the model.
+    ///
+    /// Architectures that do not provide them return none, the default.
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![]
+    }
+
+    /// Read the parameters from the standard GGUF keys of the architecture, for models that
+    /// do not record them in the GGML layout, such as those written by other tools.
+    ///
+    /// Architectures that cannot read them return an error, the default.
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Err(LoadError::InvariantBroken {
+            path: None,
+            invariant: format!(
+                "the hyperparameters of `{}` models can be read from their standard GGUF keys",
+                metadata.architecture()
+            ),
+        })
+    }
+}
+
+/// Implemented by model hyperparameters to describe them in an architecture-agnostic way.
+pub trait DescribeHyperparameters {
+    /// Describe these hyperparameters.
+    fn describe(&self) -> ModelHyperparameters;
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// An architecture-agnostic view of the hyperparameters of a model, returned by
+/// [Model::hyperparameters].
+pub struct ModelHyperparameters {
+    /// The context size the model was trained with, if it is recorded in the model.
+    pub context_size_trained: Option<usize>,
+    /// Size of the model's embedding layer.
+    pub n_embd: usize,
+    /// Number of layers in the model.
+    pub n_layer: usize,
+    /// Number of attention heads.
+    pub n_head: usize,
+    /// Number of key-value heads. This is equal to `n_head`, unless the model uses
+    /// grouped-query or multi-query attention.
+    pub n_head_kv: usize,
+    /// Size of the model's vocabulary.
+    pub n_vocab: usize,
+    /// The file type of the model, which describes how its weights are quantized.
+    pub file_type: Option<FileType>,
+    /// All of the hyperparameters as they are stored in the model, including those
+    /// that are specific to its architecture, in order.
+    pub metadata: Vec<(String, MetadataValue)>,
+}
+impl ModelHyperparameters {
+    /// Returns the value of the hyperparameter `key` in [metadata](Self::metadata), if the
+    /// model has it.
+    pub fn get(&self, key: &str) -> Option<&MetadataValue> {
+        self.metadata
+            .iter()
+            .find(|(name, _)| name == key)
+            .map(|(_, value)| value)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+/// The value of a hyperparameter in [ModelHyperparameters::metadata].
+pub enum MetadataValue {
+    /// An integer.
+    Integer(i64),
+    /// A floating-point number.
+    Float(f32),
+    /// A boolean.
+    Bool(bool),
+    /// A string.
+    String(String),
 }
+impl From<usize> for MetadataValue {
+    fn from(value: usize) -> Self {
+        Self::Integer(value as i64)
+    }
+}
+impl From<f32> for MetadataValue {
+    fn from(value: f32) -> Self {
+        Self::Float(value)
+    }
+}
+impl From<bool> for MetadataValue {
+    fn from(value: bool) -> Self {
+        Self::Bool(value)
+    }
+}
+impl From<FileType> for MetadataValue {
+    fn from(value: FileType) -> Self {
+        Self::String(value.to_string())
+    }
+}
+impl Display for MetadataValue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Integer(value) => write!(f, "{value}"),
+            Self::Float(value) => write!(f, "{value}"),
+            Self::Bool(value) => write!(f, "{value}"),
+            Self::String(value) => write!(f, "{value}"),
+        }
+    }
+}
+
 #[derive(Error, Debug)]
 /// Reported from functions that write
 pub enum HyperparametersWriteError {
@@ -193,7 +510,12 @@ pub enum HyperparametersWriteError {
 }
 
 /// Parameters for model-wide behaviour.
+///
+/// New fields may be added, so the parameters cannot be created with a struct literal
+/// outside of `llm`; start from [ModelParameters::default] and set the fields that differ,
+/// or load the model with `llm::ModelLoader`, which sets them with its methods.
 #[derive(Debug, Clone)]
+#[non_exhaustive]
 pub struct ModelParameters {
     /// For [GGML formats](ggml::ContainerType) that support it, [mmap](https://en.wikipedia.org/wiki/Mmap)
     /// is the default. Although mmap typically improves performance, setting this value to `false` may
@@ -203,7 +525,9 @@ pub struct ModelParameters {
     /// consumes more resources, but produces more consistent and coherent responses.
     pub context_size: usize,
     /// The [LoRA](https://arxiv.org/abs/2106.09685) adapters to use when loading the model. If `None`, no adapters will be used.
-    pub lora_adapters: Option<Vec<PathBuf>>,
+    ///
+    /// Adapters are applied in order, each with its own [scale](LoraAdapterConfig::scale).
+    pub lora_adapters: Option<Vec<LoraAdapterConfig>>,
     /// Whether to use GPU acceleration when available
     pub use_gpu: bool,
     /// If `use_gpu` is active this defines the number of layers to offload to the gpu. If `None`, all layers will be offloaded.
@@ -212,6 +536,66 @@ pub struct ModelParameters {
     pub rope_overrides: Option<ggml::RoPEOverrides>,
     /// Enables gouped-query attention for Llama-2 70B model
     pub n_gqa: Option<usize>,
+    /// Overrides the maximum bias of [ALiBi](https://arxiv.org/abs/2108.12409), for models
+    /// that use it. If `None`, the value from the model is used.
+    pub alibi_bias_max: Option<f32>,
+    /// Advise the OS to stream the model's memory-mapped weights from disk instead of
+    /// keeping them resident in memory.
+    ///
+    /// The weights are mapped for sequential access, so that the OS reads them ahead of
+    /// their use during each evaluation, and are released after each evaluation so that
+    /// their memory can be reclaimed. These are only hints: there is no fixed window of
+    /// resident layers, and how much of the model stays in memory is up to the OS. This
+    /// lets the OS run models that are larger than the available RAM, at a significant
+    /// cost to speed, instead of keeping the weights cached at the expense of everything
+    /// else. This requires mmap, and is only supported on Unix-like systems; it is ignored
+    /// otherwise.
+    pub stream_weights: bool,
+    /// A GGML file for the same architecture whose tensors replace the same-named tensors of
+    /// the model as it is loaded (e.g. a patched `output.weight`). If `None`, no tensors are replaced.
+    ///
+    /// The replacement tensors must have the same dimensions as the originals, but may be of a
+    /// different element type. Using overrides disables mmap.
+    pub tensor_overrides: Option<PathBuf>,
+    /// Verify the data of each tensor against the checksums stored next to the model (see
+    /// [crate::TensorChecksums]) as it is read, failing with the name of the first
+    /// corrupted tensor. This reads the whole model even if it is memory-mapped, so it is off
+    /// by default. With [Self::lazy_loading], the tensors are verified when they are read.
+    pub verify_checksums: bool,
+    /// Rules that rename the tensors of the model as it is loaded, for conversions that
+    /// name their tensors differently from what the architecture expects. If `None`, the
+    /// tensors keep their names.
+    pub tensor_name_mapping: Option<TensorNameMapping>,
+    /// Skip the tensors that cannot be loaded (such as those with more than two dimensions)
+    /// instead of failing, so that models with extra tensors (e.g. adapters or additional
+    /// heads) can still be used. Each skipped tensor is reported with
+    /// [LoadProgress::TensorSkipped], as is each tensor that the model does not use.
+    pub skip_unknown_tensors: bool,
+    /// Check the model more strictly before any of its weights are mapped or allocated, for
+    /// models from untrusted sources: the tensors' data must not overlap, the vocabulary must
+    /// not be larger than the model's, and the floating-point tensors must only hold finite
+    /// values. This reads the floating-point tensors, so it is off by default.
+    ///
+    /// To also survive a model that crashes the loader, validate it in another process first
+    /// (see `llm::sandbox`).
+    pub strict_validation: bool,
+    /// Skip checking that the model and the key/value memory of a session fit in the
+    /// available memory before loading it (see [crate::estimate_memory]), for when the
+    /// estimate is too pessimistic, e.g. because memory will be freed before it is needed.
+    pub skip_memory_check: bool,
+    /// When the model is not memory-mapped, only allocate its tensors while loading it,
+    /// and read their data when the model is first used, so that loading returns as soon
+    /// as the model's structure has been created.
+    ///
+    /// The data is read when the model is first evaluated, or when
+    /// [Model::load_deferred_tensors] is called. The tensors of layers offloaded to the GPU,
+    /// and those patched by LoRA adapters while loading, are still read while loading, as
+    /// is everything when the model is not read from a file. The file must not change until
+    /// the data has been read.
+    pub lazy_loading: bool,
+    /// Receives events about the loading of the model, and about the sessions started
+    /// from it. If `None`, no events are sent.
+    pub telemetry: Option<Arc<dyn TelemetrySink>>,
 }
 
 impl Default for ModelParameters {
@@ -224,6 +608,16 @@ impl Default for ModelParameters {
             gpu_layers: None,
             rope_overrides: None,
             n_gqa: None,
+            alibi_bias_max: None,
+            stream_weights: false,
+            tensor_overrides: None,
+            verify_checksums: false,
+            tensor_name_mapping: None,
+            skip_unknown_tensors: false,
+            strict_validation: false,
+            skip_memory_check: false,
+            lazy_loading: false,
+            telemetry: None,
         }
     }
 }
@@ -259,10 +653,12 @@ pub struct OutputRequest {
     /// that a given token will be generated based on the tokens that have been
     /// evaluated or generated so far. Output shape is `n_batch * n_vocab`.
     pub all_logits: Option<Vec<f32>>,
-    /// Returns all the embeddings for an evaluation. An embedding is a vector
-    /// that measures the relatedness of text strings. Output shape is
-    /// `n_batch * n_embd`.
+    /// Returns the embedding of the last token of an evaluation. An embedding is a vector
+    /// that measures the relatedness of text strings. Output shape is `n_embd`.
     pub embeddings: Option<Vec<f32>>,
+    /// Returns the embeddings of all of the tokens of an evaluation. Output shape is
+    /// `n_batch * n_embd`.
+    pub all_embeddings: Option<Vec<f32>>,
 }
 
 /// Contains the GGML context for a [`Model`]. Implements `Send` and `Sync`
@@ -271,6 +667,107 @@ pub struct OutputRequest {
 /// modified across threads.
 #[derive(Clone)]
 #[allow(clippy::arc_with_non_send_sync)]
-pub struct ModelContext(pub(crate) Arc<ggml::Context>);
+pub struct ModelContext {
+    pub(crate) context: Arc<ggml::Context>,
+    /// The tensors that were loaded into this context, by name.
+    pub(crate) tensors: Arc<HashMap<String, ggml::Tensor>>,
+    /// The LoRA adapters that have been applied to the tensors, in order.
+    pub(crate) lora_adapters: Arc<Mutex<Vec<LoraAdapterConfig>>>,
+    /// Whether the weights are streamed from disk; see [ModelParameters::stream_weights].
+    pub(crate) stream_weights: bool,
+    /// The reads of tensor data that were deferred until the model is first used; see
+    /// [ModelParameters::lazy_loading].
+    pub(crate) deferred_reads: Arc<Mutex<Option<DeferredReads>>>,
+}
 unsafe impl Send for ModelContext {}
 unsafe impl Sync for ModelContext {}
+impl ModelContext {
+    /// If the weights are being streamed from disk, advises the OS that they are no longer
+    /// needed so that their memory can be reclaimed until the next evaluation.
+    pub(crate) fn release_streamed_weights(&self) {
+        if !self.stream_weights {
+            return;
+        }
+
+        #[cfg(unix)]
+        if let Some(mmap) = self.context.storage().as_mmap() {
+            if let Err(err) = mmap.advise(memmap2::Advice::DontNeed) {
+                log::warn!("Could not release streamed weights: {err}");
+            }
+        }
+    }
+
+    /// Reads the data of the tensors whose reads were deferred, if it has not been read yet.
+    /// The reads are retried on the next call if they fail.
+    pub(crate) fn read_deferred_tensors(&self) -> Result<(), LoadError> {
+        let mut deferred_reads = self.deferred_reads.lock().unwrap();
+        if let Some(reads) = deferred_reads.as_ref() {
+            reads.read()?;
+            *deferred_reads = None;
+        }
+        Ok(())
+    }
+
+    fn apply_lora_adapter(&self, config: &LoraAdapterConfig) -> Result<(), LoadError> {
+        self.read_deferred_tensors()?;
+        self.patch_with_lora_adapter(config, config.scale)?;
+        self.lora_adapters.lock().unwrap().push(config.clone());
+        Ok(())
+    }
+
+    fn remove_lora_adapter(&self, config: &LoraAdapterConfig) -> Result<(), LoadError> {
+        let mut lora_adapters = self.lora_adapters.lock().unwrap();
+        let index = lora_adapters
+            .iter()
+            .rposition(|applied| applied == config)
+            .ok_or_else(|| LoadError::LoraAdapterNotApplied {
+                adapter: config.clone(),
+            })?;
+
+        self.read_deferred_tensors()?;
+        self.patch_with_lora_adapter(config, -config.scale)?;
+        lora_adapters.remove(index);
+        Ok(())
+    }
+
+    fn patch_with_lora_adapter(
+        &self,
+        config: &LoraAdapterConfig,
+        scale: f32,
+    ) -> Result<(), LoadError> {
+        if self.context.storage().as_mmap().is_some() {
+            return Err(LoadError::WeightsNotModifiable {
+                reason: "the model was loaded with mmap".to_string(),
+            });
+        }
+
+        let mut adapter = LoraAdapter::load(&LoraAdapterConfig::new(&config.path, scale))?;
+
+        // Check all of the tensors and read all of the patches before patching any of the
+        // tensors, so that an error does not leave the model in a half-patched state.
+        let mut patches = Vec::with_capacity(adapter.tensors_to_patch.len());
+        for name in adapter.tensors_to_patch.clone() {
+            let Some(tensor) = self.tensors.get(&name) else {
+                return Err(LoadError::UnknownTensor {
+                    tensor_name: name,
+                    path: adapter.path.clone(),
+                });
+            };
+            if tensor.backend() != Backend::Cpu {
+                return Err(LoadError::WeightsNotModifiable {
+                    reason: format!("the tensor `{name}` is not present on the CPU"),
+                });
+            }
+            let patch = adapter
+                .read_patch(&name)?
+                .expect("the adapter patches the tensor");
+            patches.push((tensor, patch));
+        }
+
+        for (tensor, patch) in &patches {
+            adapter.apply_patch(patch, &mut tensor.share());
+        }
+
+        Ok(())
+    }
+}
@@ -0,0 +1,258 @@
+//! Implements [GenerationPreset], a sampling configuration that can be stored in a TOML
+//! or JSON file, or picked by name from the [built-in presets](GenerationPreset::BUILTIN).
+
+use std::{
+    error::Error,
+    path::{Path, PathBuf},
+};
+
+use thiserror::Error;
+
+use crate::{samplers::SamplerConfigurationError, InferenceParameters, InferenceParametersBuilder};
+
+#[derive(Error, Debug)]
+/// Errors encountered while loading a [GenerationPreset].
+pub enum PresetError {
+    #[error("{name:?} is neither a built-in preset nor a preset file")]
+    /// The name is not a built-in preset, and there is no file at that path.
+    NotFound {
+        /// The name or path of the preset.
+        name: String,
+    },
+    #[error("could not read the preset {path:?}")]
+    /// The preset file could not be read.
+    Io {
+        /// The original error.
+        source: std::io::Error,
+        /// The path of the preset.
+        path: PathBuf,
+    },
+    #[error("could not parse the preset {path:?}")]
+    /// The preset file is not a valid TOML or JSON preset.
+    Parse {
+        /// The original error.
+        source: Box<dyn Error + Send + Sync>,
+        /// The path of the preset.
+        path: PathBuf,
+    },
+}
+
+/// A complete sampling configuration: the options of the common samplers, any other
+/// samplers, the order to run them in, token biases and the sequences that end
+/// generation.
+///
+/// Presets are loaded from TOML or JSON files with [Self::load], in which every field is
+/// optional, or picked from the [built-in presets](Self::BUILTIN) with [Self::builtin]:
+///
+/// ```toml
+/// temperature = 0.7
+/// top_p = 0.9
+/// repetition_penalty = 1.1
+/// samplers = ["dry:multiplier=0.8"]
+/// sampler_order = ["top_k", "top_p", "temperature"]
+/// stop_sequences = ["\n\n"]
+/// ```
+///
+/// The options of the common samplers and the token biases are those of an
+/// [InferenceParametersBuilder], and are read from the same fields. They come before
+/// [Self::samplers], and options that are not set keep their defaults.
+#[derive(Debug, Clone, Default, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct GenerationPreset {
+    /// The options of the common samplers and the token biases.
+    #[serde(flatten)]
+    pub parameters: InferenceParametersBuilder,
+    /// Other samplers, as sampler configuration strings such as `mirostat2:tau=5`.
+    pub samplers: Vec<String>,
+    /// The order to run the samplers in, as described in
+    /// [ConfiguredSamplers::with_order](crate::samplers::ConfiguredSamplers::with_order).
+    pub sampler_order: Vec<String>,
+    /// Texts that end generation when they are generated; see
+    /// [StopSequences](crate::StopSequences).
+    pub stop_sequences: Vec<String>,
+}
+impl GenerationPreset {
+    /// The names of the built-in presets, which can be loaded with [Self::builtin]:
+    ///
+    /// - `precise`: a low temperature and a narrow choice of tokens, for factual answers.
+    /// - `creative`: a high temperature and a wide choice of tokens, for stories and
+    ///   brainstorming.
+    /// - `code`: a low temperature and no repetition penalty, as code repeats itself.
+    pub const BUILTIN: [&'static str; 3] = ["precise", "creative", "code"];
+
+    /// Returns the built-in preset called `name`, if there is one.
+    pub fn builtin(name: &str) -> Option<Self> {
+        match name {
+            "precise" => Some(Self::with_parameters(
+                InferenceParametersBuilder::default()
+                    .temperature(0.3)
+                    .top_k(20)
+                    .top_p(0.75)
+                    .repetition_penalty(1.18),
+            )),
+            "creative" => Some(Self::with_parameters(
+                InferenceParametersBuilder::default()
+                    .temperature(1.0)
+                    .top_k(100)
+                    .top_p(0.98)
+                    .min_p(0.05)
+                    .repetition_penalty(1.15),
+            )),
+            "code" => Some(Self::with_parameters(
+                InferenceParametersBuilder::default()
+                    .temperature(0.2)
+                    .top_k(40)
+                    .top_p(0.95)
+                    .repetition_penalty(1.0),
+            )),
+            _ => None,
+        }
+    }
+
+    /// Loads the preset file at `path`, which is read as TOML if its extension is `toml`
+    /// and as JSON otherwise.
+    pub fn load(path: &Path) -> Result<Self, PresetError> {
+        let contents = std::fs::read_to_string(path).map_err(|source| PresetError::Io {
+            source,
+            path: path.to_owned(),
+        })?;
+        let is_toml = path
+            .extension()
+            .map_or(false, |extension| extension.eq_ignore_ascii_case("toml"));
+        let result: Result<Self, Box<dyn Error + Send + Sync>> = if is_toml {
+            toml::from_str(&contents).map_err(|err| err.into())
+        } else {
+            serde_json::from_str(&contents).map_err(|err| err.into())
+        };
+        result.map_err(|source| PresetError::Parse {
+            source,
+            path: path.to_owned(),
+        })
+    }
+
+    /// Returns the built-in preset called `name_or_path`, or loads the preset file at
+    /// that path if there is no such built-in preset.
+    pub fn resolve(name_or_path: &str) -> Result<Self, PresetError> {
+        if let Some(preset) = Self::builtin(name_or_path) {
+            return Ok(preset);
+        }
+        let path = Path::new(name_or_path);
+        if !path.exists() {
+            return Err(PresetError::NotFound {
+                name: name_or_path.to_owned(),
+            });
+        }
+        Self::load(path)
+    }
+
+    /// Returns a preset with the options of `parameters`, and nothing else.
+    fn with_parameters(parameters: InferenceParametersBuilder) -> Self {
+        Self {
+            parameters,
+            ..Default::default()
+        }
+    }
+
+    /// Returns the sampler configuration strings of the preset: the options of the common
+    /// samplers (see [InferenceParametersBuilder::sampler_options]), followed by
+    /// [Self::samplers].
+    pub fn sampler_options(&self) -> Vec<String> {
+        let mut options = self.parameters.sampler_options();
+        options.extend(self.samplers.iter().cloned());
+        options
+    }
+
+    /// Checks the options of the preset and builds its sampler into [InferenceParameters].
+    /// `n_vocab` is the number of tokens in the vocabulary of the model, which Mirostat 1
+    /// needs.
+    pub fn inference_parameters(
+        &self,
+        n_vocab: usize,
+    ) -> Result<InferenceParameters, SamplerConfigurationError> {
+        self.parameters
+            .clone()
+            .build_with_samplers(n_vocab, &self.samplers, &self.sampler_order)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_presets() {
+        for name in GenerationPreset::BUILTIN {
+            let preset = GenerationPreset::builtin(name).unwrap();
+            assert_eq!(
+                GenerationPreset::resolve(name).unwrap().sampler_options(),
+                preset.sampler_options()
+            );
+            preset.inference_parameters(32).unwrap();
+        }
+        assert!(GenerationPreset::builtin("missing").is_none());
+        assert!(matches!(
+            GenerationPreset::resolve("missing"),
+            Err(PresetError::NotFound { .. })
+        ));
+    }
+
+    #[test]
+    fn test_load_preset() {
+        let dir = std::env::temp_dir().join(format!("llm-preset-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let expected = GenerationPreset {
+            parameters: InferenceParametersBuilder::default()
+                .temperature(0.7)
+                .repetition_last_n(128),
+            samplers: vec!["dry:multiplier=0.8".to_string()],
+            sampler_order: vec!["temperature".to_string(), "top_k".to_string()],
+            stop_sequences: vec!["\n\n".to_string()],
+        };
+
+        let toml_path = dir.join("preset.toml");
+        std::fs::write(
+            &toml_path,
+            r#"
+temperature = 0.7
+repetition_last_n = 128
+samplers = ["dry:multiplier=0.8"]
+sampler_order = ["temperature", "top_k"]
+stop_sequences = ["\n\n"]
+"#,
+        )
+        .unwrap();
+        let json_path = dir.join("preset.json");
+        std::fs::write(&json_path, serde_json::to_string(&expected).unwrap()).unwrap();
+
+        // The builder cannot be compared, so the presets are compared as they are serialized.
+        let json = |preset: &GenerationPreset| serde_json::to_value(preset).unwrap();
+        let from_toml = GenerationPreset::resolve(toml_path.to_str().unwrap()).unwrap();
+        assert_eq!(json(&from_toml), json(&expected));
+        assert_eq!(
+            json(&GenerationPreset::load(&json_path).unwrap()),
+            json(&expected)
+        );
+        assert_eq!(
+            from_toml.sampler_options(),
+            [
+                "temperature:temperature=0.7",
+                "repetition:last_n=128",
+                "dry:multiplier=0.8"
+            ]
+        );
+        from_toml.inference_parameters(32).unwrap();
+
+        std::fs::write(&json_path, r#"{"top_k": 1, "token_bias": [[2, -1.0]]}"#).unwrap();
+        let preset = GenerationPreset::load(&json_path).unwrap();
+        assert_eq!(preset.parameters.token_biases(), [(2, -1.0)]);
+        assert_eq!(preset.sampler_options(), ["topk:k=1"]);
+
+        std::fs::write(&json_path, "{\"temperature\": \"hot\"}").unwrap();
+        assert!(matches!(
+            GenerationPreset::load(&json_path),
+            Err(PresetError::Parse { .. })
+        ));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -1,20 +1,26 @@
-//! Implements quantization of weights.
+//! Implements quantization of weights, and merging of LoRA adapters into them.
 
 use crate::{
-    loader::FileTypeFormat, model::HyperparametersWriteError, Hyperparameters, KnownModel,
-    LoadError, LoadProgress, Loader, Tokenizer,
+    gguf_metadata,
+    loader::{self, FileTypeFormat},
+    model::HyperparametersWriteError,
+    Hyperparameters, KnownModel, LoadError, LoadProgress, Loader, LoraAdapter, LoraAdapterConfig,
+    Tokenizer,
 };
-use ggml::format::{SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
+use ggml::format::{gguf, ByteOrder, SaveError, SaveHandler, TensorLoadInfo, TensorSaveInfo};
 use half::f16;
 use regex::Regex;
 use std::{
     collections::HashMap,
     io::{BufRead, Seek, Write},
-    path::PathBuf,
+    path::{Path, PathBuf},
     sync::Arc,
 };
 use thiserror::Error;
 
+/// The alignment of the data of the tensors that ggml allocates in a context.
+const TENSOR_DATA_ALIGNMENT: usize = 16;
+
 #[derive(Clone, Debug)]
 
 /// Progress of quantization.
@@ -48,6 +54,13 @@ pub enum QuantizeProgress<'a> {
         /// The history of the quantization.
         history: Vec<f32>,
     },
+    /// A LoRA adapter has been merged into a tensor.
+    LoraApplied {
+        /// Name of the tensor.
+        name: &'a str,
+        /// LoRA file the patch was applied from.
+        source: &'a Path,
+    },
     /// A tensor has been skipped.
     TensorSkipped {
         /// Name of the tensor.
@@ -118,6 +131,16 @@ pub enum QuantizeError {
     /// support vocabulary scoring, despite the model having a scored vocabulary.
     #[error("container type does not support vocabulary scoring")]
     VocabularyScoringNotSupported,
+    /// The tensors of the model were quantized with a version of the quantization formats
+    /// that can no longer be read.
+    #[error(
+        "the model was quantized with version {quantization_version} of the quantization \
+         formats, which is no longer supported; quantize it again from its f16 weights"
+    )]
+    UnsupportedQuantizationVersion {
+        /// The version of the quantization formats.
+        quantization_version: u32,
+    },
 }
 impl QuantizeError {
     pub(crate) fn from_format_error(value: SaveError<QuantizeError>, path: PathBuf) -> Self {
@@ -151,6 +174,156 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         }
     })?;
 
+    rewrite::<M, R, W>(
+        reader,
+        writer,
+        tokenizer,
+        save_container_type,
+        Some(quantization_target),
+        vec![],
+        progress_callback,
+    )
+}
+
+/// Merges LoRA adapters into the weights of a model, and saves the result as a standalone
+/// model. This means the cost of applying the adapters is paid once, instead of at every load.
+///
+/// The adapters are applied in the order given. If `quantization_type` is provided, the
+/// merged weights will also be quantized to that type.
+pub fn merge_lora<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    lora_adapters: &[LoraAdapterConfig],
+    save_container_type: ggml::format::SaveContainerType,
+    quantization_type: Option<ggml::Type>,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    let quantization_target = quantization_type
+        .map(|element_type| {
+            QuantizationTarget::try_from(element_type)
+                .map_err(|_| QuantizeError::InvalidQuantizationTarget { element_type })
+        })
+        .transpose()?;
+
+    let lora_adapters = lora_adapters
+        .iter()
+        .map(LoraAdapter::load)
+        .collect::<Result<Vec<_>, _>>()?;
+
+    rewrite::<M, R, W>(
+        reader,
+        writer,
+        tokenizer,
+        save_container_type,
+        quantization_target,
+        lora_adapters,
+        progress_callback,
+    )
+}
+
+/// Rewrites a model in a legacy container (GGML, GGMF or GGJT) as GGUF, their successor,
+/// which aligns the tensors so that they can be memory-mapped and describes the model with
+/// key-value metadata. The hyperparameters, vocabulary and tensors are copied as they are,
+/// in little-endian byte order, and `architecture` (such as `llama`) is recorded as the
+/// architecture of the model. The hyperparameters are also recorded under the standard keys
+/// of the architecture (such as `llama.context_length`), so that other tools can read them.
+///
+/// Legacy containers only record the bytes and score of each token, so the tokens are
+/// written without their types, merges or special tokens.
+///
+/// Models quantized before version 2 of the quantization formats cannot be upgraded, as
+/// their tensors can no longer be read, and must be quantized again from their f16 weights.
+pub fn upgrade<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    architecture: &str,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
+    let mut loader = Loader::<M::Hyperparameters, _>::new(Tokenizer::empty_embedded(), |p| {
+        if let LoadProgress::HyperparametersLoaded = p {
+            progress_callback(QuantizeProgress::HyperparametersLoaded)
+        }
+    });
+    ggml::format::load(reader, &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, PathBuf::default()))?;
+    let Loader {
+        container_type,
+        hyperparameters,
+        tokenizer,
+        tensors,
+        ..
+    } = loader;
+
+    // The tensors are copied as they are, so quantized ones must be readable by this
+    // version of GGML.
+    let quantization_version = loader::quantization_version(container_type, &hyperparameters);
+    if quantization_version != ggml::QNT_VERSION
+        && tensors.values().any(|t| t.element_type.is_quantized())
+    {
+        return Err(QuantizeError::UnsupportedQuantizationVersion {
+            quantization_version,
+        });
+    }
+
+    let mut hyperparameter_bytes = vec![];
+    hyperparameters
+        .write_ggml(&mut hyperparameter_bytes)
+        .map_err(QuantizeError::HyperparametersWriteError)?;
+    let vocabulary = match &tokenizer {
+        Tokenizer::Embedded(v) => v.iter().collect::<Vec<_>>(),
+        Tokenizer::HuggingFace(_) => (0..tokenizer.len())
+            .map(|id| (tokenizer.token(id), 0.0))
+            .collect(),
+    };
+    let mut metadata = gguf::vocabulary_metadata(hyperparameter_bytes, &vocabulary, None);
+    metadata.insert(
+        gguf::ARCHITECTURE_KEY,
+        gguf::MetadataValue::String(architecture.to_string()),
+    );
+    gguf_metadata::insert_gguf_hyperparameters(&mut metadata, architecture, &hyperparameters);
+
+    // Keep the tensors in the order of the original model.
+    let mut sorted_tensors: Vec<_> = tensors.values().collect();
+    sorted_tensors.sort_by_key(|info| info.start_offset);
+    let tensor_infos = sorted_tensors
+        .into_iter()
+        .map(|info| gguf::TensorInfo {
+            name: info.name.clone(),
+            dims: info.dims().to_vec(),
+            element_type: info.element_type,
+            offset: 0,
+        })
+        .collect();
+
+    let mut saver = UpgradeSaver {
+        tensors: &tensors,
+        source_reader: reader,
+        progress_callback: &progress_callback,
+        total_size: 0,
+    };
+    gguf::save_gguf(writer, &mut saver, metadata, tensor_infos)
+        .map_err(|err| QuantizeError::from_format_error(err, PathBuf::default()))?;
+
+    progress_callback(QuantizeProgress::Finished {
+        original_size: saver.total_size,
+        reduced_size: saver.total_size,
+        history: vec![],
+    });
+    Ok(())
+}
+
+/// Loads a model and saves it again, applying `lora_adapters` to and quantizing
+/// (if `quantization_target` is set) its tensors along the way.
+fn rewrite<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
+    reader: &mut R,
+    writer: &mut W,
+    tokenizer: Tokenizer,
+    save_container_type: ggml::format::SaveContainerType,
+    quantization_target: Option<QuantizationTarget>,
+    lora_adapters: Vec<LoraAdapter>,
+    progress_callback: impl Fn(QuantizeProgress),
+) -> Result<(), QuantizeError> {
     // Load the model
     let progress_callback = Arc::new(progress_callback);
 
@@ -173,11 +346,13 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         ..
     } = loader;
 
-    if let Some(ft) = hyperparameters.file_type_mut() {
-        ft.quantization_version = ggml::QNT_VERSION;
-        ft.format = quantization_target
-            .try_into()
-            .expect("format has no corresponding ftype");
+    if let Some(quantization_target) = quantization_target {
+        if let Some(ft) = hyperparameters.file_type_mut() {
+            ft.quantization_version = ggml::QNT_VERSION;
+            ft.format = quantization_target
+                .try_into()
+                .expect("format has no corresponding ftype");
+        }
     }
 
     let tokenizer = match tokenizer {
@@ -193,6 +368,7 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
         &tensors,
         &to_quantize,
         &to_skip,
+        lora_adapters,
         reader,
         |p| progress_callback(p),
     );
@@ -220,6 +396,53 @@ pub fn quantize<M: KnownModel, R: BufRead + Seek, W: Write + Seek>(
     Ok(())
 }
 
+/// Copies the tensors of a model for [upgrade], converting their data to little-endian.
+struct UpgradeSaver<'a, R, F> {
+    tensors: &'a HashMap<String, TensorLoadInfo>,
+    source_reader: &'a mut R,
+    progress_callback: &'a F,
+    /// The total size of the tensors' data, in bytes.
+    total_size: usize,
+}
+impl<R: BufRead + Seek, F: Fn(QuantizeProgress)> SaveHandler<QuantizeError>
+    for UpgradeSaver<'_, R, F>
+{
+    fn write_hyperparameters(&mut self, _writer: &mut dyn Write) -> Result<(), QuantizeError> {
+        // GGUF models carry their hyperparameters in their metadata, which [upgrade] writes.
+        Ok(())
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, QuantizeError> {
+        let tensor = self.tensors.get(tensor_name).expect(
+            "tensor not found; should be impossible due to handler being populated from loader",
+        );
+        (self.progress_callback)(QuantizeProgress::TensorLoading {
+            name: tensor_name,
+            dims: tensor.dims,
+            n_elements: tensor.n_elements,
+            element_type: tensor.element_type,
+        });
+
+        let mut data = tensor.read_data(self.source_reader)?;
+        if tensor.byte_order != ByteOrder::Little {
+            if !ggml::format::can_swap_byte_order(tensor.element_type) {
+                return Err(QuantizeError::UnsupportedElementType {
+                    element_type: tensor.element_type,
+                });
+            }
+            ggml::format::swap_byte_order(tensor.element_type, &mut data);
+        }
+        self.total_size += data.len();
+
+        Ok(TensorSaveInfo {
+            n_dims: tensor.n_dims,
+            dims: tensor.dims,
+            element_type: tensor.element_type,
+            data,
+        })
+    }
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 enum QuantizationTarget {
     Q4_0,
@@ -267,11 +490,12 @@ impl From<QuantizationTarget> for FileTypeFormat {
 
 struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> {
     // Input
-    quantization_target: QuantizationTarget,
+    quantization_target: Option<QuantizationTarget>,
     hyperparameters: &'a H,
     tensors: &'a HashMap<String, TensorLoadInfo>,
     to_quantize: &'a [Regex],
     to_skip: &'a [Regex],
+    lora_adapters: Vec<LoraAdapter>,
     source_reader: &'a mut R,
     progress_callback: F,
 
@@ -283,12 +507,14 @@ struct QuantizeSaver<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead
 impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
     QuantizeSaver<'a, F, H, R>
 {
+    #[allow(clippy::too_many_arguments)]
     fn new(
-        quantization_target: QuantizationTarget,
+        quantization_target: Option<QuantizationTarget>,
         hyperparameters: &'a H,
         tensors: &'a HashMap<String, TensorLoadInfo>,
         to_quantize: &'a [Regex],
         to_skip: &'a [Regex],
+        lora_adapters: Vec<LoraAdapter>,
         source_reader: &'a mut R,
         progress_callback: F,
     ) -> Self {
@@ -298,6 +524,7 @@ impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
             tensors,
             to_quantize,
             to_skip,
+            lora_adapters,
             source_reader,
             progress_callback,
 
@@ -306,6 +533,49 @@ impl<'a, F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek>
             history_all: vec![0; 16],
         }
     }
+
+    /// Applies every LoRA adapter that targets the tensor `name` to its `data`,
+    /// returning the patched data.
+    fn apply_lora_adapters(
+        &mut self,
+        name: &str,
+        tensor: &TensorLoadInfo,
+        data: Vec<u8>,
+    ) -> Result<Vec<u8>, QuantizeError> {
+        if !self
+            .lora_adapters
+            .iter()
+            .any(|adapter| adapter.tensors_to_patch.contains(name))
+        {
+            return Ok(data);
+        }
+
+        // Like the loader, leave room for ggml's object and tensor headers as well as the
+        // data, and for the padding ggml inserts to align the data.
+        let context = ggml::Context::new_with_allocate(
+            tensor.calc_absolute_size(false) + TENSOR_DATA_ALIGNMENT,
+        );
+        let mut target = match tensor.n_dims {
+            1 => context.new_tensor_1d(tensor.element_type, tensor.dims[0]),
+            _ => context.new_tensor_2d(tensor.element_type, tensor.dims[0], tensor.dims[1]),
+        };
+        // SAFETY: `target` was just created and is not shared with anything else.
+        unsafe { target.write_data(&data) };
+
+        for adapter in self.lora_adapters.iter_mut() {
+            if adapter.patch(name, &mut target)? {
+                (self.progress_callback)(QuantizeProgress::LoraApplied {
+                    name,
+                    source: &adapter.path,
+                });
+            }
+        }
+
+        let mut patched = vec![0; data.len()];
+        // SAFETY: as above; the patches have finished writing to `target`.
+        unsafe { target.read_data(0, &mut patched) };
+        Ok(patched)
+    }
 }
 impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler<QuantizeError>
     for QuantizeSaver<'_, F, H, R>
@@ -330,20 +600,26 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
         });
 
         // Quantize only 2D tensors
-        let quantize = tensor.n_dims == 2
-            && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
-            && !self.to_skip.iter().any(|re| re.is_match(tensor_name));
+        let quantization_target = self.quantization_target.filter(|_| {
+            tensor.n_dims == 2
+                && self.to_quantize.iter().any(|re| re.is_match(tensor_name))
+                && !self.to_skip.iter().any(|re| re.is_match(tensor_name))
+        });
         let raw_data = tensor.read_data(self.source_reader)?;
+        let original_size = raw_data.len();
+        let raw_data = self.apply_lora_adapters(tensor_name, tensor, raw_data)?;
 
-        if quantize && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16) {
+        if quantization_target.is_some()
+            && !matches!(tensor.element_type, ggml::Type::F32 | ggml::Type::F16)
+        {
             return Err(QuantizeError::UnsupportedElementType {
                 element_type: tensor.element_type,
             });
         }
 
-        self.total_size_original += raw_data.len();
+        self.total_size_original += original_size;
 
-        let (element_type, data) = if quantize {
+        let (element_type, data) = if let Some(quantization_target) = quantization_target {
             (self.progress_callback)(QuantizeProgress::TensorQuantizing { name: tensor_name });
 
             let data_f32: Vec<f32> = match tensor.element_type {
@@ -360,7 +636,7 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
                 _ => unreachable!(),
             };
 
-            let result = match self.quantization_target {
+            let result = match quantization_target {
                 QuantizationTarget::Q4_0 => {
                     ggml::quantize_q4_0(&data_f32, tensor.n_elements, tensor.dims[0])
                 }
@@ -394,7 +670,7 @@ impl<F: Fn(QuantizeProgress), H: Hyperparameters, R: BufRead + Seek> SaveHandler
 
             self.total_size_new += new_data.len();
 
-            (self.quantization_target.into(), new_data)
+            (quantization_target.into(), new_data)
         } else {
             (self.progress_callback)(QuantizeProgress::TensorSkipped {
                 name: tensor_name,
@@ -0,0 +1,439 @@
+//! Implements the validation and repair of damaged model files, such as partially downloaded
+//! or partially corrupted ones.
+//!
+//! A model file ends with its tensors, so a truncated file can be cut back to the end of its
+//! last complete tensor and still be read. The tensors that were lost, or that do not match
+//! their stored [checksums](TensorChecksums), can then be backfilled from another copy of
+//! the model.
+
+use std::{
+    cell::Cell,
+    collections::BTreeSet,
+    convert::Infallible,
+    fs::{File, OpenOptions},
+    io::{BufReader, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use ggml::format::{ByteOrder, SaveError, TensorLoadInfo, TensorSaveInfo};
+use thiserror::Error;
+use tracing::log;
+
+use crate::{
+    checksum::checksums_path, model::HyperparametersWriteError, ContainerType, Hyperparameters,
+    LoadError, LoadProgress, Loader, ModelFile, TensorChecksums, Tokenizer, TokenizerSource,
+};
+
+#[derive(Error, Debug)]
+/// Errors encountered while validating or repairing a model.
+pub enum RepairError {
+    #[error("could not open file {path:?}")]
+    /// A file failed to open.
+    OpenFileFailed {
+        /// The original error.
+        source: std::io::Error,
+        /// The path that failed.
+        path: PathBuf,
+    },
+    #[error("the header of {path:?} is damaged, so it cannot be repaired")]
+    /// The model's hyperparameters or vocabulary could not be read. Only damage to the
+    /// tensors can be repaired.
+    DamagedHeader {
+        /// The error encountered while reading the header.
+        source: LoadError,
+        /// The path of the model.
+        path: PathBuf,
+    },
+    #[error("could not read the model")]
+    /// The model, its checksums or the copy to backfill from could not be read.
+    Load(#[from] LoadError),
+    #[error("the copy {path:?} to backfill from does not match the model")]
+    /// The copy to backfill from has a different container type or byte order to the model.
+    MismatchedBackfill {
+        /// The path of the copy.
+        path: PathBuf,
+    },
+    #[error("models of the container type {0:?} cannot be repaired")]
+    /// The model's container type cannot be repaired. The tensors of a GGUF model are
+    /// listed in its header, so they cannot be appended to it.
+    UnsupportedContainerType(ContainerType),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    #[error("could not write a backfilled tensor")]
+    /// A tensor could not be appended to the model.
+    Write(#[from] SaveError<Infallible>),
+}
+
+/// Options for [repair].
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RepairOptions<'a> {
+    /// Whether to only report the damage, without modifying the model.
+    pub dry_run: bool,
+    /// Another copy of the model to take the missing and corrupted tensors from.
+    pub backfill_from: Option<&'a Path>,
+}
+
+/// The damage found in a model by [repair], and what was done about it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RepairReport {
+    /// The length of the model file before it was repaired.
+    pub original_len: u64,
+    /// The length the model was truncated to, if it ended with an incomplete tensor or
+    /// trailing garbage.
+    pub truncated_to: Option<u64>,
+    /// The tensors whose data did not match their stored checksums.
+    pub corrupted: Vec<String>,
+    /// The tensors that were expected but not found in the model. These are only known
+    /// if the model has stored checksums or there is a copy to backfill from.
+    pub missing: Vec<String>,
+    /// The corrupted and missing tensors that were (or, in a dry run, would be) replaced
+    /// with their data from the copy to backfill from.
+    pub backfilled: Vec<String>,
+}
+impl RepairReport {
+    /// Returns whether no damage was found.
+    pub fn is_intact(&self) -> bool {
+        self.truncated_to.is_none() && self.corrupted.is_empty() && self.missing.is_empty()
+    }
+
+    /// Returns the corrupted and missing tensors that were not backfilled, and so are
+    /// still damaged after the repair.
+    pub fn unrepaired(&self) -> impl Iterator<Item = &str> {
+        self.corrupted
+            .iter()
+            .chain(&self.missing)
+            .filter(|name| !self.backfilled.contains(name))
+            .map(String::as_str)
+    }
+}
+
+/// Validates the model at `path` and repairs what it can.
+///
+/// The model is truncated to the end of its last complete tensor, dropping any partially
+/// written tensor. The tensors are verified against the model's stored checksums, if it has
+/// any, and those that are missing or corrupted are copied from
+/// [backfill_from](RepairOptions::backfill_from), if given. Tensors from the copy are
+/// verified against the checksums too, and are only used if they have the same type and
+/// dimensions. Missing tensors are appended to the end of the model.
+///
+/// The model's header (its hyperparameters and vocabulary) must be intact. The model is
+/// only modified once all of this has been checked, so it is left untouched if the repair
+/// fails with anything other than an I/O error while writing.
+pub fn repair<Hp: Hyperparameters>(
+    path: &Path,
+    options: RepairOptions,
+) -> Result<RepairReport, RepairError> {
+    let mut file = OpenOptions::new()
+        .read(true)
+        .write(!options.dry_run)
+        .open(path)
+        .map_err(|source| RepairError::OpenFileFailed {
+            source,
+            path: path.to_owned(),
+        })?;
+    let original_len = file.metadata()?.len();
+
+    // Read as much of the model as possible. The loader only records tensors whose data is
+    // entirely within the file, so whatever it has read when it fails is intact.
+    let hyperparameters_loaded = Cell::new(false);
+    let mut loader: Loader<Hp, _> = Loader::new(Tokenizer::empty_embedded(), |progress| {
+        if let LoadProgress::HyperparametersLoaded = progress {
+            hyperparameters_loaded.set(true);
+        }
+    });
+    let result = ggml::format::load(&mut BufReader::new(&file), &mut loader)
+        .map_err(|err| LoadError::from_format_error(err, path.to_owned()));
+    let Loader {
+        container_type,
+        hyperparameters,
+        tokenizer,
+        tensors,
+        ..
+    } = loader;
+    if let ContainerType::Gguf(_) = container_type {
+        return Err(RepairError::UnsupportedContainerType(container_type));
+    }
+    if let Err(source) = result {
+        if !hyperparameters_loaded.get() || tokenizer.len() < hyperparameters.n_vocabulary() {
+            return Err(RepairError::DamagedHeader {
+                source,
+                path: path.to_owned(),
+            });
+        }
+        log::warn!("The model at {path:?} is damaged: {source}");
+    }
+
+    let byte_order = {
+        let mut reader = BufReader::new(&file);
+        reader.seek(SeekFrom::Start(0))?;
+        ContainerType::read_with_byte_order::<LoadError>(&mut reader)
+            .map_err(|err| LoadError::from_format_error(err, path.to_owned()))?
+            .1
+    };
+
+    // The end of the model is the end of the last complete tensor, or of the header if
+    // there are none.
+    let end = match tensors.values().map(tensor_end).max() {
+        Some(end) => end,
+        None => header_len(container_type, &hyperparameters, &tokenizer)?,
+    };
+    let truncated_to = (end != original_len).then_some(end);
+
+    let checksums = if checksums_path(path).exists() {
+        Some(TensorChecksums::read(path)?)
+    } else {
+        None
+    };
+    let mut corrupted = vec![];
+    if let Some(checksums) = &checksums {
+        let mut reader = BufReader::new(&file);
+        for (name, info) in &tensors {
+            let Some(&expected) = checksums.0.get(name) else {
+                continue;
+            };
+            let data = read_tensor(path, info, &mut reader)?;
+            if crc32fast::hash(&data) != expected {
+                corrupted.push(name.clone());
+            }
+        }
+    }
+    corrupted.sort();
+
+    let backfill = options
+        .backfill_from
+        .map(|backup| ModelFile::<Hp>::open(backup, TokenizerSource::Embedded))
+        .transpose()?;
+    let expected: BTreeSet<&String> = checksums
+        .iter()
+        .flat_map(|checksums| checksums.0.keys())
+        .chain(backfill.iter().flat_map(|backup| backup.tensors.keys()))
+        .collect();
+    let missing: Vec<String> = expected
+        .into_iter()
+        .filter(|name| !tensors.contains_key(*name))
+        .cloned()
+        .collect();
+
+    // Check the copy and read the tensors to take from it before touching the model, so
+    // that a repair that fails leaves the model as it was.
+    let mut replaced = vec![];
+    let mut appended = vec![];
+    if let Some(backup) = &backfill {
+        if backup.container_type != container_type
+            || backup
+                .tensors
+                .values()
+                .any(|info| info.byte_order != byte_order)
+            || (byte_order != ByteOrder::Little && !missing.is_empty())
+        {
+            return Err(RepairError::MismatchedBackfill {
+                path: backup.path.clone(),
+            });
+        }
+
+        for name in &corrupted {
+            let info = &tensors[name];
+            match replacement(backup, checksums.as_ref(), name)? {
+                Some((backup_info, data))
+                    if backup_info.element_type == info.element_type
+                        && backup_info.dims() == info.dims() =>
+                {
+                    replaced.push((name, info.start_offset, data));
+                }
+                _ => {}
+            }
+        }
+        for name in &missing {
+            if let Some((info, data)) = replacement(backup, checksums.as_ref(), name)? {
+                let info = TensorSaveInfo {
+                    n_dims: info.n_dims,
+                    dims: info.dims,
+                    element_type: info.element_type,
+                    data,
+                };
+                appended.push((name, info));
+            }
+        }
+    }
+    let backfilled: Vec<String> = replaced
+        .iter()
+        .map(|(name, ..)| *name)
+        .chain(appended.iter().map(|(name, _)| *name))
+        .cloned()
+        .collect();
+
+    if let Some(truncated_to) = truncated_to {
+        log::info!("Truncating {path:?} from {original_len} to {truncated_to} bytes");
+    }
+    if !options.dry_run {
+        if let Some(truncated_to) = truncated_to {
+            file.set_len(truncated_to)?;
+        }
+        for (_, offset, data) in &replaced {
+            file.seek(SeekFrom::Start(*offset))?;
+            file.write_all(data)?;
+        }
+        let align = matches!(
+            container_type,
+            ContainerType::Ggjt(_) | ContainerType::Ggla(_)
+        );
+        file.seek(SeekFrom::Start(end))?;
+        for (name, info) in &appended {
+            ggml::format::write_tensor(&mut file, name, info, align)?;
+        }
+        file.flush()?;
+    }
+
+    Ok(RepairReport {
+        original_len,
+        truncated_to,
+        corrupted,
+        missing,
+        backfilled,
+    })
+}
+
+/// Reads the tensor `name` from the copy `backup`, if it can replace the one in the model.
+fn replacement<'a, Hp: Hyperparameters>(
+    backup: &'a ModelFile<Hp>,
+    checksums: Option<&TensorChecksums>,
+    name: &str,
+) -> Result<Option<(&'a TensorLoadInfo, Vec<u8>)>, LoadError> {
+    let Some(info) = backup.tensors.get(name) else {
+        return Ok(None);
+    };
+    let data = backup.read_tensor(name)?;
+    let expected = checksums.and_then(|checksums| checksums.0.get(name));
+    if matches!(expected, Some(&expected) if crc32fast::hash(&data) != expected) {
+        log::warn!("The tensor `{name}` is also corrupted in {:?}", backup.path);
+        return Ok(None);
+    }
+    Ok(Some((info, data)))
+}
+
+/// Returns the offset of the end of the tensor's data.
+fn tensor_end(info: &TensorLoadInfo) -> u64 {
+    info.start_offset + info.calc_size() as u64
+}
+
+fn read_tensor(
+    path: &Path,
+    info: &TensorLoadInfo,
+    reader: &mut BufReader<&File>,
+) -> Result<Vec<u8>, LoadError> {
+    info.read_data(reader)
+        .map_err(|source| LoadError::TensorReadFailed {
+            tensor_name: info.name.clone(),
+            offset: info.start_offset,
+            path: path.to_owned(),
+            source,
+        })
+}
+
+/// Returns the length of the model's header, which is everything before its tensors.
+fn header_len(
+    container_type: ContainerType,
+    hyperparameters: &impl Hyperparameters,
+    tokenizer: &Tokenizer,
+) -> Result<u64, RepairError> {
+    let mut header = vec![];
+    container_type.write(&mut header)?;
+    hyperparameters
+        .write_ggml(&mut header)
+        .map_err(RepairError::HyperparametersWriteError)?;
+
+    let score_len = match container_type {
+        ContainerType::Ggmf(_) | ContainerType::Ggjt(_) => 4,
+        ContainerType::Ggml | ContainerType::Ggla(_) => 0,
+        ContainerType::Gguf(_) => {
+            return Err(RepairError::UnsupportedContainerType(container_type))
+        }
+    };
+    let vocabulary_len: usize = (0..tokenizer.len())
+        .map(|i| 4 + tokenizer.token(i).len() + score_len)
+        .sum();
+    Ok((header.len() + vocabulary_len) as u64)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_model::{write_test_file, TestHyperparameters as Hp},
+        write_checksums,
+    };
+    use ggml::format::SaveContainerType;
+
+    #[test]
+    fn test_repair_truncated_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("damaged.bin");
+        let backup_path = dir.path().join("backup.bin");
+        write_test_file(&backup_path, SaveContainerType::GgjtV3);
+        let checksums = write_checksums::<Hp>(&backup_path).unwrap();
+
+        // Simulate an interrupted download, which cuts off the last tensors.
+        let original_len = std::fs::metadata(&backup_path).unwrap().len();
+        std::fs::copy(&backup_path, &path).unwrap();
+        checksums.write(&path).unwrap();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(original_len - 1).unwrap();
+        drop(file);
+
+        let report = repair::<Hp>(&path, RepairOptions::default()).unwrap();
+        assert!(!report.is_intact());
+        assert!(report.truncated_to.unwrap() < original_len - 1);
+        assert!(!report.missing.is_empty());
+        assert!(report.backfilled.is_empty());
+        assert!(ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).is_ok());
+
+        let report = repair::<Hp>(
+            &path,
+            RepairOptions {
+                backfill_from: Some(&backup_path),
+                ..Default::default()
+            },
+        )
+        .unwrap();
+        assert_eq!(report.truncated_to, None);
+        assert_eq!(report.unrepaired().count(), 0);
+        let repaired = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        assert_eq!(TensorChecksums::compute(&repaired).unwrap(), checksums);
+
+        assert!(repair::<Hp>(&path, RepairOptions::default())
+            .unwrap()
+            .is_intact());
+    }
+
+    #[test]
+    fn test_failed_repair_leaves_model_untouched() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("mismatched.bin");
+        let backup_path = dir.path().join("mismatched-ggml.bin");
+        write_test_file(&path, SaveContainerType::GgjtV3);
+        write_test_file(&backup_path, SaveContainerType::Ggml);
+
+        let len = std::fs::metadata(&path).unwrap().len();
+        let file = OpenOptions::new().write(true).open(&path).unwrap();
+        file.set_len(len - 1).unwrap();
+        drop(file);
+        let damaged = std::fs::read(&path).unwrap();
+
+        let result = repair::<Hp>(
+            &path,
+            RepairOptions {
+                backfill_from: Some(&backup_path),
+                ..Default::default()
+            },
+        );
+        assert!(matches!(
+            result,
+            Err(RepairError::MismatchedBackfill { .. })
+        ));
+        assert_eq!(std::fs::read(&path).unwrap(), damaged);
+    }
+}
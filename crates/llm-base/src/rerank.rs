@@ -0,0 +1,163 @@
+//! Implements [Model::rerank], which scores how relevant documents are to a query by asking
+//! the model whether each of them is, so that a model can be used as a local reranker (e.g.
+//! to order the documents retrieved for retrieval-augmented generation).
+
+use thiserror::Error;
+
+use crate::{
+    util, InferenceError, InferenceFeedback, InferenceSession, InferenceSessionConfig, Model,
+    Prompt, TokenId,
+};
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+/// How a query and a document are presented to the model by [Model::rerank].
+///
+/// The prompt is the query prefix, the query, the document prefix, the document and the
+/// question. The relevance of the document is the probability that the model continues the
+/// prompt with all of the tokens of the relevant answer rather than those of the irrelevant
+/// one.
+pub struct RerankTemplate {
+    /// The text before the query.
+    pub query_prefix: String,
+    /// The text between the query and the document.
+    pub document_prefix: String,
+    /// The text after the document, which asks whether it is relevant to the query.
+    pub question: String,
+    /// The answer that the document is relevant.
+    pub relevant_answer: String,
+    /// The answer that the document is not relevant.
+    pub irrelevant_answer: String,
+}
+impl Default for RerankTemplate {
+    fn default() -> Self {
+        Self {
+            query_prefix: "Query: ".to_string(),
+            document_prefix: "\nDocument: ".to_string(),
+            question: "\nIs the document relevant to the query? Answer yes or no.\nAnswer:"
+                .to_string(),
+            relevant_answer: " yes".to_string(),
+            irrelevant_answer: " no".to_string(),
+        }
+    }
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered by [Model::rerank].
+pub enum RerankError {
+    /// One of the answers of the [RerankTemplate] has no tokens.
+    #[error("the answers of the rerank template must not be empty")]
+    EmptyAnswer,
+    /// Evaluating a query and document failed.
+    #[error("evaluating a document failed")]
+    Inference(#[from] InferenceError),
+}
+
+pub(crate) fn rerank(
+    model: &dyn Model,
+    query: &str,
+    documents: &[&str],
+    template: &RerankTemplate,
+    config: InferenceSessionConfig,
+) -> Result<Vec<f32>, RerankError> {
+    let answer_tokens = |answer: &str| -> Result<Vec<TokenId>, RerankError> {
+        let tokens = Prompt::from(answer)
+            .to_tokens(model.tokenizer(), false)
+            .map_err(InferenceError::from)?;
+        if tokens.is_empty() {
+            return Err(RerankError::EmptyAnswer);
+        }
+        Ok(tokens)
+    };
+    let relevant_answer = answer_tokens(&template.relevant_answer)?;
+    let irrelevant_answer = answer_tokens(&template.irrelevant_answer)?;
+
+    let prefix = format!(
+        "{}{query}{}",
+        template.query_prefix, template.document_prefix
+    );
+    let feed = |session: &mut InferenceSession, text: &str| {
+        session.feed_prompt(model, text, &mut Default::default(), |_| {
+            Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+        })
+    };
+    let start_session = |text: &str| -> Result<InferenceSession, RerankError> {
+        let mut session = model.start_session(config);
+        feed(&mut session, text)?;
+        Ok(session)
+    };
+
+    // When the model supports rewinding, the query is evaluated once, and each document is
+    // evaluated after it and then rewound.
+    let mut shared_session = None;
+    if model.supports_rewind() {
+        shared_session = Some(start_session(&prefix)?);
+    }
+
+    let mut scores = Vec::with_capacity(documents.len());
+    for document in documents {
+        let suffix = format!("{document}{}", template.question);
+        let (relevant, irrelevant) = match &mut shared_session {
+            Some(session) => {
+                let (n_past, last_logits) = (session.n_past, session.last_logits.clone());
+                feed(session, &suffix)?;
+                let logprobs = (
+                    answer_logprob(model, session, &relevant_answer)?,
+                    answer_logprob(model, session, &irrelevant_answer)?,
+                );
+                session
+                    .rewind(model, session.n_past - n_past)
+                    .map_err(InferenceError::from)?;
+                session.last_logits = last_logits;
+                logprobs
+            }
+            None => {
+                let prompt = format!("{prefix}{suffix}");
+                let mut session = start_session(&prompt)?;
+                let relevant = answer_logprob(model, &mut session, &relevant_answer)?;
+                // The tokens of the relevant answer cannot be removed from the session.
+                if relevant_answer.len() > 1 {
+                    session = start_session(&prompt)?;
+                }
+                (
+                    relevant,
+                    answer_logprob(model, &mut session, &irrelevant_answer)?,
+                )
+            }
+        };
+
+        // The probability of the relevant answer, out of the two answers.
+        scores.push(1.0 / (1.0 + (irrelevant - relevant).exp()));
+    }
+    Ok(scores)
+}
+
+/// Returns the log-probability that the model continues the tokens of `session` with all of
+/// the tokens of `answer`. The tokens of the answer before its last are evaluated to find
+/// it, and are rewound afterwards if the model supports rewinding.
+fn answer_logprob(
+    model: &dyn Model,
+    session: &mut InferenceSession,
+    answer: &[TokenId],
+) -> Result<f32, RerankError> {
+    let last_logits = session.last_logits.clone();
+    let mut logprob = 0.0;
+    for (i, &token) in answer.iter().enumerate() {
+        if i > 0 {
+            session.feed_prompt(
+                model,
+                Prompt::Tokens(&answer[i - 1..i]),
+                &mut Default::default(),
+                |_| InferenceFeedback::Continue,
+            )?;
+        }
+        logprob += util::log_softmax(&session.last_logits)[token as usize];
+    }
+
+    if answer.len() > 1 && model.supports_rewind() {
+        session
+            .rewind(model, answer.len() - 1)
+            .map_err(InferenceError::from)?;
+        session.last_logits = last_logits;
+    }
+    Ok(logprob)
+}
@@ -4,6 +4,7 @@
 //! The `llm-samplers` crate is also re-exported here for convenient use as `llm_samplers`.
 
 use std::{
+    collections::{HashMap, HashSet},
     error::Error,
     fmt,
     str::FromStr,
@@ -26,6 +27,10 @@ pub enum SamplerConfigurationError {
     /// when an invalid combination is specified.
     SamplerCombinationError(String),
 
+    #[error("Cannot reorder sampler {0:?}: it is unknown, listed twice, or always runs at a fixed position")]
+    /// The sampler order named a sampler that cannot be reordered, or named it more than once.
+    InvalidSamplerOrder(String),
+
     #[error("Error configuring sampler {name}: {err}")]
     /// The sampler name was unknown or the options to it were invalid.
     BuildSamplerError {
@@ -66,6 +71,17 @@ pub struct ConfiguredSamplers {
     pub mirostat2: bool,
     /// Samplers incompatible with Mirostat 1 and 2 are present.
     pub incompat_mirostat: bool,
+    /// Repetition penalties with a decay, which run first. If any are present, the default repetition penalty is not added.
+    pub repetition_decay: Vec<SampleRepetitionDecay>,
+    /// The DRY sampler, which runs after `repetition_decay`.
+    pub dry: Option<SampleDry>,
+    /// Blocks repeated n-grams. Runs after `dry` and before the samplers in `builder`.
+    pub no_repeat_ngram: Option<SampleNoRepeatNgram>,
+    /// Epsilon sampling. Added to `builder` by [Self::ensure_default_slots], after
+    /// temperature.
+    pub epsilon: Option<SampleEpsilon>,
+    /// Eta sampling. Added to `builder` by [Self::ensure_default_slots], after epsilon.
+    pub eta: Option<SampleEta>,
 }
 
 /// Construct a default instance of the structure. The `builder`
@@ -74,17 +90,22 @@ pub struct ConfiguredSamplers {
 /// We call a configuration of samplers that run in a certain order a "chain".
 /// Here is a description of the default chain `llm` uses:
 ///
-/// 1.  Repetition (present by default, multiple allowed)
-/// 2.  Frequency/Presence (optional, multiple allowed)
-/// 3.  Sequence Repetition (optional, multiple allowed)
-/// 4.  Top-K (present by default - incompatible with Mirostat)
-/// 5.  Tail Free (optional - incompatible with Mirostat)
-/// 6.  Locally Typical (optional - incompatible with Mirostat)
-/// 7.  Top-P (present by default - incompatible with Mirostat)
-/// 8.  Top-A (optional - incompatible with Mirostat)
-/// 9.  Min-P (optional - incompatible with Mirostat)
-/// 10. Temperature (present by default)
-/// 11. A Mirostat 1 or 2 sampler if configured, otherwise Random Distribution.
+/// 1.  Repetition with decay (optional, multiple allowed)
+/// 2.  DRY (optional)
+/// 3.  No Repeat N-gram (optional)
+/// 4.  Repetition (present by default unless Repetition with decay is configured, multiple allowed)
+/// 5.  Frequency/Presence (optional, multiple allowed)
+/// 6.  Sequence Repetition (optional, multiple allowed)
+/// 7.  Top-K (present by default - incompatible with Mirostat)
+/// 8.  Tail Free (optional - incompatible with Mirostat)
+/// 9.  Locally Typical (optional - incompatible with Mirostat)
+/// 10. Top-P (present by default - incompatible with Mirostat)
+/// 11. Top-A (optional - incompatible with Mirostat)
+/// 12. Min-P (optional - incompatible with Mirostat)
+/// 13. Temperature (present by default)
+/// 14. Epsilon (optional - incompatible with Mirostat)
+/// 15. Eta (optional - incompatible with Mirostat)
+/// 16. A Mirostat 1 or 2 sampler if configured, otherwise Random Distribution.
 ///
 /// Samplers listed as "present by default" but incompatible with Mirostat will
 /// only be enabled by default if there is no Mirostat sampler enabled.
@@ -94,113 +115,276 @@ pub struct ConfiguredSamplers {
 /// it then no extra "default" sampler of that type will be added. So, for example,
 /// if you wanted both the default Repetition sampler _and_ one with custom options, you'd
 /// need to configure the Repetition sampler twice.
+///
+/// The order of samplers 4 to 13 can be changed with [ConfiguredSamplers::with_order].
 impl Default for ConfiguredSamplers {
     fn default() -> Self {
-        Self {
-            builder: SamplerChainBuilder::from([
-                (
-                    "repetition",
-                    SamplerSlot::new_chain(
-                        || Box::new(SampleRepetition::default().penalty(1.30).last_n(64)),
-                        [],
-                    ),
-                ),
-                (
-                    "freqpresence",
-                    SamplerSlot::new_chain(
-                        || Box::new(SampleFreqPresence::default().last_n(64)),
-                        [],
-                    ),
-                ),
-                (
-                    "seqrepetition",
-                    SamplerSlot::new_chain(|| Box::<SampleSeqRepetition>::default(), []),
+        Self::from_order(&Self::DEFAULT_ORDER)
+    }
+}
+
+impl ConfiguredSamplers {
+    /// The samplers whose order can be changed with [Self::with_order], in their default
+    /// order.
+    pub const DEFAULT_ORDER: [&'static str; 10] = [
+        "repetition",
+        "freqpresence",
+        "seqrepetition",
+        "topk",
+        "tailfree",
+        "locallytypical",
+        "topp",
+        "topa",
+        "minp",
+        "temperature",
+    ];
+
+    /// Like [Default::default], but with the samplers named in `order` run in that order.
+    ///
+    /// The named samplers take each other's places in the default order, and the samplers
+    /// that are not named keep theirs; for example, `["temperature", "top_k"]` runs
+    /// temperature where top-k would have run, and top-k where temperature would have run.
+    /// Names are compared like sampler names in configuration strings, and `typical` can be
+    /// used for `locally_typical`. The Mirostat and random distribution samplers, which pick
+    /// the token, always run last.
+    pub fn with_order(order: &[impl AsRef<str>]) -> Result<Self, SamplerConfigurationError> {
+        let mut named = vec![];
+        for name in order {
+            let normalized: String = name
+                .as_ref()
+                .trim()
+                .to_lowercase()
+                .chars()
+                .filter(|c| *c != '_' && *c != '-')
+                .collect();
+            let normalized = match normalized.as_str() {
+                "typical" => "locallytypical",
+                other => other,
+            };
+            let name = Self::DEFAULT_ORDER
+                .iter()
+                .copied()
+                .find(|n| *n == normalized)
+                .filter(|n| !named.contains(n))
+                .ok_or_else(|| {
+                    SamplerConfigurationError::InvalidSamplerOrder(name.as_ref().to_string())
+                })?;
+            named.push(name);
+        }
+
+        let mut order = Self::DEFAULT_ORDER;
+        let mut named_in_order = named.iter();
+        for slot in order.iter_mut() {
+            if named.contains(slot) {
+                *slot = named_in_order.next().unwrap();
+            }
+        }
+        Ok(Self::from_order(&order))
+    }
+
+    fn from_order(order: &[&'static str; 10]) -> Self {
+        let mut slots = [
+            (
+                "repetition",
+                SamplerSlot::new_chain(
+                    || Box::new(SampleRepetition::default().penalty(1.30).last_n(64)),
+                    [],
                 ),
-                (
-                    "topk",
-                    SamplerSlot::new_single(
-                        || Box::new(SampleTopK::default().k(40)),
-                        Option::<SampleTopK>::None,
-                    ),
+            ),
+            (
+                "freqpresence",
+                SamplerSlot::new_chain(|| Box::new(SampleFreqPresence::default().last_n(64)), []),
+            ),
+            (
+                "seqrepetition",
+                SamplerSlot::new_chain(|| Box::<SampleSeqRepetition>::default(), []),
+            ),
+            (
+                "topk",
+                SamplerSlot::new_single(
+                    || Box::new(SampleTopK::default().k(40)),
+                    Option::<SampleTopK>::None,
                 ),
-                (
-                    "tailfree",
-                    SamplerSlot::new_single(
-                        || Box::<SampleTailFree>::default(),
-                        Option::<SampleTailFree>::None,
-                    ),
+            ),
+            (
+                "tailfree",
+                SamplerSlot::new_single(
+                    || Box::<SampleTailFree>::default(),
+                    Option::<SampleTailFree>::None,
                 ),
-                (
-                    "locallytypical",
-                    SamplerSlot::new_single(
-                        || Box::<SampleLocallyTypical>::default(),
-                        Option::<SampleLocallyTypical>::None,
-                    ),
+            ),
+            (
+                "locallytypical",
+                SamplerSlot::new_single(
+                    || Box::<SampleLocallyTypical>::default(),
+                    Option::<SampleLocallyTypical>::None,
                 ),
-                (
-                    "topp",
-                    SamplerSlot::new_single(
-                        || Box::new(SampleTopP::default().p(0.95)),
-                        Option::<SampleTopP>::None,
-                    ),
+            ),
+            (
+                "topp",
+                SamplerSlot::new_single(
+                    || Box::new(SampleTopP::default().p(0.95)),
+                    Option::<SampleTopP>::None,
                 ),
-                (
-                    "topa",
-                    SamplerSlot::new_single(
-                        || Box::new(SampleTopA::default().a1(0.0).a2(0.0)),
-                        Option::<SampleTopA>::None,
-                    ),
+            ),
+            (
+                "topa",
+                SamplerSlot::new_single(
+                    || Box::new(SampleTopA::default().a1(0.0).a2(0.0)),
+                    Option::<SampleTopA>::None,
                 ),
-                (
-                    "minp",
-                    SamplerSlot::new_single(
-                        || Box::new(SampleMinP::default().p(0.0)),
-                        Option::<SampleMinP>::None,
-                    ),
+            ),
+            (
+                "minp",
+                SamplerSlot::new_single(
+                    || Box::new(SampleMinP::default().p(0.0)),
+                    Option::<SampleMinP>::None,
                 ),
-                (
-                    "temperature",
-                    SamplerSlot::new_single(
-                        || Box::new(SampleTemperature::default().temperature(0.8)),
-                        Option::<SampleTemperature>::None,
-                    ),
+            ),
+            (
+                "temperature",
+                SamplerSlot::new_single(
+                    || Box::new(SampleTemperature::default().temperature(0.8)),
+                    Option::<SampleTemperature>::None,
                 ),
-                (
-                    "mirostat1",
-                    SamplerSlot::new_single(
-                        || Box::<SampleMirostat1>::default(),
-                        Option::<SampleMirostat1>::None,
-                    ),
+            ),
+            (
+                "mirostat1",
+                SamplerSlot::new_single(
+                    || Box::<SampleMirostat1>::default(),
+                    Option::<SampleMirostat1>::None,
                 ),
-                (
-                    "mirostat2",
-                    SamplerSlot::new_single(
-                        || Box::<SampleMirostat2>::default(),
-                        Option::<SampleMirostat2>::None,
-                    ),
+            ),
+            (
+                "mirostat2",
+                SamplerSlot::new_single(
+                    || Box::<SampleMirostat2>::default(),
+                    Option::<SampleMirostat2>::None,
                 ),
-            ]),
+            ),
+        ];
+        // The Mirostat samplers are not in `order`, so they stay at the end.
+        slots.sort_by_key(|(name, _)| order.iter().position(|n| n == name).unwrap_or(order.len()));
+
+        Self {
+            builder: SamplerChainBuilder::from(slots),
             mirostat1: false,
             mirostat2: false,
             incompat_mirostat: false,
+            repetition_decay: vec![],
+            dry: None,
+            no_repeat_ngram: None,
+            epsilon: None,
+            eta: None,
         }
     }
-}
 
-impl ConfiguredSamplers {
+    /// Configures the samplers from a string definition, as described for the [FromStr]
+    /// implementation. Use this with [Self::with_order] to configure reordered samplers.
+    pub fn with_options(self, s: &str) -> Result<Self, SamplerConfigurationError> {
+        let mut result = self;
+
+        let s = s.trim().to_lowercase();
+        let opts = s
+            .split(|c: char| c == '/' || c.is_whitespace())
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                if let Some((name, opts)) = s.split_once(':') {
+                    (
+                        name.trim()
+                            .chars()
+                            .filter(|c| *c != '_' && *c != '-')
+                            .collect(),
+                        opts.trim(),
+                    )
+                } else {
+                    (s.trim().to_string(), "")
+                }
+            })
+            .inspect(|(name, _slot)| match name.as_str() {
+                "mirostat1" => result.mirostat1 = true,
+                "mirostat2" => result.mirostat2 = true,
+                "topa" | "minp" | "topp" | "topk" | "locallytypical" | "tailfree" | "epsilon"
+                | "eta" => result.incompat_mirostat = true,
+                _ => (),
+            })
+            .collect::<Vec<_>>();
+
+        opts.into_iter().try_for_each(|(name, args)| {
+            let build_error = |err: String| SamplerConfigurationError::BuildSamplerError {
+                name: name.to_string(),
+                err: err.into(),
+            };
+            match name.as_str() {
+                "repetitiondecay" => {
+                    result
+                        .repetition_decay
+                        .push(args.parse().map_err(build_error)?);
+                    return Ok(());
+                }
+                "dry" => {
+                    result.dry = Some(args.parse().map_err(build_error)?);
+                    return Ok(());
+                }
+                "norepeatngram" => {
+                    result.no_repeat_ngram = Some(args.parse().map_err(build_error)?);
+                    return Ok(());
+                }
+                "epsilon" => {
+                    result.epsilon = Some(args.parse().map_err(build_error)?);
+                    return Ok(());
+                }
+                "eta" => {
+                    result.eta = Some(args.parse().map_err(build_error)?);
+                    return Ok(());
+                }
+                _ => {}
+            }
+            result.builder.configure(&name, args).map_err(|err| {
+                SamplerConfigurationError::BuildSamplerError {
+                    name: name.to_string(),
+                    err: err.into(),
+                }
+            })
+        })?;
+
+        result.ensure_default_slots();
+        result.ensure_valid()?;
+
+        Ok(result)
+    }
+
     /// Ensures the default slots are populated after processing options.
-    /// Currently this is: temperature and repetition samplers
+    /// Currently this is: temperature and repetition samplers (unless a repetition
+    /// sampler with decay is configured)
     /// Then if neither Mirostat 1 or 2 are enabled: top-p and top-k.
+    /// Epsilon and eta samplers are added if they are configured.
     pub fn ensure_default_slots(&mut self) {
         self.builder.iter_mut().for_each(|(name, slot)| {
             let mirostat = self.mirostat1 || self.mirostat2;
             match name as &str {
-                "temperature" | "repetition" => slot.ensure_present(),
+                "temperature" => slot.ensure_present(),
+                "repetition" if self.repetition_decay.is_empty() => slot.ensure_present(),
                 "topp" | "topk" if !mirostat => slot.ensure_present(),
                 _ => (),
             }
         });
 
+        // These are configured by `llm` rather than the builder, so they are only added
+        // once their options are known.
+        if let Some(epsilon) = self.epsilon {
+            self.builder += (
+                "epsilon".to_string(),
+                SamplerSlot::new_static(move || Box::new(epsilon)),
+            );
+        }
+        if let Some(eta) = self.eta {
+            self.builder += (
+                "eta".to_string(),
+                SamplerSlot::new_static(move || Box::new(eta)),
+            );
+        }
+
         if !(self.mirostat1 || self.mirostat2) {
             self.builder += (
                 "randdistrib".to_string(),
@@ -219,7 +403,7 @@ impl ConfiguredSamplers {
             ))?
         } else if (self.mirostat1 || self.mirostat2) && self.incompat_mirostat {
             Err(SamplerConfigurationError::SamplerCombinationError(
-                "Cannot enable top-p, top-k, top-a, min-p, locally typical or tail free samplers with Mirostat 1 or 2".to_string(),
+                "Cannot enable top-p, top-k, top-a, min-p, locally typical, tail free, epsilon or eta samplers with Mirostat 1 or 2".to_string(),
             ))?
         }
         Ok(())
@@ -239,53 +423,59 @@ impl FromStr for ConfiguredSamplers {
     type Err = SamplerConfigurationError;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        let mut result = Self::default();
-
-        let s = s.trim().to_lowercase();
-        let opts = s
-            .split(|c: char| c == '/' || c.is_whitespace())
-            .filter(|s| !s.is_empty())
-            .map(|s| {
-                if let Some((name, opts)) = s.split_once(':') {
-                    (
-                        name.trim()
-                            .chars()
-                            .filter(|c| *c != '_' && *c != '-')
-                            .collect(),
-                        opts.trim(),
-                    )
-                } else {
-                    (s.trim().to_string(), "")
-                }
-            })
-            .inspect(|(name, _slot)| match name.as_str() {
-                "mirostat1" => result.mirostat1 = true,
-                "mirostat2" => result.mirostat2 = true,
-                "topa" | "minp" | "topp" | "topk" | "locallytypical" | "tailfree" => {
-                    result.incompat_mirostat = true
-                }
-                _ => (),
-            })
-            .collect::<Vec<_>>();
+        Self::default().with_options(s)
+    }
+}
 
-        opts.into_iter().try_for_each(|(name, args)| {
-            result.builder.configure(&name, args).map_err(|err| {
-                SamplerConfigurationError::BuildSamplerError {
-                    name: name.to_string(),
-                    err: err.into(),
-                }
-            })
-        })?;
+/// Modifies the logits of the next token before it is sampled, given the tokens that
+/// came before it.
+///
+/// Logits processors run in the order they were registered in
+/// [InferenceParameters::logits_processors](crate::InferenceParameters::logits_processors),
+/// before the sampler. They are the place for constraints that are not expressed as
+/// samplers, such as grammars, watermarking or experiments with the raw logits.
+pub trait LogitsProcessor: fmt::Debug + Send + Sync {
+    /// Modifies `logits`, which has one entry per token in the vocabulary. Setting a
+    /// logit to negative infinity prevents its token from being sampled.
+    fn process(&mut self, previous_tokens: &[TokenId], logits: &mut [f32]) -> anyhow::Result<()>;
+}
 
-        result.ensure_default_slots();
-        result.ensure_valid()?;
+/// A [LogitsProcessor] that prevents the given tokens from being sampled.
+#[derive(Debug, Clone, Default)]
+pub struct SuppressTokens(pub Vec<TokenId>);
+impl LogitsProcessor for SuppressTokens {
+    fn process(&mut self, _previous_tokens: &[TokenId], logits: &mut [f32]) -> anyhow::Result<()> {
+        for token in &self.0 {
+            if let Some(logit) = logits.get_mut(*token as usize) {
+                *logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(())
+    }
+}
 
-        Ok(result)
+/// Runs each of `processors` over `logits` in turn.
+pub fn process_logits(
+    processors: &[Arc<Mutex<dyn LogitsProcessor>>],
+    previous_tokens: &[TokenId],
+    logits: &mut [f32],
+) -> Result<(), SamplingError> {
+    for processor in processors {
+        processor
+            .lock()
+            .unwrap()
+            .process(previous_tokens, logits)
+            .map_err(|err| SamplingError::InternalSamplingError(err.into()))?;
     }
+    Ok(())
 }
 
 /// Sample a token. This convenience function handles building
 /// the sampler resources and logits objects the sampler needs.
+#[cfg_attr(
+    feature = "instrumentation",
+    tracing::instrument(level = "trace", skip_all, fields(n_previous_tokens = previous_tokens.len()))
+)]
 pub fn sample_token(
     mut sampler: impl Sampler,
     rng: &mut impl rand::Rng,
@@ -315,6 +505,19 @@ pub fn build_sampler(
     n_vocab: usize,
     bias: &[(TokenId, f32)],
     args: &[impl AsRef<str>],
+) -> Result<Arc<Mutex<dyn Sampler>>, SamplerConfigurationError> {
+    build_sampler_with_order(n_vocab, bias, args, &[] as &[&str])
+}
+
+/// Like [build_sampler], but with the samplers named in `order` run in that order, as
+/// described in [ConfiguredSamplers::with_order]. The order of samplers can change the
+/// output considerably; for example, applying temperature before top-p changes which
+/// tokens top-p keeps.
+pub fn build_sampler_with_order(
+    n_vocab: usize,
+    bias: &[(TokenId, f32)],
+    args: &[impl AsRef<str>],
+    order: &[impl AsRef<str>],
 ) -> Result<Arc<Mutex<dyn Sampler>>, SamplerConfigurationError> {
     let mut samplers = SamplerChain::new();
 
@@ -329,7 +532,8 @@ pub fn build_sampler(
         .map(|s| "/".to_string() + s)
         .collect::<String>();
 
-    let mut configured_samplers = ConfiguredSamplers::from_str(&sampler_options)?;
+    let mut configured_samplers =
+        ConfiguredSamplers::with_order(order)?.with_options(&sampler_options)?;
     if configured_samplers.mirostat1 {
         configured_samplers
             .builder
@@ -339,10 +543,475 @@ pub fn build_sampler(
                 err: err.into(),
             })?;
     }
+    for sampler in configured_samplers.repetition_decay {
+        samplers += sampler;
+    }
+    if let Some(dry) = configured_samplers.dry {
+        samplers += dry;
+    }
+    if let Some(no_repeat_ngram) = configured_samplers.no_repeat_ngram {
+        samplers += no_repeat_ngram;
+    }
     samplers += configured_samplers.builder.into_chain();
     Ok(Arc::new(Mutex::new(samplers)))
 }
 
+/// A repetition penalty that penalizes recent tokens more than distant ones.
+///
+/// Each of the last `last_n` tokens is penalized by up to `penalty`, scaled down by
+/// the [RepetitionDecay] according to how long ago it last appeared; the most recent token
+/// is always penalized by the full `penalty`. Configured from a string as
+/// `repetition_decay:penalty=1.3:last_n=256:decay=exponential:half_life=64`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SampleRepetitionDecay {
+    /// The penalty for repeating the most recent token. 1.0 is no penalty.
+    pub penalty: f32,
+    /// The number of previous tokens to consider, independently of the context size.
+    pub last_n: usize,
+    /// How the penalty decreases for tokens that appeared longer ago.
+    pub decay: RepetitionDecay,
+}
+impl Default for SampleRepetitionDecay {
+    fn default() -> Self {
+        Self {
+            penalty: 1.30,
+            last_n: 64,
+            decay: RepetitionDecay::Linear,
+        }
+    }
+}
+impl SampleRepetitionDecay {
+    /// The penalty for a token that last appeared `distance` tokens ago, where 0 is the
+    /// most recent token.
+    pub fn penalty_at(&self, distance: usize) -> f32 {
+        1.0 + (self.penalty - 1.0) * self.decay.weight(distance, self.last_n)
+    }
+}
+impl Sampler for SampleRepetitionDecay {
+    fn sample<'a>(
+        &mut self,
+        res: &mut dyn HasSamplerResources,
+        logits: &'a mut Logits,
+    ) -> anyhow::Result<&'a mut Logits> {
+        // The penalty of each token is determined by its most recent appearance.
+        let mut penalties = HashMap::new();
+        res.with_last_tokens(&mut |tokens| {
+            for (distance, token) in tokens.iter().rev().take(self.last_n).enumerate() {
+                penalties
+                    .entry(*token)
+                    .or_insert_with(|| self.penalty_at(distance));
+            }
+        })?;
+
+        for logit in logits.iter_mut() {
+            if let Some(&penalty) = penalties.get(&logit.token_id) {
+                if logit.logit <= 0.0 {
+                    logit.logit *= penalty;
+                } else {
+                    logit.logit /= penalty;
+                }
+            }
+        }
+        Ok(logits)
+    }
+}
+impl FromStr for SampleRepetitionDecay {
+    type Err = String;
+
+    /// Parses the options of the sampler, as described in [parse_options].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Self::default();
+        let mut half_life = None;
+        for (key, value) in parse_options(s)? {
+            match key.as_str() {
+                "penalty" => result.penalty = parse_value(&key, value)?,
+                "lastn" => result.last_n = parse_value(&key, value)?,
+                "halflife" => half_life = Some(parse_value::<f32>(&key, value)?),
+                "decay" => {
+                    result.decay = match value {
+                        "none" => RepetitionDecay::None,
+                        "linear" => RepetitionDecay::Linear,
+                        "exponential" | "exp" => RepetitionDecay::Exponential { half_life: 0.0 },
+                        _ => return Err(format!("invalid value for {key}: {value:?}")),
+                    }
+                }
+                _ => return Err(format!("unknown option {key:?}")),
+            }
+        }
+
+        if let RepetitionDecay::Exponential { half_life: h } = &mut result.decay {
+            // Halve the penalty every quarter of the window by default.
+            *h = half_life.unwrap_or(result.last_n as f32 / 4.0);
+            if h.is_nan() || *h <= 0.0 {
+                return Err(format!("half_life must be positive, got {h}"));
+            }
+        } else if half_life.is_some() {
+            return Err("half_life only applies to decay=exponential".to_string());
+        }
+        Ok(result)
+    }
+}
+
+/// The DRY ("Don't Repeat Yourself") sampler, which penalizes tokens that would continue
+/// a sequence of tokens that already appeared earlier.
+///
+/// If the last tokens repeat a sequence from earlier in the text, the token that followed
+/// that sequence is penalized by `multiplier * base ^ (length - allowed_length)`, where
+/// `length` is the length of the repeated sequence, as long as it is at least
+/// `allowed_length` tokens long. This stops loops without penalizing common tokens, unlike
+/// a repetition penalty. See <https://github.com/oobabooga/text-generation-webui/pull/5677>.
+///
+/// Configured from a string as `dry:multiplier=0.8:base=1.75:allowed_length=2`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct SampleDry {
+    /// The penalty for a repeated sequence of `allowed_length` tokens. 0.0 disables the sampler.
+    pub multiplier: f32,
+    /// How quickly the penalty grows with the length of the repeated sequence.
+    pub base: f32,
+    /// The length of the longest sequence that can be repeated without a penalty.
+    pub allowed_length: usize,
+    /// The number of previous tokens to search for repeated sequences, or 0 to search all
+    /// of them.
+    pub last_n: usize,
+}
+impl Default for SampleDry {
+    fn default() -> Self {
+        Self {
+            multiplier: 0.8,
+            base: 1.75,
+            allowed_length: 2,
+            last_n: 0,
+        }
+    }
+}
+impl SampleDry {
+    /// Longer repeated sequences are penalized as much as sequences of this length, which
+    /// bounds the time taken to find them.
+    const MAX_MATCH_LENGTH: usize = 64;
+
+    /// The penalty for each token that would continue a repeated sequence at the end of
+    /// `tokens`.
+    pub fn penalties(&self, tokens: &[TokenId]) -> HashMap<TokenId, f32> {
+        let tokens = match self.last_n {
+            0 => tokens,
+            last_n => &tokens[tokens.len().saturating_sub(last_n)..],
+        };
+        let mut match_lengths: HashMap<TokenId, usize> = HashMap::new();
+        let Some(last) = tokens.len().checked_sub(1) else {
+            return HashMap::new();
+        };
+
+        // For each earlier position, find how many of the tokens up to it match the tokens
+        // at the end; the token after it would continue that sequence.
+        for i in 0..last {
+            let mut length = 0;
+            while length <= i
+                && length < Self::MAX_MATCH_LENGTH
+                && tokens[i - length] == tokens[last - length]
+            {
+                length += 1;
+            }
+            if length > 0 && length >= self.allowed_length {
+                let longest = match_lengths.entry(tokens[i + 1]).or_default();
+                *longest = (*longest).max(length);
+            }
+        }
+
+        match_lengths
+            .into_iter()
+            .map(|(token, length)| {
+                let exponent = (length - self.allowed_length) as f32;
+                (token, self.multiplier * self.base.powf(exponent))
+            })
+            .collect()
+    }
+}
+impl Sampler for SampleDry {
+    fn sample<'a>(
+        &mut self,
+        res: &mut dyn HasSamplerResources,
+        logits: &'a mut Logits,
+    ) -> anyhow::Result<&'a mut Logits> {
+        if self.multiplier == 0.0 {
+            return Ok(logits);
+        }
+
+        let mut penalties = HashMap::new();
+        res.with_last_tokens(&mut |tokens| penalties = self.penalties(tokens))?;
+        for logit in logits.iter_mut() {
+            if let Some(&penalty) = penalties.get(&logit.token_id) {
+                logit.logit -= penalty;
+            }
+        }
+        Ok(logits)
+    }
+}
+impl FromStr for SampleDry {
+    type Err = String;
+
+    /// Parses the options of the sampler, as described in [parse_options].
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut result = Self::default();
+        for (key, value) in parse_options(s)? {
+            match key.as_str() {
+                "multiplier" => result.multiplier = parse_value(&key, value)?,
+                "base" => result.base = parse_value(&key, value)?,
+                "allowedlength" => result.allowed_length = parse_value(&key, value)?,
+                "lastn" => result.last_n = parse_value(&key, value)?,
+                _ => return Err(format!("unknown option {key:?}")),
+            }
+        }
+        Ok(result)
+    }
+}
+
+/// Prevents any n-gram of `size` tokens from appearing twice, by masking out every token
+/// that would complete an n-gram that already appeared. This is the same as the
+/// `no_repeat_ngram_size` option of Hugging Face Transformers.
+///
+/// Configured from a string as `no_repeat_ngram:size=3`, or `no_repeat_ngram:3`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, serde::Serialize, serde::Deserialize)]
+pub struct SampleNoRepeatNgram {
+    /// The size of the n-grams that cannot be repeated. 0 disables the sampler.
+    pub size: usize,
+}
+impl SampleNoRepeatNgram {
+    /// The tokens that would complete an n-gram that already appeared in `tokens`.
+    pub fn banned_tokens(&self, tokens: &[TokenId]) -> HashSet<TokenId> {
+        let n = self.size;
+        if n == 0 || tokens.len() < n {
+            return HashSet::new();
+        }
+
+        let prefix = &tokens[tokens.len() + 1 - n..];
+        tokens
+            .windows(n)
+            .filter(|ngram| ngram.starts_with(prefix))
+            .map(|ngram| ngram[n - 1])
+            .collect()
+    }
+}
+impl Sampler for SampleNoRepeatNgram {
+    fn sample<'a>(
+        &mut self,
+        res: &mut dyn HasSamplerResources,
+        logits: &'a mut Logits,
+    ) -> anyhow::Result<&'a mut Logits> {
+        let mut banned = HashSet::new();
+        res.with_last_tokens(&mut |tokens| banned = self.banned_tokens(tokens))?;
+        for logit in logits.iter_mut() {
+            if banned.contains(&logit.token_id) {
+                logit.logit = f32::NEG_INFINITY;
+            }
+        }
+        Ok(logits)
+    }
+}
+impl FromStr for SampleNoRepeatNgram {
+    type Err = String;
+
+    /// Parses the options of the sampler, as described in [parse_options]. As `size` is
+    /// the only option, it can also be given without its key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            size: parse_single_option(s, "size")?,
+        })
+    }
+}
+
+/// Epsilon sampling, which removes the tokens whose probability is below `epsilon`.
+/// See <https://arxiv.org/abs/2210.15191>.
+///
+/// Configured from a string as `epsilon:epsilon=0.0003`, or `epsilon:0.0003`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SampleEpsilon {
+    /// The minimum probability of a token. 3e-4 is a reasonable value.
+    pub epsilon: f32,
+}
+impl Sampler for SampleEpsilon {
+    fn sample<'a>(
+        &mut self,
+        _res: &mut dyn HasSamplerResources,
+        logits: &'a mut Logits,
+    ) -> anyhow::Result<&'a mut Logits> {
+        remove_improbable_tokens(logits, |_| self.epsilon);
+        Ok(logits)
+    }
+}
+impl FromStr for SampleEpsilon {
+    type Err = String;
+
+    /// Parses the options of the sampler, as described in [parse_options]. As `epsilon` is
+    /// the only option, it can also be given without its key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            epsilon: parse_single_option(s, "epsilon")?,
+        })
+    }
+}
+impl<UI: ConfigurableNumValue, F: ConfigurableNumValue> ConfigurableSampler<UI, F>
+    for SampleEpsilon
+{
+}
+impl<UI: ConfigurableNumValue, F: ConfigurableNumValue> HasSamplerMetadata<UI, F>
+    for SampleEpsilon
+{
+    fn sampler_metadata(&self) -> SamplerMetadata {
+        SamplerMetadata {
+            name: "epsilon",
+            description: Some("Removes the tokens whose probability is below a fixed threshold."),
+            options: vec![],
+        }
+    }
+}
+
+/// Eta sampling, which removes the tokens whose probability is below
+/// `min(eta, sqrt(eta) * exp(-entropy))`, so that fewer tokens are removed when the model
+/// is uncertain. See <https://arxiv.org/abs/2210.15191>.
+///
+/// Configured from a string as `eta:eta=0.0009`, or `eta:0.0009`.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub struct SampleEta {
+    /// The largest minimum probability of a token. Values from 3e-4 to 2e-3 are reasonable.
+    pub eta: f32,
+}
+impl SampleEta {
+    /// The minimum probability of a token, given the probabilities of all tokens.
+    pub fn threshold(&self, probabilities: &[f32]) -> f32 {
+        let entropy: f32 = probabilities
+            .iter()
+            .filter(|p| **p > 0.0)
+            .map(|p| -p * p.ln())
+            .sum();
+        self.eta.min(self.eta.sqrt() * (-entropy).exp())
+    }
+}
+impl Sampler for SampleEta {
+    fn sample<'a>(
+        &mut self,
+        _res: &mut dyn HasSamplerResources,
+        logits: &'a mut Logits,
+    ) -> anyhow::Result<&'a mut Logits> {
+        remove_improbable_tokens(logits, |probabilities| self.threshold(probabilities));
+        Ok(logits)
+    }
+}
+impl FromStr for SampleEta {
+    type Err = String;
+
+    /// Parses the options of the sampler, as described in [parse_options]. As `eta` is the
+    /// only option, it can also be given without its key.
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Ok(Self {
+            eta: parse_single_option(s, "eta")?,
+        })
+    }
+}
+impl<UI: ConfigurableNumValue, F: ConfigurableNumValue> ConfigurableSampler<UI, F> for SampleEta {}
+impl<UI: ConfigurableNumValue, F: ConfigurableNumValue> HasSamplerMetadata<UI, F> for SampleEta {
+    fn sampler_metadata(&self) -> SamplerMetadata {
+        SamplerMetadata {
+            name: "eta",
+            description: Some("Removes the tokens whose probability is below a threshold that depends on the entropy."),
+            options: vec![],
+        }
+    }
+}
+
+/// Removes the tokens whose probability is below the threshold computed from the
+/// probabilities of all tokens. The most probable token is always kept.
+fn remove_improbable_tokens(logits: &mut Logits, threshold: impl FnOnce(&[f32]) -> f32) {
+    let max = logits
+        .iter()
+        .map(|l| l.logit)
+        .fold(f32::NEG_INFINITY, f32::max);
+    if !max.is_finite() {
+        return;
+    }
+    let mut probabilities: Vec<f32> = logits.iter().map(|l| (l.logit - max).exp()).collect();
+    let sum: f32 = probabilities.iter().sum();
+    probabilities.iter_mut().for_each(|p| *p /= sum);
+
+    let threshold = threshold(&probabilities);
+    for (logit, probability) in logits.iter_mut().zip(probabilities) {
+        // The most probable token has a probability of at least `1 / n_vocab`, but that can
+        // still be below the threshold, so it is kept explicitly.
+        if probability < threshold && logit.logit < max {
+            logit.logit = f32::NEG_INFINITY;
+        }
+    }
+}
+
+/// Parses the options of a sampler that only has the option `key`, which can also be given
+/// without its key.
+fn parse_single_option<T: FromStr>(s: &str, key: &str) -> Result<T, String> {
+    if let Ok(value) = s.trim().parse() {
+        return Ok(value);
+    }
+
+    let mut result = None;
+    for (k, value) in parse_options(s)? {
+        if k != key {
+            return Err(format!("unknown option {k:?}"));
+        }
+        result = Some(parse_value(&k, value)?);
+    }
+    result.ok_or_else(|| format!("missing option {key}"))
+}
+
+/// Splits the options of a sampler that is configured by `llm` rather than `llm-samplers`
+/// into keys and values. The options are `key=value`, separated by colons; underscores
+/// and dashes in keys are ignored.
+fn parse_options(s: &str) -> Result<Vec<(String, &str)>, String> {
+    s.split(':')
+        .map(str::trim)
+        .filter(|o| !o.is_empty())
+        .map(|option| {
+            let (key, value) = option
+                .split_once('=')
+                .ok_or_else(|| format!("expected key=value, got {option:?}"))?;
+            let key = key.chars().filter(|c| *c != '_' && *c != '-').collect();
+            Ok((key, value))
+        })
+        .collect()
+}
+
+fn parse_value<T: FromStr>(key: &str, value: &str) -> Result<T, String> {
+    value
+        .parse()
+        .map_err(|_| format!("invalid value for {key}: {value:?}"))
+}
+
+/// How a [SampleRepetitionDecay] penalty decreases for tokens that appeared longer ago.
+#[derive(Debug, Clone, Copy, PartialEq, serde::Serialize, serde::Deserialize)]
+pub enum RepetitionDecay {
+    /// Every token in the window is penalized equally.
+    None,
+    /// The penalty decreases linearly to no penalty at the end of the window.
+    Linear,
+    /// The penalty halves every `half_life` tokens.
+    Exponential {
+        /// The number of tokens after which the penalty is halved.
+        half_life: f32,
+    },
+}
+impl RepetitionDecay {
+    /// How much of the penalty applies to a token `distance` tokens ago, from 1.0 for the
+    /// most recent token to 0.0, in a window of `last_n` tokens.
+    pub fn weight(&self, distance: usize, last_n: usize) -> f32 {
+        if distance >= last_n {
+            return 0.0;
+        }
+        match *self {
+            RepetitionDecay::None => 1.0,
+            RepetitionDecay::Linear => 1.0 - distance as f32 / last_n as f32,
+            RepetitionDecay::Exponential { half_life } => 0.5f32.powf(distance as f32 / half_life),
+        }
+    }
+}
+
 /// Get the default sampler chain.
 pub fn default_samplers() -> Arc<Mutex<dyn Sampler>> {
     let mut result = ConfiguredSamplers::default();
@@ -350,6 +1019,209 @@ pub fn default_samplers() -> Arc<Mutex<dyn Sampler>> {
     Arc::new(Mutex::new(result.builder.into_chain()))
 }
 
+/// Builds [InferenceParameters](crate::InferenceParameters) from the common sampling
+/// options, checking that they are valid. Created with
+/// [InferenceParameters::builder](crate::InferenceParameters::builder).
+///
+/// The defaults are those of the default sampler chain (see [ConfiguredSamplers]):
+///
+/// - temperature: 0.8
+/// - top-k: 40
+/// - top-p: 0.95
+/// - min-p: 0.0 (disabled)
+/// - repetition penalty: 1.30, over the last 64 tokens
+///
+/// For the other samplers, or to change their order, build the sampler with
+/// [build_sampler_with_order] and set [InferenceParameters::sampler](crate::InferenceParameters::sampler)
+/// instead.
+///
+/// The options can be serialized, e.g. to store them in a preset file, and deserialized
+/// from an object with the same fields as the setters, where missing fields keep their
+/// defaults: `{"temperature": 0.7, "top_p": 0.9, "token_bias": [[2, -1.0]]}`. The logits
+/// processors are not serialized.
+#[derive(Debug, Clone, serde::Serialize, serde::Deserialize)]
+#[serde(default)]
+pub struct InferenceParametersBuilder {
+    temperature: f32,
+    top_k: usize,
+    top_p: f32,
+    min_p: f32,
+    repetition_penalty: f32,
+    repetition_last_n: usize,
+    #[serde(rename = "token_bias")]
+    bias: Vec<(TokenId, f32)>,
+    #[serde(skip)]
+    logits_processors: Vec<Arc<Mutex<dyn LogitsProcessor>>>,
+}
+impl Default for InferenceParametersBuilder {
+    fn default() -> Self {
+        Self {
+            temperature: 0.8,
+            top_k: 40,
+            top_p: 0.95,
+            min_p: 0.0,
+            repetition_penalty: 1.30,
+            repetition_last_n: 64,
+            bias: vec![],
+            logits_processors: vec![],
+        }
+    }
+}
+impl InferenceParametersBuilder {
+    /// Sets the temperature, which must be greater than 0. Higher values make the output
+    /// more random; use [Self::top_k] with 1 for greedy sampling.
+    pub fn temperature(mut self, temperature: f32) -> Self {
+        self.temperature = temperature;
+        self
+    }
+
+    /// Sets the number of most probable tokens to sample from, which must be at least 1.
+    pub fn top_k(mut self, k: usize) -> Self {
+        self.top_k = k;
+        self
+    }
+
+    /// Sets the cumulative probability of the most probable tokens to sample from, which
+    /// must be greater than 0 and at most 1. 1 disables the sampler.
+    pub fn top_p(mut self, p: f32) -> Self {
+        self.top_p = p;
+        self
+    }
+
+    /// Sets the minimum probability of a token to be sampled, relative to the most
+    /// probable token, from 0 to 1. 0 disables the sampler.
+    pub fn min_p(mut self, p: f32) -> Self {
+        self.min_p = p;
+        self
+    }
+
+    /// Sets the penalty for repeating tokens, which must be greater than 0. 1 disables
+    /// the penalty.
+    pub fn repetition_penalty(mut self, penalty: f32) -> Self {
+        self.repetition_penalty = penalty;
+        self
+    }
+
+    /// Sets the number of most recent tokens the repetition penalty applies to.
+    pub fn repetition_last_n(mut self, last_n: usize) -> Self {
+        self.repetition_last_n = last_n;
+        self
+    }
+
+    /// Adds `bias` to the logit of `token` before sampling.
+    pub fn token_bias(mut self, token: TokenId, bias: f32) -> Self {
+        self.bias.push((token, bias));
+        self
+    }
+
+    /// Adds `processor` to the end of the
+    /// [logits_processors](crate::InferenceParameters::logits_processors).
+    pub fn logits_processor(mut self, processor: impl LogitsProcessor + 'static) -> Self {
+        self.logits_processors.push(Arc::new(Mutex::new(processor)));
+        self
+    }
+
+    /// Returns the token biases added with [Self::token_bias].
+    pub fn token_biases(&self) -> &[(TokenId, f32)] {
+        &self.bias
+    }
+
+    /// Returns the sampler configuration strings (see [ConfiguredSamplers]) of the options
+    /// that differ from their defaults. The others are left to the sampler chain, which has
+    /// the same defaults.
+    pub fn sampler_options(&self) -> Vec<String> {
+        let defaults = Self::default();
+        let mut options = vec![];
+        if self.temperature != defaults.temperature {
+            options.push(format!("temperature:temperature={}", self.temperature));
+        }
+        if self.top_k != defaults.top_k {
+            options.push(format!("topk:k={}", self.top_k));
+        }
+        if self.top_p != defaults.top_p {
+            options.push(format!("topp:p={}", self.top_p));
+        }
+        if self.min_p != defaults.min_p {
+            options.push(format!("minp:p={}", self.min_p));
+        }
+        if self.repetition_penalty != defaults.repetition_penalty
+            || self.repetition_last_n != defaults.repetition_last_n
+        {
+            let mut option = "repetition".to_string();
+            if self.repetition_penalty != defaults.repetition_penalty {
+                option += &format!(":penalty={}", self.repetition_penalty);
+            }
+            if self.repetition_last_n != defaults.repetition_last_n {
+                option += &format!(":last_n={}", self.repetition_last_n);
+            }
+            options.push(option);
+        }
+        options
+    }
+
+    /// Checks the options and builds the parameters.
+    pub fn build(self) -> Result<crate::InferenceParameters, SamplerConfigurationError> {
+        // The vocabulary size is only used by Mirostat, which is not configured here.
+        self.build_with_samplers(0, &[] as &[&str], &[] as &[&str])
+    }
+
+    /// Like [Self::build], but with the other `samplers` (as sampler configuration strings)
+    /// after the options, and the samplers run in `order` (see [build_sampler_with_order]).
+    /// `n_vocab` is the number of tokens in the vocabulary of the model, which Mirostat 1
+    /// needs.
+    pub(crate) fn build_with_samplers(
+        self,
+        n_vocab: usize,
+        samplers: &[impl AsRef<str>],
+        order: &[impl AsRef<str>],
+    ) -> Result<crate::InferenceParameters, SamplerConfigurationError> {
+        let check = |name: &str, valid: bool, requirement: &str| {
+            if valid {
+                Ok(())
+            } else {
+                Err(SamplerConfigurationError::BuildSamplerError {
+                    name: name.to_string(),
+                    err: requirement.into(),
+                })
+            }
+        };
+        check(
+            "temperature",
+            self.temperature.is_finite() && self.temperature > 0.0,
+            "the temperature must be greater than 0",
+        )?;
+        check("topk", self.top_k >= 1, "k must be at least 1")?;
+        check(
+            "topp",
+            self.top_p > 0.0 && self.top_p <= 1.0,
+            "p must be greater than 0 and at most 1",
+        )?;
+        check(
+            "minp",
+            (0.0..=1.0).contains(&self.min_p),
+            "p must be from 0 to 1",
+        )?;
+        check(
+            "repetition",
+            self.repetition_penalty.is_finite() && self.repetition_penalty > 0.0,
+            "the penalty must be greater than 0",
+        )?;
+        check(
+            "bias",
+            self.bias.iter().all(|(_, bias)| !bias.is_nan()),
+            "a token bias is not a number",
+        )?;
+
+        let mut options = self.sampler_options();
+        options.extend(samplers.iter().map(|sampler| sampler.as_ref().to_string()));
+        let mut parameters = crate::InferenceParameters::new(build_sampler_with_order(
+            n_vocab, &self.bias, &options, order,
+        )?);
+        parameters.logits_processors = self.logits_processors;
+        Ok(parameters)
+    }
+}
+
 // Structure used to temporarily hold resources for the `llm-samplers`
 // sampler.
 struct SamplerResources<'pt, 'r> {
@@ -380,3 +1252,286 @@ impl<'pt, 'r> HasSamplerResources for SamplerResources<'pt, 'r> {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_suppress_tokens() {
+        let mut logits = vec![1.0; 3];
+        SuppressTokens(vec![2, 7])
+            .process(&[], &mut logits)
+            .unwrap();
+        assert_eq!(logits, [1.0, 1.0, f32::NEG_INFINITY]);
+    }
+
+    #[test]
+    fn test_logits_processors_run_in_order() {
+        /// Bans the token after the last one, and records the logit it saw for token 0.
+        #[derive(Debug, Default)]
+        struct BanNext {
+            seen: Vec<f32>,
+        }
+        impl LogitsProcessor for BanNext {
+            fn process(
+                &mut self,
+                previous_tokens: &[TokenId],
+                logits: &mut [f32],
+            ) -> anyhow::Result<()> {
+                self.seen.push(logits[0]);
+                let next = previous_tokens.last().map_or(0, |t| *t as usize + 1);
+                logits[next] = f32::NEG_INFINITY;
+                Ok(())
+            }
+        }
+
+        let first = Arc::new(Mutex::new(BanNext::default()));
+        let second = Arc::new(Mutex::new(BanNext::default()));
+        let processors: Vec<Arc<Mutex<dyn LogitsProcessor>>> = vec![first.clone(), second.clone()];
+
+        let mut logits = vec![1.0; 4];
+        process_logits(&processors, &[], &mut logits).unwrap();
+        assert_eq!(logits, [f32::NEG_INFINITY, 1.0, 1.0, 1.0]);
+        assert_eq!(first.lock().unwrap().seen, [1.0]);
+        assert_eq!(second.lock().unwrap().seen, [f32::NEG_INFINITY]);
+
+        let mut logits = vec![1.0; 4];
+        process_logits(&processors, &[1], &mut logits).unwrap();
+        assert_eq!(logits, [1.0, 1.0, f32::NEG_INFINITY, 1.0]);
+    }
+
+    #[test]
+    fn test_inference_parameters_builder() {
+        use rand::SeedableRng;
+
+        let parameters = crate::InferenceParameters::builder()
+            .temperature(0.7)
+            .top_k(1)
+            .top_p(0.9)
+            .min_p(0.05)
+            .logits_processor(SuppressTokens(vec![1]))
+            .build()
+            .unwrap();
+        assert_eq!(parameters.logits_processors.len(), 1);
+        // Only the options that differ from the defaults of the sampler chain are set.
+        assert_eq!(
+            crate::InferenceParameters::builder()
+                .temperature(0.7)
+                .repetition_last_n(128)
+                .sampler_options(),
+            ["temperature:temperature=0.7", "repetition:last_n=128"]
+        );
+
+        // With top-k at 1, the most probable token is always sampled.
+        let mut rng = rand::rngs::StdRng::seed_from_u64(0);
+        for _ in 0..8 {
+            let token =
+                sample_token(parameters.sampler.clone(), &mut rng, &[], [0.0, 5.0, 1.0]).unwrap();
+            assert_eq!(token, 1);
+        }
+
+        let invalid = |builder: InferenceParametersBuilder| match builder.build() {
+            Err(SamplerConfigurationError::BuildSamplerError { name, .. }) => name,
+            other => panic!("expected an invalid option, got {other:?}"),
+        };
+        let builder = crate::InferenceParameters::builder();
+        assert_eq!(invalid(builder.clone().temperature(0.0)), "temperature");
+        assert_eq!(
+            invalid(builder.clone().temperature(f32::NAN)),
+            "temperature"
+        );
+        assert_eq!(invalid(builder.clone().top_k(0)), "topk");
+        assert_eq!(invalid(builder.clone().top_p(1.5)), "topp");
+        assert_eq!(invalid(builder.clone().min_p(-0.1)), "minp");
+        assert_eq!(invalid(builder.repetition_penalty(0.0)), "repetition");
+    }
+
+    #[test]
+    fn test_serde_sampler_configs() {
+        // Missing options keep their defaults.
+        let builder: InferenceParametersBuilder =
+            serde_json::from_str(r#"{"temperature": 0.5, "token_bias": [[2, -1.0]]}"#).unwrap();
+        assert_eq!(builder.temperature, 0.5);
+        assert_eq!(builder.top_k, 40);
+        assert_eq!(builder.bias, [(2, -1.0)]);
+        let json = serde_json::to_string(&builder).unwrap();
+        let roundtrip: InferenceParametersBuilder = serde_json::from_str(&json).unwrap();
+        assert_eq!(roundtrip.temperature, 0.5);
+        assert_eq!(roundtrip.bias, builder.bias);
+
+        // Parameters are checked when they are deserialized.
+        assert!(serde_json::from_str::<crate::InferenceParameters>(r#"{"top_p": 0.9}"#).is_ok());
+        let err = serde_json::from_str::<crate::InferenceParameters>(r#"{"temperature": 0.0}"#)
+            .unwrap_err();
+        assert!(err.to_string().contains("temperature"));
+        assert!(serde_json::from_str::<crate::InferenceParameters>(r#"{"top_k": "a"}"#).is_err());
+
+        let decay = SampleRepetitionDecay {
+            decay: RepetitionDecay::Exponential { half_life: 32.0 },
+            ..Default::default()
+        };
+        let json = serde_json::to_string(&decay).unwrap();
+        assert_eq!(
+            serde_json::from_str::<SampleRepetitionDecay>(&json).unwrap(),
+            decay
+        );
+        assert_eq!(
+            serde_json::from_str::<SampleDry>(r#"{"multiplier": 0.5}"#).unwrap(),
+            SampleDry {
+                multiplier: 0.5,
+                ..Default::default()
+            }
+        );
+        assert_eq!(
+            serde_json::from_str::<SampleNoRepeatNgram>(r#"{"size": 3}"#).unwrap(),
+            SampleNoRepeatNgram { size: 3 }
+        );
+    }
+
+    #[test]
+    fn test_sampler_order() {
+        let names = |samplers: &mut ConfiguredSamplers| -> Vec<String> {
+            samplers
+                .builder
+                .iter_mut()
+                .map(|(name, _)| name.to_string())
+                .collect()
+        };
+
+        let mut reordered =
+            ConfiguredSamplers::with_order(&["temperature", "top_k", "Top-P"]).unwrap();
+        assert_eq!(
+            names(&mut reordered),
+            [
+                "repetition",
+                "freqpresence",
+                "seqrepetition",
+                "temperature",
+                "tailfree",
+                "locallytypical",
+                "topk",
+                "topa",
+                "minp",
+                "topp",
+                "mirostat1",
+                "mirostat2",
+            ]
+        );
+
+        let mut default = ConfiguredSamplers::with_order(&[] as &[&str]).unwrap();
+        assert_eq!(
+            names(&mut default),
+            names(&mut ConfiguredSamplers::default())
+        );
+
+        assert!(ConfiguredSamplers::with_order(&["mirostat1"]).is_err());
+        assert!(ConfiguredSamplers::with_order(&["top_k", "topk"]).is_err());
+    }
+
+    #[test]
+    fn test_repetition_decay_weights() {
+        assert_eq!(RepetitionDecay::None.weight(0, 4), 1.0);
+        assert_eq!(RepetitionDecay::None.weight(3, 4), 1.0);
+        assert_eq!(RepetitionDecay::None.weight(4, 4), 0.0);
+
+        assert_eq!(RepetitionDecay::Linear.weight(0, 4), 1.0);
+        assert_eq!(RepetitionDecay::Linear.weight(2, 4), 0.5);
+        assert_eq!(RepetitionDecay::Linear.weight(4, 4), 0.0);
+
+        let exponential = RepetitionDecay::Exponential { half_life: 2.0 };
+        assert_eq!(exponential.weight(0, 16), 1.0);
+        assert_eq!(exponential.weight(2, 16), 0.5);
+        assert_eq!(exponential.weight(4, 16), 0.25);
+        assert_eq!(exponential.weight(16, 16), 0.0);
+    }
+
+    #[test]
+    fn test_parse_repetition_decay() {
+        let sampler: SampleRepetitionDecay =
+            "penalty=1.5:last_n=128:decay=exponential".parse().unwrap();
+        assert_eq!(
+            sampler,
+            SampleRepetitionDecay {
+                penalty: 1.5,
+                last_n: 128,
+                decay: RepetitionDecay::Exponential { half_life: 32.0 },
+            }
+        );
+        assert_eq!(sampler.penalty_at(0), 1.5);
+        assert_eq!(sampler.penalty_at(32), 1.25);
+
+        assert!("decay=linear:half_life=4"
+            .parse::<SampleRepetitionDecay>()
+            .is_err());
+        assert!("window=4".parse::<SampleRepetitionDecay>().is_err());
+
+        let configured: ConfiguredSamplers = "repetition_decay:decay=none".parse().unwrap();
+        assert_eq!(configured.repetition_decay.len(), 1);
+    }
+
+    #[test]
+    fn test_epsilon_and_eta_remove_improbable_tokens() {
+        let mut logits = Logits::try_from_iter([2.0f32, 0.0, -10.0]).unwrap();
+        let mut rng = rand::rngs::mock::StepRng::new(0, 1);
+        let mut res = SamplerResources {
+            previous_tokens: &[],
+            rng: &mut rng,
+        };
+        SampleEpsilon { epsilon: 0.001 }
+            .sample(&mut res, &mut logits)
+            .unwrap();
+        let kept: Vec<f32> = logits.iter().map(|l| l.logit).collect();
+        assert_eq!(kept, [2.0, 0.0, f32::NEG_INFINITY]);
+
+        // With a uniform distribution over 4 tokens, the entropy is ln(4).
+        let eta = SampleEta { eta: 0.25 };
+        assert!((eta.threshold(&[0.25; 4]) - 0.125).abs() < 1e-6);
+        let eta = SampleEta { eta: 0.01 };
+        assert!((eta.threshold(&[0.25; 4]) - 0.01).abs() < 1e-6);
+
+        let configured: ConfiguredSamplers = "eta:0.0009 epsilon:epsilon=0.0003".parse().unwrap();
+        assert_eq!(configured.eta, Some(SampleEta { eta: 0.0009 }));
+        assert_eq!(configured.epsilon, Some(SampleEpsilon { epsilon: 0.0003 }));
+        assert!("eta:0.001 mirostat2".parse::<ConfiguredSamplers>().is_err());
+    }
+
+    #[test]
+    fn test_dry_penalizes_continuing_repeated_sequences() {
+        let dry = SampleDry::default();
+
+        // "1 2" repeats the start, so 3 would continue the repetition.
+        let penalties = dry.penalties(&[1, 2, 3, 1, 2]);
+        assert_eq!(penalties, HashMap::from([(3, dry.multiplier)]));
+
+        // Longer repetitions are penalized more.
+        let penalties = dry.penalties(&[1, 2, 3, 4, 1, 2, 3]);
+        assert_eq!(penalties, HashMap::from([(4, dry.multiplier * dry.base)]));
+
+        // Repetitions shorter than the allowed length are not penalized.
+        assert!(dry.penalties(&[1, 2, 3, 2]).is_empty());
+        assert!(dry.penalties(&[]).is_empty());
+
+        // Only the last `last_n` tokens are searched.
+        let dry = SampleDry { last_n: 3, ..dry };
+        assert!(dry.penalties(&[1, 2, 3, 1, 2]).is_empty());
+    }
+
+    #[test]
+    fn test_no_repeat_ngram_bans_completing_tokens() {
+        let trigrams: SampleNoRepeatNgram = "3".parse().unwrap();
+        assert_eq!(trigrams, "size=3".parse().unwrap());
+
+        // "1 2 3" and "1 2 4" appeared, so neither 3 nor 4 can follow "1 2" again.
+        assert_eq!(
+            trigrams.banned_tokens(&[1, 2, 3, 1, 2, 4, 1, 2]),
+            HashSet::from([3, 4])
+        );
+        assert!(trigrams.banned_tokens(&[1, 2, 3, 2, 1]).is_empty());
+        assert!(trigrams.banned_tokens(&[1]).is_empty());
+
+        // Unigrams ban every token that appeared.
+        let unigrams = SampleNoRepeatNgram { size: 1 };
+        assert_eq!(unigrams.banned_tokens(&[5, 6, 5]), HashSet::from([5, 6]));
+    }
+}
@@ -0,0 +1,132 @@
+//! Implements a pool of pre-allocated inference sessions ("slots") for serving many
+//! requests with one model, reusing the key/value memory of previous requests that
+//! share a prefix with a new one.
+
+use tracing::log;
+
+use crate::{InferenceSession, InferenceSessionConfig, Model, TokenId};
+
+/// A fixed number of pre-allocated [InferenceSession]s for one model.
+///
+/// Allocating the key/value memory of a session is expensive, so servers should
+/// allocate their sessions up-front and hand them out to requests as they arrive.
+/// When a request is assigned a slot, the slot whose previous occupant shares the
+/// longest prefix with the request's prompt is preferred, and that prefix is kept
+/// in the session so that it does not need to be evaluated again.
+pub struct SessionSlots {
+    config: InferenceSessionConfig,
+    slots: Vec<Option<InferenceSession>>,
+}
+
+/// A slot that has been assigned to a request with [SessionSlots::acquire].
+///
+/// It must be returned with [SessionSlots::release] once the request is done, so that
+/// the slot can be reused.
+pub struct AcquiredSlot {
+    index: usize,
+    /// The session of this slot.
+    pub session: InferenceSession,
+    /// The number of tokens of the prompt that are already in the session. Only the
+    /// remaining tokens (`&prompt_tokens[cached_tokens..]`) need to be fed to it.
+    pub cached_tokens: usize,
+}
+impl AcquiredSlot {
+    /// The index of this slot in its [SessionSlots].
+    pub fn index(&self) -> usize {
+        self.index
+    }
+}
+
+impl SessionSlots {
+    /// Allocates `n_slots` sessions for `model` with the given `config`. The context
+    /// length of each session is the [context size](crate::ModelParameters::context_size)
+    /// of the model.
+    pub fn new(model: &dyn Model, n_slots: usize, config: InferenceSessionConfig) -> Self {
+        Self {
+            config,
+            slots: (0..n_slots)
+                .map(|_| Some(model.start_session(config)))
+                .collect(),
+        }
+    }
+
+    /// The total number of slots.
+    pub fn len(&self) -> usize {
+        self.slots.len()
+    }
+
+    /// Returns whether there are no slots at all.
+    pub fn is_empty(&self) -> bool {
+        self.slots.is_empty()
+    }
+
+    /// The number of slots that are not currently assigned to a request.
+    pub fn n_free(&self) -> usize {
+        self.slots.iter().filter(|slot| slot.is_some()).count()
+    }
+
+    /// Assigns a free slot to a request that will feed `prompt_tokens`, or returns `None`
+    /// if all slots are in use.
+    ///
+    /// The free slot sharing the longest prefix with `prompt_tokens` is chosen. If the
+    /// model supports [rewinding](Model::supports_rewind), the session is rewound to
+    /// that prefix, and [AcquiredSlot::cached_tokens] reports its length; otherwise, the
+    /// session is reset unless it contains exactly a prefix of the prompt.
+    ///
+    /// At least one token of the prompt is always left to be fed, so that the session
+    /// has up-to-date logits once the prompt has been fed.
+    pub fn acquire(
+        &mut self,
+        model: &dyn Model,
+        prompt_tokens: &[TokenId],
+    ) -> Option<AcquiredSlot> {
+        let max_cached = prompt_tokens.len().saturating_sub(1);
+        let (index, prefix_len) = self
+            .slots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, slot)| {
+                let session = slot.as_ref()?;
+                Some((index, common_prefix_len(session.tokens(), prompt_tokens)))
+            })
+            .max_by_key(|&(index, prefix_len)| (prefix_len, std::cmp::Reverse(index)))?;
+
+        let mut session = self.slots[index].take()?;
+        let prefix_len = prefix_len.min(max_cached);
+        let surplus = session.tokens().len() - prefix_len;
+
+        let reusable = surplus == 0
+            || (prefix_len > 0
+                && model.supports_rewind()
+                && session.rewind(model, surplus).is_ok());
+        let cached_tokens = if reusable {
+            prefix_len
+        } else {
+            session = model.start_session(self.config);
+            0
+        };
+
+        log::trace!("Assigned slot {index}, reusing {cached_tokens} cached tokens");
+        Some(AcquiredSlot {
+            index,
+            session,
+            cached_tokens,
+        })
+    }
+
+    /// Returns a slot acquired with [Self::acquire], keeping its session so that its
+    /// tokens can be reused by later requests.
+    ///
+    /// # Panics
+    ///
+    /// - If the slot does not belong to this [SessionSlots], or was already released.
+    pub fn release(&mut self, slot: AcquiredSlot) {
+        let entry = &mut self.slots[slot.index];
+        assert!(entry.is_none(), "slot {} was already released", slot.index);
+        *entry = Some(slot.session);
+    }
+}
+
+fn common_prefix_len(a: &[TokenId], b: &[TokenId]) -> usize {
+    a.iter().zip(b).take_while(|(a, b)| a == b).count()
+}
@@ -0,0 +1,499 @@
+//! Implements splitting a model file into shards of a maximum size, which `load` reads
+//! transparently through a manifest, so that models can be hosted on services that limit
+//! the size of each file, and merging the shards back into one file.
+//!
+//! The shards are consecutive byte ranges of the model file, which are cut between tensors
+//! where possible. The manifest is a text file whose first line is `ggml-split` and the
+//! version, followed by a line for each shard with its length in bytes, two spaces, and
+//! its file name, relative to the manifest. Shards must be in the manifest's directory or
+//! below it, so their names cannot be absolute or contain `..`.
+
+use std::{
+    fs::{self, File},
+    io::{self, BufReader, Read, Seek, SeekFrom, Write},
+    path::{Component, Path, PathBuf},
+};
+
+use regex::Regex;
+use thiserror::Error;
+
+use crate::{
+    checksum::checksums_path, util, Hyperparameters, LoadError, ModelFile, TokenizerSource,
+};
+
+/// The start of the first line of a split model manifest.
+pub const SPLIT_MANIFEST_MAGIC: &str = "ggml-split";
+const VERSION: u32 = 1;
+
+#[derive(Error, Debug)]
+/// Errors encountered while splitting a model.
+pub enum SplitError {
+    #[error("could not load model")]
+    /// There was an error while attempting to read the model.
+    Load(#[from] LoadError),
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] io::Error),
+    #[error("the maximum size of a shard must be greater than 0")]
+    /// The maximum size of a shard was 0.
+    InvalidMaxSize,
+    #[error("{path:?} is neither a split model manifest nor named like a shard")]
+    /// The path given as a split model is not one.
+    NotSplit {
+        /// The path that was given.
+        path: PathBuf,
+    },
+    #[error("the shard {path:?} does not exist")]
+    /// One of the shards of a split model does not exist.
+    MissingShard {
+        /// The path of the shard.
+        path: PathBuf,
+    },
+    #[error("GGUF models split by llama.cpp cannot be merged, as each shard has its own header")]
+    /// The shards are of a GGUF model, whose shards each have their own header.
+    UnsupportedGguf,
+}
+
+/// A shard of a split model.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Shard {
+    /// The file name of the shard, relative to the manifest.
+    pub path: PathBuf,
+    /// The length of the shard in bytes.
+    pub len: u64,
+}
+
+/// The manifest of a split model, which lists its shards in order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SplitManifest {
+    /// The shards of the model.
+    pub shards: Vec<Shard>,
+}
+impl SplitManifest {
+    /// Reads the manifest at `path`, rejecting shards that are not relative paths within
+    /// its directory.
+    pub fn read(path: &Path) -> io::Result<Self> {
+        let invalid = |message: String| io::Error::new(io::ErrorKind::InvalidData, message);
+
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines().filter(|line| !line.trim().is_empty());
+        let version = lines
+            .next()
+            .and_then(|line| line.strip_prefix(SPLIT_MANIFEST_MAGIC))
+            .and_then(|version| version.trim().parse::<u32>().ok())
+            .ok_or_else(|| invalid(format!("{path:?} is not a split model manifest")))?;
+        if version != VERSION {
+            return Err(invalid(format!(
+                "unsupported split model manifest version {version}"
+            )));
+        }
+
+        lines
+            .map(|line| {
+                let shard = line
+                    .split_once("  ")
+                    .and_then(|(len, name)| {
+                        Some(Shard {
+                            path: PathBuf::from(name),
+                            len: len.parse().ok()?,
+                        })
+                    })
+                    .ok_or_else(|| invalid(format!("invalid shard line {line:?}")))?;
+                if !is_relative_file_path(&shard.path) {
+                    return Err(invalid(format!(
+                        "the shard {:?} is outside the directory of the manifest",
+                        shard.path
+                    )));
+                }
+                Ok(shard)
+            })
+            .collect::<io::Result<_>>()
+            .map(|shards| Self { shards })
+    }
+
+    /// Writes the manifest to `path`.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut contents = format!("{SPLIT_MANIFEST_MAGIC} {VERSION}\n");
+        for shard in &self.shards {
+            contents += &format!("{}  {}\n", shard.len, shard.path.display());
+        }
+        fs::write(path, contents)
+    }
+
+    /// The length of the model file in bytes.
+    pub fn len(&self) -> u64 {
+        self.shards.iter().map(|shard| shard.len).sum()
+    }
+
+    /// Whether the model file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// Splits the model at `source` into shards of at most `max_size` bytes, which are written
+/// next to the manifest at `manifest_path`. The shards are named after the manifest, with
+/// their number and the number of shards appended (e.g. `model.bin-00001-of-00003`).
+///
+/// Shards are cut between tensors, unless a tensor is larger than `max_size` on its own.
+/// If the model has stored checksums, they are copied for the manifest so that they are
+/// verified when the split model is loaded.
+pub fn split<Hp: Hyperparameters>(
+    source: &Path,
+    manifest_path: &Path,
+    max_size: u64,
+) -> Result<SplitManifest, SplitError> {
+    if max_size == 0 {
+        return Err(SplitError::InvalidMaxSize);
+    }
+
+    let model_file = ModelFile::<Hp>::open(source, TokenizerSource::Embedded)?;
+    let len = fs::metadata(source)?.len();
+
+    // The ends of the tensors are where the next tensor's header starts, so cutting there
+    // keeps each tensor's header with its data.
+    let mut boundaries: Vec<u64> = model_file
+        .tensors
+        .values()
+        .map(|info| info.start_offset + info.calc_size() as u64)
+        .chain([len])
+        .filter(|&boundary| boundary <= len)
+        .collect();
+    boundaries.sort_unstable();
+    boundaries.dedup();
+
+    let mut cuts = vec![0];
+    let mut start = 0;
+    let mut last = 0;
+    for boundary in boundaries {
+        if boundary - start > max_size {
+            if last > start {
+                start = last;
+                cuts.push(start);
+            }
+            while boundary - start > max_size {
+                start += max_size;
+                cuts.push(start);
+            }
+        }
+        last = boundary;
+    }
+    cuts.push(len);
+
+    let file_name = manifest_path
+        .file_name()
+        .map(|name| name.to_string_lossy().into_owned())
+        .unwrap_or_default();
+    let directory = manifest_path.parent().unwrap_or_else(|| Path::new(""));
+    let n_shards = cuts.len() - 1;
+
+    let mut reader = BufReader::new(File::open(source)?);
+    let mut shards = Vec::with_capacity(n_shards);
+    for (i, range) in cuts.windows(2).enumerate() {
+        let shard = Shard {
+            path: PathBuf::from(format!("{file_name}-{:05}-of-{n_shards:05}", i + 1)),
+            len: range[1] - range[0],
+        };
+        let mut writer = io::BufWriter::new(File::create(directory.join(&shard.path))?);
+        reader.seek(SeekFrom::Start(range[0]))?;
+        io::copy(&mut (&mut reader).take(shard.len), &mut writer)?;
+        writer.flush()?;
+        shards.push(shard);
+    }
+
+    let manifest = SplitManifest { shards };
+    manifest.write(manifest_path)?;
+    if checksums_path(source).exists() {
+        fs::copy(checksums_path(source), checksums_path(manifest_path))?;
+    }
+    Ok(manifest)
+}
+
+/// Finds the shards of the split model at `path`, in order.
+///
+/// `path` is either a manifest written by [split], or any shard of a model whose shards
+/// are named with their number and the number of shards, as in `model-00001-of-00003.bin`
+/// or `model.bin-00001-of-00003`. All of the shards must exist.
+pub fn find_shards(path: &Path) -> Result<Vec<PathBuf>, SplitError> {
+    if is_split_manifest(path)? {
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        return Ok(SplitManifest::read(path)?
+            .shards
+            .into_iter()
+            .map(|shard| directory.join(shard.path))
+            .collect());
+    }
+
+    let not_split = || SplitError::NotSplit {
+        path: path.to_owned(),
+    };
+    let file_name = path
+        .file_name()
+        .and_then(|name| name.to_str())
+        .ok_or_else(not_split)?;
+    let pattern = Regex::new(r"^(.*)-(\d+)-of-(\d+)(.*)$").unwrap();
+    let captures = pattern.captures(file_name).ok_or_else(not_split)?;
+    let (prefix, suffix) = (&captures[1], &captures[4]);
+    let width = captures[2].len();
+    let n_shards: usize = captures[3].parse().map_err(|_| not_split())?;
+
+    let paths: Vec<PathBuf> = (1..=n_shards)
+        .map(|i| path.with_file_name(format!("{prefix}-{i:0width$}-of-{}{suffix}", &captures[3])))
+        .collect();
+    if let Some(missing) = paths.iter().find(|path| !path.exists()) {
+        return Err(SplitError::MissingShard {
+            path: missing.clone(),
+        });
+    }
+    Ok(paths)
+}
+
+/// Reassembles the split model at `path` (see [find_shards]) into one file, written to
+/// `destination`. Returns the size of the model file.
+///
+/// Only models split byte-wise, like those written by [split], can be merged. GGUF models
+/// split by `llama.cpp` have a header in each shard, and are not supported.
+pub fn merge_shards(path: &Path, destination: &mut impl Write) -> Result<u64, SplitError> {
+    let paths = find_shards(path)?;
+    let mut reader = if is_split_manifest(path)? {
+        SplitReader::open(path)?
+    } else {
+        SplitReader::from_paths(&paths)?
+    };
+
+    let mut magic = [0; 4];
+    reader.read_exact(&mut magic)?;
+    if &magic == b"GGUF" {
+        return Err(SplitError::UnsupportedGguf);
+    }
+    reader.seek(SeekFrom::Start(0))?;
+
+    let len = io::copy(&mut reader, destination)?;
+    destination.flush()?;
+    Ok(len)
+}
+
+/// Whether the file at `path` is a split model manifest.
+pub fn is_split_manifest(path: &Path) -> io::Result<bool> {
+    let mut magic = [0; SPLIT_MANIFEST_MAGIC.len()];
+    match File::open(path)?.read_exact(&mut magic) {
+        Ok(()) => Ok(magic == SPLIT_MANIFEST_MAGIC.as_bytes()),
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+        Err(err) => Err(err),
+    }
+}
+
+/// Whether `path` is a relative path that stays within the directory it is relative to,
+/// which is to say that it names a file and consists only of file and directory names and `.`.
+fn is_relative_file_path(path: &Path) -> bool {
+    path.components()
+        .any(|component| matches!(component, Component::Normal(_)))
+        && path
+            .components()
+            .all(|component| matches!(component, Component::Normal(_) | Component::CurDir))
+}
+
+/// Reads the model file that was split into the shards of a manifest, as if it were one file.
+pub struct SplitReader {
+    /// The shards, with the offset in the model file that each starts at.
+    shards: Vec<(u64, u64, File)>,
+    len: u64,
+    position: u64,
+}
+impl SplitReader {
+    /// Opens the shards of the manifest at `path`, checking that they have the lengths
+    /// that the manifest lists.
+    pub fn open(path: &Path) -> io::Result<Self> {
+        let manifest = SplitManifest::read(path)?;
+        let directory = path.parent().unwrap_or_else(|| Path::new(""));
+        Self::open_shards(
+            manifest
+                .shards
+                .iter()
+                .map(|shard| (directory.join(&shard.path), Some(shard.len))),
+        )
+    }
+
+    /// Opens the shards at `paths`, in order.
+    pub fn from_paths(paths: &[PathBuf]) -> io::Result<Self> {
+        Self::open_shards(paths.iter().map(|path| (path.clone(), None)))
+    }
+
+    fn open_shards(paths: impl Iterator<Item = (PathBuf, Option<u64>)>) -> io::Result<Self> {
+        let mut shards = vec![];
+        let mut offset = 0;
+        for (shard_path, expected_len) in paths {
+            let file = File::open(&shard_path)?;
+            let len = file.metadata()?.len();
+            if let Some(expected_len) = expected_len.filter(|&expected_len| expected_len != len) {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    format!(
+                        "the shard {shard_path:?} is {len} bytes long, but should be \
+                         {expected_len} bytes"
+                    ),
+                ));
+            }
+            shards.push((offset, len, file));
+            offset += len;
+        }
+
+        Ok(Self {
+            shards,
+            len: offset,
+            position: 0,
+        })
+    }
+
+    /// The length of the model file in bytes.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    /// Whether the model file is empty.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+impl Read for SplitReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.position >= self.len || buf.is_empty() {
+            return Ok(0);
+        }
+        let position = self.position;
+        let index = self
+            .shards
+            .partition_point(|(offset, len, _)| offset + len <= position);
+        let (offset, len, file) = &mut self.shards[index];
+        let local = position - *offset;
+        let n = (buf.len() as u64).min(*len - local) as usize;
+        file.seek(SeekFrom::Start(local))?;
+        let n = file.read(&mut buf[..n])?;
+        self.position += n as u64;
+        Ok(n)
+    }
+}
+impl Seek for SplitReader {
+    fn seek(&mut self, pos: SeekFrom) -> io::Result<u64> {
+        self.position = util::seek_position(self.position, self.len, pos)?;
+        Ok(self.position)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        test_model::{write_test_file, TestHyperparameters as Hp},
+        test_vocabulary, Loader, Tokenizer,
+    };
+    use ggml::format::SaveContainerType;
+
+    #[test]
+    fn test_manifest_round_trip() {
+        let path = std::env::temp_dir().join(format!("llm-manifest-{}", std::process::id()));
+        let manifest = SplitManifest {
+            shards: vec![
+                Shard {
+                    path: PathBuf::from("model.bin-00001-of-00002"),
+                    len: 4096,
+                },
+                Shard {
+                    path: PathBuf::from("model.bin-00002-of-00002"),
+                    len: 17,
+                },
+            ],
+        };
+        manifest.write(&path).unwrap();
+        assert!(is_split_manifest(&path).unwrap());
+        assert_eq!(SplitManifest::read(&path).unwrap(), manifest);
+        assert_eq!(manifest.len(), 4113);
+
+        for shard in ["../model.bin", "shards/../../model.bin", "/etc/model.bin"] {
+            fs::write(&path, format!("ggml-split 1\n10  {shard}\n")).unwrap();
+            assert_eq!(
+                SplitManifest::read(&path).unwrap_err().kind(),
+                io::ErrorKind::InvalidData,
+                "{shard}"
+            );
+        }
+        fs::write(&path, "ggml-split 1\n10  shards/model.bin\n").unwrap();
+        assert!(SplitManifest::read(&path).is_ok());
+
+        fs::write(&path, "ggml-split 2\n").unwrap();
+        assert!(SplitManifest::read(&path).is_err());
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn test_find_and_merge_numbered_shards() {
+        let directory = std::env::temp_dir();
+        let name = |i: usize| format!("llm-shards-{}-{i:05}-of-00003.bin", std::process::id());
+        for i in 1..=3 {
+            fs::write(directory.join(name(i)), [i as u8; 10]).unwrap();
+        }
+
+        let paths = find_shards(&directory.join(name(2))).unwrap();
+        assert_eq!(
+            paths,
+            (1..=3).map(|i| directory.join(name(i))).collect::<Vec<_>>()
+        );
+
+        let mut merged = vec![];
+        assert_eq!(
+            merge_shards(&directory.join(name(1)), &mut merged).unwrap(),
+            30
+        );
+        assert_eq!(&merged[..11], &[1, 1, 1, 1, 1, 1, 1, 1, 1, 1, 2]);
+
+        fs::remove_file(directory.join(name(3))).unwrap();
+        assert!(matches!(
+            find_shards(&directory.join(name(1))),
+            Err(SplitError::MissingShard { .. })
+        ));
+        let unsplit = directory.join(format!("llm-unsplit-{}.bin", std::process::id()));
+        fs::write(&unsplit, [0; 10]).unwrap();
+        assert!(matches!(
+            find_shards(&unsplit),
+            Err(SplitError::NotSplit { .. })
+        ));
+        fs::remove_file(&unsplit).unwrap();
+        for i in 1..=2 {
+            fs::remove_file(directory.join(name(i))).unwrap();
+        }
+    }
+
+    #[test]
+    fn test_split_model() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("unsplit.bin");
+        let manifest_path = dir.path().join("split.bin");
+        write_test_file(&path, SaveContainerType::GgjtV3);
+
+        let len = fs::metadata(&path).unwrap().len();
+        let manifest = split::<Hp>(&path, &manifest_path, len / 3).unwrap();
+        assert!(manifest.shards.len() >= 3);
+        assert!(manifest.shards.iter().all(|shard| shard.len <= len / 3));
+        assert_eq!(manifest.len(), len);
+
+        let mut joined = vec![];
+        SplitReader::open(&manifest_path)
+            .unwrap()
+            .read_to_end(&mut joined)
+            .unwrap();
+        assert_eq!(joined, fs::read(&path).unwrap());
+        let mut merged = vec![];
+        merge_shards(&manifest_path, &mut merged).unwrap();
+        assert_eq!(merged, joined);
+
+        // The model is read from the shards as if they were one file.
+        let mut loader = Loader::<Hp, _>::new(Tokenizer::empty_embedded(), |_| {});
+        ggml::format::load(
+            &mut BufReader::new(SplitReader::open(&manifest_path).unwrap()),
+            &mut loader,
+        )
+        .unwrap();
+        assert_eq!(loader.tokenizer.len(), test_vocabulary().len());
+        assert_eq!(loader.tensors.len(), 8);
+    }
+}
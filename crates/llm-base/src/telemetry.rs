@@ -0,0 +1,79 @@
+//! Hooks for a host application to collect metrics about the models it loads and the
+//! inference done with them.
+
+use std::{fmt, path::Path, time::Duration};
+
+use crate::{InferenceStats, TokenId};
+
+/// Receives events about the loading of a model and the inference done with it, so that
+/// an application embedding `llm` can collect metrics without wrapping every call.
+///
+/// A sink is registered for a model with [ModelParameters::telemetry](crate::ModelParameters::telemetry),
+/// which also registers it for the sessions started from the model, or for a single
+/// session with [InferenceSession::set_telemetry_sink](crate::InferenceSession::set_telemetry_sink).
+/// Every method does nothing by default, so that a sink only needs to implement the
+/// events it is interested in.
+///
+/// The methods are called on the thread doing the work, in the middle of it, so they
+/// should return quickly: send the event elsewhere or update a counter, instead of
+/// doing I/O.
+pub trait TelemetrySink: Send + Sync {
+    /// Called when a model has finished loading.
+    fn model_loaded(&self, _event: &ModelLoadedEvent) {}
+
+    /// Called when a prompt has been fed to a session, by
+    /// [InferenceSession::feed_prompt](crate::InferenceSession::feed_prompt) or as part
+    /// of an inference.
+    fn prompt_fed(&self, _event: &PromptFedEvent) {}
+
+    /// Called for each token a session generates, including the end-of-text token.
+    fn token_generated(&self, _event: &TokenGeneratedEvent) {}
+
+    /// Called when [InferenceSession::infer](crate::InferenceSession::infer) or
+    /// [InferenceSession::infill](crate::InferenceSession::infill) has finished
+    /// generating, with the statistics it returns.
+    fn generation_finished(&self, _stats: &InferenceStats) {}
+
+    /// Called when feeding a prompt, an inference or an infill fails. Errors loading a
+    /// model are only returned by the function loading it.
+    fn error(&self, _error: &(dyn std::error::Error + 'static)) {}
+}
+impl fmt::Debug for dyn TelemetrySink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("TelemetrySink")
+    }
+}
+
+/// A model finished loading (see [TelemetrySink::model_loaded]).
+#[derive(Debug, Clone, Copy)]
+pub struct ModelLoadedEvent<'a> {
+    /// The path of the model, or the name it was loaded with if it was not loaded from a
+    /// file.
+    pub path: &'a Path,
+    /// The size of the model in bytes.
+    pub file_size: u64,
+    /// The number of tensors in the model.
+    pub tensor_count: usize,
+    /// How long it took to load the tensors of the model, after its header was read.
+    pub duration: Duration,
+}
+
+/// A prompt was fed to a session (see [TelemetrySink::prompt_fed]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PromptFedEvent {
+    /// The number of tokens that were fed. This is less than the number of tokens in the
+    /// prompt if feeding it was halted, and does not include the tokens dropped when it
+    /// was truncated.
+    pub tokens: usize,
+    /// How long it took to feed the prompt.
+    pub duration: Duration,
+}
+
+/// A session generated a token (see [TelemetrySink::token_generated]).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TokenGeneratedEvent {
+    /// The generated token.
+    pub token_id: TokenId,
+    /// How long it took to sample and evaluate the token.
+    pub latency: Duration,
+}
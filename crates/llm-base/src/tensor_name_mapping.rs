@@ -0,0 +1,156 @@
+//! Implements rules that rename the tensors of a model as it is loaded, so that conversions
+//! that name their tensors differently can be loaded without converting them again.
+
+use std::{borrow::Cow, path::Path};
+
+use regex::Regex;
+use thiserror::Error;
+
+#[derive(Error, Debug)]
+/// Errors encountered while reading a [TensorNameMapping].
+pub enum TensorNameMappingError {
+    #[error("could not read tensor name mapping")]
+    /// The mapping file could not be read.
+    Io(#[from] std::io::Error),
+    #[error("line {line} of the tensor name mapping is not of the form `pattern => name`")]
+    /// A line of the mapping is not a rule.
+    InvalidLine {
+        /// The line number, starting from 1.
+        line: usize,
+    },
+    #[error("line {line} of the tensor name mapping has an invalid pattern")]
+    /// The pattern of a rule is not a valid regular expression.
+    InvalidPattern {
+        /// The line number, starting from 1.
+        line: usize,
+        /// The underlying error.
+        #[source]
+        source: regex::Error,
+    },
+}
+
+#[derive(Debug, Clone, Default)]
+/// Rules that map the names of a model's tensors to the names its architecture expects.
+///
+/// Each rule is a regular expression that must match the whole name of a tensor, and the
+/// name to replace it with, which can refer to the groups of the expression (e.g. `$1`).
+/// The first rule that matches a tensor is applied; tensors that no rule matches keep
+/// their names.
+pub struct TensorNameMapping {
+    /// The patterns as given, their anchored expressions, and their replacements.
+    rules: Vec<(String, Regex, String)>,
+}
+impl TensorNameMapping {
+    /// Creates a mapping from `rules` of patterns and replacements.
+    pub fn new<'a>(
+        rules: impl IntoIterator<Item = (&'a str, &'a str)>,
+    ) -> Result<Self, regex::Error> {
+        let rules = rules
+            .into_iter()
+            .map(|(pattern, name)| {
+                let regex = Regex::new(&format!("^(?:{pattern})$"))?;
+                Ok((pattern.to_owned(), regex, name.to_owned()))
+            })
+            .collect::<Result<_, regex::Error>>()?;
+        Ok(Self { rules })
+    }
+
+    /// Parses a mapping with one `pattern => name` rule per line. Empty lines and lines
+    /// starting with `#` are ignored.
+    pub fn parse(text: &str) -> Result<Self, TensorNameMappingError> {
+        let mut rules = vec![];
+        for (index, line) in text.lines().enumerate() {
+            let line_number = index + 1;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let (pattern, name) = line
+                .split_once("=>")
+                .ok_or(TensorNameMappingError::InvalidLine { line: line_number })?;
+            let mapping = Self::new([(pattern.trim(), name.trim())]).map_err(|source| {
+                TensorNameMappingError::InvalidPattern {
+                    line: line_number,
+                    source,
+                }
+            })?;
+            rules.extend(mapping.rules);
+        }
+        Ok(Self { rules })
+    }
+
+    /// Reads the mapping in the file at `path`; see [TensorNameMapping::parse].
+    pub fn read(path: &Path) -> Result<Self, TensorNameMappingError> {
+        Self::parse(&std::fs::read_to_string(path)?)
+    }
+
+    /// Returns the rules of the mapping, as the patterns and replacements they were
+    /// created from.
+    pub fn rules(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.rules
+            .iter()
+            .map(|(pattern, _, name)| (pattern.as_str(), name.as_str()))
+    }
+
+    /// Returns the name that the tensor `name` is mapped to.
+    pub fn map<'a>(&self, name: &'a str) -> Cow<'a, str> {
+        self.try_map(name).unwrap_or(Cow::Borrowed(name))
+    }
+
+    /// Returns the name that the tensor `name` is mapped to, or `None` if no rule matches it.
+    pub fn try_map<'a>(&self, name: &'a str) -> Option<Cow<'a, str>> {
+        self.rules
+            .iter()
+            .find(|(_, pattern, _)| pattern.is_match(name))
+            .map(|(_, pattern, replacement)| pattern.replace(name, replacement.as_str()))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_map_first_matching_rule() {
+        let mapping = TensorNameMapping::parse(
+            "# Layers are named `blk` in this conversion.
+            blk\\.(\\d+)\\.attn_q\\.weight => layers.$1.attention.wq.weight
+
+            token_embd\\.weight => tok_embeddings.weight
+            token_embd.* => unused",
+        )
+        .unwrap();
+
+        assert_eq!(
+            mapping.map("blk.12.attn_q.weight"),
+            "layers.12.attention.wq.weight"
+        );
+        assert_eq!(mapping.map("token_embd.weight"), "tok_embeddings.weight");
+        // Patterns must match the whole name.
+        assert_eq!(
+            mapping.map("blk.1.attn_q.weight.bias"),
+            "blk.1.attn_q.weight.bias"
+        );
+        assert_eq!(mapping.map("output.weight"), "output.weight");
+        assert_eq!(mapping.try_map("output.weight"), None);
+        assert_eq!(
+            mapping.rules().next(),
+            Some((
+                "blk\\.(\\d+)\\.attn_q\\.weight",
+                "layers.$1.attention.wq.weight"
+            ))
+        );
+    }
+
+    #[test]
+    fn test_parse_errors() {
+        assert!(matches!(
+            TensorNameMapping::parse("a => b\nno arrow"),
+            Err(TensorNameMappingError::InvalidLine { line: 2 })
+        ));
+        assert!(matches!(
+            TensorNameMapping::parse("a( => b"),
+            Err(TensorNameMappingError::InvalidPattern { line: 1, .. })
+        ));
+    }
+}
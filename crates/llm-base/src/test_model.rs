@@ -0,0 +1,296 @@
+//! Implements generating tiny models with random weights, so that loading and inference
+//! can be tested without downloading a real model.
+
+use crate::{model::HyperparametersWriteError, Hyperparameters, KnownModel, LoraParameters};
+use ggml::format::{SaveContainerType, SaveError, SaveHandler, TensorSaveInfo};
+use std::{
+    collections::HashMap,
+    io::{Seek, Write},
+};
+use thiserror::Error;
+
+/// The layout of a tiny model of an architecture, as returned by [KnownModel::test_model].
+#[derive(Debug, Clone, PartialEq)]
+pub struct TestModel<Hp> {
+    /// The hyperparameters of the model.
+    pub hyperparameters: Hp,
+    /// The name and dimensions of each tensor of the model, in the order they are saved.
+    /// Tensors with one dimension are normalization weights or biases.
+    pub tensors: Vec<(String, Vec<usize>)>,
+}
+
+#[derive(Error, Debug)]
+/// Errors encountered while writing a test model.
+pub enum TestModelError {
+    #[error("non-specific I/O error")]
+    /// A non-specific IO error.
+    Io(#[from] std::io::Error),
+    #[error("invalid integer conversion")]
+    /// One of the integers encountered could not be converted to a more appropriate type.
+    InvalidIntegerConversion(#[from] std::num::TryFromIntError),
+    /// An invariant was broken.
+    #[error("invariant broken: {invariant}")]
+    InvariantBroken {
+        /// The invariant that was broken.
+        invariant: String,
+    },
+    /// An error was encountered while writing the hyperparameters.
+    #[error("an error was encountered while writing the hyperparameters")]
+    HyperparametersWriteError(#[source] HyperparametersWriteError),
+    /// An attempt was made to save a model with a container type that does not
+    /// support vocabulary scoring, despite the model having a scored vocabulary.
+    #[error("container type does not support vocabulary scoring")]
+    VocabularyScoringNotSupported,
+    /// The architecture does not provide a [test model](KnownModel::test_model).
+    #[error("the architecture does not provide a test model")]
+    Unsupported,
+}
+impl TestModelError {
+    fn from_format_error(value: SaveError<TestModelError>) -> Self {
+        match value {
+            SaveError::Io(io) => TestModelError::Io(io),
+            SaveError::InvalidIntegerConversion(e) => TestModelError::InvalidIntegerConversion(e),
+            SaveError::ImplementationError(e) => e,
+            SaveError::InvariantBroken(invariant) => TestModelError::InvariantBroken { invariant },
+            SaveError::VocabularyScoringNotSupported => {
+                TestModelError::VocabularyScoringNotSupported
+            }
+        }
+    }
+}
+
+/// The special tokens at the start of the vocabulary of a test model. These cover the
+/// beginning and end of text tokens of all of the supported architectures.
+const SPECIAL_TOKENS: &[&str] = &["<unk>", "<s>", "</s>", "<|endoftext|>", "<|padding|>"];
+
+/// Returns the vocabulary of a test model: the special tokens of all supported
+/// architectures, followed by every printable ASCII character.
+pub fn test_vocabulary() -> Vec<Vec<u8>> {
+    SPECIAL_TOKENS
+        .iter()
+        .map(|token| token.as_bytes().to_vec())
+        .chain((b' '..=b'~').map(|c| vec![c]))
+        .collect()
+}
+
+/// Writes a tiny model of the architecture `M` with the [test vocabulary](test_vocabulary)
+/// to `writer`.
+///
+/// The weights are pseudo-random `f32`s that only depend on `seed`, so the same model is
+/// written on every machine. The model is not useful for anything but testing: it loads
+/// and runs like any other model, but generates gibberish.
+pub fn write_test_model<M: KnownModel, W: Write + Seek>(
+    writer: &mut W,
+    save_container_type: SaveContainerType,
+    seed: u64,
+) -> Result<(), TestModelError> {
+    let vocabulary: Vec<_> = test_vocabulary()
+        .into_iter()
+        .map(|token| (token, 0.0))
+        .collect();
+    let TestModel {
+        hyperparameters,
+        tensors,
+    } = M::test_model(vocabulary.len()).ok_or(TestModelError::Unsupported)?;
+
+    let tensor_names: Vec<_> = tensors.iter().map(|(name, _)| name.clone()).collect();
+    let mut handler = TestModelSaver {
+        hyperparameters: &hyperparameters,
+        tensors: tensors.into_iter().collect(),
+        rng: XorShift::new(seed),
+    };
+
+    ggml::format::save(
+        writer,
+        &mut handler,
+        save_container_type,
+        &vocabulary,
+        &tensor_names,
+    )
+    .map_err(TestModelError::from_format_error)
+}
+
+/// Writes a [LoRA](https://arxiv.org/abs/2106.09685) adapter for the tensors `tensor_names`
+/// of the test model of the architecture `M` to `writer`.
+///
+/// Like those of [write_test_model], the weights of the adapter are pseudo-random `f32`s
+/// that only depend on `seed`.
+pub fn write_test_lora_adapter<M: KnownModel, W: Write + Seek>(
+    writer: &mut W,
+    tensor_names: &[&str],
+    parameters: LoraParameters,
+    seed: u64,
+) -> Result<(), TestModelError> {
+    let TestModel { tensors, .. } =
+        M::test_model(test_vocabulary().len()).ok_or(TestModelError::Unsupported)?;
+    let r = usize::try_from(parameters.r)?;
+
+    // The patch of a tensor `w` is `a × b`, where `a` and `b` share the rank as their
+    // first dimension, and have the first and second dimension of `w` as their second.
+    let mut lora_tensors = vec![];
+    for &name in tensor_names {
+        let dims = tensors
+            .iter()
+            .find_map(|(n, dims)| (n == name).then_some(dims))
+            .filter(|dims| dims.len() == 2)
+            .ok_or_else(|| TestModelError::InvariantBroken {
+                invariant: format!("the test model has a matrix named {name}"),
+            })?;
+        lora_tensors.push((format!("{name}.loraA"), vec![r, dims[0]]));
+        lora_tensors.push((format!("{name}.loraB"), vec![r, dims[1]]));
+    }
+
+    let tensor_names: Vec<_> = lora_tensors.iter().map(|(name, _)| name.clone()).collect();
+    let mut handler = TestModelSaver {
+        hyperparameters: &parameters,
+        tensors: lora_tensors.into_iter().collect(),
+        rng: XorShift::new(seed),
+    };
+
+    ggml::format::save(
+        writer,
+        &mut handler,
+        SaveContainerType::GgjtV3,
+        &[],
+        &tensor_names,
+    )
+    .map_err(TestModelError::from_format_error)
+}
+
+struct TestModelSaver<'a, H: Hyperparameters> {
+    hyperparameters: &'a H,
+    tensors: HashMap<String, Vec<usize>>,
+    rng: XorShift,
+}
+impl<H: Hyperparameters> SaveHandler<TestModelError> for TestModelSaver<'_, H> {
+    fn write_hyperparameters(&mut self, writer: &mut dyn Write) -> Result<(), TestModelError> {
+        self.hyperparameters
+            .write_ggml(writer)
+            .map_err(TestModelError::HyperparametersWriteError)
+    }
+
+    fn tensor_data(&mut self, tensor_name: &str) -> Result<TensorSaveInfo, TestModelError> {
+        let dims =
+            self.tensors
+                .get(tensor_name)
+                .ok_or_else(|| TestModelError::InvariantBroken {
+                    invariant: format!("the test model has a tensor named {tensor_name}"),
+                })?;
+        if dims.is_empty() || dims.len() > 2 {
+            return Err(TestModelError::InvariantBroken {
+                invariant: format!("{tensor_name} has one or two dimensions"),
+            });
+        }
+
+        let data: Vec<f32> = if dims.len() == 1 {
+            // Normalization weights and biases; ones keep the activations in a sane range.
+            vec![1.0; dims[0]]
+        } else {
+            let scale = 1.0 / (dims[0] as f32).sqrt();
+            (0..dims[0] * dims[1])
+                .map(|_| self.rng.next_f32() * scale)
+                .collect()
+        };
+
+        Ok(TensorSaveInfo {
+            n_dims: dims.len(),
+            dims: [dims[0], dims.get(1).copied().unwrap_or(1)],
+            element_type: ggml::Type::F32,
+            data: data.iter().flat_map(|v| v.to_le_bytes()).collect(),
+        })
+    }
+}
+
+/// A xorshift generator, so that the generated weights do not depend on the version
+/// of `rand`.
+struct XorShift(u64);
+impl XorShift {
+    fn new(seed: u64) -> Self {
+        Self(seed.max(1))
+    }
+
+    /// Returns a number in `[-1, 1)`.
+    fn next_f32(&mut self) -> f32 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 >> 40) as f32 / (1u64 << 23) as f32 - 1.0
+    }
+}
+
+#[cfg(test)]
+pub(crate) use self::tests::{write_test_file, TestHyperparameters};
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{util, FileType, LoadError};
+    use std::{io::BufRead, path::Path};
+
+    /// The hyperparameters of the models written by [write_test_file]. They let the tests
+    /// of the functions that only handle model files, such as [repair](crate::repair), run
+    /// without an architecture.
+    #[derive(Debug, Default, PartialEq, Eq)]
+    pub(crate) struct TestHyperparameters {
+        pub n_vocab: usize,
+        pub file_type: FileType,
+    }
+    impl Hyperparameters for TestHyperparameters {
+        fn read_ggml(reader: &mut dyn BufRead) -> Result<Self, LoadError> {
+            Ok(Self {
+                n_vocab: util::read_i32(reader)?.try_into()?,
+                file_type: util::read_filetype(reader)?,
+            })
+        }
+
+        fn write_ggml(&self, writer: &mut dyn Write) -> Result<(), HyperparametersWriteError> {
+            util::write_i32(writer, self.n_vocab.try_into()?)?;
+            util::write_i32(writer, self.file_type.into())?;
+            Ok(())
+        }
+
+        fn n_vocabulary(&self) -> usize {
+            self.n_vocab
+        }
+
+        fn file_type(&self) -> Option<FileType> {
+            Some(self.file_type)
+        }
+
+        fn file_type_mut(&mut self) -> Option<&mut FileType> {
+            Some(&mut self.file_type)
+        }
+    }
+
+    /// Writes a model with [TestHyperparameters], the [test vocabulary](test_vocabulary) and
+    /// eight random 16×16 matrices to `path`.
+    pub(crate) fn write_test_file(path: &Path, save_container_type: SaveContainerType) {
+        let vocabulary: Vec<_> = test_vocabulary()
+            .into_iter()
+            .map(|token| (token, 0.0))
+            .collect();
+        let hyperparameters = TestHyperparameters {
+            n_vocab: vocabulary.len(),
+            file_type: FileType::default(),
+        };
+        let tensor_names: Vec<_> = (0..8).map(|i| format!("tensors.{i}")).collect();
+        let mut handler = TestModelSaver {
+            hyperparameters: &hyperparameters,
+            tensors: tensor_names
+                .iter()
+                .map(|name| (name.clone(), vec![16, 16]))
+                .collect(),
+            rng: XorShift::new(1),
+        };
+
+        let mut writer = std::io::BufWriter::new(std::fs::File::create(path).unwrap());
+        ggml::format::save(
+            &mut writer,
+            &mut handler,
+            save_container_type,
+            &vocabulary,
+            &tensor_names,
+        )
+        .unwrap();
+    }
+}
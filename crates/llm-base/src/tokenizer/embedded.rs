@@ -1,17 +1,7 @@
-use std::collections::HashMap;
-
-use thiserror::Error;
+use std::collections::{BTreeSet, HashMap};
 
 use super::{Token, TokenId, TokenScore, TokenizationError};
 
-#[derive(Debug, Error)]
-/// Errors that can occur when using a model tokenizer.
-pub enum EmbeddedTokenizerError {
-    /// Arbitrary error that occurred during use of the model tokenizer.
-    #[error("Arbitrary error: {0:?}")]
-    Arbitrary(String),
-}
-
 /// The built-in GGML tokenizer.
 #[derive(Debug, Clone, Default)]
 pub struct EmbeddedTokenizer {
@@ -27,8 +17,26 @@ pub struct EmbeddedTokenizer {
 
     /// The longest token in this tokenizer.
     max_token_length: usize,
+
+    /// The IDs of the special tokens, which decoding can skip.
+    special_token_ids: BTreeSet<TokenId>,
+
+    /// Whether the special tokens were given by the metadata of the model, rather than
+    /// recognized by their text.
+    special_tokens_from_metadata: bool,
 }
 
+/// The special tokens of the models that `llm` supports, which are recognized by their text
+/// when the model does not say which its special tokens are.
+const CONVENTIONAL_SPECIAL_TOKENS: [&[u8]; 6] = [
+    b"<unk>",
+    b"<s>",
+    b"</s>",
+    b"<pad>",
+    b"<|endoftext|>",
+    b"<|padding|>",
+];
+
 impl EmbeddedTokenizer {
     /// Add a token to the internal vocabulary.
     ///
@@ -47,27 +55,64 @@ impl EmbeddedTokenizer {
         }
 
         self.max_token_length = self.max_token_length.max(content.len());
+        if !self.special_tokens_from_metadata
+            && CONVENTIONAL_SPECIAL_TOKENS.contains(&content.as_slice())
+        {
+            self.special_token_ids.insert(id);
+        }
         self.id_to_token.push(content.clone());
         self.id_to_token_score.push(score);
         self.token_to_id.insert(content, id);
     }
 
-    pub(crate) fn id(&self, token: &[u8]) -> Option<TokenId> {
+    /// Sets the IDs of the special tokens, as given by the metadata of the model, in place
+    /// of the tokens recognized by their text.
+    pub(crate) fn set_special_token_ids(&mut self, ids: impl IntoIterator<Item = TokenId>) {
+        self.special_token_ids = ids.into_iter().collect();
+        self.special_tokens_from_metadata = true;
+    }
+
+    /// Converts a token to the token ID it represents in this tokenizer.
+    pub fn id(&self, token: &[u8]) -> Option<TokenId> {
         self.token_to_id.get(token).copied()
     }
 
-    /// Converts a token index to the token it represents in this tokenizer.
-    pub(crate) fn token(&self, idx: usize) -> Vec<u8> {
-        self.id_to_token[idx].clone()
+    /// Converts a token index to the token it represents in this tokenizer, or an empty
+    /// token if the index is not in the vocabulary.
+    pub fn token(&self, idx: usize) -> Vec<u8> {
+        self.id_to_token.get(idx).cloned().unwrap_or_default()
+    }
+
+    /// Returns the score of the token with the ID `id`, or `None` if it is not in the
+    /// vocabulary. Higher scores are preferred when the vocabulary was built.
+    pub fn score(&self, id: TokenId) -> Option<f32> {
+        self.id_to_token_score.get(id as usize).copied()
+    }
+
+    /// Returns whether the token with the ID `id` is a special token. These are given by the
+    /// metadata of GGUF models; for other models, they are the tokens whose text is that of
+    /// a special token of a supported model, such as `<s>`, `</s>` or `<|endoftext|>`.
+    pub fn is_special_token(&self, id: TokenId) -> bool {
+        self.special_token_ids.contains(&id)
+    }
+
+    /// Returns the IDs of the [special tokens](Self::is_special_token), in order.
+    pub fn special_token_ids(&self) -> impl Iterator<Item = TokenId> + '_ {
+        self.special_token_ids.iter().copied()
+    }
+
+    /// Returns the length in bytes of the longest token in the vocabulary.
+    pub fn max_token_length(&self) -> usize {
+        self.max_token_length
     }
 
     /// Returns the number of tokens in the tokenizer.
-    pub(crate) fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.id_to_token.len()
     }
 
     /// Returns whether the tokenizer is empty.
-    pub(crate) fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.id_to_token.is_empty()
     }
 
@@ -110,12 +155,10 @@ impl EmbeddedTokenizer {
         while i > 0 {
             let token_id = prev[i];
             if token_id == 0 {
-                return Err(TokenizationError::TokenizationFailed {
-                    error: Box::new(EmbeddedTokenizerError::Arbitrary(
-                        "the backward pass for the tokenizer encountered a non-set token"
-                            .to_string(),
-                    )),
-                });
+                // No token ends at `i`; report everything since the last position that
+                // could be reached.
+                let start = (0..i).rev().find(|&j| j == 0 || prev[j] != 0).unwrap_or(0);
+                return Err(TokenizationError::UntokenizableInput { span: start..i });
             }
             let token = self.id_to_token[token_id as usize].as_slice();
             res.push((token.to_vec(), token_id));
@@ -138,17 +181,20 @@ impl EmbeddedTokenizer {
         let mut vec = vec![];
 
         for token in tokens {
-            if skip_special_tokens && token == 1 {
+            if skip_special_tokens && self.is_special_token(token) {
                 continue;
             }
 
-            vec.append(&mut self.id_to_token[token as usize].to_vec());
+            if let Some(token) = self.id_to_token.get(token as usize) {
+                vec.extend_from_slice(token);
+            }
         }
 
         vec
     }
 
-    pub(crate) fn iter(&self) -> impl Iterator<Item = (Token, f32)> + '_ {
+    /// Iterates over the tokens of the vocabulary and their scores, in order of their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = (Vec<u8>, f32)> + '_ {
         self.id_to_token
             .iter()
             .zip(self.id_to_token_score.iter())
@@ -1,40 +1,66 @@
+use std::collections::BTreeSet;
+
 use super::{TokenId, TokenizationError};
 
 /// A Hugging Face tokenizer.
 #[derive(Debug, Clone)]
 pub struct HuggingFaceTokenizer {
     pub(crate) tokenizer: tokenizers::Tokenizer,
+    special_token_ids: BTreeSet<TokenId>,
 }
 
 impl HuggingFaceTokenizer {
     /// Create a new `HuggingFaceTokenizer`.
     pub fn new(tokenizer: tokenizers::Tokenizer) -> Self {
-        Self { tokenizer }
+        let special_token_ids = read_special_token_ids(&tokenizer);
+        Self {
+            tokenizer,
+            special_token_ids,
+        }
+    }
+
+    /// Returns the underlying Hugging Face tokenizer.
+    pub fn inner(&self) -> &tokenizers::Tokenizer {
+        &self.tokenizer
     }
 }
 
 impl HuggingFaceTokenizer {
-    pub(crate) fn id(&self, token: &[u8]) -> Option<TokenId> {
-        self.tokenizer
-            .token_to_id(std::str::from_utf8(token).unwrap())
+    /// Converts a token to the token ID it represents in this tokenizer.
+    pub fn id(&self, token: &[u8]) -> Option<TokenId> {
+        self.tokenizer.token_to_id(std::str::from_utf8(token).ok()?)
     }
 
-    /// Converts a token index to the token it represents in this tokenizer.
-    pub(crate) fn token(&self, idx: usize) -> Vec<u8> {
+    /// Converts a token index to the token it represents in this tokenizer, or an empty
+    /// token if it cannot be decoded.
+    pub fn token(&self, idx: usize) -> Vec<u8> {
+        let Ok(id) = u32::try_from(idx) else {
+            return vec![];
+        };
         self.tokenizer
-            .decode(&[idx as u32], true)
-            .expect("Cannot decode token from tokenizer tokenizer.")
-            .as_bytes()
-            .to_vec()
+            .decode(&[id], true)
+            .unwrap_or_default()
+            .into_bytes()
+    }
+
+    /// Returns whether the token with the ID `id` is one of the special tokens that the
+    /// tokenizer added to its vocabulary.
+    pub fn is_special_token(&self, id: TokenId) -> bool {
+        self.special_token_ids.contains(&id)
+    }
+
+    /// Returns the IDs of the [special tokens](Self::is_special_token), in order.
+    pub fn special_token_ids(&self) -> Vec<TokenId> {
+        self.special_token_ids.iter().copied().collect()
     }
 
     /// Returns the number of tokens in the tokenizer.
-    pub(crate) fn len(&self) -> usize {
+    pub fn len(&self) -> usize {
         self.tokenizer.get_vocab_size(false)
     }
 
     /// Returns whether the tokenizer is empty.
-    pub(crate) fn is_empty(&self) -> bool {
+    pub fn is_empty(&self) -> bool {
         self.tokenizer.get_vocab_size(false) == 0
     }
 
@@ -68,8 +94,29 @@ impl HuggingFaceTokenizer {
     pub(crate) fn decode(&self, tokens: Vec<TokenId>, skip_special_tokens: bool) -> Vec<u8> {
         self.tokenizer
             .decode(&tokens, skip_special_tokens)
-            .expect("Cannot decode token from tokenizer.")
-            .as_bytes()
-            .to_vec()
+            .unwrap_or_default()
+            .into_bytes()
+    }
+}
+
+/// Reads the IDs of the tokens that `tokenizer` added to its vocabulary as special tokens.
+/// The tokenizer does not expose its added vocabulary, but serializes it with whether each
+/// token is special.
+fn read_special_token_ids(tokenizer: &tokenizers::Tokenizer) -> BTreeSet<TokenId> {
+    #[derive(serde::Deserialize)]
+    struct AddedToken {
+        id: TokenId,
+        special: bool,
     }
+
+    let added_tokens = serde_json::to_value(tokenizer)
+        .ok()
+        .and_then(|mut tokenizer| tokenizer.get_mut("added_tokens").map(|t| t.take()))
+        .and_then(|added_tokens| serde_json::from_value::<Vec<AddedToken>>(added_tokens).ok())
+        .unwrap_or_default();
+    added_tokens
+        .into_iter()
+        .filter(|token| token.special)
+        .map(|token| token.id)
+        .collect()
 }
@@ -1,6 +1,7 @@
 use std::{
     error::Error,
     fmt::Display,
+    ops::Range,
     path::{Path, PathBuf},
     str::FromStr,
 };
@@ -27,9 +28,20 @@ pub enum TokenizationError {
         /// The error that occurred during tokenization.
         error: Box<dyn Error + Send + Sync>,
     },
-    #[error("the token ID {0} was invalid for this model")]
+    #[error("the input at bytes {span:?} could not be represented by any token in the vocabulary")]
+    /// Part of the input text could not be represented by the tokens in the vocabulary.
+    UntokenizableInput {
+        /// The byte range of the input that could not be tokenized.
+        span: Range<usize>,
+    },
+    #[error("the token ID {token_id} at position {index} was invalid for this model")]
     /// One of the tokens provided by the user was invalid, and did not belong to this model's tokenizer.
-    InvalidTokenId(TokenId),
+    InvalidTokenId {
+        /// The invalid token ID.
+        token_id: TokenId,
+        /// The position of the invalid token in the provided tokens.
+        index: usize,
+    },
 }
 
 #[derive(Error, Debug)]
@@ -106,6 +118,11 @@ impl TokenizerSource {
 }
 
 /// Encapsulates the tokenizer for a model, and provides methods to tokenize text.
+///
+/// The vocabulary of the tokenizer maps each token ID to the bytes of its token
+/// ([Self::token]) and back ([Self::id]). [Self::iter] lists the whole vocabulary, and
+/// [Self::score] and [Self::is_special_token] describe a single token, so that tools can
+/// constrain, render or count tokens without tokenizing text.
 pub enum Tokenizer {
     /// The vocabulary built-in to the model.
     Embedded(EmbeddedTokenizer),
@@ -139,7 +156,8 @@ impl Tokenizer {
         }
     }
 
-    /// Converts a token index to the token it represents in this tokenizer.
+    /// Converts a token index to the token it represents in this tokenizer. Returns an
+    /// empty token if the index is not in the vocabulary.
     pub fn token(&self, idx: usize) -> Vec<u8> {
         match self {
             Tokenizer::Embedded(v) => v.token(idx),
@@ -147,6 +165,47 @@ impl Tokenizer {
         }
     }
 
+    /// Returns the score of the token with the ID `id`, or `None` if it is not in the
+    /// vocabulary or the tokenizer does not score its tokens. Only the
+    /// [embedded](Self::Embedded) vocabulary has scores.
+    pub fn score(&self, id: TokenId) -> Option<f32> {
+        match self {
+            Tokenizer::Embedded(v) => v.score(id),
+            Tokenizer::HuggingFace(_) => None,
+        }
+    }
+
+    /// Iterates over the IDs of the tokens in the vocabulary and the tokens they
+    /// represent, in order of their IDs.
+    pub fn iter(&self) -> impl Iterator<Item = (TokenId, Vec<u8>)> + '_ {
+        (0..self.len()).map(|idx| (idx as TokenId, self.token(idx)))
+    }
+
+    /// Returns whether the token with the ID `id` is a special token, which
+    /// [Self::decode] leaves out when it skips special tokens.
+    ///
+    /// The special tokens of a Hugging Face tokenizer are the ones it marks as special. Those
+    /// of the embedded vocabulary are given by the metadata of GGUF models, or recognized by
+    /// their text for other models (see [EmbeddedTokenizer::is_special_token]). Which special
+    /// tokens a model starts and ends its text with depends on the model, and is given by
+    /// [Model::bot_token_id](crate::Model::bot_token_id) and
+    /// [Model::eot_token_id](crate::Model::eot_token_id).
+    pub fn is_special_token(&self, id: TokenId) -> bool {
+        match self {
+            Tokenizer::Embedded(v) => v.is_special_token(id),
+            Tokenizer::HuggingFace(v) => v.is_special_token(id),
+        }
+    }
+
+    /// Returns the IDs of the [special tokens](Self::is_special_token) of the vocabulary, in
+    /// order.
+    pub fn special_token_ids(&self) -> Vec<TokenId> {
+        match self {
+            Tokenizer::Embedded(v) => v.special_token_ids().collect(),
+            Tokenizer::HuggingFace(v) => v.special_token_ids(),
+        }
+    }
+
     /// Returns the number of tokens in the tokenizer.
     pub fn len(&self) -> usize {
         match self {
@@ -186,6 +245,59 @@ impl Tokenizer {
     }
 }
 
+#[derive(Debug, PartialEq, Eq, Clone, Copy)]
+/// The special tokens that a model trained for fill-in-the-middle (infill) uses to mark
+/// the code before and after the gap, and where to generate the code for the gap.
+pub struct InfillTokens {
+    /// Marks the start of the code before the gap.
+    pub prefix: TokenId,
+    /// Marks the start of the code after the gap.
+    pub suffix: TokenId,
+    /// Marks where the code for the gap starts.
+    pub middle: TokenId,
+    /// Marks the end of the code for the gap, if the model does not use its end-of-text
+    /// token for this.
+    pub end: Option<TokenId>,
+}
+impl InfillTokens {
+    /// Finds the infill tokens of CodeLlama-style (`<PRE>`, `<SUF>`, `<MID>`, `<EOT>`) or
+    /// StarCoder-style (`<fim_prefix>`, `<fim_suffix>`, `<fim_middle>`) models in `tokenizer`,
+    /// or returns `None` if it has neither.
+    pub fn detect(tokenizer: &Tokenizer) -> Option<Self> {
+        // SentencePiece tokenizers prefix these tokens with a word boundary, which
+        // conversions to GGML usually replace with a space.
+        let find = |name: &str| {
+            [
+                format!(" {name}"),
+                format!("\u{2581}{name}"),
+                name.to_owned(),
+            ]
+            .iter()
+            .find_map(|token| tokenizer.id(token.as_bytes()))
+        };
+
+        if let (Some(prefix), Some(suffix), Some(middle)) =
+            (find("<PRE>"), find("<SUF>"), find("<MID>"))
+        {
+            return Some(Self {
+                prefix,
+                suffix,
+                middle,
+                end: find("<EOT>"),
+            });
+        }
+
+        ["_", "-"].iter().find_map(|separator| {
+            Some(Self {
+                prefix: tokenizer.id(format!("<fim{separator}prefix>").as_bytes())?,
+                suffix: tokenizer.id(format!("<fim{separator}suffix>").as_bytes())?,
+                middle: tokenizer.id(format!("<fim{separator}middle>").as_bytes())?,
+                end: None,
+            })
+        })
+    }
+}
+
 #[derive(Debug, PartialEq, Clone, Copy)]
 /// Represents the prompt, which can be specified as either text or tokens.
 ///
@@ -219,12 +331,12 @@ impl Prompt<'_> {
                 .map(|(_, tok)| *tok)
                 .collect(),
             Self::Tokens(tokens) => {
-                if let Some(t) = tokens
+                if let Some((index, &token_id)) = tokens
                     .iter()
-                    .copied()
-                    .find(|t| vocab.token(*t as usize).is_empty())
+                    .enumerate()
+                    .find(|(_, t)| vocab.token(**t as usize).is_empty())
                 {
-                    return Err(TokenizationError::InvalidTokenId(t));
+                    return Err(TokenizationError::InvalidTokenId { token_id, index });
                 }
                 tokens.to_vec()
             }
@@ -360,3 +472,82 @@ impl std::fmt::Display for TokenBias {
         write!(f, "{:?}", self.0)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn embedded(tokens: &[&str]) -> Tokenizer {
+        let mut tokenizer = EmbeddedTokenizer::default();
+        for (id, token) in tokens.iter().enumerate() {
+            tokenizer.push_token(id as TokenId, token.as_bytes().to_vec(), 0.0);
+        }
+        Tokenizer::Embedded(tokenizer)
+    }
+
+    #[test]
+    fn test_detect_infill_tokens() {
+        let codellama = embedded(&["<s>", " <PRE>", " <SUF>", " <MID>", " <EOT>"]);
+        assert_eq!(
+            InfillTokens::detect(&codellama),
+            Some(InfillTokens {
+                prefix: 1,
+                suffix: 2,
+                middle: 3,
+                end: Some(4),
+            })
+        );
+
+        let starcoder = embedded(&[
+            "<|endoftext|>",
+            "<fim_prefix>",
+            "<fim_middle>",
+            "<fim_suffix>",
+        ]);
+        assert_eq!(
+            InfillTokens::detect(&starcoder),
+            Some(InfillTokens {
+                prefix: 1,
+                suffix: 3,
+                middle: 2,
+                end: None,
+            })
+        );
+
+        assert_eq!(InfillTokens::detect(&embedded(&["<s>", "<PRE>"])), None);
+    }
+
+    #[test]
+    fn test_vocabulary() {
+        let tokenizer = embedded(&["<unk>", "<s>", "</s>", "a", "ab"]);
+        assert_eq!(tokenizer.len(), 5);
+        assert_eq!(tokenizer.id(b"ab"), Some(4));
+        assert_eq!(tokenizer.id(b"abc"), None);
+        assert_eq!(tokenizer.token(4), b"ab");
+        assert_eq!(tokenizer.score(4), Some(0.0));
+        assert_eq!(tokenizer.score(5), None);
+        assert_eq!(
+            tokenizer.iter().collect::<Vec<_>>(),
+            ["<unk>", "<s>", "</s>", "a", "ab"]
+                .iter()
+                .enumerate()
+                .map(|(id, token)| (id as TokenId, token.as_bytes().to_vec()))
+                .collect::<Vec<_>>()
+        );
+
+        // Without metadata, the special tokens are recognized by their text.
+        assert_eq!(tokenizer.special_token_ids(), vec![0, 1, 2]);
+        assert!(!tokenizer.is_special_token(3));
+        assert_eq!(tokenizer.decode(vec![1, 3, 2, 4], true), b"aab");
+        assert_eq!(tokenizer.decode(vec![1, 3], false), b"<s>a");
+        let Tokenizer::Embedded(mut embedded) = tokenizer else {
+            unreachable!()
+        };
+        assert_eq!(embedded.max_token_length(), 5);
+
+        // The metadata of a model replaces them.
+        embedded.set_special_token_ids([2, 4]);
+        assert_eq!(embedded.special_token_ids().collect::<Vec<_>>(), vec![2, 4]);
+        assert!(!embedded.is_special_token(1));
+    }
+}
@@ -2,7 +2,8 @@
 pub use ggml::util::*;
 
 use std::{
-    io::BufRead,
+    fs::File,
+    io::{self, BufRead, SeekFrom},
     path::{Path, PathBuf},
 };
 
@@ -32,6 +33,64 @@ pub fn read_filetype(reader: &mut dyn BufRead) -> Result<FileType, LoadError> {
     FileType::try_from(ftype).map_err(|_| LoadError::UnsupportedFileType(ftype))
 }
 
+/// The position that `pos` seeks to, for a [Seek](std::io::Seek) implementation that is at
+/// `position` in a stream of `len` bytes.
+pub(crate) fn seek_position(position: u64, len: u64, pos: SeekFrom) -> io::Result<u64> {
+    let add_signed = |base: u64, offset: i64| {
+        if offset >= 0 {
+            base.checked_add(offset as u64)
+        } else {
+            base.checked_sub(offset.unsigned_abs())
+        }
+    };
+    match pos {
+        SeekFrom::Start(offset) => Some(offset),
+        SeekFrom::End(offset) => add_signed(len, offset),
+        SeekFrom::Current(offset) => add_signed(position, offset),
+    }
+    .ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            "invalid seek to a negative or overflowing position",
+        )
+    })
+}
+
+/// Reads exactly `buf.len()` bytes from `file` at `offset`, without using or moving the
+/// file's cursor, so that several threads can read from the same file at once.
+#[cfg(any(unix, windows))]
+pub(crate) fn read_exact_at(file: &File, mut buf: &mut [u8], mut offset: u64) -> io::Result<()> {
+    #[cfg(unix)]
+    use std::os::unix::fs::FileExt;
+    #[cfg(windows)]
+    use std::os::windows::fs::FileExt;
+
+    while !buf.is_empty() {
+        #[cfg(unix)]
+        let n = file.read_at(buf, offset)?;
+        #[cfg(windows)]
+        let n = file.seek_read(buf, offset)?;
+        if n == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "failed to fill whole buffer",
+            ));
+        }
+        buf = &mut buf[n..];
+        offset += n as u64;
+    }
+    Ok(())
+}
+
+/// Positional reads are not available on this platform, so they are never used.
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn read_exact_at(_file: &File, _buf: &mut [u8], _offset: u64) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "positional reads are not supported on this platform",
+    ))
+}
+
 /// Used to buffer incoming tokens until they produce a valid string of UTF-8 text.
 ///
 /// Tokens are *not* valid UTF-8 by themselves. However, the LLM will produce valid UTF-8
@@ -150,6 +209,18 @@ pub fn softmax(logits: &[f32]) -> Vec<f32> {
     probs
 }
 
+/// Calculate the logarithm of the softmax for a slice, which is more numerically
+/// stable than taking the logarithm of [softmax].
+pub fn log_softmax(logits: &[f32]) -> Vec<f32> {
+    let max_logit = logits.iter().fold(f32::NEG_INFINITY, |a, &b| a.max(b));
+    let log_sum = logits
+        .iter()
+        .map(|v| (v - max_logit).exp())
+        .sum::<f32>()
+        .ln();
+    logits.iter().map(|v| v - max_logit - log_sum).collect()
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -175,6 +246,17 @@ mod tests {
         assert_eq!(expected_paths.as_slice(), output_paths);
     }
 
+    #[test]
+    fn test_log_softmax() {
+        let logits = [1.0, 2.0, 3.0, -1000.0];
+        let log_probs = log_softmax(&logits);
+        for (log_prob, prob) in log_probs.iter().zip(softmax(&logits)).take(3) {
+            assert!((log_prob - prob.ln()).abs() < 1e-5);
+        }
+        // Very unlikely tokens still have a finite log-probability.
+        assert!(log_probs[3].is_finite());
+    }
+
     #[test]
     fn test_valid_utf8() {
         let mut buffer = TokenUtf8Buffer::new();
@@ -0,0 +1,158 @@
+//! Benchmarks tokenization, prompt feeding at several batch sizes, and generating a
+//! single token.
+//!
+//! By default, these run on a tiny generated LLaMA model, which measures the overhead of
+//! `llm` more than the speed of the kernels. To benchmark a real model, set
+//! `LLM_BENCH_MODEL` to its path and `LLM_BENCH_ARCHITECTURE` to its architecture.
+//!
+//! Run with `cargo bench -p llm`.
+
+use std::path::PathBuf;
+
+use criterion::{criterion_group, criterion_main, BatchSize, BenchmarkId, Criterion, Throughput};
+use llm::{
+    InferenceFeedback, InferenceSession, InferenceSessionConfig, Model, ModelArchitecture,
+    ModelParameters, OutputRequest, Prompt, TokenId, TokenizerSource,
+};
+
+const TEXT: &str = "The quick brown fox jumps over the lazy dog. \
+    Pack my box with five dozen liquor jugs. \
+    How vexingly quick daft zebras jump! \
+    Sphinx of black quartz, judge my vow. ";
+
+const PROMPT_LENGTH: usize = 128;
+const BATCH_SIZES: [usize; 4] = [1, 8, 32, 128];
+
+fn load_model() -> Box<dyn Model> {
+    let (architecture, path) = match std::env::var_os("LLM_BENCH_MODEL") {
+        Some(path) => {
+            let architecture = std::env::var("LLM_BENCH_ARCHITECTURE")
+                .expect("LLM_BENCH_ARCHITECTURE must be set with LLM_BENCH_MODEL")
+                .parse::<ModelArchitecture>()
+                .unwrap();
+            (architecture, PathBuf::from(path))
+        }
+        None => {
+            let path = std::env::temp_dir().join(format!("llm-bench-{}.bin", std::process::id()));
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+            llm::write_test_model::<llm::models::Llama, _>(
+                &mut file,
+                llm::ggml_format::SaveContainerType::GgjtV3,
+                0,
+            )
+            .unwrap();
+            (ModelArchitecture::Llama, path)
+        }
+    };
+
+    let model = llm::load_dynamic(
+        Some(architecture),
+        &path,
+        TokenizerSource::Embedded,
+        {
+            let mut params = ModelParameters::default();
+            params.context_size = 2 * PROMPT_LENGTH;
+            params
+        },
+        |_| {},
+    )
+    .unwrap();
+
+    if std::env::var_os("LLM_BENCH_MODEL").is_none() {
+        std::fs::remove_file(&path).unwrap();
+    }
+    model
+}
+
+fn session_config(n_batch: usize) -> InferenceSessionConfig {
+    InferenceSessionConfig::builder()
+        .n_batch(n_batch)
+        .n_threads(
+            std::thread::available_parallelism()
+                .map(|n| n.get())
+                .unwrap_or(1),
+        )
+        .build()
+        .unwrap()
+}
+
+fn prompt_tokens(model: &dyn Model) -> Vec<TokenId> {
+    let tokens: Vec<TokenId> = model
+        .tokenizer()
+        .tokenize(&TEXT.repeat(PROMPT_LENGTH), true)
+        .unwrap()
+        .into_iter()
+        .map(|(_, id)| id)
+        .take(PROMPT_LENGTH)
+        .collect();
+    assert_eq!(tokens.len(), PROMPT_LENGTH);
+    tokens
+}
+
+fn feed(model: &dyn Model, session: &mut InferenceSession, tokens: &[TokenId]) {
+    session
+        .feed_prompt(
+            model,
+            Prompt::Tokens(tokens),
+            &mut OutputRequest::default(),
+            |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+        )
+        .unwrap();
+}
+
+fn inference(c: &mut Criterion) {
+    let model = load_model();
+    let model = model.as_ref();
+    let tokens = prompt_tokens(model);
+
+    let mut group = c.benchmark_group("tokenize");
+    group.throughput(Throughput::Bytes(TEXT.len() as u64));
+    group.bench_function("text", |b| {
+        b.iter(|| model.tokenizer().tokenize(TEXT, true).unwrap())
+    });
+    group.finish();
+
+    let mut group = c.benchmark_group("prefill");
+    group.throughput(Throughput::Elements(PROMPT_LENGTH as u64));
+    for n_batch in BATCH_SIZES {
+        group.bench_with_input(
+            BenchmarkId::from_parameter(n_batch),
+            &n_batch,
+            |b, &n_batch| {
+                b.iter_batched_ref(
+                    || model.start_session(session_config(n_batch)),
+                    |session| feed(model, session, &tokens),
+                    BatchSize::PerIteration,
+                )
+            },
+        );
+    }
+    group.finish();
+
+    let mut group = c.benchmark_group("decode");
+    group.throughput(Throughput::Elements(1));
+    group.bench_function("single_token", |b| {
+        b.iter_batched_ref(
+            || {
+                let mut session = model.start_session(session_config(8));
+                feed(model, &mut session, &tokens);
+                session
+            },
+            |session| {
+                model
+                    .evaluate(session, &tokens[..1], &mut OutputRequest::default())
+                    .unwrap()
+            },
+            BatchSize::PerIteration,
+        )
+    });
+    group.finish();
+}
+
+criterion_group! {
+    name = benches;
+    // Evaluating a real model is slow, so take fewer samples than the default.
+    config = Criterion::default().sample_size(20);
+    targets = inference
+}
+criterion_main!(benches);
@@ -60,18 +60,23 @@ fn main() {
     .unwrap_or_else(|err| {
         panic!("Failed to load {model_architecture} model from {model_path:?}: {err}")
     });
-    let inference_parameters = llm::InferenceParameters::default();
 
     // Generate embeddings for query and comparands
-    let query_embeddings = get_embeddings(model.as_ref(), &inference_parameters, query);
+    let texts: Vec<&str> = std::iter::once(query)
+        .chain(comparands.iter().map(|text| text.as_str()))
+        .collect();
+    let embeddings = model
+        .embed(
+            &texts,
+            &llm::EmbeddingOptions::default(),
+            llm::InferenceSessionConfig::default(),
+        )
+        .unwrap_or_else(|err| panic!("Failed to compute embeddings: {err}"));
+    let query_embeddings = embeddings.get(0).unwrap().to_vec();
     let comparand_embeddings: Vec<(String, Vec<f32>)> = comparands
         .iter()
-        .map(|text| {
-            (
-                text.clone(),
-                get_embeddings(model.as_ref(), &inference_parameters, text),
-            )
-        })
+        .cloned()
+        .zip(embeddings.iter().skip(1).map(|e| e.to_vec()))
         .collect();
 
     // Print embeddings
@@ -107,28 +112,6 @@ fn main() {
     }
 }
 
-fn get_embeddings(
-    model: &dyn llm::Model,
-    inference_parameters: &llm::InferenceParameters,
-    query: &str,
-) -> Vec<f32> {
-    let mut session = model.start_session(Default::default());
-    let mut output_request = llm::OutputRequest {
-        all_logits: None,
-        embeddings: Some(Vec::new()),
-    };
-    let vocab = model.tokenizer();
-    let beginning_of_sentence = true;
-    let query_token_ids = vocab
-        .tokenize(query, beginning_of_sentence)
-        .unwrap()
-        .iter()
-        .map(|(_, tok)| *tok)
-        .collect::<Vec<_>>();
-    model.evaluate(&mut session, &query_token_ids, &mut output_request);
-    output_request.embeddings.unwrap()
-}
-
 fn cosine_similarity(v1: &[f32], v2: &[f32]) -> f32 {
     let dot_product = dot(v1, v2);
     let magnitude1 = magnitude(v1);
@@ -1,5 +1,5 @@
 use clap::Parser;
-use std::{convert::Infallible, io::Write, path::PathBuf};
+use std::{io::Write, path::PathBuf};
 
 #[derive(Parser)]
 struct Args {
@@ -56,15 +56,10 @@ fn main() {
 
     let mut session = model.start_session(Default::default());
 
-    let res = session.infer::<Infallible>(
+    let res = session.infer(
         model.as_ref(),
         &mut rand::thread_rng(),
-        &llm::InferenceRequest {
-            prompt: prompt.into(),
-            parameters: &llm::InferenceParameters::default(),
-            play_back_previous_tokens: false,
-            maximum_token_count: None,
-        },
+        &llm::InferenceRequest::builder(prompt, &llm::InferenceParameters::default()).build(),
         // OutputRequest
         &mut Default::default(),
         |r| match r {
@@ -72,9 +67,9 @@ fn main() {
                 print!("{t}");
                 std::io::stdout().flush().unwrap();
 
-                Ok(llm::InferenceFeedback::Continue)
+                llm::InferenceFeedback::Continue
             }
-            _ => Ok(llm::InferenceFeedback::Continue),
+            _ => llm::InferenceFeedback::Continue,
         },
     );
 
@@ -0,0 +1,32 @@
+/* The C ABI of the `llm-ffi` example. See `llm-ffi.rs` for the documentation of each function. */
+
+#ifndef LLM_FFI_H
+#define LLM_FFI_H
+
+#include <stdbool.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+typedef struct LlmModel LlmModel;
+
+typedef bool (*LlmTokenCallback)(const char *token, void *user_data);
+
+const char *llm_last_error(void);
+
+LlmModel *llm_model_load(const char *architecture, const char *path, uint32_t context_size);
+
+void llm_model_free(LlmModel *model);
+
+char *llm_generate(const LlmModel *model, const char *prompt, uint32_t max_tokens,
+                   uint32_t n_threads, LlmTokenCallback callback, void *user_data);
+
+void llm_string_free(char *s);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif
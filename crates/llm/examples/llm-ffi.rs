@@ -0,0 +1,190 @@
+//! A C ABI for loading a model and generating text, built as a shared and a static
+//! library, for embedding `llm` in applications written in other languages, such as
+//! Android and iOS apps. `llm-ffi.h` declares the functions for C, Objective-C and Swift.
+//!
+//! The functions only take and return opaque handles, null-terminated UTF-8 strings and
+//! plain integers, so they can be called from a small JNI shim (or from JNA, Swift or C)
+//! without any knowledge of Rust types. Strings returned by the library are freed with
+//! [llm_string_free], and no panic crosses the boundary: a function that fails returns
+//! null and sets an error message that [llm_last_error] returns.
+//!
+//! To build it for Android with [cargo-ndk](https://github.com/bbqsrc/cargo-ndk):
+//!
+//! ```bash
+//! cargo ndk -t arm64-v8a build --release -p llm --example llm-ffi \
+//!     --no-default-features --features llama
+//! ```
+//!
+//! which produces `libllm_ffi.so` for the app's `jniLibs/arm64-v8a` directory. For iOS,
+//! build it with `--target aarch64-apple-ios` and link `libllm_ffi.a` into the app,
+//! along with the Accelerate framework.
+
+use std::{
+    cell::RefCell,
+    ffi::{c_char, c_void, CStr, CString},
+    panic::{catch_unwind, AssertUnwindSafe},
+};
+
+/// A loaded model, owned by the caller until it is passed to [llm_model_free].
+pub struct LlmModel(Box<dyn llm::Model>);
+
+/// Called with each generated token, as a null-terminated UTF-8 string that is only valid
+/// during the call, and the `user_data` given to [llm_generate]. Returning `false` stops
+/// generation.
+pub type LlmTokenCallback = extern "C" fn(token: *const c_char, user_data: *mut c_void) -> bool;
+
+thread_local! {
+    static LAST_ERROR: RefCell<Option<CString>> = RefCell::new(None);
+}
+
+fn set_last_error(message: impl ToString) {
+    let message = CString::new(message.to_string().replace('\0', ""))
+        .expect("interior null bytes were removed");
+    LAST_ERROR.with(|last_error| *last_error.borrow_mut() = Some(message));
+}
+
+/// Runs `f`, turning errors and panics into a null result and the last error.
+fn ffi_call<T>(f: impl FnOnce() -> Result<*mut T, String>) -> *mut T {
+    match catch_unwind(AssertUnwindSafe(f)) {
+        Ok(Ok(result)) => result,
+        Ok(Err(message)) => {
+            set_last_error(message);
+            std::ptr::null_mut()
+        }
+        Err(_) => {
+            set_last_error("the library panicked");
+            std::ptr::null_mut()
+        }
+    }
+}
+
+unsafe fn to_str<'a>(s: *const c_char, name: &str) -> Result<&'a str, String> {
+    if s.is_null() {
+        return Err(format!("{name} is null"));
+    }
+    CStr::from_ptr(s)
+        .to_str()
+        .map_err(|_| format!("{name} is not valid UTF-8"))
+}
+
+/// Returns the message of the last error on this thread, or null if there was none. The
+/// message is valid until the next call into the library on this thread.
+#[no_mangle]
+pub extern "C" fn llm_last_error() -> *const c_char {
+    LAST_ERROR.with(|last_error| {
+        last_error
+            .borrow()
+            .as_ref()
+            .map_or(std::ptr::null(), |message| message.as_ptr())
+    })
+}
+
+/// Loads the model of the given `architecture` (e.g. `llama`) at `path`, with a context
+/// window of `context_size` tokens. Returns null if it cannot be loaded.
+///
+/// # Safety
+///
+/// `architecture` and `path` must be valid null-terminated strings.
+#[no_mangle]
+pub unsafe extern "C" fn llm_model_load(
+    architecture: *const c_char,
+    path: *const c_char,
+    context_size: u32,
+) -> *mut LlmModel {
+    ffi_call(|| {
+        let architecture: llm::ModelArchitecture = to_str(architecture, "architecture")?
+            .parse()
+            .map_err(|err| format!("{err}"))?;
+        let path = to_str(path, "path")?;
+        let model = llm::ModelLoader::from_path(path)
+            .architecture(architecture)
+            .context_size(context_size as usize)
+            .load()
+            .map_err(|err| format!("could not load the model: {err}"))?;
+        Ok(Box::into_raw(Box::new(LlmModel(model))))
+    })
+}
+
+/// Frees a model returned by [llm_model_load]. Does nothing if `model` is null.
+///
+/// # Safety
+///
+/// `model` must be null or a model returned by [llm_model_load] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn llm_model_free(model: *mut LlmModel) {
+    if !model.is_null() {
+        drop(Box::from_raw(model));
+    }
+}
+
+/// Completes `prompt` with up to `max_tokens` tokens (or until the end of the text or the
+/// context window if it is 0), using `n_threads` threads. Each token is passed to
+/// `callback`, if it is not null, as it is generated. Returns the generated text, which
+/// must be freed with [llm_string_free], or null if generation failed.
+///
+/// # Safety
+///
+/// `model` must be a model returned by [llm_model_load] that has not been freed, and
+/// `prompt` must be a valid null-terminated string. `user_data` is only passed to
+/// `callback`.
+#[no_mangle]
+pub unsafe extern "C" fn llm_generate(
+    model: *const LlmModel,
+    prompt: *const c_char,
+    max_tokens: u32,
+    n_threads: u32,
+    callback: Option<LlmTokenCallback>,
+    user_data: *mut c_void,
+) -> *mut c_char {
+    ffi_call(|| {
+        let model = model.as_ref().ok_or_else(|| "model is null".to_string())?;
+        let prompt = to_str(prompt, "prompt")?;
+
+        let config = llm::InferenceSessionConfig::builder()
+            .n_threads((n_threads as usize).max(1))
+            .build()
+            .map_err(|err| err.to_string())?;
+        let mut session = model.0.start_session(config);
+        let mut text = String::new();
+        let result = session.infer(
+            model.0.as_ref(),
+            &mut rand::thread_rng(),
+            &llm::InferenceRequest::builder(prompt, &llm::InferenceParameters::default())
+                .maximum_token_count((max_tokens > 0).then_some(max_tokens as usize))
+                .build(),
+            &mut Default::default(),
+            |response| {
+                let llm::InferenceResponse::InferredToken(token) = response else {
+                    return llm::InferenceFeedback::Continue;
+                };
+                text.push_str(&token);
+                match (callback, CString::new(token.replace('\0', ""))) {
+                    (Some(callback), Ok(token)) if !callback(token.as_ptr(), user_data) => {
+                        llm::InferenceFeedback::Halt
+                    }
+                    _ => llm::InferenceFeedback::Continue,
+                }
+            },
+        );
+        match result {
+            Ok(_) | Err(llm::InferenceError::ContextFull) => {}
+            Err(err) => return Err(format!("generation failed: {err}")),
+        }
+
+        CString::new(text.replace('\0', ""))
+            .map(CString::into_raw)
+            .map_err(|err| err.to_string())
+    })
+}
+
+/// Frees a string returned by the library. Does nothing if `s` is null.
+///
+/// # Safety
+///
+/// `s` must be null or a string returned by [llm_generate] that has not been freed.
+#[no_mangle]
+pub unsafe extern "C" fn llm_string_free(s: *mut c_char) {
+    if !s.is_null() {
+        drop(CString::from_raw(s));
+    }
+}
@@ -1,7 +1,7 @@
 use clap::Parser;
 use llm_base::conversation_inference_callback;
 use rustyline::error::ReadlineError;
-use std::{convert::Infallible, io::Write, path::PathBuf};
+use std::{io::Write, path::PathBuf};
 
 #[derive(Parser)]
 struct Args {
@@ -65,9 +65,9 @@ fn main() {
                 | llm::InferenceResponse::InferredToken(t) => {
                     print_token(t);
 
-                    Ok::<llm::InferenceFeedback, Infallible>(llm::InferenceFeedback::Continue)
+                    llm::InferenceFeedback::Continue
                 }
-                _ => Ok(llm::InferenceFeedback::Continue),
+                _ => llm::InferenceFeedback::Continue,
             }),
         )
         .expect("Failed to ingest initial prompt.");
@@ -84,17 +84,14 @@ fn main() {
         match readline {
             Ok(line) => {
                 let stats = session
-                    .infer::<Infallible>(
+                    .infer(
                         model.as_ref(),
                         &mut rng,
-                        &llm::InferenceRequest {
-                            prompt: format!("{user_name}: {line}\n{character_name}:")
-                                .as_str()
-                                .into(),
-                            parameters: &inference_parameters,
-                            play_back_previous_tokens: false,
-                            maximum_token_count: None,
-                        },
+                        &llm::InferenceRequest::builder(
+                            format!("{user_name}: {line}\n{character_name}:").as_str(),
+                            &inference_parameters,
+                        )
+                        .build(),
                         &mut Default::default(),
                         conversation_inference_callback(&format!("{character_name}:"), print_token),
                     )
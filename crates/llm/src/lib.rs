@@ -7,6 +7,7 @@
 //! - [GPT-NeoX](llm_gptneox)
 //! - [LLaMA](llm_llama)
 //! - [MPT](llm_mpt)
+//! - [GPT-BigCode](llm_bigcode) (includes StarCoder and SantaCoder)
 //! - Falcon (currently disabled due to incompleteness)
 //!
 //! At present, the only supported backend is [GGML](https://github.com/ggerganov/ggml), but this is expected to
@@ -33,19 +34,18 @@
 //!
 //! // use the model to generate text from a prompt
 //! let mut session = llama.start_session(Default::default());
-//! let res = session.infer::<std::convert::Infallible>(
+//! let res = session.infer(
 //!     // model to use for text generation
 //!     &llama,
 //!     // randomness provider
 //!     &mut rand::thread_rng(),
 //!     // the prompt to use for text generation, as well as other
 //!     // inference parameters
-//!     &llm::InferenceRequest {
-//!         prompt: "Rust is a cool programming language because".into(),
-//!         parameters: &llm::InferenceParameters::default(),
-//!         play_back_previous_tokens: false,
-//!         maximum_token_count: None,
-//!     },
+//!     &llm::InferenceRequest::builder(
+//!         "Rust is a cool programming language because",
+//!         &llm::InferenceParameters::default(),
+//!     )
+//!     .build(),
 //!     // llm::OutputRequest
 //!     &mut Default::default(),
 //!     // output callback
@@ -54,9 +54,9 @@
 //!             print!("{t}");
 //!             std::io::stdout().flush().unwrap();
 //!
-//!             Ok(llm::InferenceFeedback::Continue)
+//!             llm::InferenceFeedback::Continue
 //!         }
-//!         _ => Ok(llm::InferenceFeedback::Continue),
+//!         _ => llm::InferenceFeedback::Continue,
 //!     }
 //! );
 //!
@@ -65,11 +65,52 @@
 //!     Err(err) => println!("\n{err}"),
 //! }
 //! ```
+//!
+//! # Sharing a model between threads
+//!
+//! Models are [Send] and [Sync], and their weights are never modified during inference,
+//! so one loaded model can be used by any number of [InferenceSession]s at once. Each
+//! session owns its own key/value memory and scratch buffers, so the usual pattern is to
+//! wrap the model in an [Arc](std::sync::Arc) and start one session per thread:
+//!
+//! ```no_run
+//! use std::sync::Arc;
+//!
+//! let model: Arc<dyn llm::Model> = Arc::from(llm::load_dynamic(
+//!     Some(llm::ModelArchitecture::Llama),
+//!     std::path::Path::new("/path/to/model"),
+//!     llm::TokenizerSource::Embedded,
+//!     Default::default(),
+//!     |_| {},
+//! )?);
+//!
+//! let handles: Vec<_> = (0..4)
+//!     .map(|_| {
+//!         let model = model.clone();
+//!         std::thread::spawn(move || {
+//!             let _session = model.start_session(Default::default());
+//!             // ... use the session with `model.as_ref()` ...
+//!         })
+//!     })
+//!     .collect();
+//! for handle in handles {
+//!     handle.join().unwrap();
+//! }
+//! # Ok::<(), llm::LoadError>(())
+//! ```
+//!
+//! When GPU acceleration is enabled, graph computation is serialized between threads,
+//! as the GPU backends share global state. Modifying the weights (e.g. with
+//! [Model::apply_lora_adapter]) requires exclusive access to the model.
 #![deny(missing_docs)]
 
+mod model_loader;
+pub mod sandbox;
+
 use std::{
     error::Error,
     fmt::{Debug, Display},
+    io::{Read, Seek},
     path::Path,
     str::FromStr,
 };
@@ -77,18 +118,40 @@ use std::{
 // Try not to expose too many GGML details here.
 // This is the "user-facing" API, and GGML may not always be our backend.
 pub use llm_base::{
-    conversation_inference_callback, feed_prompt_callback,
+    checksums_path, conversation_inference_callback, convert_hf, convert_hf_vocabulary,
+    estimate_memory, feed_prompt_callback, find_shards, generate,
     ggml::accelerator::get_accelerator as ggml_get_accelerator,
     ggml::accelerator::Accelerator as GgmlAccelerator, ggml::format as ggml_format,
-    ggml::RoPEOverrides, load, load_progress_callback_stdout, quantize, samplers, ElementType,
-    FileType, FileTypeFormat, FormatMagic, Hyperparameters, InferenceError, InferenceFeedback,
-    InferenceParameters, InferenceRequest, InferenceResponse, InferenceSession,
-    InferenceSessionConfig, InferenceSnapshot, InferenceSnapshotRef, InferenceStats,
-    InvalidTokenBias, KnownModel, LoadError, LoadProgress, Loader, Model, ModelKVMemoryType,
-    ModelParameters, OutputRequest, Prompt, QuantizeError, QuantizeProgress, RewindError,
-    SnapshotError, TokenBias, TokenId, TokenUtf8Buffer, TokenizationError, Tokenizer,
-    TokenizerSource,
+    ggml::RoPEOverrides, is_compressed, load, load_from_reader, load_progress_callback_stdout,
+    merge, merge_lora, merge_shards, pack, quantize, read_gguf_metadata, remove_gguf_metadata,
+    repair, samplers, set_gguf_metadata, split, test_vocabulary, unpack, upgrade, write_checksums,
+    write_test_lora_adapter, write_test_model, write_vocabulary_only, AcquiredSlot, ChatTemplate,
+    Choice, ChooseError, CompressedReader, ContainerType, ControlVector, ControlVectorError,
+    Conversation, ConversationError, ConvertError, ConvertProgress, DescribeHyperparameters,
+    ElementType, EmbeddedTokenizer, EmbeddingError, EmbeddingOptions, Embeddings, EvaluatedLayers,
+    FeedPromptProgress, FileType, FileTypeFormat, FormatMagic, GenerateOptions, GeneratedSequence,
+    GenerationPreset, GenerationResult, GgufMetadataError, HeaderRewrite, HfConfig,
+    HuggingFaceTokenizer, Hyperparameters, InferenceCallbackResult, InferenceError,
+    InferenceFeedback, InferenceParameters, InferenceParametersBuilder, InferenceRequest,
+    InferenceRequestBuilder, InferenceResponse, InferenceSession, InferenceSessionConfig,
+    InferenceSessionConfigBuilder, InferenceSessionConfigError, InferenceSnapshot,
+    InferenceSnapshotRef, InferenceStats, InfillError, InfillRequest, InfillRequestBuilder,
+    InfillTokens, InvalidTokenBias, KnownModel, LatencyStats, LoadError, LoadProgress, Loader,
+    LogitsProcessor, LoraAdapterConfig, LoraParameters, MemoryEstimate, MemoryStats, MergeError,
+    MergeMethod, MergeProgress, MetadataValue, Model, ModelFile, ModelHyperparameters,
+    ModelKVMemoryType, ModelLoadedEvent, ModelParameters, OutputRequest, OverflowStrategy,
+    PackError, PackStats, PendingPrompt, Pooling, PresetError, Prompt, PromptFedEvent,
+    PromptTruncation, QuantizeError, QuantizeProgress, RankedSequence, ReadSeek, RepairError,
+    RepairOptions, RepairReport, RerankError, RerankTemplate, RewindError, SessionSlots, Shard,
+    SnapshotError, SplitError, SplitManifest, SplitReader, StopReason, StopSequences,
+    TelemetrySink, TensorChecksums, TensorNameMapping, TensorNameMappingError, TestModel,
+    TestModelError, TokenBias, TokenGeneratedEvent, TokenId, TokenLogprobs, TokenTiming,
+    TokenUtf8Buffer, TokenizationError, Tokenizer, TokenizerSource, Turn,
+    DEFAULT_SUMMARY_INSTRUCTION,
 };
+#[cfg(feature = "http")]
+pub use llm_base::{load_from_url, HttpRangeReader};
+pub use model_loader::ModelLoader;
 
 use serde::Serialize;
 
@@ -120,12 +183,26 @@ macro_rules! define_models {
                     Self::$model_pascalcase,
                 )*
             ];
+
+            /// The registrations of all available model architectures, in the same order as [Self::ALL].
+            pub const REGISTRY: &'static [ModelArchitectureRegistration] = &[
+                $(
+                    #[cfg(feature = $model_lowercase_str)]
+                    ModelArchitectureRegistration {
+                        architecture: Self::$model_pascalcase,
+                        name: $model_lowercase_str,
+                        display_name: $display_name,
+                        load: load_boxed::<models::$model_pascalcase>,
+                        load_from_reader: load_boxed_from_reader::<models::$model_pascalcase>,
+                    },
+                )*
+            ];
         }
 
         impl ModelArchitecture {
             /// Use a visitor to dispatch some code based on the model architecture.
             pub fn visit<R>(&self, visitor: &mut impl ModelArchitectureVisitor<R>) -> R {
-                match self {
+                match *self {
                     $(
                         #[cfg(feature = $model_lowercase_str)]
                         Self::$model_pascalcase => visitor.visit::<models::$model_pascalcase>(),
@@ -138,7 +215,6 @@ macro_rules! define_models {
             type Err = UnsupportedModelArchitecture;
 
             fn from_str(s: &str) -> Result<Self, Self::Err> {
-                use ModelArchitecture::*;
                 match s
                     .to_lowercase()
                     .chars()
@@ -148,7 +224,12 @@ macro_rules! define_models {
                 {
                     $(
                         #[cfg(feature = $model_lowercase_str)]
-                        $model_lowercase_str => Ok($model_pascalcase),
+                        $model_lowercase_str => Ok(Self::$model_pascalcase),
+                        #[cfg(not(feature = $model_lowercase_str))]
+                        $model_lowercase_str => Err(UnsupportedModelArchitecture(format!(
+                            "{s} is supported, but was not enabled in this build of `llm`; enable the `{}` feature to use it",
+                            $model_lowercase_str
+                        ))),
                     )*
 
                     _ => Err(UnsupportedModelArchitecture(format!(
@@ -160,7 +241,7 @@ macro_rules! define_models {
 
         impl Display for ModelArchitecture {
             fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
-                match self {
+                match *self {
                     $(
                         #[cfg(feature = $model_lowercase_str)]
                         Self::$model_pascalcase => write!(f, $display_name),
@@ -178,9 +259,144 @@ define_models!(
     (gptneox, "gptneox", GptNeoX, llm_gptneox, "GPT-NeoX"),
     (llama, "llama", Llama, llm_llama, "LLaMA"),
     (mpt, "mpt", Mpt, llm_mpt, "MPT"),
+    (bigcode, "bigcode", GptBigCode, llm_bigcode, "GPT-BigCode"),
     (falcon, "falcon", Falcon, llm_falcon, "Falcon")
 );
 
+impl ModelArchitecture {
+    /// The registration of this architecture, which can be used to load models of it.
+    pub fn registration(&self) -> &'static ModelArchitectureRegistration {
+        ModelArchitecture::REGISTRY
+            .iter()
+            .find(|r| r.architecture == *self)
+            .expect("all available architectures should be registered")
+    }
+
+    /// The name of this architecture, as accepted by [ModelArchitecture::from_str].
+    pub fn name(&self) -> &'static str {
+        self.registration().name
+    }
+}
+
+/// The signature of a function that loads a model of a specific architecture.
+pub type DynamicLoadFn = fn(
+    &Path,
+    TokenizerSource,
+    ModelParameters,
+    &mut dyn FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError>;
+
+/// The signature of a function that loads a model of a specific architecture from a reader.
+pub type DynamicLoadFromReaderFn = fn(
+    &mut dyn ReadSeek,
+    &Path,
+    TokenizerSource,
+    ModelParameters,
+    &mut dyn FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError>;
+
+#[derive(Clone, Copy)]
+/// A model architecture, along with the information needed to load models of it
+/// without knowing the architecture at compile time.
+pub struct ModelArchitectureRegistration {
+    /// The architecture.
+    pub architecture: ModelArchitecture,
+    /// The name of the architecture, as accepted by [ModelArchitecture::from_str].
+    pub name: &'static str,
+    /// The human-readable name of the architecture.
+    pub display_name: &'static str,
+    /// Loads a model of this architecture.
+    pub load: DynamicLoadFn,
+    /// Loads a model of this architecture from a reader.
+    pub load_from_reader: DynamicLoadFromReaderFn,
+}
+impl Debug for ModelArchitectureRegistration {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ModelArchitectureRegistration")
+            .field("architecture", &self.architecture)
+            .field("name", &self.name)
+            .field("display_name", &self.display_name)
+            .finish()
+    }
+}
+
+// Unused if no architectures are enabled.
+#[allow(dead_code)]
+fn load_boxed<M: KnownModel + 'static>(
+    path: &Path,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: &mut dyn FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    Ok(Box::new(load::<M>(
+        path,
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )?))
+}
+
+// Unused if no architectures are enabled.
+#[allow(dead_code)]
+fn load_boxed_from_reader<M: KnownModel + 'static>(
+    reader: &mut dyn ReadSeek,
+    name: &Path,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: &mut dyn FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    Ok(Box::new(load_from_reader::<M>(
+        reader,
+        name,
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )?))
+}
+
+/// Guesses the architecture of the GGML model at `path`, for models whose architecture is
+/// not known, such as those in old collections of models. GGML files do not record their
+/// architecture, so this is only a best guess: it reads the header of the model as each of
+/// the available architectures, and matches the names of its tensors against those that
+/// the architecture loads.
+///
+/// Returns the architectures that the model could be, best match first. Some
+/// architectures cannot be told apart by the names of their tensors (e.g. GPT-2 and
+/// GPT-BigCode), so more than one architecture may match equally well.
+pub fn guess_model_architectures(path: &Path) -> Vec<ModelArchitecture> {
+    struct GuessVisitor<'a>(&'a Path);
+    impl ModelArchitectureVisitor<Option<usize>> for GuessVisitor<'_> {
+        fn visit<M: KnownModel + 'static>(&mut self) -> Option<usize> {
+            let model_file =
+                ModelFile::<M::Hyperparameters>::open(self.0, TokenizerSource::Embedded).ok()?;
+            // The test model has the tensors of the architecture, for its first layers.
+            let expected = M::test_model(model_file.tokenizer.len())?.tensors;
+            let matching = expected
+                .iter()
+                .filter(|(name, _)| model_file.tensors.contains_key(name))
+                .count();
+            // The first tensor is an embedding, which all models of the architecture have.
+            let has_embeddings = expected
+                .first()
+                .map_or(false, |(name, _)| model_file.tensors.contains_key(name));
+            (has_embeddings && matching * 2 >= expected.len()).then_some(matching)
+        }
+    }
+
+    let mut matches: Vec<_> = ModelArchitecture::ALL
+        .iter()
+        .filter_map(|architecture| {
+            let matching = architecture.visit(&mut GuessVisitor(path))?;
+            Some((*architecture, matching))
+        })
+        .collect();
+    matches.sort_by_key(|(_, matching)| std::cmp::Reverse(*matching));
+    matches
+        .into_iter()
+        .map(|(architecture, _)| architecture)
+        .collect()
+}
+
 /// Used to dispatch some code based on the model architecture.
 pub trait ModelArchitectureVisitor<R> {
     /// Visit a model architecture.
@@ -216,56 +432,234 @@ pub fn load_dynamic(
     path: &Path,
     tokenizer_source: TokenizerSource,
     params: ModelParameters,
-    load_progress_callback: impl FnMut(LoadProgress),
+    mut load_progress_callback: impl FnMut(LoadProgress),
 ) -> Result<Box<dyn Model>, LoadError> {
-    fn load_model<M: KnownModel + 'static>(
-        path: &Path,
-        tokenizer_source: TokenizerSource,
-        params: ModelParameters,
-        load_progress_callback: impl FnMut(LoadProgress),
-    ) -> Result<Box<dyn Model>, LoadError> {
-        Ok(Box::new(load::<M>(
-            path,
-            tokenizer_source,
-            params,
-            load_progress_callback,
-        )?))
-    }
-
     let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
         path: path.to_owned(),
     })?;
 
-    struct LoadVisitor<'a, F: FnMut(LoadProgress)> {
-        path: &'a Path,
-        tokenizer_source: TokenizerSource,
-        params: ModelParameters,
-        load_progress_callback: F,
-    }
-    impl<'a, F: FnMut(LoadProgress)> ModelArchitectureVisitor<Result<Box<dyn Model>, LoadError>>
-        for LoadVisitor<'a, F>
-    {
-        fn visit<M: KnownModel + 'static>(&mut self) -> Result<Box<dyn Model>, LoadError> {
-            load_model::<M>(
-                self.path,
-                self.tokenizer_source.clone(),
-                self.params.clone(),
-                &mut self.load_progress_callback,
-            )
-        }
-    }
+    (architecture.registration().load)(path, tokenizer_source, params, &mut load_progress_callback)
+}
+
+/// Like [load_dynamic], but loads the model from `reader` instead of a file; see
+/// [load_from_reader]. `name` identifies the model in errors.
+pub fn load_dynamic_from_reader(
+    architecture: Option<ModelArchitecture>,
+    mut reader: impl Read + Seek,
+    name: &Path,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    mut load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    let architecture = architecture.ok_or_else(|| LoadError::MissingModelArchitecture {
+        path: name.to_owned(),
+    })?;
+
+    (architecture.registration().load_from_reader)(
+        &mut reader,
+        name,
+        tokenizer_source,
+        params,
+        &mut load_progress_callback,
+    )
+}
 
-    architecture.visit(&mut LoadVisitor {
+/// Like [load_dynamic], but loads the model from `bytes`, which hold the contents of a
+/// model file; see [KnownModel::load_from_bytes].
+pub fn load_dynamic_from_bytes(
+    architecture: Option<ModelArchitecture>,
+    bytes: &[u8],
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    load_dynamic_from_reader(
+        architecture,
+        std::io::Cursor::new(bytes),
+        Path::new("<bytes>"),
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )
+}
+
+/// Like [load_dynamic], but loads the model from `url` with HTTP range requests; see
+/// [load_from_url]. The downloaded parts of the model are cached in `cache_dir`, if given.
+#[cfg(feature = "http")]
+pub fn load_dynamic_from_url(
+    architecture: Option<ModelArchitecture>,
+    url: &str,
+    cache_dir: Option<&Path>,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    load_dynamic_from_reader(
+        architecture,
+        HttpRangeReader::new(url, cache_dir)?,
+        Path::new(url),
+        tokenizer_source,
+        params,
+        load_progress_callback,
+    )
+}
+
+/// Like [load_dynamic], but with the architecture specified by its name (e.g. `"gptj"`).
+/// Any name accepted by [ModelArchitecture::from_str] can be used; see
+/// [ModelArchitecture::REGISTRY] for the available architectures.
+pub fn load_dynamic_by_name(
+    architecture: Option<&str>,
+    path: &Path,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    load_progress_callback: impl FnMut(LoadProgress),
+) -> Result<Box<dyn Model>, LoadError> {
+    let architecture = architecture
+        .map(|name| {
+            name.parse::<ModelArchitecture>().map_err(|err| {
+                LoadError::UnsupportedModelArchitecture {
+                    architecture: name.to_owned(),
+                    reason: err.to_string(),
+                }
+            })
+        })
+        .transpose()?;
+
+    load_dynamic(
+        architecture,
         path,
         tokenizer_source,
         params,
         load_progress_callback,
-    })
+    )
 }
 
 #[cfg(test)]
 mod tests {
     use super::*;
+    use std::path::PathBuf;
+
+    /// Returns the contents of the LLaMA test model, which the tests below share.
+    #[cfg(feature = "llama")]
+    fn test_model_bytes() -> Vec<u8> {
+        let mut buffer = std::io::Cursor::new(vec![]);
+        write_test_model::<models::Llama, _>(
+            &mut buffer,
+            ggml_format::SaveContainerType::GgjtV3,
+            1,
+        )
+        .unwrap();
+        buffer.into_inner()
+    }
+
+    /// Writes the LLaMA test model to the file `name` in `dir`, usually a
+    /// [tempfile::TempDir] that removes it when the test ends, and returns its path.
+    #[cfg(feature = "llama")]
+    fn write_test_model_file(dir: &Path, name: &str) -> PathBuf {
+        let path = dir.join(name);
+        std::fs::write(&path, test_model_bytes()).unwrap();
+        path
+    }
+
+    /// Writes a LoRA adapter for a few tensors of the LLaMA test model to the file `name`
+    /// in `dir`, and returns its path.
+    #[cfg(feature = "llama")]
+    fn write_test_lora_adapter_file(dir: &Path, name: &str, seed: u64) -> PathBuf {
+        let path = dir.join(name);
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        write_test_lora_adapter::<models::Llama, _>(
+            &mut file,
+            &["layers.0.attention.wq.weight", "output.weight"],
+            LoraParameters { r: 4, alpha: 4 },
+            seed,
+        )
+        .unwrap();
+        drop(file);
+        path
+    }
+
+    /// Returns the IDs of the tokens of `text`, as `model` tokenizes it.
+    fn token_ids(model: &dyn Model, text: &str, bos: bool) -> Vec<TokenId> {
+        model
+            .tokenizer()
+            .tokenize(text, bos)
+            .unwrap()
+            .into_iter()
+            .map(|(_, id)| id)
+            .collect()
+    }
+
+    /// Returns whether `a` and `b` are equal, up to floating-point rounding.
+    #[cfg(feature = "llama")]
+    fn logits_are_close(a: &[f32], b: &[f32]) -> bool {
+        a.len() == b.len() && a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4)
+    }
+
+    /// The default parameters, with a context of `context_size` tokens.
+    fn test_parameters(context_size: usize) -> ModelParameters {
+        let mut params = ModelParameters::default();
+        params.context_size = context_size;
+        params
+    }
+
+    /// Loads the LLaMA test model with a context of `context_size` tokens.
+    #[cfg(feature = "llama")]
+    fn load_test_model(context_size: usize) -> Box<dyn Model> {
+        load_test_model_with(test_parameters(context_size))
+    }
+
+    /// Loads the LLaMA test model with the given parameters.
+    #[cfg(feature = "llama")]
+    fn load_test_model_with(params: ModelParameters) -> Box<dyn Model> {
+        Box::new(
+            models::Llama::load_from_bytes(
+                &test_model_bytes(),
+                TokenizerSource::Embedded,
+                params,
+                |_| {},
+            )
+            .unwrap(),
+        )
+    }
+
+    #[test]
+    fn test_model_architecture_registry() {
+        assert_eq!(
+            ModelArchitecture::ALL.len(),
+            ModelArchitecture::REGISTRY.len()
+        );
+        for (arch, registration) in ModelArchitecture::ALL
+            .iter()
+            .zip(ModelArchitecture::REGISTRY)
+        {
+            assert_eq!(arch, &registration.architecture);
+            assert_eq!(
+                arch,
+                &registration.name.parse::<ModelArchitecture>().unwrap()
+            );
+            assert_eq!(arch.to_string(), registration.display_name);
+        }
+    }
+
+    #[test]
+    fn test_models_can_be_shared_between_threads() {
+        fn assert_send_sync<T: Send + Sync + ?Sized>() {}
+        fn assert_send<T: Send>() {}
+
+        assert_send_sync::<dyn Model>();
+        assert_send_sync::<std::sync::Arc<dyn Model>>();
+        assert_send::<InferenceSession>();
+
+        struct SendSyncVisitor;
+        impl ModelArchitectureVisitor<()> for SendSyncVisitor {
+            fn visit<M: KnownModel + 'static>(&mut self) {
+                assert_send_sync::<M>();
+            }
+        }
+        for architecture in ModelArchitecture::ALL {
+            architecture.visit(&mut SendSyncVisitor);
+        }
+    }
 
     #[test]
     fn test_model_architecture_from_str() {
@@ -276,4 +670,1940 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn test_test_models_load_and_evaluate() {
+        struct WriteVisitor<'a>(&'a Path);
+        impl ModelArchitectureVisitor<()> for WriteVisitor<'_> {
+            fn visit<M: KnownModel + 'static>(&mut self) {
+                let mut file = std::io::BufWriter::new(std::fs::File::create(self.0).unwrap());
+                write_test_model::<M, _>(&mut file, ggml_format::SaveContainerType::GgjtV3, 1)
+                    .unwrap();
+            }
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        for architecture in ModelArchitecture::ALL {
+            let path = dir.path().join(format!("{}.bin", architecture.name()));
+            architecture.visit(&mut WriteVisitor(&path));
+
+            let model = load_dynamic(
+                Some(*architecture),
+                &path,
+                TokenizerSource::Embedded,
+                test_parameters(64),
+                |_| {},
+            )
+            .unwrap();
+            assert_eq!(model.tokenizer().len(), test_vocabulary().len());
+
+            let mut session = model.start_session(Default::default());
+            session.set_capture_layer_outputs(true);
+            let mut output_request = OutputRequest {
+                all_logits: Some(vec![]),
+                ..Default::default()
+            };
+            let tokens = token_ids(model.as_ref(), "Hello, world!", false);
+            model
+                .evaluate(&mut session, &tokens, &mut output_request)
+                .unwrap();
+
+            let logits = output_request.all_logits.unwrap();
+            assert_eq!(logits.len(), tokens.len() * model.tokenizer().len());
+            assert!(logits.iter().all(|l| l.is_finite()), "{architecture}");
+
+            // The test models have two layers, with an embedding size of 64.
+            let layer_outputs = session.layer_outputs();
+            assert_eq!(layer_outputs.len(), 2, "{architecture}");
+            for output in layer_outputs {
+                let output = output.as_ref().unwrap();
+                assert_eq!(output.len(), tokens.len() * 64, "{architecture}");
+                assert!(output.iter().all(|v| v.is_finite()), "{architecture}");
+            }
+
+            session.set_record_token_timings(true);
+            session.set_record_logprobs(Some(3));
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+            for _ in 0..2 {
+                // The test models are random, so they may well generate an end-of-text token.
+                let _ = session.infer_next_token(
+                    model.as_ref(),
+                    &Default::default(),
+                    &mut Default::default(),
+                    &mut rng,
+                );
+            }
+            let timings = session.token_timings();
+            assert_eq!(timings.len(), 2, "{architecture}");
+            assert_eq!(timings[1].token_id, *session.tokens().last().unwrap());
+            assert!(timings[0].timestamp <= timings[1].timestamp);
+
+            let logprobs = session.token_logprobs();
+            assert_eq!(logprobs.len(), 2, "{architecture}");
+            for token in logprobs {
+                assert_eq!(token.top_logprobs.len(), 3, "{architecture}");
+                assert!(token.top_logprobs[0].1 >= token.top_logprobs[2].1);
+                assert!(token.logprob <= 0.0 && token.logprob <= token.top_logprobs[0].1);
+            }
+
+            if model.supports_rewind() {
+                let tokens = session.tokens().to_vec();
+                let choice = session
+                    .choose(model.as_ref(), &["Hello", " world"])
+                    .unwrap();
+                assert_eq!(choice.scores.len(), 2, "{architecture}");
+                assert!(choice.scores.iter().all(|s| s.is_finite() && *s <= 0.0));
+                assert_eq!(session.tokens(), tokens, "{architecture}");
+            }
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_choose_empty_candidate() {
+        let model = load_test_model(64);
+        let mut session = model.start_session(Default::default());
+        session
+            .feed_prompt(
+                model.as_ref(),
+                "Is it red?",
+                &mut Default::default(),
+                |_| InferenceFeedback::Continue,
+            )
+            .unwrap();
+        let tokens = session.tokens().to_vec();
+
+        // An empty candidate would score 0.0, more than any real candidate.
+        assert!(matches!(
+            session.choose(model.as_ref(), &[" yes", "", " no"]),
+            Err(ChooseError::EmptyCandidate { index: 1 })
+        ));
+        assert_eq!(session.tokens(), tokens);
+
+        let choice = session.choose(model.as_ref(), &[" yes", " no"]).unwrap();
+        assert!(choice.scores.iter().all(|s| s.is_finite() && *s < 0.0));
+        assert_eq!(session.tokens(), tokens);
+    }
+
+    #[test]
+    fn test_alibi_bias_max() {
+        struct ScoreVisitor(Option<f32>);
+        impl ModelArchitectureVisitor<Vec<f32>> for ScoreVisitor {
+            fn visit<M: KnownModel + 'static>(&mut self) -> Vec<f32> {
+                let mut buffer = std::io::Cursor::new(vec![]);
+                write_test_model::<M, _>(&mut buffer, ggml_format::SaveContainerType::GgjtV3, 1)
+                    .unwrap();
+                let model = M::load_from_bytes(
+                    &buffer.into_inner(),
+                    TokenizerSource::Embedded,
+                    {
+                        let mut params = test_parameters(64);
+                        params.alibi_bias_max = self.0;
+                        params
+                    },
+                    |_| {},
+                )
+                .unwrap();
+                model.score("Hello, world!", Default::default()).unwrap()
+            }
+        }
+
+        // The architectures that use ALiBi are biased by it, and the others ignore it.
+        for architecture in ModelArchitecture::ALL {
+            let uses_alibi = matches!(architecture.name(), "bloom" | "mpt");
+            let default = architecture.visit(&mut ScoreVisitor(None));
+            let overridden = architecture.visit(&mut ScoreVisitor(Some(1.0)));
+            assert_eq!(default != overridden, uses_alibi, "{architecture}");
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_estimate_memory() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "estimate-memory.bin");
+
+        let estimate = estimate_memory::<models::Llama>(&path, 64, 8).unwrap();
+        let file_size = std::fs::metadata(&path).unwrap().len() as usize;
+        assert!(estimate.weights > 0 && estimate.weights < 2 * file_size);
+
+        let longer_context = estimate_memory::<models::Llama>(&path, 128, 8).unwrap();
+        assert_eq!(longer_context.weights, estimate.weights);
+        assert!(longer_context.kv_cache > estimate.kv_cache);
+
+        let larger_batch = estimate_memory::<models::Llama>(&path, 64, 32).unwrap();
+        assert!(larger_batch.scratch > estimate.scratch);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_load_from_reader() {
+        let model = load_dynamic_from_reader(
+            Some(ModelArchitecture::Llama),
+            std::io::Cursor::new(test_model_bytes()),
+            Path::new("<memory>"),
+            TokenizerSource::Embedded,
+            test_parameters(64),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(model.tokenizer().len(), test_vocabulary().len());
+
+        let logits = model.score("Hello, world!", Default::default()).unwrap();
+        assert!(logits.iter().all(|l| l.is_finite()));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_load_without_mmap_matches_mmap() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "no-mmap.bin");
+
+        // Without mmap, the tensors are read on several threads.
+        let score = |prefer_mmap| {
+            let model = load_dynamic(
+                Some(ModelArchitecture::Llama),
+                &path,
+                TokenizerSource::Embedded,
+                {
+                    let mut params = test_parameters(64);
+                    params.prefer_mmap = prefer_mmap;
+                    params
+                },
+                |_| {},
+            )
+            .unwrap();
+            model.score("Hello, world!", Default::default()).unwrap()
+        };
+        assert_eq!(score(false), score(true));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_stream_weights() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "stream-weights.bin");
+        let load_with = |stream_weights, prefer_mmap| {
+            ModelLoader::from_path(&path)
+                .context_size(64)
+                .mmap(prefer_mmap)
+                .stream_weights(stream_weights)
+                .load_as::<models::Llama>()
+                .unwrap()
+        };
+        let score =
+            |model: &models::Llama| model.score("Hello, world!", Default::default()).unwrap();
+        let resident = score(&load_with(false, true));
+
+        // The released weights are read again from disk for every evaluation.
+        let streamed = load_with(true, true);
+        assert_eq!(score(&streamed), resident);
+        assert_eq!(score(&streamed), resident);
+
+        // Without mmap, there is nothing to stream, and the weights stay in memory.
+        assert_eq!(score(&load_with(true, false)), resident);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_lazy_loading() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "lazy-loading.bin");
+        let load_with = |lazy_loading| {
+            ModelLoader::from_path(&path)
+                .context_size(64)
+                .mmap(false)
+                .lazy_loading(lazy_loading)
+                .load_as::<models::Llama>()
+                .unwrap()
+        };
+        let score =
+            |model: &models::Llama| model.score("Hello, world!", Default::default()).unwrap();
+        let eager = score(&load_with(false));
+
+        // The weights are read on first use.
+        let lazy = load_with(true);
+        assert_eq!(score(&lazy), eager);
+        assert_eq!(score(&lazy), eager);
+
+        // The weights are not read while loading, so a file that is truncated afterwards
+        // fails when they are.
+        let lazy = load_with(true);
+        let len = std::fs::metadata(&path).unwrap().len();
+        std::fs::OpenOptions::new()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_len(len / 2)
+            .unwrap();
+        assert!(matches!(
+            lazy.load_deferred_tensors(),
+            Err(LoadError::TensorReadFailed { .. })
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_lazy_loading_without_weights() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "model.bin");
+        let model: Box<dyn Model> = Box::new(
+            ModelLoader::from_path(&path)
+                .context_size(64)
+                .mmap(false)
+                .lazy_loading(true)
+                .load_as::<models::Llama>()
+                .unwrap(),
+        );
+
+        // The model keeps the file open to read its weights, so deleting it would not lose
+        // them on Unix; emptying it does.
+        std::fs::File::create(&path).unwrap();
+
+        let mut session = model.start_session(Default::default());
+        let result = session.feed_prompt(
+            model.as_ref(),
+            "Hello, world!",
+            &mut Default::default(),
+            |_| InferenceFeedback::Continue,
+        );
+        assert!(matches!(result, Err(InferenceError::WeightsUnavailable(_))));
+        assert!(session.tokens().is_empty());
+
+        let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+        let result = session.infer_next_token(
+            model.as_ref(),
+            &Default::default(),
+            &mut Default::default(),
+            &mut rng,
+        );
+        assert!(matches!(result, Err(InferenceError::WeightsUnavailable(_))));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_stacked_lora_adapters() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "lora-base.bin");
+        let first = LoraAdapterConfig::new(
+            write_test_lora_adapter_file(dir.path(), "lora-first.bin", 2),
+            1.0,
+        );
+        let second = LoraAdapterConfig::new(
+            write_test_lora_adapter_file(dir.path(), "lora-second.bin", 3),
+            0.5,
+        );
+        let load_with = |lora_adapters| {
+            load::<models::Llama>(
+                &path,
+                TokenizerSource::Embedded,
+                {
+                    let mut params = test_parameters(64);
+                    params.lora_adapters = lora_adapters;
+                    params
+                },
+                |_| {},
+            )
+            .unwrap()
+        };
+        let score =
+            |model: &models::Llama| model.score("Hello, world!", Default::default()).unwrap();
+
+        let mut model = load_with(None);
+        let base = score(&model);
+
+        // Applying the adapters one after the other matches applying them at load time.
+        model.apply_lora_adapter(&first).unwrap();
+        let with_first = score(&model);
+        assert!(!logits_are_close(&with_first, &base));
+        model.apply_lora_adapter(&second).unwrap();
+        let loaded = load_with(Some(vec![first.clone(), second.clone()]));
+        assert!(logits_are_close(&score(&model), &score(&loaded)));
+        assert_eq!(model.lora_adapters(), vec![first.clone(), second.clone()]);
+        assert_eq!(loaded.lora_adapters(), model.lora_adapters());
+
+        // Removing an adapter reverts only its own patch.
+        model.remove_lora_adapter(&second).unwrap();
+        assert!(logits_are_close(&score(&model), &with_first));
+        model.remove_lora_adapter(&first).unwrap();
+        assert!(logits_are_close(&score(&model), &base));
+        assert!(model.lora_adapters().is_empty());
+        assert!(matches!(
+            model.remove_lora_adapter(&first),
+            Err(LoadError::LoraAdapterNotApplied { .. })
+        ));
+
+        // An adapter with a scale of zero leaves the model as it was.
+        let disabled = LoraAdapterConfig::new(&first.path, 0.0);
+        assert_eq!(score(&load_with(Some(vec![disabled]))), base);
+
+        // An adapter that cannot be applied in full, because it patches a tensor that the
+        // model does not have or lacks half of a patch, leaves the model as it was.
+        let adapter_bytes = std::fs::read(&first.path).unwrap();
+        let rename = |name: &str, from: &[u8], to: &[u8]| {
+            let mut bytes = adapter_bytes.clone();
+            for start in 0..=bytes.len() - from.len() {
+                if bytes[start..].starts_with(from) {
+                    bytes[start..start + to.len()].copy_from_slice(to);
+                }
+            }
+            let path = dir.path().join(name);
+            std::fs::write(&path, bytes).unwrap();
+            LoraAdapterConfig::new(path, 1.0)
+        };
+        let unknown_tensor = rename(
+            "lora-unknown-tensor.bin",
+            b"output.weight",
+            b"unknown.weigh",
+        );
+        let missing_half = rename(
+            "lora-missing-half.bin",
+            b"output.weight.loraB",
+            b"output.weight.loraC",
+        );
+        let unpatched = score(&model);
+        for adapter in [unknown_tensor, missing_half] {
+            assert!(matches!(
+                model.apply_lora_adapter(&adapter),
+                Err(LoadError::UnknownTensor { .. })
+            ));
+            assert_eq!(score(&model), unpatched);
+            assert!(model.lora_adapters().is_empty());
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_merge_lora() {
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "merge-base.bin");
+        let merged_path = dir.path().join("merged.bin");
+        let adapter = LoraAdapterConfig::new(
+            write_test_lora_adapter_file(dir.path(), "merge-lora.bin", 2),
+            0.5,
+        );
+
+        let mut source = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let mut destination = std::io::BufWriter::new(std::fs::File::create(&merged_path).unwrap());
+        let patched = std::cell::RefCell::new(vec![]);
+        merge_lora::<models::Llama, _, _>(
+            &mut source,
+            &mut destination,
+            TokenizerSource::Embedded.retrieve(&path).unwrap(),
+            std::slice::from_ref(&adapter),
+            ggml_format::SaveContainerType::GgjtV3,
+            None,
+            |progress| {
+                if let QuantizeProgress::LoraApplied { name, .. } = progress {
+                    patched.borrow_mut().push(name.to_owned());
+                }
+            },
+        )
+        .unwrap();
+        drop(destination);
+        let mut patched = patched.into_inner();
+        patched.sort();
+        assert_eq!(patched, ["layers.0.attention.wq.weight", "output.weight"]);
+
+        // The merged model scores like the base model with the adapter applied at load time.
+        let score = |path: &Path, lora_adapters| {
+            load::<models::Llama>(
+                path,
+                TokenizerSource::Embedded,
+                {
+                    let mut params = test_parameters(64);
+                    params.lora_adapters = lora_adapters;
+                    params
+                },
+                |_| {},
+            )
+            .unwrap()
+            .score("Hello, world!", Default::default())
+            .unwrap()
+        };
+        assert!(logits_are_close(
+            &score(&merged_path, None),
+            &score(&path, Some(vec![adapter.clone()]))
+        ));
+        assert!(!logits_are_close(
+            &score(&merged_path, None),
+            &score(&path, None)
+        ));
+        let merged = ModelFile::<Hp>::open(&merged_path, TokenizerSource::Embedded).unwrap();
+        assert_eq!(merged.tokenizer.len(), test_vocabulary().len());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_control_vectors() {
+        use std::collections::HashMap;
+
+        let model = load_test_model(64);
+        let tokens = token_ids(model.as_ref(), "Hello, world!", true);
+        let score_with = |control_vectors: &[ControlVector]| {
+            let mut session = model.start_session(Default::default());
+            session.set_control_vectors(control_vectors).unwrap();
+            session.score(model.as_ref(), &tokens).unwrap()
+        };
+        let direction = |value: f32, strength| {
+            ControlVector::new(HashMap::from([(0, vec![value; 64])]), strength)
+        };
+
+        let base = score_with(&[]);
+        assert!(logits_are_close(&score_with(&[direction(0.0, 1.0)]), &base));
+        let steered = score_with(&[direction(0.5, 1.0)]);
+        assert!(!logits_are_close(&steered, &base));
+
+        // Directions for the same layer are summed, each scaled by its strength.
+        assert!(logits_are_close(
+            &score_with(&[direction(0.25, 1.0), direction(0.25, 1.0)]),
+            &steered
+        ));
+        assert!(logits_are_close(
+            &score_with(&[direction(0.25, 2.0)]),
+            &steered
+        ));
+
+        // Restricting the vector to layers it has no direction for disables it.
+        assert!(logits_are_close(
+            &score_with(&[direction(0.5, 1.0).with_layers(1..)]),
+            &base
+        ));
+
+        let mut session = model.start_session(Default::default());
+        assert!(matches!(
+            session.set_control_vectors(&[ControlVector::new(
+                HashMap::from([(1, vec![1.0; 3])]),
+                1.0
+            )]),
+            Err(ControlVectorError::DimensionMismatch {
+                layer: 1,
+                expected: 64,
+                actual: 3
+            })
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_evaluated_layers() {
+        let model = load_test_model(64);
+        let tokens = token_ids(model.as_ref(), "Hello, world!", true);
+        let score_with = |evaluated_layers| {
+            let mut session = model.start_session(Default::default());
+            session.set_evaluated_layers(evaluated_layers);
+            session.set_capture_layer_outputs(true);
+            let logits = session.score(model.as_ref(), &tokens).unwrap();
+            (logits, session.layer_outputs().to_vec())
+        };
+
+        let (all, all_outputs) = score_with(EvaluatedLayers::default());
+        assert_eq!(all_outputs.len(), 2);
+        assert!(all_outputs
+            .iter()
+            .all(|output| output.as_ref().unwrap().len() == 64 * tokens.len()));
+
+        // Exiting after the first layer is the same as skipping the second one.
+        let (early_exit, early_exit_outputs) = score_with(EvaluatedLayers {
+            first_n: Some(1),
+            ..Default::default()
+        });
+        let (skipped, _) = score_with(EvaluatedLayers {
+            skip: [1].into_iter().collect(),
+            ..Default::default()
+        });
+        assert_eq!(early_exit, skipped);
+        assert!(!logits_are_close(&early_exit, &all));
+        assert_eq!(early_exit_outputs[0], all_outputs[0]);
+        assert_eq!(early_exit_outputs[1], None);
+
+        // Skipping the first layer feeds the embeddings straight into the second.
+        let (skipped_first, skipped_first_outputs) = score_with(EvaluatedLayers {
+            skip: [0].into_iter().collect(),
+            ..Default::default()
+        });
+        assert!(!logits_are_close(&skipped_first, &all));
+        assert_eq!(skipped_first_outputs[0], None);
+        assert_ne!(skipped_first_outputs[1], all_outputs[1]);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_tensor_overrides() {
+        let dir = tempfile::tempdir().unwrap();
+        use std::collections::HashMap;
+
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+
+        /// Saves tensors of `source` under new names, as `(name, source name)` pairs.
+        struct OverrideSaver<'a> {
+            source: &'a ModelFile<Hp>,
+            names: HashMap<String, String>,
+        }
+        impl ggml_format::SaveHandler<LoadError> for OverrideSaver<'_> {
+            fn write_hyperparameters(
+                &mut self,
+                writer: &mut dyn std::io::Write,
+            ) -> Result<(), LoadError> {
+                self.source.hyperparameters.write_ggml(writer).unwrap();
+                Ok(())
+            }
+
+            fn tensor_data(
+                &mut self,
+                tensor_name: &str,
+            ) -> Result<ggml_format::TensorSaveInfo, LoadError> {
+                let source_name = &self.names[tensor_name];
+                let info = &self.source.tensors[source_name];
+                Ok(ggml_format::TensorSaveInfo {
+                    n_dims: info.n_dims,
+                    dims: info.dims,
+                    element_type: info.element_type,
+                    data: self.source.read_tensor(source_name)?,
+                })
+            }
+        }
+
+        let path = write_test_model_file(dir.path(), "overrides-base.bin");
+        let other_path = dir.path().join("overrides-other.bin");
+        let overrides_path = dir.path().join("overrides.bin");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&other_path).unwrap());
+        write_test_model::<models::Llama, _>(&mut file, ggml_format::SaveContainerType::GgjtV3, 2)
+            .unwrap();
+        drop(file);
+        let other = ModelFile::<Hp>::open(&other_path, TokenizerSource::Embedded).unwrap();
+
+        // The overrides are read with the hyperparameters of the model, so they include its
+        // vocabulary, which is ignored.
+        let vocabulary: Vec<_> = test_vocabulary()
+            .into_iter()
+            .map(|token| (token, 0.0))
+            .collect();
+        let write_overrides = |names: &[(&str, &str)]| {
+            let names: HashMap<_, _> = names
+                .iter()
+                .map(|&(name, source)| (name.to_owned(), source.to_owned()))
+                .collect();
+            let tensor_names: Vec<_> = names.keys().cloned().collect();
+            let mut file = std::io::BufWriter::new(std::fs::File::create(&overrides_path).unwrap());
+            ggml_format::save(
+                &mut file,
+                &mut OverrideSaver {
+                    source: &other,
+                    names,
+                },
+                ggml_format::SaveContainerType::GgjtV3,
+                &vocabulary,
+                &tensor_names,
+            )
+            .unwrap();
+        };
+        let load_with = |path: &Path, tensor_overrides| {
+            load::<models::Llama>(
+                path,
+                TokenizerSource::Embedded,
+                {
+                    let mut params = test_parameters(64);
+                    params.tensor_overrides = tensor_overrides;
+                    params
+                },
+                |_| {},
+            )
+        };
+        let score =
+            |model: models::Llama| model.score("Hello, world!", Default::default()).unwrap();
+        let base = score(load_with(&path, None).unwrap());
+
+        // Overriding a single tensor changes the model.
+        write_overrides(&[("output.weight", "output.weight")]);
+        let overridden = score(load_with(&path, Some(overrides_path.clone())).unwrap());
+        assert!(!logits_are_close(&overridden, &base));
+
+        // Overriding every tensor gives the other model.
+        let all: Vec<_> = other
+            .tensors
+            .keys()
+            .map(|name| (name.as_str(), name.as_str()))
+            .collect();
+        write_overrides(&all);
+        assert_eq!(
+            score(load_with(&path, Some(overrides_path.clone())).unwrap()),
+            score(load_with(&other_path, None).unwrap())
+        );
+
+        // Overrides must replace a tensor of the model with the same dimensions.
+        write_overrides(&[("output.weight", "norm.weight")]);
+        assert!(matches!(
+            load_with(&path, Some(overrides_path.clone())),
+            Err(LoadError::TensorWrongSize { .. })
+        ));
+        write_overrides(&[("missing.weight", "norm.weight")]);
+        assert!(matches!(
+            load_with(&path, Some(overrides_path.clone())),
+            Err(LoadError::UnknownTensor { .. })
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_session_slots() {
+        let model = load_test_model(64);
+        let tokenize = |text| token_ids(model.as_ref(), text, true);
+        let system = tokenize("You are helpful.");
+        let first = tokenize("You are helpful. Hello!");
+        let second = tokenize("You are helpful. Goodbye!");
+        let unrelated = tokenize("Something else");
+        assert_eq!(first[..system.len()], system[..]);
+        assert_eq!(second[..system.len()], system[..]);
+
+        let mut slots = SessionSlots::new(model.as_ref(), 2, Default::default());
+        assert_eq!((slots.len(), slots.n_free()), (2, 2));
+
+        let mut slot = slots.acquire(model.as_ref(), &first).unwrap();
+        assert_eq!((slot.index(), slot.cached_tokens), (0, 0));
+        slot.session.score(model.as_ref(), &first).unwrap();
+        slots.release(slot);
+
+        // A request sharing a prefix reuses the slot, and only feeds the rest of its prompt.
+        let mut slot = slots.acquire(model.as_ref(), &second).unwrap();
+        assert_eq!(slot.index(), 0);
+        assert!(slot.cached_tokens >= system.len());
+        assert!(slot.cached_tokens < second.len());
+        slot.session
+            .score(model.as_ref(), &second[slot.cached_tokens..])
+            .unwrap();
+        assert_eq!(slot.session.tokens(), &second[..]);
+        let mut fresh = model.start_session(Default::default());
+        fresh.score(model.as_ref(), &second).unwrap();
+        assert!(logits_are_close(
+            &slot.session.last_logits,
+            &fresh.last_logits
+        ));
+
+        // While the slot is in use, other requests get the remaining slot, and then none.
+        let other = slots.acquire(model.as_ref(), &unrelated).unwrap();
+        assert_eq!((other.index(), other.cached_tokens), (1, 0));
+        assert!(slots.acquire(model.as_ref(), &unrelated).is_none());
+        assert_eq!(slots.n_free(), 0);
+
+        // The same prompt again always leaves a token to be fed.
+        slots.release(slot);
+        let slot = slots.acquire(model.as_ref(), &second).unwrap();
+        assert_eq!(slot.cached_tokens, second.len() - 1);
+        slots.release(slot);
+        slots.release(other);
+        assert_eq!(slots.n_free(), 2);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_attention_sinks() {
+        let model = load_test_model(16);
+
+        let infer = |attention_sinks| {
+            let mut config = InferenceSessionConfig::default();
+            config.attention_sinks = attention_sinks;
+            let mut session = model.start_session(config);
+            let result = session.infer(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &InferenceRequest::builder("Hello, world!", &Default::default())
+                    .maximum_token_count(40)
+                    .ignore_eos(true)
+                    .seed(0)
+                    .build(),
+                &mut Default::default(),
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            );
+            (session, result)
+        };
+
+        let (_, result) = infer(None);
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+
+        let (session, result) = infer(Some(4));
+        result.unwrap();
+        let prompt = token_ids(model.as_ref(), "Hello, world!", true);
+        assert_eq!(session.tokens()[..4], prompt[..4]);
+        assert_eq!(session.tokens().len(), session.n_past);
+        assert!(session.n_past < 16);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_prompt_truncation() {
+        let model = load_test_model(16);
+
+        let text = "abcdefghijklmnopqrstuvwxyz";
+        let prompt = token_ids(model.as_ref(), text, true);
+        let n_bot = usize::from(prompt.first().copied() == model.bot_token_id());
+        let feed = |prompt_truncation| {
+            let mut session = model.start_session(
+                InferenceSessionConfig::builder()
+                    .prompt_truncation(prompt_truncation)
+                    .build()
+                    .unwrap(),
+            );
+            let result = session.feed_prompt(model.as_ref(), text, &mut Default::default(), |_| {
+                Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue)
+            });
+            (session, result)
+        };
+
+        let (_, result) = feed(PromptTruncation::Error);
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+
+        // The prompt is cut to half of the 15 tokens that fit, after the tokens it keeps,
+        // leaving the rest of the context window to generate with.
+        for keep in [0, 4] {
+            let truncation = if keep == 0 {
+                PromptTruncation::TruncateStart
+            } else {
+                PromptTruncation::TruncateMiddle { keep }
+            };
+            let (mut session, result) = feed(truncation);
+            result.unwrap();
+            let n_kept = n_bot + keep;
+            let n_end = (15 - n_kept) / 2;
+            assert_eq!(session.tokens().len(), n_kept + n_end);
+            assert_eq!(session.tokens()[..n_kept], prompt[..n_kept]);
+            assert_eq!(session.tokens()[n_kept..], prompt[prompt.len() - n_end..]);
+
+            let stats = session
+                .infer(
+                    model.as_ref(),
+                    &mut rand::thread_rng(),
+                    &InferenceRequest::builder(Prompt::Tokens(&[]), &Default::default())
+                        .maximum_token_count(4)
+                        .ignore_eos(true)
+                        .seed(0)
+                        .build(),
+                    &mut Default::default(),
+                    |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+                )
+                .unwrap();
+            assert_eq!(stats.predict_tokens, 4);
+        }
+
+        // Nothing after the tokens to keep would fit.
+        let (_, result) = feed(PromptTruncation::TruncateMiddle { keep: 15 });
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_infer_sequences() {
+        let model = load_test_model(64);
+        let parameters = Default::default();
+        let request = |seed: u64| {
+            InferenceRequest::builder("Hello, world!", &parameters)
+                .maximum_token_count(8)
+                .ignore_eos(true)
+                .seed(seed)
+                .build()
+        };
+        let mut session = model.start_session(Default::default());
+        let sequences = session
+            .infer_sequences(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request(0),
+                3,
+                |_, _| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+            .unwrap();
+        assert_eq!(sequences.len(), 3);
+        assert!(sequences.iter().all(|s| s.tokens.len() == 8));
+        // The session is left with just the prompt.
+        let prompt_tokens = session.tokens().to_vec();
+        assert_eq!(session.n_past, prompt_tokens.len());
+
+        // Each sequence matches generating it on its own.
+        for (seed, sequence) in sequences.iter().enumerate() {
+            let mut session = model.start_session(Default::default());
+            session
+                .infer(
+                    model.as_ref(),
+                    &mut rand::thread_rng(),
+                    &request(seed as u64),
+                    &mut Default::default(),
+                    |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+                )
+                .unwrap();
+            assert_eq!(session.tokens()[prompt_tokens.len()..], sequence.tokens);
+        }
+
+        // The sequences are generated together, so evaluating fewer of them at a time
+        // gives the same sequences.
+        let mut session = model.start_session(
+            InferenceSessionConfig::builder()
+                .n_batch(2)
+                .build()
+                .unwrap(),
+        );
+        let batched = session
+            .infer_sequences(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request(0),
+                3,
+                |_, _| InferenceFeedback::Continue,
+            )
+            .unwrap();
+        for (a, b) in batched.iter().zip(&sequences) {
+            assert_eq!(a.tokens, b.tokens);
+        }
+
+        // All of the sequences must fit in the context window after the prompt.
+        let model = load_test_model(prompt_tokens.len() + 16);
+        let mut session = model.start_session(Default::default());
+        let result = session.infer_sequences(
+            model.as_ref(),
+            &mut rand::thread_rng(),
+            &request(0),
+            3,
+            |_, _| InferenceFeedback::Continue,
+        );
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+        assert_eq!(session.n_past, prompt_tokens.len());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_infer_best_of() {
+        let model = load_test_model(64);
+        let parameters = Default::default();
+        let request = InferenceRequest::builder("Hello, world!", &parameters)
+            .maximum_token_count(8)
+            .ignore_eos(true)
+            .seed(0)
+            .build();
+        let mut session = model.start_session(Default::default());
+        let ranked = session
+            .infer_best_of(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request,
+                4,
+                GeneratedSequence::mean_logprob,
+                |_, _| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+            .unwrap();
+
+        assert_eq!(ranked.len(), 4);
+        for candidate in &ranked {
+            assert_eq!(candidate.sequence.token_logprobs.len(), 8);
+            assert_eq!(candidate.score, candidate.sequence.mean_logprob());
+        }
+        assert!(ranked.windows(2).all(|w| w[0].score >= w[1].score));
+        // The session was not recording log-probabilities, and still is not.
+        assert!(session.token_logprobs().is_empty());
+
+        // A custom score ranks the candidates by it.
+        let mut request = request;
+        request.prompt = Prompt::Tokens(&[]);
+        let ranked = session
+            .infer_best_of(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request,
+                3,
+                |sequence| -(sequence.tokens[0] as f32),
+                |_, _| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+            .unwrap();
+        assert!(ranked
+            .windows(2)
+            .all(|w| w[0].sequence.tokens[0] <= w[1].sequence.tokens[0]));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_maximum_duration() {
+        let model = load_test_model(64);
+        let parameters = Default::default();
+        let request = |maximum_duration: Option<std::time::Duration>| {
+            InferenceRequest::builder("Hello, world!", &parameters)
+                .maximum_token_count(8)
+                .maximum_duration(maximum_duration)
+                .minimum_token_count(8)
+                .ignore_eos(true)
+                .seed(0)
+                .build()
+        };
+        let infer = |maximum_duration| {
+            let mut session = model.start_session(Default::default());
+            session
+                .infer(
+                    model.as_ref(),
+                    &mut rand::thread_rng(),
+                    &request(maximum_duration),
+                    &mut Default::default(),
+                    |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+                )
+                .unwrap()
+        };
+
+        // The prompt is fed in full, but an expired deadline stops generation before the
+        // first token, even though more tokens were required.
+        let stats = infer(Some(std::time::Duration::ZERO));
+        assert!(stats.prompt_tokens > 0);
+        assert_eq!(stats.predict_tokens, 0);
+
+        // A deadline that is too far away to represent is never reached.
+        assert_eq!(infer(Some(std::time::Duration::MAX)).predict_tokens, 8);
+        assert_eq!(infer(None).predict_tokens, 8);
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_callback_feedback() {
+        let model = load_test_model(64);
+        let parameters = Default::default();
+        let request = InferenceRequest::builder("Hello", &parameters)
+            .maximum_token_count(8)
+            .minimum_token_count(8)
+            .ignore_eos(true)
+            .seed(0)
+            .build();
+
+        // A callback that cannot fail halts generation by returning the feedback alone.
+        let mut session = model.start_session(Default::default());
+        let stats = session
+            .infer(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request,
+                &mut Default::default(),
+                |response| match response {
+                    InferenceResponse::InferredToken(_) => InferenceFeedback::Halt,
+                    _ => InferenceFeedback::Continue,
+                },
+            )
+            .unwrap();
+        assert_eq!(stats.predict_tokens, 1);
+
+        // A callback that fails stops generation with its error.
+        #[derive(Debug)]
+        struct Stop;
+        impl std::fmt::Display for Stop {
+            fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+                f.write_str("stop")
+            }
+        }
+        impl std::error::Error for Stop {}
+        let mut session = model.start_session(Default::default());
+        let result = session.infer(
+            model.as_ref(),
+            &mut rand::thread_rng(),
+            &request,
+            &mut Default::default(),
+            |response| match response {
+                InferenceResponse::InferredToken(_) => Err(Stop),
+                _ => Ok(InferenceFeedback::Continue),
+            },
+        );
+        assert!(matches!(result, Err(InferenceError::UserCallback(_))));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_generate() {
+        let model = load_test_model(16);
+        let options = GenerateOptions {
+            parameters: InferenceParameters::default()
+                .with_logits_processor(samplers::SuppressTokens(vec![model.eot_token_id()])),
+            maximum_token_count: Some(4),
+            seed: Some(0),
+            ..Default::default()
+        };
+
+        let result = generate(model.as_ref(), "Hello", &options).unwrap();
+        assert_eq!(result.stop_reason, StopReason::MaximumTokens);
+        assert_eq!(result.tokens.len(), 4);
+        assert_eq!(result.stats.predict_tokens, 4);
+        assert!(result.stats.prompt_tokens > 0);
+        assert!(!result.text.is_empty());
+
+        // Without a limit, generation stops when the context window is full instead of
+        // failing.
+        let full = generate(
+            model.as_ref(),
+            "Hello",
+            &GenerateOptions {
+                maximum_token_count: None,
+                ..options.clone()
+            },
+        )
+        .unwrap();
+        assert_eq!(full.stop_reason, StopReason::ContextFull);
+        assert_eq!(full.stats.prompt_tokens + full.tokens.len(), 15);
+        assert!(full.text.starts_with(&result.text));
+
+        // The same completion stops at a stop sequence, which is left out of the text.
+        let stop = result.text[1..].to_string();
+        let stopped = generate(
+            model.as_ref(),
+            "Hello",
+            &GenerateOptions {
+                maximum_token_count: None,
+                stop_sequences: vec![stop.clone()],
+                ..options
+            },
+        )
+        .unwrap();
+        assert_eq!(stopped.stop_reason, StopReason::StopSequence);
+        assert!(result.text.starts_with(&stopped.text));
+        assert!(!stopped.text.contains(&stop));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_zero_batch_size() {
+        let model = load_test_model(64);
+        let prompt = "Hello world, hello world";
+
+        let mut fed = model.start_session(Default::default());
+        fed.feed_prompt(model.as_ref(), prompt, &mut Default::default(), |_| {
+            InferenceFeedback::Continue
+        })
+        .unwrap();
+
+        // A batch size of 0 feeds the prompt one token at a time instead of panicking.
+        // The builder rejects a batch size of 0, but the field can still be set to it.
+        let mut config = InferenceSessionConfig::default();
+        config.n_batch = 0;
+        let mut session = model.start_session(config);
+        let mut batches = 0;
+        session
+            .feed_prompt_with_progress(model.as_ref(), prompt, &mut Default::default(), |_| {
+                batches += 1;
+                InferenceFeedback::Continue
+            })
+            .unwrap();
+        assert_eq!(session.tokens(), fed.tokens());
+        assert_eq!(batches, fed.tokens().len());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_feed_prompt_progress() {
+        let model = load_test_model(64);
+        let config = InferenceSessionConfig::builder()
+            .n_batch(2)
+            .build()
+            .unwrap();
+        let prompt = "Hello world, hello world";
+
+        let mut fed = model.start_session(Default::default());
+        fed.feed_prompt(model.as_ref(), prompt, &mut Default::default(), |_| {
+            InferenceFeedback::Continue
+        })
+        .unwrap();
+        let n_tokens = fed.tokens().len();
+        assert!(n_tokens > 2);
+
+        // Progress is reported after every batch.
+        let mut reported = vec![];
+        let mut session = model.start_session(config);
+        session
+            .feed_prompt_with_progress(
+                model.as_ref(),
+                prompt,
+                &mut Default::default(),
+                |progress| {
+                    reported.push(progress);
+                    InferenceFeedback::Continue
+                },
+            )
+            .unwrap();
+        assert_eq!(session.tokens(), fed.tokens());
+        assert_eq!(reported.len(), (n_tokens + 1) / 2);
+        for (i, progress) in reported.iter().enumerate() {
+            assert_eq!(progress.batches_fed, i + 1);
+            assert_eq!(progress.tokens_fed, ((i + 1) * 2).min(n_tokens));
+            assert_eq!(progress.total_tokens, n_tokens);
+        }
+
+        // Halting stops after the current batch.
+        let mut session = model.start_session(config);
+        session
+            .feed_prompt_with_progress(model.as_ref(), prompt, &mut Default::default(), |_| {
+                InferenceFeedback::Halt
+            })
+            .unwrap();
+        assert_eq!(session.tokens(), &fed.tokens()[..2]);
+
+        // Feeding the prompt a batch at a time gives the same session.
+        let mut session = model.start_session(config);
+        let mut pending = session.prepare_prompt(model.as_ref(), prompt).unwrap();
+        assert_eq!(pending.tokens(), fed.tokens());
+        let mut calls = 0;
+        while !pending.is_finished() {
+            let progress = session
+                .feed_prompt_batch(model.as_ref(), &mut pending, &mut Default::default())
+                .unwrap();
+            calls += 1;
+            assert_eq!(progress, reported[calls - 1]);
+        }
+        assert_eq!(calls, reported.len());
+        assert_eq!(session.tokens(), fed.tokens());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_telemetry() {
+        #[derive(Default)]
+        struct Recorder(std::sync::Mutex<Vec<String>>);
+        impl Recorder {
+            fn record(&self, event: String) {
+                self.0.lock().unwrap().push(event);
+            }
+        }
+        impl TelemetrySink for Recorder {
+            fn model_loaded(&self, event: &ModelLoadedEvent) {
+                self.record(format!("loaded {} tensors", event.tensor_count));
+            }
+            fn prompt_fed(&self, event: &PromptFedEvent) {
+                self.record(format!("fed {}", event.tokens));
+            }
+            fn token_generated(&self, _event: &TokenGeneratedEvent) {
+                self.record("token".to_owned());
+            }
+            fn generation_finished(&self, stats: &InferenceStats) {
+                self.record(format!("finished {}", stats.predict_tokens));
+            }
+            fn error(&self, error: &(dyn std::error::Error + 'static)) {
+                self.record(format!("error: {error}"));
+            }
+        }
+
+        let recorder = std::sync::Arc::new(Recorder::default());
+        let mut params = test_parameters(16);
+        params.telemetry = Some(recorder.clone() as std::sync::Arc<dyn TelemetrySink>);
+        let model = load_test_model_with(params);
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        assert_eq!(events.len(), 1);
+        assert!(events[0].starts_with("loaded "));
+
+        let parameters = Default::default();
+        let request = |prompt| {
+            InferenceRequest::builder(prompt, &parameters)
+                .maximum_token_count(3)
+                .ignore_eos(true)
+                .seed(0)
+                .build()
+        };
+        let infer = |session: &mut InferenceSession, prompt| {
+            session.infer(
+                model.as_ref(),
+                &mut rand::thread_rng(),
+                &request(prompt),
+                &mut Default::default(),
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+        };
+
+        // Sessions started from the model send their events to its sink.
+        let mut session = model.start_session(Default::default());
+        let stats = infer(&mut session, "Hi".into()).unwrap();
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        assert_eq!(
+            events,
+            [
+                format!("fed {}", stats.prompt_tokens),
+                "token".to_owned(),
+                "token".to_owned(),
+                "token".to_owned(),
+                "finished 3".to_owned(),
+            ]
+        );
+
+        // A failed inference is reported once, not also by the prompt it failed to feed.
+        let result = infer(&mut session, "abcdefghijklmnopqrstuvwxyz".into());
+        assert!(matches!(result, Err(InferenceError::ContextFull)));
+        let events = std::mem::take(&mut *recorder.0.lock().unwrap());
+        assert_eq!(events, [format!("error: {}", InferenceError::ContextFull)]);
+
+        session.set_telemetry_sink(None);
+        infer(&mut session, Prompt::Tokens(&[])).unwrap();
+        assert!(recorder.0.lock().unwrap().is_empty());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_rerank() {
+        let model = load_test_model(128);
+        // The test vocabulary has no line breaks.
+        let template = RerankTemplate {
+            document_prefix: " Document: ".to_string(),
+            question: " Relevant?".to_string(),
+            ..Default::default()
+        };
+        let documents = ["Llamas are camelids.", "Rust is a language."];
+
+        let scores = model
+            .rerank("llamas", &documents, &template, Default::default())
+            .unwrap();
+        assert_eq!(scores.len(), 2);
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+
+        // Each document is scored as if it were the only one.
+        let second = model
+            .rerank("llamas", &documents[1..], &template, Default::default())
+            .unwrap();
+        assert!((scores[1] - second[0]).abs() < 1e-4);
+
+        // Answers that start with the same token are told apart by the rest of their tokens.
+        let shared_start = RerankTemplate {
+            relevant_answer: " Llamas are".to_string(),
+            irrelevant_answer: " Llamas is".to_string(),
+            ..template.clone()
+        };
+        let scores = model
+            .rerank("llamas", &documents, &shared_start, Default::default())
+            .unwrap();
+        assert!(scores.iter().all(|s| (0.0..=1.0).contains(s)));
+        assert!(scores.iter().any(|&s| s != 0.5));
+        let second = model
+            .rerank("llamas", &documents[1..], &shared_start, Default::default())
+            .unwrap();
+        assert!((scores[1] - second[0]).abs() < 1e-4);
+
+        let template = RerankTemplate {
+            relevant_answer: String::new(),
+            ..template
+        };
+        assert!(matches!(
+            model.rerank("llamas", &documents, &template, Default::default()),
+            Err(RerankError::EmptyAnswer)
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_embed() {
+        let model = load_test_model(64);
+        let texts = ["Hello, world!", "Llamas are camelids.", "Rust"];
+        let n_embd = model.hyperparameters().n_embd;
+
+        for pooling in [Pooling::LastToken, Pooling::Mean, Pooling::EndOfText] {
+            let options = EmbeddingOptions {
+                pooling,
+                normalize: false,
+            };
+            let embeddings = model.embed(&texts, &options, Default::default()).unwrap();
+            assert_eq!(embeddings.len(), texts.len());
+            assert_eq!(embeddings.n_embd(), n_embd);
+            assert_eq!(embeddings.as_slice().len(), texts.len() * n_embd);
+
+            // Evaluating the texts together, padded to the longest of them, does not change
+            // the embeddings.
+            let config = InferenceSessionConfig::builder()
+                .n_batch(2)
+                .build()
+                .unwrap();
+            for (index, text) in texts.iter().enumerate() {
+                let single = model.embed(&[text], &options, config).unwrap();
+                let (a, b) = (embeddings.get(index).unwrap(), single.get(0).unwrap());
+                assert!(a.iter().zip(b).all(|(a, b)| (a - b).abs() < 1e-4));
+            }
+
+            // Nor does evaluating them in several passes when they do not all fit in the
+            // context window together.
+            let small_model = load_test_model(32);
+            let split = small_model
+                .embed(&texts, &options, Default::default())
+                .unwrap();
+            assert!(logits_are_close(embeddings.as_slice(), split.as_slice()));
+        }
+
+        let options = EmbeddingOptions {
+            pooling: Pooling::Mean,
+            normalize: true,
+        };
+        let embeddings = model.embed(&texts, &options, Default::default()).unwrap();
+        for embedding in embeddings.iter() {
+            let norm = embedding.iter().map(|v| v * v).sum::<f32>().sqrt();
+            assert!((norm - 1.0).abs() < 1e-4);
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_conversation_makes_room_for_messages() {
+        let model = load_test_model(128);
+        // The test vocabulary has no newlines.
+        let template = ChatTemplate {
+            system_prefix: "S:".to_string(),
+            user_prefix: "U:".to_string(),
+            assistant_prefix: "A:".to_string(),
+            message_suffix: " | ".to_string(),
+            stop_sequence: "|".to_string(),
+        };
+
+        for overflow_strategy in [
+            OverflowStrategy::Evict,
+            OverflowStrategy::Summarize {
+                instruction: "Summarize:".to_string(),
+                maximum_token_count: 8,
+            },
+        ] {
+            let mut conversation = Conversation::new(
+                model.as_ref(),
+                Default::default(),
+                template.clone(),
+                Some("Be brief."),
+            );
+            conversation.reply_token_budget = 8;
+            conversation.overflow_strategy = overflow_strategy.clone();
+
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+            for _ in 0..8 {
+                conversation
+                    .send(
+                        model.as_ref(),
+                        &mut rng,
+                        &Default::default(),
+                        "Hello!",
+                        |_| {},
+                    )
+                    .unwrap();
+                assert!(conversation.session().n_past < 128);
+            }
+            assert!(conversation.turns().len() < 8);
+            let n_history_tokens: usize = conversation.turns().iter().map(|t| t.n_tokens).sum();
+            assert!(n_history_tokens < conversation.session().n_past);
+            assert_eq!(
+                conversation.summary().is_some(),
+                overflow_strategy != OverflowStrategy::Evict
+            );
+        }
+    }
+
+    /// Loads the LLaMA test model with a newline in place of `~` in its vocabulary, which
+    /// has no newlines otherwise, for the chat templates that separate messages with them.
+    #[cfg(feature = "llama")]
+    fn load_test_model_with_newlines() -> Box<dyn Model> {
+        let mut bytes = test_model_bytes();
+        // Each token is written as its length, its bytes and its score.
+        let tilde = [&1u32.to_le_bytes()[..], b"~", &0f32.to_le_bytes()].concat();
+        let position = bytes
+            .windows(tilde.len())
+            .position(|window| window == tilde)
+            .unwrap();
+        bytes[position + 4] = b'\n';
+        Box::new(
+            models::Llama::load_from_bytes(
+                &bytes,
+                TokenizerSource::Embedded,
+                Default::default(),
+                |_| {},
+            )
+            .unwrap(),
+        )
+    }
+
+    /// A [LogitsProcessor] that makes the model generate the scripted tokens, in order.
+    #[cfg(feature = "llama")]
+    #[derive(Debug)]
+    struct ScriptedTokens(std::collections::VecDeque<TokenId>);
+    #[cfg(feature = "llama")]
+    impl LogitsProcessor for ScriptedTokens {
+        fn process(
+            &mut self,
+            _previous_tokens: &[TokenId],
+            logits: &mut [f32],
+        ) -> anyhow::Result<()> {
+            let token = self
+                .0
+                .pop_front()
+                .expect("only the scripted tokens are generated");
+            for (id, logit) in logits.iter_mut().enumerate() {
+                if id != token as usize {
+                    *logit = f32::NEG_INFINITY;
+                }
+            }
+            Ok(())
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_conversation_session_text() {
+        let model = load_test_model_with_newlines();
+        for template in [ChatTemplate::plain("User", "Bot"), ChatTemplate::chatml()] {
+            let mut conversation = Conversation::new(
+                model.as_ref(),
+                Default::default(),
+                template.clone(),
+                Some("Be brief."),
+            );
+            let mut rng = <rand::rngs::StdRng as rand::SeedableRng>::seed_from_u64(0);
+            let mut expected = format!(
+                "{}Be brief.{}",
+                template.system_prefix, template.message_suffix
+            );
+            for (message, reply) in [("Hello!", "Hi."), ("How are you?", "Fine.")] {
+                // The model ends its reply with the stop sequence.
+                let script = token_ids(
+                    model.as_ref(),
+                    &format!("{reply}{}", template.stop_sequence),
+                    false,
+                );
+                let parameters = InferenceParameters::default()
+                    .with_logits_processor(ScriptedTokens(script.into()));
+                let generated = conversation
+                    .send(model.as_ref(), &mut rng, &parameters, message, |_| {})
+                    .unwrap();
+                assert_eq!(generated, reply);
+
+                // The session has the whole message suffix after the reply, and not the
+                // stop sequence, so it matches the history that it would be rebuilt from.
+                expected.push_str(&format!(
+                    "{}{message}{}{}{reply}{}",
+                    template.user_prefix,
+                    template.message_suffix,
+                    template.assistant_prefix,
+                    template.message_suffix
+                ));
+                let session = conversation.session();
+                assert_eq!(
+                    String::from_utf8(model.tokenizer().decode(session.tokens().to_vec(), true))
+                        .unwrap(),
+                    expected
+                );
+                assert_eq!(session.tokens(), token_ids(model.as_ref(), &expected, true));
+                assert_eq!(session.n_past, session.tokens().len());
+            }
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_model_loader() {
+        let dir = tempfile::tempdir().unwrap();
+        let bytes = test_model_bytes();
+
+        let mut loaded = false;
+        let model = ModelLoader::from_bytes(&bytes)
+            .architecture(ModelArchitecture::Llama)
+            .context_size(64)
+            .progress(|progress| loaded |= matches!(progress, LoadProgress::Loaded { .. }))
+            .load()
+            .unwrap();
+        assert!(loaded);
+        assert_eq!(model.context_size(), 64);
+        assert_eq!(model.tokenizer().len(), test_vocabulary().len());
+
+        let path = dir.path().join("test-model-loader.bin");
+        std::fs::write(&path, &bytes).unwrap();
+        let model = ModelLoader::from_path(&path)
+            .context_size(32)
+            .load_as::<models::Llama>()
+            .unwrap();
+        assert_eq!(KnownModel::context_size(&model), 32);
+
+        assert!(matches!(
+            ModelLoader::from_path(&path).load(),
+            Err(LoadError::MissingModelArchitecture { .. })
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_load_from_bytes() {
+        let bytes = test_model_bytes();
+
+        let model = models::Llama::load_from_bytes(
+            &bytes,
+            TokenizerSource::Embedded,
+            Default::default(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(KnownModel::tokenizer(&model).len(), test_vocabulary().len());
+
+        // A truncated model fails to load instead of reading past the end of the buffer.
+        assert!(load_dynamic_from_bytes(
+            Some(ModelArchitecture::Llama),
+            &bytes[..bytes.len() / 2],
+            TokenizerSource::Embedded,
+            Default::default(),
+            |_| {},
+        )
+        .is_err());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_untrusted_models_fail_without_panicking() {
+        let bytes = test_model_bytes();
+        let params = test_parameters(64);
+        let load = |bytes: &[u8], params: ModelParameters| {
+            load_dynamic_from_bytes(
+                Some(ModelArchitecture::Llama),
+                bytes,
+                TokenizerSource::Embedded,
+                params,
+                |_| {},
+            )
+        };
+        // The hyperparameters of a LLaMA model follow the magic and version, in the order
+        // n_vocab, n_embd, n_mult, n_head, n_layer, n_rot and ftype.
+        let with_hyperparameter = |index: usize, value: i32| {
+            let mut bytes = bytes.clone();
+            let offset = 8 + 4 * index;
+            bytes[offset..offset + 4].copy_from_slice(&value.to_le_bytes());
+            bytes
+        };
+
+        for (index, value) in [(3, 0), (3, 7), (4, 0), (5, i32::MAX)] {
+            let result = load(&with_hyperparameter(index, value), params.clone());
+            assert!(
+                matches!(result, Err(LoadError::InvalidHyperparameters { .. })),
+                "hyperparameter {index} = {value}: {:?}",
+                result.err()
+            );
+        }
+        // n_embd that divides by n_head, but does not match the embeddings.
+        assert!(matches!(
+            load(&with_hyperparameter(1, 32), params.clone()),
+            Err(LoadError::TensorWrongSize { tensor_name, .. }) if tensor_name == "tok_embeddings.weight"
+        ));
+        let mut no_context = params.clone();
+        no_context.context_size = 0;
+        let mut no_gqa = params.clone();
+        no_gqa.n_gqa = Some(0);
+        for params in [no_context, no_gqa] {
+            assert!(matches!(
+                load(&bytes, params),
+                Err(LoadError::InvalidHyperparameters { .. })
+            ));
+        }
+        assert!(load(&[0xff; 64], params.clone()).is_err());
+        assert!(load(&bytes[..20], params.clone()).is_err());
+
+        // Whatever the hyperparameters are, the model either fails to load or evaluates.
+        for index in 0..7 {
+            for value in [0, 1, -1, 255, i32::MAX] {
+                if let Ok(model) = load(&with_hyperparameter(index, value), params.clone()) {
+                    let _ = model.score("Hello, world!", Default::default());
+                }
+            }
+        }
+
+        // Tokens that are not in the vocabulary are rejected instead of being evaluated.
+        let model = load(&bytes, params).unwrap();
+        let n_vocab = model.tokenizer().len() as TokenId;
+        assert!(model.tokenizer().token(n_vocab as usize).is_empty());
+        let mut session = model.start_session(Default::default());
+        assert!(matches!(
+            session.score(model.as_ref(), &[1, n_vocab]),
+            Err(InferenceError::TokenizationFailed(
+                TokenizationError::InvalidTokenId { token_id, index: 1 }
+            )) if token_id == n_vocab
+        ));
+        assert!(session
+            .feed_prompt(
+                model.as_ref(),
+                Prompt::Tokens(&[1, TokenId::MAX]),
+                &mut OutputRequest::default(),
+                |_| Ok::<_, std::convert::Infallible>(InferenceFeedback::Continue),
+            )
+            .is_err());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_pack_and_unpack() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "pack.bin");
+        let packed_path = path.with_extension("bin.zst");
+
+        let mut packed = std::io::BufWriter::new(std::fs::File::create(&packed_path).unwrap());
+        let stats =
+            pack::<<models::Llama as KnownModel>::Hyperparameters>(&path, &mut packed, 3).unwrap();
+        drop(packed);
+        assert_eq!(stats.original_size, std::fs::metadata(&path).unwrap().len());
+        assert!(is_compressed(&packed_path).unwrap());
+        assert!(!is_compressed(&path).unwrap());
+
+        let mut unpacked = vec![];
+        let packed = std::fs::File::open(&packed_path).unwrap();
+        unpack(packed, &mut unpacked).unwrap();
+        assert_eq!(unpacked, std::fs::read(&path).unwrap());
+
+        // Compressed models are loaded transparently.
+        let model = load_dynamic(
+            Some(ModelArchitecture::Llama),
+            &packed_path,
+            TokenizerSource::Embedded,
+            Default::default(),
+            |_| {},
+        )
+        .unwrap();
+        assert_eq!(model.tokenizer().len(), test_vocabulary().len());
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_model_file_reads_tensors_on_demand() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = write_test_model_file(dir.path(), "model-file.bin");
+
+        let model_file = ModelFile::<<models::Llama as KnownModel>::Hyperparameters>::open(
+            &path,
+            TokenizerSource::Embedded,
+        )
+        .unwrap();
+        assert_eq!(model_file.tokenizer.len(), test_vocabulary().len());
+        let hyperparameters = model_file.hyperparameters.describe();
+        assert_eq!(
+            hyperparameters.get("n_vocab"),
+            Some(&MetadataValue::Integer(test_vocabulary().len() as i64))
+        );
+        assert_eq!(hyperparameters.get("missing"), None);
+
+        let (name, info) = model_file.tensors.iter().next().unwrap();
+        assert_eq!(
+            model_file.read_tensor(name).unwrap().len(),
+            info.calc_size()
+        );
+        assert!(matches!(
+            model_file.read_tensor("missing"),
+            Err(LoadError::UnknownTensor { .. })
+        ));
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_upgrade_and_guess_architecture() {
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.bin");
+        let upgraded_path = dir.path().join("upgraded.bin");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        write_test_model::<models::Llama, _>(&mut file, ggml_format::SaveContainerType::Ggml, 1)
+            .unwrap();
+        drop(file);
+
+        assert_eq!(
+            guess_model_architectures(&path).first(),
+            Some(&ModelArchitecture::Llama)
+        );
+
+        let mut source = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let mut destination =
+            std::io::BufWriter::new(std::fs::File::create(&upgraded_path).unwrap());
+        upgrade::<models::Llama, _, _>(&mut source, &mut destination, "llama", |_| {}).unwrap();
+        drop(destination);
+
+        let legacy = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        let upgraded = ModelFile::<Hp>::open(&upgraded_path, TokenizerSource::Embedded).unwrap();
+        assert_eq!(legacy.container_type, ContainerType::Ggml);
+        assert_eq!(upgraded.container_type, ContainerType::Gguf(3));
+        let header = ggml_format::gguf::Gguf::read::<std::io::Error>(&mut std::io::BufReader::new(
+            std::fs::File::open(&upgraded_path).unwrap(),
+        ))
+        .unwrap();
+        assert_eq!(
+            header
+                .metadata
+                .get(ggml_format::gguf::ARCHITECTURE_KEY)
+                .and_then(|v| v.as_str()),
+            Some("llama")
+        );
+        assert_eq!(upgraded.hyperparameters, legacy.hyperparameters);
+        assert_eq!(upgraded.tokenizer.len(), legacy.tokenizer.len());
+        for name in legacy.tensors.keys() {
+            assert_eq!(
+                upgraded.read_tensor(name).unwrap(),
+                legacy.read_tensor(name).unwrap()
+            );
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_load_gguf_with_standard_keys() {
+        use ggml_format::gguf::{self, MetadataValue};
+        use std::io::{Read, Seek, SeekFrom};
+
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("legacy.bin");
+        let upgraded_path = dir.path().join("upgraded.gguf");
+        let standard_path = dir.path().join("standard.gguf");
+        let mut file = std::io::BufWriter::new(std::fs::File::create(&path).unwrap());
+        write_test_model::<models::Llama, _>(&mut file, ggml_format::SaveContainerType::Ggml, 1)
+            .unwrap();
+        drop(file);
+        let mut source = std::io::BufReader::new(std::fs::File::open(&path).unwrap());
+        let mut destination =
+            std::io::BufWriter::new(std::fs::File::create(&upgraded_path).unwrap());
+        upgrade::<models::Llama, _, _>(&mut source, &mut destination, "llama", |_| {}).unwrap();
+        drop(destination);
+
+        let mut upgraded = std::io::BufReader::new(std::fs::File::open(&upgraded_path).unwrap());
+        let mut header = gguf::Gguf::read::<std::io::Error>(&mut upgraded).unwrap();
+        let legacy = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        let get = |key: &str| header.metadata.get(key).and_then(|v| v.as_u64());
+        let hp = &legacy.hyperparameters;
+        assert_eq!(get("llama.embedding_length"), Some(hp.n_embd as u64));
+        assert_eq!(get("llama.block_count"), Some(hp.n_layer as u64));
+        assert_eq!(get("llama.attention.head_count"), Some(hp.n_head as u64));
+        assert_eq!(get("llama.attention.head_count_kv"), Some(hp.n_head as u64));
+        assert_eq!(get("llama.rope.dimension_count"), Some(hp.n_rot as u64));
+        assert_eq!(get(gguf::FILE_TYPE_KEY), Some(0));
+
+        // Write the model again without the hyperparameters in the layout of the older
+        // containers, as other tools do.
+        let data_position = header.tensor_data_position();
+        assert!(matches!(
+            header.metadata.remove(gguf::HYPERPARAMETERS_KEY),
+            Some(MetadataValue::Array(..))
+        ));
+        let mut data = vec![];
+        upgraded.seek(SeekFrom::Start(data_position)).unwrap();
+        upgraded.read_to_end(&mut data).unwrap();
+        let mut bytes = vec![];
+        header.write(&mut bytes).unwrap();
+        bytes.extend(data);
+        std::fs::write(&standard_path, bytes).unwrap();
+
+        let standard = ModelFile::<Hp>::open(&standard_path, TokenizerSource::Embedded).unwrap();
+        // `n_mult` is not recorded, so it is derived from the feed-forward size instead.
+        assert_eq!(
+            Hp {
+                n_mult: hp.n_mult,
+                ..standard.hyperparameters
+            },
+            legacy.hyperparameters
+        );
+        assert_eq!(standard.tokenizer.len(), legacy.tokenizer.len());
+        for name in legacy.tensors.keys() {
+            assert_eq!(
+                standard.read_tensor(name).unwrap(),
+                legacy.read_tensor(name).unwrap()
+            );
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_verify_checksums() {
+        let dir = tempfile::tempdir().unwrap();
+        use std::io::{Seek, SeekFrom, Write};
+
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+        let path = write_test_model_file(dir.path(), "checksums.bin");
+
+        let packed_path = path.with_extension("bin.zst");
+        let load_verified_with = |path: &std::path::Path, prefer_mmap| {
+            let mut params = ModelParameters::default();
+            params.verify_checksums = true;
+            params.prefer_mmap = prefer_mmap;
+            load::<models::Llama>(path, TokenizerSource::Embedded, params, |_| {})
+        };
+        // The tensors are verified as they are mapped, and as they are read.
+        let load_verified = || {
+            let mapped = load_verified_with(&path, true);
+            let read = load_verified_with(&path, false);
+            assert_eq!(mapped.is_ok(), read.is_ok());
+            mapped
+        };
+
+        // Without stored checksums, the model cannot be verified.
+        assert!(matches!(
+            load_verified(),
+            Err(LoadError::OpenFileFailed { .. })
+        ));
+
+        let checksums = write_checksums::<Hp>(&path).unwrap();
+        assert_eq!(TensorChecksums::read(&path).unwrap(), checksums);
+        load_verified().unwrap();
+
+        // A compressed model is verified with the checksums of the model it was packed from.
+        let mut packed = std::io::BufWriter::new(std::fs::File::create(&packed_path).unwrap());
+        pack::<Hp>(&path, &mut packed, 3).unwrap();
+        drop(packed);
+        load_verified_with(&packed_path, false).unwrap();
+
+        // Corrupt the first byte of a tensor.
+        let model_file = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        let (name, info) = model_file.tensors.iter().next().unwrap();
+        let mut data = model_file.read_tensor(name).unwrap();
+        data[0] ^= 0xff;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(info.start_offset)).unwrap();
+        file.write_all(&data[..1]).unwrap();
+        drop(file);
+
+        match load_verified() {
+            Err(LoadError::TensorChecksumMismatch { tensor_name, .. }) => {
+                assert_eq!(&tensor_name, name)
+            }
+            other => panic!("expected a checksum mismatch, got {:?}", other.err()),
+        }
+    }
+
+    #[cfg(feature = "llama")]
+    #[test]
+    fn test_strict_validation() {
+        let dir = tempfile::tempdir().unwrap();
+        use std::io::{Seek, SeekFrom, Write};
+
+        type Hp = <models::Llama as KnownModel>::Hyperparameters;
+        let path = write_test_model_file(dir.path(), "strict.bin");
+
+        let load_with = |strict_validation| {
+            load::<models::Llama>(
+                &path,
+                TokenizerSource::Embedded,
+                {
+                    let mut params = ModelParameters::default();
+                    params.strict_validation = strict_validation;
+                    params
+                },
+                |_| {},
+            )
+        };
+        load_with(true).unwrap();
+
+        // Replace the last value of a floating-point tensor with NaN.
+        let model_file = ModelFile::<Hp>::open(&path, TokenizerSource::Embedded).unwrap();
+        let (name, info) = model_file
+            .tensors
+            .iter()
+            .find(|(_, info)| info.element_type == ElementType::F32)
+            .unwrap();
+        let offset = info.start_offset + info.calc_size() as u64 - 4;
+        let mut file = std::fs::OpenOptions::new().write(true).open(&path).unwrap();
+        file.seek(SeekFrom::Start(offset)).unwrap();
+        file.write_all(&f32::NAN.to_le_bytes()).unwrap();
+        drop(file);
+
+        load_with(false).unwrap();
+        match load_with(true) {
+            Err(LoadError::StrictValidationFailed { reason, .. }) => {
+                assert!(reason.contains(name.as_str()), "{reason}")
+            }
+            other => panic!("expected strict validation to fail, got {:?}", other.err()),
+        }
+
+        assert!(matches!(
+            sandbox::SandboxedValidator::with_program(path.with_extension("missing"))
+                .validate(&path, ModelArchitecture::Llama),
+            Err(sandbox::SandboxError::Spawn { .. })
+        ));
+    }
+
+    #[cfg(not(feature = "falcon"))]
+    #[test]
+    fn test_disabled_model_architecture_from_str() {
+        let err = "falcon".parse::<ModelArchitecture>().unwrap_err();
+        assert!(err.to_string().contains("`falcon` feature"));
+    }
 }
@@ -0,0 +1,262 @@
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use crate::{
+    load_dynamic, load_dynamic_from_bytes, KnownModel, LoadError, LoadProgress, LoraAdapterConfig,
+    Model, ModelArchitecture, ModelParameters, RoPEOverrides, TelemetrySink, TensorNameMapping,
+    TokenizerSource,
+};
+
+/// Where a [ModelLoader] reads the model from.
+#[derive(Clone, Copy)]
+enum ModelSource<'a> {
+    Path(&'a Path),
+    Bytes(&'a [u8]),
+}
+impl fmt::Debug for ModelSource<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Path(path) => f.debug_tuple("Path").field(path).finish(),
+            Self::Bytes(bytes) => write!(f, "Bytes({} bytes)", bytes.len()),
+        }
+    }
+}
+
+/// Loads a model with the given options, as an alternative to passing [ModelParameters]
+/// and the other arguments of [load_dynamic] and [load](crate::load) by hand.
+///
+/// Options that are not set keep the defaults of [ModelParameters]: a context of 2048
+/// tokens, mmap if the format supports it, no GPU acceleration and no adapters.
+///
+/// ```no_run
+/// let model = llm::ModelLoader::from_path("/path/to/model")
+///     .architecture(llm::ModelArchitecture::Llama)
+///     .context_size(4096)
+///     .gpu_layers(20)
+///     .progress(llm::load_progress_callback_stdout)
+///     .load()?;
+/// # Ok::<(), llm::LoadError>(())
+/// ```
+pub struct ModelLoader<'a> {
+    source: ModelSource<'a>,
+    architecture: Option<ModelArchitecture>,
+    tokenizer_source: TokenizerSource,
+    params: ModelParameters,
+    progress: Box<dyn FnMut(LoadProgress) + 'a>,
+}
+impl<'a> ModelLoader<'a> {
+    /// Loads the model at `path`, which may also be the manifest of a split model or a
+    /// compressed model.
+    pub fn from_path(path: &'a (impl AsRef<Path> + ?Sized)) -> Self {
+        Self::new(ModelSource::Path(path.as_ref()))
+    }
+
+    /// Loads the model from `bytes`, which hold the contents of a model file.
+    pub fn from_bytes(bytes: &'a [u8]) -> Self {
+        Self::new(ModelSource::Bytes(bytes))
+    }
+
+    fn new(source: ModelSource<'a>) -> Self {
+        Self {
+            source,
+            architecture: None,
+            tokenizer_source: TokenizerSource::Embedded,
+            params: ModelParameters::default(),
+            progress: Box::new(|_| {}),
+        }
+    }
+
+    /// Sets the architecture of the model, which [Self::load] requires.
+    pub fn architecture(mut self, architecture: ModelArchitecture) -> Self {
+        self.architecture = Some(architecture);
+        self
+    }
+
+    /// Sets where the tokenizer is loaded from. By default, the tokenizer embedded in
+    /// the model is used.
+    pub fn tokenizer(mut self, tokenizer_source: TokenizerSource) -> Self {
+        self.tokenizer_source = tokenizer_source;
+        self
+    }
+
+    /// Replaces all of the parameters at once, including those set by the other methods.
+    pub fn parameters(mut self, params: ModelParameters) -> Self {
+        self.params = params;
+        self
+    }
+
+    /// Sets [ModelParameters::context_size].
+    pub fn context_size(mut self, context_size: usize) -> Self {
+        self.params.context_size = context_size;
+        self
+    }
+
+    /// Sets [ModelParameters::prefer_mmap].
+    pub fn mmap(mut self, prefer_mmap: bool) -> Self {
+        self.params.prefer_mmap = prefer_mmap;
+        self
+    }
+
+    /// Sets [ModelParameters::use_gpu]. All layers are offloaded unless
+    /// [Self::gpu_layers] is also set.
+    pub fn use_gpu(mut self, use_gpu: bool) -> Self {
+        self.params.use_gpu = use_gpu;
+        self
+    }
+
+    /// Enables GPU acceleration and offloads `gpu_layers` layers of the model to the GPU.
+    pub fn gpu_layers(mut self, gpu_layers: usize) -> Self {
+        self.params.use_gpu = true;
+        self.params.gpu_layers = Some(gpu_layers);
+        self
+    }
+
+    /// Applies the LoRA adapter at `path` with the given `scale`, after the adapters
+    /// added before it.
+    pub fn lora(mut self, path: impl Into<PathBuf>, scale: f32) -> Self {
+        self.params
+            .lora_adapters
+            .get_or_insert_with(Vec::new)
+            .push(LoraAdapterConfig::new(path, scale));
+        self
+    }
+
+    /// Applies the given LoRA adapters in order, after the adapters added before them.
+    pub fn lora_adapters(mut self, adapters: impl IntoIterator<Item = LoraAdapterConfig>) -> Self {
+        // Any adapters at all disable mmap, so an empty list is left unset.
+        let adapters: Vec<_> = adapters.into_iter().collect();
+        if !adapters.is_empty() {
+            self.params
+                .lora_adapters
+                .get_or_insert_with(Vec::new)
+                .extend(adapters);
+        }
+        self
+    }
+
+    /// Sets [ModelParameters::rope_overrides].
+    pub fn rope_overrides(mut self, rope_overrides: RoPEOverrides) -> Self {
+        self.params.rope_overrides = Some(rope_overrides);
+        self
+    }
+
+    /// Sets [ModelParameters::n_gqa].
+    pub fn n_gqa(mut self, n_gqa: usize) -> Self {
+        self.params.n_gqa = Some(n_gqa);
+        self
+    }
+
+    /// Sets [ModelParameters::alibi_bias_max].
+    pub fn alibi_bias_max(mut self, alibi_bias_max: f32) -> Self {
+        self.params.alibi_bias_max = Some(alibi_bias_max);
+        self
+    }
+
+    /// Replaces the tensors of the model with the same-named tensors of the file at
+    /// `path`; see [ModelParameters::tensor_overrides].
+    pub fn tensor_overrides(mut self, path: impl Into<PathBuf>) -> Self {
+        self.params.tensor_overrides = Some(path.into());
+        self
+    }
+
+    /// Sets [ModelParameters::tensor_name_mapping].
+    pub fn tensor_name_mapping(mut self, mapping: TensorNameMapping) -> Self {
+        self.params.tensor_name_mapping = Some(mapping);
+        self
+    }
+
+    /// Sets [ModelParameters::stream_weights].
+    pub fn stream_weights(mut self, stream_weights: bool) -> Self {
+        self.params.stream_weights = stream_weights;
+        self
+    }
+
+    /// Sets [ModelParameters::verify_checksums].
+    pub fn verify_checksums(mut self, verify_checksums: bool) -> Self {
+        self.params.verify_checksums = verify_checksums;
+        self
+    }
+
+    /// Sets [ModelParameters::skip_unknown_tensors].
+    pub fn skip_unknown_tensors(mut self, skip_unknown_tensors: bool) -> Self {
+        self.params.skip_unknown_tensors = skip_unknown_tensors;
+        self
+    }
+
+    /// Sets [ModelParameters::strict_validation].
+    pub fn strict_validation(mut self, strict_validation: bool) -> Self {
+        self.params.strict_validation = strict_validation;
+        self
+    }
+
+    /// Sets [ModelParameters::lazy_loading].
+    pub fn lazy_loading(mut self, lazy_loading: bool) -> Self {
+        self.params.lazy_loading = lazy_loading;
+        self
+    }
+
+    /// Sets [ModelParameters::skip_memory_check].
+    pub fn skip_memory_check(mut self, skip_memory_check: bool) -> Self {
+        self.params.skip_memory_check = skip_memory_check;
+        self
+    }
+
+    /// Sets [ModelParameters::telemetry].
+    pub fn telemetry(mut self, sink: Arc<dyn TelemetrySink>) -> Self {
+        self.params.telemetry = Some(sink);
+        self
+    }
+
+    /// Reports the progress of loading the model to `callback`.
+    pub fn progress(mut self, callback: impl FnMut(LoadProgress) + 'a) -> Self {
+        self.progress = Box::new(callback);
+        self
+    }
+
+    /// Loads the model with the architecture set by [Self::architecture], failing with
+    /// [LoadError::MissingModelArchitecture] if it was not set.
+    pub fn load(self) -> Result<Box<dyn Model>, LoadError> {
+        match self.source {
+            ModelSource::Path(path) => load_dynamic(
+                self.architecture,
+                path,
+                self.tokenizer_source,
+                self.params,
+                self.progress,
+            ),
+            ModelSource::Bytes(bytes) => load_dynamic_from_bytes(
+                self.architecture,
+                bytes,
+                self.tokenizer_source,
+                self.params,
+                self.progress,
+            ),
+        }
+    }
+
+    /// Loads the model as the architecture `M`, which is known at compile time. The
+    /// architecture set by [Self::architecture], if any, is ignored.
+    pub fn load_as<M: KnownModel>(self) -> Result<M, LoadError> {
+        match self.source {
+            ModelSource::Path(path) => {
+                crate::load(path, self.tokenizer_source, self.params, self.progress)
+            }
+            ModelSource::Bytes(bytes) => {
+                M::load_from_bytes(bytes, self.tokenizer_source, self.params, self.progress)
+            }
+        }
+    }
+}
+impl fmt::Debug for ModelLoader<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ModelLoader")
+            .field("source", &self.source)
+            .field("architecture", &self.architecture)
+            .field("tokenizer_source", &self.tokenizer_source)
+            .field("params", &self.params)
+            .finish_non_exhaustive()
+    }
+}
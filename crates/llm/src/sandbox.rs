@@ -0,0 +1,394 @@
+//! Validates untrusted models in a separate process before they are loaded, so that a model
+//! that crashes the loader or the evaluation, or that makes them hang, cannot take the
+//! application down with it.
+//!
+//! The validation runs in a new instance of the application's own executable, which must
+//! call [run_validator_if_requested] before anything else in `main`:
+//!
+//! ```no_run
+//! fn main() -> Result<(), Box<dyn std::error::Error>> {
+//!     llm::sandbox::run_validator_if_requested();
+//!
+//!     let path = std::path::Path::new("/path/to/uploaded/model");
+//!     llm::sandbox::SandboxedValidator::new()?
+//!         .timeout(std::time::Duration::from_secs(60))
+//!         .validate(path, llm::ModelArchitecture::Llama)?;
+//!
+//!     let model = llm::ModelLoader::from_path(path)
+//!         .architecture(llm::ModelArchitecture::Llama)
+//!         .strict_validation(true)
+//!         .load()?;
+//!     // ...
+//!     # Ok(())
+//! }
+//! ```
+//!
+//! The validator loads the model with [strict validation](crate::ModelParameters::strict_validation)
+//! and evaluates a short prompt with it. Give it the same LoRA adapters, tensor overrides and
+//! tensor name mapping the application loads the model with, as those files are read by the
+//! validator too. A model that passes can then be loaded by the
+//! application without running into the crashes the validator would have, as long as the
+//! file is not replaced in between, so keep untrusted models where only the application
+//! can write them.
+
+use std::{
+    ffi::OsString,
+    io::Read,
+    path::{Path, PathBuf},
+    process::{Command, ExitStatus, Stdio},
+    time::{Duration, Instant},
+};
+
+use thiserror::Error;
+
+use crate::{LoraAdapterConfig, ModelArchitecture, ModelLoader, TensorNameMapping};
+
+const VALIDATE_VAR: &str = "LLM_SANDBOX_VALIDATE";
+const ARCHITECTURE_VAR: &str = "LLM_SANDBOX_ARCHITECTURE";
+const CONTEXT_SIZE_VAR: &str = "LLM_SANDBOX_CONTEXT_SIZE";
+
+// The options of the validator, which follow the path of the model in its arguments.
+const LORA_ARG: &str = "--lora";
+const TENSOR_OVERRIDES_ARG: &str = "--tensor-overrides";
+const TENSOR_NAME_RULE_ARG: &str = "--tensor-name-rule";
+const SKIP_UNKNOWN_TENSORS_ARG: &str = "--skip-unknown-tensors";
+
+/// The exit code of a validator that rejected the model.
+const REJECTED_EXIT_CODE: i32 = 3;
+
+#[derive(Error, Debug)]
+/// Errors encountered while validating a model with a [SandboxedValidator].
+pub enum SandboxError {
+    #[error("could not start the validator {program:?}")]
+    /// The validator process could not be started.
+    Spawn {
+        /// The original error.
+        source: std::io::Error,
+        /// The program that was started.
+        program: PathBuf,
+    },
+    #[error("the model {path:?} was rejected: {reason}")]
+    /// The model failed to load or to evaluate in the validator.
+    Rejected {
+        /// The path of the model.
+        path: PathBuf,
+        /// Why the model was rejected.
+        reason: String,
+    },
+    #[error("the validator crashed while validating {path:?} ({status})")]
+    /// The validator exited without reporting a result, e.g. because the model crashed it.
+    /// The model should be treated as rejected.
+    Crashed {
+        /// The path of the model.
+        path: PathBuf,
+        /// How the validator exited.
+        status: ExitStatus,
+    },
+    #[error("the validator did not finish validating {path:?} within {timeout:?}")]
+    /// The validator took longer than [SandboxedValidator::timeout], and was killed.
+    TimedOut {
+        /// The path of the model.
+        path: PathBuf,
+        /// The timeout that was exceeded.
+        timeout: Duration,
+    },
+}
+
+/// Validates models in a separate process; see the [module documentation](self).
+#[derive(Debug, Clone)]
+pub struct SandboxedValidator {
+    program: PathBuf,
+    context_size: usize,
+    lora_adapters: Vec<LoraAdapterConfig>,
+    tensor_overrides: Option<PathBuf>,
+    tensor_name_mapping: Option<TensorNameMapping>,
+    skip_unknown_tensors: bool,
+    timeout: Option<Duration>,
+    #[cfg(unix)]
+    user: Option<(u32, u32)>,
+    #[cfg(unix)]
+    memory_limit: Option<u64>,
+}
+impl SandboxedValidator {
+    /// Creates a validator that runs the current executable, which must call
+    /// [run_validator_if_requested].
+    pub fn new() -> Result<Self, SandboxError> {
+        let program = std::env::current_exe().map_err(|source| SandboxError::Spawn {
+            source,
+            program: PathBuf::new(),
+        })?;
+        Ok(Self::with_program(program))
+    }
+
+    /// Creates a validator that runs `program`, which must call
+    /// [run_validator_if_requested] and be built with the architectures to validate.
+    pub fn with_program(program: impl Into<PathBuf>) -> Self {
+        Self {
+            program: program.into(),
+            context_size: 512,
+            lora_adapters: vec![],
+            tensor_overrides: None,
+            tensor_name_mapping: None,
+            skip_unknown_tensors: false,
+            timeout: None,
+            #[cfg(unix)]
+            user: None,
+            #[cfg(unix)]
+            memory_limit: None,
+        }
+    }
+
+    /// Sets the context size the model is loaded with, which defaults to 512 tokens.
+    /// Hyperparameters that do not fit the context size are rejected.
+    pub fn context_size(mut self, context_size: usize) -> Self {
+        self.context_size = context_size;
+        self
+    }
+
+    /// Sets the LoRA adapters the model is loaded with; see [ModelLoader::lora_adapters].
+    pub fn lora_adapters(mut self, adapters: impl IntoIterator<Item = LoraAdapterConfig>) -> Self {
+        self.lora_adapters = adapters.into_iter().collect();
+        self
+    }
+
+    /// Sets the file whose tensors replace those of the model; see
+    /// [ModelLoader::tensor_overrides].
+    pub fn tensor_overrides(mut self, path: impl Into<PathBuf>) -> Self {
+        self.tensor_overrides = Some(path.into());
+        self
+    }
+
+    /// Sets the rules that rename the tensors of the model; see
+    /// [ModelLoader::tensor_name_mapping].
+    pub fn tensor_name_mapping(mut self, mapping: TensorNameMapping) -> Self {
+        self.tensor_name_mapping = Some(mapping);
+        self
+    }
+
+    /// Sets whether tensors that cannot be loaded are skipped; see
+    /// [ModelLoader::skip_unknown_tensors].
+    pub fn skip_unknown_tensors(mut self, skip_unknown_tensors: bool) -> Self {
+        self.skip_unknown_tensors = skip_unknown_tensors;
+        self
+    }
+
+    /// Kills the validator, and rejects the model, if validating it takes longer than
+    /// `timeout`. By default, there is no timeout.
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Runs the validator as the user `uid` and the group `gid`, e.g. an unprivileged user
+    /// that can only read the model. The application must be allowed to switch to them,
+    /// which usually means that it runs as root.
+    #[cfg(unix)]
+    pub fn user(mut self, uid: u32, gid: u32) -> Self {
+        self.user = Some((uid, gid));
+        self
+    }
+
+    /// Limits the address space of the validator to `bytes`, so that a model that makes it
+    /// allocate too much crashes it instead of exhausting the memory of the system. As the
+    /// model is memory-mapped, the limit must leave room for the whole file, as well as for
+    /// the context and the LoRA adapters. By default, there is no limit.
+    #[cfg(unix)]
+    pub fn memory_limit(mut self, bytes: u64) -> Self {
+        self.memory_limit = Some(bytes);
+        self
+    }
+
+    /// Validates the model at `path` as the given `architecture` in a new validator
+    /// process, returning once it has exited.
+    pub fn validate(
+        &self,
+        path: &Path,
+        architecture: ModelArchitecture,
+    ) -> Result<(), SandboxError> {
+        let mut command = Command::new(&self.program);
+        command
+            .arg(path)
+            .env(VALIDATE_VAR, "1")
+            .env(ARCHITECTURE_VAR, architecture.name())
+            .env(CONTEXT_SIZE_VAR, self.context_size.to_string())
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null());
+        for adapter in &self.lora_adapters {
+            command
+                .arg(LORA_ARG)
+                .arg(adapter.scale.to_string())
+                .arg(&adapter.path);
+        }
+        if let Some(path) = &self.tensor_overrides {
+            command.arg(TENSOR_OVERRIDES_ARG).arg(path);
+        }
+        for (pattern, name) in self.tensor_name_mapping.iter().flat_map(|m| m.rules()) {
+            command.args([TENSOR_NAME_RULE_ARG, pattern, name]);
+        }
+        if self.skip_unknown_tensors {
+            command.arg(SKIP_UNKNOWN_TENSORS_ARG);
+        }
+        #[cfg(unix)]
+        if let Some((uid, gid)) = self.user {
+            use std::os::unix::process::CommandExt;
+            command.uid(uid).gid(gid);
+        }
+        #[cfg(unix)]
+        if let Some(limit) = self.memory_limit {
+            use std::os::unix::process::CommandExt;
+            // SAFETY: `setrlimit` is async-signal-safe, and does not allocate or take locks
+            // that the parent may have held when it forked.
+            unsafe {
+                command.pre_exec(move || {
+                    let limit = libc::rlimit {
+                        rlim_cur: limit as libc::rlim_t,
+                        rlim_max: limit as libc::rlim_t,
+                    };
+                    if libc::setrlimit(libc::RLIMIT_AS, &limit) == 0 {
+                        Ok(())
+                    } else {
+                        Err(std::io::Error::last_os_error())
+                    }
+                });
+            }
+        }
+        let mut child = command.spawn().map_err(|source| SandboxError::Spawn {
+            source,
+            program: self.program.clone(),
+        })?;
+
+        let started_at = Instant::now();
+        let status = loop {
+            if let Some(status) = child.try_wait().map_err(|source| SandboxError::Spawn {
+                source,
+                program: self.program.clone(),
+            })? {
+                break status;
+            }
+            if let Some(timeout) = self.timeout.filter(|&t| started_at.elapsed() > t) {
+                let _ = child.kill();
+                let _ = child.wait();
+                return Err(SandboxError::TimedOut {
+                    path: path.to_owned(),
+                    timeout,
+                });
+            }
+            std::thread::sleep(Duration::from_millis(10));
+        };
+
+        match status.code() {
+            Some(0) => Ok(()),
+            Some(REJECTED_EXIT_CODE) => {
+                let mut reason = String::new();
+                if let Some(mut stdout) = child.stdout.take() {
+                    let _ = stdout.read_to_string(&mut reason);
+                }
+                Err(SandboxError::Rejected {
+                    path: path.to_owned(),
+                    reason: reason.trim().to_owned(),
+                })
+            }
+            _ => Err(SandboxError::Crashed {
+                path: path.to_owned(),
+                status,
+            }),
+        }
+    }
+}
+
+/// If this process was started by a [SandboxedValidator], validates the model it was asked
+/// to and exits; otherwise, returns immediately.
+///
+/// Call this at the start of `main`, before parsing arguments or doing anything else.
+pub fn run_validator_if_requested() {
+    if std::env::var_os(VALIDATE_VAR).is_none() {
+        return;
+    }
+
+    match validate_in_this_process() {
+        Ok(()) => std::process::exit(0),
+        Err(reason) => {
+            println!("{reason}");
+            std::process::exit(REJECTED_EXIT_CODE);
+        }
+    }
+}
+
+fn validate_in_this_process() -> Result<(), String> {
+    let mut args = std::env::args_os().skip(1);
+    let path = args
+        .next()
+        .map(PathBuf::from)
+        .ok_or("the validator was not given a model")?;
+    let architecture = std::env::var(ARCHITECTURE_VAR)
+        .map_err(|err| err.to_string())?
+        .parse::<ModelArchitecture>()
+        .map_err(|err| err.to_string())?;
+    let context_size = std::env::var(CONTEXT_SIZE_VAR)
+        .map_err(|err| err.to_string())?
+        .parse::<usize>()
+        .map_err(|err| err.to_string())?;
+
+    let mut loader = ModelLoader::from_path(&path)
+        .architecture(architecture)
+        .context_size(context_size)
+        .strict_validation(true);
+    let mut lora_adapters = vec![];
+    let mut tensor_name_rules = vec![];
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| format!("the validator option {arg:?} has no value"))
+        };
+        match arg.to_str() {
+            Some(LORA_ARG) => {
+                let scale = utf8(value()?)?
+                    .parse::<f32>()
+                    .map_err(|err| err.to_string())?;
+                lora_adapters.push(LoraAdapterConfig::new(value()?, scale));
+            }
+            Some(TENSOR_OVERRIDES_ARG) => loader = loader.tensor_overrides(value()?),
+            Some(TENSOR_NAME_RULE_ARG) => {
+                tensor_name_rules.push((utf8(value()?)?, utf8(value()?)?));
+            }
+            Some(SKIP_UNKNOWN_TENSORS_ARG) => loader = loader.skip_unknown_tensors(true),
+            _ => return Err(format!("unknown validator option {arg:?}")),
+        }
+    }
+    if !tensor_name_rules.is_empty() {
+        let mapping = TensorNameMapping::new(
+            tensor_name_rules
+                .iter()
+                .map(|(pattern, name)| (pattern.as_str(), name.as_str())),
+        )
+        .map_err(|err| err.to_string())?;
+        loader = loader.tensor_name_mapping(mapping);
+    }
+
+    let model = loader
+        .lora_adapters(lora_adapters)
+        .load()
+        .map_err(|err| error_chain(&err))?;
+    model
+        .score("Hello, world!", Default::default())
+        .map_err(|err| error_chain(&err))?;
+    Ok(())
+}
+
+/// Converts an argument of the validator to a string.
+fn utf8(arg: OsString) -> Result<String, String> {
+    arg.into_string()
+        .map_err(|arg| format!("the validator argument {arg:?} is not valid UTF-8"))
+}
+
+/// Formats `error` with its sources, as the validator only reports text.
+fn error_chain(error: &dyn std::error::Error) -> String {
+    let mut message = error.to_string();
+    let mut source = error.source();
+    while let Some(error) = source {
+        message += &format!(": {error}");
+        source = error.source();
+    }
+    message
+}
@@ -0,0 +1,558 @@
+//! An implementation of [GPT-BigCode](https://huggingface.co/docs/transformers/model_doc/gpt_bigcode)
+//! (the architecture of the StarCoder and SantaCoder code models) for the `llm` ecosystem.
+//!
+//! The architecture is GPT-2 with multi-query attention: all of the attention heads share a
+//! single key and value head.
+#![deny(missing_docs)]
+
+use ggml::Tensor;
+use llm_base::{
+    ggml,
+    model::{common, HyperparametersWriteError},
+    util, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters, GraphOutputs,
+    InferenceError, InferenceSession, InferenceSessionConfig, KnownModel, LoadError, MetadataValue,
+    ModelContext, ModelHyperparameters, ModelParameters, OutputRequest, Regex, TestModel, TokenId,
+    Tokenizer,
+};
+
+/// The GPT-BigCode model. Ref: [SantaCoder: don't reach for the stars!](https://arxiv.org/abs/2301.03988)
+///
+/// # Safety
+/// This implements [Send] and [Sync] as it is immutable after construction.
+pub struct GptBigCode {
+    params: ModelParameters,
+
+    hyperparameters: Hyperparameters,
+    tokenizer: Tokenizer,
+
+    // model-global weights
+    // normalization gain & bias
+    ln_f_g: Tensor,
+    ln_f_b: Tensor,
+    // weighted token embeddings
+    wte: Tensor,
+    // weighted positional encodings
+    wpe: Tensor,
+    // language model head
+    //
+    // Optional: if not present, the `wte` tensor is used instead.
+    lm_head: Option<Tensor>,
+
+    // weights for the model
+    layers: Vec<Layer>,
+
+    // must be kept alive for the model
+    context: ModelContext,
+}
+
+unsafe impl Send for GptBigCode {}
+unsafe impl Sync for GptBigCode {}
+
+impl KnownModel for GptBigCode {
+    type Hyperparameters = Hyperparameters;
+
+    fn new<E: std::error::Error>(
+        hyperparameters: Self::Hyperparameters,
+        mut params: ModelParameters,
+        tokenizer: Tokenizer,
+        tensor_loader: impl llm_base::TensorLoader<E>,
+    ) -> Result<Self, E> {
+        let mut tl = tensor_loader;
+
+        // model-global weights
+        let backend = params.backend(0);
+
+        let Hyperparameters {
+            n_vocab,
+            n_ctx,
+            n_embd,
+            ..
+        } = hyperparameters;
+        let wpe = tl
+            .load_with_dims("model/wpe", &[n_embd, n_ctx])?
+            .transfer_to(backend);
+        let wte = tl
+            .load_with_dims("model/wte", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+
+        // There are only position embeddings for the context the model was trained with.
+        params.context_size = params.context_size.min(n_ctx);
+
+        let ln_f_g = tl.load("model/ln_f/g")?.transfer_to(backend);
+        let ln_f_b = tl.load("model/ln_f/b")?.transfer_to(backend);
+
+        // StarCoder ties its language model head to `wte`, so conversions may omit it.
+        let lm_head = tl
+            .load_optional_with_dims("model/lm_head", &[n_embd, n_vocab])?
+            .map(|tensor| tensor.transfer_to(backend));
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("model/h{i}/"),
+            |tl| {
+                Ok(Layer {
+                    ln_1_g: tl.load("ln_1/g")?,
+                    ln_1_b: tl.load("ln_1/b")?,
+                    ln_2_g: tl.load("ln_2/g")?,
+                    ln_2_b: tl.load("ln_2/b")?,
+                    c_attn_attn_w: tl.load("attn/c_attn/w")?,
+                    c_attn_attn_b: tl.load("attn/c_attn/b")?,
+                    c_attn_proj_w: tl.load("attn/c_proj/w")?,
+                    c_attn_proj_b: tl.load("attn/c_proj/b")?,
+                    c_mlp_fc_w: tl.load("mlp/c_fc/w")?,
+                    c_mlp_fc_b: tl.load("mlp/c_fc/b")?,
+                    c_mlp_proj_w: tl.load("mlp/c_proj/w")?,
+                    c_mlp_proj_b: tl.load("mlp/c_proj/b")?,
+                })
+            },
+        )?;
+
+        let context = tl.finish();
+
+        Ok(GptBigCode {
+            hyperparameters,
+            params,
+            tokenizer,
+            layers,
+            ln_f_g,
+            ln_f_b,
+            wte,
+            wpe,
+            lm_head,
+            context,
+        })
+    }
+
+    fn start_session(&self, config: InferenceSessionConfig) -> InferenceSession {
+        InferenceSession::new(
+            config,
+            &self.params,
+            self.hyperparameters.n_layer,
+            self.hyperparameters.n_embd,
+            self.hyperparameters.n_vocab,
+        )
+    }
+
+    fn evaluate(
+        &self,
+        session: &mut InferenceSession,
+        input_tokens: &[TokenId],
+        output_request: &mut OutputRequest,
+    ) -> Result<(), InferenceError> {
+        let input_len = input_tokens.len();
+        let session_len = session.n_past;
+        let ctx_size = self.params.context_size;
+
+        let Hyperparameters {
+            n_embd,
+            n_head,
+            n_vocab,
+            n_layer,
+            ..
+        } = self.hyperparameters;
+
+        // There is a single key/value head, so only `head_dim` keys and values are stored
+        // for each token.
+        let head_dim = n_embd / n_head;
+        let n_past_and_input = session_len + input_len;
+
+        let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
+            let mut ctx0 = builder.ctx0.borrow_mut();
+            let (memory_k_size, memory_v_size) = (
+                builder.memory_k.element_size(),
+                builder.memory_v.element_size(),
+            );
+            let embd = &builder.embd;
+
+            let position_buf: Vec<i32> = (0..input_len).map(|i| (session_len + i) as i32).collect();
+
+            let mut position = ctx0.new_tensor_1d(ggml::Type::I32, input_len);
+            unsafe { position.write_data(bytemuck::cast_slice(&position_buf)) };
+
+            let mut input_layer = ctx0.op_add(
+                &ctx0.op_get_rows(&self.wte, embd),
+                &ctx0.op_get_rows(&self.wpe, &position),
+            );
+
+            let mut gf = ctx0.create_compute_graph();
+            for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
+                ctx0.set_offloading(self.params.should_offload(il));
+                ctx0.use_scratch(builder.get_scratch(0));
+                // norm
+                let mut current = ctx0.op_norm(&input_layer);
+                current = ctx0.op_add(
+                    &ctx0.op_mul(&current, &self.layers[il].ln_1_g),
+                    &self.layers[il].ln_1_b,
+                );
+
+                // attn
+                current = ctx0.op_mul_mat(&self.layers[il].c_attn_attn_w, &current);
+                current = ctx0.op_add(&current, &self.layers[il].c_attn_attn_b);
+
+                // self-attn: the fused projection is `n_embd` queries, followed by
+                // `head_dim` keys and `head_dim` values
+                let nb = current.get_nb()[1];
+                let f32_size = std::mem::size_of::<f32>();
+                let qcur = ctx0.op_view_2d(&current, (n_embd, input_len), nb, 0);
+                let kcur = ctx0.op_view_2d(&current, (head_dim, input_len), nb, f32_size * n_embd);
+                let vcur = ctx0.op_view_2d(
+                    &current,
+                    (head_dim, input_len),
+                    nb,
+                    f32_size * (n_embd + head_dim),
+                );
+
+                // store key and value to memory
+                let k = ctx0.op_view_1d(
+                    builder.memory_k,
+                    input_len * head_dim,
+                    (memory_k_size * head_dim) * (il * ctx_size + session_len),
+                );
+                let v = ctx0.op_view_1d(
+                    builder.memory_v,
+                    input_len * head_dim,
+                    (memory_v_size * head_dim) * (il * ctx_size + session_len),
+                );
+                gf.build_forward_expand(&ctx0.op_cpy(&kcur, &k));
+                gf.build_forward_expand(&ctx0.op_cpy(&vcur, &v));
+
+                let q = ctx0.op_permute(
+                    &ctx0.op_cpy(
+                        &qcur,
+                        &ctx0.new_tensor_3d(ggml::Type::F32, head_dim, n_head, input_len),
+                    ),
+                    (0, 2, 1, 3),
+                );
+
+                // The single key head is shared by every query head, which `op_mul_mat`
+                // broadcasts over. The keys are copied to 32-bit floats, as broadcasting
+                // is not supported for 16-bit memory.
+                let k = ctx0.op_cpy(
+                    &ctx0.op_view_2d(
+                        builder.memory_k,
+                        (head_dim, n_past_and_input),
+                        memory_k_size * head_dim,
+                        il * ctx_size * memory_k_size * head_dim,
+                    ),
+                    &ctx0.new_tensor_2d(ggml::Type::F32, head_dim, n_past_and_input),
+                );
+
+                let kq = ctx0.op_mul_mat(&k, &q);
+                let kq_scaled =
+                    ctx0.op_scale_inplace(&kq, &ctx0.new_f32(1f32 / f32::sqrt(head_dim as f32)));
+
+                let kq_masked = ctx0.op_diag_mask_inf_inplace(&kq_scaled, session_len);
+                let kq_softmax = ctx0.op_soft_max_inplace(&kq_masked);
+
+                let v_trans = ctx0.op_cpy(
+                    &ctx0.op_transpose(&ctx0.op_view_2d(
+                        builder.memory_v,
+                        (head_dim, n_past_and_input),
+                        memory_v_size * head_dim,
+                        il * ctx_size * memory_v_size * head_dim,
+                    )),
+                    &ctx0.new_tensor_2d(ggml::Type::F32, n_past_and_input, head_dim),
+                );
+
+                let kqv = ctx0.op_mul_mat(&v_trans, &kq_softmax);
+                let kqv_merged = ctx0.op_permute(&kqv, (0, 2, 1, 3));
+
+                current = ctx0.op_cpy(
+                    &kqv_merged,
+                    &ctx0.new_tensor_2d(ggml::Type::F32, n_embd, input_len),
+                );
+
+                // projection
+                current = ctx0.op_mul_mat(&self.layers[il].c_attn_proj_w, &current);
+                current = ctx0.op_add(&current, &self.layers[il].c_attn_proj_b);
+
+                // add input
+                current = ctx0.op_add(&current, &input_layer);
+
+                // feed-forward
+                let ff_in = current.share();
+
+                ctx0.use_scratch(builder.get_scratch(1));
+
+                // feed-forward normalization
+                current = ctx0.op_norm(&ff_in);
+                current = ctx0.op_add(
+                    &ctx0.op_mul(&current, &self.layers[il].ln_2_g),
+                    &self.layers[il].ln_2_b,
+                );
+
+                // feed-forward fully connected
+                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_fc_w, &current);
+                current = ctx0.op_add(&current, &self.layers[il].c_mlp_fc_b);
+
+                // feed-forward activation
+                current = ctx0.op_gelu(&current);
+
+                // feed-forward projection
+                current = ctx0.op_mul_mat(&self.layers[il].c_mlp_proj_w, &current);
+                current = ctx0.op_add(&current, &self.layers[il].c_mlp_proj_b);
+
+                // input for next layer
+                input_layer = builder.finish_layer(&ctx0, il, ctx0.op_add(&current, &ff_in));
+            }
+
+            ctx0.use_scratch(builder.get_scratch(0));
+
+            // normalization
+            input_layer = ctx0.op_norm(&input_layer);
+            input_layer = ctx0.op_add(&ctx0.op_mul(&input_layer, &self.ln_f_g), &self.ln_f_b);
+
+            ctx0.use_scratch(None);
+            ctx0.set_offloading(false);
+
+            let embeddings_tensor: ggml::Tensor = input_layer.share();
+
+            let head = self.lm_head.as_ref().unwrap_or(&self.wte);
+            input_layer = ctx0.op_mul_mat(head, &input_layer);
+
+            (
+                gf,
+                GraphOutputs {
+                    result: input_layer,
+                    embedding_result: embeddings_tensor,
+                },
+            )
+        })?;
+
+        // finish evaluation
+        common::read_last_token(session, &outputs.result, n_vocab, input_len);
+        common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
+        common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
+    }
+
+    fn hyperparameters(&self) -> &Self::Hyperparameters {
+        &self.hyperparameters
+    }
+
+    fn tokenizer(&self) -> &Tokenizer {
+        &self.tokenizer
+    }
+
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
+    fn context_size(&self) -> usize {
+        self.params.context_size
+    }
+
+    fn bot_token_id(&self) -> Option<TokenId> {
+        None
+    }
+
+    fn eot_token_id(&self) -> TokenId {
+        self.tokenizer.id("<|endoftext|>".as_bytes()).unwrap()
+    }
+
+    fn quantize_tensors() -> Vec<Regex> {
+        [
+            "model/wte",
+            "model/lm_head",
+            "model/h.*/attn/c_attn/w",
+            "model/h.*/attn/c_proj/w",
+            "model/h.*/mlp/c_fc/w",
+            "model/h.*/mlp/c_proj/w",
+        ]
+        .into_iter()
+        .map(|s| Regex::new(s).unwrap())
+        .collect()
+    }
+
+    fn skip_quantize_tensors() -> Vec<Regex> {
+        vec![]
+    }
+
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_ctx, n_embd, n_head, n_layer) = (2048, 64, 4, 2);
+        let head_dim = n_embd / n_head;
+
+        let mut tensors = vec![
+            ("model/wpe".to_string(), vec![n_embd, n_ctx]),
+            ("model/wte".to_string(), vec![n_embd, n_vocab]),
+            ("model/ln_f/g".to_string(), vec![n_embd]),
+            ("model/ln_f/b".to_string(), vec![n_embd]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("ln_1/g", vec![n_embd]),
+                    ("ln_1/b", vec![n_embd]),
+                    ("ln_2/g", vec![n_embd]),
+                    ("ln_2/b", vec![n_embd]),
+                    ("attn/c_attn/w", vec![n_embd, n_embd + 2 * head_dim]),
+                    ("attn/c_attn/b", vec![n_embd + 2 * head_dim]),
+                    ("attn/c_proj/w", vec![n_embd, n_embd]),
+                    ("attn/c_proj/b", vec![n_embd]),
+                    ("mlp/c_fc/w", vec![n_embd, 4 * n_embd]),
+                    ("mlp/c_fc/b", vec![4 * n_embd]),
+                    ("mlp/c_proj/w", vec![4 * n_embd, n_embd]),
+                    ("mlp/c_proj/b", vec![n_embd]),
+                ]
+                .map(|(name, dims)| (format!("model/h{i}/{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_ctx,
+                n_embd,
+                n_head,
+                n_layer,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
+    fn supports_rewind(&self) -> bool {
+        true
+    }
+}
+
+/// GPT-BigCode [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct Hyperparameters {
+    /// Size of the model's vocabulary
+    n_vocab: usize,
+    /// Size of the model's context
+    n_ctx: usize,
+    /// Size of the model's embedding layer
+    n_embd: usize,
+    /// n_head
+    n_head: usize,
+    /// Number of layers in the model
+    n_layer: usize,
+    /// file type
+    file_type: FileType,
+}
+
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: Some(self.n_ctx),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: 1,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_ctx".to_string(), self.n_ctx.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
+impl llm_base::Hyperparameters for Hyperparameters {
+    fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
+        let hyperparameters = Hyperparameters {
+            n_vocab: util::read_i32(reader)?.try_into()?,
+            n_ctx: util::read_i32(reader)?.try_into()?,
+            n_embd: util::read_i32(reader)?.try_into()?,
+            n_head: util::read_i32(reader)?.try_into()?,
+            n_layer: util::read_i32(reader)?.try_into()?,
+            file_type: util::read_filetype(reader)?,
+        };
+
+        let n_vocab = util::read_i32(reader)? as usize;
+        if hyperparameters.n_vocab != n_vocab {
+            return Err(LoadError::InvariantBroken {
+                path: None,
+                invariant: format!(
+                    "GPT-BigCode model expected n_vocab {} found {}",
+                    hyperparameters.n_vocab, n_vocab
+                ),
+            });
+        }
+
+        Ok(hyperparameters)
+    }
+
+    fn write_ggml(&self, writer: &mut dyn std::io::Write) -> Result<(), HyperparametersWriteError> {
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+        util::write_i32(writer, self.n_ctx.try_into()?)?;
+        util::write_i32(writer, self.n_embd.try_into()?)?;
+        util::write_i32(writer, self.n_head.try_into()?)?;
+        util::write_i32(writer, self.n_layer.try_into()?)?;
+        util::write_i32(writer, self.file_type.into())?;
+        util::write_i32(writer, self.n_vocab.try_into()?)?;
+
+        Ok(())
+    }
+
+    fn n_vocabulary(&self) -> usize {
+        self.n_vocab
+    }
+
+    fn file_type(&self) -> Option<FileType> {
+        Some(self.file_type)
+    }
+
+    fn file_type_mut(&mut self) -> Option<&mut FileType> {
+        Some(&mut self.file_type)
+    }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("context_length", self.n_ctx.into()),
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_ctx: metadata.usize("context_length")?,
+            n_embd: metadata.usize("embedding_length")?,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            file_type: metadata.file_type()?,
+        })
+    }
+}
+
+struct Layer {
+    // normalization
+    ln_1_g: Tensor,
+    ln_1_b: Tensor,
+
+    ln_2_g: Tensor,
+    ln_2_b: Tensor,
+
+    // attention
+    c_attn_attn_w: Tensor,
+    c_attn_attn_b: Tensor,
+
+    c_attn_proj_w: Tensor,
+    c_attn_proj_b: Tensor,
+
+    // mlp
+    c_mlp_fc_w: Tensor,
+    c_mlp_fc_b: Tensor,
+
+    c_mlp_proj_w: Tensor,
+    c_mlp_proj_b: Tensor,
+}
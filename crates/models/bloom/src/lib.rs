@@ -5,8 +5,10 @@
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters, GraphOutputs,
+    InferenceError, InferenceSession, InferenceSessionConfig, KnownModel, MetadataValue,
+    ModelContext, ModelHyperparameters, ModelParameters, OutputRequest, Regex, TestModel, TokenId,
+    Tokenizer,
 };
 
 /// The BLOOM model. Ref: [Introducing BLOOM](https://bigscience.huggingface.co/blog/bloom)
@@ -53,38 +55,41 @@ impl KnownModel for Bloom {
         let mut tl = tensor_loader;
 
         // model-global weights
-        let wte = tl.load("tok_embeddings.weight")?;
-        let norm = tl.load("norm.weight")?;
-        let norm_bias = tl.load("norm.bias")?;
-        let output_norm = tl.load("output_norm.weight")?;
-        let output_norm_bias = tl.load("output_norm.bias")?;
-        let output = tl.load("output.weight")?;
-
-        let mut layers = Vec::new();
-        for i in 0..hyperparameters.n_layer {
-            let layer = Layer {
-                attention_norm: tl.load(&format!("layers.{i}.attention_norm.weight"))?,
-                attention_norm_b: tl.load(&format!("layers.{i}.attention_norm.bias"))?,
-
-                query_key_value: tl
-                    .load(&format!("layers.{i}.attention.query_key_value.weight"))?,
-                query_key_value_b: tl
-                    .load(&format!("layers.{i}.attention.query_key_value.bias"))?,
-
-                wo: tl.load(&format!("layers.{i}.attention.wo.weight"))?,
-                wo_b: tl.load(&format!("layers.{i}.attention.wo.bias"))?,
-
-                ffn_norm: tl.load(&format!("layers.{i}.ffn_norm.weight"))?,
-                ffn_norm_b: tl.load(&format!("layers.{i}.ffn_norm.bias"))?,
-
-                w1: tl.load(&format!("layers.{i}.feed_forward.w1.weight"))?,
-                w1_b: tl.load(&format!("layers.{i}.feed_forward.w1.bias"))?,
-                w2: tl.load(&format!("layers.{i}.feed_forward.w2.weight"))?,
-                w2_b: tl.load(&format!("layers.{i}.feed_forward.w2.bias"))?,
-            };
-
-            layers.push(layer);
-        }
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let wte = tl.load_with_dims("tok_embeddings.weight", &[n_embd, n_vocab])?;
+        let backend = params.backend(0);
+
+        let norm = tl.load("norm.weight")?.transfer_to(backend);
+        let norm_bias = tl.load("norm.bias")?.transfer_to(backend);
+        let output_norm = tl.load("output_norm.weight")?.transfer_to(backend);
+        let output_norm_bias = tl.load("output_norm.bias")?.transfer_to(backend);
+        let output = tl
+            .load_with_dims("output.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("layers.{i}."),
+            |tl| {
+                Ok(Layer {
+                    attention_norm: tl.load("attention_norm.weight")?,
+                    attention_norm_b: tl.load("attention_norm.bias")?,
+                    query_key_value: tl.load("attention.query_key_value.weight")?,
+                    query_key_value_b: tl.load("attention.query_key_value.bias")?,
+                    wo: tl.load("attention.wo.weight")?,
+                    wo_b: tl.load("attention.wo.bias")?,
+                    ffn_norm: tl.load("ffn_norm.weight")?,
+                    ffn_norm_b: tl.load("ffn_norm.bias")?,
+                    w1: tl.load("feed_forward.w1.weight")?,
+                    w1_b: tl.load("feed_forward.w1.bias")?,
+                    w2: tl.load("feed_forward.w2.weight")?,
+                    w2_b: tl.load("feed_forward.w2.bias")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -118,7 +123,7 @@ impl KnownModel for Bloom {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -131,9 +136,12 @@ impl KnownModel for Bloom {
             n_layer,
             file_type: _,
         } = self.hyperparameters;
+        let positional_bias = common::PositionalBias::Alibi {
+            bias_max: self.params.alibi_bias_max.unwrap_or(8.0),
+        };
 
         let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
-            let ctx0 = builder.ctx0.borrow();
+            let mut ctx0 = builder.ctx0.borrow_mut();
             let (memory_k_size, memory_v_size) = (
                 builder.memory_k.element_size(),
                 builder.memory_v.element_size(),
@@ -148,6 +156,12 @@ impl KnownModel for Bloom {
 
             let mut gf = ctx0.create_compute_graph();
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
+                ctx0.set_offloading(self.params.should_offload(il));
+
                 let input_self_attention = input_layer.share();
                 let mut current: ggml::Tensor;
 
@@ -237,7 +251,8 @@ impl KnownModel for Bloom {
 
                 //alibi
                 // KQ_scaled_alibi = KQ_scaled + alibi_bias
-                let k_q_scaled_alibi = ctx0.op_alibi(&k_q_scaled, session_len, n_head, 8f32);
+                let k_q_scaled_alibi =
+                    positional_bias.apply(&ctx0, &k_q_scaled, session_len, n_head);
 
                 // KQ_masked = mask_past(KQ_scaled)
                 let k_q_masked = ctx0.op_diag_mask_inf(&k_q_scaled_alibi, session_len);
@@ -310,7 +325,7 @@ impl KnownModel for Bloom {
                 current = ctx0.op_add(&current, &input_feed_forward);
 
                 // input for next layer
-                input_layer = current;
+                input_layer = builder.finish_layer(&ctx0, il, current);
             }
 
             // norm
@@ -323,6 +338,7 @@ impl KnownModel for Bloom {
 
             let embeddings_tensor: ggml::Tensor = input_layer.share();
 
+            ctx0.set_offloading(false);
             // lm_head
             input_layer = ctx0.op_mul_mat(&self.output, &input_layer);
 
@@ -333,12 +349,14 @@ impl KnownModel for Bloom {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, input_len);
         common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -349,6 +367,10 @@ impl KnownModel for Bloom {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -369,6 +391,53 @@ impl KnownModel for Bloom {
         vec![]
     }
 
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_embd, n_mult, n_head, n_layer) = (64, 1, 4, 2);
+
+        let mut tensors = vec![
+            ("tok_embeddings.weight".to_string(), vec![n_embd, n_vocab]),
+            ("norm.weight".to_string(), vec![n_embd]),
+            ("norm.bias".to_string(), vec![n_embd]),
+            ("output_norm.weight".to_string(), vec![n_embd]),
+            ("output_norm.bias".to_string(), vec![n_embd]),
+            ("output.weight".to_string(), vec![n_embd, n_vocab]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("attention_norm.weight", vec![n_embd]),
+                    ("attention_norm.bias", vec![n_embd]),
+                    ("attention.query_key_value.weight", vec![n_embd, 3 * n_embd]),
+                    ("attention.query_key_value.bias", vec![3 * n_embd]),
+                    ("attention.wo.weight", vec![n_embd, n_embd]),
+                    ("attention.wo.bias", vec![n_embd]),
+                    ("ffn_norm.weight", vec![n_embd]),
+                    ("ffn_norm.bias", vec![n_embd]),
+                    ("feed_forward.w1.weight", vec![n_embd, 4 * n_embd]),
+                    ("feed_forward.w1.bias", vec![4 * n_embd]),
+                    ("feed_forward.w2.weight", vec![4 * n_embd, n_embd]),
+                    ("feed_forward.w2.bias", vec![n_embd]),
+                ]
+                .map(|(name, dims)| (format!("layers.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_embd,
+                n_mult,
+                n_head,
+                n_layer,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -391,6 +460,28 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: None,
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_mult".to_string(), self.n_mult.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, llm_base::LoadError> {
         Ok(Hyperparameters {
@@ -424,6 +515,26 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, llm_base::LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_embd: metadata.usize("embedding_length")?,
+            // `n_mult` is not used by BLOOM, and is only recorded for compatibility.
+            n_mult: 1,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
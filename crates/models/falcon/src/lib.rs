@@ -11,8 +11,10 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, ConvertError, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters,
+    GraphOutputs, HfConfig, InferenceError, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, MetadataValue, ModelContext, ModelHyperparameters, ModelParameters, OutputRequest,
+    Regex, TensorNameMapping, TestModel, TokenId, Tokenizer,
 };
 
 /// The Falcon model. Ref: [Technology Innovation Institute](https://huggingface.co/tiiuae)
@@ -55,75 +57,57 @@ impl KnownModel for Falcon {
         let mut tl = tensor_loader;
 
         // model-gobal weights
-        let tok_embeddings = tl.load("transformer.word_embeddings.weight")?;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let tok_embeddings =
+            tl.load_with_dims("transformer.word_embeddings.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
         let output_norm = tl.load("transformer.ln_f.weight")?.transfer_to(backend);
         let output_norm_b = tl.load("transformer.ln_f.bias")?.transfer_to(backend);
-        let lm_head = tl.load("lm_head.weight")?.transfer_to(backend);
+        let lm_head = tl
+            .load_with_dims("lm_head.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
 
-        let mut layers = Vec::new();
         // utilizing n_head_kv to determine the model version (parameters)
         let Hyperparameters { n_head_kv, .. } = hyperparameters;
-        for i in 0..hyperparameters.n_layer {
-            let backend = params.backend(i);
-
-            let (input_layernorm_name, attention_norm_name) = if n_head_kv == 1 {
-                // falcon 7b
-                (format!("transformer.h.{i}.input_layernorm"), None)
-            } else {
-                // falcon 40b
-                (
-                    format!("transformer.h.{i}.ln_mlp"),
-                    Some(format!("transformer.h.{i}.ln_attn")),
-                )
-            };
-
-            let (attention_norm_weight, attention_norm_bias) =
-                if let Some(norm_name) = attention_norm_name {
-                    (
-                        Some(
-                            tl.load(&format!("{}.weight", norm_name))?
-                                .transfer_to(backend),
-                        ),
-                        Some(
-                            tl.load(&format!("{}.bias", norm_name))?
-                                .transfer_to(backend),
-                        ),
-                    )
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("transformer.h.{i}."),
+            |tl| {
+                let (input_layernorm_name, attention_norm_name) = if n_head_kv == 1 {
+                    // falcon 7b
+                    ("input_layernorm", None)
                 } else {
-                    (None, None)
+                    // falcon 40b
+                    ("ln_mlp", Some("ln_attn"))
                 };
 
-            let layer = Layer {
-                input_layernorm: tl
-                    .load(&format!("{}.weight", input_layernorm_name))?
-                    .transfer_to(backend),
-                input_layernorm_b: tl
-                    .load(&format!("{}.bias", input_layernorm_name))?
-                    .transfer_to(backend),
-                attention_norm: attention_norm_weight,
-                attention_norm_b: attention_norm_bias,
-                query_key_value: tl
-                    .load(&format!(
-                        "transformer.h.{i}.self_attention.query_key_value.weight"
-                    ))?
-                    .transfer_to(backend),
-                wo: tl
-                    .load(&format!("transformer.h.{i}.self_attention.dense.weight"))?
-                    .transfer_to(backend),
-
-                ffn_up: tl
-                    .load(&format!("transformer.h.{i}.mlp.dense_h_to_4h.weight"))?
-                    .transfer_to(backend),
-                ffn_down: tl
-                    .load(&format!("transformer.h.{i}.mlp.dense_4h_to_h.weight"))?
-                    .transfer_to(backend),
-            };
-
-            layers.push(layer);
-        }
+                let (attention_norm, attention_norm_b) =
+                    if let Some(norm_name) = attention_norm_name {
+                        (
+                            Some(tl.load(&format!("{norm_name}.weight"))?),
+                            Some(tl.load(&format!("{norm_name}.bias"))?),
+                        )
+                    } else {
+                        (None, None)
+                    };
+
+                Ok(Layer {
+                    input_layernorm: tl.load(&format!("{input_layernorm_name}.weight"))?,
+                    input_layernorm_b: tl.load(&format!("{input_layernorm_name}.bias"))?,
+                    attention_norm,
+                    attention_norm_b,
+                    query_key_value: tl.load("self_attention.query_key_value.weight")?,
+                    wo: tl.load("self_attention.dense.weight")?,
+                    ffn_up: tl.load("mlp.dense_h_to_4h.weight")?,
+                    ffn_down: tl.load("mlp.dense_4h_to_h.weight")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -155,7 +139,7 @@ impl KnownModel for Falcon {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -191,6 +175,10 @@ impl KnownModel for Falcon {
             let mut layernorm_output: Tensor;
 
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
                 // attention uses first scratch buffer
                 ctx0.use_scratch(builder.get_scratch(0));
                 ctx0.set_offloading(self.params.should_offload(il));
@@ -333,7 +321,7 @@ impl KnownModel for Falcon {
                 current = ctx0.op_add(&current, &attn_out);
                 current = ctx0.op_add(&current, &input_layer);
 
-                input_layer = current.share();
+                input_layer = builder.finish_layer(&ctx0, il, current.share());
             }
 
             ctx0.use_scratch(builder.get_scratch(0));
@@ -361,12 +349,14 @@ impl KnownModel for Falcon {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, input_len);
         common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -377,6 +367,10 @@ impl KnownModel for Falcon {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -396,6 +390,146 @@ impl KnownModel for Falcon {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        // A single key/value head, as in Falcon 7B.
+        let (n_embd, n_head, n_head_kv, n_layer) = (64, 4, 1, 2);
+        let head_dim = n_embd / n_head;
+
+        let mut tensors = vec![
+            (
+                "transformer.word_embeddings.weight".to_string(),
+                vec![n_embd, n_vocab],
+            ),
+            ("transformer.ln_f.weight".to_string(), vec![n_embd]),
+            ("transformer.ln_f.bias".to_string(), vec![n_embd]),
+            ("lm_head.weight".to_string(), vec![n_embd, n_vocab]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("input_layernorm.weight", vec![n_embd]),
+                    ("input_layernorm.bias", vec![n_embd]),
+                    (
+                        "self_attention.query_key_value.weight",
+                        vec![n_embd, head_dim * (n_head + 2 * n_head_kv)],
+                    ),
+                    ("self_attention.dense.weight", vec![n_embd, n_embd]),
+                    ("mlp.dense_h_to_4h.weight", vec![n_embd, 4 * n_embd]),
+                    ("mlp.dense_4h_to_h.weight", vec![4 * n_embd, n_embd]),
+                ]
+                .map(|(name, dims)| (format!("transformer.h.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_embd,
+                n_head,
+                n_head_kv,
+                n_layer,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, ConvertError> {
+        // The original checkpoints (`RWForCausalLM`) use different names for some of the
+        // hyperparameters than the ones in `transformers`.
+        let usize_or = |key: &str, fallback: &str| match config.optional_usize(key)? {
+            Some(value) => Ok(value),
+            None => config.usize(fallback),
+        };
+
+        // Falcon RW uses ALiBi instead of rotary position embeddings, which is not supported.
+        if config.optional_bool("alibi")?.unwrap_or(false) {
+            return Err(ConvertError::UnsupportedArchitecture);
+        }
+
+        let n_head = usize_or("num_attention_heads", "n_head")?;
+        let n_head_kv = match config.optional_usize("num_kv_heads")? {
+            Some(n_head_kv) => n_head_kv,
+            None => match config.optional_usize("n_head_kv")? {
+                Some(n_head_kv) => n_head_kv,
+                None if config.optional_bool("multi_query")?.unwrap_or(true) => 1,
+                None => n_head,
+            },
+        };
+
+        Ok(Hyperparameters {
+            n_vocab: config.usize("vocab_size")?,
+            n_embd: usize_or("hidden_size", "n_embed")?,
+            n_head,
+            n_head_kv,
+            n_layer: usize_or("num_hidden_layers", "n_layer")?,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_names() -> TensorNameMapping {
+        // The checkpoint uses the same names.
+        TensorNameMapping::new([
+            (
+                r"transformer\.word_embeddings\.weight",
+                "transformer.word_embeddings.weight",
+            ),
+            (r"transformer\.ln_f\.(weight|bias)", "transformer.ln_f.$1"),
+            (r"lm_head\.weight", "lm_head.weight"),
+            (
+                r"transformer\.h\.(\d+)\.(input_layernorm|ln_attn|ln_mlp)\.(weight|bias)",
+                "transformer.h.$1.$2.$3",
+            ),
+            (
+                r"transformer\.h\.(\d+)\.(self_attention\.query_key_value|self_attention\.dense|mlp\.dense_h_to_4h|mlp\.dense_4h_to_h)\.weight",
+                "transformer.h.$1.$2.weight",
+            ),
+        ])
+        .unwrap()
+    }
+
+    fn hf_transform_tensor(
+        name: &str,
+        shape: &[usize],
+        data: &mut [f32],
+        hyperparameters: &Self::Hyperparameters,
+    ) {
+        // The checkpoint groups the heads of the fused query/key/value weight by key/value
+        // head (each group's query heads, then its key head and its value head); evaluation
+        // expects all of the query heads, then all of the key heads, then all of the value
+        // heads.
+        if name.ends_with("self_attention.query_key_value.weight") {
+            let Hyperparameters {
+                n_head, n_head_kv, ..
+            } = *hyperparameters;
+            let head_rows = shape[0] / (n_head + 2 * n_head_kv);
+            let head_len = head_rows * shape[1];
+            let n_query_per_group = n_head / n_head_kv;
+
+            let grouped = data.to_vec();
+            let mut copy_head = |source: usize, destination: usize| {
+                data[destination * head_len..(destination + 1) * head_len]
+                    .copy_from_slice(&grouped[source * head_len..(source + 1) * head_len]);
+            };
+            for group in 0..n_head_kv {
+                let group_start = group * (n_query_per_group + 2);
+                for i in 0..n_query_per_group {
+                    copy_head(group_start + i, group * n_query_per_group + i);
+                }
+                copy_head(group_start + n_query_per_group, n_head + group);
+                copy_head(
+                    group_start + n_query_per_group + 1,
+                    n_head + n_head_kv + group,
+                );
+            }
+        }
+    }
 }
 
 /// Falcon [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -415,6 +549,28 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: None,
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head_kv,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_head_kv".to_string(), self.n_head_kv.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -450,6 +606,29 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+            ("attention.head_count_kv", self.n_head_kv.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        let n_head = metadata.usize("attention.head_count")?;
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_embd: metadata.usize("embedding_length")?,
+            n_head,
+            n_head_kv: metadata
+                .optional_usize("attention.head_count_kv")?
+                .unwrap_or(n_head),
+            n_layer: metadata.usize("block_count")?,
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
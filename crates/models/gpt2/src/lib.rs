@@ -5,8 +5,10 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters, GraphOutputs,
+    InferenceError, InferenceSession, InferenceSessionConfig, KnownModel, LoadError, MetadataValue,
+    ModelContext, ModelHyperparameters, ModelParameters, OutputRequest, Regex, TestModel, TokenId,
+    Tokenizer,
 };
 
 /// The GPT-2 model. Ref: [The Illustrated GPT-2](https://jalammar.github.io/illustrated-gpt2/)
@@ -47,7 +49,7 @@ impl KnownModel for Gpt2 {
 
     fn new<E: std::error::Error>(
         hyperparameters: Self::Hyperparameters,
-        params: ModelParameters,
+        mut params: ModelParameters,
         tokenizer: Tokenizer,
         tensor_loader: impl llm_base::TensorLoader<E>,
     ) -> Result<Self, E> {
@@ -56,58 +58,52 @@ impl KnownModel for Gpt2 {
         // model-global weights
         let backend = params.backend(0);
 
-        let wpe = tl.load("model/wpe")?.transfer_to(backend);
-        let wte = tl.load("model/wte")?.transfer_to(backend);
+        let Hyperparameters {
+            n_vocab,
+            n_ctx,
+            n_embd,
+            ..
+        } = hyperparameters;
+        let wpe = tl
+            .load_with_dims("model/wpe", &[n_embd, n_ctx])?
+            .transfer_to(backend);
+        let wte = tl
+            .load_with_dims("model/wte", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+
+        // There are only position embeddings for the context the model was trained with.
+        params.context_size = params.context_size.min(n_ctx);
 
         let ln_f_g = tl.load("model/ln_f/g")?.transfer_to(backend);
         let ln_f_b = tl.load("model/ln_f/b")?.transfer_to(backend);
 
         // GPT-2's language model head is optional; if it is not present,
         // the `wte` tensor is used instead.
-        let lm_head = {
-            if let Ok(tensor) = tl.load("model/lm_head") {
-                Some(tensor.transfer_to(backend))
-            } else {
-                None
-            }
-        };
-
-        let mut layers = Vec::new();
-        for i in 0..hyperparameters.n_layer {
-            let backend = params.backend(i);
-            let layer = Layer {
-                ln_1_g: tl.load(&format!("model/h{i}/ln_1/g"))?.transfer_to(backend),
-                ln_1_b: tl.load(&format!("model/h{i}/ln_1/b"))?.transfer_to(backend),
-                ln_2_g: tl.load(&format!("model/h{i}/ln_2/g"))?.transfer_to(backend),
-                ln_2_b: tl.load(&format!("model/h{i}/ln_2/b"))?.transfer_to(backend),
-                c_attn_attn_w: tl
-                    .load(&format!("model/h{i}/attn/c_attn/w"))?
-                    .transfer_to(backend),
-                c_attn_attn_b: tl
-                    .load(&format!("model/h{i}/attn/c_attn/b"))?
-                    .transfer_to(backend),
-                c_attn_proj_w: tl
-                    .load(&format!("model/h{i}/attn/c_proj/w"))?
-                    .transfer_to(backend),
-                c_attn_proj_b: tl
-                    .load(&format!("model/h{i}/attn/c_proj/b"))?
-                    .transfer_to(backend),
-                c_mlp_fc_w: tl
-                    .load(&format!("model/h{i}/mlp/c_fc/w"))?
-                    .transfer_to(backend),
-                c_mlp_fc_b: tl
-                    .load(&format!("model/h{i}/mlp/c_fc/b"))?
-                    .transfer_to(backend),
-                c_mlp_proj_w: tl
-                    .load(&format!("model/h{i}/mlp/c_proj/w"))?
-                    .transfer_to(backend),
-                c_mlp_proj_b: tl
-                    .load(&format!("model/h{i}/mlp/c_proj/b"))?
-                    .transfer_to(backend),
-            };
-
-            layers.push(layer);
-        }
+        let lm_head = tl
+            .load_optional_with_dims("model/lm_head", &[n_embd, n_vocab])?
+            .map(|tensor| tensor.transfer_to(backend));
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("model/h{i}/"),
+            |tl| {
+                Ok(Layer {
+                    ln_1_g: tl.load("ln_1/g")?,
+                    ln_1_b: tl.load("ln_1/b")?,
+                    ln_2_g: tl.load("ln_2/g")?,
+                    ln_2_b: tl.load("ln_2/b")?,
+                    c_attn_attn_w: tl.load("attn/c_attn/w")?,
+                    c_attn_attn_b: tl.load("attn/c_attn/b")?,
+                    c_attn_proj_w: tl.load("attn/c_proj/w")?,
+                    c_attn_proj_b: tl.load("attn/c_proj/b")?,
+                    c_mlp_fc_w: tl.load("mlp/c_fc/w")?,
+                    c_mlp_fc_b: tl.load("mlp/c_fc/b")?,
+                    c_mlp_proj_w: tl.load("mlp/c_proj/w")?,
+                    c_mlp_proj_b: tl.load("mlp/c_proj/b")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -140,7 +136,7 @@ impl KnownModel for Gpt2 {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -173,6 +169,10 @@ impl KnownModel for Gpt2 {
 
             let mut gf = ctx0.create_compute_graph();
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
                 ctx0.set_offloading(self.params.should_offload(il));
                 ctx0.use_scratch(builder.get_scratch(0));
                 // norm
@@ -302,7 +302,7 @@ impl KnownModel for Gpt2 {
                 current = ctx0.op_add(&current, &self.layers[il].c_mlp_proj_b);
 
                 // input for next layer
-                input_layer = ctx0.op_add(&current, &ff_in);
+                input_layer = builder.finish_layer(&ctx0, il, ctx0.op_add(&current, &ff_in));
             }
 
             ctx0.use_scratch(builder.get_scratch(0));
@@ -326,12 +326,14 @@ impl KnownModel for Gpt2 {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, input_len);
         common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -342,6 +344,10 @@ impl KnownModel for Gpt2 {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -371,6 +377,51 @@ impl KnownModel for Gpt2 {
     fn skip_quantize_tensors() -> Vec<Regex> {
         vec![]
     }
+
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_ctx, n_embd, n_head, n_layer) = (2048, 64, 4, 2);
+
+        let mut tensors = vec![
+            ("model/wpe".to_string(), vec![n_embd, n_ctx]),
+            ("model/wte".to_string(), vec![n_embd, n_vocab]),
+            ("model/ln_f/g".to_string(), vec![n_embd]),
+            ("model/ln_f/b".to_string(), vec![n_embd]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("ln_1/g", vec![n_embd]),
+                    ("ln_1/b", vec![n_embd]),
+                    ("ln_2/g", vec![n_embd]),
+                    ("ln_2/b", vec![n_embd]),
+                    ("attn/c_attn/w", vec![n_embd, 3 * n_embd]),
+                    ("attn/c_attn/b", vec![3 * n_embd]),
+                    ("attn/c_proj/w", vec![n_embd, n_embd]),
+                    ("attn/c_proj/b", vec![n_embd]),
+                    ("mlp/c_fc/w", vec![n_embd, 4 * n_embd]),
+                    ("mlp/c_fc/b", vec![4 * n_embd]),
+                    ("mlp/c_proj/w", vec![4 * n_embd, n_embd]),
+                    ("mlp/c_proj/b", vec![n_embd]),
+                ]
+                .map(|(name, dims)| (format!("model/h{i}/{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_ctx,
+                n_embd,
+                n_head,
+                n_layer,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
 }
 
 /// GPT-2 [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -390,6 +441,28 @@ pub struct Hyperparameters {
     file_type: FileType,
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: Some(self.n_ctx),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_ctx".to_string(), self.n_ctx.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -438,6 +511,26 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("context_length", self.n_ctx.into()),
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_ctx: metadata.usize("context_length")?,
+            n_embd: metadata.usize("embedding_length")?,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
@@ -7,8 +7,10 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters, GraphOutputs,
+    InferenceError, InferenceSession, InferenceSessionConfig, KnownModel, LoadError, MetadataValue,
+    ModelContext, ModelHyperparameters, ModelParameters, OutputRequest, Regex, TensorLoader,
+    TestModel, TokenId, Tokenizer,
 };
 
 /// The GPT-J model. Ref: [GitHub](https://github.com/kingoflolz/mesh-transformer-jax/#gpt-j-6b)
@@ -56,54 +58,41 @@ impl KnownModel for GptJ {
         let mut tl = tensor_loader;
 
         // model-global weights
-        let wte = tl.load("transformer.wte.weight")?;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let wte = tl.load_with_dims("transformer.wte.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
         let ln_f_g = tl.load("transformer.ln_f.weight")?.transfer_to(backend);
         let ln_f_b = tl.load("transformer.ln_f.bias")?.transfer_to(backend);
-        let lmh_g = tl.load("lm_head.weight")?.transfer_to(backend);
-        let lmh_b = tl.load("lm_head.bias")?.transfer_to(backend);
-
-        let mut layers = Vec::new();
-        for i in 0..hyperparameters.n_layer {
-            let backend = params.backend(i);
-
-            let layer = Layer {
-                ln_1_g: tl
-                    .load(&format!("transformer.h.{i}.ln_1.weight"))?
-                    .transfer_to(backend),
-                ln_1_b: tl
-                    .load(&format!("transformer.h.{i}.ln_1.bias"))?
-                    .transfer_to(backend),
-                c_attn_q_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.q_proj.weight"))?
-                    .transfer_to(backend),
-                c_attn_k_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.k_proj.weight"))?
-                    .transfer_to(backend),
-                c_attn_v_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.v_proj.weight"))?
-                    .transfer_to(backend),
-                c_attn_proj_w: tl
-                    .load(&format!("transformer.h.{i}.attn.out_proj.weight"))?
-                    .transfer_to(backend),
-                c_mlp_fc_w: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_in.weight"))?
-                    .transfer_to(backend),
-                c_mlp_fc_b: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_in.bias"))?
-                    .transfer_to(backend),
-                c_mlp_proj_w: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_out.weight"))?
-                    .transfer_to(backend),
-                c_mlp_proj_b: tl
-                    .load(&format!("transformer.h.{i}.mlp.fc_out.bias"))?
-                    .transfer_to(backend),
-            };
-
-            layers.push(layer);
-        }
+        let lmh_g = tl
+            .load_with_dims("lm_head.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+        let lmh_b = tl
+            .load_with_dims("lm_head.bias", &[n_vocab])?
+            .transfer_to(backend);
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("transformer.h.{i}."),
+            |tl| {
+                Ok(Layer {
+                    ln_1_g: tl.load("ln_1.weight")?,
+                    ln_1_b: tl.load("ln_1.bias")?,
+                    c_attn_q_proj_w: tl.load("attn.q_proj.weight")?,
+                    c_attn_k_proj_w: tl.load("attn.k_proj.weight")?,
+                    c_attn_v_proj_w: tl.load("attn.v_proj.weight")?,
+                    c_attn_proj_w: tl.load("attn.out_proj.weight")?,
+                    c_mlp_fc_w: tl.load("mlp.fc_in.weight")?,
+                    c_mlp_fc_b: tl.load("mlp.fc_in.bias")?,
+                    c_mlp_proj_w: tl.load("mlp.fc_out.weight")?,
+                    c_mlp_proj_b: tl.load("mlp.fc_out.bias")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -136,7 +125,7 @@ impl KnownModel for GptJ {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -162,6 +151,10 @@ impl KnownModel for GptJ {
 
             let mut gf = ctx0.create_compute_graph();
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
                 ctx0.set_offloading(self.params.should_offload(il));
 
                 // norm
@@ -279,7 +272,7 @@ impl KnownModel for GptJ {
                 current = ctx0.op_add(&current, &ff_in);
 
                 // input for next layer
-                input_layer = ctx0.op_add(&current, &input_layer);
+                input_layer = builder.finish_layer(&ctx0, il, ctx0.op_add(&current, &input_layer));
             }
 
             // norm
@@ -302,12 +295,14 @@ impl KnownModel for GptJ {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, input_len);
         common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -318,6 +313,10 @@ impl KnownModel for GptJ {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -338,6 +337,51 @@ impl KnownModel for GptJ {
         vec![]
     }
 
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_ctx, n_embd, n_head, n_layer) = (2048, 64, 4, 2);
+
+        let mut tensors = vec![
+            ("transformer.wte.weight".to_string(), vec![n_embd, n_vocab]),
+            ("transformer.ln_f.weight".to_string(), vec![n_embd]),
+            ("transformer.ln_f.bias".to_string(), vec![n_embd]),
+            ("lm_head.weight".to_string(), vec![n_embd, n_vocab]),
+            ("lm_head.bias".to_string(), vec![n_vocab]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("ln_1.weight", vec![n_embd]),
+                    ("ln_1.bias", vec![n_embd]),
+                    ("attn.q_proj.weight", vec![n_embd, n_embd]),
+                    ("attn.k_proj.weight", vec![n_embd, n_embd]),
+                    ("attn.v_proj.weight", vec![n_embd, n_embd]),
+                    ("attn.out_proj.weight", vec![n_embd, n_embd]),
+                    ("mlp.fc_in.weight", vec![n_embd, 4 * n_embd]),
+                    ("mlp.fc_in.bias", vec![4 * n_embd]),
+                    ("mlp.fc_out.weight", vec![4 * n_embd, n_embd]),
+                    ("mlp.fc_out.bias", vec![n_embd]),
+                ]
+                .map(|(name, dims)| (format!("transformer.h.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_ctx,
+                n_embd,
+                n_head,
+                n_layer,
+                n_rot: n_embd / n_head,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -362,6 +406,29 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: Some(self.n_ctx),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_ctx".to_string(), self.n_ctx.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("n_rot".to_string(), self.n_rot.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -411,6 +478,28 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("context_length", self.n_ctx.into()),
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+            ("rope.dimension_count", self.n_rot.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_ctx: metadata.usize("context_length")?,
+            n_embd: metadata.usize("embedding_length")?,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            n_rot: metadata.usize("rope.dimension_count")?,
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
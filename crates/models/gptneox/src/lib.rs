@@ -8,8 +8,10 @@ use ggml::Tensor;
 use llm_base::{
     ggml,
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    util, ConvertError, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters,
+    GraphOutputs, HfConfig, InferenceError, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, MetadataValue, ModelContext, ModelHyperparameters, ModelParameters, OutputRequest,
+    Regex, TensorLoader, TensorNameMapping, TestModel, TokenId, Tokenizer,
 };
 
 /// The GPT-NeoX model. Ref: [GitHub](https://github.com/EleutherAI/gpt-neox)
@@ -56,7 +58,10 @@ impl KnownModel for GptNeoX {
         let mut tl = tensor_loader;
 
         // model-global weights
-        let wte = tl.load("gpt_neox.embed_in.weight")?;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let wte = tl.load_with_dims("gpt_neox.embed_in.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
@@ -66,65 +71,31 @@ impl KnownModel for GptNeoX {
         let ln_f_b = tl
             .load("gpt_neox.final_layer_norm.bias")?
             .transfer_to(backend);
-        let lmh_g = tl.load("embed_out.weight")?.transfer_to(backend);
-
-        let mut layers = Vec::new();
-        for i in 0..hyperparameters.n_layer {
-            let backend = params.backend(i);
-            let layer = Layer {
-                ln_1_g: tl
-                    .load(&format!("gpt_neox.layers.{i}.input_layernorm.weight"))?
-                    .transfer_to(backend),
-                ln_1_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.input_layernorm.bias"))?
-                    .transfer_to(backend),
-
-                c_attn_attn_w: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.attention.query_key_value.weight"
-                    ))?
-                    .transfer_to(backend),
-                c_attn_attn_b: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.attention.query_key_value.bias"
-                    ))?
-                    .transfer_to(backend),
-
-                c_attn_proj_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.attention.dense.weight"))?
-                    .transfer_to(backend),
-                c_attn_proj_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.attention.dense.bias"))?
-                    .transfer_to(backend),
-
-                ln_2_g: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.post_attention_layernorm.weight"
-                    ))?
-                    .transfer_to(backend),
-                ln_2_b: tl
-                    .load(&format!(
-                        "gpt_neox.layers.{i}.post_attention_layernorm.bias"
-                    ))?
-                    .transfer_to(backend),
-
-                c_mlp_fc_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.weight"))?
-                    .transfer_to(backend),
-                c_mlp_fc_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_h_to_4h.bias"))?
-                    .transfer_to(backend),
-
-                c_mlp_proj_w: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.weight"))?
-                    .transfer_to(backend),
-                c_mlp_proj_b: tl
-                    .load(&format!("gpt_neox.layers.{i}.mlp.dense_4h_to_h.bias"))?
-                    .transfer_to(backend),
-            };
-
-            layers.push(layer);
-        }
+        let lmh_g = tl
+            .load_with_dims("embed_out.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("gpt_neox.layers.{i}."),
+            |tl| {
+                Ok(Layer {
+                    ln_1_g: tl.load("input_layernorm.weight")?,
+                    ln_1_b: tl.load("input_layernorm.bias")?,
+                    c_attn_attn_w: tl.load("attention.query_key_value.weight")?,
+                    c_attn_attn_b: tl.load("attention.query_key_value.bias")?,
+                    c_attn_proj_w: tl.load("attention.dense.weight")?,
+                    c_attn_proj_b: tl.load("attention.dense.bias")?,
+                    ln_2_g: tl.load("post_attention_layernorm.weight")?,
+                    ln_2_b: tl.load("post_attention_layernorm.bias")?,
+                    c_mlp_fc_w: tl.load("mlp.dense_h_to_4h.weight")?,
+                    c_mlp_fc_b: tl.load("mlp.dense_h_to_4h.bias")?,
+                    c_mlp_proj_w: tl.load("mlp.dense_4h_to_h.weight")?,
+                    c_mlp_proj_b: tl.load("mlp.dense_4h_to_h.bias")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -158,7 +129,7 @@ impl KnownModel for GptNeoX {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let n = input_tokens.len();
         let n_past = session.n_past;
         let n_ctx = self.params.context_size;
@@ -185,6 +156,10 @@ impl KnownModel for GptNeoX {
             let mut gf = ctx0.create_compute_graph();
 
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
                 ctx0.set_offloading(self.params.should_offload(il));
                 // attention uses first scratch buffer
                 ctx0.use_scratch(builder.get_scratch(0));
@@ -324,6 +299,8 @@ impl KnownModel for GptNeoX {
                     // input for next layer
                     input_layer = ctx0.op_add(&current, &input_layer);
                 }
+
+                input_layer = builder.finish_layer(&ctx0, il, input_layer);
             }
 
             // use the first scratch for the norm
@@ -349,12 +326,14 @@ impl KnownModel for GptNeoX {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, n);
         common::extract_logits(output_request, &outputs.result, n_vocab, n);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, n);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -365,6 +344,10 @@ impl KnownModel for GptNeoX {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -385,6 +368,96 @@ impl KnownModel for GptNeoX {
         vec![]
     }
 
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_ctx, n_embd, n_head, n_layer) = (2048, 64, 4, 2);
+
+        let mut tensors = vec![
+            (
+                "gpt_neox.embed_in.weight".to_string(),
+                vec![n_embd, n_vocab],
+            ),
+            ("gpt_neox.final_layer_norm.weight".to_string(), vec![n_embd]),
+            ("gpt_neox.final_layer_norm.bias".to_string(), vec![n_embd]),
+            ("embed_out.weight".to_string(), vec![n_embd, n_vocab]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("input_layernorm.weight", vec![n_embd]),
+                    ("input_layernorm.bias", vec![n_embd]),
+                    ("attention.query_key_value.weight", vec![n_embd, 3 * n_embd]),
+                    ("attention.query_key_value.bias", vec![3 * n_embd]),
+                    ("attention.dense.weight", vec![n_embd, n_embd]),
+                    ("attention.dense.bias", vec![n_embd]),
+                    ("post_attention_layernorm.weight", vec![n_embd]),
+                    ("post_attention_layernorm.bias", vec![n_embd]),
+                    ("mlp.dense_h_to_4h.weight", vec![n_embd, 4 * n_embd]),
+                    ("mlp.dense_h_to_4h.bias", vec![4 * n_embd]),
+                    ("mlp.dense_4h_to_h.weight", vec![4 * n_embd, n_embd]),
+                    ("mlp.dense_4h_to_h.bias", vec![n_embd]),
+                ]
+                .map(|(name, dims)| (format!("gpt_neox.layers.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_ctx,
+                n_embd,
+                n_head,
+                n_layer,
+                n_rot: n_embd / n_head,
+                use_parallel_residual: true,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, ConvertError> {
+        let n_embd = config.usize("hidden_size")?;
+        let n_head = config.usize("num_attention_heads")?;
+        let rotary_pct = config.optional_f32("rotary_pct")?.unwrap_or(1.0);
+
+        Ok(Hyperparameters {
+            n_vocab: config.usize("vocab_size")?,
+            n_ctx: config.usize("max_position_embeddings")?,
+            n_embd,
+            n_head,
+            n_layer: config.usize("num_hidden_layers")?,
+            n_rot: ((n_embd / n_head) as f32 * rotary_pct) as usize,
+            use_parallel_residual: config
+                .optional_bool("use_parallel_residual")?
+                .unwrap_or(true),
+            file_type,
+        })
+    }
+
+    fn hf_tensor_names() -> TensorNameMapping {
+        // The checkpoint uses the same names; these rules only leave out the buffers of the
+        // attention (`attention.bias`, `attention.masked_bias` and `rotary_emb.inv_freq`).
+        TensorNameMapping::new([
+            (r"gpt_neox\.embed_in\.weight", "gpt_neox.embed_in.weight"),
+            (
+                r"gpt_neox\.final_layer_norm\.(weight|bias)",
+                "gpt_neox.final_layer_norm.$1",
+            ),
+            (r"embed_out\.weight", "embed_out.weight"),
+            (
+                r"gpt_neox\.layers\.(\d+)\.(input_layernorm|attention\.query_key_value|attention\.dense|post_attention_layernorm|mlp\.dense_h_to_4h|mlp\.dense_4h_to_h)\.(weight|bias)",
+                "gpt_neox.layers.$1.$2.$3",
+            ),
+        ])
+        .unwrap()
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -427,6 +500,33 @@ impl Default for Hyperparameters {
     }
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: Some(self.n_ctx),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_ctx".to_string(), self.n_ctx.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("n_rot".to_string(), self.n_rot.into()),
+                (
+                    "use_parallel_residual".to_string(),
+                    self.use_parallel_residual.into(),
+                ),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         Ok(Hyperparameters {
@@ -464,6 +564,32 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("context_length", self.n_ctx.into()),
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+            ("rope.dimension_count", self.n_rot.into()),
+            ("use_parallel_residual", self.use_parallel_residual.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_ctx: metadata.usize("context_length")?,
+            n_embd: metadata.usize("embedding_length")?,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            n_rot: metadata.usize("rope.dimension_count")?,
+            use_parallel_residual: metadata
+                .optional_bool("use_parallel_residual")?
+                .unwrap_or(true),
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
@@ -6,8 +6,11 @@ use std::error::Error;
 use llm_base::{
     ggml::{self},
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TensorLoader, TokenId, Tokenizer,
+    reverse_hf_rotary_permutation, util, ConvertError, DescribeHyperparameters, FileType,
+    FileTypeFormat, GgufHyperparameters, GraphOutputs, HfConfig, InferenceError, InferenceSession,
+    InferenceSessionConfig, KnownModel, LoadError, MetadataValue, ModelContext,
+    ModelHyperparameters, ModelParameters, OutputRequest, Regex, TensorLoader, TensorNameMapping,
+    TestModel, TokenId, Tokenizer,
 };
 
 /// The LLaMA model. Ref: [Introducing LLaMA](https://ai.facebook.com/blog/large-language-model-llama-meta-ai/)
@@ -49,49 +52,36 @@ impl KnownModel for Llama {
         let mut tl = tensor_loader;
 
         // model-global weights
-        let wte = tl.load("tok_embeddings.weight")?;
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let wte = tl.load_with_dims("tok_embeddings.weight", &[n_embd, n_vocab])?;
 
         let backend = params.backend(0);
 
         let norm = tl.load("norm.weight")?.transfer_to(backend);
-        let output = tl.load("output.weight")?.transfer_to(backend);
-
-        let mut layers = Vec::new();
-
-        for i in 0..hyperparameters.n_layer {
-            let backend = params.backend(i);
-
-            let layer = Layer {
-                attention_norm: tl
-                    .load(&format!("layers.{i}.attention_norm.weight"))?
-                    .transfer_to(backend),
-                wq: tl
-                    .load(&format!("layers.{i}.attention.wq.weight"))?
-                    .transfer_to(backend),
-                wk: tl
-                    .load(&format!("layers.{i}.attention.wk.weight"))?
-                    .transfer_to(backend),
-                wv: tl
-                    .load(&format!("layers.{i}.attention.wv.weight"))?
-                    .transfer_to(backend),
-                wo: tl
-                    .load(&format!("layers.{i}.attention.wo.weight"))?
-                    .transfer_to(backend),
-                ffn_norm: tl
-                    .load(&format!("layers.{i}.ffn_norm.weight"))?
-                    .transfer_to(backend),
-                w1: tl
-                    .load(&format!("layers.{i}.feed_forward.w1.weight"))?
-                    .transfer_to(backend),
-                w2: tl
-                    .load(&format!("layers.{i}.feed_forward.w2.weight"))?
-                    .transfer_to(backend),
-                w3: tl
-                    .load(&format!("layers.{i}.feed_forward.w3.weight"))?
-                    .transfer_to(backend),
-            };
-            layers.push(layer);
-        }
+        let output = tl
+            .load_with_dims("output.weight", &[n_embd, n_vocab])?
+            .transfer_to(backend);
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("layers.{i}."),
+            |tl| {
+                Ok(Layer {
+                    attention_norm: tl.load("attention_norm.weight")?,
+                    wq: tl.load("attention.wq.weight")?,
+                    wk: tl.load("attention.wk.weight")?,
+                    wv: tl.load("attention.wv.weight")?,
+                    wo: tl.load("attention.wo.weight")?,
+                    ffn_norm: tl.load("ffn_norm.weight")?,
+                    w1: tl.load("feed_forward.w1.weight")?,
+                    w2: tl.load("feed_forward.w2.weight")?,
+                    w3: tl.load("feed_forward.w3.weight")?,
+                })
+            },
+        )?;
         let context = tl.finish();
 
         // TODO: read from file
@@ -105,12 +95,8 @@ impl KnownModel for Llama {
         };
         // TODO: temporary fix for 70B models
         if let Some(n_gqa) = params.n_gqa {
+            // The loader has checked that `n_head` is a multiple of `n_gqa`.
             if hyperparameters.n_layer >= 80 {
-                assert_eq!(
-                    hyperparameters.n_head % n_gqa,
-                    0,
-                    "assuming 70B Llama2 model based on GQA == 8"
-                );
                 hyperparameters.n_head_kv = hyperparameters.n_head / n_gqa;
                 version = LlamaModelType::Model70b;
             }
@@ -146,7 +132,7 @@ impl KnownModel for Llama {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let input_len = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -172,6 +158,10 @@ impl KnownModel for Llama {
             let mut gf = ctx0.create_compute_graph();
 
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
                 ctx0.set_offloading(self.params.should_offload(il));
 
                 let input_self_attention = input_layer.share();
@@ -186,34 +176,44 @@ impl KnownModel for Llama {
                 current = ctx0.op_mul(&current, &self.layers[il].attention_norm);
 
                 // self-attention
-                // compute Q and K and RoPE them
+                // compute Q and K and RoPE them, from the position of each sequence
                 let overrides = self.params.rope_overrides.as_ref();
                 let q_current = ctx0
-                    .op_rope_inplace(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wq, &current),
-                            n_embd / n_head,
-                            n_head,
-                            input_len,
+                    .op_reshape_3d(
+                        &ctx0.op_rope_inplace(
+                            &builder.split_sequences(
+                                &ctx0,
+                                &ctx0.op_mul_mat(&self.layers[il].wq, &current),
+                                n_embd / n_head,
+                                n_head,
+                            ),
+                            builder.position,
+                            n_rot,
+                            0,
+                            overrides,
                         ),
-                        session_len,
-                        n_rot,
-                        0,
-                        overrides,
+                        n_embd / n_head,
+                        n_head,
+                        input_len,
                     )
                     .set_name("Qcur");
                 let k_current = ctx0
-                    .op_rope_inplace(
-                        &ctx0.op_reshape_3d(
-                            &ctx0.op_mul_mat(&self.layers[il].wk, &current),
-                            n_embd / n_head,
-                            n_head_kv,
-                            input_len,
+                    .op_reshape_3d(
+                        &ctx0.op_rope_inplace(
+                            &builder.split_sequences(
+                                &ctx0,
+                                &ctx0.op_mul_mat(&self.layers[il].wk, &current),
+                                n_embd / n_head,
+                                n_head_kv,
+                            ),
+                            builder.position,
+                            n_rot,
+                            0,
+                            overrides,
                         ),
-                        session_len,
-                        n_rot,
-                        0,
-                        overrides,
+                        n_embd / n_head,
+                        n_head_kv,
+                        input_len,
                     )
                     .set_name("Kcur");
 
@@ -271,8 +271,8 @@ impl KnownModel for Llama {
                 let k_q_scaled = ctx0.op_scale_inplace(&k_q, &kq_scale).set_name("KQ_scaled");
 
                 // KQ_masked = mask_past(KQ_scaled)
-                let k_q_masked = ctx0
-                    .op_diag_mask_inf_inplace(&k_q_scaled, session_len)
+                let k_q_masked = builder
+                    .mask_attention(&ctx0, &k_q_scaled, session_len)
                     .set_name("KQ_masked");
 
                 // KQ = soft_max(KQ_masked)
@@ -334,7 +334,7 @@ impl KnownModel for Llama {
                 current = ctx0.op_add(&current, &input_feed_forward);
 
                 // input for next layer
-                input_layer = current;
+                input_layer = builder.finish_layer(&ctx0, il, current);
             }
 
             ctx0.use_scratch(builder.get_scratch(0));
@@ -359,12 +359,14 @@ impl KnownModel for Llama {
                     embedding_result,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, input_len);
         common::extract_logits(output_request, &outputs.result, n_vocab, input_len);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, input_len);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -375,6 +377,10 @@ impl KnownModel for Llama {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -395,9 +401,148 @@ impl KnownModel for Llama {
         vec![]
     }
 
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (n_embd, n_mult, n_head, n_layer) = (64, 32, 4, 2);
+        let n_ff = feed_forward_length(n_embd, n_mult);
+
+        let mut tensors = vec![
+            ("tok_embeddings.weight".to_string(), vec![n_embd, n_vocab]),
+            ("norm.weight".to_string(), vec![n_embd]),
+            ("output.weight".to_string(), vec![n_embd, n_vocab]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("attention_norm.weight", vec![n_embd]),
+                    ("attention.wq.weight", vec![n_embd, n_embd]),
+                    ("attention.wk.weight", vec![n_embd, n_embd]),
+                    ("attention.wv.weight", vec![n_embd, n_embd]),
+                    ("attention.wo.weight", vec![n_embd, n_embd]),
+                    ("ffn_norm.weight", vec![n_embd]),
+                    ("feed_forward.w1.weight", vec![n_embd, n_ff]),
+                    ("feed_forward.w2.weight", vec![n_ff, n_embd]),
+                    ("feed_forward.w3.weight", vec![n_embd, n_ff]),
+                ]
+                .map(|(name, dims)| (format!("layers.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_vocab,
+                n_embd,
+                n_mult,
+                n_head,
+                n_head_kv: n_head,
+                n_layer,
+                n_rot: n_embd / n_head,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, ConvertError> {
+        let n_embd = config.usize("hidden_size")?;
+        let n_head = config.usize("num_attention_heads")?;
+        let n_head_kv = config
+            .optional_usize("num_key_value_heads")?
+            .unwrap_or(n_head);
+        let n_ff = config.usize("intermediate_size")?;
+        if n_head_kv != n_head {
+            tracing::warn!(
+                "The model uses grouped-query attention, which GGML files do not record; \
+                 load it with `ModelParameters::n_gqa` set to {}",
+                n_head / n_head_kv
+            );
+        }
+
+        Ok(Hyperparameters {
+            n_vocab: config.usize("vocab_size")?,
+            n_embd,
+            n_mult: n_mult(n_embd, n_ff),
+            n_head,
+            n_head_kv,
+            n_layer: config.usize("num_hidden_layers")?,
+            n_rot: n_embd / n_head,
+            file_type,
+        })
+    }
+
+    fn hf_tensor_names() -> TensorNameMapping {
+        TensorNameMapping::new([
+            (r"model\.embed_tokens\.weight", "tok_embeddings.weight"),
+            (r"model\.norm\.weight", "norm.weight"),
+            (r"lm_head\.weight", "output.weight"),
+            (
+                r"model\.layers\.(\d+)\.input_layernorm\.weight",
+                "layers.$1.attention_norm.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.self_attn\.q_proj\.weight",
+                "layers.$1.attention.wq.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.self_attn\.k_proj\.weight",
+                "layers.$1.attention.wk.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.self_attn\.v_proj\.weight",
+                "layers.$1.attention.wv.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.self_attn\.o_proj\.weight",
+                "layers.$1.attention.wo.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.post_attention_layernorm\.weight",
+                "layers.$1.ffn_norm.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.mlp\.gate_proj\.weight",
+                "layers.$1.feed_forward.w1.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.mlp\.down_proj\.weight",
+                "layers.$1.feed_forward.w2.weight",
+            ),
+            (
+                r"model\.layers\.(\d+)\.mlp\.up_proj\.weight",
+                "layers.$1.feed_forward.w3.weight",
+            ),
+        ])
+        .unwrap()
+    }
+
+    fn hf_transform_tensor(
+        name: &str,
+        shape: &[usize],
+        data: &mut [f32],
+        hyperparameters: &Self::Hyperparameters,
+    ) {
+        // The Hugging Face conversion permutes the query and key weights for its
+        // implementation of rotary position embeddings.
+        if name.ends_with("attention.wq.weight") {
+            reverse_hf_rotary_permutation(data, shape[0], hyperparameters.n_head);
+        } else if name.ends_with("attention.wk.weight") {
+            reverse_hf_rotary_permutation(data, shape[0], hyperparameters.n_head_kv);
+        }
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
+
+    fn supports_sequence_batches(&self) -> bool {
+        // The offloaded operations do not support the attention mask of the sequences.
+        !self.params.use_gpu
+    }
 }
 
 /// LLaMA [hyperparameters](https://en.wikipedia.org/wiki/Hyperparameter_(machine_learning))
@@ -421,6 +566,30 @@ pub struct Hyperparameters {
     pub file_type: FileType,
 }
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: None,
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head_kv,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("n_mult".to_string(), self.n_mult.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_head_kv".to_string(), self.n_head_kv.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("n_rot".to_string(), self.n_rot.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let n_vocab = util::read_i32(reader)?.try_into()?;
@@ -468,6 +637,55 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            (
+                "feed_forward_length",
+                feed_forward_length(self.n_embd, self.n_mult).into(),
+            ),
+            ("attention.head_count", self.n_head.into()),
+            ("attention.head_count_kv", self.n_head_kv.into()),
+            ("rope.dimension_count", self.n_rot.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        let n_embd = metadata.usize("embedding_length")?;
+        let n_head = metadata.usize("attention.head_count")?;
+        Ok(Hyperparameters {
+            n_vocab: metadata.n_vocab(),
+            n_embd,
+            n_mult: n_mult(n_embd, metadata.usize("feed_forward_length")?),
+            n_head,
+            n_head_kv: metadata
+                .optional_usize("attention.head_count_kv")?
+                .unwrap_or(n_head),
+            n_layer: metadata.usize("block_count")?,
+            n_rot: match metadata.optional_usize("rope.dimension_count")? {
+                Some(n_rot) => n_rot,
+                None => n_embd.checked_div(n_head).unwrap_or_default(),
+            },
+            file_type: metadata.file_type()?,
+        })
+    }
+}
+
+/// Returns the size of the feed-forward layers of a model with `n_embd` and `n_mult`.
+fn feed_forward_length(n_embd: usize, n_mult: usize) -> usize {
+    let n_mult = n_mult.max(1);
+    ((2 * (4 * n_embd) / 3 + n_mult - 1) / n_mult) * n_mult
+}
+
+/// Returns the `n_mult` that the feed-forward size `n_ff` would be derived from. It is only
+/// recorded for compatibility, as the feed-forward size is read from the tensors.
+fn n_mult(n_embd: usize, n_ff: usize) -> usize {
+    (1..=8192)
+        .rev()
+        .find(|&n_mult| feed_forward_length(n_embd, n_mult) == n_ff)
+        .unwrap_or(256)
 }
 
 struct Layer {
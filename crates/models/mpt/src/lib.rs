@@ -5,8 +5,10 @@ use ggml::Tensor;
 use llm_base::{
     ggml::{self},
     model::{common, HyperparametersWriteError},
-    util, FileType, GraphOutputs, InferenceSession, InferenceSessionConfig, KnownModel, LoadError,
-    ModelContext, ModelParameters, OutputRequest, Regex, TokenId, Tokenizer,
+    util, ConvertError, DescribeHyperparameters, FileType, FileTypeFormat, GgufHyperparameters,
+    GraphOutputs, HfConfig, InferenceError, InferenceSession, InferenceSessionConfig, KnownModel,
+    LoadError, MetadataValue, ModelContext, ModelHyperparameters, ModelParameters, OutputRequest,
+    Regex, TensorNameMapping, TestModel, TokenId, Tokenizer,
 };
 
 /// The MosaicML Pretrained Transformer (MPT) model. Ref: [Mosaic ML](https://www.mosaicml.com/blog/mpt-7b)
@@ -47,25 +49,28 @@ impl KnownModel for Mpt {
         let mut tl = tensor_loader;
 
         // model-gobal weights
-        let wte = tl.load("transformer.wte.weight")?;
-        let norm = tl.load("transformer.norm_f.weight")?;
-
-        let mut layers = Vec::new();
-        for i in 0..hyperparameters.n_layer {
-            let layer = Layer {
-                norm_1_weight: tl.load(&format!("transformer.blocks.{i}.norm_1.weight"))?,
-                c_attn_wqkv_weight: tl.load(&format!("transformer.blocks.{i}.attn.Wqkv.weight"))?,
-
-                c_attn_out_proj_weight: tl
-                    .load(&format!("transformer.blocks.{i}.attn.out_proj.weight"))?,
-                norm_2_weight: tl.load(&format!("transformer.blocks.{i}.norm_2.weight"))?,
-
-                ffn_up_proj: tl.load(&format!("transformer.blocks.{i}.ffn.up_proj.weight"))?,
-                ffn_down_proj: tl.load(&format!("transformer.blocks.{i}.ffn.down_proj.weight"))?,
-            };
-
-            layers.push(layer);
-        }
+        let Hyperparameters {
+            n_vocab, n_embd, ..
+        } = hyperparameters;
+        let wte = tl.load_with_dims("transformer.wte.weight", &[n_embd, n_vocab])?;
+        let backend = params.backend(0);
+        let norm = tl.load("transformer.norm_f.weight")?.transfer_to(backend);
+
+        let layers = tl.load_layers(
+            &params,
+            hyperparameters.n_layer,
+            |i| format!("transformer.blocks.{i}."),
+            |tl| {
+                Ok(Layer {
+                    norm_1_weight: tl.load("norm_1.weight")?,
+                    c_attn_wqkv_weight: tl.load("attn.Wqkv.weight")?,
+                    c_attn_out_proj_weight: tl.load("attn.out_proj.weight")?,
+                    norm_2_weight: tl.load("norm_2.weight")?,
+                    ffn_up_proj: tl.load("ffn.up_proj.weight")?,
+                    ffn_down_proj: tl.load("ffn.down_proj.weight")?,
+                })
+            },
+        )?;
 
         let context = tl.finish();
 
@@ -95,7 +100,7 @@ impl KnownModel for Mpt {
         session: &mut InferenceSession,
         input_tokens: &[TokenId],
         output_request: &mut OutputRequest,
-    ) {
+    ) -> Result<(), InferenceError> {
         let n = input_tokens.len();
         let session_len = session.n_past;
         let ctx_size = self.params.context_size;
@@ -108,9 +113,12 @@ impl KnownModel for Mpt {
             alibi_bias_max,
             ..
         } = self.hyperparameters;
+        let positional_bias = common::PositionalBias::Alibi {
+            bias_max: self.params.alibi_bias_max.unwrap_or(alibi_bias_max),
+        };
 
         let outputs = session.compute(self.context.clone(), input_tokens, |builder| {
-            let ctx0 = builder.ctx0.borrow();
+            let mut ctx0 = builder.ctx0.borrow_mut();
             let (memory_k_size, memory_v_size) = (
                 builder.memory_k.element_size(),
                 builder.memory_v.element_size(),
@@ -123,6 +131,12 @@ impl KnownModel for Mpt {
 
             let mut gf = ctx0.create_compute_graph();
             for il in 0..n_layer {
+                if !builder.should_evaluate_layer(il) {
+                    continue;
+                }
+
+                ctx0.set_offloading(self.params.should_offload(il));
+
                 // attention uses first scratch buffer
                 ctx0.use_scratch(builder.get_scratch(0));
 
@@ -177,8 +191,7 @@ impl KnownModel for Mpt {
                     &kq,
                     &ctx0.new_f32(1f32 / f32::sqrt(n_embd as f32 / n_head as f32)),
                 );
-                let kq_scaled_alibi =
-                    ctx0.op_alibi(&kq_scaled, session_len, n_head, alibi_bias_max);
+                let kq_scaled_alibi = positional_bias.apply(&ctx0, &kq_scaled, session_len, n_head);
                 let kq_masked = ctx0.op_diag_mask_inf(&kq_scaled_alibi, session_len);
                 let kq_softmax = ctx0.op_soft_max(&kq_masked);
 
@@ -226,9 +239,10 @@ impl KnownModel for Mpt {
                 // projection
                 current = ctx0.op_mul_mat(&self.layers[il].ffn_down_proj, &current);
 
-                input_layer = ctx0.op_add(&input_layer, &current);
+                input_layer = builder.finish_layer(&ctx0, il, ctx0.op_add(&input_layer, &current));
             }
 
+            ctx0.set_offloading(false);
             //use scratch buffer 0 for the rest
             ctx0.use_scratch(builder.get_scratch(0));
 
@@ -250,12 +264,14 @@ impl KnownModel for Mpt {
                     embedding_result: embeddings_tensor,
                 },
             )
-        });
+        })?;
 
         // finish evaluation
         common::read_last_token(session, &outputs.result, n_vocab, n);
         common::extract_logits(output_request, &outputs.result, n_vocab, n);
         common::extract_embeddings(output_request, &outputs.embedding_result, n_embd, n);
+
+        Ok(())
     }
 
     fn hyperparameters(&self) -> &Self::Hyperparameters {
@@ -266,6 +282,10 @@ impl KnownModel for Mpt {
         &self.tokenizer
     }
 
+    fn context(&self) -> &ModelContext {
+        &self.context
+    }
+
     fn context_size(&self) -> usize {
         self.params.context_size
     }
@@ -286,6 +306,81 @@ impl KnownModel for Mpt {
         vec![]
     }
 
+    fn test_model(n_vocab: usize) -> Option<TestModel<Self::Hyperparameters>> {
+        let (max_seq_len, n_embd, n_head, n_layer) = (2048, 64, 4, 2);
+
+        let mut tensors = vec![
+            ("transformer.wte.weight".to_string(), vec![n_embd, n_vocab]),
+            ("transformer.norm_f.weight".to_string(), vec![n_embd]),
+        ];
+        for i in 0..n_layer {
+            tensors.extend(
+                [
+                    ("norm_1.weight", vec![n_embd]),
+                    ("attn.Wqkv.weight", vec![n_embd, 3 * n_embd]),
+                    ("attn.out_proj.weight", vec![n_embd, n_embd]),
+                    ("norm_2.weight", vec![n_embd]),
+                    ("ffn.up_proj.weight", vec![n_embd, 4 * n_embd]),
+                    ("ffn.down_proj.weight", vec![4 * n_embd, n_embd]),
+                ]
+                .map(|(name, dims)| (format!("transformer.blocks.{i}.{name}"), dims)),
+            );
+        }
+
+        Some(TestModel {
+            hyperparameters: Hyperparameters {
+                n_embd,
+                max_seq_len,
+                n_head,
+                n_layer,
+                n_vocab,
+                alibi_bias_max: 8.0,
+                clip_kqv: 0.0,
+                file_type: FileType {
+                    format: FileTypeFormat::F32,
+                    quantization_version: 0,
+                },
+            },
+            tensors,
+        })
+    }
+
+    fn hf_hyperparameters(
+        config: &HfConfig,
+        file_type: FileType,
+    ) -> Result<Self::Hyperparameters, ConvertError> {
+        let attn_config = config.optional_section("attn_config")?;
+        let attn_value = |key: &str| match &attn_config {
+            Some(attn_config) => attn_config.optional_f32(key),
+            None => Ok(None),
+        };
+
+        Ok(Hyperparameters {
+            n_embd: config.usize("d_model")?,
+            max_seq_len: config.usize("max_seq_len")?,
+            n_head: config.usize("n_heads")?,
+            n_layer: config.usize("n_layers")?,
+            n_vocab: config.usize("vocab_size")?,
+            alibi_bias_max: attn_value("alibi_bias_max")?.unwrap_or(8.0),
+            // A clip of 0 disables clipping.
+            clip_kqv: attn_value("clip_qkv")?.unwrap_or(0.0),
+            file_type,
+        })
+    }
+
+    fn hf_tensor_names() -> TensorNameMapping {
+        // The checkpoint uses the same names.
+        TensorNameMapping::new([
+            (r"transformer\.wte\.weight", "transformer.wte.weight"),
+            (r"transformer\.norm_f\.weight", "transformer.norm_f.weight"),
+            (
+                r"transformer\.blocks\.(\d+)\.(norm_1|attn\.Wqkv|attn\.out_proj|norm_2|ffn\.up_proj|ffn\.down_proj)\.weight",
+                "transformer.blocks.$1.$2.weight",
+            ),
+        ])
+        .unwrap()
+    }
+
     fn supports_rewind(&self) -> bool {
         true
     }
@@ -313,6 +408,30 @@ pub struct Hyperparameters {
 }
 impl Eq for Hyperparameters {}
 
+impl DescribeHyperparameters for Hyperparameters {
+    fn describe(&self) -> ModelHyperparameters {
+        ModelHyperparameters {
+            context_size_trained: Some(self.max_seq_len),
+            n_embd: self.n_embd,
+            n_layer: self.n_layer,
+            n_head: self.n_head,
+            n_head_kv: self.n_head,
+            n_vocab: self.n_vocab,
+            file_type: Some(self.file_type),
+            metadata: vec![
+                ("n_embd".to_string(), self.n_embd.into()),
+                ("max_seq_len".to_string(), self.max_seq_len.into()),
+                ("n_head".to_string(), self.n_head.into()),
+                ("n_layer".to_string(), self.n_layer.into()),
+                ("n_vocab".to_string(), self.n_vocab.into()),
+                ("alibi_bias_max".to_string(), self.alibi_bias_max.into()),
+                ("clip_kqv".to_string(), self.clip_kqv.into()),
+                ("file_type".to_string(), self.file_type.into()),
+            ],
+        }
+    }
+}
+
 impl llm_base::Hyperparameters for Hyperparameters {
     fn read_ggml(reader: &mut dyn std::io::BufRead) -> Result<Self, LoadError> {
         let hyperparameters = Hyperparameters {
@@ -352,6 +471,33 @@ impl llm_base::Hyperparameters for Hyperparameters {
     fn file_type_mut(&mut self) -> Option<&mut FileType> {
         Some(&mut self.file_type)
     }
+
+    fn gguf_metadata(&self) -> Vec<(&'static str, MetadataValue)> {
+        vec![
+            ("context_length", self.max_seq_len.into()),
+            ("embedding_length", self.n_embd.into()),
+            ("block_count", self.n_layer.into()),
+            ("attention.head_count", self.n_head.into()),
+            ("attention.max_alibi_bias", self.alibi_bias_max.into()),
+            ("attention.clamp_kqv", self.clip_kqv.into()),
+        ]
+    }
+
+    fn read_gguf(metadata: &GgufHyperparameters) -> Result<Self, LoadError> {
+        Ok(Hyperparameters {
+            n_embd: metadata.usize("embedding_length")?,
+            max_seq_len: metadata.usize("context_length")?,
+            n_head: metadata.usize("attention.head_count")?,
+            n_layer: metadata.usize("block_count")?,
+            n_vocab: metadata.n_vocab(),
+            alibi_bias_max: metadata
+                .optional_f32("attention.max_alibi_bias")?
+                .unwrap_or(8.0),
+            // A clip of 0 disables clipping.
+            clip_kqv: metadata.optional_f32("attention.clamp_kqv")?.unwrap_or(0.0),
+            file_type: metadata.file_type()?,
+        })
+    }
 }
 
 struct Layer {
@@ -0,0 +1,38 @@
+//! Parses arbitrary bytes as a GGML, GGMF, GGJT or GGLA file, with hyperparameters that
+//! only consist of the vocabulary size.
+
+#![no_main]
+
+use std::io::{BufRead, Cursor};
+
+use ggml::format::{load, LoadHandler, PartialHyperparameters, TensorLoadInfo};
+use libfuzzer_sys::fuzz_target;
+
+struct Handler;
+impl LoadHandler<std::io::Error> for Handler {
+    fn container_type(&mut self, _: ggml::ContainerType) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn vocabulary_token(&mut self, _: usize, _: Vec<u8>, _: f32) -> Result<(), std::io::Error> {
+        Ok(())
+    }
+
+    fn read_hyperparameters(
+        &mut self,
+        reader: &mut dyn BufRead,
+    ) -> Result<PartialHyperparameters, std::io::Error> {
+        let n_vocab = ggml::util::read_u32(reader)? as usize;
+        Ok(PartialHyperparameters { n_vocab })
+    }
+
+    fn tensor_buffer(&mut self, info: TensorLoadInfo) -> Result<(), std::io::Error> {
+        // The loader must only report tensors whose size can be computed.
+        let _ = info.calc_size();
+        Ok(())
+    }
+}
+
+fuzz_target!(|data: &[u8]| {
+    let _ = load(&mut Cursor::new(data), &mut Handler);
+});
@@ -0,0 +1,23 @@
+//! Parses arbitrary bytes as a LLaMA model with an embedded vocabulary, as `llm_base::load`
+//! does before creating the model's tensors.
+
+#![no_main]
+
+use std::{io::Cursor, path::Path};
+
+use libfuzzer_sys::fuzz_target;
+use llm_base::{Loader, TokenizerSource};
+
+fuzz_target!(|data: &[u8]| {
+    let tokenizer = TokenizerSource::Embedded.retrieve(Path::new("")).unwrap();
+    let mut loader = Loader::<llm_llama::Hyperparameters, _>::new(tokenizer, |_| {});
+    if ggml::format::load(&mut Cursor::new(data), &mut loader).is_err() {
+        return;
+    }
+
+    // Everything the loader accepted must be usable.
+    for info in loader.tensors.values() {
+        let _ = info.calc_absolute_size(false);
+    }
+    let _ = loader.tokenizer.tokenize("The quick brown fox", true);
+});
@@ -0,0 +1,43 @@
+//! Tokenizes arbitrary text with an arbitrary embedded vocabulary, and checks that
+//! decoding the tokens returns the text.
+
+#![no_main]
+
+use std::{io::Cursor, path::Path};
+
+use ggml::{util, ContainerType};
+use libfuzzer_sys::{arbitrary::Arbitrary, fuzz_target};
+use llm_base::{Hyperparameters, Loader, TokenizerSource};
+
+#[derive(Arbitrary, Debug)]
+struct Input {
+    vocabulary: Vec<(Vec<u8>, f32)>,
+    text: String,
+}
+
+fuzz_target!(|input: Input| {
+    // Write a model that consists of nothing but the vocabulary.
+    let mut file = vec![];
+    ContainerType::Ggjt(3).write(&mut file).unwrap();
+    let hyperparameters = llm_llama::Hyperparameters {
+        n_vocab: input.vocabulary.len(),
+        ..Default::default()
+    };
+    hyperparameters.write_ggml(&mut file).unwrap();
+    for (token, score) in &input.vocabulary {
+        util::write_u32(&mut file, token.len() as u32).unwrap();
+        file.extend_from_slice(token);
+        util::write_f32(&mut file, *score).unwrap();
+    }
+
+    let tokenizer = TokenizerSource::Embedded.retrieve(Path::new("")).unwrap();
+    let mut loader = Loader::<llm_llama::Hyperparameters, _>::new(tokenizer, |_| {});
+    ggml::format::load(&mut Cursor::new(file), &mut loader).unwrap();
+    let tokenizer = loader.tokenizer;
+
+    let Ok(tokens) = tokenizer.tokenize(&input.text, false) else {
+        return;
+    };
+    let token_ids = tokens.iter().map(|(_, id)| *id).collect();
+    assert_eq!(tokenizer.decode(token_ids, false), input.text.as_bytes());
+});